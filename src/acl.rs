@@ -0,0 +1,210 @@
+//! Which clients may query the DNS server.
+//!
+//! `dns_allow`/`dns_deny` decide who gets any answer at all; `forward_allow`
+//! additionally restricts who may have names forwarded upstream, so glued
+//! can answer container names widely without being an open resolver.
+//! `axfr_allow` lists the secondaries that may transfer the local zone;
+//! unlike the others, it allows no one when empty.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+/// An IPv4 or IPv6 network such as `10.0.0.0/8` or `fd00::/8`.  A bare
+/// address is a single host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Whether `ip` lies in this network.  IPv4-mapped IPv6 clients, as seen
+    /// on dual-stack sockets, match IPv4 networks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+
+    /// The prefix length; more is a narrower network.
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+}
+
+/// Whether the top `prefix` of `bits` bits agree.
+fn prefix_matches(net: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+    let shift = u32::from(bits - prefix);
+    prefix == 0 || net >> shift == ip >> shift
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid network '{}': {}", s, e))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| anyhow::anyhow!("Invalid prefix length in '{}'", s))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+/// The DNS server's client access lists.
+#[derive(Debug, Clone, Default)]
+pub struct DnsAcl {
+    /// When non-empty, only these clients are answered.
+    allow: Vec<Cidr>,
+    /// Never answered, even if allowed.
+    deny: Vec<Cidr>,
+    /// When non-empty, only these clients have names forwarded upstream.
+    forward_allow: Vec<Cidr>,
+    /// The only clients that may transfer the zone.
+    axfr_allow: Vec<Cidr>,
+}
+
+impl DnsAcl {
+    pub fn new(
+        allow: Vec<Cidr>,
+        deny: Vec<Cidr>,
+        forward_allow: Vec<Cidr>,
+        axfr_allow: Vec<Cidr>,
+    ) -> Self {
+        Self {
+            allow,
+            deny,
+            forward_allow,
+            axfr_allow,
+        }
+    }
+
+    pub fn from_config(cfg: &Config) -> Self {
+        Self::new(
+            cfg.dns_allow.clone(),
+            cfg.dns_deny.clone(),
+            cfg.forward_allow.clone(),
+            cfg.axfr_allow.clone(),
+        )
+    }
+
+    /// Whether `client` may query at all.
+    pub fn permits(&self, client: IpAddr) -> bool {
+        !self.deny.iter().any(|net| net.contains(client))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(client)))
+    }
+
+    /// Whether `client`, already permitted, may have names forwarded upstream.
+    pub fn permits_forwarding(&self, client: IpAddr) -> bool {
+        self.forward_allow.is_empty() || self.forward_allow.iter().any(|net| net.contains(client))
+    }
+
+    /// Whether `client`, already permitted, may transfer the zone.
+    pub fn permits_transfer(&self, client: IpAddr) -> bool {
+        self.axfr_allow.iter().any(|net| net.contains(client))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nets(list: &[&str]) -> Vec<Cidr> {
+        list.iter().map(|net| net.parse().unwrap()).collect()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn networks_match_by_prefix() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.200.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(net.contains(ip("::ffff:10.1.0.9")));
+        assert!(!net.contains(ip("fd00::1")));
+
+        let net: Cidr = "fd00:1::/32".parse().unwrap();
+        assert!(net.contains(ip("fd00:1:ffff::1")));
+        assert!(!net.contains(ip("fd00:2::1")));
+
+        let host: Cidr = "192.0.2.7".parse().unwrap();
+        assert_eq!(host.to_string(), "192.0.2.7/32");
+        assert!(host.contains(ip("192.0.2.7")));
+        assert!(!host.contains(ip("192.0.2.8")));
+
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("8.8.8.8")));
+        assert!("::/0".parse::<Cidr>().unwrap().contains(ip("2001:db8::1")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn deny_wins_and_forwarding_is_stricter() {
+        let acl = DnsAcl::new(
+            nets(&["10.0.0.0/8", "fd00::/8"]),
+            nets(&["10.9.0.0/16"]),
+            nets(&["10.1.0.0/16"]),
+            nets(&["10.1.0.53"]),
+        );
+        assert!(acl.permits(ip("10.1.2.3")));
+        assert!(acl.permits(ip("fd00::5")));
+        assert!(!acl.permits(ip("10.9.0.1")));
+        assert!(!acl.permits(ip("192.0.2.1")));
+        assert!(acl.permits_forwarding(ip("10.1.2.3")));
+        assert!(!acl.permits_forwarding(ip("10.2.0.1")));
+        assert!(acl.permits_transfer(ip("10.1.0.53")));
+        assert!(!acl.permits_transfer(ip("10.1.2.3")));
+
+        let open = DnsAcl::default();
+        assert!(open.permits(ip("203.0.113.1")));
+        assert!(open.permits_forwarding(ip("203.0.113.1")));
+        assert!(!open.permits_transfer(ip("203.0.113.1")));
+    }
+}
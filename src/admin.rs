@@ -0,0 +1,646 @@
+//! Admin HTTP API, served on `admin_bind` when it is set.
+//!
+//! A deliberately small HTTP/1.1 server: one request per connection and
+//! JSON responses.  Endpoints:
+//!
+//! * `GET /v1/entries`: every name served, with its addresses, ports and
+//!   where it came from.
+//! * `POST /v1/entries` with `{"name", "ip", "ttl"?}`: pins the name to the
+//!   address on every node, for `ttl` seconds or until deleted.
+//! * `DELETE /v1/entries/{name}`: drops a pin.
+//! * `GET /v1/peers`: the gossip peers met, and since when we hold an
+//!   authenticated session with each.
+//! * `GET /v1/conflicts`: names published by several nodes, with the node
+//!   served and those set aside; see [`crate::conflicts`].
+//! * `POST /v1/evict/{node}`: drops every entry the node, named by its
+//!   NodeId or short NodeId, published, on every node; see
+//!   [`crate::evict`].
+//! * `POST /v1/reconcile`: rescans the containers now and asks peers for a
+//!   sync, answering with the entries added and removed; see
+//!   [`crate::resync`].
+//! * `GET /metrics`: counters, DNS query latency and the age of the
+//!   stalest entry in the Prometheus text format.  DNS queries are only
+//!   timed while the admin API runs.
+//! * `GET /healthz`: answers while the process serves at all.
+//!
+//! With `admin_token` set, every endpoint but `/healthz` requires
+//! `Authorization: Bearer <admin_token>`; with `metrics_token` set,
+//! `/metrics` requires either token, else it is open.  Requests without
+//! the right token get the same 401 whatever is configured and whatever
+//! the path, so they learn nothing about either.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use log::{debug, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use crate::evict;
+use crate::metrics::{self, METRICS};
+use crate::names::normalize;
+use crate::peers::PeerTable;
+use crate::pins::PinRequest;
+use crate::registry::{LocalSource, LocalUpdate};
+use crate::status::Status;
+use crate::types::{now_millis, Entry, SharedState, Source, StateMap, Update};
+
+/// Largest request head accepted.
+const MAX_HEAD: usize = 16 * 1024;
+
+/// Largest request body accepted.
+const MAX_BODY: usize = 64 * 1024;
+
+/// Connections that haven't sent a full request by then are closed.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An HTTP request's line and body.
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    /// The `Authorization` header.
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(value: &impl Serialize) -> Self {
+        match serde_json::to_string_pretty(value) {
+            Ok(body) => Self {
+                status: 200,
+                content_type: "application/json",
+                body,
+            },
+            Err(e) => Self::error(500, &e.to_string()),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        let body = serde_json::json!({ "error": message }).to_string();
+        Self {
+            status,
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            201 => "Created",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+/// An entry as listed by `/v1/entries`.
+#[derive(Serialize)]
+pub(crate) struct EntryView<'a> {
+    name: &'a str,
+    #[serde(flatten)]
+    entry: &'a Entry,
+}
+
+/// What the admin API works on.
+pub struct Admin {
+    pub state: SharedState,
+    pub status: Arc<Status>,
+    /// The local update pipeline, which applies updates and gossips them.
+    pub updates: mpsc::Sender<LocalUpdate>,
+    /// `admin_token`, required by all but `/healthz`.
+    pub token: Option<String>,
+    /// `metrics_token`, which `/metrics` requires besides `token`.
+    pub metrics_token: Option<String>,
+}
+
+impl Admin {
+    /// Whether `request` carries a token its path accepts, if it needs
+    /// one.
+    fn permits(&self, request: &Request) -> bool {
+        let accepted: Vec<&str> = match (request.path.as_str(), &self.metrics_token) {
+            ("/healthz", _) | ("/metrics", None) => return true,
+            ("/metrics", Some(metrics)) => std::iter::once(metrics.as_str())
+                .chain(self.token.as_deref())
+                .collect(),
+            _ => match &self.token {
+                Some(token) => vec![token],
+                None => return true,
+            },
+        };
+        let presented = request
+            .authorization
+            .as_deref()
+            .and_then(|value| value.trim().split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map_or("", |(_, token)| token.trim());
+        // Every token is compared, so the time taken doesn't tell which.
+        accepted
+            .iter()
+            .fold(false, |ok, token| token_matches(presented, token) | ok)
+    }
+}
+
+/// Compares digests, so neither the contents nor the length of the token
+/// show in the time taken.
+fn token_matches(presented: &str, token: &str) -> bool {
+    let presented = Sha256::digest(presented.as_bytes());
+    let token = Sha256::digest(token.as_bytes());
+    presented.ct_eq(&token).into()
+}
+
+/// Serves the admin API on `listener` until the task is aborted.
+pub async fn run_admin(listener: TcpListener, admin: Admin) {
+    metrics::enable();
+    let admin = Arc::new(admin);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Admin API accept failed: {}", e);
+                continue;
+            }
+        };
+        let admin = Arc::clone(&admin);
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(stream, &admin).await {
+                debug!("Admin API connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn serve_connection(mut stream: TcpStream, admin: &Admin) -> anyhow::Result<()> {
+    let response = match tokio::time::timeout(READ_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(request)) => route(&request, admin).await,
+        Ok(Err(e)) => Response::error(400, &e.to_string()),
+        Err(_) => return Err(anyhow!("timed out reading the request")),
+    };
+    let challenge = match response.status {
+        401 => "WWW-Authenticate: Bearer\r\n",
+        _ => "",
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\n{}Content-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        challenge,
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Reads a request head, parses its request line and reads the body
+/// `Content-Length` announces.
+async fn read_request(stream: &mut TcpStream) -> anyhow::Result<Request> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    let head_end = loop {
+        if let Some(at) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break at + 4;
+        }
+        if buf.len() > MAX_HEAD {
+            bail!("request head too large");
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            bail!("connection closed mid-request");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+    let head = String::from_utf8_lossy(&buf[..head_end]).into_owned();
+    let mut lines = head.lines();
+    let mut parts = lines.next().unwrap_or_default().split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        bail!("malformed request line");
+    };
+    let headers: Vec<(&str, &str)> = lines.filter_map(|line| line.split_once(':')).collect();
+    let header = |wanted: &str| {
+        headers
+            .iter()
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| value.trim())
+    };
+    let authorization = header("authorization").map(str::to_string);
+    let length = header("content-length")
+        .map(|value| value.parse::<usize>())
+        .transpose()
+        .map_err(|_| anyhow!("invalid Content-Length"))?
+        .unwrap_or(0);
+    if length > MAX_BODY {
+        bail!("request body too large");
+    }
+    let mut body = buf.split_off(head_end);
+    while body.len() < length {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            bail!("connection closed mid-request");
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+    body.truncate(length);
+    // The query string is unused.
+    let path = target.split('?').next().unwrap_or_default();
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        authorization,
+        body,
+    })
+}
+
+/// What `/v1/info` says about this node.
+async fn info(admin: &Admin) -> serde_json::Value {
+    serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "entries": admin.state.read().await.len(),
+        "conflict_policy": admin.state.conflict_policy().as_str(),
+        "ready": admin.status.is_ready(),
+    })
+}
+
+async fn route(request: &Request, admin: &Admin) -> Response {
+    if !admin.permits(request) {
+        return Response::error(401, "unauthorized");
+    }
+    if let Some(node) = request.path.strip_prefix("/v1/evict/") {
+        return match request.method.as_str() {
+            "POST" => evict(node, admin).await,
+            _ => Response::error(405, "method not allowed"),
+        };
+    }
+    let entry = request.path.strip_prefix("/v1/entries/");
+    match (request.method.as_str(), request.path.as_str(), entry) {
+        ("GET", "/v1/entries", _) => Response::json(&entries(&*admin.state.read().await)),
+        ("POST", "/v1/entries", _) => pin(request, admin).await,
+        (_, "/v1/entries", _) => Response::error(405, "method not allowed"),
+        ("GET", "/v1/peers", _) => Response::json(&peers(&admin.status.peers())),
+        (_, "/v1/peers", _) => Response::error(405, "method not allowed"),
+        ("GET", "/v1/info", _) => Response::json(&info(admin).await),
+        (_, "/v1/info", _) => Response::error(405, "method not allowed"),
+        ("GET", "/v1/conflicts", _) => Response::json(&admin.state.conflicts().await),
+        (_, "/v1/conflicts", _) => Response::error(405, "method not allowed"),
+        ("POST", "/v1/reconcile", _) => Response::json(&admin.status.resync().run().await),
+        (_, "/v1/reconcile", _) => Response::error(405, "method not allowed"),
+        ("GET", "/v1/ready", _) => match admin.status.unready() {
+            None => Response::json(&serde_json::json!({ "ready": true })),
+            Some(reason) => Response::error(503, reason),
+        },
+        (_, "/v1/ready", _) => Response::error(405, "method not allowed"),
+        ("GET", "/metrics", _) => Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: METRICS.render()
+                + &metrics::render_state(&*admin.state.read().await, now_millis()),
+        },
+        (_, "/metrics", _) => Response::error(405, "method not allowed"),
+        ("GET", "/healthz", _) => Response::json(&serde_json::json!({ "ok": true })),
+        (_, "/healthz", _) => Response::error(405, "method not allowed"),
+        ("DELETE", _, Some(name)) if !name.is_empty() => unpin(name, admin).await,
+        (_, _, Some(name)) if !name.is_empty() => Response::error(405, "method not allowed"),
+        _ => Response::error(404, "not found"),
+    }
+}
+
+/// `POST /v1/entries`: publishes a pin.
+async fn pin(request: &Request, admin: &Admin) -> Response {
+    let pin = match serde_json::from_slice::<PinRequest>(&request.body) {
+        Ok(pin) => pin.validate(),
+        Err(e) => Err(format!("invalid entry: {}", e)),
+    };
+    let pin = match pin {
+        Ok(pin) => pin,
+        Err(e) => return Response::error(400, &e),
+    };
+    let created = Response {
+        status: 201,
+        ..Response::json(&pin)
+    };
+    let update = (pin.into(), LocalSource::AdminApi);
+    if admin.updates.send(update).await.is_err() {
+        return Response::error(503, "the update pipeline has stopped");
+    }
+    created
+}
+
+/// `DELETE /v1/entries/{name}`: drops a pin.
+async fn unpin(name: &str, admin: &Admin) -> Response {
+    let name = normalize(name);
+    match admin
+        .state
+        .read()
+        .await
+        .get(&name)
+        .map(|entry| entry.source)
+    {
+        None => return Response::error(404, "no such entry"),
+        Some(Source::Manual) => {}
+        Some(_) => return Response::error(409, "not a manual entry"),
+    }
+    if admin
+        .updates
+        .send((Update::Unpin { name: name.clone() }, LocalSource::AdminApi))
+        .await
+        .is_err()
+    {
+        return Response::error(503, "the update pipeline has stopped");
+    }
+    Response::json(&serde_json::json!({ "name": name }))
+}
+
+/// `POST /v1/evict/{node}`: evicts a node.
+async fn evict(node: &str, admin: &Admin) -> Response {
+    let node = match evict::short_id(node) {
+        Ok(node) => node,
+        Err(e) => return Response::error(400, &e.to_string()),
+    };
+    if admin
+        .updates
+        .send((
+            Update::EvictNode { node: node.clone() },
+            LocalSource::AdminApi,
+        ))
+        .await
+        .is_err()
+    {
+        return Response::error(503, "the update pipeline has stopped");
+    }
+    Response::json(&serde_json::json!({ "node": node }))
+}
+
+/// The state map as a list sorted by name.
+pub(crate) fn entries(map: &StateMap) -> Vec<EntryView<'_>> {
+    let mut entries: Vec<EntryView> = map
+        .iter()
+        .map(|(name, entry)| EntryView { name, entry })
+        .collect();
+    entries.sort_by_key(|view| view.name);
+    entries
+}
+
+/// The peer table as listed by `/v1/peers` and `glued ctl peers`.
+pub(crate) fn peers(table: &PeerTable) -> Vec<serde_json::Value> {
+    table
+        .iter()
+        .map(|(node, info)| {
+            serde_json::json!({
+                "node": node.to_string(),
+                "name": info.name,
+                "neighbor": info.neighbor,
+                "authenticated": info.authenticated,
+                "session_since": info.session_since,
+                "path": info.path.to_string(),
+                "last_seen": info.last_seen,
+                "malformed": info.malformed,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Port, PortProtocol};
+
+    async fn get(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn entries_list_names_with_their_ports() {
+        let state = SharedState::default();
+        {
+            let mut map = state.write().await;
+            map.insert(
+                "web-1".into(),
+                Entry {
+                    ports: vec![Port {
+                        port: 80,
+                        protocol: PortProtocol::Tcp,
+                    }],
+                    ..Entry::new("10.0.0.2".parse().unwrap())
+                },
+            );
+            map.insert("db".into(), Entry::new("10.0.0.3".parse().unwrap()));
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (updates, _published) = mpsc::channel(1);
+        let admin = Admin {
+            state,
+            status: Arc::new(Status::new(false)),
+            updates,
+            token: None,
+            metrics_token: None,
+        };
+        let server = tokio::spawn(run_admin(listener, admin));
+
+        let response = get(addr, "GET /v1/entries HTTP/1.1\r\nHost: glued\r\n\r\n").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        let listed: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(listed[0]["name"], "db");
+        assert!(listed[0].get("ports").is_none());
+        assert_eq!(listed[1]["name"], "web-1");
+        assert_eq!(
+            listed[1]["ports"],
+            serde_json::json!([{ "port": 80, "protocol": "tcp" }])
+        );
+
+        let response = get(addr, "GET /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(response.contains("Content-Type: text/plain"));
+        assert!(response.contains("# TYPE glued_dns_query_seconds histogram"));
+
+        let response = get(addr, "GET /v1/nothing HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404"));
+        let response = get(addr, "DELETE /v1/entries HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405"));
+        let response = get(addr, "GET /v1/peers HTTP/1.1\r\n\r\n").await;
+        assert!(response.ends_with("[]"), "{}", response);
+        let response = get(addr, "GET /v1/ready HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        let response = get(addr, "GET /v1/info HTTP/1.1\r\n\r\n").await;
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let info: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(info["conflict_policy"], "lowest-node");
+        assert_eq!(info["entries"], 2);
+
+        // Without runtime monitors or gossip, there is nothing to repair.
+        let response = get(addr, "POST /v1/reconcile HTTP/1.1\r\n\r\n").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", response);
+        let report: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(report["added"], 0);
+        assert_eq!(report["sync_requested"], false);
+        let response = get(addr, "GET /v1/reconcile HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405"));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn pins_are_published_and_dropped() {
+        let state = SharedState::default();
+        state
+            .write()
+            .await
+            .insert("web-1".into(), Entry::new("10.0.0.2".parse().unwrap()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (updates, mut published) = mpsc::channel(4);
+        let admin = Admin {
+            state: Arc::clone(&state),
+            status: Arc::new(Status::new(false)),
+            updates,
+            token: None,
+            metrics_token: None,
+        };
+        let server = tokio::spawn(run_admin(listener, admin));
+        let post = |body: &str| {
+            format!(
+                "POST /v1/entries HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        };
+
+        let response = get(addr, &post(r#"{"name": "Canary", "ip": "10.0.0.9"}"#)).await;
+        assert!(response.starts_with("HTTP/1.1 201"), "{}", response);
+        let (pin, source) = published.recv().await.unwrap();
+        assert_eq!(source, LocalSource::AdminApi);
+        assert_eq!(
+            pin,
+            Update::Pin {
+                name: "canary".into(),
+                ip: "10.0.0.9".parse().unwrap(),
+                expires_at: None
+            }
+        );
+        crate::gossip::apply_update(pin, source, &state).await;
+
+        for bad in [
+            r#"{"name": "two.labels", "ip": "10.0.0.9"}"#,
+            r#"{"name": "canary", "ip": "10.0.0"}"#,
+            r#"{"name": "canary"}"#,
+        ] {
+            let response = get(addr, &post(bad)).await;
+            assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+        }
+
+        let response = get(addr, "DELETE /v1/entries/web-1 HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 409"));
+        let response = get(addr, "DELETE /v1/entries/missing HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404"));
+        let response = get(addr, "DELETE /v1/entries/canary HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert_eq!(
+            published.recv().await,
+            Some((
+                Update::Unpin {
+                    name: "canary".into()
+                },
+                LocalSource::AdminApi
+            ))
+        );
+
+        let response = get(addr, "POST /v1/evict/AB12CD34EF HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert_eq!(
+            published.recv().await,
+            Some((
+                Update::EvictNode {
+                    node: "ab12cd34ef".into()
+                },
+                LocalSource::AdminApi
+            ))
+        );
+        let response = get(addr, "POST /v1/evict/web-1 HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400"));
+        let response = get(addr, "GET /v1/evict/ab12cd34ef HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405"));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn tokens_guard_everything_but_healthz() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (updates, _published) = mpsc::channel(1);
+        let admin = Admin {
+            state: SharedState::default(),
+            status: Arc::new(Status::new(false)),
+            updates,
+            token: Some("s3cret".into()),
+            metrics_token: Some("scrape".into()),
+        };
+        let server = tokio::spawn(run_admin(listener, admin));
+        let with = |path: &str, auth: &str| format!("GET {} HTTP/1.1\r\n{}\r\n", path, auth);
+        let status = |response: String| response[9..12].to_string();
+
+        for auth in [
+            "",
+            "Authorization: Bearer wrong\r\n",
+            "Authorization: s3cret\r\n",
+        ] {
+            let denied = get(addr, &with("/v1/entries", auth)).await;
+            assert_eq!(status(denied.clone()), "401", "{}", auth);
+            assert!(denied.contains("WWW-Authenticate: Bearer"));
+            // Unknown paths look the same, so nothing is learnt from them.
+            let unknown = get(addr, &with("/v1/nothing", auth)).await;
+            assert_eq!(unknown, denied);
+        }
+        let response = get(
+            addr,
+            &with("/v1/entries", "Authorization: Bearer s3cret\r\n"),
+        )
+        .await;
+        assert_eq!(status(response), "200");
+        let response = get(
+            addr,
+            &with("/v1/entries", "authorization: bearer s3cret\r\n"),
+        )
+        .await;
+        assert_eq!(status(response), "200");
+
+        // Metrics take either token; health checks need none.
+        let response = get(addr, &with("/metrics", "")).await;
+        assert_eq!(status(response), "401");
+        for token in ["scrape", "s3cret"] {
+            let auth = format!("Authorization: Bearer {}\r\n", token);
+            assert_eq!(status(get(addr, &with("/metrics", &auth)).await), "200");
+        }
+        let response = get(
+            addr,
+            &with("/v1/entries", "Authorization: Bearer scrape\r\n"),
+        )
+        .await;
+        assert_eq!(status(response), "401");
+        assert_eq!(status(get(addr, &with("/healthz", "")).await), "200");
+        server.abort();
+    }
+}
@@ -0,0 +1,353 @@
+//! Audit log of registry changes, as JSON Lines.
+//!
+//! With `audit_log` set, every add, remove, pin, unpin and eviction the registry
+//! announces, whether made here or heard over gossip, is appended to that
+//! file as one JSON object:
+//!
+//! ```text
+//! {"timestamp": 1709251199999, "action": "add", "name": "web-1", "ip": "10.0.0.2", "origin_node": "ab12cd34ef", "origin_node_name": "swarm-worker-3", "source": "gossip"}
+//! ```
+//!
+//! `source` says what made the change: `docker-event`, `containerd-event`,
+//! `reconcile`, `health-check`, `admin-api`, `control` or `gossip`.  Updates are recorded
+//! as they came, including parts dropped as beyond the state map's limits.
+//!
+//! The log follows the registry's change feed, so writing it never holds up
+//! an update.  Lines are buffered and written once the feed is idle, or at
+//! the latest every second.  Records that can't be written, because the log
+//! fell behind or the file failed, are dropped with a warning and counted.
+//! For log rotation, the file is reopened on SIGUSR1, and when the path no
+//! longer names the open file because it was renamed or deleted.
+
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use log::{info, warn};
+use serde::Serialize;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::broadcast;
+
+use crate::metrics::METRICS;
+use crate::registry::Change;
+use crate::types::{now_millis, Update};
+
+/// Longest a written record stays in the buffer.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// One change to one name, as logged.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Record {
+    /// Unix time in milliseconds the change was applied.
+    pub timestamp: u64,
+    pub action: Action,
+    pub name: String,
+    /// The new address; none for removals.
+    pub ip: Option<IpAddr>,
+    /// Short NodeId of the peer that published the change; none for this
+    /// node's own.
+    pub origin_node: Option<String>,
+    pub origin_node_name: Option<String>,
+    pub source: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Add,
+    Remove,
+    Pin,
+    Unpin,
+    /// `name` is the evicted node's short NodeId.
+    Evict,
+}
+
+impl Record {
+    /// The records `change` makes, in order.  Ports, leases and scoped
+    /// addresses aren't recorded.
+    pub fn from_change(change: &Change) -> Vec<Record> {
+        let mut records = Vec::new();
+        let timestamp = now_millis();
+        collect(&change.update, &mut |action, name: &str, ip| {
+            let peer = change.origin.peer();
+            records.push(Record {
+                timestamp,
+                action,
+                name: name.to_string(),
+                ip,
+                origin_node: peer.map(|peer| peer.id.clone()),
+                origin_node_name: peer.and_then(|peer| peer.name.clone()),
+                source: change.origin.source(),
+            });
+        });
+        records
+    }
+}
+
+fn collect(update: &Update, record: &mut impl FnMut(Action, &str, Option<IpAddr>)) {
+    match update {
+        Update::Add { name, ip } => record(Action::Add, name, Some(*ip)),
+        Update::Remove { name } => record(Action::Remove, name, None),
+        Update::Pin { name, ip, .. } => record(Action::Pin, name, Some(*ip)),
+        Update::Unpin { name } => record(Action::Unpin, name, None),
+        Update::EvictNode { node } => record(Action::Evict, node, None),
+        Update::Ports { .. }
+        | Update::Lease { .. }
+        | Update::ScopedIps { .. }
+        | Update::Txt { .. } => {}
+        Update::Batch(updates) => {
+            for update in updates {
+                collect(update, record);
+            }
+        }
+    }
+}
+
+/// The open audit log file.
+pub struct AuditLog {
+    path: PathBuf,
+    /// `None` after a failure, until the file is reopened.
+    file: Option<BufWriter<File>>,
+    /// Device and inode of the open file, to notice it being renamed.
+    id: Option<(u64, u64)>,
+    /// Records written since the last flush, lost if it fails.
+    buffered: u64,
+}
+
+impl AuditLog {
+    /// Opens `path` for appending, creating it if needed.
+    pub async fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let mut log = Self {
+            path: path.into(),
+            file: None,
+            id: None,
+            buffered: 0,
+        };
+        log.reopen().await?;
+        Ok(log)
+    }
+
+    /// Flushes what is buffered and opens the file at the path again.
+    async fn reopen(&mut self) -> anyhow::Result<()> {
+        self.flush().await;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .with_context(|| format!("Failed to open audit log {}", self.path.display()))?;
+        self.id = file_id(&file.metadata().await?);
+        self.file = Some(BufWriter::new(file));
+        Ok(())
+    }
+
+    /// Reopens the file if it failed, or if the path now names another
+    /// file or none, as after log rotation.
+    async fn check(&mut self) {
+        let moved = match tokio::fs::metadata(&self.path).await {
+            Ok(meta) => file_id(&meta) != self.id,
+            Err(_) => true,
+        };
+        if self.file.is_some() && !moved {
+            return;
+        }
+        match self.reopen().await {
+            Ok(()) if moved => info!("Reopened audit log {}", self.path.display()),
+            Ok(()) => info!("Audit log {} is writable again", self.path.display()),
+            Err(e) => warn!("{:#}", e),
+        }
+    }
+
+    /// Buffers `records`, dropping them if the file has failed.
+    async fn append(&mut self, records: &[Record]) {
+        let Some(file) = &mut self.file else {
+            METRICS
+                .audit_dropped
+                .fetch_add(records.len() as u64, Ordering::Relaxed);
+            return;
+        };
+        let mut lines = Vec::new();
+        for record in records {
+            serde_json::to_writer(&mut lines, record).expect("records serialize");
+            lines.push(b'\n');
+        }
+        match file.write_all(&lines).await {
+            Ok(()) => self.buffered += records.len() as u64,
+            Err(e) => {
+                self.fail(&e);
+                METRICS
+                    .audit_dropped
+                    .fetch_add(records.len() as u64, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Writes out what is buffered.
+    async fn flush(&mut self) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        if let Err(e) = file.flush().await {
+            self.fail(&e);
+        }
+        self.buffered = 0;
+    }
+
+    /// Closes the file after a failure; it is reopened on the next check.
+    fn fail(&mut self, e: &std::io::Error) {
+        warn!(
+            "Failed to write audit log {}: {}; dropping records until it can be reopened",
+            self.path.display(),
+            e
+        );
+        METRICS
+            .audit_dropped
+            .fetch_add(self.buffered, Ordering::Relaxed);
+        self.buffered = 0;
+        self.file = None;
+    }
+}
+
+#[cfg(unix)]
+fn file_id(meta: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Appends every change announced on `changes` to `log`, until the task is
+/// aborted or the registry goes.
+pub async fn run_audit(mut log: AuditLog, mut changes: broadcast::Receiver<Arc<Change>>) {
+    let mut ticker = tokio::time::interval(FLUSH_INTERVAL);
+    #[cfg(unix)]
+    let mut reopen = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())
+        .map_err(|e| warn!("Can't reopen the audit log on SIGUSR1: {}", e))
+        .ok();
+    loop {
+        #[cfg(unix)]
+        let reopen_requested = async {
+            match &mut reopen {
+                Some(signal) => signal.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let reopen_requested = std::future::pending::<Option<()>>();
+        tokio::select! {
+            change = changes.recv() => match change {
+                Ok(change) => {
+                    log.append(&Record::from_change(&change)).await;
+                    if changes.is_empty() {
+                        log.flush().await;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Audit log missed {} registry changes", missed);
+                    METRICS.audit_dropped.fetch_add(missed, Ordering::Relaxed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = ticker.tick() => {
+                log.flush().await;
+                log.check().await;
+            }
+            Some(()) = reopen_requested => {
+                match log.reopen().await {
+                    Ok(()) => info!("Reopened audit log {}", log.path.display()),
+                    Err(e) => warn!("{:#}", e),
+                }
+            }
+        }
+    }
+    log.flush().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::{ChangeOrigin, LocalSource, PeerNode};
+
+    #[test]
+    fn changes_are_recorded_per_name_with_their_source() {
+        let change = Change {
+            update: Update::batch(vec![
+                Update::Add {
+                    name: "web-1".into(),
+                    ip: "10.0.0.2".parse().unwrap(),
+                },
+                Update::Lease {
+                    name: "web-1".into(),
+                    valid_for_secs: 600,
+                },
+                Update::Unpin {
+                    name: "canary".into(),
+                },
+            ]),
+            origin: ChangeOrigin::Peer(Some(PeerNode {
+                id: "ab12cd34ef".into(),
+                name: Some("swarm-worker-3".into()),
+                stamp: None,
+            })),
+        };
+        let records = Record::from_change(&change);
+        assert_eq!(records.len(), 2);
+        let line = serde_json::to_value(&records[0]).unwrap();
+        assert_eq!(line["action"], "add");
+        assert_eq!(line["ip"], "10.0.0.2");
+        assert_eq!(line["origin_node"], "ab12cd34ef");
+        assert_eq!(line["origin_node_name"], "swarm-worker-3");
+        assert_eq!(line["source"], "gossip");
+        assert_eq!((records[1].action, records[1].ip), (Action::Unpin, None));
+
+        let local = Change {
+            update: Update::Remove {
+                name: "web-1".into(),
+            },
+            origin: ChangeOrigin::Local(LocalSource::Reconcile),
+        };
+        let records = Record::from_change(&local);
+        assert_eq!(records[0].source, "reconcile");
+        assert_eq!(records[0].origin_node, None);
+    }
+
+    #[tokio::test]
+    async fn log_follows_a_rotated_file() {
+        let dir = std::env::temp_dir().join(format!("glued-audit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.jsonl");
+        let record = |name: &str| Record {
+            timestamp: 1,
+            action: Action::Remove,
+            name: name.into(),
+            ip: None,
+            origin_node: None,
+            origin_node_name: None,
+            source: "control",
+        };
+
+        let mut log = AuditLog::open(&path).await.unwrap();
+        log.append(&[record("web-1"), record("web-2")]).await;
+        log.flush().await;
+        let rotated = dir.join("audit.jsonl.1");
+        std::fs::rename(&path, &rotated).unwrap();
+        log.check().await;
+        log.append(&[record("web-3")]).await;
+        log.flush().await;
+
+        let lines = |path| std::fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(lines(&rotated), 2);
+        assert_eq!(lines(&path), 1);
+        let text = std::fs::read_to_string(&path).unwrap();
+        let last: serde_json::Value = serde_json::from_str(text.trim()).unwrap();
+        assert_eq!(last["name"], "web-3");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
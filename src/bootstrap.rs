@@ -0,0 +1,197 @@
+//! Bootstrap peer discovery through Docker Swarm service DNS.
+//!
+//! Swarm publishes the addresses of a service's tasks under
+//! `tasks.<service>`.  The lookup is repeated periodically so replicas that
+//! start after us, or move, are still found.  Discovered addresses are
+//! direct-address hints for the configured bootstrap NodeIds.
+//!
+//! The name is resolved through the system's resolver, which in a container
+//! is Docker's, asking for IPv4 and IPv6 addresses alike so IPv6-only
+//! overlays work.  Each address is paired with `gossip_port`, which every
+//! node binds on both families.
+
+use std::collections::BTreeSet;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use hickory_resolver::config::{LookupIpStrategy, ResolverConfig, ResolverOpts};
+use hickory_resolver::system_conf::read_system_conf;
+use hickory_resolver::TokioAsyncResolver;
+use log::{debug, info, warn};
+use tokio::sync::watch;
+
+/// Lookups made before settling for an empty initial answer.
+const INITIAL_ATTEMPTS: u32 = 4;
+/// Delay before the first retry; doubled after each failure.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Resolves the task addresses of a Swarm service.
+pub struct ServiceDiscovery {
+    resolver: TokioAsyncResolver,
+    lookup_name: String,
+    port: u16,
+}
+
+impl ServiceDiscovery {
+    /// Discovery for `service`, whose peers listen for gossip on `port`.
+    pub fn new(service: &str, port: u16) -> Self {
+        let (config, opts) = read_system_conf().unwrap_or_else(|e| {
+            warn!(
+                "Failed to load system resolv.conf: {}. Bootstrap lookups use Google DNS.",
+                e
+            );
+            (ResolverConfig::google(), ResolverOpts::default())
+        });
+        Self::with_resolver(config, opts, service, port)
+    }
+
+    fn with_resolver(
+        config: ResolverConfig,
+        mut opts: ResolverOpts,
+        service: &str,
+        port: u16,
+    ) -> Self {
+        opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        Self {
+            resolver: TokioAsyncResolver::tokio(config, opts),
+            // The hostname for a Docker Swarm service's tasks is `tasks.<service_name>`
+            lookup_name: format!("tasks.{}", service),
+            port,
+        }
+    }
+
+    /// The service's task addresses, with the gossip port.
+    pub async fn lookup(&self) -> anyhow::Result<BTreeSet<SocketAddr>> {
+        let response = self.resolver.lookup_ip(self.lookup_name.as_str()).await?;
+        Ok(response
+            .iter()
+            .map(|ip: IpAddr| SocketAddr::new(ip, self.port))
+            .collect())
+    }
+
+    /// The first lookup retries with backoff, since other tasks of the service
+    /// may still be starting.
+    async fn initial_lookup(&self) -> BTreeSet<SocketAddr> {
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1..=INITIAL_ATTEMPTS {
+            match self.lookup().await {
+                Ok(addrs) if !addrs.is_empty() => return addrs,
+                Ok(_) => debug!("DNS lookup for '{}' returned no IPs.", self.lookup_name),
+                Err(e) => debug!("DNS lookup for '{}' failed: {}", self.lookup_name, e),
+            }
+            if attempt < INITIAL_ATTEMPTS {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+        warn!(
+            "No peers found via '{}' after {} attempts. Continuing with configured peers.",
+            self.lookup_name, INITIAL_ATTEMPTS
+        );
+        BTreeSet::new()
+    }
+
+    /// Publishes the discovered addresses to `peers`, refreshing them every
+    /// `interval`.  Addresses that disappear from DNS are dropped.
+    pub async fn run(self, interval: Duration, peers: watch::Sender<BTreeSet<SocketAddr>>) {
+        info!(
+            "Discovering bootstrap peers for '{}' every {:?}",
+            self.lookup_name, interval
+        );
+        let initial = self.initial_lookup().await;
+        publish(&peers, initial);
+
+        loop {
+            tokio::time::sleep(interval).await;
+            match self.lookup().await {
+                Ok(addrs) => publish(&peers, addrs),
+                // Keep the last known set; a resolver hiccup isn't a departure.
+                Err(e) => warn!(
+                    "DNS lookup for '{}' failed: {}. Keeping known peers.",
+                    self.lookup_name, e
+                ),
+            }
+        }
+    }
+}
+
+fn publish(peers: &watch::Sender<BTreeSet<SocketAddr>>, addrs: BTreeSet<SocketAddr>) {
+    peers.send_if_modified(|known| {
+        let added: Vec<_> = addrs.difference(known).collect();
+        let removed: Vec<_> = known.difference(&addrs).collect();
+        if added.is_empty() && removed.is_empty() {
+            return false;
+        }
+        if !added.is_empty() {
+            info!("Discovered bootstrap peers: {:?}", added);
+        }
+        if !removed.is_empty() {
+            info!("Bootstrap peers gone from DNS: {:?}", removed);
+        }
+        *known = addrs;
+        true
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(list: &[&str]) -> BTreeSet<SocketAddr> {
+        list.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn publish_replaces_the_set_only_when_it_changes() {
+        let (tx, mut rx) = watch::channel(BTreeSet::new());
+        publish(&tx, addrs(&["10.0.0.2:4919", "10.0.0.3:4919"]));
+        assert!(rx.has_changed().unwrap());
+        rx.borrow_and_update();
+
+        publish(&tx, addrs(&["10.0.0.3:4919", "10.0.0.2:4919"]));
+        assert!(!rx.has_changed().unwrap());
+
+        publish(&tx, addrs(&["10.0.0.3:4919"]));
+        assert_eq!(*rx.borrow_and_update(), addrs(&["10.0.0.3:4919"]));
+    }
+
+    #[tokio::test]
+    async fn both_address_families_are_discovered() {
+        use hickory_proto::op::{Message, MessageType};
+        use hickory_proto::rr::rdata::{A, AAAA};
+        use hickory_proto::rr::{RData, Record, RecordType};
+        use hickory_resolver::config::{NameServerConfig, Protocol};
+        use tokio::net::UdpSocket;
+
+        // Answers A and AAAA queries for any name with one address each.
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            loop {
+                let (len, src) = socket.recv_from(&mut buf).await.unwrap();
+                let mut msg = Message::from_vec(&buf[..len]).unwrap();
+                let query = msg.queries()[0].clone();
+                let rdata = match query.query_type() {
+                    RecordType::A => RData::A(A::new(10, 0, 0, 2)),
+                    _ => RData::AAAA(AAAA::new(0xfd00, 0, 0, 0, 0, 0, 0, 2)),
+                };
+                msg.set_message_type(MessageType::Response)
+                    .add_answer(Record::from_rdata(query.name().clone(), 60, rdata));
+                socket.send_to(&msg.to_vec().unwrap(), src).await.unwrap();
+            }
+        });
+
+        let config = ResolverConfig::from_parts(
+            None,
+            vec![],
+            vec![NameServerConfig::new(server, Protocol::Udp)],
+        );
+        let discovery =
+            ServiceDiscovery::with_resolver(config, ResolverOpts::default(), "main", 4919);
+        assert_eq!(
+            discovery.lookup().await.unwrap(),
+            addrs(&["10.0.0.2:4919", "[fd00::2]:4919"])
+        );
+    }
+}
@@ -0,0 +1,65 @@
+//! CHAOS-class identification queries (`dig CH TXT version.bind`).
+//!
+//! Monitoring uses these to tell DNS servers apart.  They are answered
+//! from the config and never forwarded; with `dns_chaos = false` they are
+//! REFUSED, for operators who would rather not say what they run.
+
+use crate::config::Config;
+
+/// What glued says about itself in the CHAOS class.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChaosIdentity {
+    pub version: String,
+    pub hostname: String,
+}
+
+impl ChaosIdentity {
+    /// The identity to serve, or `None` when `dns_chaos` is off.
+    pub fn from_config(cfg: &Config) -> Option<Self> {
+        if !cfg.dns_chaos {
+            return None;
+        }
+        let version = cfg
+            .dns_chaos_version
+            .clone()
+            .unwrap_or_else(|| format!("glued {}", env!("CARGO_PKG_VERSION")));
+        let hostname = cfg
+            .dns_chaos_hostname
+            .clone()
+            .unwrap_or_else(|| cfg.node_name());
+        Some(Self { version, hostname })
+    }
+
+    /// The TXT string for `qname` (normalized), if it is one we answer.
+    pub fn answer(&self, qname: &str) -> Option<&str> {
+        match qname {
+            "version.bind" | "version.server" => Some(&self.version),
+            "hostname.bind" | "id.server" => Some(&self.hostname),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_and_configured_strings() {
+        let chaos = ChaosIdentity::from_config(&Config {
+            dns_chaos_hostname: Some("dns-a".into()),
+            ..Config::default()
+        })
+        .unwrap();
+        assert!(chaos.answer("version.bind").unwrap().starts_with("glued "));
+        assert_eq!(chaos.answer("hostname.bind"), Some("dns-a"));
+        assert_eq!(chaos.answer("id.server"), Some("dns-a"));
+        assert_eq!(chaos.answer("authors.bind"), None);
+
+        let off = Config {
+            dns_chaos: false,
+            ..Config::default()
+        };
+        assert_eq!(ChaosIdentity::from_config(&off), None);
+    }
+}
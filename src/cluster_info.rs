@@ -0,0 +1,118 @@
+//! Names that tell about the cluster itself.
+//!
+//! Each replica publishes an entry for itself, `glued-node-<node_name>`, at
+//! its address on the monitored network: `advertise_ip`, or else that of
+//! the container glued runs in.  It travels like a container's entry, so
+//! `dig glued-node-<name>` on any node shows whether that replica's updates
+//! reach it.  `node_entry = false` leaves it out.
+//!
+//! Two names are answered from this node's view of the cluster and never
+//! forwarded: `_glued.nodes` has the address of every node entry, and TXT
+//! strings `<name>=<ip>` for them; `TXT _glued.cluster` counts the entries,
+//! the node entries among them and the peers connected.  With
+//! `dns_cluster_info = false` they are looked up like any other name.
+
+use log::warn;
+
+use crate::config::Config;
+use crate::limits::check_name;
+use crate::names::NamePolicy;
+use crate::types::{Entry, StateMap};
+
+/// What every node entry's name starts with.
+pub const NODE_PREFIX: &str = "glued-node-";
+
+/// Answered with counts of what this node knows.
+pub const CLUSTER: &str = "_glued.cluster";
+
+/// Answered with the node entries.
+pub const NODES: &str = "_glued.nodes";
+
+/// The name this replica's node entry is published under, or `None` when
+/// `node_entry` is off or the node name makes no DNS label.
+pub fn node_entry_name(cfg: &Config) -> Option<String> {
+    if !cfg.node_entry {
+        return None;
+    }
+    let name = format!("{}{}", NODE_PREFIX, cfg.node_name());
+    // Host names are often qualified; their dots become hyphens too.
+    let replace = format!(".{}", cfg.name_replace_chars);
+    let published = NamePolicy::Sanitize
+        .apply(&name, &replace)
+        .filter(|published| check_name(published).is_ok());
+    if published.is_none() {
+        warn!(
+            "Not publishing a node entry: {} isn't a valid DNS label",
+            name
+        );
+    }
+    published
+}
+
+/// The node entries of `map`'s shared namespace, sorted by name.
+pub fn nodes(map: &StateMap) -> Vec<(&str, &Entry)> {
+    let mut nodes: Vec<(&str, &Entry)> = map
+        .iter()
+        .filter(|(name, _)| name.starts_with(NODE_PREFIX) && !name.contains('.'))
+        .map(|(name, entry)| (name.as_str(), entry))
+        .collect();
+    nodes.sort_by_key(|(name, _)| *name);
+    nodes
+}
+
+/// The TXT strings answering `_glued.cluster`; `peers` is how many peers
+/// are connected, when known.
+pub fn summary(map: &StateMap, peers: Option<usize>) -> Vec<String> {
+    let mut strings = vec![
+        format!("entries={}", map.len()),
+        format!("nodes={}", nodes(map).len()),
+    ];
+    strings.extend(peers.map(|peers| format!("peers={}", peers)));
+    strings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_entries_are_named_after_the_node() {
+        let named = |node_name: &str| Config {
+            node_name: Some(node_name.into()),
+            ..Config::default()
+        };
+        assert_eq!(
+            node_entry_name(&named("swarm-worker-3")).as_deref(),
+            Some("glued-node-swarm-worker-3")
+        );
+        assert_eq!(
+            node_entry_name(&named("Worker_3.example.com")).as_deref(),
+            Some("glued-node-worker-3-example-com")
+        );
+        assert_eq!(
+            node_entry_name(&named(&"x".repeat(80)))
+                .as_deref()
+                .map(str::len),
+            Some(63)
+        );
+        let off = Config {
+            node_entry: false,
+            ..named("worker-3")
+        };
+        assert_eq!(node_entry_name(&off), None);
+
+        let mut map = StateMap::new();
+        for name in [
+            "glued-node-b",
+            "web-1",
+            "glued-node-a",
+            "glued-node-c.net-a",
+        ] {
+            map.insert(name.into(), Entry::new("10.0.0.2".parse().unwrap()));
+        }
+        let listed: Vec<&str> = nodes(&map).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(listed, ["glued-node-a", "glued-node-b"]);
+        assert_eq!(summary(&map, Some(2)), ["entries=4", "nodes=2", "peers=2"]);
+        assert_eq!(summary(&map, None), ["entries=4", "nodes=2"]);
+    }
+}
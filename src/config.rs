@@ -2,35 +2,560 @@ use figment::{
     providers::{Env, Format, Json, Serialized, Toml},
     Figment,
 };
+use iroh::{NodeAddr, NodeId, RelayMap, RelayMode, RelayUrl};
+use log::warn;
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::acl::Cidr;
+use crate::conflicts::ConflictPolicy;
+use crate::forward::Upstream;
+use crate::names::NamePolicy;
+use crate::peers::node_ids;
+
+/// Where the control socket is, unless configured or an `instance_id` moves it.
+const DEFAULT_CONTROL_SOCKET: &str = "/run/glued/glued.sock";
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
+    /// `replica` watches `network_name` and `networks` and publishes their
+    /// containers, `dns` only serves what it learns over gossip, and `auto`
+    /// picks replica when either is set.
+    pub role: NodeRole,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub network_name: Option<String>,
+    /// Wait for `network_name` to be created instead of failing startup
+    /// when it doesn't exist, as when glued starts before the stack.
+    pub network_wait: bool,
+    /// Further networks, each with its own gossip topic and namespace:
+    /// `[[networks]] name = "net-a", topic = "..."`, optionally with the
+    /// `subnets` its clients query from.  See [`crate::networks`].
+    pub networks: Vec<NetworkConfig>,
+    /// How this node is called in entry metadata, TXT records and logs.
+    /// Defaults to the host name.  Only informational: peers are still
+    /// told apart by NodeId.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_name: Option<String>,
+    /// Tells apart glued processes sharing a host, as during a blue/green
+    /// upgrade: the default `control_socket` becomes
+    /// `/run/glued/glued-<instance_id>.sock`, and the default `node_name`
+    /// gets `-<instance_id>` after the host name.  Letters, digits, `-`
+    /// and `_`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance_id: Option<String>,
+    /// Control socket of the instance this one replaces.  Once warmed up,
+    /// this one asks it to hand over: to stop publishing changes and
+    /// report itself unready.  See `glued ctl handover`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub handover_from: Option<String>,
     pub topic_id: String,
-    pub bootstrap_peers: Vec<String>,
+    /// Peers to dial: a NodeId, optionally with direct addresses
+    /// (`nodeid@10.0.0.5:4919,10.0.0.6:4919`), or a table
+    /// `{ node_id = "...", addrs = ["10.0.0.5:4919"] }`.
+    pub bootstrap_peers: Vec<BootstrapPeer>,
+    /// NodeIds allowed to take part in gossip.  Empty allows any peer that
+    /// knows the cluster secret.
+    #[serde(with = "node_ids")]
+    pub allowed_peers: Vec<NodeId>,
+    /// NodeIds never accepted or dialled.
+    #[serde(with = "node_ids")]
+    pub denied_peers: Vec<NodeId>,
+    /// A replica with bootstrap peers isn't ready until it has a gossip neighbor.
+    pub ready_requires_neighbor: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     /// Optional swarm service name used for DNS-based bootstrapping (e.g. the main instance).
+    /// Its task addresses are re-resolved every `bootstrap_interval_secs`.
     pub bootstrap_service: Option<String>,
+    pub bootstrap_interval_secs: u64,
+    /// UDP port the gossip endpoint binds on IPv4 and IPv6, and the port
+    /// dialled on peers found through `bootstrap_service`.
+    pub gossip_port: u16,
+    /// How peers' addresses are found: `n0` (public DNS/pkarr), `local`
+    /// (mDNS on the LAN) or `none` (configured addresses only).
+    pub discovery: DiscoveryMode,
+    /// Relay servers: `default` (n0's), `disabled`, or `{ url = "https://..." }`.
+    pub relay: RelayConfig,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub bind_ip: Option<String>,
-    pub dns_bind: SocketAddr,
+    /// Where DNS is served over UDP and TCP: one address or a list.
+    #[serde(deserialize_with = "one_or_many")]
+    pub dns_bind: Vec<SocketAddr>,
+    /// Also listen on `[::]` wherever `dns_bind` listens on `0.0.0.0`.
+    pub dns_bind_v6: bool,
+    /// Serve on the `dns_bind` addresses that could be bound instead of
+    /// failing startup when one can't.
+    pub dns_bind_best_effort: bool,
+    /// Bind the DNS sockets with `SO_REUSEPORT`, so that another instance
+    /// doing the same can serve the same port during a handover.  Unix only.
+    pub dns_reuse_port: bool,
+    /// Ports tried in order, on the same address, when a `dns_bind` port is
+    /// taken (e.g. `[5353]` next to systemd-resolved).
+    pub dns_fallback_ports: Vec<u16>,
+    /// TCP connections served at once; further ones are closed on accept.
+    pub dns_tcp_max_connections: usize,
+    /// Queries answered on one TCP connection before it is closed.  Zero is unlimited.
+    pub dns_tcp_max_queries: usize,
+    /// Idle TCP connections are closed after this long.
+    pub dns_tcp_timeout_secs: u64,
+    /// Networks (`10.0.0.0/8`, `fd00::/8`) whose clients are answered.  Empty
+    /// allows any client.
+    pub dns_allow: Vec<Cidr>,
+    /// Clients refused even if allowed.
+    pub dns_deny: Vec<Cidr>,
+    /// Networks whose clients may have names forwarded upstream.  Empty
+    /// allows every client that `dns_allow` does.
+    pub forward_allow: Vec<Cidr>,
+    /// Networks whose clients (secondary servers) may transfer
+    /// `local_domain` with AXFR over TCP.  Empty allows none.
+    pub axfr_allow: Vec<Cidr>,
+    /// Serve DNS over TLS on this address, with the PEM certificate chain
+    /// and key below.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dns_tls_bind: Option<SocketAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dns_tls_cert: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dns_tls_key: Option<String>,
+    /// UDP responses per second to one client /24 (IPv6: /56) before
+    /// responses are dropped.  Zero disables rate limiting.
+    pub dns_rrl_rate: u32,
+    /// Responses a client network may get in a burst above `dns_rrl_rate`.
+    pub dns_rrl_burst: u32,
+    /// Fraction (0 to 1) of rate-limited responses sent truncated instead
+    /// of dropped, so real clients retry over TCP.
+    pub dns_rrl_slip: f64,
+    /// Client networks and the address scope each prefers, such as `host`:
+    /// `[[views]] subnets = ["192.168.1.0/24"], scope = "host"`.  The
+    /// narrowest network containing a client decides.  See
+    /// [`crate::views`].
+    pub views: Vec<View>,
+    /// Names whose subdomains all resolve to the name's address, as
+    /// `anything.<name>` does for containers labelled `glued.wildcard=true`.
+    /// Deeper names only match below the local zone or a network's suffix.
+    pub wildcard_names: Vec<String>,
+    /// Answer ANY queries with a single HINFO record as RFC 8482 allows,
+    /// instead of every record for the name.
+    pub dns_minimal_any: bool,
+    /// Answer TXT queries for cluster names with the publishing node and
+    /// update time.  Off by default, as it reveals the cluster's layout.
+    pub dns_txt_metadata: bool,
+    /// Answer CHAOS-class `version.bind`, `hostname.bind` and `id.server`
+    /// queries.  When false they are REFUSED.
+    pub dns_chaos: bool,
+    /// Defaults to `glued <version>`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dns_chaos_version: Option<String>,
+    /// Defaults to the node name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dns_chaos_hostname: Option<String>,
+    /// For up to this long after startup, until entries come in from a peer
+    /// (and, on replicas, the first container scan), names we don't have
+    /// are answered SERVFAIL rather than NXDOMAIN, which clients would
+    /// cache.  Zero answers NXDOMAIN from the start.
+    pub dns_warmup_timeout_secs: u64,
+    /// During the warm-up, how long a query for a name we don't have waits
+    /// for it to end before getting SERVFAIL.  Zero doesn't wait.
+    pub dns_warmup_delay_ms: u64,
+    /// Answer `TXT _glued.cluster` and `_glued.nodes` from this node's view
+    /// of the cluster.  When false they are looked up like any other name.
+    pub dns_cluster_info: bool,
+    /// Forward names that aren't ours upstream.  When false glued is
+    /// authoritative-only: other names are REFUSED and no resolver is set up.
+    pub forwarding: bool,
+    /// Zone glued is authoritative for: `web-1.<local_domain>` answers like
+    /// `web-1`, and the apex gets a synthesized SOA and NS record.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_domain: Option<String>,
+    /// SOA primary server and mailbox; default to `ns.` and `hostmaster.`
+    /// under `local_domain`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub soa_mname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub soa_rname: Option<String>,
+    /// SOA serial; defaults to the startup time in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub soa_serial: Option<u32>,
+    /// SOA minimum, which is how long resolvers cache NXDOMAIN and NODATA
+    /// answers for names in `local_domain`.
+    pub soa_minimum: u32,
+    /// Domains forwarded to their own upstreams instead of the system resolver:
+    /// `[forward_zones."corp.example"] upstreams = ["10.1.1.53"]`.
+    pub forward_zones: BTreeMap<String, ForwardZone>,
+    /// Forwarded lookups taking longer are abandoned and answered SERVFAIL.
+    pub dns_forward_timeout_ms: u64,
+    /// Forwarded lookups in flight at once.
+    pub dns_forward_max_inflight: usize,
+    /// Queries waiting for one of those to finish, within their timeout;
+    /// queries beyond it get SERVFAIL at once.
+    pub dns_forward_max_queued: usize,
+    /// Forwarded queries per second from one client /24 (IPv6: /56), of
+    /// every protocol; those beyond it are REFUSED.  Names of ours and
+    /// cached NXDOMAIN answers don't count.  Zero disables the limit.
+    pub dns_forward_rate: u32,
+    /// Forwarded queries a client network may send in a burst above
+    /// `dns_forward_rate`.
+    pub dns_forward_burst: u32,
+    /// Shortest TTL forwarded answers are served with, in seconds.
+    pub dns_forward_ttl_min: u32,
+    /// Longest TTL forwarded answers are served with, in seconds.  The
+    /// resolver's cache still keeps them for the TTL the upstream gave.
+    pub dns_forward_ttl_max: u32,
+    /// NXDOMAIN answers of upstreams kept, to answer again without a
+    /// lookup.  Zero keeps none.
+    pub dns_negative_cache_size: usize,
+    /// Longest an NXDOMAIN answer is kept, whatever its negative TTL.
+    pub dns_negative_cache_max_ttl_secs: u64,
+    /// Names for hosts that aren't containers, served alongside them and
+    /// never changed by gossip: `nas = "192.168.1.10"`, or
+    /// `router = { ips = ["192.168.1.1", "fd00::1"], txt = ["model=ax3000"] }`.
+    pub static_records: BTreeMap<String, StaticRecord>,
+    /// An `/etc/hosts`-style file whose names are served too.  Changes to
+    /// the file are picked up without a restart.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hosts_file: Option<String>,
+    /// A hosts file that registered names are written into, between marker
+    /// comments, for consumers that don't use glued for DNS.  Lines outside
+    /// the markers are kept.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hosts_export_path: Option<String>,
+    /// Rewrite `hosts_export_path` in place instead of replacing it, for a
+    /// file that is a bind mount, such as `/etc/hosts` in a container.
+    pub hosts_export_in_place: bool,
+    /// A JSON Lines file every registry change is appended to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audit_log: Option<String>,
+    /// Answer mDNS queries for `<name>.<mdns_suffix>` on the LAN.
+    pub mdns_advertise: bool,
+    pub mdns_suffix: String,
     pub cluster_secret: String,
+    /// Interval between full Docker rescans that repair drift in the announced state.
+    pub reconcile_interval_secs: u64,
+    /// How long a replica's entries stay on other nodes without being
+    /// renewed, which every rescan does; keep it well above
+    /// `reconcile_interval_secs`.  Zero publishes entries that never lapse.
+    pub entry_ttl_secs: u64,
+    /// Window in which events for the same container are coalesced.
+    pub debounce_ms: u64,
+    /// Withhold containers that start more than this many times within
+    /// `flap_window_secs` until they stay up.  Zero disables suppression.
+    pub flap_restart_limit: u32,
+    pub flap_window_secs: u64,
+    /// Only register containers with a healthcheck once they report healthy.
+    /// Can also be enabled per container with the `glued.require_healthy=true` label.
+    pub require_healthy: bool,
+    /// Probe every container's address, `tcp:<port>` or
+    /// `http:<port>[/path]`, withdrawing its names while it fails.  A
+    /// `glued.healthcheck` label sets a container's own, or `none`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<String>,
+    /// Time between probes of one container, plus up to `health_jitter_ms`.
+    pub health_interval_secs: u64,
+    pub health_jitter_ms: u64,
+    /// Probes taking longer fail.
+    pub health_timeout_ms: u64,
+    /// Failed probes in a row that withdraw a container's names.
+    pub health_fall: u32,
+    /// Passed probes in a row that publish them again.
+    pub health_rise: u32,
+    /// Docker event actions watched.  `destroy` and `rename` withdraw and
+    /// move names at once; any other action makes the container be looked
+    /// at again, so new ones can be added here as Docker grows them.
+    pub docker_events: Vec<String>,
+    /// Container inspects run at once while handling events.
+    pub inspect_max_inflight: usize,
+    /// Inspects taking longer are abandoned and retried.
+    pub inspect_timeout_secs: u64,
+    /// Withdraw this node's entries when Docker stays unreachable for
+    /// `clear_on_disconnect_secs`, instead of serving them until it returns.
+    pub clear_on_disconnect: bool,
+    pub clear_on_disconnect_secs: u64,
+    /// Container runtime a replica watches: `docker` or `containerd`.
+    pub runtime: RuntimeKind,
+    /// containerd's gRPC socket, with `runtime = "containerd"`.
+    pub containerd_socket: String,
+    /// containerd namespace to watch: `default` for nerdctl, `k8s.io` for
+    /// Kubernetes pods.
+    pub containerd_namespace: String,
+    /// Where CNI plugins cache their results, which is where containerd
+    /// containers' addresses on `network_name` are looked up.
+    pub cni_results_dir: String,
+    /// Which ports entries list for SRV and the admin API: the `container`
+    /// ports, which overlay peers connect to, or the `host`-published ones.
+    pub port_report: PortReport,
+    /// This host's address outside the overlay.  Containers with ports
+    /// published on every interface get it in the `host` scope; ports
+    /// published on one address give that address.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_address: Option<IpAddr>,
+    /// Publish `glued-node-<node_name>` for this replica.
+    pub node_entry: bool,
+    /// The address this replica's node entry gets.  Defaults to that of the
+    /// container glued runs in on `network_name`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub advertise_ip: Option<IpAddr>,
+    /// Docker daemon address (`unix://`, `tcp://` or `https://`).  Defaults to
+    /// `DOCKER_HOST`, then the local socket.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub docker_host: Option<String>,
+    /// TLS material for a TCP daemon; defaults to `DOCKER_CERT_PATH`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub docker_ca: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub docker_cert: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub docker_key: Option<String>,
+    /// Skip the container glued itself runs in.
+    pub exclude_self: bool,
+    /// Our own container ID, for when it can't be detected (e.g. a
+    /// container with a custom hostname on Windows).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub self_container_id: Option<String>,
+    /// Glob patterns of container names never to register.
+    pub exclude_names: Vec<String>,
+    /// Label globs (`key` or `key=value`) marking containers never to register.
+    pub exclude_labels: Vec<String>,
+    /// How container names are turned into DNS labels: `sanitize`, `strict` or `as-is`.
+    pub name_policy: NamePolicy,
+    /// Characters replaced with `-` by the `sanitize` policy.
+    pub name_replace_chars: String,
+    /// Serve the admin HTTP API (`GET /v1/entries`) on this address.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_bind: Option<SocketAddr>,
+    /// Bearer token the admin API requires, all but `/healthz`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_token: Option<String>,
+    /// Bearer token `/metrics` requires, besides the admin token; unset
+    /// leaves metrics open.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics_token: Option<String>,
+    /// URLs POSTed a JSON event for every change to the registry.
+    pub webhooks: Vec<String>,
+    /// HMAC key for webhook signatures; derived from `cluster_secret`
+    /// when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook_secret: Option<String>,
+    /// Undelivered events kept per webhook before the oldest are dropped.
+    pub webhook_queue_capacity: usize,
+    /// Retries of a failed delivery, with exponential backoff.
+    pub webhook_max_retries: u32,
+    /// Unix socket for `glued ctl`; empty disables it.
+    pub control_socket: String,
+    /// Permissions of the control socket, in octal.
+    pub control_socket_mode: FileMode,
+    /// Log filter, e.g. `info` or `info,glued::gossip=debug`.  `RUST_LOG`
+    /// takes precedence when set.
+    pub log_level: String,
+    /// `text` for people, `json` for log shippers.
+    pub log_format: LogFormat,
+    /// Directory for persistent state such as the state map snapshot.
+    pub data_dir: String,
+    /// Save the state map to `data_dir` and restore it on startup.
+    pub persist_state: bool,
+    pub snapshot_interval_secs: u64,
+    /// Maximum number of incoming auth handshakes processed at once; further
+    /// connections are refused until one completes.
+    pub auth_max_inflight: usize,
+    /// Failed handshakes from one NodeId before it is locked out.  Each
+    /// further failure doubles the lockout, up to `auth_lockout_max_secs`.
+    /// Zero disables lockouts.
+    pub auth_lockout_threshold: u32,
+    pub auth_lockout_base_secs: u64,
+    pub auth_lockout_max_secs: u64,
+    /// Deadline for connecting to a peer and for completing the auth handshake.
+    pub auth_timeout_secs: u64,
+    /// Gossip messages stamped more than this from our clock, either way,
+    /// are dropped, which bounds how long a captured message can be
+    /// replayed.  Must exceed the clock skew across the cluster; zero
+    /// disables the check.  Unless zero, messages not stamped at all, from
+    /// nodes that predate stamping, are dropped too.
+    pub gossip_max_skew_secs: u64,
+    /// Tuning of the gossip protocol, `[gossip] active_view_capacity = 8`;
+    /// see [`GossipConfig`].
+    pub gossip: GossipConfig,
+    /// Most entries in the state map; new names beyond it are dropped.
+    /// Zero means no limit.
+    pub max_entries: usize,
+    /// Most entries one peer may publish; zero means no limit.
+    pub max_entries_per_node: usize,
+    /// Which entry is served for a name several nodes publish:
+    /// `lowest-node`, `first-registration`, `last-write`, `prefer-local`
+    /// or `merge`; see [`crate::conflicts`].
+    pub conflict_policy: ConflictPolicy,
+    /// How long updates from a node evicted with `glued ctl evict` are
+    /// ignored, so its last gossip doesn't bring its entries back; see
+    /// [`crate::evict`].
+    pub eviction_grace_secs: u64,
+    /// Capacity of the internal update channels.  When full, the container
+    /// monitor waits rather than dropping updates.  Also the number of
+    /// registry changes kept for a subscriber that is behind.
+    pub update_channel_capacity: usize,
+    /// Most updates coalesced into one gossip message and state map write.
+    pub batch_max_updates: usize,
+    /// How long to wait for more updates after the first of a burst.
+    /// `[gossip] batch_window_ms` wins when set.
+    pub batch_window_ms: u64,
+    /// Times DNS, gossip or the runtime monitor is restarted after it
+    /// stops before the daemon exits with an error.  A start that runs for
+    /// [`crate::supervise::HEALTHY_RUN`] counts from zero again.
+    pub subsystem_restarts: u32,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            role: NodeRole::Auto,
             network_name: None,
+            network_wait: false,
+            networks: Vec::new(),
+            node_name: None,
+            instance_id: None,
+            handover_from: None,
             // Default topic: 32 bytes of 0x42 encoded as hex
             topic_id: "4242424242424242424242424242424242424242424242424242424242424242".into(),
             bootstrap_peers: Vec::new(),
+            allowed_peers: Vec::new(),
+            denied_peers: Vec::new(),
+            ready_requires_neighbor: true,
             bootstrap_service: Some("main".into()),
+            bootstrap_interval_secs: 60,
+            gossip_port: 4919,
+            discovery: DiscoveryMode::N0,
+            relay: RelayConfig::Mode(RelayModeName::Default),
             bind_ip: None,
-            dns_bind: "0.0.0.0:53".parse().unwrap(),
+            dns_bind: vec!["0.0.0.0:53".parse().unwrap()],
+            dns_bind_v6: false,
+            dns_bind_best_effort: false,
+            dns_reuse_port: false,
+            dns_fallback_ports: Vec::new(),
+            dns_tcp_max_connections: 64,
+            dns_tcp_max_queries: 100,
+            dns_tcp_timeout_secs: 10,
+            dns_allow: Vec::new(),
+            dns_deny: Vec::new(),
+            forward_allow: Vec::new(),
+            axfr_allow: Vec::new(),
+            dns_tls_bind: None,
+            dns_tls_cert: None,
+            dns_tls_key: None,
+            dns_rrl_rate: 0,
+            dns_rrl_burst: 20,
+            dns_rrl_slip: 0.5,
+            views: Vec::new(),
+            wildcard_names: Vec::new(),
+            dns_minimal_any: false,
+            dns_txt_metadata: false,
+            dns_chaos: true,
+            dns_chaos_version: None,
+            dns_chaos_hostname: None,
+            dns_warmup_timeout_secs: 10,
+            dns_warmup_delay_ms: 0,
+            dns_cluster_info: true,
+            forwarding: true,
+            local_domain: None,
+            soa_mname: None,
+            soa_rname: None,
+            soa_serial: None,
+            soa_minimum: 30,
+            forward_zones: BTreeMap::new(),
+            dns_forward_timeout_ms: 2000,
+            dns_forward_max_inflight: 256,
+            dns_forward_max_queued: 256,
+            dns_forward_rate: 0,
+            dns_forward_burst: 50,
+            dns_forward_ttl_min: 0,
+            dns_forward_ttl_max: 86400,
+            dns_negative_cache_size: 4096,
+            dns_negative_cache_max_ttl_secs: 300,
+            static_records: BTreeMap::new(),
+            hosts_file: None,
+            hosts_export_path: None,
+            hosts_export_in_place: false,
+            audit_log: None,
+            mdns_advertise: false,
+            mdns_suffix: "local".into(),
             cluster_secret: "default_insecure_secret".into(),
+            reconcile_interval_secs: 300,
+            entry_ttl_secs: 600,
+            debounce_ms: 2000,
+            flap_restart_limit: 0,
+            flap_window_secs: 60,
+            require_healthy: false,
+            health_check: None,
+            health_interval_secs: 10,
+            health_jitter_ms: 1000,
+            health_timeout_ms: 2000,
+            health_fall: 3,
+            health_rise: 2,
+            docker_events: [
+                "start",
+                "die",
+                "kill",
+                "stop",
+                "destroy",
+                "rename",
+                "oom",
+                "health_status",
+                "connect",
+                "disconnect",
+            ]
+            .map(String::from)
+            .to_vec(),
+            inspect_max_inflight: 4,
+            inspect_timeout_secs: 10,
+            clear_on_disconnect: false,
+            clear_on_disconnect_secs: 60,
+            runtime: RuntimeKind::Docker,
+            containerd_socket: "/run/containerd/containerd.sock".into(),
+            containerd_namespace: "default".into(),
+            cni_results_dir: "/var/lib/cni/results".into(),
+            port_report: PortReport::Container,
+            host_address: None,
+            node_entry: true,
+            advertise_ip: None,
+            docker_host: None,
+            docker_ca: None,
+            docker_cert: None,
+            docker_key: None,
+            exclude_self: true,
+            self_container_id: None,
+            exclude_names: Vec::new(),
+            exclude_labels: Vec::new(),
+            name_policy: NamePolicy::Sanitize,
+            name_replace_chars: "_.".into(),
+            admin_bind: None,
+            admin_token: None,
+            metrics_token: None,
+            webhooks: Vec::new(),
+            webhook_secret: None,
+            webhook_queue_capacity: 1024,
+            webhook_max_retries: 5,
+            control_socket: DEFAULT_CONTROL_SOCKET.into(),
+            control_socket_mode: FileMode(0o660),
+            log_level: "info".into(),
+            log_format: LogFormat::Text,
+            data_dir: "/var/lib/glued".into(),
+            persist_state: true,
+            snapshot_interval_secs: 30,
+            auth_max_inflight: 16,
+            auth_lockout_threshold: 5,
+            auth_lockout_base_secs: 10,
+            auth_lockout_max_secs: 900,
+            auth_timeout_secs: 10,
+            gossip_max_skew_secs: 300,
+            gossip: GossipConfig::default(),
+            max_entries: 10_000,
+            max_entries_per_node: 2_000,
+            conflict_policy: ConflictPolicy::LowestNode,
+            eviction_grace_secs: 600,
+            update_channel_capacity: 128,
+            batch_max_updates: 100,
+            batch_window_ms: 200,
+            subsystem_restarts: 3,
         }
     }
 }
@@ -48,15 +573,895 @@ impl Config {
         if let Ok(secret_file) = std::env::var("GLUED_CLUSTER_SECRET_FILE") {
             config.cluster_secret = std::fs::read_to_string(secret_file)?.trim().to_string();
         }
+        for (var, token) in [
+            ("GLUED_ADMIN_TOKEN_FILE", &mut config.admin_token),
+            ("GLUED_METRICS_TOKEN_FILE", &mut config.metrics_token),
+        ] {
+            if let Ok(token_file) = std::env::var(var) {
+                *token = Some(std::fs::read_to_string(token_file)?.trim().to_string());
+            }
+        }
+        if [&config.admin_token, &config.metrics_token]
+            .iter()
+            .any(|token| token.as_deref() == Some(""))
+        {
+            anyhow::bail!("admin_token and metrics_token can't be empty");
+        }
 
         // If bind_ip is set, override the IP part of dns_bind
         if let Some(ref ip) = config.bind_ip {
-            let port = config.dns_bind.port();
-            config.dns_bind = format!("{}:{}", ip, port)
+            let ip: IpAddr = ip
                 .parse()
                 .map_err(|e| anyhow::anyhow!("Invalid bind_ip: {}", e))?;
+            for addr in &mut config.dns_bind {
+                addr.set_ip(ip);
+            }
+        }
+
+        if let Some(id) = &config.instance_id {
+            if id.is_empty()
+                || !id
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+            {
+                anyhow::bail!(
+                    "instance_id '{}' may only hold letters, digits, '-' and '_'",
+                    id
+                );
+            }
+            if config.control_socket == DEFAULT_CONTROL_SOCKET {
+                config.control_socket = format!("/run/glued/glued-{}.sock", id);
+            }
+        }
+        if config.handover_from.as_deref() == Some(config.control_socket.as_str()) {
+            anyhow::bail!("handover_from can't be this instance's own control_socket");
         }
 
+        if config.hosts_export_path.is_some() && config.hosts_export_path == config.hosts_file {
+            anyhow::bail!("hosts_export_path can't be the hosts_file it would be read back from");
+        }
+        config.gossip.to_proto()?;
+
         Ok(config)
     }
+
+    /// `node_name`, or the host's name, with the `instance_id` if there
+    /// is one, when it isn't set.
+    pub fn node_name(&self) -> String {
+        self.node_name
+            .clone()
+            .filter(|name| !name.trim().is_empty())
+            .unwrap_or_else(|| match &self.instance_id {
+                Some(id) => format!("{}-{}", local_hostname(), id),
+                None => local_hostname(),
+            })
+    }
+
+    /// The lease a replica puts on its entries, in seconds, unless
+    /// `entry_ttl_secs` turns leases off.
+    pub fn entry_lease(&self) -> Option<u64> {
+        if self.entry_ttl_secs == 0 {
+            return None;
+        }
+        if self.entry_ttl_secs <= self.reconcile_interval_secs {
+            warn!(
+                "entry_ttl_secs ({}) is not above reconcile_interval_secs ({}); entries will lapse between renewals",
+                self.entry_ttl_secs, self.reconcile_interval_secs
+            );
+        }
+        Some(self.entry_ttl_secs)
+    }
+
+    /// The addresses to serve DNS on: `dns_bind`, plus `[::]` next to each
+    /// `0.0.0.0` with `dns_bind_v6`.
+    pub fn dns_bind_addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs = Vec::with_capacity(self.dns_bind.len());
+        for addr in &self.dns_bind {
+            if !addrs.contains(addr) {
+                addrs.push(*addr);
+            }
+            if self.dns_bind_v6 && addr.ip() == IpAddr::V4(Ipv4Addr::UNSPECIFIED) {
+                let v6 = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), addr.port());
+                if !addrs.contains(&v6) {
+                    addrs.push(v6);
+                }
+            }
+        }
+        addrs
+    }
+
+    /// Resolves `role` against `network_name` and `networks`.
+    pub fn resolve_role(&self) -> anyhow::Result<Role> {
+        let watched: Vec<Watched> = self
+            .network_name
+            .iter()
+            .map(|network| Watched {
+                network: network.clone(),
+                namespaced: false,
+            })
+            .chain(self.networks.iter().map(|network| Watched {
+                network: network.name.clone(),
+                namespaced: true,
+            }))
+            .collect();
+        match self.role {
+            NodeRole::Dns => {
+                if let Some(network) = &self.network_name {
+                    warn!("role = \"dns\": ignoring network_name '{}'", network);
+                }
+                Ok(Role::Dns)
+            }
+            NodeRole::Replica if watched.is_empty() => {
+                anyhow::bail!("role = \"replica\" requires network_name or networks to be set")
+            }
+            _ if watched.is_empty() => Ok(Role::Dns),
+            _ => Ok(Role::Replica(watched)),
+        }
+    }
+}
+
+/// A node's resolved role.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Role {
+    /// Serves DNS from gossip and never publishes.
+    Dns,
+    /// Watches these networks and publishes their containers.
+    Replica(Vec<Watched>),
+}
+
+/// A network a replica watches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watched {
+    pub network: String,
+    /// Its containers go into the network's namespace, as for `networks`,
+    /// instead of the shared one, as for `network_name`.
+    pub namespaced: bool,
+}
+
+/// Client networks preferring one address scope.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct View {
+    pub subnets: Vec<Cidr>,
+    pub scope: String,
+}
+
+/// A network with its own gossip topic and namespace.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct NetworkConfig {
+    /// The container network, which must be a DNS label: its names are
+    /// served as `<name>.<network>`.
+    pub name: String,
+    /// 32-byte hex gossip topic, the same on every node.
+    pub topic: String,
+    /// Clients here are answered from the network's namespace for plain
+    /// names.
+    #[serde(default)]
+    pub subnets: Vec<Cidr>,
+}
+
+/// Tuning of the gossip protocol.  Fields left out keep their defaults:
+/// iroh-gossip's for the protocol, ours for the rest.  Larger clusters
+/// want more neighbors; small ones, quicker membership timeouts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GossipConfig {
+    /// Largest gossip message in bytes.  A sync answer carries all of a
+    /// node's entries, so it has to fit them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_message_size: Option<usize>,
+    /// Neighbors a node broadcasts through.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub active_view_capacity: Option<usize>,
+    /// Peers kept in reserve to replace lost neighbors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub passive_view_capacity: Option<usize>,
+    /// How often the reserve is refreshed from a random peer's.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shuffle_interval_secs: Option<u64>,
+    /// How long a peer asked to become a neighbor has to answer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub neighbor_request_timeout_ms: Option<u64>,
+    /// How long a message announced but not received is waited for
+    /// before asking the announcing peer for it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub graft_timeout_1_ms: Option<u64>,
+    /// How long after that the next announcing peer is asked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub graft_timeout_2_ms: Option<u64>,
+    /// How long announcements of received messages are collected before
+    /// they go out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dispatch_timeout_ms: Option<u64>,
+    /// How long received messages are kept for peers that ask for them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_cache_retention_secs: Option<u64>,
+    /// How long to wait for more updates after the first of a burst;
+    /// defaults to `batch_window_ms`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub batch_window_ms: Option<u64>,
+    /// How often sessions with bootstrap peers are checked and the peers
+    /// without one dialled.  Defaults to 10 seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heartbeat_secs: Option<u64>,
+    /// Largest sealed payload opened; larger ones are dropped unread, and
+    /// our own batches are split to fit.  Defaults to 64 KiB.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_payload_bytes: Option<usize>,
+    /// Malformed messages in a row after which the peer delivering them is
+    /// ignored, for `auth_lockout_base_secs` doubling up to
+    /// `auth_lockout_max_secs`.  Defaults to 5; zero never ignores a peer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub malformed_threshold: Option<u32>,
+    /// How often this node's state digest is broadcast for peers to
+    /// compare with theirs.  Defaults to 30 seconds; zero sends none, as
+    /// nodes that predate digests need until they are upgraded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest_interval_secs: Option<u64>,
+    /// Digests in a row from one peer differing from ours after which a
+    /// sync is asked for.  Defaults to 3.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest_mismatches: Option<u32>,
+}
+
+impl GossipConfig {
+    /// The iroh-gossip protocol config.  Fails on the first field out of
+    /// range, ours included.
+    pub fn to_proto(&self) -> anyhow::Result<iroh_gossip::proto::Config> {
+        let mut proto = iroh_gossip::proto::Config::default();
+        let (membership, broadcast) = (&mut proto.membership, &mut proto.broadcast);
+        if let Some(size) = within("max_message_size", self.max_message_size, 1024..=1 << 24)? {
+            proto.max_message_size = size;
+        }
+        if let Some(n) = within("active_view_capacity", self.active_view_capacity, 1..=64)? {
+            membership.active_view_capacity = n;
+        }
+        if let Some(n) = within(
+            "passive_view_capacity",
+            self.passive_view_capacity,
+            1..=1024,
+        )? {
+            membership.passive_view_capacity = n;
+        }
+        if let Some(s) = within(
+            "shuffle_interval_secs",
+            self.shuffle_interval_secs,
+            1..=3600,
+        )? {
+            membership.shuffle_interval = Duration::from_secs(s);
+        }
+        let timeout = self.neighbor_request_timeout_ms;
+        if let Some(ms) = within("neighbor_request_timeout_ms", timeout, 10..=60_000)? {
+            membership.neighbor_request_timeout = Duration::from_millis(ms);
+        }
+        if let Some(ms) = within("graft_timeout_1_ms", self.graft_timeout_1_ms, 10..=60_000)? {
+            broadcast.graft_timeout_1 = Duration::from_millis(ms);
+        }
+        if let Some(ms) = within("graft_timeout_2_ms", self.graft_timeout_2_ms, 10..=60_000)? {
+            broadcast.graft_timeout_2 = Duration::from_millis(ms);
+        }
+        if let Some(ms) = within("dispatch_timeout_ms", self.dispatch_timeout_ms, 1..=10_000)? {
+            broadcast.dispatch_timeout = Duration::from_millis(ms);
+        }
+        let retention = self.message_cache_retention_secs;
+        if let Some(s) = within("message_cache_retention_secs", retention, 1..=3600)? {
+            broadcast.message_cache_retention = Duration::from_secs(s);
+        }
+        within("batch_window_ms", self.batch_window_ms, 0..=10_000)?;
+        within("heartbeat_secs", self.heartbeat_secs, 1..=3600)?;
+        within("max_payload_bytes", self.max_payload_bytes, 1024..=1 << 24)?;
+        within("malformed_threshold", self.malformed_threshold, 0..=1000)?;
+        within("digest_interval_secs", self.digest_interval_secs, 0..=3600)?;
+        within("digest_mismatches", self.digest_mismatches, 1..=100)?;
+        Ok(proto)
+    }
+
+    /// How often sessions with bootstrap peers are looked after.
+    pub fn heartbeat(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_secs.unwrap_or(10))
+    }
+
+    pub fn max_payload_bytes(&self) -> usize {
+        self.max_payload_bytes.unwrap_or(64 * 1024)
+    }
+
+    pub fn malformed_threshold(&self) -> u32 {
+        self.malformed_threshold.unwrap_or(5)
+    }
+
+    /// How often the state digest is broadcast, if at all.
+    pub fn digest_interval(&self) -> Option<Duration> {
+        match self.digest_interval_secs.unwrap_or(30) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    pub fn digest_mismatches(&self) -> u32 {
+        self.digest_mismatches.unwrap_or(3)
+    }
+}
+
+/// `value`, unless it is set and outside `range`.
+fn within<T>(field: &str, value: Option<T>, range: RangeInclusive<T>) -> anyhow::Result<Option<T>>
+where
+    T: PartialOrd + fmt::Display,
+{
+    match value {
+        Some(value) if !range.contains(&value) => anyhow::bail!(
+            "gossip.{} must be between {} and {}, not {}",
+            field,
+            range.start(),
+            range.end(),
+            value
+        ),
+        value => Ok(value),
+    }
+}
+
+/// The host's name, as far as the environment or `/etc/hostname` tell.
+fn local_hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "glued".into())
+}
+
+/// Accepts a single value where a list is expected.
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+/// Upstreams for one conditionally forwarded zone.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ForwardZone {
+    /// Tried in order: `ip` or `ip:port` (default 53), or DNS over TLS as
+    /// `tls://ip[:port]#server-name` (default 853).
+    pub upstreams: Vec<Upstream>,
+    /// Use the default resolver when every upstream is unreachable, instead
+    /// of answering SERVFAIL.
+    #[serde(default)]
+    pub fallthrough: bool,
+}
+
+/// Addresses and TXT strings configured for one name.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "RawStaticRecord")]
+pub struct StaticRecord {
+    /// At least one address.
+    pub ips: Vec<IpAddr>,
+    pub txt: Vec<String>,
+}
+
+/// The accepted spellings of a static record: an address, a list of
+/// addresses, or a table.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawStaticRecord {
+    One(String),
+    Many(Vec<String>),
+    Table {
+        ips: Vec<String>,
+        #[serde(default)]
+        txt: Vec<String>,
+    },
+}
+
+impl TryFrom<RawStaticRecord> for StaticRecord {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawStaticRecord) -> anyhow::Result<Self> {
+        let (ips, txt) = match raw {
+            RawStaticRecord::One(ip) => (vec![ip], Vec::new()),
+            RawStaticRecord::Many(ips) => (ips, Vec::new()),
+            RawStaticRecord::Table { ips, txt } => (ips, txt),
+        };
+        if ips.is_empty() {
+            anyhow::bail!("A static record needs at least one address");
+        }
+        let ips = ips
+            .iter()
+            .map(|ip| {
+                ip.trim()
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("Invalid address '{}': {}", ip, e))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { ips, txt })
+    }
+}
+
+/// What a node does in the cluster.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeRole {
+    Auto,
+    Replica,
+    Dns,
+}
+
+/// Container runtime watched by a replica.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuntimeKind {
+    Docker,
+    Containerd,
+}
+
+/// Which side of a published port is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PortReport {
+    Container,
+    Host,
+}
+
+/// Unix permission bits, written in octal: `"0660"`, or `660` where a
+/// number is expected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "RawFileMode", into = "String")]
+pub struct FileMode(pub u32);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawFileMode {
+    Digits(u64),
+    Text(String),
+}
+
+impl TryFrom<RawFileMode> for FileMode {
+    type Error = String;
+
+    fn try_from(raw: RawFileMode) -> Result<Self, Self::Error> {
+        let text = match raw {
+            RawFileMode::Digits(digits) => digits.to_string(),
+            RawFileMode::Text(text) => text,
+        };
+        let digits = text.trim_start_matches("0o");
+        u32::from_str_radix(digits, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .map(FileMode)
+            .ok_or_else(|| format!("'{}' is not an octal file mode", text))
+    }
+}
+
+impl From<FileMode> for String {
+    fn from(mode: FileMode) -> Self {
+        format!("{:04o}", mode.0)
+    }
+}
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Address discovery used by the gossip endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoveryMode {
+    N0,
+    Local,
+    None,
+}
+
+/// Relay servers used by the gossip endpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum RelayConfig {
+    Mode(RelayModeName),
+    Custom { url: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayModeName {
+    Default,
+    Disabled,
+}
+
+impl RelayConfig {
+    pub fn to_relay_mode(&self) -> anyhow::Result<RelayMode> {
+        Ok(match self {
+            RelayConfig::Mode(RelayModeName::Default) => RelayMode::Default,
+            RelayConfig::Mode(RelayModeName::Disabled) => RelayMode::Disabled,
+            RelayConfig::Custom { url } => {
+                let url: RelayUrl = url
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("Invalid relay url '{}': {}", url, e))?;
+                RelayMode::Custom(RelayMap::from_url(url))
+            }
+        })
+    }
+}
+
+impl fmt::Display for RelayConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RelayConfig::Mode(RelayModeName::Default) => write!(f, "default"),
+            RelayConfig::Mode(RelayModeName::Disabled) => write!(f, "disabled"),
+            RelayConfig::Custom { url } => write!(f, "{}", url),
+        }
+    }
+}
+
+/// A configured bootstrap peer: its NodeId and any known direct addresses.
+///
+/// Direct addresses let peers connect without n0 discovery, e.g. in
+/// air-gapped or relay-less deployments.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "RawBootstrapPeer", into = "String")]
+pub struct BootstrapPeer {
+    pub node_id: NodeId,
+    pub addrs: Vec<SocketAddr>,
+}
+
+impl BootstrapPeer {
+    pub fn to_node_addr(&self) -> NodeAddr {
+        NodeAddr::new(self.node_id).with_direct_addresses(self.addrs.iter().copied())
+    }
+}
+
+impl FromStr for BootstrapPeer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (id, addrs) = match s.split_once('@') {
+            Some((id, addrs)) => (id, Some(addrs)),
+            None => (s, None),
+        };
+        let node_id = id
+            .trim()
+            .parse::<NodeId>()
+            .map_err(|e| anyhow::anyhow!("Invalid bootstrap peer ID '{}': {}", id, e))?;
+        let addrs = match addrs {
+            Some(addrs) => addrs
+                .split(',')
+                .map(|addr| {
+                    addr.trim().parse::<SocketAddr>().map_err(|e| {
+                        anyhow::anyhow!("Invalid address '{}' for peer {}: {}", addr, id, e)
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?,
+            None => Vec::new(),
+        };
+        Ok(Self { node_id, addrs })
+    }
+}
+
+impl fmt::Display for BootstrapPeer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.node_id)?;
+        for (i, addr) in self.addrs.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { '@' } else { ',' }, addr)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<BootstrapPeer> for String {
+    fn from(peer: BootstrapPeer) -> Self {
+        peer.to_string()
+    }
+}
+
+/// The accepted spellings of a bootstrap peer in config files.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawBootstrapPeer {
+    Short(String),
+    Table {
+        node_id: String,
+        #[serde(default)]
+        addrs: Vec<String>,
+    },
+}
+
+impl TryFrom<RawBootstrapPeer> for BootstrapPeer {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawBootstrapPeer) -> anyhow::Result<Self> {
+        match raw {
+            RawBootstrapPeer::Short(s) => s.parse(),
+            RawBootstrapPeer::Table { node_id, addrs } if addrs.is_empty() => node_id.parse(),
+            RawBootstrapPeer::Table { node_id, addrs } => {
+                format!("{}@{}", node_id, addrs.join(",")).parse()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 8032 test vector public key, a valid ed25519 point.
+    const NODE_ID: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+
+    #[test]
+    fn bootstrap_peer_short_syntax() {
+        let plain: BootstrapPeer = NODE_ID.parse().unwrap();
+        assert!(plain.addrs.is_empty());
+
+        let full: BootstrapPeer = format!("{}@10.0.0.5:4433, 10.0.0.5:4434", NODE_ID)
+            .parse()
+            .unwrap();
+        assert_eq!(full.node_id, plain.node_id);
+        assert_eq!(
+            full.addrs,
+            [
+                "10.0.0.5:4433".parse().unwrap(),
+                "10.0.0.5:4434".parse().unwrap()
+            ]
+        );
+
+        assert!(format!("{}@", NODE_ID).parse::<BootstrapPeer>().is_err());
+        assert!(format!("{}@10.0.0.5", NODE_ID)
+            .parse::<BootstrapPeer>()
+            .is_err());
+    }
+
+    #[test]
+    fn bootstrap_peers_from_toml() {
+        let toml = format!(
+            r#"bootstrap_peers = ["{id}", {{ node_id = "{id}", addrs = ["[fd00::5]:4919"] }}]"#,
+            id = NODE_ID
+        );
+        let cfg: Config = Figment::from(Serialized::defaults(Config::default()))
+            .merge(Toml::string(&toml))
+            .extract()
+            .unwrap();
+        assert_eq!(cfg.bootstrap_peers.len(), 2);
+        assert_eq!(
+            cfg.bootstrap_peers[1].addrs,
+            ["[fd00::5]:4919".parse::<SocketAddr>().unwrap()]
+        );
+
+        let bad = r#"bootstrap_peers = ["not-a-node@10.0.0.5:4919"]"#;
+        assert!(Figment::from(Serialized::defaults(Config::default()))
+            .merge(Toml::string(bad))
+            .extract::<Config>()
+            .is_err());
+    }
+
+    #[test]
+    fn dns_bind_takes_one_address_or_many() {
+        let extract = |toml: &str| -> Config {
+            Figment::from(Serialized::defaults(Config::default()))
+                .merge(Toml::string(toml))
+                .extract()
+                .unwrap()
+        };
+        let one = extract(r#"dns_bind = "10.0.0.2:53""#);
+        assert_eq!(one.dns_bind, ["10.0.0.2:53".parse().unwrap()]);
+
+        let mut many = extract(r#"dns_bind = ["0.0.0.0:53", "127.0.0.1:5353"]"#);
+        assert_eq!(many.dns_bind_addrs().len(), 2);
+        many.dns_bind_v6 = true;
+        let addrs: Vec<SocketAddr> = ["0.0.0.0:53", "[::]:53", "127.0.0.1:5353"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        assert_eq!(many.dns_bind_addrs(), addrs);
+    }
+
+    #[test]
+    fn control_socket_mode_is_octal() {
+        let extract = |toml: &str| {
+            Figment::from(Serialized::defaults(Config::default()))
+                .merge(Toml::string(toml))
+                .extract::<Config>()
+                .ok()
+                .map(|cfg| cfg.control_socket_mode)
+        };
+        assert_eq!(extract("").unwrap(), FileMode(0o660));
+        assert_eq!(
+            extract(r#"control_socket_mode = "0600""#).unwrap(),
+            FileMode(0o600)
+        );
+        assert_eq!(
+            extract("control_socket_mode = 640").unwrap(),
+            FileMode(0o640)
+        );
+        assert!(extract(r#"control_socket_mode = "0689""#).is_none());
+    }
+
+    #[test]
+    fn gossip_tuning_from_toml() {
+        let extract = |toml: &str| -> Config {
+            Figment::from(Serialized::defaults(Config::default()))
+                .merge(Toml::string(toml))
+                .extract()
+                .unwrap()
+        };
+        let cfg = extract(
+            r#"
+            [gossip]
+            active_view_capacity = 8
+            heartbeat_secs = 2
+            fanout = 3
+        "#,
+        );
+        let proto = cfg.gossip.to_proto().unwrap();
+        let defaults = iroh_gossip::proto::Config::default();
+        assert_eq!(proto.membership.active_view_capacity, 8);
+        assert_eq!(
+            proto.membership.passive_view_capacity,
+            defaults.membership.passive_view_capacity
+        );
+        assert_eq!(proto.max_message_size, defaults.max_message_size);
+        assert_eq!(cfg.gossip.heartbeat(), Duration::from_secs(2));
+        assert_eq!(
+            Config::default().gossip.heartbeat(),
+            Duration::from_secs(10)
+        );
+
+        let cfg = extract("gossip = { active_view_capacity = 0 }");
+        let e = cfg.gossip.to_proto().unwrap_err().to_string();
+        assert!(e.contains("gossip.active_view_capacity"), "{}", e);
+        let cfg = extract("gossip = { heartbeat_secs = 0 }");
+        assert!(cfg.gossip.to_proto().is_err());
+
+        assert_eq!(
+            Config::default().gossip.digest_interval(),
+            Some(Duration::from_secs(30))
+        );
+        let cfg = extract("gossip = { digest_interval_secs = 0 }");
+        assert_eq!(cfg.gossip.digest_interval(), None);
+        let cfg = extract("gossip = { digest_mismatches = 0 }");
+        assert!(cfg.gossip.to_proto().is_err());
+    }
+
+    #[test]
+    fn discovery_and_relay_from_toml() {
+        let toml = r#"
+            discovery = "none"
+            relay = { url = "https://relay.example" }
+        "#;
+        let cfg: Config = Figment::from(Serialized::defaults(Config::default()))
+            .merge(Toml::string(toml))
+            .extract()
+            .unwrap();
+        assert_eq!(cfg.discovery, DiscoveryMode::None);
+        assert_eq!(
+            cfg.relay,
+            RelayConfig::Custom {
+                url: "https://relay.example".into()
+            }
+        );
+
+        let cfg: Config = Figment::from(Serialized::defaults(Config::default()))
+            .merge(Toml::string(r#"relay = "disabled""#))
+            .extract()
+            .unwrap();
+        assert_eq!(cfg.relay, RelayConfig::Mode(RelayModeName::Disabled));
+    }
+
+    #[test]
+    fn forward_zones_from_toml() {
+        let toml = r#"
+            [forward_zones."corp.example"]
+            upstreams = ["10.1.1.53", "10.1.2.53:5353"]
+
+            [forward_zones."lab.example"]
+            upstreams = ["10.2.0.1"]
+            fallthrough = true
+        "#;
+        let cfg: Config = Figment::from(Serialized::defaults(Config::default()))
+            .merge(Toml::string(toml))
+            .extract()
+            .unwrap();
+        assert_eq!(
+            cfg.forward_zones["corp.example"],
+            ForwardZone {
+                upstreams: vec![
+                    "10.1.1.53".parse().unwrap(),
+                    "10.1.2.53:5353".parse().unwrap()
+                ],
+                fallthrough: false,
+            }
+        );
+        assert!(cfg.forward_zones["lab.example"].fallthrough);
+
+        let bad = r#"forward_zones."corp.example".upstreams = ["tls://10.1.1.53"]"#;
+        assert!(Figment::from(Serialized::defaults(Config::default()))
+            .merge(Toml::string(bad))
+            .extract::<Config>()
+            .is_err());
+    }
+
+    #[test]
+    fn static_records_from_toml() {
+        let toml = r#"
+            [static_records]
+            nas = "192.168.1.10"
+            printer = ["192.168.1.20", "fd00::20"]
+            router = { ips = ["192.168.1.1"], txt = ["model=ax3000"] }
+        "#;
+        let cfg: Config = Figment::from(Serialized::defaults(Config::default()))
+            .merge(Toml::string(toml))
+            .extract()
+            .unwrap();
+        assert_eq!(
+            cfg.static_records["nas"].ips,
+            ["192.168.1.10".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(cfg.static_records["printer"].ips.len(), 2);
+        assert_eq!(cfg.static_records["router"].txt, ["model=ax3000"]);
+
+        for bad in [r#"nas = "nas.lan""#, "nas = []"] {
+            assert!(Figment::from(Serialized::defaults(Config::default()))
+                .merge(Toml::string(&format!("[static_records]\n{}", bad)))
+                .extract::<Config>()
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn role_is_resolved_against_network_name() {
+        let with = |role, network: Option<&str>| Config {
+            role,
+            network_name: network.map(Into::into),
+            ..Config::default()
+        };
+        assert_eq!(
+            with(NodeRole::Auto, None).resolve_role().unwrap(),
+            Role::Dns
+        );
+        assert_eq!(
+            with(NodeRole::Auto, Some("web")).resolve_role().unwrap(),
+            Role::Replica(vec![Watched {
+                network: "web".into(),
+                namespaced: false,
+            }])
+        );
+        assert_eq!(
+            with(NodeRole::Dns, Some("web")).resolve_role().unwrap(),
+            Role::Dns
+        );
+        assert!(with(NodeRole::Replica, None).resolve_role().is_err());
+
+        let cfg: Config = Figment::from(Serialized::defaults(Config::default()))
+            .merge(Toml::string(r#"role = "dns""#))
+            .extract()
+            .unwrap();
+        assert_eq!(cfg.role, NodeRole::Dns);
+
+        let toml = r#"
+            network_name = "web"
+
+            [[networks]]
+            name = "net-a"
+            topic = "0101010101010101010101010101010101010101010101010101010101010101"
+            subnets = ["10.1.0.0/24"]
+        "#;
+        let cfg: Config = Figment::from(Serialized::defaults(Config::default()))
+            .merge(Toml::string(toml))
+            .extract()
+            .unwrap();
+        let Role::Replica(watched) = cfg.resolve_role().unwrap() else {
+            panic!("not a replica");
+        };
+        assert_eq!(watched.len(), 2);
+        assert!(watched[1].namespaced);
+        assert_eq!(cfg.networks[0].subnets[0].to_string(), "10.1.0.0/24");
+    }
 }
@@ -0,0 +1,644 @@
+//! Container names published by more than one node.
+//!
+//! Two hosts can each run a container called `redis`.  An entry holds one
+//! address, so every node serves one publisher's entry and sets the others
+//! aside, as `conflict_policy` says:
+//!
+//! - `lowest-node`, the default: the publisher with the lowest short
+//!   NodeId, in whatever order the entries arrive.
+//! - `first-registration`: the entry heard of first; later ones wait.
+//! - `last-write`: the entry heard of last.  An entry announced again
+//!   unchanged, as containers are on every reconciliation, isn't a new
+//!   write and doesn't take the name back.
+//! - `prefer-local`: this node's own container, and between the others the
+//!   lowest NodeId.  Each node answers with its own, by design.
+//! - `merge`: every publisher's address, the lowest NodeId's first.
+//!
+//! Gossip delivers updates in no fixed order, so with `first-registration`
+//! and `last-write` nodes can disagree about names claimed at about the
+//! same time, and after a restart; `lowest-node` and `merge` give the same
+//! answer on every node.
+//!
+//! A set-aside entry still follows its publisher's ports, leases and
+//! `Remove`, as the served one does, and no other node's.  When the served
+//! container goes, by a `Remove` or its lease running out, the entry set
+//! aside that the policy ranks first takes its place.
+//!
+//! An entry that names no publishing node is this node's.  Until gossip
+//! has started, and for peers that don't say who published an update, the
+//! last `Add` still wins.
+//!
+//! Every entry set aside is counted in `name_conflicts` and warned about,
+//! at most every [`WARN_INTERVAL`] per name; `GET /v1/conflicts` on the
+//! admin API lists the names with the node served and those set aside.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::{inc, METRICS};
+use crate::types::{Entry, Source, StateMap};
+
+/// Shortest time between two warnings about the same name.
+pub const WARN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Which entry is served for a name several nodes publish.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    /// The publisher with the lowest short NodeId.
+    #[default]
+    LowestNode,
+    /// The entry that arrived first.
+    FirstRegistration,
+    /// The entry that arrived last, announcements of an unchanged entry
+    /// aside.
+    LastWrite,
+    /// This node's own entry, else the lowest NodeId.
+    PreferLocal,
+    /// Every publisher's address in one entry.
+    Merge,
+}
+
+impl ConflictPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConflictPolicy::LowestNode => "lowest-node",
+            ConflictPolicy::FirstRegistration => "first-registration",
+            ConflictPolicy::LastWrite => "last-write",
+            ConflictPolicy::PreferLocal => "prefer-local",
+            ConflictPolicy::Merge => "merge",
+        }
+    }
+
+    /// How the served entry was chosen, for warnings.
+    fn serving(self) -> &'static str {
+        match self {
+            ConflictPolicy::LowestNode => "from the lowest NodeId",
+            ConflictPolicy::FirstRegistration => "announced first",
+            ConflictPolicy::LastWrite => "announced last",
+            ConflictPolicy::PreferLocal => "from this node or the lowest NodeId",
+            ConflictPolicy::Merge => "along with the other",
+        }
+    }
+}
+
+/// Entries set aside for names another node is served for.
+#[derive(Debug, Default)]
+pub struct Conflicts {
+    policy: ConflictPolicy,
+    /// This node's short NodeId, once gossip has started.
+    ours: Option<String>,
+    /// By name, then by publishing node.
+    set_aside: HashMap<String, BTreeMap<String, Entry>>,
+    /// When each name was last warned about.
+    warned: HashMap<String, Instant>,
+}
+
+/// A name with entries from several nodes, as listed by `/v1/conflicts`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Conflict {
+    pub name: String,
+    /// The entry answered with; none while a pin hides every container.
+    pub served: Option<Claimant>,
+    pub set_aside: Vec<Claimant>,
+}
+
+/// A node publishing a name, and the address it publishes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Claimant {
+    /// Short NodeId.
+    pub node: String,
+    pub node_name: Option<String>,
+    pub ip: IpAddr,
+}
+
+impl Claimant {
+    fn new(node: &str, entry: &Entry) -> Self {
+        Self {
+            node: node.to_string(),
+            node_name: entry.node_name.clone(),
+            ip: entry.ip,
+        }
+    }
+}
+
+impl Conflicts {
+    pub fn new(policy: ConflictPolicy) -> Self {
+        Self {
+            policy,
+            ..Self::default()
+        }
+    }
+
+    pub fn policy(&self) -> ConflictPolicy {
+        self.policy
+    }
+
+    /// Sets this node's short NodeId, which its own entries go by.
+    pub fn set_node(&mut self, id: String) {
+        self.ours = Some(id);
+    }
+
+    /// The node an update from `node` was published by: `node`, or this
+    /// node for its own updates.
+    fn publisher(&self, node: Option<&str>) -> Option<String> {
+        node.or(self.ours.as_deref()).map(str::to_owned)
+    }
+
+    /// Decides between `entry`, an `Add` for `name` from `node`, and the
+    /// container entry `map` serves for it.  Returns `entry` when it is to
+    /// be served, setting the served one aside if it came from another
+    /// node, or `None` once `entry` itself is set aside.
+    pub fn contest(
+        &mut self,
+        map: &StateMap,
+        name: &str,
+        node: Option<&str>,
+        entry: Entry,
+    ) -> Option<Entry> {
+        let Some(publisher) = self.publisher(node) else {
+            return Some(entry);
+        };
+        let served = container_entry(map, name)
+            .and_then(|served| Some((self.publisher(served.node.as_deref())?, served)));
+        let Some((owner, served)) = served.filter(|(owner, _)| *owner != publisher) else {
+            // Now served, it isn't set aside any more.
+            self.drop_claim(name, &publisher);
+            return Some(entry);
+        };
+        if self.prevails(name, &publisher, &entry, &owner) {
+            let served = served.clone();
+            self.set_aside(name, &owner, served, &publisher, &entry);
+            self.drop_claim(name, &publisher);
+            Some(entry)
+        } else {
+            let served = served.clone();
+            self.set_aside(name, &publisher, entry, &owner, &served);
+            None
+        }
+    }
+
+    /// Whether `entry`, from `publisher`, takes the place of the entry
+    /// `owner` is served for with `name`.
+    fn prevails(&self, name: &str, publisher: &str, entry: &Entry, owner: &str) -> bool {
+        match self.policy {
+            ConflictPolicy::LowestNode | ConflictPolicy::Merge => publisher < owner,
+            ConflictPolicy::PreferLocal => self.rank(publisher) < self.rank(owner),
+            ConflictPolicy::FirstRegistration => false,
+            ConflictPolicy::LastWrite => self
+                .set_aside
+                .get(name)
+                .and_then(|aside| aside.get(publisher))
+                .is_none_or(|claim| claim.ip != entry.ip),
+        }
+    }
+
+    /// Where `node` ranks with `prefer-local`: this node first, then the
+    /// lowest NodeId.
+    fn rank<'a>(&self, node: &'a str) -> (bool, &'a str) {
+        (self.ours.as_deref() != Some(node), node)
+    }
+
+    /// The node whose entry set aside in `aside` is served next.
+    fn successor(&self, aside: &BTreeMap<String, Entry>) -> Option<String> {
+        let (node, _) = match self.policy {
+            ConflictPolicy::LowestNode | ConflictPolicy::Merge => aside.iter().next(),
+            ConflictPolicy::PreferLocal => aside.iter().min_by_key(|(node, _)| self.rank(node)),
+            ConflictPolicy::FirstRegistration => {
+                aside.iter().min_by_key(|(_, entry)| entry.updated_at)
+            }
+            // Ties go to the lowest NodeId, as with the others.
+            ConflictPolicy::LastWrite => {
+                aside.iter().rev().max_by_key(|(_, entry)| entry.updated_at)
+            }
+        }?;
+        Some(node.clone())
+    }
+
+    /// Sets `entry`, from `node`, aside for `name`, which `winner` is
+    /// served for with `served`.  An entry set aside again unchanged keeps
+    /// the time it was set.
+    fn set_aside(&mut self, name: &str, node: &str, entry: Entry, winner: &str, served: &Entry) {
+        let aside = self.set_aside.entry(name.to_string()).or_default();
+        let mut entry = entry;
+        if let Some(claim) = aside.get(node).filter(|claim| claim.ip == entry.ip) {
+            entry.updated_at = claim.updated_at;
+        }
+        if aside.insert(node.to_string(), entry.clone()).is_none() {
+            inc(&METRICS.name_conflicts);
+        }
+        let now = Instant::now();
+        if self
+            .warned
+            .get(name)
+            .is_some_and(|at| now.duration_since(*at) < WARN_INTERVAL)
+        {
+            return;
+        }
+        self.warned.insert(name.to_string(), now);
+        let describe = |node: &str, entry: &Entry| match &entry.node_name {
+            Some(node_name) => format!("{} ({})", node_name, node),
+            None => node.to_string(),
+        };
+        warn!(
+            "{} is published by node {} as {} and by node {} as {}; serving {} {}. Rename one of the containers",
+            name,
+            describe(winner, served),
+            served.ip,
+            describe(node, &entry),
+            entry.ip,
+            served.ip,
+            self.policy.serving()
+        );
+    }
+
+    /// Drops what `node` had set aside for `name`.
+    fn drop_claim(&mut self, name: &str, node: &str) -> bool {
+        let Some(aside) = self.set_aside.get_mut(name) else {
+            return false;
+        };
+        let dropped = aside.remove(node).is_some();
+        if aside.is_empty() {
+            self.set_aside.remove(name);
+            self.warned.remove(name);
+            info!("{} is published by one node again", name);
+        }
+        dropped
+    }
+
+    /// Applies a `Remove` for `name` from `node` to the entry it had set
+    /// aside.  Returns whether it had one; if not, the served entry goes.
+    pub fn withdraw(&mut self, name: &str, node: Option<&str>) -> bool {
+        match self.publisher(node) {
+            Some(publisher) => self.drop_claim(name, &publisher),
+            None => false,
+        }
+    }
+
+    /// The entry set aside for `name` from `node`, for its ports, scoped
+    /// addresses and lease.
+    pub fn claim_mut(&mut self, name: &str, node: Option<&str>) -> Option<&mut Entry> {
+        let publisher = self.publisher(node)?;
+        self.set_aside.get_mut(name)?.get_mut(&publisher)
+    }
+
+    /// Serves the entry set aside for `name` that ranks first if `map` has
+    /// no container entry for it any more.  Returns whether one was.
+    pub fn promote(&mut self, map: &mut StateMap, name: &str) -> bool {
+        match map.get(name) {
+            Some(pinned) if pinned.source == Source::Manual && pinned.shadowed.is_none() => {}
+            None => {}
+            _ => return false,
+        }
+        let Some(node) = self
+            .set_aside
+            .get(name)
+            .and_then(|aside| self.successor(aside))
+        else {
+            return false;
+        };
+        let aside = self.set_aside.get_mut(name).expect("just looked up");
+        let entry = aside.remove(&node).expect("just chosen");
+        if aside.is_empty() {
+            self.set_aside.remove(name);
+            self.warned.remove(name);
+        }
+        info!(
+            "Serving {} -> {} from node {}, set aside until now",
+            name, entry.ip, node
+        );
+        match map.get_mut(name) {
+            Some(pinned) => pinned.shadowed = Some(Box::new(entry)),
+            None => {
+                map.insert(name.to_string(), entry);
+            }
+        }
+        true
+    }
+
+    /// Drops the entries set aside whose lease ran out by `now` (Unix time
+    /// in milliseconds), and serves those left for names whose container
+    /// entry went.  Returns the names now served from another node.
+    pub fn expire(&mut self, map: &mut StateMap, now: u64) -> Vec<String> {
+        for aside in self.set_aside.values_mut() {
+            aside.retain(|_, entry| entry.expires_at.is_none_or(|at| at > now));
+        }
+        self.set_aside.retain(|_, aside| !aside.is_empty());
+        self.warned
+            .retain(|name, _| self.set_aside.contains_key(name));
+        let names: Vec<String> = self.set_aside.keys().cloned().collect();
+        names
+            .into_iter()
+            .filter(|name| self.promote(map, name))
+            .collect()
+    }
+
+    /// With `merge`, answers `name` with the addresses of every entry for
+    /// it: the served one's first, then those set aside.
+    pub fn merge(&self, map: &mut StateMap, name: &str) {
+        if self.policy != ConflictPolicy::Merge {
+            return;
+        }
+        let entry = match map.get_mut(name) {
+            Some(pinned) if pinned.source == Source::Manual => pinned.shadowed.as_deref_mut(),
+            Some(entry) if entry.source == Source::Cluster => Some(entry),
+            _ => None,
+        };
+        let Some(entry) = entry else {
+            return;
+        };
+        let mut ips = Vec::new();
+        for ip in self.set_aside_ips(name) {
+            if ip != entry.ip && !ips.contains(&ip) {
+                ips.push(ip);
+            }
+        }
+        entry.extra_ips = ips;
+    }
+
+    /// The addresses of the entries set aside for `name`, by node.
+    pub fn set_aside_ips(&self, name: &str) -> Vec<IpAddr> {
+        self.set_aside
+            .get(name)
+            .map(|aside| aside.values().map(|entry| entry.ip).collect())
+            .unwrap_or_default()
+    }
+
+    /// The names with entries set aside, unsorted.
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.set_aside.keys()
+    }
+
+    /// The names with entries set aside, sorted.
+    pub fn list(&self, map: &StateMap) -> Vec<Conflict> {
+        let mut conflicts: Vec<Conflict> = self
+            .set_aside
+            .iter()
+            .map(|(name, aside)| {
+                let served = container_entry(map, name).and_then(|entry| {
+                    let node = self.publisher(entry.node.as_deref())?;
+                    Some(Claimant::new(&node, entry))
+                });
+                Conflict {
+                    name: name.clone(),
+                    served,
+                    set_aside: aside
+                        .iter()
+                        .map(|(node, entry)| Claimant::new(node, entry))
+                        .collect(),
+                }
+            })
+            .collect();
+        conflicts.sort_by(|a, b| a.name.cmp(&b.name));
+        conflicts
+    }
+}
+
+/// The container entry `map` has for `name`, served or under a pin.
+fn container_entry<'a>(map: &'a StateMap, name: &str) -> Option<&'a Entry> {
+    match map.get(name)? {
+        pinned if pinned.source == Source::Manual => pinned.shadowed.as_deref(),
+        entry if entry.source == Source::Cluster => Some(entry),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConflictPolicy;
+    use crate::gossip::apply_update_from;
+    use crate::registry::Registry;
+    use crate::types::{Entry, SharedState, Update};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const A: Option<&str> = Some("1111111111");
+    const B: Option<&str> = Some("2222222222");
+    const C: Option<&str> = Some("0000000000");
+
+    fn add(ip: &str) -> Update {
+        Update::Add {
+            name: "redis".into(),
+            ip: ip.parse().unwrap(),
+        }
+    }
+
+    fn remove() -> Update {
+        Update::Remove {
+            name: "redis".into(),
+        }
+    }
+
+    async fn served(state: &SharedState) -> Option<String> {
+        let map = state.read().await;
+        map.get("redis").map(|entry| entry.ip.to_string())
+    }
+
+    /// A registry with `policy`, as the node with short NodeId `3333333333`.
+    async fn with_policy(policy: ConflictPolicy) -> SharedState {
+        let state = Arc::new(Registry::default().with_conflict_policy(policy));
+        state.set_node("3333333333".into()).await;
+        state
+    }
+
+    /// Entries set aside at the same millisecond rank by NodeId; these
+    /// tests want them apart.
+    async fn tick() {
+        tokio::time::sleep(Duration::from_millis(2)).await;
+    }
+
+    #[tokio::test]
+    async fn the_lowest_node_is_served_whatever_the_order() {
+        let (low, high) = (Some("1111111111"), Some("2222222222"));
+        for order in [[low, high], [high, low]] {
+            let state = SharedState::default();
+            for node in order.iter().chain(&order) {
+                let ip = if *node == low { "10.0.0.1" } else { "10.0.0.2" };
+                apply_update_from(add(ip), *node, &state).await;
+            }
+            assert_eq!(served(&state).await.as_deref(), Some("10.0.0.1"));
+            let conflicts = state.conflicts().await;
+            assert_eq!(conflicts.len(), 1);
+            assert_eq!(conflicts[0].served.as_ref().unwrap().node, "1111111111");
+            assert_eq!(conflicts[0].set_aside[0].node, "2222222222");
+            assert_eq!(conflicts[0].set_aside[0].ip.to_string(), "10.0.0.2");
+        }
+    }
+
+    #[tokio::test]
+    async fn the_next_node_is_served_when_the_first_goes() {
+        let (low, high) = (Some("1111111111"), Some("2222222222"));
+        let state = SharedState::default();
+        apply_update_from(add("10.0.0.2"), high, &state).await;
+        apply_update_from(add("10.0.0.1"), low, &state).await;
+        let remove = || Update::Remove {
+            name: "redis".into(),
+        };
+        apply_update_from(remove(), low, &state).await;
+        assert_eq!(served(&state).await.as_deref(), Some("10.0.0.2"));
+        assert!(state.conflicts().await.is_empty());
+
+        // A node whose entry is set aside only withdraws its own.
+        apply_update_from(add("10.0.0.1"), low, &state).await;
+        apply_update_from(remove(), high, &state).await;
+        assert_eq!(served(&state).await.as_deref(), Some("10.0.0.1"));
+        assert!(state.conflicts().await.is_empty());
+
+        // Set-aside entries lapse like served ones.
+        apply_update_from(add("10.0.0.2"), high, &state).await;
+        let lease = Update::Lease {
+            name: "redis".into(),
+            valid_for_secs: 60,
+        };
+        apply_update_from(lease, high, &state).await;
+        assert_eq!(state.read().await["redis"].expires_at, None);
+        let mut map = state.write().await;
+        map.remove("redis");
+        assert_eq!(
+            state.expire_set_aside(&mut map, u64::MAX),
+            Vec::<String>::new()
+        );
+        assert!(map.get("redis").is_none());
+    }
+
+    #[tokio::test]
+    async fn each_policy_decides_between_two_nodes() {
+        use ConflictPolicy::*;
+        // Who is served after A then B add, and after B then A.
+        for (policy, a_first, b_first) in [
+            (LowestNode, "10.0.0.1", "10.0.0.1"),
+            (FirstRegistration, "10.0.0.1", "10.0.0.2"),
+            (LastWrite, "10.0.0.2", "10.0.0.1"),
+            (PreferLocal, "10.0.0.1", "10.0.0.1"),
+            (Merge, "10.0.0.1", "10.0.0.1"),
+        ] {
+            for (order, expected) in [([A, B], a_first), ([B, A], b_first)] {
+                let state = with_policy(policy).await;
+                for node in order {
+                    let ip = if node == A { "10.0.0.1" } else { "10.0.0.2" };
+                    apply_update_from(add(ip), node, &state).await;
+                    tick().await;
+                }
+                assert_eq!(
+                    served(&state).await.as_deref(),
+                    Some(expected),
+                    "{:?}",
+                    policy
+                );
+                assert_eq!(state.conflicts().await.len(), 1);
+                // Whoever was served, removing A leaves B.
+                apply_update_from(remove(), A, &state).await;
+                assert_eq!(
+                    served(&state).await.as_deref(),
+                    Some("10.0.0.2"),
+                    "{:?}",
+                    policy
+                );
+                assert!(state.read().await["redis"].extra_ips.is_empty());
+                assert!(state.conflicts().await.is_empty());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn the_first_registration_waits_in_line() {
+        let state = with_policy(ConflictPolicy::FirstRegistration).await;
+        for (node, ip) in [(B, "10.0.0.2"), (A, "10.0.0.1"), (C, "10.0.0.3")] {
+            apply_update_from(add(ip), node, &state).await;
+            tick().await;
+        }
+        // Announced again, the first stays; A set aside before C comes next.
+        apply_update_from(add("10.0.0.1"), A, &state).await;
+        apply_update_from(add("10.0.0.2"), B, &state).await;
+        assert_eq!(served(&state).await.as_deref(), Some("10.0.0.2"));
+        apply_update_from(remove(), B, &state).await;
+        assert_eq!(served(&state).await.as_deref(), Some("10.0.0.1"));
+        apply_update_from(remove(), A, &state).await;
+        assert_eq!(served(&state).await.as_deref(), Some("10.0.0.3"));
+    }
+
+    #[tokio::test]
+    async fn the_last_write_is_a_change_not_an_announcement() {
+        let state = with_policy(ConflictPolicy::LastWrite).await;
+        apply_update_from(add("10.0.0.1"), A, &state).await;
+        tick().await;
+        apply_update_from(add("10.0.0.2"), B, &state).await;
+        tick().await;
+        // A announcing the same address again, as on reconciliation.
+        apply_update_from(add("10.0.0.1"), A, &state).await;
+        assert_eq!(served(&state).await.as_deref(), Some("10.0.0.2"));
+        apply_update_from(add("10.0.0.9"), A, &state).await;
+        assert_eq!(served(&state).await.as_deref(), Some("10.0.0.9"));
+        tick().await;
+        apply_update_from(add("10.0.0.3"), C, &state).await;
+        assert_eq!(served(&state).await.as_deref(), Some("10.0.0.3"));
+        // Of the entries set aside, the latest write comes next.
+        apply_update_from(remove(), C, &state).await;
+        assert_eq!(served(&state).await.as_deref(), Some("10.0.0.9"));
+        apply_update_from(remove(), A, &state).await;
+        assert_eq!(served(&state).await.as_deref(), Some("10.0.0.2"));
+    }
+
+    #[tokio::test]
+    async fn prefer_local_serves_our_own_container() {
+        let state = with_policy(ConflictPolicy::PreferLocal).await;
+        apply_update_from(add("10.0.0.2"), B, &state).await;
+        apply_update_from(add("10.0.0.3"), None, &state).await;
+        apply_update_from(add("10.0.0.1"), A, &state).await;
+        assert_eq!(served(&state).await.as_deref(), Some("10.0.0.3"));
+        let conflicts = state.conflicts().await;
+        assert_eq!(conflicts[0].served.as_ref().unwrap().node, "3333333333");
+        // Between the others, the lowest NodeId.
+        apply_update_from(remove(), None, &state).await;
+        assert_eq!(served(&state).await.as_deref(), Some("10.0.0.1"));
+        apply_update_from(add("10.0.0.3"), None, &state).await;
+        assert_eq!(served(&state).await.as_deref(), Some("10.0.0.3"));
+    }
+
+    #[tokio::test]
+    async fn merge_answers_with_every_address() {
+        let state = with_policy(ConflictPolicy::Merge).await;
+        apply_update_from(add("10.0.0.2"), B, &state).await;
+        apply_update_from(add("10.0.0.1"), A, &state).await;
+        apply_update_from(add("10.0.0.2"), C, &state).await;
+        let ips = |entry: &Entry| entry.ips().map(|ip| ip.to_string()).collect::<Vec<_>>();
+        let entry = state.read().await["redis"].clone();
+        // C is the lowest; the address B shares with it is answered once.
+        assert_eq!(ips(&entry), ["10.0.0.2", "10.0.0.1"]);
+        apply_update_from(add("10.0.0.2"), C, &state).await;
+        assert_eq!(ips(&state.read().await["redis"]), ["10.0.0.2", "10.0.0.1"]);
+        apply_update_from(remove(), A, &state).await;
+        assert_eq!(ips(&state.read().await["redis"]), ["10.0.0.2"]);
+        apply_update_from(remove(), C, &state).await;
+        apply_update_from(add("10.0.0.1"), A, &state).await;
+        assert_eq!(ips(&state.read().await["redis"]), ["10.0.0.1", "10.0.0.2"]);
+    }
+
+    #[tokio::test]
+    async fn snapshot_entries_are_contested_like_adds() {
+        let state = with_policy(ConflictPolicy::LowestNode).await;
+        apply_update_from(add("10.0.0.2"), B, &state).await;
+        let restored = |node: Option<&str>, ip: &str| Entry {
+            node: node.map(str::to_owned),
+            ..Entry::new(ip.parse().unwrap())
+        };
+        state
+            .restore(vec![
+                ("redis".into(), restored(A, "10.0.0.1")),
+                ("web-1".into(), restored(A, "10.0.0.5")),
+            ])
+            .await;
+        assert_eq!(served(&state).await.as_deref(), Some("10.0.0.1"));
+        assert_eq!(state.conflicts().await[0].set_aside[0].node, "2222222222");
+        assert!(state.read().await.contains_key("web-1"));
+        // What the map has from the same node stays.
+        state
+            .restore(vec![("redis".into(), restored(A, "10.0.0.7"))])
+            .await;
+        assert_eq!(served(&state).await.as_deref(), Some("10.0.0.1"));
+    }
+}
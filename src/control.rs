@@ -0,0 +1,502 @@
+//! Control socket, and the `glued ctl` client for it.
+//!
+//! A unix socket at `control_socket` speaking line-delimited JSON.  Each
+//! request is an object naming a `command`:
+//!
+//! ```text
+//! {"command": "list-entries"}
+//! {"command": "list-peers"}
+//! {"command": "add-static-entry", "name": "printer", "ip": "10.0.0.9"}
+//! {"command": "remove-entry", "name": "printer"}
+//! {"command": "import-entries", "entries": [{"name": "printer", "ip": "10.0.0.9"}], "replace": false, "dry_run": false}
+//! {"command": "reload-config"}
+//! {"command": "reconcile"}
+//! {"command": "evict-node", "node": "ab12cd34ef"}
+//! {"command": "handover"}
+//! {"command": "dump-config"}
+//! ```
+//!
+//! and is answered by one line, `{"ok": true, "result": ...}` or
+//! `{"ok": false, "error": "..."}`.  Static entries added here last until
+//! the next restart or a reload that changes `static_records`; removing a
+//! cluster entry withdraws it over gossip until its publisher announces it
+//! again.  `reload-config` reloads like SIGHUP does (see [`crate::reload`])
+//! and answers with the fields it applied and those awaiting a restart.
+//! `import-entries` pins the names of a `list-entries` result, as planned
+//! by [`crate::pins::plan_import`], and answers with the plan.
+//! `reconcile` rescans and resyncs like `POST /v1/reconcile`, and
+//! `evict-node` evicts like `POST /v1/evict/{node}`.
+//!
+//! `handover` is for a blue/green upgrade on one host: the instance taking
+//! over, with `handover_from` naming this one's socket, sends it once it
+//! is warmed up (see [`take_over`]).  This instance then publishes no more
+//! changes of its runtime, leaves answering peers' sync requests to its
+//! successor, so its entries lapse with their leases, and reports itself
+//! unready.  It still serves DNS, and applies operators' changes made
+//! through it, until stopped.
+
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::admin;
+use crate::config::Config;
+use crate::evict;
+use crate::gossip::Batching;
+use crate::names::{is_valid_label, normalize};
+use crate::pins::{self, ImportRecord};
+use crate::registry::{LocalSource, LocalUpdate};
+use crate::reload::Reloader;
+use crate::status::Status;
+use crate::types::{now_millis, Entry, SharedState, Source, Update};
+
+const USAGE: &str = "usage: glued ctl [--socket PATH] entries | peers | add NAME IP | remove NAME \
+    | export | import [--replace] [--dry-run] FILE | reload | reconcile | evict NODE | handover \
+    | config";
+
+/// A control request.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Command {
+    ListEntries,
+    ListPeers,
+    AddStaticEntry {
+        name: String,
+        ip: IpAddr,
+    },
+    RemoveEntry {
+        name: String,
+    },
+    ImportEntries {
+        entries: Vec<ImportRecord>,
+        #[serde(default)]
+        replace: bool,
+        #[serde(default)]
+        dry_run: bool,
+    },
+    ReloadConfig,
+    Reconcile,
+    EvictNode {
+        node: String,
+    },
+    Handover,
+    DumpConfig,
+}
+
+impl Command {
+    /// Parses `glued ctl` arguments: `entries`, `peers`, `add NAME IP`,
+    /// `remove NAME`, `export`, `import [--replace] [--dry-run] FILE`,
+    /// `reload`, `reconcile`, `evict NODE`, `handover` or `config`.  The
+    /// protocol's command names work too.
+    /// `import` reads its file here, on the client side.
+    pub fn from_args(args: &[String]) -> anyhow::Result<Self> {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        Ok(match args.as_slice() {
+            ["entries" | "list-entries" | "export"] => Self::ListEntries,
+            ["peers" | "list-peers"] => Self::ListPeers,
+            ["add" | "add-static-entry", name, ip] => Self::AddStaticEntry {
+                name: name.to_string(),
+                ip: ip
+                    .parse()
+                    .map_err(|e| anyhow!("Invalid address '{}': {}", ip, e))?,
+            },
+            ["remove" | "remove-entry", name] => Self::RemoveEntry {
+                name: name.to_string(),
+            },
+            ["import" | "import-entries", flags @ ..] => Self::import_from_args(flags)?,
+            ["reload" | "reload-config"] => Self::ReloadConfig,
+            ["reconcile"] => Self::Reconcile,
+            ["evict" | "evict-node", node] => Self::EvictNode {
+                node: node.to_string(),
+            },
+            ["handover"] => Self::Handover,
+            ["config" | "dump-config"] => Self::DumpConfig,
+            _ => bail!(USAGE),
+        })
+    }
+
+    fn import_from_args(args: &[&str]) -> anyhow::Result<Self> {
+        let (mut replace, mut dry_run, mut file) = (false, false, None);
+        for &arg in args {
+            match arg {
+                "--replace" => replace = true,
+                "--dry-run" => dry_run = true,
+                path if file.is_none() && !path.starts_with("--") => file = Some(path),
+                _ => bail!(USAGE),
+            }
+        }
+        let Some(file) = file else {
+            bail!(USAGE);
+        };
+        let text =
+            std::fs::read_to_string(file).with_context(|| format!("Failed to read {}", file))?;
+        let entries = serde_json::from_str(&text)
+            .with_context(|| format!("{} is not a list of entries", file))?;
+        Ok(Self::ImportEntries {
+            entries,
+            replace,
+            dry_run,
+        })
+    }
+}
+
+/// What the control socket works on.
+pub struct Control {
+    pub state: SharedState,
+    pub status: Arc<Status>,
+    /// The local update pipeline, which applies updates and gossips them.
+    pub updates: mpsc::Sender<LocalUpdate>,
+    /// The config in effect, which `reload-config` reloads.
+    pub reloader: Arc<Reloader>,
+}
+
+impl Control {
+    pub async fn execute(&self, command: Command) -> anyhow::Result<Value> {
+        match command {
+            Command::ListEntries => {
+                let map = self.state.read().await;
+                Ok(serde_json::to_value(admin::entries(&map))?)
+            }
+            Command::ListPeers => Ok(Value::Array(admin::peers(&self.status.peers()))),
+            Command::AddStaticEntry { name, ip } => {
+                let name = normalize(&name);
+                if !is_valid_label(&name) {
+                    bail!("'{}' is not a valid single DNS label", name);
+                }
+                let entry = Entry {
+                    source: Source::Static,
+                    ..Entry::new(ip)
+                };
+                if let Some(old) = self.state.write().await.insert(name.clone(), entry) {
+                    warn!(
+                        "Static entry {} replaces the {:?} entry -> {}",
+                        name, old.source, old.ip
+                    );
+                }
+                info!(
+                    "Added static entry {} -> {} over the control socket",
+                    name, ip
+                );
+                Ok(json!({ "name": name, "ip": ip }))
+            }
+            Command::RemoveEntry { name } => {
+                let name = normalize(&name);
+                let mut map = self.state.write().await;
+                match map.get(&name).map(|entry| entry.source) {
+                    None => bail!("No entry named '{}'", name),
+                    Some(Source::Hosts) => {
+                        bail!("'{}' comes from the hosts file; remove it there", name)
+                    }
+                    Some(Source::Static) => {
+                        map.remove(&name);
+                    }
+                    Some(source @ (Source::Cluster | Source::Manual)) => {
+                        drop(map);
+                        let update = match source {
+                            Source::Manual => Update::Unpin { name: name.clone() },
+                            _ => Update::Remove { name: name.clone() },
+                        };
+                        self.updates
+                            .send((update, LocalSource::Control))
+                            .await
+                            .map_err(|_| anyhow!("The update pipeline has stopped"))?;
+                    }
+                }
+                info!("Removed {} over the control socket", name);
+                Ok(json!({ "name": name }))
+            }
+            Command::ImportEntries {
+                entries,
+                replace,
+                dry_run,
+            } => {
+                let plan =
+                    pins::plan_import(&*self.state.read().await, entries, replace, now_millis())
+                        .map_err(|e| anyhow!("Nothing imported: {}", e))?;
+                if !dry_run {
+                    let batching = Batching::from_config(&self.reloader.config());
+                    for update in plan.updates(batching.max) {
+                        self.updates
+                            .send((update, LocalSource::Control))
+                            .await
+                            .map_err(|_| anyhow!("The update pipeline has stopped"))?;
+                    }
+                    info!(
+                        "Imported entries over the control socket: {} pinned, {} unpinned, {} unchanged",
+                        plan.pinned.len(),
+                        plan.unpinned.len(),
+                        plan.unchanged.len()
+                    );
+                }
+                let mut result = serde_json::to_value(&plan)?;
+                result["dry_run"] = json!(dry_run);
+                Ok(result)
+            }
+            Command::ReloadConfig => {
+                info!("Reloading the config over the control socket");
+                let reloaded = self.reloader.reload().await?;
+                Ok(serde_json::to_value(reloaded)?)
+            }
+            Command::Reconcile => {
+                info!("Reconciling over the control socket");
+                Ok(serde_json::to_value(self.status.resync().run().await)?)
+            }
+            Command::EvictNode { node } => {
+                let node = evict::short_id(&node)?;
+                self.updates
+                    .send((
+                        Update::EvictNode { node: node.clone() },
+                        LocalSource::Control,
+                    ))
+                    .await
+                    .map_err(|_| anyhow!("The update pipeline has stopped"))?;
+                info!("Evicted node {} over the control socket", node);
+                Ok(json!({ "node": node }))
+            }
+            Command::Handover => {
+                if !self.status.hand_over() {
+                    warn!("Handed over to another instance: publishing no more container changes");
+                }
+                Ok(json!({ "handed_over": true }))
+            }
+            Command::DumpConfig => {
+                let mut dump = serde_json::to_value(&*self.reloader.config())?;
+                dump["cluster_secret"] = json!("<redacted>");
+                for secret in ["webhook_secret", "admin_token", "metrics_token"] {
+                    if dump.get(secret).is_some() {
+                        dump[secret] = json!("<redacted>");
+                    }
+                }
+                Ok(dump)
+            }
+        }
+    }
+
+    /// The response line for one request line.
+    async fn respond(&self, line: &str) -> Value {
+        let result = match serde_json::from_str::<Command>(line) {
+            Ok(command) => self.execute(command).await,
+            Err(e) => Err(anyhow!("Invalid request: {}", e)),
+        };
+        match result {
+            Ok(result) => json!({ "ok": true, "result": result }),
+            Err(e) => json!({ "ok": false, "error": format!("{:#}", e) }),
+        }
+    }
+}
+
+/// Binds the control socket at `path` with permissions `mode`, replacing a
+/// socket left behind by an earlier run.
+#[cfg(unix)]
+pub fn listen(path: &Path, mode: u32) -> anyhow::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => bail!("{} exists and is not a socket", path.display()),
+        Err(_) => {}
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Serves the control socket until the task is aborted.
+#[cfg(unix)]
+pub async fn run_control(listener: tokio::net::UnixListener, control: Arc<Control>) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Control socket accept failed: {}", e);
+                continue;
+            }
+        };
+        let control = Arc::clone(&control);
+        tokio::spawn(async move {
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            loop {
+                let line = match lines.next_line().await {
+                    Ok(Some(line)) => line,
+                    Ok(None) => break,
+                    Err(e) => {
+                        debug!("Control connection failed: {}", e);
+                        break;
+                    }
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let mut response = control.respond(&line).await.to_string();
+                response.push('\n');
+                if let Err(e) = write.write_all(response.as_bytes()).await {
+                    debug!("Control connection failed: {}", e);
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// Sends `command` to the daemon at `path` and returns its result.
+#[cfg(unix)]
+pub async fn request(path: &Path, command: &Command) -> anyhow::Result<Value> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let stream = tokio::net::UnixStream::connect(path)
+        .await
+        .map_err(|e| anyhow!("Cannot reach glued at {}: {}", path.display(), e))?;
+    let (read, mut write) = stream.into_split();
+    let mut line = serde_json::to_string(command)?;
+    line.push('\n');
+    write.write_all(line.as_bytes()).await?;
+    let Some(response) = BufReader::new(read).lines().next_line().await? else {
+        bail!("glued closed the control connection without answering");
+    };
+    let mut response: Value = serde_json::from_str(&response)?;
+    if response["ok"] == json!(true) {
+        Ok(response["result"].take())
+    } else {
+        bail!("{}", response["error"].as_str().unwrap_or("unknown error"))
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn request(_path: &Path, _command: &Command) -> anyhow::Result<Value> {
+    bail!("The control socket is only available on unix platforms")
+}
+
+/// Once this node is warmed up, asks the instance with the control socket
+/// at `path` to hand over to it.
+pub async fn take_over(path: PathBuf, status: Arc<Status>) {
+    status.warmed_up().await;
+    match request(&path, &Command::Handover).await {
+        Ok(_) => info!("Took over from the instance at {}", path.display()),
+        Err(e) => warn!(
+            "Failed to take over from the instance at {}: {:#}",
+            path.display(),
+            e
+        ),
+    }
+}
+
+/// `glued ctl`: runs one command against the local daemon and prints the
+/// result.  The socket is `--socket PATH`, else `control_socket`.
+pub async fn ctl(args: &[String]) -> anyhow::Result<()> {
+    let (path, args) = match args {
+        [flag, path, rest @ ..] if flag == "--socket" => (path.clone(), rest),
+        _ => (Config::load()?.control_socket, args),
+    };
+    let command = Command::from_args(args)?;
+    let result = request(Path::new(&path), &command).await?;
+    println!("{}", serde_json::to_string_pretty(&result)?);
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn commands_over_the_socket() {
+        let path = std::env::temp_dir().join(format!("glued-ctl-{}.sock", std::process::id()));
+        let listener = listen(&path, 0o600).unwrap();
+        let state = SharedState::default();
+        state
+            .write()
+            .await
+            .insert("web-1".into(), Entry::new("10.0.0.2".parse().unwrap()));
+        let (updates, mut published) = mpsc::channel(4);
+        let status = Arc::new(Status::new(false));
+        let control = Arc::new(Control {
+            state: Arc::clone(&state),
+            status: Arc::clone(&status),
+            updates,
+            reloader: Arc::new(Reloader::new(Config::default(), Arc::clone(&state))),
+        });
+        let server = tokio::spawn(run_control(listener, control));
+        let args = |args: &[&str]| {
+            Command::from_args(&args.iter().map(|a| a.to_string()).collect::<Vec<_>>()).unwrap()
+        };
+
+        let added = request(&path, &args(&["add", "Printer", "10.0.0.9"])).await;
+        assert_eq!(added.unwrap()["name"], "printer");
+        let entries = request(&path, &args(&["entries"])).await.unwrap();
+        assert_eq!(entries[0]["name"], "printer");
+        assert_eq!(entries[0]["source"], "static");
+        assert_eq!(entries[1]["name"], "web-1");
+
+        request(&path, &args(&["remove", "printer"])).await.unwrap();
+        assert!(!state.read().await.contains_key("printer"));
+        // Cluster entries are withdrawn through the update pipeline.
+        request(&path, &args(&["remove", "web-1"])).await.unwrap();
+        assert_eq!(
+            published.recv().await,
+            Some((
+                Update::Remove {
+                    name: "web-1".into()
+                },
+                LocalSource::Control
+            ))
+        );
+        let missing = request(&path, &args(&["remove", "nothing"])).await;
+        assert!(missing.unwrap_err().to_string().contains("No entry"));
+
+        let config = request(&path, &args(&["config"])).await.unwrap();
+        assert_eq!(config["cluster_secret"], "<redacted>");
+        assert_eq!(config["control_socket_mode"], "0660");
+        assert_eq!(request(&path, &args(&["peers"])).await.unwrap(), json!([]));
+        let reconciled = request(&path, &args(&["reconcile"])).await.unwrap();
+        assert_eq!(reconciled["removed"], 0);
+        let evicted = request(&path, &args(&["evict", "AB12CD34EF"]))
+            .await
+            .unwrap();
+        assert_eq!(evicted["node"], "ab12cd34ef");
+        let (evict, _) = published.recv().await.unwrap();
+        assert_eq!(
+            evict,
+            Update::EvictNode {
+                node: "ab12cd34ef".into()
+            }
+        );
+        // The instance taking over asks once it is warmed up.
+        assert!(status.is_ready());
+        take_over(path.clone(), Arc::new(Status::new(false))).await;
+        assert!(status.is_handed_over());
+        assert_eq!(status.unready(), Some("handed over to another instance"));
+        assert!(Command::from_args(&["add".into(), "x".into()]).is_err());
+
+        let import = |dry_run| Command::ImportEntries {
+            entries: vec![ImportRecord {
+                name: "Canary".into(),
+                ip: "10.0.0.8".into(),
+                source: Source::Cluster,
+                expires_at: None,
+            }],
+            replace: false,
+            dry_run,
+        };
+        let planned = request(&path, &import(true)).await.unwrap();
+        assert_eq!(planned["pinned"][0]["name"], "canary");
+        assert_eq!(planned["dry_run"], true);
+        request(&path, &import(false)).await.unwrap();
+        let (pin, source) = published.recv().await.unwrap();
+        assert_eq!(source, LocalSource::Control);
+        assert!(matches!(pin, Update::Pin { name, .. } if name == "canary"));
+
+        server.abort();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
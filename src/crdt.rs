@@ -0,0 +1,263 @@
+//! Peers' updates, merged the same whatever the delivery order.
+//!
+//! Gossip delivers messages in no fixed order, and at times twice.  Each
+//! one a node sends is stamped with its [`Origin`], whose timestamp and
+//! sequence number order it among the node's others: its [`Stamp`].  The
+//! registry keys container entries by name and publishing node, and only
+//! that node changes its entry (see `gossip::apply_to`), so of two changes
+//! to one the newer stamp is the later change.  [`Dots`] keeps the stamp
+//! of the newest change applied to each, and drops older and repeated
+//! ones.  A `Remove` leaves its stamp too, like a tombstone, so an `Add`
+//! it overtook can't bring the entry back.  Ports, leases, scoped
+//! addresses and TXT strings follow an `Add` rather than replace the
+//! entry: they are dropped if older than its last `Add` or `Remove`, but
+//! leave no stamp, so a lease renewal overtaking the `Add` it follows
+//! doesn't make that look old.
+//!
+//! With every conflict policy but `first-registration` and `last-write`,
+//! which go by arrival by design, the entry served for a name depends only
+//! on which nodes have one.  So replicas that applied the same messages
+//! hold the same map, however often and in whatever order they came.
+//!
+//! Pins are kept apart from container entries, per name and pinning node.
+//! Between nodes the pin applied last still wins, as for any operator's
+//! change.
+//!
+//! Stamps are forgotten [`FORGET_AFTER`] after they are recorded: gossip
+//! drops messages older than the last from their node anyway (see
+//! [`crate::dedup`]), so only those crossing on the way need them.  Peers
+//! still send each other updates as they happen, and what they own on
+//! joining; sending only what a peer lacks is left for later.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use log::debug;
+
+use crate::names::normalize;
+use crate::networks::{leaves, name_of};
+use crate::types::Update;
+use crate::wire::Origin;
+
+/// How long the stamp of a change is kept once applied.
+pub const FORGET_AFTER: Duration = Duration::from_secs(600);
+
+/// When a node made a change: its clock, then its message counter, which
+/// tells apart messages sent within a millisecond.  The counter restarts
+/// with the node, but its clock has moved on by then.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Stamp {
+    /// Unix time in milliseconds, on the node's clock.
+    pub timestamp: u64,
+    pub seq: u64,
+}
+
+impl From<&Origin> for Stamp {
+    fn from(origin: &Origin) -> Self {
+        Self {
+            timestamp: origin.timestamp,
+            seq: origin.seq,
+        }
+    }
+}
+
+/// What a node's change to a name is to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    /// Its container entry, with the ports, lease, scoped addresses and
+    /// TXT strings following it.
+    Entry,
+    Pin,
+}
+
+/// A node's entry or pin for a name.
+type Key = (String, String, Kind);
+
+/// The newest change applied to each entry from a peer.
+#[derive(Debug, Default)]
+pub struct Dots {
+    /// Stamps, with when they were recorded.
+    latest: HashMap<Key, (Stamp, u64)>,
+    /// When stamps were last forgotten.
+    forgotten_at: u64,
+}
+
+impl Dots {
+    /// The parts of `update`, made by `node` at `stamp`, newer than what
+    /// was applied to the entries they change, or `None` if no part is.
+    /// Parts of one update come as one change, so an `Add` and the ports
+    /// following it are applied together.  `now` is Unix time in
+    /// milliseconds.
+    pub fn admit(&mut self, node: &str, stamp: Stamp, update: Update, now: u64) -> Option<Update> {
+        self.forget(now);
+        let parts = leaves(update.clone());
+        let mut kept = Vec::with_capacity(parts.len());
+        let mut changed = Vec::new();
+        let mut dropped = false;
+        for part in parts {
+            let Some(key) = key(node, &part) else {
+                kept.push(part);
+                continue;
+            };
+            let replaces = replaces(&part);
+            let stale = self
+                .latest
+                .get(&key)
+                .is_some_and(|(applied, _)| match replaces {
+                    true => *applied >= stamp,
+                    false => *applied > stamp,
+                });
+            if stale {
+                debug!(
+                    "Dropping {:?} from node {}: older than its last change",
+                    part, node
+                );
+                dropped = true;
+                continue;
+            }
+            if replaces {
+                changed.push(key);
+            }
+            kept.push(part);
+        }
+        for key in changed {
+            self.latest.insert(key, (stamp, now));
+        }
+        if kept.is_empty() {
+            None
+        } else if dropped {
+            Some(Update::batch(kept))
+        } else {
+            Some(update)
+        }
+    }
+
+    /// Drops the stamps recorded over [`FORGET_AFTER`] before `now`, once
+    /// a minute at most.
+    fn forget(&mut self, now: u64) {
+        if now.saturating_sub(self.forgotten_at) < 60_000 {
+            return;
+        }
+        self.forgotten_at = now;
+        let horizon = now.saturating_sub(FORGET_AFTER.as_millis() as u64);
+        self.latest.retain(|_, (_, recorded)| *recorded >= horizon);
+    }
+}
+
+/// The entry `part` of an update from `node` changes; `None` for one that
+/// changes other nodes' entries, as an eviction does.
+fn key(node: &str, part: &Update) -> Option<Key> {
+    let kind = match part {
+        Update::Pin { .. } | Update::Unpin { .. } => Kind::Pin,
+        _ => Kind::Entry,
+    };
+    Some((normalize(name_of(part)?), node.to_string(), kind))
+}
+
+/// Whether `part` replaces the entry or pin it changes, rather than
+/// following an `Add` with more about it.
+fn replaces(part: &Update) -> bool {
+    matches!(
+        part,
+        Update::Add { .. } | Update::Remove { .. } | Update::Pin { .. } | Update::Unpin { .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(name: &str, ip: &str) -> Update {
+        Update::Add {
+            name: name.into(),
+            ip: ip.parse().unwrap(),
+        }
+    }
+
+    fn remove(name: &str) -> Update {
+        Update::Remove { name: name.into() }
+    }
+
+    fn stamp(timestamp: u64) -> Stamp {
+        Stamp { timestamp, seq: 0 }
+    }
+
+    #[test]
+    fn older_and_repeated_changes_are_dropped() {
+        let mut dots = Dots::default();
+        let ports = Update::Ports {
+            name: "web-1".into(),
+            ports: Vec::new(),
+        };
+        let added = Update::Batch(vec![add("web-1", "10.0.0.1"), ports.clone()]);
+        // The remove overtook the add, which mustn't bring the entry back.
+        assert_eq!(
+            dots.admit("a", stamp(2), remove("web-1"), 0),
+            Some(remove("web-1"))
+        );
+        assert_eq!(dots.admit("a", stamp(1), added.clone(), 0), None);
+        assert_eq!(dots.admit("a", stamp(2), remove("web-1"), 0), None);
+        // Ports and all, as one change.
+        assert_eq!(dots.admit("a", stamp(3), added.clone(), 0), Some(added));
+        assert_eq!(dots.admit("a", stamp(2), ports.clone(), 0), None);
+        // Following the add, or a renewal overtaking the next one.
+        assert_eq!(
+            dots.admit("a", stamp(3), ports.clone(), 0),
+            Some(ports.clone())
+        );
+        assert_eq!(dots.admit("a", stamp(5), ports.clone(), 0), Some(ports));
+        let readded = add("web-1", "10.0.0.2");
+        assert_eq!(dots.admit("a", stamp(4), readded.clone(), 0), Some(readded));
+        // Names are keyed as the map keys them.
+        assert_eq!(dots.admit("a", stamp(4), remove("Web-1"), 0), None);
+        // Within a millisecond, the counter orders them.
+        let later = Stamp {
+            timestamp: 4,
+            seq: 1,
+        };
+        assert_eq!(
+            dots.admit("a", later, remove("web-1"), 0),
+            Some(remove("web-1"))
+        );
+    }
+
+    #[test]
+    fn each_node_and_pin_is_ordered_apart() {
+        let mut dots = Dots::default();
+        let pin = Update::Pin {
+            name: "web-1".into(),
+            ip: "10.0.0.9".parse().unwrap(),
+            expires_at: None,
+        };
+        let evict = Update::EvictNode { node: "b".into() };
+        assert!(dots
+            .admit("a", stamp(5), add("web-1", "10.0.0.1"), 0)
+            .is_some());
+        assert!(dots
+            .admit("b", stamp(1), add("web-1", "10.0.0.2"), 0)
+            .is_some());
+        assert!(dots.admit("a", stamp(1), pin, 0).is_some());
+        // Only the stale part goes; evictions are no entry's.
+        let batch = Update::Batch(vec![add("db", "10.0.0.3"), remove("web-1"), evict.clone()]);
+        assert_eq!(
+            dots.admit("a", stamp(4), batch, 0),
+            Some(Update::Batch(vec![add("db", "10.0.0.3"), evict]))
+        );
+        assert_eq!(dots.latest.len(), 4);
+    }
+
+    #[test]
+    fn stamps_are_forgotten_in_time() {
+        let mut dots = Dots::default();
+        let forget_after = FORGET_AFTER.as_millis() as u64;
+        assert!(dots.admit("a", stamp(2), remove("web-1"), 1).is_some());
+        assert!(dots
+            .admit("a", stamp(1), remove("db"), forget_after)
+            .is_some());
+        assert_eq!(dots.latest.len(), 2);
+        // A minute on, the first is forgotten.
+        let now = forget_after + 60_000;
+        assert!(dots.admit("a", stamp(1), remove("web-1"), now).is_some());
+        assert_eq!(dots.latest.len(), 2);
+    }
+}
@@ -0,0 +1,296 @@
+//! Duplicate and replay suppression for gossip messages.
+//!
+//! Gossip redelivers messages, and a node hears its own broadcasts back.
+//! Each message carries an [`Origin`]; per origin we remember the newest
+//! message applied and drop anything that isn't newer.
+//!
+//! That doesn't stop a captured message from being replayed to a node that
+//! has never heard from its origin, or forgot it, or restarted since.  With
+//! a skew window set, messages stamped further than that from our clock,
+//! either way, are dropped, so a replay has to come within the window.
+//! Within it, the nonces of each origin's recent messages are kept, and a
+//! message repeating one is dropped as a replay.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use crate::wire::Origin;
+
+/// Origins remembered before the least recently heard one is forgotten.
+const DEFAULT_CAPACITY: usize = 4096;
+
+/// Nonces remembered per origin, at most.
+const NONCES_PER_ORIGIN: usize = 256;
+
+/// Why a message was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// We sent it.
+    OwnEcho,
+    /// Same as, or older than, a message already applied from its origin.
+    Duplicate,
+    /// Repeats the nonce of a recent message from its origin.
+    Replay,
+    /// Stamped further from our clock than the skew window; positive when
+    /// the origin's clock is ahead.  `None` for a message not stamped at
+    /// all, which has no place in a window.
+    Skewed { ahead_ms: Option<i64> },
+}
+
+#[derive(Debug, Clone)]
+struct LastSeen {
+    seq: u64,
+    timestamp: u64,
+    /// Nonces of recent messages with their timestamps, oldest first.
+    nonces: VecDeque<(u64, u64)>,
+}
+
+/// Newest message seen per origin node.
+#[derive(Debug)]
+pub struct SeenTable {
+    our_id: [u8; 32],
+    last: HashMap<[u8; 32], LastSeen>,
+    capacity: usize,
+    max_skew: Option<Duration>,
+}
+
+impl SeenTable {
+    pub fn new(our_id: [u8; 32]) -> Self {
+        Self::with_capacity(our_id, DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(our_id: [u8; 32], capacity: usize) -> Self {
+        Self {
+            our_id,
+            last: HashMap::new(),
+            capacity: capacity.max(1),
+            max_skew: None,
+        }
+    }
+
+    /// Drops messages stamped more than `max_skew` from our clock.
+    pub fn with_max_skew(mut self, max_skew: Duration) -> Self {
+        self.max_skew = Some(max_skew);
+        self
+    }
+
+    /// Whether a message without an [`Origin`], from a node that predates
+    /// origin stamping, should be applied: only while there's no skew
+    /// window, since it can't be placed in one or told apart from a replay.
+    pub fn check_unstamped(&self) -> Result<(), Rejection> {
+        match self.max_skew {
+            Some(_) => Err(Rejection::Skewed { ahead_ms: None }),
+            None => Ok(()),
+        }
+    }
+
+    /// Records the message from `origin` carrying `nonce`, received at `now`
+    /// (Unix time in milliseconds), and returns whether it should be applied.
+    ///
+    /// Messages are ordered by timestamp, then sequence number.  The sequence
+    /// number separates messages sent within the same millisecond; when the
+    /// sender restarts (or its counter wraps) a lower sequence number with a
+    /// newer timestamp is still accepted.
+    pub fn check(
+        &mut self,
+        origin: &Origin,
+        nonce: Option<u64>,
+        now: u64,
+    ) -> Result<(), Rejection> {
+        if origin.node == self.our_id {
+            return Err(Rejection::OwnEcho);
+        }
+        if let Some(max_skew) = self.max_skew {
+            let ahead_ms = origin.timestamp as i64 - now as i64;
+            if ahead_ms.unsigned_abs() > max_skew.as_millis() as u64 {
+                return Err(Rejection::Skewed {
+                    ahead_ms: Some(ahead_ms),
+                });
+            }
+        }
+        // Nonces stamped before the window can't come back within it.
+        let window_start = self.max_skew.map_or(0, |max_skew| {
+            now.saturating_sub(max_skew.as_millis() as u64)
+        });
+        match self.last.get_mut(&origin.node) {
+            Some(last) => {
+                while last
+                    .nonces
+                    .front()
+                    .is_some_and(|(at, _)| *at < window_start)
+                {
+                    last.nonces.pop_front();
+                }
+                if let Some(nonce) = nonce {
+                    if last.nonces.iter().any(|(_, seen)| *seen == nonce) {
+                        return Err(Rejection::Replay);
+                    }
+                }
+                if (origin.timestamp, origin.seq) <= (last.timestamp, last.seq) {
+                    return Err(Rejection::Duplicate);
+                }
+                last.seq = origin.seq;
+                last.timestamp = origin.timestamp;
+                if let Some(nonce) = nonce {
+                    if last.nonces.len() >= NONCES_PER_ORIGIN {
+                        last.nonces.pop_front();
+                    }
+                    last.nonces.push_back((origin.timestamp, nonce));
+                }
+            }
+            None => {
+                if self.last.len() >= self.capacity {
+                    self.evict_oldest();
+                }
+                let seen = LastSeen {
+                    seq: origin.seq,
+                    timestamp: origin.timestamp,
+                    nonces: nonce
+                        .map(|nonce| (origin.timestamp, nonce))
+                        .into_iter()
+                        .collect(),
+                };
+                self.last.insert(origin.node, seen);
+            }
+        }
+        Ok(())
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some(node) = self
+            .last
+            .iter()
+            .min_by_key(|(_, seen)| seen.timestamp)
+            .map(|(node, _)| *node)
+        {
+            self.last.remove(&node);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const US: [u8; 32] = [0; 32];
+    const PEER: [u8; 32] = [1; 32];
+
+    fn origin(node: [u8; 32], seq: u64, timestamp: u64) -> Origin {
+        Origin {
+            node,
+            seq,
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn drops_own_echoes_and_redeliveries() {
+        let mut seen = SeenTable::new(US);
+        assert_eq!(
+            seen.check(&origin(US, 1, 1000), None, 1000),
+            Err(Rejection::OwnEcho)
+        );
+
+        assert_eq!(seen.check(&origin(PEER, 1, 1000), None, 1000), Ok(()));
+        assert_eq!(
+            seen.check(&origin(PEER, 1, 1000), None, 1000),
+            Err(Rejection::Duplicate)
+        );
+        assert_eq!(seen.check(&origin(PEER, 2, 1001), None, 1001), Ok(()));
+        // A late copy of an older message.
+        assert_eq!(
+            seen.check(&origin(PEER, 1, 1000), None, 1000),
+            Err(Rejection::Duplicate)
+        );
+    }
+
+    #[test]
+    fn accepts_sequence_reset_with_newer_timestamp() {
+        let mut seen = SeenTable::new(US);
+        assert_eq!(seen.check(&origin(PEER, 500, 1000), None, 1000), Ok(()));
+        // Peer restarted: counter back at zero, clock moved on.
+        assert_eq!(seen.check(&origin(PEER, 0, 5000), None, 5000), Ok(()));
+        assert_eq!(seen.check(&origin(PEER, 1, 5001), None, 5001), Ok(()));
+        // A stale pre-restart message still in flight.
+        assert_eq!(
+            seen.check(&origin(PEER, 501, 1001), None, 1001),
+            Err(Rejection::Duplicate)
+        );
+    }
+
+    #[test]
+    fn accepts_wraparound() {
+        let mut seen = SeenTable::new(US);
+        assert_eq!(
+            seen.check(&origin(PEER, u64::MAX, 1000), None, 1000),
+            Ok(())
+        );
+        assert_eq!(seen.check(&origin(PEER, 0, 1001), None, 1001), Ok(()));
+    }
+
+    #[test]
+    fn drops_messages_outside_the_skew_window() {
+        let mut seen = SeenTable::new(US).with_max_skew(Duration::from_secs(300));
+        let now = 1_000_000;
+        assert_eq!(
+            seen.check(&origin(PEER, 1, now - 300_000), Some(1), now),
+            Ok(())
+        );
+        assert_eq!(
+            seen.check(&origin(PEER, 2, now - 300_001), Some(2), now),
+            Err(Rejection::Skewed {
+                ahead_ms: Some(-300_001)
+            })
+        );
+        assert_eq!(
+            seen.check(&origin(PEER, 3, now + 300_001), Some(3), now),
+            Err(Rejection::Skewed {
+                ahead_ms: Some(300_001)
+            })
+        );
+        assert_eq!(
+            seen.check(&origin(PEER, 4, now + 1_000), Some(4), now),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn unstamped_messages_only_pass_without_a_window() {
+        assert_eq!(SeenTable::new(US).check_unstamped(), Ok(()));
+        let seen = SeenTable::new(US).with_max_skew(Duration::from_secs(300));
+        assert_eq!(
+            seen.check_unstamped(),
+            Err(Rejection::Skewed { ahead_ms: None })
+        );
+    }
+
+    #[test]
+    fn drops_replayed_nonces_within_the_window() {
+        let mut seen = SeenTable::new(US).with_max_skew(Duration::from_secs(300));
+        let now = 1_000_000;
+        assert_eq!(seen.check(&origin(PEER, 1, now), Some(7), now), Ok(()));
+        // A copy is caught by its nonce before its age.
+        assert_eq!(
+            seen.check(&origin(PEER, 1, now), Some(7), now + 1),
+            Err(Rejection::Replay)
+        );
+        assert_eq!(
+            seen.check(&origin(PEER, 2, now + 1), Some(8), now + 1),
+            Ok(())
+        );
+        // Nonces are forgotten once their messages are out of the window.
+        let later = now + 300_001;
+        assert_eq!(seen.check(&origin(PEER, 3, later), Some(7), later), Ok(()));
+    }
+
+    #[test]
+    fn forgets_least_recent_origin_at_capacity() {
+        let mut seen = SeenTable::with_capacity(US, 2);
+        assert_eq!(seen.check(&origin([1; 32], 1, 1000), None, 1000), Ok(()));
+        assert_eq!(seen.check(&origin([2; 32], 1, 2000), None, 2000), Ok(()));
+        assert_eq!(seen.check(&origin([3; 32], 1, 3000), None, 3000), Ok(()));
+        assert_eq!(seen.last.len(), 2);
+        // The oldest origin was forgotten, so its redelivery is accepted again.
+        assert_eq!(seen.check(&origin([1; 32], 1, 1000), None, 1000), Ok(()));
+    }
+}
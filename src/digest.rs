@@ -0,0 +1,151 @@
+//! A digest of the entries every node should agree on.
+//!
+//! Each node broadcasts the count and a hash of the entries gossip keeps in
+//! step: those published by replicas or pinned by operators, in the shared
+//! namespace, and those set aside for names several nodes publish.  Which
+//! of those is served may differ from node to node, as may static records,
+//! the hosts file and network entries, so none of that counts.  A peer
+//! whose digest keeps differing from ours has missed updates, or we have.
+//!
+//! Each entry hashes its name and address, and the hashes are summed, so
+//! the digest follows the map name by name as updates are applied instead
+//! of hashing every entry again for each heartbeat.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::conflicts::Conflicts;
+use crate::types::{Entry, Source, StateMap};
+
+/// The entries counted and the sum of their hashes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateDigest {
+    pub entries: u64,
+    pub hash: u64,
+}
+
+/// The digest of a map, and what each name adds to it.
+#[derive(Debug, Default)]
+pub struct DigestTable {
+    counted: HashMap<String, StateDigest>,
+    digest: StateDigest,
+}
+
+impl DigestTable {
+    pub fn digest(&self) -> StateDigest {
+        self.digest
+    }
+
+    /// Counts `name` again as it now is in `map` and `conflicts`.
+    pub fn update(&mut self, map: &StateMap, conflicts: &Conflicts, name: &str) {
+        if let Some(counted) = self.counted.remove(name) {
+            self.digest.entries -= counted.entries;
+            self.digest.hash = self.digest.hash.wrapping_sub(counted.hash);
+        }
+        if name.strip_prefix("*.").unwrap_or(name).contains('.') {
+            return;
+        }
+        let mut counted = StateDigest::default();
+        let mut count = |ip: IpAddr| {
+            counted.entries += 1;
+            counted.hash = counted.hash.wrapping_add(entry_hash(name, ip));
+        };
+        for entry in map.get(name).into_iter().flat_map(claims) {
+            count(entry.ip);
+        }
+        conflicts.set_aside_ips(name).into_iter().for_each(count);
+        if counted.entries == 0 {
+            return;
+        }
+        self.digest.entries += counted.entries;
+        self.digest.hash = self.digest.hash.wrapping_add(counted.hash);
+        self.counted.insert(name.to_string(), counted);
+    }
+
+    /// Counts all of `map` and `conflicts` again.
+    pub fn recount(&mut self, map: &StateMap, conflicts: &Conflicts) {
+        *self = Self::default();
+        for name in map.keys().chain(conflicts.names()) {
+            self.update(map, conflicts, name);
+        }
+    }
+}
+
+/// The entries under `entry` gossip keeps the same on every node: a pin,
+/// the container entry it hides, or a container entry alone.
+fn claims(entry: &Entry) -> Vec<&Entry> {
+    match entry.source {
+        Source::Cluster => vec![entry],
+        Source::Manual => std::iter::once(entry)
+            .chain(entry.shadowed.as_deref())
+            .collect(),
+        Source::Static | Source::Hosts => Vec::new(),
+    }
+}
+
+fn entry_hash(name: &str, ip: IpAddr) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(name.as_bytes());
+    hasher.update([0]);
+    hasher.update(ip.to_string().as_bytes());
+    let hash = hasher.finalize();
+    u64::from_be_bytes(hash[..8].try_into().expect("SHA-256 is 32 bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn updates_by_name_match_a_recount() {
+        let mut map = StateMap::new();
+        let mut table = DigestTable::default();
+        let none = Conflicts::default();
+        for (name, ip) in [
+            ("web-1", "10.0.0.2"),
+            ("*.web", "10.0.0.3"),
+            ("db", "10.0.0.4"),
+        ] {
+            map.insert(name.into(), Entry::new(ip.parse().unwrap()));
+            table.update(&map, &none, name);
+        }
+        // Neither a static record nor a network's entry counts.
+        map.insert(
+            "nas".into(),
+            Entry {
+                source: Source::Static,
+                ..Entry::new("192.168.1.5".parse().unwrap())
+            },
+        );
+        table.update(&map, &none, "nas");
+        map.insert(
+            "web-1.net-a".into(),
+            Entry::new("10.1.0.2".parse().unwrap()),
+        );
+        table.update(&map, &none, "web-1.net-a");
+        assert_eq!(table.digest().entries, 3);
+
+        map.get_mut("web-1").unwrap().ip = "10.0.0.9".parse().unwrap();
+        table.update(&map, &none, "web-1");
+        map.remove("db");
+        table.update(&map, &none, "db");
+        let digest = table.digest();
+        assert_eq!(digest.entries, 2);
+        let mut recounted = DigestTable::default();
+        recounted.recount(&map, &none);
+        assert_eq!(recounted.digest(), digest);
+
+        // The same entries make the same digest, however they came.
+        let mut other = StateMap::new();
+        other.insert("*.web".into(), Entry::new("10.0.0.3".parse().unwrap()));
+        other.insert("web-1".into(), Entry::new("10.0.0.9".parse().unwrap()));
+        recounted.recount(&other, &none);
+        assert_eq!(recounted.digest(), digest);
+        other.insert("web-1".into(), Entry::new("10.0.0.2".parse().unwrap()));
+        recounted.recount(&other, &none);
+        assert_ne!(recounted.digest(), digest);
+    }
+}
@@ -7,202 +7,1416 @@
 //!
 //! * **Single‑label names** (no dots): treated as container names.  The
 //!   server looks up the name in the shared state map and, if
-//!   present, returns an A or AAAA record with the container's IP.
-//! * **FQDNs** (names containing a dot): forwarded to upstream
-//!   resolvers using the `hickory-resolver` crate.
+//!   present, returns an A or AAAA record with the container's IP, or
+//!   the addresses and TXT strings of a static record.  With
+//!   `txt_metadata`, TXT queries for cluster names also say which node
+//!   published the name and when.
+//! * **FQDNs** (names containing a dot): answered from the state map when a
+//!   hosts file provides them or they fall under a wildcard name
+//!   (`anything.web-1`), otherwise forwarded to upstream
+//!   resolvers using the `hickory-resolver` crate; see [`crate::forward`]
+//!   for per-zone upstreams.
+//!
+//! SRV queries for `_service._proto.<name>` list the ports `<name>`
+//! exposes; see [`crate::srv`].
+//!
+//! Which of a name's addresses a client gets depends on the view its
+//! address falls in; see [`crate::views`].
+//!
+//! With `local_domain` set, `<name>.<local_domain>` is answered like
+//! `<name>` and never forwarded; see [`crate::local_zone`] for the SOA and
+//! NS records of the zone apex.  The apex, and names of ours with none of
+//! their own but names below them (`b` when `a.b` is registered), answer
+//! NODATA rather than NXDOMAIN, so that resolvers minimizing query names
+//! (RFC 9156) carry on down to the names below.  Secondaries in
+//! `axfr_allow` may transfer the zone over TCP; an IXFR is answered with
+//! the whole zone too.
+//!
+//! CHAOS-class queries are answered by [`crate::chaos`] and never reach
+//! any of this; nor do `_glued.cluster` and `_glued.nodes`, answered as
+//! [`crate::cluster_info`] describes.
+//!
+//! Clients are checked against [`crate::acl`] before any of this; refused
+//! clients get REFUSED without a lookup.  UDP clients are also rate limited
+//! by [`crate::rrl`].  Then opcodes other than QUERY (NOTIFY, UPDATE, ...)
+//! get NOTIMP, and classes other than IN and CHAOS get REFUSED, before the
+//! state map or an upstream is consulted.
+//!
+//! Responses to clients that sent EDNS carry an OPT record offering
+//! 1232-byte UDP payloads; refusals and failures add an extended DNS error
+//! saying why, as [`crate::ede`] describes.
 
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
+use hickory_resolver::config::ResolverOpts;
+use hickory_resolver::error::ResolveErrorKind;
 use hickory_resolver::TokioAsyncResolver;
 use hickory_server::authority::MessageResponseBuilder;
-use hickory_server::proto::op::{Header, ResponseCode};
-use hickory_server::proto::rr::rdata::{A, AAAA};
-use hickory_server::proto::rr::{RData, Record, RecordType};
+use hickory_server::proto::op::{Edns, Header, Message, OpCode, ResponseCode};
+use hickory_server::proto::rr::rdata::opt::EdnsOption;
+use hickory_server::proto::rr::rdata::{A, AAAA, HINFO, TXT};
+use hickory_server::proto::rr::{DNSClass, LowerName, Name, RData, Record, RecordType};
 use hickory_server::server::{
-    Request, RequestHandler, ResponseHandler, ResponseInfo, ServerFuture,
+    Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo, ServerFuture,
 };
-use log::{error, info, warn};
+use log::{debug, error, info, warn, Level};
+use socket2::{Domain, Socket, Type};
 use tokio::net::{TcpListener, UdpSocket};
-use tokio::sync::RwLock;
-use tokio::time::Duration;
+use tokio::sync::{watch, Semaphore};
+use tracing::{info_span, Instrument};
 
-/// Timeout for idle TCP connections.
-const TCP_TIMEOUT: Duration = Duration::from_secs(10);
+use crate::acl::DnsAcl;
+use crate::chaos::ChaosIdentity;
+use crate::cluster_info;
+use crate::config::{Config, ForwardZone};
+use crate::dns_tcp::{serve_tcp, TcpLimits};
+use crate::dns_tls::TlsListener;
+use crate::ede::ExtendedError;
+use crate::forward::{system_resolver, ForwardLimits, TtlClamp, Upstreams};
+use crate::local_zone::{InZone, LocalZone};
+use crate::log_dedup::LogDedup;
+use crate::metrics::{self, inc, QueryOutcome, METRICS};
+use crate::names::normalize;
+use crate::negative_cache::NegativeCache;
+use crate::networks::Networks;
+use crate::resolver::GluedResolver;
+use crate::rrl::{RateLimiter, RatePolicy, Verdict};
+use crate::srv::{self, SrvQuery};
+use crate::status::Status;
+use crate::txt;
+use crate::types::{Entry, SharedState};
+use crate::views::Views;
 
-/// Start the DNS server.
-pub async fn run_dns_server(
-    bind_addr: SocketAddr,
-    state: Arc<RwLock<HashMap<String, String>>>,
-) -> anyhow::Result<()> {
-    info!("DNS server starting on {}", bind_addr);
+/// Largest UDP payload we send, whatever the client advertises.  1232 bytes
+/// avoids IP fragmentation on common paths (DNS flag day 2020).
+const UDP_MAX_PAYLOAD: u16 = 1232;
 
-    // Create a system resolver for forwarding FQDNs.
-    let resolver = TokioAsyncResolver::tokio_from_system_conf().unwrap_or_else(|e| {
-        error!(
-            "Failed to load system resolv.conf: {}. Falling back to Google DNS.",
-            e
-        );
-        TokioAsyncResolver::tokio_from_system_conf().unwrap_or_else(|_| {
-            panic!("Failed to create DNS resolver: {}", e);
+/// TTL of the HINFO record sent for ANY queries with `minimal_any`.
+const ANY_HINFO_TTL: u32 = 3600;
+
+/// Records per message of a zone transfer, which keeps each well within
+/// the 64 KiB a TCP message may hold.
+const TRANSFER_CHUNK: usize = 100;
+
+/// Settings for the DNS server.
+#[derive(Debug, Clone)]
+pub struct DnsOptions {
+    pub tcp: TcpLimits,
+    /// Forward non-local names upstream; otherwise they are REFUSED.
+    pub forwarding: bool,
+    pub forward_zones: BTreeMap<String, ForwardZone>,
+    pub forward_limits: ForwardLimits,
+    /// Bounds on the TTLs of forwarded answers.
+    pub forward_ttl: TtlClamp,
+    /// Where upstreams' NXDOMAIN answers are kept.
+    pub negative_cache: NegativeCache,
+    pub acl: DnsAcl,
+    pub rate_limit: RatePolicy,
+    /// Answer ANY with a single HINFO record (RFC 8482).
+    pub minimal_any: bool,
+    /// Add a TXT record naming who published a cluster entry, and when.
+    pub txt_metadata: bool,
+    /// What CHAOS TXT queries are answered with; `None` refuses them.
+    pub chaos: Option<ChaosIdentity>,
+    /// Answer `_glued.cluster` and `_glued.nodes` locally.
+    pub cluster_info: bool,
+    /// Names answering for every name below them, normalized.
+    pub wildcard_names: HashSet<String>,
+    /// DNS over TLS, if configured.
+    pub tls: Option<TlsListener>,
+    /// The zone we are authoritative for, if configured.
+    pub local_zone: Option<LocalZone>,
+    /// Namespaces of the `networks`, answered by suffix or client subnet.
+    pub networks: Networks,
+    /// Which scope of addresses each client network gets.
+    pub views: Views,
+    /// Answers SERVFAIL instead of NXDOMAIN while it is warming up; none
+    /// never does.
+    pub status: Option<Arc<Status>>,
+    /// How long a miss during the warm-up waits for it to end.
+    pub warmup_delay: Duration,
+    /// Reloaded configs, whose ACLs and forward zones replace these.
+    pub reloads: Option<watch::Receiver<Arc<Config>>>,
+}
+
+impl DnsOptions {
+    /// Fails if the DNS-over-TLS certificate or key can't be loaded, the
+    /// local zone's names or the networks are invalid or a forwarding limit
+    /// is zero.
+    pub fn from_config(cfg: &Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            tcp: TcpLimits::from_config(cfg),
+            forwarding: cfg.forwarding,
+            forward_zones: cfg.forward_zones.clone(),
+            forward_limits: ForwardLimits::from_config(cfg)?,
+            forward_ttl: TtlClamp::from_config(cfg)?,
+            negative_cache: NegativeCache::from_config(cfg),
+            acl: DnsAcl::from_config(cfg),
+            rate_limit: RatePolicy::from_config(cfg),
+            minimal_any: cfg.dns_minimal_any,
+            txt_metadata: cfg.dns_txt_metadata,
+            chaos: ChaosIdentity::from_config(cfg),
+            cluster_info: cfg.dns_cluster_info,
+            wildcard_names: cfg.wildcard_names.iter().map(|n| normalize(n)).collect(),
+            tls: TlsListener::from_config(cfg)?,
+            local_zone: LocalZone::from_config(cfg)?,
+            networks: Networks::from_config(cfg)?,
+            views: Views::from_config(cfg),
+            status: None,
+            warmup_delay: Duration::from_millis(cfg.dns_warmup_delay_ms),
+            reloads: None,
         })
-    });
+    }
+}
+
+impl Default for DnsOptions {
+    fn default() -> Self {
+        Self::from_config(&Config::default()).expect("the default config is valid")
+    }
+}
+
+/// The sockets the DNS server answers on, bound before it starts so that
+/// startup can fail when they can't be.
+#[derive(Debug)]
+pub struct DnsSockets {
+    udp: Vec<UdpSocket>,
+    tcp: Vec<TcpListener>,
+}
+
+impl DnsSockets {
+    /// Binds UDP and TCP on each of `binds`, trying `fallback_ports` in
+    /// order when an address's own port is taken.  An address that can't be
+    /// bound at all is an error, unless `best_effort` is set and another
+    /// one could be.
+    pub fn bind(
+        binds: &[SocketAddr],
+        fallback_ports: &[u16],
+        best_effort: bool,
+    ) -> anyhow::Result<Self> {
+        Self::bind_with(binds, fallback_ports, best_effort, false)
+    }
 
-    let handler = GluedDns { state, resolver };
-    let mut server = ServerFuture::new(handler);
+    /// Binds like [`DnsSockets::bind`], with `SO_REUSEPORT` set, so another
+    /// process doing the same can serve the same addresses, as during a
+    /// handover between instances.  The kernel spreads queries between
+    /// them.  Unix only; elsewhere it binds as `bind` does.
+    pub fn bind_sharing_port(
+        binds: &[SocketAddr],
+        fallback_ports: &[u16],
+        best_effort: bool,
+    ) -> anyhow::Result<Self> {
+        Self::bind_with(binds, fallback_ports, best_effort, true)
+    }
 
-    // Register UDP listener.
-    let udp = UdpSocket::bind(bind_addr).await?;
-    server.register_socket(udp);
+    fn bind_with(
+        binds: &[SocketAddr],
+        fallback_ports: &[u16],
+        best_effort: bool,
+        reuse_port: bool,
+    ) -> anyhow::Result<Self> {
+        let mut sockets = Self {
+            udp: Vec::with_capacity(binds.len()),
+            tcp: Vec::with_capacity(binds.len()),
+        };
+        for &addr in binds {
+            match bind_with_fallback(addr, fallback_ports, reuse_port) {
+                Ok((udp, tcp)) => {
+                    sockets.udp.push(udp);
+                    sockets.tcp.push(tcp);
+                }
+                Err(e) if best_effort => warn!("{:#}; serving DNS without it", e),
+                Err(e) => return Err(e),
+            }
+        }
+        if sockets.udp.is_empty() {
+            anyhow::bail!("DNS server could not bind any address");
+        }
+        Ok(sockets)
+    }
 
-    // Register TCP listener.
-    let tcp = TcpListener::bind(bind_addr).await?;
-    server.register_listener(tcp, TCP_TIMEOUT);
+    /// The addresses actually bound, in `binds` order.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.udp
+            .iter()
+            .filter_map(|socket| socket.local_addr().ok())
+            .collect()
+    }
+}
+
+/// Binds `addr`, or failing that the same IP on the first free fallback port.
+fn bind_with_fallback(
+    addr: SocketAddr,
+    fallback_ports: &[u16],
+    reuse_port: bool,
+) -> anyhow::Result<(UdpSocket, TcpListener)> {
+    let first_error = match bind(addr, reuse_port) {
+        Ok(bound) => return Ok(bound),
+        Err(e) => e,
+    };
+    for &port in fallback_ports.iter().filter(|&&port| port != addr.port()) {
+        let fallback = SocketAddr::new(addr.ip(), port);
+        match bind(fallback, reuse_port) {
+            Ok(bound) => {
+                warn!("{:#}; serving DNS on {} instead", first_error, fallback);
+                return Ok(bound);
+            }
+            Err(e) => debug!("Fallback DNS port unavailable: {:#}", e),
+        }
+    }
+    if fallback_ports.is_empty() {
+        Err(first_error)
+    } else {
+        Err(first_error.context(format!(
+            "none of the fallback ports {:?} were free either",
+            fallback_ports
+        )))
+    }
+}
+
+/// Binds UDP and TCP on `addr`.  IPv6 sockets are IPv6-only, so `[::]` and
+/// `0.0.0.0` can both be bound.
+fn bind(addr: SocketAddr, reuse_port: bool) -> anyhow::Result<(UdpSocket, TcpListener)> {
+    let udp = bind_socket(addr, Type::DGRAM, socket2::Protocol::UDP, reuse_port)
+        .and_then(|socket| UdpSocket::from_std(socket.into()))
+        .map_err(|e| bind_error(e, addr, "UDP"))?;
+    // Same port as UDP, should `addr` have asked for any.
+    let addr = udp.local_addr()?;
+    let tcp = bind_socket(addr, Type::STREAM, socket2::Protocol::TCP, reuse_port)
+        .and_then(|socket| {
+            socket.listen(1024)?;
+            TcpListener::from_std(socket.into())
+        })
+        .map_err(|e| bind_error(e, addr, "TCP"))?;
+    Ok((udp, tcp))
+}
+
+/// Explains a failure to bind `addr`, with the usual causes.
+fn bind_error(e: std::io::Error, addr: SocketAddr, protocol: &str) -> anyhow::Error {
+    let hint = match e.kind() {
+        std::io::ErrorKind::AddrInUse if addr.port() == 53 => {
+            ". Something else already serves DNS on port 53; on hosts running \
+             systemd-resolved that is its stub listener (set DNSStubListener=no in \
+             /etc/systemd/resolved.conf), or bind a specific address or set \
+             dns_fallback_ports"
+        }
+        std::io::ErrorKind::PermissionDenied if addr.port() < 1024 => {
+            ". Ports below 1024 need root or CAP_NET_BIND_SERVICE"
+        }
+        _ => "",
+    };
+    anyhow::anyhow!(
+        "Failed to bind DNS on {} ({}, port {}): {}{}",
+        addr,
+        protocol,
+        addr.port(),
+        e,
+        hint
+    )
+}
+
+fn bind_socket(
+    addr: SocketAddr,
+    kind: Type,
+    protocol: socket2::Protocol,
+    reuse_port: bool,
+) -> std::io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), kind, Some(protocol))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    if kind == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    #[cfg(unix)]
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    #[cfg(not(unix))]
+    let _ = reuse_port;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+/// Serves DNS on an already bound socket and listener until the server
+/// shuts down.
+pub async fn serve_dns(
+    udp: UdpSocket,
+    tcp: TcpListener,
+    state: SharedState,
+    options: DnsOptions,
+) -> anyhow::Result<()> {
+    let sockets = DnsSockets {
+        udp: vec![udp],
+        tcp: vec![tcp],
+    };
+    run_dns_server(sockets, state, options).await
+}
+
+/// Serves DNS on `sockets` until the server shuts down.
+pub async fn run_dns_server(
+    sockets: DnsSockets,
+    state: SharedState,
+    options: DnsOptions,
+) -> anyhow::Result<()> {
+    let upstreams = if options.forwarding {
+        // Create a system resolver for forwarding FQDNs.
+        let resolver = system_resolver();
+        let upstreams = Upstreams::new(
+            resolver.clone(),
+            &options.forward_zones,
+            ResolverOpts::default(),
+        )?;
+        Some((resolver, Arc::new(ArcSwap::from_pointee(upstreams))))
+    } else {
+        info!("Forwarding disabled; answering only local names");
+        if !options.forward_zones.is_empty() {
+            warn!("forward_zones are ignored with forwarding = false");
+        }
+        None
+    };
+    let handler = GluedDns::new(
+        state,
+        &options,
+        upstreams
+            .as_ref()
+            .map(|(_, upstreams)| Arc::clone(upstreams)),
+    );
+    let reloads = follow_reloads(
+        options.reloads,
+        options.forward_zones,
+        Arc::clone(&handler.acl),
+        upstreams,
+        Arc::clone(&handler.negative_cache),
+    );
+    let mut server = ServerFuture::new(handler.clone());
 
-    // Run the server until future resolves.
-    server.block_until_done().await?;
+    for socket in sockets.udp {
+        server.register_socket(socket);
+    }
+
+    if let Some(tls) = options.tls {
+        let listener = TcpListener::bind(tls.bind).await?;
+        info!("DNS over TLS on {}", tls.bind);
+        server.register_tls_listener_with_tls_config(
+            listener,
+            options.tcp.idle_timeout,
+            tls.config,
+        )?;
+    }
+
+    // TCP is served separately so that connections can be limited.
+    tokio::select! {
+        result = server.block_until_done() => result?,
+        () = serve_tcp(sockets.tcp, handler, options.tcp) => {}
+        () = reloads => {}
+    }
     Ok(())
 }
 
+/// Swaps in the ACLs and forward zones of each reloaded config, forgetting
+/// the NXDOMAIN answers of the old zones.  Never returns.
+async fn follow_reloads(
+    reloads: Option<watch::Receiver<Arc<Config>>>,
+    mut forward_zones: BTreeMap<String, ForwardZone>,
+    acl: Arc<ArcSwap<DnsAcl>>,
+    upstreams: Option<(TokioAsyncResolver, Arc<ArcSwap<Upstreams>>)>,
+    negative_cache: Arc<Mutex<NegativeCache>>,
+) {
+    let Some(mut reloads) = reloads else {
+        return std::future::pending().await;
+    };
+    while reloads.changed().await.is_ok() {
+        let cfg = Arc::clone(&reloads.borrow_and_update());
+        acl.store(Arc::new(DnsAcl::from_config(&cfg)));
+        let Some((resolver, upstreams)) = &upstreams else {
+            continue;
+        };
+        // Rebuilt only on a change, which starts their caches over.
+        if cfg.forward_zones == forward_zones {
+            continue;
+        }
+        match Upstreams::new(
+            resolver.clone(),
+            &cfg.forward_zones,
+            ResolverOpts::default(),
+        ) {
+            Ok(rebuilt) => {
+                upstreams.store(Arc::new(rebuilt));
+                let mut cache = negative_cache.lock().unwrap();
+                METRICS
+                    .dns_negative_cache_entries
+                    .fetch_sub(cache.len() as u64, Ordering::Relaxed);
+                cache.clear();
+                forward_zones = cfg.forward_zones.clone();
+            }
+            Err(e) => error!("Keeping the forward zones in use: {:#}", e),
+        }
+    }
+    std::future::pending().await
+}
+
+/// The EDNS record for a response, if the request carried one.
+fn response_edns(request: &Request) -> Option<Edns> {
+    request.edns().map(|_| {
+        let mut edns = Edns::new();
+        edns.set_max_payload(UDP_MAX_PAYLOAD);
+        edns
+    })
+}
+
+/// Whether `answers` and `authority` would exceed the payload size the
+/// client accepts over UDP.
+fn exceeds_udp_payload(
+    request: &Request,
+    header: &Header,
+    answers: &[Record],
+    authority: &[Record],
+) -> bool {
+    if !matches!(request.protocol(), Protocol::Udp) {
+        return false;
+    }
+    // `max_payload` is 512 without EDNS, and never less.
+    let limit = request.max_payload().min(UDP_MAX_PAYLOAD);
+    let mut message = Message::new();
+    message
+        .set_header(*header)
+        .add_query(request.query().original().clone())
+        .add_answers(answers.iter().cloned())
+        .add_name_servers(authority.iter().cloned());
+    if let Some(edns) = response_edns(request) {
+        message.set_edns(edns);
+    }
+    message
+        .to_vec()
+        .map_or(true, |bytes| bytes.len() > usize::from(limit))
+}
+
+/// Sends `answers`.
+async fn respond<R: ResponseHandler>(
+    request: &Request,
+    response_handle: R,
+    header: Header,
+    answers: &[Record],
+) -> ResponseInfo {
+    respond_with_authority(request, response_handle, header, answers, &[]).await
+}
+
+/// Sends `answers`, and `authority` (the zone SOA of a negative answer).
+async fn respond_with_authority<R: ResponseHandler>(
+    request: &Request,
+    response_handle: R,
+    header: Header,
+    answers: &[Record],
+    authority: &[Record],
+) -> ResponseInfo {
+    send(request, response_handle, header, answers, authority, None).await
+}
+
+/// Sends the response code of `error`, with it as an extended DNS error
+/// naming request `id` for clients that sent EDNS.
+async fn fail<R: ResponseHandler>(
+    request: &Request,
+    response_handle: R,
+    mut header: Header,
+    error: ExtendedError,
+    id: &str,
+) -> ResponseInfo {
+    header.set_response_code(error.response_code());
+    let option = Some(error.option(id));
+    send(request, response_handle, header, &[], &[], option).await
+}
+
+/// Sends a response, with `option` in its OPT record if the request had
+/// one.  A UDP response that would not fit is sent empty with the TC flag
+/// set, so the client retries over TCP.
+async fn send<R: ResponseHandler>(
+    request: &Request,
+    mut response_handle: R,
+    mut header: Header,
+    answers: &[Record],
+    authority: &[Record],
+    option: Option<EdnsOption>,
+) -> ResponseInfo {
+    let (answers, authority) = if exceeds_udp_payload(request, &header, answers, authority) {
+        header.set_truncated(true);
+        (&[][..], &[][..])
+    } else {
+        (answers, authority)
+    };
+    let mut builder = MessageResponseBuilder::from_message_request(request);
+    if let Some(mut edns) = response_edns(request) {
+        if let Some(option) = option {
+            edns.options_mut().insert(option);
+        }
+        builder.edns(edns);
+    }
+    let response = builder.build(
+        header,
+        answers.iter(),
+        authority.iter(),
+        std::iter::empty(),
+        std::iter::empty(),
+    );
+    match response_handle.send_response(response).await {
+        Ok(info) => info,
+        Err(e) => {
+            warn!("Failed to send DNS response to {}: {}", request.src(), e);
+            header.into()
+        }
+    }
+}
+
+#[derive(Clone)]
 struct GluedDns {
-    state: Arc<RwLock<HashMap<String, String>>>,
-    resolver: TokioAsyncResolver,
+    state: SharedState,
+    resolver: GluedResolver,
+    /// `None` in authoritative-only mode.
+    upstreams: Option<Arc<ArcSwap<Upstreams>>>,
+    forward_timeout: Duration,
+    /// One permit per forwarded lookup in flight.
+    forward_slots: Arc<Semaphore>,
+    /// Queries waiting for one of those permits.
+    forward_queued: Arc<AtomicUsize>,
+    max_queued: usize,
+    forward_limiter: Arc<RateLimiter>,
+    forward_ttl: TtlClamp,
+    /// Why forwarded lookups fail, logged once a minute while they do.
+    forward_failures: Arc<Mutex<LogDedup<&'static str>>>,
+    negative_cache: Arc<Mutex<NegativeCache>>,
+    acl: Arc<ArcSwap<DnsAcl>>,
+    limiter: Arc<RateLimiter>,
+    minimal_any: bool,
+    txt_metadata: bool,
+    chaos: Option<Arc<ChaosIdentity>>,
+    cluster_info: bool,
+    local_zone: Option<Arc<LocalZone>>,
+    status: Option<Arc<Status>>,
+    warmup_delay: Duration,
+}
+
+impl GluedDns {
+    /// A handler answering from `state` as `options` say, forwarding to
+    /// `upstreams` if given.
+    fn new(
+        state: SharedState,
+        options: &DnsOptions,
+        upstreams: Option<Arc<ArcSwap<Upstreams>>>,
+    ) -> Self {
+        let limits = &options.forward_limits;
+        let resolver = GluedResolver::new(Arc::clone(&state))
+            .with_wildcard_names(options.wildcard_names.clone())
+            .with_networks(options.networks.clone())
+            .with_views(options.views.clone());
+        Self {
+            state,
+            resolver,
+            upstreams,
+            forward_timeout: limits.timeout,
+            forward_slots: Arc::new(Semaphore::new(limits.max_inflight)),
+            forward_queued: Arc::default(),
+            max_queued: limits.max_queued,
+            forward_limiter: Arc::new(RateLimiter::new(limits.rate)),
+            forward_ttl: options.forward_ttl,
+            forward_failures: Arc::new(Mutex::new(LogDedup::new(module_path!(), Level::Warn))),
+            negative_cache: Arc::new(Mutex::new(options.negative_cache.clone())),
+            acl: Arc::new(ArcSwap::from_pointee(options.acl.clone())),
+            limiter: Arc::new(RateLimiter::new(options.rate_limit)),
+            minimal_any: options.minimal_any,
+            txt_metadata: options.txt_metadata,
+            chaos: options.chaos.clone().map(Arc::new),
+            cluster_info: options.cluster_info,
+            local_zone: options.local_zone.clone().map(Arc::new),
+            status: options.status.clone(),
+            warmup_delay: options.warmup_delay,
+        }
+    }
+
+    /// The records answering `_glued.cluster` or `_glued.nodes` (`qname`),
+    /// never cached as they change with every entry.
+    async fn cluster_records(&self, qname: &str, qtype: RecordType, owner: &Name) -> Vec<Record> {
+        let map = self.state.read().await;
+        let txt = qtype == RecordType::TXT || qtype == RecordType::ANY;
+        let mut rdatas = Vec::new();
+        if qname == cluster_info::CLUSTER {
+            if txt {
+                let peers = self
+                    .status
+                    .as_deref()
+                    .map(|status| status.peers().neighbors());
+                rdatas.push(RData::TXT(TXT::new(cluster_info::summary(&map, peers))));
+            }
+        } else {
+            for (name, entry) in cluster_info::nodes(&map) {
+                match entry.ip {
+                    IpAddr::V4(ipv4) if qtype == RecordType::A || qtype == RecordType::ANY => {
+                        rdatas.push(RData::A(A(ipv4)));
+                    }
+                    IpAddr::V6(ipv6) if qtype == RecordType::AAAA || qtype == RecordType::ANY => {
+                        rdatas.push(RData::AAAA(AAAA(ipv6)));
+                    }
+                    _ => {}
+                }
+                if txt {
+                    rdatas.push(RData::TXT(TXT::new(vec![format!("{}={}", name, entry.ip)])));
+                }
+            }
+        }
+        rdatas
+            .into_iter()
+            .map(|rdata| Record::from_rdata(owner.clone(), 0, rdata))
+            .collect()
+    }
 }
 
 #[async_trait]
 impl RequestHandler for GluedDns {
-    async fn handle_request<R>(&self, request: &Request, mut response_handle: R) -> ResponseInfo
+    async fn handle_request<R>(&self, request: &Request, response_handle: R) -> ResponseInfo
     where
         R: ResponseHandler + Send,
     {
         let query = request.query();
-        let qname = query.name().to_string().trim_end_matches('.').to_string();
+        // Everything logged for the query carries its id, so a slow answer
+        // can be matched with the upstream failure behind it.
+        let id = request_id();
+        let span = info_span!(
+            target: "glued::dns",
+            "dns_request",
+            id = %id,
+            qname = %query.name(),
+            qtype = %query.query_type(),
+            client = %request.src(),
+        );
+        let started = Instant::now();
+        let mut forwarded = false;
+        let info = self
+            .answer(request, response_handle, &id, &mut forwarded)
+            .instrument(span.clone())
+            .await;
+        let elapsed = started.elapsed();
+        span.in_scope(|| {
+            debug!(
+                "Answered {} in {:?}{}",
+                info.response_code(),
+                elapsed,
+                if forwarded { " (forwarded)" } else { "" }
+            )
+        });
+        if metrics::enabled() {
+            let outcome = match (info.response_code(), forwarded) {
+                (ResponseCode::Refused | ResponseCode::NotImp, _) => QueryOutcome::Refused,
+                (ResponseCode::NoError, true) => QueryOutcome::ForwardedOk,
+                (_, true) => QueryOutcome::ForwardedFail,
+                (ResponseCode::NoError, false) => QueryOutcome::LocalHit,
+                (ResponseCode::NXDomain, false) => QueryOutcome::LocalNxdomain,
+                (_, false) => QueryOutcome::LocalFail,
+            };
+            METRICS.dns_query_seconds(outcome).observe(elapsed);
+        }
+        info
+    }
+}
+
+/// A short id for one query, to find its log lines by.
+fn request_id() -> String {
+    format!("{:08x}", rand::random::<u32>())
+}
+
+impl GluedDns {
+    /// Answers `request`, whose request id is `id`, setting `forwarded`
+    /// once the query is handed to the forwarding path.
+    async fn answer<R>(
+        &self,
+        request: &Request,
+        response_handle: R,
+        id: &str,
+        forwarded: &mut bool,
+    ) -> ResponseInfo
+    where
+        R: ResponseHandler + Send,
+    {
+        let client = request.src().ip();
+        if !self.acl.load().permits(client) {
+            inc(&METRICS.dns_refused);
+            debug!("Refusing DNS query from {}", client);
+            let header = Header::response_from_request(request.header());
+            return fail(request, response_handle, header, ExtendedError::Blocked, id).await;
+        }
+
+        if matches!(request.protocol(), Protocol::Udp) {
+            match self.limiter.check(client, tokio::time::Instant::now()) {
+                Verdict::Answer => {}
+                Verdict::Slip => {
+                    inc(&METRICS.dns_rate_limited);
+                    let mut header = Header::response_from_request(request.header());
+                    header.set_truncated(true);
+                    return respond(request, response_handle, header, &[]).await;
+                }
+                Verdict::Drop => {
+                    inc(&METRICS.dns_rate_limited);
+                    return Header::response_from_request(request.header()).into();
+                }
+            }
+        }
+
+        // Only plain queries, in the IN class or for the CHAOS names, are
+        // looked at any further.
+        let query = request.query();
+        if request.op_code() != OpCode::Query {
+            debug!("Not implemented: {} from {}", request.op_code(), client);
+            let header = Header::response_from_request(request.header());
+            return fail(
+                request,
+                response_handle,
+                header,
+                ExtendedError::UnsupportedOpcode,
+                id,
+            )
+            .await;
+        }
+        if !matches!(query.query_class(), DNSClass::IN | DNSClass::CH) {
+            debug!(
+                "Refusing a {} class query from {}",
+                query.query_class(),
+                client
+            );
+            let header = Header::response_from_request(request.header());
+            return fail(
+                request,
+                response_handle,
+                header,
+                ExtendedError::UnsupportedClass,
+                id,
+            )
+            .await;
+        }
+
+        let qname = normalize(&query.name().to_string());
+        // Answers repeat the name as the client spelled it (dns-0x20).
+        let owner = query.original().name().clone();
         let qtype = query.query_type();
 
         // Build response header
         let mut header = Header::response_from_request(request.header());
-        header.set_recursion_available(true);
-
-        // Single-label check
-        let is_single_label = !qname.contains('.');
-        if is_single_label {
-            let ip_opt = {
-                let map = self.state.read().await;
-                map.get(&qname).cloned()
+        header.set_recursion_available(self.upstreams.is_some());
+
+        if query.query_class() == DNSClass::CH {
+            let Some(text) = self.chaos.as_ref().and_then(|chaos| chaos.answer(&qname)) else {
+                return fail(
+                    request,
+                    response_handle,
+                    header,
+                    ExtendedError::UnsupportedClass,
+                    id,
+                )
+                .await;
             };
+            let mut records = Vec::new();
+            if qtype == RecordType::TXT || qtype == RecordType::ANY {
+                let txt = TXT::new(vec![text.to_string()]);
+                let mut record = Record::from_rdata(owner.clone(), 0, RData::TXT(txt));
+                record.set_dns_class(DNSClass::CH);
+                records.push(record);
+            }
+            return respond(request, response_handle, header, &records).await;
+        }
 
-            match ip_opt {
-                Some(ip_str) => match ip_str.parse::<std::net::IpAddr>() {
-                    Ok(std::net::IpAddr::V4(ipv4)) => {
-                        if qtype == RecordType::A || qtype == RecordType::ANY {
-                            let record = Record::from_rdata(
-                                query.name().clone().into(),
-                                5,
-                                RData::A(A(ipv4)),
-                            );
-                            let builder = MessageResponseBuilder::from_message_request(request);
-                            let records = [record];
-                            let response = builder.build(
-                                header,
-                                records.iter(),
-                                std::iter::empty(),
-                                std::iter::empty(),
-                                std::iter::empty(),
-                            );
-                            return response_handle.send_response(response).await.unwrap();
-                        } else {
-                            header.set_response_code(ResponseCode::NoError);
-                            let builder = MessageResponseBuilder::from_message_request(request);
-                            let response = builder.build_no_records(header);
-                            return response_handle.send_response(response).await.unwrap();
-                        }
-                    }
-                    Ok(std::net::IpAddr::V6(ipv6)) => {
-                        if qtype == RecordType::AAAA || qtype == RecordType::ANY {
-                            let record = Record::from_rdata(
-                                query.name().clone().into(),
-                                5,
-                                RData::AAAA(AAAA(ipv6)),
-                            );
-                            let builder = MessageResponseBuilder::from_message_request(request);
-                            let records = [record];
-                            let response = builder.build(
-                                header,
-                                records.iter(),
-                                std::iter::empty(),
-                                std::iter::empty(),
-                                std::iter::empty(),
-                            );
-                            return response_handle.send_response(response).await.unwrap();
-                        } else {
-                            header.set_response_code(ResponseCode::NoError);
-                            let builder = MessageResponseBuilder::from_message_request(request);
-                            let response = builder.build_no_records(header);
-                            return response_handle.send_response(response).await.unwrap();
-                        }
+        if self.cluster_info && (qname == cluster_info::CLUSTER || qname == cluster_info::NODES) {
+            let records = self.cluster_records(&qname, qtype, &owner).await;
+            return respond(request, response_handle, header, &records).await;
+        }
+
+        if matches!(qtype, RecordType::AXFR | RecordType::IXFR) {
+            return self
+                .transfer(request, response_handle, header, &qname, id)
+                .await;
+        }
+
+        if qtype == RecordType::ANY && self.minimal_any {
+            let hinfo = HINFO::new("RFC8482".into(), String::new());
+            let record = Record::from_rdata(owner.clone(), ANY_HINFO_TTL, RData::HINFO(hinfo));
+            return respond(request, response_handle, header, &[record]).await;
+        }
+
+        // `_service._proto.<name>` is answered for `<name>`.
+        let srv = if qtype == RecordType::SRV {
+            SrvQuery::parse(&qname)
+        } else {
+            None
+        };
+        let name = srv.as_ref().map_or(qname.as_str(), |srv| srv.name);
+
+        let zone = self
+            .local_zone
+            .as_deref()
+            .and_then(|zone| Some((zone, zone.locate(name)?)));
+        if zone.is_some() {
+            header.set_authoritative(true);
+        }
+        if let Some((zone, InZone::Apex)) = zone {
+            // Secondaries poll the SOA to learn whether to transfer again.
+            if matches!(qtype, RecordType::SOA | RecordType::ANY) {
+                zone.refresh_serial(&*self.state.read().await);
+            }
+            let records = match qtype {
+                RecordType::SOA => vec![zone.soa_record()],
+                RecordType::NS => vec![zone.ns_record()],
+                RecordType::ANY => vec![zone.soa_record(), zone.ns_record()],
+                _ => Vec::new(),
+            };
+            let authority = if records.is_empty() {
+                vec![zone.soa_record()]
+            } else {
+                Vec::new()
+            };
+            return respond_with_authority(request, response_handle, header, &records, &authority)
+                .await;
+        }
+
+        // Single labels are ours, as is the local zone; so are hosts file
+        // names and names below a wildcard entry.
+        let find = || async {
+            let map = self.state.read().await;
+            match zone {
+                Some((_, InZone::Below(below))) => self
+                    .resolver
+                    .entry(&map, name, Some(client))
+                    .or_else(|| self.resolver.entry_in_zone(&map, below, Some(client))),
+                _ => self.resolver.entry(&map, name, Some(client)),
+            }
+        };
+        let mut entry = find().await;
+        // A name without an entry of its own still exists when names below
+        // it do (an empty non-terminal), as QNAME-minimizing resolvers find
+        // on their way down to them.
+        let has_names_below = || async {
+            let map = self.state.read().await;
+            match zone {
+                Some((_, InZone::Below(below))) => {
+                    self.resolver.has_names_below(&map, name, client)
+                        || self.resolver.has_names_below(&map, below, client)
+                }
+                _ => self.resolver.has_names_below(&map, name, client),
+            }
+        };
+        // Negative answers in the zone carry its SOA.
+        let soa: Vec<Record> = zone.iter().map(|(zone, _)| zone.soa_record()).collect();
+        let is_single_label = !name.contains('.');
+        // Names under a network's suffix are ours even when missing.
+        let is_namespaced = self.resolver.networks().split(name).is_some();
+        if is_single_label || is_namespaced || zone.is_some() || entry.is_some() {
+            // Clients cache NXDOMAIN, but not SERVFAIL; until the node has
+            // heard from its peers, a miss may only mean not yet.
+            if let Some(status) = self.status.as_deref().filter(|_| entry.is_none()) {
+                if status.is_warming_up() && !self.warmup_delay.is_zero() {
+                    let _ = tokio::time::timeout(self.warmup_delay, status.warmed_up()).await;
+                    entry = find().await;
+                }
+                if entry.is_none() && status.is_warming_up() && !has_names_below().await {
+                    inc(&METRICS.dns_warmup_servfail);
+                    debug!("Warming up; answering SERVFAIL for {}", qname);
+                    return fail(
+                        request,
+                        response_handle,
+                        header,
+                        ExtendedError::WarmingUp,
+                        id,
+                    )
+                    .await;
+                }
+            }
+            let Some(entry) = entry else {
+                if !has_names_below().await {
+                    header.set_response_code(ResponseCode::NXDomain);
+                }
+                return respond_with_authority(request, response_handle, header, &[], &soa).await;
+            };
+
+            // A name without a record of this type gets an empty answer.
+            let mut rdatas = Vec::new();
+            for ip in entry.ips_for(self.resolver.views().scope_for(client)) {
+                match ip {
+                    std::net::IpAddr::V4(ipv4)
+                        if qtype == RecordType::A || qtype == RecordType::ANY =>
+                    {
+                        rdatas.push(RData::A(A(ipv4)));
                     }
-                    Err(_) => {
-                        header.set_response_code(ResponseCode::ServFail);
-                        let builder = MessageResponseBuilder::from_message_request(request);
-                        let response = builder.build_no_records(header);
-                        return response_handle.send_response(response).await.unwrap();
+                    std::net::IpAddr::V6(ipv6)
+                        if qtype == RecordType::AAAA || qtype == RecordType::ANY =>
+                    {
+                        rdatas.push(RData::AAAA(AAAA(ipv6)));
                     }
-                },
-                None => {
-                    header.set_response_code(ResponseCode::NXDomain);
-                    let builder = MessageResponseBuilder::from_message_request(request);
-                    let response = builder.build_no_records(header);
-                    return response_handle.send_response(response).await.unwrap();
+                    _ => {}
                 }
             }
+            if let Some(srv) = &srv {
+                rdatas.extend(srv.rdatas(&entry.ports, &srv::target(&owner)));
+            }
+            if qtype == RecordType::TXT || qtype == RecordType::ANY {
+                if !entry.txt.is_empty() {
+                    rdatas.push(RData::TXT(TXT::new(txt::segments(&entry.txt))));
+                }
+                let metadata = if self.txt_metadata {
+                    txt::metadata(&entry)
+                } else {
+                    Vec::new()
+                };
+                if !metadata.is_empty() {
+                    rdatas.push(RData::TXT(TXT::new(metadata)));
+                }
+            }
+            let records: Vec<Record> = rdatas
+                .into_iter()
+                .map(|rdata| Record::from_rdata(owner.clone(), 5, rdata))
+                .collect();
+            let authority = if records.is_empty() { &soa[..] } else { &[] };
+            return respond_with_authority(request, response_handle, header, &records, authority)
+                .await;
         }
 
         // Forward FQDN
-        match self.resolver.lookup_ip(qname.clone()).await {
+        *forwarded = true;
+        let Some(upstreams) = self.upstreams.as_deref().map(ArcSwap::load_full) else {
+            return fail(
+                request,
+                response_handle,
+                header,
+                ExtendedError::NotAuthoritative,
+                id,
+            )
+            .await;
+        };
+        if !self.acl.load().permits_forwarding(client) {
+            inc(&METRICS.dns_forward_refused);
+            debug!("Refusing to forward {} for {}", qname, client);
+            return fail(
+                request,
+                response_handle,
+                header,
+                ExtendedError::ForwardingBlocked,
+                id,
+            )
+            .await;
+        }
+        let now = tokio::time::Instant::now();
+        let cached = self.negative_cache.lock().unwrap().soa(query.name(), now);
+        if let Some(soa) = cached {
+            inc(&METRICS.dns_negative_cache_hits);
+            header.set_response_code(ResponseCode::NXDomain);
+            return respond_with_authority(request, response_handle, header, &[], &[soa]).await;
+        }
+        inc(&METRICS.dns_negative_cache_misses);
+        if self.forward_limiter.check(client, now) != Verdict::Answer {
+            inc(&METRICS.dns_forward_rate_limited);
+            debug!(
+                "Refusing to forward {} for {}: over its rate",
+                qname, client
+            );
+            return fail(
+                request,
+                response_handle,
+                header,
+                ExtendedError::RateLimited,
+                id,
+            )
+            .await;
+        }
+        // Waiting for a slot counts against the timeout.
+        let deadline = now + self.forward_timeout;
+        let _slot = match self.forward_slots.try_acquire() {
+            Ok(slot) => slot,
+            Err(_) => {
+                let Some(_waiting) = Waiting::enter(&self.forward_queued, self.max_queued) else {
+                    inc(&METRICS.dns_forward_overloaded);
+                    debug!("Too many forwarded lookups in flight; failing {}", qname);
+                    return fail(
+                        request,
+                        response_handle,
+                        header,
+                        ExtendedError::Overloaded,
+                        id,
+                    )
+                    .await;
+                };
+                let Ok(Ok(slot)) =
+                    tokio::time::timeout_at(deadline, self.forward_slots.acquire()).await
+                else {
+                    inc(&METRICS.dns_forward_timeouts);
+                    debug!("No forwarded lookup finished in time for {}", qname);
+                    return fail(
+                        request,
+                        response_handle,
+                        header,
+                        ExtendedError::UpstreamTimeout,
+                        id,
+                    )
+                    .await;
+                };
+                slot
+            }
+        };
+        // On expiry the lookup future is dropped, which cancels it.
+        let lookup = tokio::time::timeout_at(deadline, upstreams.lookup_ip(query.name()));
+        let Ok(result) = lookup.await else {
+            inc(&METRICS.dns_forward_timeouts);
+            debug!("Forwarded lookup for {} timed out", qname);
+            return fail(
+                request,
+                response_handle,
+                header,
+                ExtendedError::UpstreamTimeout,
+                id,
+            )
+            .await;
+        };
+        match result {
             Ok(lookup) => {
+                self.forward_recovered();
                 let mut records = Vec::new();
-                for addr in lookup.iter() {
-                    let addr: std::net::IpAddr = addr;
-                    match (addr, qtype) {
-                        (std::net::IpAddr::V4(ipv4), RecordType::A)
-                        | (std::net::IpAddr::V4(ipv4), RecordType::ANY) => {
-                            records.push(Record::from_rdata(
-                                query.name().clone().into(),
-                                60,
-                                RData::A(A(ipv4)),
-                            ));
+                // TTLs as the upstream gave them, less the time spent in
+                // the resolver's cache.
+                for record in lookup.as_lookup().record_iter() {
+                    let ttl = self.forward_ttl.apply(record.ttl());
+                    match (record.data(), qtype) {
+                        (Some(RData::A(a)), RecordType::A | RecordType::ANY) => {
+                            records.push(Record::from_rdata(owner.clone(), ttl, RData::A(*a)));
                         }
-                        (std::net::IpAddr::V6(ipv6), RecordType::AAAA)
-                        | (std::net::IpAddr::V6(ipv6), RecordType::ANY) => {
+                        (Some(RData::AAAA(aaaa)), RecordType::AAAA | RecordType::ANY) => {
                             records.push(Record::from_rdata(
-                                query.name().clone().into(),
-                                60,
-                                RData::AAAA(AAAA(ipv6)),
+                                owner.clone(),
+                                ttl,
+                                RData::AAAA(*aaaa),
                             ));
                         }
                         _ => {}
                     }
                 }
                 header.set_response_code(ResponseCode::NoError);
-                let builder = MessageResponseBuilder::from_message_request(request);
-                let response = builder.build(
-                    header,
-                    records.iter(),
-                    std::iter::empty(),
-                    std::iter::empty(),
-                    std::iter::empty(),
-                );
-                response_handle.send_response(response).await.unwrap()
+                respond(request, response_handle, header, &records).await
             }
             Err(e) => {
-                warn!("Resolver lookup failed for {}: {}", qname, e);
-                header.set_response_code(ResponseCode::ServFail);
-                let builder = MessageResponseBuilder::from_message_request(request);
-                let response = builder.build_no_records(header);
-                response_handle.send_response(response).await.unwrap()
+                // Negative answers carry the upstream's SOA, with the
+                // negative TTL as its own.
+                let mut authority = Vec::new();
+                match e.kind() {
+                    ResolveErrorKind::NoRecordsFound {
+                        response_code,
+                        soa,
+                        negative_ttl,
+                        ..
+                    } => {
+                        if let (Some(soa), Some(ttl)) = (soa, negative_ttl) {
+                            let mut soa = soa.as_ref().clone().into_record_of_rdata();
+                            soa.set_ttl(*ttl);
+                            authority.push(soa);
+                        }
+                        if *response_code == ResponseCode::NXDomain {
+                            if let Some(soa) = authority.first() {
+                                self.remember_nxdomain(query.name().clone(), soa.clone());
+                            }
+                            header.set_response_code(ResponseCode::NXDomain);
+                        } else {
+                            header.set_response_code(ResponseCode::NoError);
+                        }
+                    }
+                    ResolveErrorKind::Timeout => {
+                        debug!("Resolver lookup timed out for {}", qname);
+                        self.forward_failed("timeout", "Resolver lookups time out".into());
+                        return fail(
+                            request,
+                            response_handle,
+                            header,
+                            ExtendedError::UpstreamTimeout,
+                            id,
+                        )
+                        .await;
+                    }
+                    _ => {
+                        debug!("Resolver lookup failed for {}: {}", qname, e);
+                        self.forward_failed("failure", format!("Resolver lookups fail: {}", e));
+                        return fail(
+                            request,
+                            response_handle,
+                            header,
+                            ExtendedError::UpstreamUnreachable,
+                            id,
+                        )
+                        .await;
+                    }
+                }
+                respond_with_authority(request, response_handle, header, &[], &authority).await
+            }
+        }
+    }
+
+    /// Sends the local zone, if `qname` is its apex, to a client in
+    /// `axfr_allow` over TCP: the SOA, the NS record, the addresses of every
+    /// name but wildcards, as the client's view has them, and the SOA
+    /// again.  Anything else is refused.
+    async fn transfer<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        response_handle: R,
+        mut header: Header,
+        qname: &str,
+        id: &str,
+    ) -> ResponseInfo {
+        let client = request.src().ip();
+        let zone = self
+            .local_zone
+            .as_deref()
+            .filter(|zone| zone.locate(qname) == Some(InZone::Apex))
+            .filter(|_| !matches!(request.protocol(), Protocol::Udp))
+            .filter(|_| self.acl.load().permits_transfer(client));
+        let Some(zone) = zone else {
+            inc(&METRICS.dns_transfers_refused);
+            debug!("Refusing a transfer of {} to {}", qname, client);
+            return fail(
+                request,
+                response_handle,
+                header,
+                ExtendedError::TransferRefused,
+                id,
+            )
+            .await;
+        };
+        header.set_authoritative(true);
+
+        let records = {
+            let map = self.state.read().await;
+            zone.refresh_serial(&map);
+            let mut names: Vec<(&String, &Entry)> = map
+                .iter()
+                .filter(|(name, _)| !name.starts_with("*."))
+                .collect();
+            names.sort_by_key(|(name, _)| *name);
+            let scope = self.resolver.views().scope_for(client);
+            let mut records = vec![zone.soa_record(), zone.ns_record()];
+            for (name, entry) in names {
+                let Some(owner) = zone.owner(name) else {
+                    continue;
+                };
+                for ip in entry.ips_for(scope) {
+                    let rdata = match ip {
+                        IpAddr::V4(ipv4) => RData::A(A(ipv4)),
+                        IpAddr::V6(ipv6) => RData::AAAA(AAAA(ipv6)),
+                    };
+                    records.push(Record::from_rdata(owner.clone(), 5, rdata));
+                }
+            }
+            records.push(zone.soa_record());
+            records
+        };
+        inc(&METRICS.dns_transfers);
+        info!(
+            "Transferring {} to {}: {} records, serial {}",
+            qname,
+            client,
+            records.len(),
+            zone.serial()
+        );
+        let mut info = header.into();
+        for chunk in records.chunks(TRANSFER_CHUNK) {
+            info = respond(request, response_handle.clone(), header, chunk).await;
+        }
+        info
+    }
+
+    /// Logs why a forwarded lookup failed, unless that's been logged in
+    /// the last minute.
+    fn forward_failed(&self, key: &'static str, message: String) {
+        self.forward_failures.lock().unwrap().log(key, message);
+    }
+
+    /// Notes an upstream answering, after failures if there were any.
+    fn forward_recovered(&self) {
+        let mut failures = self.forward_failures.lock().unwrap();
+        if failures.recovered(&"timeout") | failures.recovered(&"failure") {
+            info!("Resolver lookups succeed again");
+        }
+    }
+
+    /// Keeps `name` in the negative cache, keeping count of its entries.
+    fn remember_nxdomain(&self, name: LowerName, soa: Record) {
+        let mut cache = self.negative_cache.lock().unwrap();
+        let before = cache.len();
+        cache.insert(name, soa, tokio::time::Instant::now());
+        METRICS
+            .dns_negative_cache_entries
+            .fetch_add((cache.len() - before) as u64, Ordering::Relaxed);
+    }
+}
+
+/// A query waiting for a forwarded lookup slot, counted until it stops.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    /// Joins the queries in `waiting`, unless `max` are already.
+    fn enter(waiting: &'a AtomicUsize, max: usize) -> Option<Self> {
+        if waiting.fetch_add(1, Ordering::Relaxed) >= max {
+            waiting.fetch_sub(1, Ordering::Relaxed);
+            return None;
+        }
+        METRICS.dns_forward_queued.fetch_add(1, Ordering::Relaxed);
+        Some(Self(waiting))
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+        METRICS.dns_forward_queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_server::authority::{MessageRequest, MessageResponse};
+    use hickory_server::proto::op::Query;
+    use hickory_server::proto::serialize::binary::{BinDecodable, BinEncoder};
+
+    fn request(protocol: Protocol, edns_payload: Option<u16>) -> Request {
+        let mut message = Message::new();
+        message.add_query(Query::query(
+            Name::from_ascii("big.example.").unwrap(),
+            RecordType::A,
+        ));
+        if let Some(payload) = edns_payload {
+            let mut edns = Edns::new();
+            edns.set_max_payload(payload);
+            message.set_edns(edns);
+        }
+        let message = MessageRequest::from_bytes(&message.to_vec().unwrap()).unwrap();
+        Request::new(message, "127.0.0.1:5300".parse().unwrap(), protocol)
+    }
+
+    fn answers(count: u8) -> Vec<Record> {
+        (0..count)
+            .map(|i| {
+                Record::from_rdata(
+                    Name::from_ascii("big.example.").unwrap(),
+                    60,
+                    RData::A(A::new(10, 0, 0, i)),
+                )
+            })
+            .collect()
+    }
+
+    /// Keeps the response it is sent.
+    #[derive(Clone, Default)]
+    struct Capture(Arc<std::sync::Mutex<Option<Message>>>);
+
+    #[async_trait]
+    impl ResponseHandler for Capture {
+        async fn send_response<'a>(
+            &mut self,
+            response: MessageResponse<
+                '_,
+                'a,
+                impl Iterator<Item = &'a Record> + Send + 'a,
+                impl Iterator<Item = &'a Record> + Send + 'a,
+                impl Iterator<Item = &'a Record> + Send + 'a,
+                impl Iterator<Item = &'a Record> + Send + 'a,
+            >,
+        ) -> std::io::Result<ResponseInfo> {
+            let mut bytes = Vec::new();
+            let info = response.destructive_emit(&mut BinEncoder::new(&mut bytes))?;
+            *self.0.lock().unwrap() = Some(Message::from_vec(&bytes)?);
+            Ok(info)
+        }
+    }
+
+    #[tokio::test]
+    async fn unsupported_opcodes_and_classes_are_turned_away_unlooked() {
+        let state = SharedState::default();
+        state
+            .write()
+            .await
+            .insert("web-1".into(), Entry::new("10.0.0.2".parse().unwrap()));
+        let handler = GluedDns::new(state, &DnsOptions::default(), None);
+        let ask = |op_code: OpCode, class: DNSClass, name: &str| {
+            let mut query = Query::query(Name::from_ascii(name).unwrap(), RecordType::A);
+            query.set_query_class(class);
+            let mut message = Message::new();
+            message.set_op_code(op_code).add_query(query);
+            let message = MessageRequest::from_bytes(&message.to_vec().unwrap()).unwrap();
+            let request = Request::new(message, "127.0.0.1:5300".parse().unwrap(), Protocol::Udp);
+            let handler = handler.clone();
+            async move {
+                let capture = Capture::default();
+                handler.handle_request(&request, capture.clone()).await;
+                let response = capture.0.lock().unwrap().take().unwrap();
+                response
+            }
+        };
+
+        let plain = ask(OpCode::Query, DNSClass::IN, "web-1.").await;
+        assert_eq!(plain.response_code(), ResponseCode::NoError);
+        assert_eq!(plain.answers().len(), 1);
+        for op_code in [OpCode::Notify, OpCode::Update, OpCode::Status] {
+            for name in ["web-1.", "www.example.com."] {
+                let response = ask(op_code, DNSClass::IN, name).await;
+                assert_eq!(response.response_code(), ResponseCode::NotImp);
+                assert_eq!(response.op_code(), op_code);
+                assert!(response.answers().is_empty());
+            }
+        }
+        for class in [DNSClass::HS, DNSClass::NONE, DNSClass::ANY] {
+            for name in ["web-1.", "www.example.com."] {
+                let response = ask(OpCode::Query, class, name).await;
+                assert_eq!(response.response_code(), ResponseCode::Refused);
+                assert!(response.answers().is_empty());
             }
         }
+        // CHAOS is still answered.
+        let chaos = ask(OpCode::Query, DNSClass::CH, "version.bind.").await;
+        assert_eq!(chaos.response_code(), ResponseCode::NoError);
+    }
+
+    #[test]
+    fn large_udp_answers_need_truncation() {
+        let header = Header::new();
+        // 40 A records are ~650 bytes: too big for plain DNS, fine with EDNS.
+        let big = answers(40);
+        assert!(exceeds_udp_payload(
+            &request(Protocol::Udp, None),
+            &header,
+            &big,
+            &[]
+        ));
+        assert!(!exceeds_udp_payload(
+            &request(Protocol::Udp, Some(4096)),
+            &header,
+            &big,
+            &[]
+        ));
+        assert!(!exceeds_udp_payload(
+            &request(Protocol::Tcp, None),
+            &header,
+            &big,
+            &[]
+        ));
+        assert!(!exceeds_udp_payload(
+            &request(Protocol::Udp, None),
+            &header,
+            &answers(4),
+            &[]
+        ));
+
+        // Past our own cap, even when the client would take more.
+        let huge = answers(100);
+        assert!(exceeds_udp_payload(
+            &request(Protocol::Udp, Some(4096)),
+            &header,
+            &huge,
+            &[]
+        ));
     }
 }
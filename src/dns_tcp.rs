@@ -0,0 +1,195 @@
+//! DNS over TCP with connection limits.
+//!
+//! hickory's TCP listener only has an idle timeout, so TCP is served here:
+//! the number of open connections and the queries answered on each are
+//! capped, and queries pipelined on one connection (RFC 7766) are handled
+//! concurrently, with each response written as soon as it is ready.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures_util::future::join_all;
+use hickory_server::authority::{MessageRequest, MessageResponse};
+use hickory_server::proto::rr::Record;
+use hickory_server::proto::serialize::binary::{BinDecodable, BinEncoder};
+use hickory_server::server::{Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo};
+use log::debug;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Semaphore};
+
+use crate::config::Config;
+
+/// Limits applied to DNS clients connecting over TCP.
+#[derive(Debug, Clone, Copy)]
+pub struct TcpLimits {
+    /// Connections served at once; further connections are closed on accept.
+    pub max_connections: usize,
+    /// Queries answered per connection before it is closed.  Zero is unlimited.
+    pub max_queries: usize,
+    /// How long a connection may sit without sending a query.
+    pub idle_timeout: Duration,
+}
+
+impl TcpLimits {
+    pub fn from_config(cfg: &Config) -> Self {
+        Self {
+            max_connections: cfg.dns_tcp_max_connections.max(1),
+            max_queries: cfg.dns_tcp_max_queries,
+            idle_timeout: Duration::from_secs(cfg.dns_tcp_timeout_secs.max(1)),
+        }
+    }
+}
+
+impl Default for TcpLimits {
+    fn default() -> Self {
+        Self::from_config(&Config::default())
+    }
+}
+
+/// Accepts TCP connections on `listeners` and answers them with `handler`.
+/// The connection limit is shared by all listeners.
+pub async fn serve_tcp<H: RequestHandler>(
+    listeners: Vec<TcpListener>,
+    handler: H,
+    limits: TcpLimits,
+) {
+    let handler = Arc::new(handler);
+    let slots = Arc::new(Semaphore::new(limits.max_connections));
+    join_all(
+        listeners
+            .into_iter()
+            .map(|listener| accept_loop(listener, &handler, &slots, limits)),
+    )
+    .await;
+}
+
+async fn accept_loop<H: RequestHandler>(
+    listener: TcpListener,
+    handler: &Arc<H>,
+    slots: &Arc<Semaphore>,
+    limits: TcpLimits,
+) {
+    loop {
+        let (stream, src) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                debug!("Failed to accept TCP DNS connection: {}", e);
+                // Typically out of file descriptors; don't spin.
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let Ok(permit) = Arc::clone(slots).try_acquire_owned() else {
+            debug!(
+                "Closing TCP DNS connection from {}: {} connections open",
+                src, limits.max_connections
+            );
+            continue;
+        };
+        let handler = Arc::clone(handler);
+        tokio::spawn(async move {
+            let _permit = permit;
+            if let Err(e) = serve_connection(stream, src, handler, limits).await {
+                debug!("TCP DNS connection from {} failed: {}", src, e);
+            }
+        });
+    }
+}
+
+async fn serve_connection<H: RequestHandler>(
+    stream: TcpStream,
+    src: SocketAddr,
+    handler: Arc<H>,
+    limits: TcpLimits,
+) -> io::Result<()> {
+    let (mut reader, mut writer) = stream.into_split();
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(16);
+    let write = async move {
+        while let Some(response) = rx.recv().await {
+            let Ok(len) = u16::try_from(response.len()) else {
+                debug!("Dropping oversized TCP DNS response to {}", src);
+                continue;
+            };
+            writer.write_u16(len).await?;
+            writer.write_all(&response).await?;
+        }
+        writer.shutdown().await
+    };
+
+    let read = async move {
+        let mut served = 0;
+        while limits.max_queries == 0 || served < limits.max_queries {
+            let len = match tokio::time::timeout(limits.idle_timeout, reader.read_u16()).await {
+                Ok(Ok(len)) => len,
+                Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Ok(Err(e)) => return Err(e),
+                Err(_) => {
+                    debug!("Closing idle TCP DNS connection from {}", src);
+                    break;
+                }
+            };
+            let mut buf = vec![0; usize::from(len)];
+            tokio::time::timeout(limits.idle_timeout, reader.read_exact(&mut buf))
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+            let message = MessageRequest::from_bytes(&buf)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            served += 1;
+
+            let handler = Arc::clone(&handler);
+            let responder = TcpResponder(tx.clone());
+            tokio::spawn(async move {
+                let request = Request::new(message, src, Protocol::Tcp);
+                handler.handle_request(&request, responder).await;
+            });
+        }
+        if limits.max_queries != 0 && served >= limits.max_queries {
+            debug!(
+                "Closing TCP DNS connection from {} after {} queries",
+                src, served
+            );
+        }
+        Ok(())
+    };
+
+    // The writer finishes once every spawned query has dropped its sender.
+    let (read, write) = tokio::join!(read, write);
+    read.and(write)
+}
+
+/// Hands encoded responses to the connection's writer.
+#[derive(Clone)]
+struct TcpResponder(mpsc::Sender<Vec<u8>>);
+
+#[async_trait]
+impl ResponseHandler for TcpResponder {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> io::Result<ResponseInfo> {
+        let mut buffer = Vec::with_capacity(512);
+        let info = {
+            let mut encoder = BinEncoder::new(&mut buffer);
+            encoder.set_max_size(u16::MAX);
+            response
+                .destructive_emit(&mut encoder)
+                .map_err(io::Error::other)?
+        };
+        self.0
+            .send(buffer)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(info)
+    }
+}
@@ -0,0 +1,154 @@
+//! DNS over TLS (RFC 7858) for clients on untrusted networks.
+//!
+//! The listener is served by the same handler as UDP and TCP.  The
+//! certificate chain and key are read from PEM files at startup, and a key
+//! that doesn't belong to the certificate is refused there rather than
+//! failing every handshake later.
+
+use std::fs;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use rustls::{Certificate, PrivateKey, ServerConfig, SignatureScheme};
+
+use crate::config::Config;
+
+/// Schemes tried when checking that the key matches the certificate.
+const CHECK_SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::ED25519,
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::RSA_PSS_SHA256,
+];
+
+/// Where DNS over TLS is served, and with what certificate.
+#[derive(Clone)]
+pub struct TlsListener {
+    pub bind: SocketAddr,
+    pub config: Arc<ServerConfig>,
+}
+
+impl std::fmt::Debug for TlsListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsListener")
+            .field("bind", &self.bind)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TlsListener {
+    /// The listener configured by `dns_tls_bind`, if any.
+    pub fn from_config(cfg: &Config) -> anyhow::Result<Option<Self>> {
+        let Some(bind) = cfg.dns_tls_bind else {
+            return Ok(None);
+        };
+        let (Some(cert), Some(key)) = (&cfg.dns_tls_cert, &cfg.dns_tls_key) else {
+            anyhow::bail!("dns_tls_bind requires dns_tls_cert and dns_tls_key");
+        };
+        let config = server_config(Path::new(cert), Path::new(key))?;
+        Ok(Some(Self { bind, config }))
+    }
+}
+
+/// A TLS server config for the PEM certificate chain and key at the given
+/// paths.
+pub fn server_config(cert_path: &Path, key_path: &Path) -> anyhow::Result<Arc<ServerConfig>> {
+    let certs = read_certs(cert_path)?;
+    let key = read_key(key_path)?;
+    check_key_matches(&certs[0], &key).with_context(|| {
+        format!(
+            "DNS-over-TLS key {} does not match certificate {}",
+            key_path.display(),
+            cert_path.display()
+        )
+    })?;
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid DNS-over-TLS certificate or key")?;
+    Ok(Arc::new(config))
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+    let file = fs::File::open(path)
+        .with_context(|| format!("Failed to open DNS-over-TLS certificate {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to read certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates in {}", path.display());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    let file = fs::File::open(path)
+        .with_context(|| format!("Failed to open DNS-over-TLS key {}", path.display()))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to read private key from {}", path.display()))?;
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .with_context(|| format!("No private key in {}", path.display()))
+}
+
+/// Signs a message with `key` and verifies it with the public key in `cert`.
+fn check_key_matches(cert: &Certificate, key: &PrivateKey) -> anyhow::Result<()> {
+    let signing_key = rustls::sign::any_supported_type(key)
+        .map_err(|_| anyhow::anyhow!("Unsupported private key type"))?;
+    let signer = signing_key
+        .choose_scheme(CHECK_SCHEMES)
+        .context("Unsupported private key type")?;
+    let algorithm = match signer.scheme() {
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        _ => &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    };
+    let message = b"glued dns-over-tls key check";
+    let signature = signer.sign(message)?;
+    let cert = webpki::EndEntityCert::try_from(cert.0.as_slice())
+        .map_err(|e| anyhow::anyhow!("Invalid certificate: {:?}", e))?;
+    cert.verify_signature(algorithm, message, &signature)
+        .map_err(|_| anyhow::anyhow!("The private key belongs to a different certificate"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("glued-dns-tls-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn key_must_match_certificate() {
+        let dir = scratch_dir("mismatch");
+        let ours = rcgen::generate_simple_self_signed(vec!["dns.example".into()]).unwrap();
+        let other = rcgen::generate_simple_self_signed(vec!["dns.example".into()]).unwrap();
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        let other_key = dir.join("other.pem");
+        fs::write(&cert, ours.serialize_pem().unwrap()).unwrap();
+        fs::write(&key, ours.serialize_private_key_pem()).unwrap();
+        fs::write(&other_key, other.serialize_private_key_pem()).unwrap();
+
+        assert!(server_config(&cert, &key).is_ok());
+        let err = server_config(&cert, &other_key).unwrap_err();
+        assert!(format!("{:#}", err).contains("does not match"), "{:#}", err);
+        assert!(server_config(&key, &key).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,369 @@
+//! `glued doctor`: checks a node's setup without starting it.
+//!
+//! Each check goes through the code the daemon itself uses: the config is
+//! loaded and validated, the container runtime asked for the monitored
+//! network (on replicas), the DNS addresses bound and released, every
+//! upstream resolver sent a probe query, and a gossip endpoint bound to
+//! reach the relay and dial the bootstrap peers.  Nothing is published and
+//! no state is written.  A dialled peer does see a short authenticated
+//! connection from a NodeId it doesn't know.
+//!
+//! Checks that can't run, because what they depend on failed or isn't
+//! configured, are skipped.  `glued doctor` exits non-zero when any check
+//! failed; `--json` prints the report as JSON.
+
+use std::fmt;
+use std::time::Duration;
+
+use hickory_resolver::config::ResolverOpts;
+use iroh::Endpoint;
+use serde::Serialize;
+
+use crate::bootstrap::ServiceDiscovery;
+use crate::config::{Config, RelayConfig, RelayModeName, Role, RuntimeKind, Watched};
+use crate::dns_server::{DnsOptions, DnsSockets};
+use crate::forward::{system_resolver, Upstreams};
+use crate::gossip;
+use crate::logging;
+use crate::networks::Networks;
+use crate::peers::PeerPolicy;
+use crate::runtime::{ContainerdRuntime, DockerRuntime};
+use crate::static_records;
+
+/// How long the endpoint gets to connect to its home relay.
+const RELAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Pass,
+    Fail,
+    Skip,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::Pass => "PASS",
+            Outcome::Fail => "FAIL",
+            Outcome::Skip => "SKIP",
+        })
+    }
+}
+
+/// The result of one check.
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+/// Every check run, in order.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    /// No check failed; skipped ones don't count.
+    pub ok: bool,
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    fn record(&mut self, name: impl Into<String>, outcome: Outcome, detail: impl Into<String>) {
+        self.checks.push(Check {
+            name: name.into(),
+            outcome,
+            detail: detail.into(),
+        });
+    }
+
+    fn pass(&mut self, name: impl Into<String>, detail: impl Into<String>) {
+        self.record(name, Outcome::Pass, detail);
+    }
+
+    fn fail(&mut self, name: impl Into<String>, e: impl fmt::Display) {
+        self.record(name, Outcome::Fail, e.to_string());
+    }
+
+    fn skip(&mut self, name: impl Into<String>, why: impl Into<String>) {
+        self.record(name, Outcome::Skip, why);
+    }
+
+    fn finish(mut self) -> Self {
+        self.ok = self.checks.iter().all(|c| c.outcome != Outcome::Fail);
+        self
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self.checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+        for check in &self.checks {
+            writeln!(
+                f,
+                "{}  {:width$}  {}",
+                check.outcome, check.name, check.detail
+            )?;
+        }
+        let failed = self
+            .checks
+            .iter()
+            .filter(|c| c.outcome == Outcome::Fail)
+            .count();
+        match failed {
+            0 => write!(f, "All checks passed"),
+            1 => write!(f, "1 check failed"),
+            n => write!(f, "{} checks failed", n),
+        }
+    }
+}
+
+/// Runs `glued doctor [--json]`, printing the report.  Returns whether
+/// every check passed.
+pub async fn doctor(args: &[String]) -> anyhow::Result<bool> {
+    let json = match args {
+        [] => false,
+        [flag] if flag == "--json" => true,
+        _ => anyhow::bail!("Usage: glued doctor [--json]"),
+    };
+    let report = run_checks().await;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("{}", report);
+    }
+    Ok(report.ok)
+}
+
+/// Runs every check against the config from the environment.
+pub async fn run_checks() -> Report {
+    let mut report = Report::default();
+    let (cfg, role) = match Config::load().and_then(|cfg| {
+        let role = validate(&cfg)?;
+        Ok((cfg, role))
+    }) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            report.fail("config", format!("{:#}", e));
+            for name in ["runtime", "dns_bind", "upstreams", "gossip_endpoint"] {
+                report.skip(name, "config did not load");
+            }
+            return report.finish();
+        }
+    };
+    let role_label = match &role {
+        Role::Dns => "dns",
+        Role::Replica(_) => "replica",
+    };
+    report.pass(
+        "config",
+        format!("loaded; {} role, node name {}", role_label, cfg.node_name()),
+    );
+
+    check_runtime(&mut report, &cfg, &role).await;
+    check_dns_bind(&mut report, &cfg);
+    check_upstreams(&mut report, &cfg).await;
+    check_gossip(&mut report, &cfg).await;
+    report.finish()
+}
+
+/// What startup checks before running anything, returning the role.
+fn validate(cfg: &Config) -> anyhow::Result<Role> {
+    let role = cfg.resolve_role()?;
+    logging::check_level(&cfg.log_level)?;
+    DnsOptions::from_config(cfg)?;
+    cfg.relay.to_relay_mode()?;
+    static_records::install(&mut Default::default(), &cfg.static_records)?;
+    Networks::from_config(cfg)?;
+    Ok(role)
+}
+
+async fn check_runtime(report: &mut Report, cfg: &Config, role: &Role) {
+    let Role::Replica(watched) = role else {
+        report.skip("runtime", "DNS-only node watches no containers");
+        return;
+    };
+    for Watched { network, .. } in watched {
+        let checked = match cfg.runtime {
+            RuntimeKind::Docker => match DockerRuntime::new(network.clone(), cfg) {
+                Ok(runtime) => runtime.check().await,
+                Err(e) => Err(e),
+            },
+            RuntimeKind::Containerd => match ContainerdRuntime::new(network.clone(), cfg) {
+                Ok(runtime) => runtime.check().await,
+                Err(e) => Err(e),
+            },
+        };
+        match checked {
+            Ok(()) => report.pass(
+                "runtime",
+                format!(
+                    "{:?} answers; network '{}' is visible",
+                    cfg.runtime, network
+                ),
+            ),
+            Err(e) => report.fail("runtime", format!("network '{}': {:#}", network, e)),
+        }
+    }
+}
+
+fn check_dns_bind(report: &mut Report, cfg: &Config) {
+    // The sockets are closed again when dropped.  Sharing the port, an
+    // instance already serving it doesn't stand in the way.
+    let bind = if cfg.dns_reuse_port {
+        DnsSockets::bind_sharing_port
+    } else {
+        DnsSockets::bind
+    };
+    match bind(
+        &cfg.dns_bind_addrs(),
+        &cfg.dns_fallback_ports,
+        cfg.dns_bind_best_effort,
+    ) {
+        Ok(sockets) => {
+            let bound: Vec<String> = sockets
+                .local_addrs()
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect();
+            report.pass("dns_bind", format!("can bind {}", bound.join(", ")));
+        }
+        Err(e) => report.fail("dns_bind", format!("{:#}", e)),
+    }
+}
+
+async fn check_upstreams(report: &mut Report, cfg: &Config) {
+    if !cfg.forwarding {
+        report.skip("upstreams", "forwarding is disabled");
+        return;
+    }
+    let upstreams = match Upstreams::new(
+        system_resolver(),
+        &cfg.forward_zones,
+        ResolverOpts::default(),
+    ) {
+        Ok(upstreams) => upstreams,
+        Err(e) => return report.fail("upstreams", format!("{:#}", e)),
+    };
+    for (upstream, result) in upstreams.probe().await {
+        let name = format!("upstream {}", upstream);
+        match result {
+            Ok(()) => report.pass(name, "answers"),
+            Err(e) => report.fail(name, e),
+        }
+    }
+}
+
+async fn check_gossip(report: &mut Report, cfg: &Config) {
+    let endpoint = match gossip::bind_endpoint(cfg).await {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            report.fail("gossip_endpoint", format!("{:#}", e));
+            return;
+        }
+    };
+    report.pass(
+        "gossip_endpoint",
+        format!("bound on port {}", cfg.gossip_port),
+    );
+    check_relay(report, cfg, &endpoint).await;
+    check_bootstrap(report, cfg, &endpoint).await;
+    let _ = endpoint.close().await;
+}
+
+async fn check_relay(report: &mut Report, cfg: &Config, endpoint: &Endpoint) {
+    if cfg.relay == RelayConfig::Mode(RelayModeName::Disabled) {
+        report.skip("relay", "relay is disabled");
+        return;
+    }
+    let deadline = tokio::time::Instant::now() + RELAY_TIMEOUT;
+    loop {
+        if let Some(relay) = endpoint.home_relay() {
+            return report.pass("relay", format!("connected to {}", relay));
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return report.fail(
+                "relay",
+                format!(
+                    "no relay ({}) reached within {:?}",
+                    cfg.relay, RELAY_TIMEOUT
+                ),
+            );
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+async fn check_bootstrap(report: &mut Report, cfg: &Config, endpoint: &Endpoint) {
+    // Addresses of the bootstrap service's tasks help dial peers, as they
+    // do for the daemon.
+    let mut hints = Default::default();
+    if let Some(service) = &cfg.bootstrap_service {
+        let name = format!("bootstrap_service {}", service);
+        match ServiceDiscovery::new(service, cfg.gossip_port)
+            .lookup()
+            .await
+        {
+            Ok(addrs) if addrs.is_empty() => report.fail(name, "resolves to no addresses"),
+            Ok(addrs) => {
+                report.pass(name, format!("resolves to {} addresses", addrs.len()));
+                hints = addrs;
+            }
+            Err(e) => report.fail(name, format!("{:#}", e)),
+        }
+    }
+
+    if cfg.bootstrap_peers.is_empty() {
+        report.skip("bootstrap_peers", "none configured");
+        return;
+    }
+    let policy = PeerPolicy::from_config(cfg);
+    let deadline = Duration::from_secs(cfg.auth_timeout_secs);
+    // Dialled one at a time; a stuck peer costs at most two deadlines.
+    for peer in &cfg.bootstrap_peers {
+        let name = format!("bootstrap peer {}", peer.node_id.fmt_short());
+        if let Some(reason) = policy.rejects(&peer.node_id) {
+            report.skip(name, format!("not dialled: {}", reason));
+            continue;
+        }
+        let mut addr = peer.to_node_addr();
+        addr.info.direct_addresses.extend(hints.iter().copied());
+        if !addr.info.direct_addresses.is_empty() {
+            let _ = endpoint.add_node_addr(addr);
+        }
+        let result = gossip::dial(endpoint, peer.node_id, &cfg.cluster_secret, deadline).await;
+        match result {
+            Ok(connection) => {
+                connection.close(0u32.into(), b"doctor done");
+                report.pass(name, "connected and authenticated");
+            }
+            Err(e) => report.fail(name, format!("{:#}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skipped_checks_dont_fail_the_report() {
+        let mut report = Report::default();
+        report.pass("config", "loaded");
+        report.skip("relay", "relay is disabled");
+        let report = report.finish();
+        assert!(report.ok);
+        assert!(report.to_string().ends_with("All checks passed"));
+
+        let mut report = Report::default();
+        report.pass("config", "loaded");
+        report.fail("dns_bind", "Address in use");
+        let report = report.finish();
+        assert!(!report.ok);
+        let text = report.to_string();
+        assert!(text.contains("FAIL  dns_bind  Address in use"));
+        assert!(text.ends_with("1 check failed"));
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["ok"], false);
+        assert_eq!(json["checks"][1]["outcome"], "fail");
+    }
+}
@@ -0,0 +1,123 @@
+//! Extended DNS errors (RFC 8914): why a query was refused or failed.
+//!
+//! SERVFAIL and REFUSED alone don't say whether the upstream timed out, the
+//! client is blocked or the node is still warming up.  Clients that sent
+//! EDNS get an EDE option saying which, with the query's request id in its
+//! text so that it can be matched with the server's log lines.
+
+use hickory_server::proto::op::ResponseCode;
+use hickory_server::proto::rr::rdata::opt::EdnsOption;
+
+/// The EDNS option code of an extended DNS error.
+pub const EDE: u16 = 15;
+
+/// What went wrong, as told to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtendedError {
+    /// The forwarded lookup didn't finish in time.
+    UpstreamTimeout,
+    /// The upstream couldn't be reached or gave no usable answer.
+    UpstreamUnreachable,
+    /// Too many forwarded lookups in flight and waiting.
+    Overloaded,
+    /// The client is over its forwarding rate.
+    RateLimited,
+    /// The client isn't allowed by `dns_allow`/`dns_deny`.
+    Blocked,
+    /// The client isn't allowed by `forward_allow`.
+    ForwardingBlocked,
+    /// Forwarding is off and the name isn't ours.
+    NotAuthoritative,
+    /// The node hasn't heard from its peers yet.
+    WarmingUp,
+    /// An opcode other than QUERY.
+    UnsupportedOpcode,
+    /// A class other than IN and CHAOS, or a CHAOS name glued doesn't
+    /// answer.
+    UnsupportedClass,
+    /// A zone transfer the client may not have.
+    TransferRefused,
+}
+
+impl ExtendedError {
+    /// The response code sent with it.
+    pub fn response_code(self) -> ResponseCode {
+        match self {
+            ExtendedError::UpstreamTimeout
+            | ExtendedError::UpstreamUnreachable
+            | ExtendedError::Overloaded
+            | ExtendedError::WarmingUp => ResponseCode::ServFail,
+            ExtendedError::UnsupportedOpcode => ResponseCode::NotImp,
+            _ => ResponseCode::Refused,
+        }
+    }
+
+    /// The INFO-CODE from the IANA registry.
+    pub fn info_code(self) -> u16 {
+        match self {
+            ExtendedError::UpstreamTimeout | ExtendedError::Overloaded => 22,
+            ExtendedError::UpstreamUnreachable => 23,
+            ExtendedError::RateLimited => 0,
+            ExtendedError::Blocked
+            | ExtendedError::ForwardingBlocked
+            | ExtendedError::TransferRefused => 18,
+            ExtendedError::NotAuthoritative => 20,
+            ExtendedError::WarmingUp => 14,
+            ExtendedError::UnsupportedOpcode | ExtendedError::UnsupportedClass => 21,
+        }
+    }
+
+    pub fn text(self) -> &'static str {
+        match self {
+            ExtendedError::UpstreamTimeout => "upstream timeout",
+            ExtendedError::UpstreamUnreachable => "upstream unreachable",
+            ExtendedError::Overloaded => "too many forwarded lookups",
+            ExtendedError::RateLimited => "forwarding rate exceeded",
+            ExtendedError::Blocked => "blocked by ACL",
+            ExtendedError::ForwardingBlocked => "forwarding blocked by ACL",
+            ExtendedError::NotAuthoritative => "forwarding disabled",
+            ExtendedError::WarmingUp => "not ready (warming up)",
+            ExtendedError::UnsupportedOpcode => "opcode not supported",
+            ExtendedError::UnsupportedClass => "class not supported",
+            ExtendedError::TransferRefused => "zone transfer not allowed",
+        }
+    }
+
+    /// The EDE option for the query with request id `id`.
+    pub fn option(self, id: &str) -> EdnsOption {
+        let text = format!("{} (request {})", self.text(), id);
+        let mut data = self.info_code().to_be_bytes().to_vec();
+        data.extend_from_slice(text.as_bytes());
+        EdnsOption::Unknown(EDE, data)
+    }
+}
+
+/// The INFO-CODE and text of the EDE option in `option`, if it is one.
+pub fn parse(option: &EdnsOption) -> Option<(u16, String)> {
+    let EdnsOption::Unknown(EDE, data) = option else {
+        return None;
+    };
+    let code = u16::from_be_bytes([*data.first()?, *data.get(1)?]);
+    Some((code, String::from_utf8_lossy(&data[2..]).into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_server::proto::rr::rdata::opt::EdnsCode;
+
+    #[test]
+    fn options_carry_the_code_text_and_request_id() {
+        let option = ExtendedError::UpstreamTimeout.option("1a2b3c4d");
+        assert_eq!(EdnsCode::from(&option), EdnsCode::from(EDE));
+        assert_eq!(
+            parse(&option),
+            Some((22, "upstream timeout (request 1a2b3c4d)".to_string()))
+        );
+        assert_eq!(
+            parse(&ExtendedError::WarmingUp.option("0")).map(|(code, _)| code),
+            Some(14)
+        );
+        assert_eq!(parse(&EdnsOption::Unknown(EDE, vec![0])), None);
+    }
+}
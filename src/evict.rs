@@ -0,0 +1,184 @@
+//! Evicting a decommissioned node's entries from every node at once.
+//!
+//! A node's entries last until it withdraws them or, with leases, until
+//! they run out, which for a host taken down for good may be never.  `POST
+//! /v1/evict/{node}` or `glued ctl evict NODE` publishes an
+//! [`Update::EvictNode`], and every node applying it drops what that node
+//! published: its container entries, served or set aside, and its pins.
+//! For `eviction_grace_secs` after, updates from the node are ignored, so
+//! that gossip still in flight, or the node's own last announcements, don't
+//! bring them back.  Then a node with that NodeId can publish again.
+//!
+//! [`Update::EvictNode`]: crate::types::Update::EvictNode
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::bail;
+use iroh::NodeId;
+
+use crate::conflicts::Conflicts;
+use crate::gossip::short_node_id;
+use crate::pins;
+use crate::types::{Entry, Source, StateMap};
+
+/// How long updates from an evicted node are ignored, by default.
+pub const DEFAULT_GRACE: Duration = Duration::from_secs(600);
+
+/// The nodes evicted lately, and until when their updates are ignored.
+#[derive(Debug)]
+pub struct Evictions {
+    grace: Duration,
+    /// Unix time in milliseconds, by short NodeId.
+    until: HashMap<String, u64>,
+}
+
+impl Default for Evictions {
+    fn default() -> Self {
+        Self::new(DEFAULT_GRACE)
+    }
+}
+
+impl Evictions {
+    pub fn new(grace: Duration) -> Self {
+        Self {
+            grace,
+            until: HashMap::new(),
+        }
+    }
+
+    /// Ignores updates from `node` for the grace period from `now` (Unix
+    /// time in milliseconds).
+    pub fn evict(&mut self, node: &str, now: u64) {
+        let until = now.saturating_add(self.grace.as_millis() as u64);
+        self.until.insert(node.to_string(), until);
+    }
+
+    /// Whether updates from `node` are ignored at `now`.  Evictions whose
+    /// grace period has passed are forgotten.
+    pub fn ignores(&mut self, node: &str, now: u64) -> bool {
+        self.until.retain(|_, until| *until > now);
+        self.until.contains_key(node)
+    }
+}
+
+/// The short NodeId entries record for `node`, given as a NodeId or in
+/// that short form.
+pub fn short_id(node: &str) -> anyhow::Result<String> {
+    if let Ok(id) = node.parse::<NodeId>() {
+        return Ok(short_node_id(id.as_bytes()));
+    }
+    let short = node.to_ascii_lowercase();
+    if short.len() != 10 || !short.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("'{}' is neither a NodeId nor a short one", node);
+    }
+    Ok(short)
+}
+
+/// Drops what `node` published from `map` and `conflicts`, serving the
+/// entries others set aside where its went.  Returns the names it had
+/// entries for.
+pub fn drop_entries(map: &mut StateMap, conflicts: &mut Conflicts, node: &str) -> Vec<String> {
+    let by_node = |entry: &Entry| entry.node.as_deref() == Some(node);
+    let mut names: Vec<String> = map
+        .iter()
+        .filter(|(_, entry)| by_node(entry) || entry.shadowed.as_deref().is_some_and(by_node))
+        .map(|(name, _)| name.clone())
+        .collect();
+    let set_aside: Vec<String> = conflicts.names().cloned().collect();
+    for name in set_aside {
+        if conflicts.withdraw(&name, Some(node)) {
+            names.push(name);
+        }
+    }
+    names.sort();
+    names.dedup();
+    for name in &names {
+        if let Some(entry) = map.get_mut(name) {
+            if entry.shadowed.as_deref().is_some_and(by_node) {
+                entry.shadowed = None;
+            }
+            if by_node(entry) {
+                match entry.source {
+                    Source::Manual => {
+                        pins::unpin(map, name);
+                    }
+                    _ => {
+                        map.remove(name);
+                    }
+                }
+            }
+        }
+        conflicts.promote(map, name);
+        conflicts.merge(map, name);
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gossip::apply_update_from;
+    use crate::types::{SharedState, Update};
+    use iroh::SecretKey;
+
+    const GONE: &str = "aaaaaaaaaa";
+    const STAYS: &str = "bbbbbbbbbb";
+
+    fn add(name: &str, ip: &str) -> Update {
+        Update::Add {
+            name: name.into(),
+            ip: ip.parse().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn an_evicted_node_loses_its_entries_until_the_grace_period_ends() {
+        let state = SharedState::default();
+        apply_update_from(add("web-1", "10.0.0.1"), Some(GONE), &state).await;
+        apply_update_from(add("db", "10.0.0.2"), Some(GONE), &state).await;
+        // Served from the lower NodeId; the other node's is set aside.
+        apply_update_from(add("db", "10.0.0.3"), Some(STAYS), &state).await;
+        apply_update_from(add("api", "10.0.0.4"), Some(STAYS), &state).await;
+        let pin = Update::Pin {
+            name: "api".into(),
+            ip: "10.0.0.5".parse().unwrap(),
+            expires_at: None,
+        };
+        apply_update_from(pin, Some(GONE), &state).await;
+
+        let evict = Update::EvictNode { node: GONE.into() };
+        apply_update_from(evict, Some(STAYS), &state).await;
+        {
+            let map = state.read().await;
+            assert!(!map.contains_key("web-1"));
+            assert_eq!(map["db"].ip.to_string(), "10.0.0.3");
+            // Its pin went, and the container it hid is served again.
+            assert_eq!(map["api"].source, Source::Cluster);
+            assert_eq!(map["api"].node.as_deref(), Some(STAYS));
+        }
+        assert!(state.conflicts().await.is_empty());
+
+        // Late gossip from it is ignored.
+        apply_update_from(add("web-1", "10.0.0.1"), Some(GONE), &state).await;
+        assert!(!state.read().await.contains_key("web-1"));
+
+        let mut evictions = Evictions::new(Duration::from_secs(60));
+        evictions.evict(GONE, 1_000);
+        assert!(evictions.ignores(GONE, 60_999));
+        assert!(!evictions.ignores(STAYS, 1_000));
+        assert!(!evictions.ignores(GONE, 61_000));
+    }
+
+    #[test]
+    fn nodes_are_named_in_full_or_short() {
+        let id = SecretKey::from_bytes(&[7; 32]).public();
+        assert_eq!(
+            short_id(&id.to_string()).unwrap(),
+            short_node_id(id.as_bytes())
+        );
+        assert_eq!(short_id("AB12CD34EF").unwrap(), "ab12cd34ef");
+        assert!(short_id("ab12").is_err());
+        assert!(short_id("web-1.example").is_err());
+    }
+}
@@ -0,0 +1,557 @@
+//! Upstream resolvers for forwarded queries.
+//!
+//! Names under a configured forward zone go to that zone's upstreams; the
+//! zone with the longest matching suffix wins.  Everything else goes to the
+//! default (system) resolver.  A zone's upstreams are tried in the order
+//! given; when all are unreachable the lookup fails, unless the zone allows
+//! falling through to the default resolver.
+//!
+//! Upstreams are plain DNS (`ip` or `ip:port`) or DNS over TLS
+//! (`tls://ip[:port]#server-name`), whose certificate must be valid for
+//! `server-name`.
+//!
+//! Each forwarded lookup is bounded by [`ForwardLimits`]: it is abandoned
+//! after a timeout, and only so many run at once, with so many more
+//! waiting.  Each client network may be limited to a rate of them, and
+//! names upstreams said don't exist are kept in a
+//! [`crate::negative_cache`].
+//!
+//! Forwarded answers keep the TTLs of the upstream's records, as left in
+//! the resolver's cache, within a [`TtlClamp`].  Only the answer is
+//! clamped: the cache keeps each record for the TTL the upstream gave.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Context;
+use hickory_proto::rr::{LowerName, Name, RecordType};
+use hickory_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use hickory_resolver::lookup_ip::LookupIp;
+use hickory_resolver::TokioAsyncResolver;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, ForwardZone};
+use crate::rrl::RatePolicy;
+
+/// Default DNS port for upstreams given without one.
+const DNS_PORT: u16 = 53;
+
+/// Default port for DNS over TLS (RFC 7858).
+const DOT_PORT: u16 = 853;
+
+/// Scheme prefix of a DNS-over-TLS upstream.
+const TLS_SCHEME: &str = "tls://";
+
+/// Bounds on forwarded lookups.
+#[derive(Debug, Clone, Copy)]
+pub struct ForwardLimits {
+    /// How long a client waits before getting SERVFAIL.
+    pub timeout: Duration,
+    /// Lookups in flight at once.
+    pub max_inflight: usize,
+    /// Queries waiting for a lookup to finish; further queries get
+    /// SERVFAIL at once.
+    pub max_queued: usize,
+    /// How fast each client network may have names forwarded.
+    pub rate: RatePolicy,
+}
+
+impl ForwardLimits {
+    pub fn from_config(cfg: &Config) -> anyhow::Result<Self> {
+        if cfg.dns_forward_timeout_ms == 0 {
+            anyhow::bail!("dns_forward_timeout_ms must be greater than zero");
+        }
+        if cfg.dns_forward_max_inflight == 0 {
+            anyhow::bail!("dns_forward_max_inflight must be greater than zero");
+        }
+        Ok(Self {
+            timeout: Duration::from_millis(cfg.dns_forward_timeout_ms),
+            max_inflight: cfg.dns_forward_max_inflight,
+            max_queued: cfg.dns_forward_max_queued,
+            rate: RatePolicy::for_forwarding(cfg),
+        })
+    }
+}
+
+/// Bounds on the TTLs forwarded answers are served with, in seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TtlClamp {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl TtlClamp {
+    pub fn from_config(cfg: &Config) -> anyhow::Result<Self> {
+        if cfg.dns_forward_ttl_min > cfg.dns_forward_ttl_max {
+            anyhow::bail!(
+                "dns_forward_ttl_min ({}) is above dns_forward_ttl_max ({})",
+                cfg.dns_forward_ttl_min,
+                cfg.dns_forward_ttl_max
+            );
+        }
+        Ok(Self {
+            min: cfg.dns_forward_ttl_min,
+            max: cfg.dns_forward_ttl_max,
+        })
+    }
+
+    pub fn apply(self, ttl: u32) -> u32 {
+        ttl.clamp(self.min, self.max)
+    }
+}
+
+/// One upstream server.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Upstream {
+    pub addr: SocketAddr,
+    /// Name the server's certificate is verified against; `None` for plain DNS.
+    pub tls_name: Option<String>,
+}
+
+impl FromStr for Upstream {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        let Some(rest) = s.strip_prefix(TLS_SCHEME) else {
+            return Ok(Self {
+                addr: parse_addr(s, DNS_PORT)?,
+                tls_name: None,
+            });
+        };
+        let (addr, name) = rest.split_once('#').ok_or_else(|| {
+            anyhow::anyhow!(
+                "TLS upstream '{}' needs a server name: tls://ip:port#name",
+                s
+            )
+        })?;
+        let name = name.trim_end_matches('.');
+        if name.is_empty() || Name::from_ascii(name).is_err() {
+            anyhow::bail!("Invalid TLS server name in upstream '{}'", s);
+        }
+        Ok(Self {
+            addr: parse_addr(addr, DOT_PORT)?,
+            tls_name: Some(name.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.tls_name {
+            Some(name) => write!(f, "{}{}#{}", TLS_SCHEME, self.addr, name),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+impl TryFrom<String> for Upstream {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Upstream> for String {
+    fn from(upstream: Upstream) -> Self {
+        upstream.to_string()
+    }
+}
+
+struct Zone {
+    name: LowerName,
+    /// One per upstream, in the configured order.
+    resolvers: Vec<(Upstream, TokioAsyncResolver)>,
+    fallthrough: bool,
+}
+
+/// The resolver `/etc/resolv.conf` names, or Google DNS without one.
+pub fn system_resolver() -> TokioAsyncResolver {
+    TokioAsyncResolver::tokio_from_system_conf().unwrap_or_else(|e| {
+        error!(
+            "Failed to load system resolv.conf: {}. Falling back to Google DNS.",
+            e
+        );
+        TokioAsyncResolver::tokio(ResolverConfig::google(), ResolverOpts::default())
+    })
+}
+
+/// The default resolver and the per-zone forwarders.
+pub struct Upstreams {
+    default: TokioAsyncResolver,
+    /// Most specific zone first.
+    zones: Vec<Zone>,
+}
+
+impl Upstreams {
+    /// Builds a resolver for each of `zones`, using `opts` for all of them.
+    pub fn new(
+        default: TokioAsyncResolver,
+        zones: &BTreeMap<String, ForwardZone>,
+        opts: ResolverOpts,
+    ) -> anyhow::Result<Self> {
+        let mut built = Vec::with_capacity(zones.len());
+        for (zone, forward) in zones {
+            let name = Name::from_ascii(zone.trim_end_matches('.'))
+                .with_context(|| format!("Invalid forward zone '{}'", zone))?;
+            if forward.upstreams.is_empty() {
+                anyhow::bail!("Forward zone '{}' has no upstreams", zone);
+            }
+            let list: Vec<String> = forward.upstreams.iter().map(|u| u.to_string()).collect();
+            info!("Forwarding *.{} to {}", name, list.join(", "));
+            built.push(Zone {
+                name: LowerName::new(&name),
+                resolvers: forward
+                    .upstreams
+                    .iter()
+                    .map(|upstream| (upstream.clone(), resolver_for(upstream, opts.clone())))
+                    .collect(),
+                fallthrough: forward.fallthrough,
+            });
+        }
+        built.sort_by_key(|zone| std::cmp::Reverse(zone.name.num_labels()));
+        Ok(Self {
+            default,
+            zones: built,
+        })
+    }
+
+    /// The most specific zone containing `name`.
+    fn zone_for(&self, name: &LowerName) -> Option<&Zone> {
+        self.zones.iter().find(|zone| zone.name.zone_of(name))
+    }
+
+    /// Resolves `name` through its zone's upstreams or the default resolver.
+    pub async fn lookup_ip(&self, name: &LowerName) -> Result<LookupIp, ResolveError> {
+        let host = name.to_string();
+        let Some(zone) = self.zone_for(name) else {
+            return self.default.lookup_ip(host).await;
+        };
+        match zone.lookup_ip(&host).await {
+            Err(e) if zone.fallthrough && is_unreachable(&e) => {
+                debug!(
+                    "Upstreams for {} unreachable ({}); using the default resolver",
+                    zone.name, e
+                );
+                self.default.lookup_ip(host).await
+            }
+            result => result,
+        }
+    }
+
+    /// Asks the default resolver for the root's name servers, and each
+    /// zone upstream for its zone's SOA, returning who failed to answer.
+    /// An answer without records counts as an answer.
+    pub async fn probe(&self) -> Vec<(String, Result<(), ResolveError>)> {
+        let answered = |result: Result<_, ResolveError>| match result {
+            Err(e) if is_unreachable(&e) => Err(e),
+            _ => Ok(()),
+        };
+        let mut results = vec![(
+            "system resolver".to_string(),
+            answered(self.default.lookup(Name::root(), RecordType::NS).await),
+        )];
+        for zone in &self.zones {
+            for (upstream, resolver) in &zone.resolvers {
+                let name = Name::from(&zone.name);
+                let result = answered(resolver.lookup(name, RecordType::SOA).await);
+                results.push((format!("{} for {}", upstream, zone.name), result));
+            }
+        }
+        results
+    }
+}
+
+impl Zone {
+    /// Asks each upstream in turn until one answers.
+    async fn lookup_ip(&self, host: &str) -> Result<LookupIp, ResolveError> {
+        let mut result = Err(ResolveError::from("no upstreams"));
+        for (i, (_, resolver)) in self.resolvers.iter().enumerate() {
+            result = resolver.lookup_ip(host).await;
+            match &result {
+                Err(e) if is_unreachable(e) && i + 1 < self.resolvers.len() => {
+                    debug!(
+                        "Upstream {} for {} unreachable ({}); trying the next",
+                        i + 1,
+                        self.name,
+                        e
+                    );
+                }
+                _ => break,
+            }
+        }
+        result
+    }
+}
+
+/// Whether `e` means no upstream answered, rather than an answer without records.
+fn is_unreachable(e: &ResolveError) -> bool {
+    !matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. })
+}
+
+/// Parses `ip` or `ip:port`, including `[v6]:port`.
+fn parse_addr(addr: &str, default_port: u16) -> anyhow::Result<SocketAddr> {
+    if let Ok(addr) = addr.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    let ip: IpAddr = addr
+        .parse()
+        .with_context(|| format!("'{}' is not an address", addr))?;
+    Ok(SocketAddr::new(ip, default_port))
+}
+
+/// A resolver querying only `upstream`: over TLS, or over UDP with TCP fallback.
+fn resolver_for(upstream: &Upstream, opts: ResolverOpts) -> TokioAsyncResolver {
+    let servers = match &upstream.tls_name {
+        Some(name) => {
+            let mut server = NameServerConfig::new(upstream.addr, Protocol::Tls);
+            server.tls_dns_name = Some(name.clone());
+            vec![server]
+        }
+        None => vec![
+            NameServerConfig::new(upstream.addr, Protocol::Udp),
+            NameServerConfig::new(upstream.addr, Protocol::Tcp),
+        ],
+    };
+    TokioAsyncResolver::tokio(ResolverConfig::from_parts(None, vec![], servers), opts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hickory_proto::op::{Message, MessageType};
+    use hickory_proto::rr::rdata::A;
+    use hickory_proto::rr::{RData, Record};
+    use tokio::net::UdpSocket;
+
+    /// A UDP DNS server answering every query with `ip`.
+    async fn fake_upstream(ip: [u8; 4]) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            loop {
+                let (len, src) = socket.recv_from(&mut buf).await.unwrap();
+                let mut msg = Message::from_vec(&buf[..len]).unwrap();
+                let name = msg.queries()[0].name().clone();
+                msg.set_message_type(MessageType::Response)
+                    .add_answer(Record::from_rdata(
+                        name,
+                        60,
+                        RData::A(A::from(std::net::Ipv4Addr::from(ip))),
+                    ));
+                socket.send_to(&msg.to_vec().unwrap(), src).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    /// An address nothing listens on.
+    async fn dead_upstream() -> SocketAddr {
+        UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    fn opts() -> ResolverOpts {
+        let mut opts = ResolverOpts::default();
+        opts.timeout = Duration::from_millis(200);
+        opts.attempts = 1;
+        opts.cache_size = 0;
+        opts
+    }
+
+    fn zone(upstream: SocketAddr, fallthrough: bool) -> ForwardZone {
+        ForwardZone {
+            upstreams: vec![plain(upstream)],
+            fallthrough,
+        }
+    }
+
+    fn plain(addr: SocketAddr) -> Upstream {
+        Upstream {
+            addr,
+            tls_name: None,
+        }
+    }
+
+    async fn resolve(upstreams: &Upstreams, name: &str) -> Result<Vec<IpAddr>, ResolveError> {
+        let name = LowerName::new(&Name::from_ascii(name).unwrap());
+        Ok(upstreams.lookup_ip(&name).await?.iter().collect())
+    }
+
+    fn ips(list: &[&str]) -> Vec<IpAddr> {
+        list.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn longest_matching_zone_wins() {
+        let default = resolver_for(&plain(fake_upstream([192, 0, 2, 1]).await), opts());
+        let mut zones = BTreeMap::new();
+        zones.insert(
+            "corp.example".to_string(),
+            zone(fake_upstream([192, 0, 2, 2]).await, false),
+        );
+        zones.insert(
+            "lab.corp.example.".to_string(),
+            zone(fake_upstream([192, 0, 2, 3]).await, false),
+        );
+        let upstreams = Upstreams::new(default, &zones, opts()).unwrap();
+
+        for (name, ip) in [
+            ("www.example.org.", "192.0.2.1"),
+            ("corp.example.", "192.0.2.2"),
+            ("git.corp.example.", "192.0.2.2"),
+            ("host.lab.corp.example.", "192.0.2.3"),
+            ("notcorp.example.", "192.0.2.1"),
+        ] {
+            assert_eq!(
+                resolve(&upstreams, name).await.unwrap(),
+                ips(&[ip]),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_zone_fails_unless_it_falls_through() {
+        let default = || TokioAsyncResolver::tokio(ResolverConfig::new(), opts());
+        let dead = dead_upstream().await;
+
+        let mut zones = BTreeMap::new();
+        zones.insert("down.example".to_string(), zone(dead, false));
+        let upstreams = Upstreams::new(default(), &zones, opts()).unwrap();
+        assert!(resolve(&upstreams, "host.down.example.").await.is_err());
+
+        let fallback = resolver_for(&plain(fake_upstream([192, 0, 2, 9]).await), opts());
+        zones.insert("down.example".to_string(), zone(dead, true));
+        let upstreams = Upstreams::new(fallback, &zones, opts()).unwrap();
+        assert_eq!(
+            resolve(&upstreams, "host.down.example.").await.unwrap(),
+            ips(&["192.0.2.9"])
+        );
+    }
+
+    #[tokio::test]
+    async fn upstreams_are_tried_in_order() {
+        let default = || TokioAsyncResolver::tokio(ResolverConfig::new(), opts());
+        // A TLS upstream whose port refuses connections.
+        let refused = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let first = fake_upstream([192, 0, 2, 1]).await;
+        let second = fake_upstream([192, 0, 2, 2]).await;
+
+        let mut zones = BTreeMap::new();
+        zones.insert(
+            "mixed.example".to_string(),
+            ForwardZone {
+                upstreams: vec![
+                    format!("tls://{}#dns.example", refused).parse().unwrap(),
+                    plain(dead_upstream().await),
+                    plain(first),
+                    plain(second),
+                ],
+                fallthrough: false,
+            },
+        );
+        let upstreams = Upstreams::new(default(), &zones, opts()).unwrap();
+        assert_eq!(
+            resolve(&upstreams, "host.mixed.example.").await.unwrap(),
+            ips(&["192.0.2.1"])
+        );
+    }
+
+    #[test]
+    fn forward_limits_must_be_non_zero() {
+        let limits = ForwardLimits::from_config(&Config::default()).unwrap();
+        assert_eq!(limits.timeout, Duration::from_secs(2));
+        for cfg in [
+            Config {
+                dns_forward_timeout_ms: 0,
+                ..Config::default()
+            },
+            Config {
+                dns_forward_max_inflight: 0,
+                ..Config::default()
+            },
+        ] {
+            assert!(ForwardLimits::from_config(&cfg).is_err());
+        }
+    }
+
+    #[test]
+    fn upstream_addresses() {
+        let upstream = |s: &str| s.parse::<Upstream>();
+        assert_eq!(
+            upstream("10.1.1.53").unwrap(),
+            plain("10.1.1.53:53".parse().unwrap())
+        );
+        assert_eq!(
+            upstream("[fd00::1]:5353").unwrap(),
+            plain("[fd00::1]:5353".parse().unwrap())
+        );
+        assert!(upstream("dns.example").is_err());
+
+        let tls = upstream("tls://1.1.1.1#cloudflare-dns.com").unwrap();
+        assert_eq!(tls.addr, "1.1.1.1:853".parse().unwrap());
+        assert_eq!(tls.tls_name.as_deref(), Some("cloudflare-dns.com"));
+        assert_eq!(tls.to_string(), "tls://1.1.1.1:853#cloudflare-dns.com");
+        assert_eq!(
+            upstream("tls://[2606:4700::1111]:8853#one.one.one.one")
+                .unwrap()
+                .addr,
+            "[2606:4700::1111]:8853".parse().unwrap()
+        );
+        for bad in [
+            "tls://1.1.1.1:853",
+            "tls://1.1.1.1#",
+            "tls://1.1.1.1#bad name",
+            "tls://cloudflare-dns.com#cloudflare-dns.com",
+        ] {
+            assert!(upstream(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn probe_reports_each_upstream() {
+        let default = resolver_for(&plain(fake_upstream([192, 0, 2, 1]).await), opts());
+        let dead = dead_upstream().await;
+        let live = fake_upstream([192, 0, 2, 2]).await;
+        let mut zones = BTreeMap::new();
+        zones.insert(
+            "corp.example".to_string(),
+            ForwardZone {
+                upstreams: vec![plain(dead), plain(live)],
+                fallthrough: true,
+            },
+        );
+        let upstreams = Upstreams::new(default, &zones, opts()).unwrap();
+
+        let probed = upstreams.probe().await;
+        let answered: Vec<(&str, bool)> = probed
+            .iter()
+            .map(|(upstream, result)| (upstream.as_str(), result.is_ok()))
+            .collect();
+        assert_eq!(
+            answered,
+            vec![
+                ("system resolver", true),
+                (format!("{} for corp.example", dead).as_str(), false),
+                (format!("{} for corp.example", live).as_str(), true),
+            ]
+        );
+    }
+}
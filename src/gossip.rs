@@ -1,227 +1,1963 @@
 //! Gossip subsystem based on Iroh.
 
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use iroh::{Endpoint, NodeId};
-use iroh_gossip::{net::Gossip, proto::TopicId};
-use log::{error, info, warn};
-use sha2::Digest;
-use tokio::sync::{mpsc, RwLock};
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use futures_util::StreamExt;
 
-use crate::types::Update;
+use hmac::{Hmac, Mac};
+use iroh::discovery::local_swarm_discovery::LocalSwarmDiscovery;
+use iroh::{Endpoint, NodeId, SecretKey};
+use iroh_gossip::net::{Event, Gossip, GossipEvent, GossipReceiver, GossipSender, GOSSIP_ALPN};
+use iroh_gossip::proto::TopicId;
+use log::{debug, error, info, warn, Level};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::{field, info_span, Instrument, Span};
+
+use crate::bootstrap::ServiceDiscovery;
+use crate::config::{BootstrapPeer, Config, DiscoveryMode, NodeRole};
+use crate::conflicts::Conflicts;
+use crate::crdt::Stamp;
+use crate::dedup::{Rejection, SeenTable};
+use crate::evict;
+use crate::limits::{self, check_name, Limits};
+use crate::lockout::{Lockout, LockoutPolicy};
+use crate::log_dedup::LogDedup;
+use crate::metrics::{self, METRICS};
+use crate::names::normalize;
+use crate::networks::{parse_topic, Network, Networks};
+use crate::peers::{PathKind, PeerPolicy};
+use crate::pins;
+use crate::registry::{Change, ChangeOrigin, LocalSource, LocalUpdate, PeerNode};
+use crate::seal::GossipKey;
+use crate::sessions::SessionTable;
+use crate::status::Status;
+use crate::txt;
+use crate::types::{now_millis, Entry, Port, ScopedIp, SharedState, Source, StateMap, Update};
+use crate::wire::{self, Body, Origin};
+
+/// ALPN of the mutual authentication handshake.
+const AUTH_ALPN: &[u8] = b"glued/auth/2";
+/// ALPN spoken by releases that only authenticated the initiator.  Still
+/// accepted so such peers get a clear error instead of a bare ALPN mismatch.
+const LEGACY_AUTH_ALPN: &[u8] = b"glued/auth/1";
+/// Version byte leading each side's hello.
+const AUTH_VERSION: u8 = 2;
+const AUTH_OK: &[u8; 7] = b"AUTH_OK";
+const NONCE_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Minimum gap between answers to sync requests, so a burst of joining
+/// nodes doesn't make every publisher resend its entries for each one.
+const SYNC_ANSWER_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the mesh summary is logged.
+const PEER_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Shortest time between two warnings about malformed gossip delivered by
+/// one peer.
+const MALFORMED_WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Requests to withdraw this node's entries on shutdown, each answered once
+/// the removals have gone out.
+pub type Withdrawals = mpsc::Receiver<oneshot::Sender<()>>;
 
-/// Runs the gossip subsystem.
 /// Runs the gossip subsystem.
 pub async fn run_gossip(
-    topic_id: String,
-    bootstrap_peers: Vec<String>,
-    mut outbound_rx: mpsc::Receiver<Update>,
-    inbound_tx: mpsc::Sender<Update>,
-    cluster_secret: String,
+    cfg: Config,
+    outbound_rx: &mut mpsc::Receiver<Update>,
+    withdrawals: &mut Withdrawals,
+    inbound_tx: mpsc::Sender<(Update, Option<PeerNode>)>,
+    status: Arc<Status>,
+    state: SharedState,
+    mut reloads: watch::Receiver<Arc<Config>>,
 ) -> anyhow::Result<()> {
-    // Create a new Iroh endpoint.
-    let endpoint = Endpoint::builder().discovery_n0().bind().await?;
+    let auth_timeout = Duration::from_secs(cfg.auth_timeout_secs);
+    // Background tasks go when this returns or unwinds, so a restart
+    // doesn't leave the old endpoint's tasks running.
+    let mut tasks = JoinSet::new();
+
+    let endpoint = bind_endpoint(&cfg).await?;
     let our_id = endpoint.node_id();
-    info!("Gossip endpoint created with ID: {}", our_id);
+    let our_name = cfg.node_name();
+    state.set_node(short_node_id(our_id.as_bytes())).await;
+    info!(
+        "Gossip endpoint created with ID: {} (name: {}, discovery: {:?}, relay: {}, port: {})",
+        our_id, our_name, cfg.discovery, cfg.relay, cfg.gossip_port
+    );
 
     // Spawn gossip protocol
     let my_addr = endpoint.node_addr().await?;
-    let gossip = Gossip::from_endpoint(
-        endpoint.clone(),
-        iroh_gossip::proto::Config::default(),
-        &my_addr.info,
-    );
+    let gossip = Gossip::from_endpoint(endpoint.clone(), cfg.gossip.to_proto()?, &my_addr.info);
 
-    // Decode topic ID
-    let topic_bytes = hex::decode(&topic_id)?;
-    let _topic_id_struct = TopicId::from_bytes(
-        topic_bytes
-            .try_into()
-            .map_err(|_| anyhow::anyhow!("Invalid topic ID length"))?,
-    );
+    let shared_topic = parse_topic(&cfg.topic_id)?;
+    let networks = Arc::new(Networks::from_config(&cfg)?);
 
-    // Parse bootstrap peers and filter out self
-    let mut bootstrap_ids = Vec::new();
-    for peer in bootstrap_peers {
-        if let Ok(id) = peer.parse::<NodeId>() {
-            if id != our_id {
-                bootstrap_ids.push(id);
+    // The peer lists as last reloaded, which may be newer than `cfg`.
+    let policy = PeerPolicy::from_config(&reloads.borrow_and_update());
+    let policy = Arc::new(ArcSwap::from_pointee(policy));
+    // Filter ourselves and peers we would refuse out of the bootstrap peers
+    let bootstrap_peers: Vec<BootstrapPeer> = cfg
+        .bootstrap_peers
+        .iter()
+        .filter(|peer| peer.node_id != our_id)
+        .filter(|peer| match policy.load().rejects(&peer.node_id) {
+            Some(reason) => {
+                warn!("Not dialling bootstrap peer {}: {}", peer.node_id, reason);
+                false
             }
-        } else {
-            warn!("Invalid bootstrap peer ID: {}", peer);
+            None => true,
+        })
+        .cloned()
+        .collect();
+
+    // Keep re-resolving the bootstrap service; its addresses become dial hints.
+    let (discovered_tx, discovered_rx) = watch::channel(BTreeSet::new());
+    if let Some(service) = &cfg.bootstrap_service {
+        if bootstrap_peers.is_empty() {
+            warn!(
+                "bootstrap_service '{}' only finds addresses for bootstrap_peers, and none are configured",
+                service
+            );
         }
+        let discovery = ServiceDiscovery::new(service, cfg.gossip_port);
+        let interval = Duration::from_secs(cfg.bootstrap_interval_secs.max(1));
+        tasks.spawn(discovery.run(interval, discovered_tx));
     }
 
-    // Join the gossip topic
-    // Note: In iroh-gossip 0.29, we join via the router or similar.
-    // For now, we'll assume standard join logic if available, or just use the gossip handle.
-    // The original code had commented out subscription. We will re-enable basic join if possible,
-    // but first let's handle the authentication and connection management.
+    // Join the shared topic and each network's; the bootstrap peers are
+    // our first neighbors on all of them.
+    let key = Arc::new(GossipKey::from_secret(&cfg.cluster_secret));
+    let neighbors: Vec<NodeId> = bootstrap_peers.iter().map(|peer| peer.node_id).collect();
+    let mut topics = Vec::new();
+    let mut receivers = Vec::new();
+    for network in std::iter::once(None).chain(networks.iter().cloned().map(Some)) {
+        let topic_id = network
+            .as_ref()
+            .map_or(shared_topic, |network| network.topic);
+        let (sender, receiver) = gossip
+            .subscribe(TopicId::from_bytes(topic_id), neighbors.clone())?
+            .split();
+        let publisher = Publisher {
+            sender,
+            key: Arc::clone(&key),
+            node: *our_id.as_bytes(),
+            name: our_name.clone(),
+            seq: AtomicU64::new(0),
+            max_payload: cfg.gossip.max_payload_bytes(),
+        };
+        topics.push(Arc::new(Topic {
+            network,
+            publisher,
+            owned: Mutex::default(),
+        }));
+        receivers.push(receiver);
+    }
 
-    // Authentication Handler Task
-    let auth_endpoint = endpoint.clone();
-    let auth_secret = cluster_secret.clone();
-    let auth_node_id = our_id;
-    tokio::spawn(async move {
-        while let Some(incoming) = auth_endpoint.accept().await {
-            let secret = auth_secret.clone();
+    // Incoming connections: auth handshakes and gossip sessions.
+    let acceptor = Arc::new(Acceptor {
+        secret: cfg.cluster_secret.clone(),
+        our_id,
+        lockout: Mutex::new(Lockout::new(LockoutPolicy::from_config(&cfg))),
+        policy: Arc::clone(&policy),
+        deadline: auth_timeout,
+        gossip: gossip.clone(),
+        status: Arc::clone(&status),
+    });
+    let accept_endpoint = endpoint.clone();
+    let handshakes = Arc::new(Semaphore::new(cfg.auth_max_inflight.max(1)));
+    tasks.spawn(async move {
+        while let Some(incoming) = accept_endpoint.accept().await {
+            let Ok(permit) = Arc::clone(&handshakes).try_acquire_owned() else {
+                debug!(
+                    "Refusing connection from {}: too many handshakes in flight",
+                    incoming.remote_address()
+                );
+                incoming.refuse();
+                continue;
+            };
+            let acceptor = Arc::clone(&acceptor);
             tokio::spawn(async move {
-                if let Err(e) = handle_incoming_connection(incoming, secret, auth_node_id).await {
+                let _permit = permit;
+                if let Err(e) = handle_incoming_connection(incoming, &acceptor).await {
                     warn!("Incoming connection failed auth: {}", e);
                 }
             });
         }
     });
 
+    let originates = cfg.role != NodeRole::Dns;
+
+    // Receive loops: track neighbors, apply updates from peers and answer
+    // sync requests.
+    let inbound = Inbound {
+        key,
+        status: Arc::clone(&status),
+        networks: Arc::clone(&networks),
+        updates: inbound_tx,
+        our_id,
+        our_name,
+        max_skew_secs: cfg.gossip_max_skew_secs,
+        max_payload: cfg.gossip.max_payload_bytes(),
+        malformed: Arc::new(Mutex::new(Malformed::new(LockoutPolicy::for_malformed(
+            &cfg,
+        )))),
+        state: Arc::clone(&state),
+        digest_mismatches: cfg.gossip.digest_mismatches(),
+    };
+    for (topic, receiver) in topics.iter().zip(receivers) {
+        tasks.spawn(inbound.clone().receive(Arc::clone(topic), receiver));
+    }
+
+    // Periodic mesh summary, refreshing how each peer is reached.
+    let report_endpoint = endpoint.clone();
+    let report_status = Arc::clone(&status);
+    tasks.spawn(async move {
+        let mut ticker = tokio::time::interval(PEER_REPORT_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let mut table = report_status.peers_mut();
+            let ids: Vec<NodeId> = table.iter().map(|(id, _)| *id).collect();
+            for id in ids {
+                if let Some(remote) = report_endpoint.remote_info(id) {
+                    table.set_path(&id, PathKind::from(&remote.conn_type));
+                }
+            }
+            info!(
+                "gossip: {} neighbors, {} authenticated",
+                table.neighbors(),
+                table.authenticated_count()
+            );
+            drop(table);
+            if let Some(reason) = report_status.unready() {
+                warn!("Not ready: {}", reason);
+            }
+        }
+    });
+
+    // Our state digest goes out on the shared topic, where every node
+    // compares it with its own.
+    if let Some(interval) = cfg.gossip.digest_interval() {
+        let digest_topic = topics
+            .iter()
+            .find(|topic| topic.network.is_none())
+            .cloned()
+            .expect("the shared topic is joined");
+        let digest_state = Arc::clone(&state);
+        tasks.spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // The first tick is immediate; there is nothing to compare yet.
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let digest = Body::Digest(digest_state.digest());
+                digest_topic.publisher.publish(&digest).await;
+            }
+        });
+    }
+
+    // Syncs asked for on demand go out on every topic, as on joining.
+    let mut syncs = status.resync().syncs();
+    let sync_topics = topics.clone();
+    tasks.spawn(async move {
+        while !matches!(syncs.recv().await, Err(broadcast::error::RecvError::Closed)) {
+            info!("Asking peers for a sync on request");
+            for topic in &sync_topics {
+                topic.publisher.publish(&Body::SyncRequest).await;
+            }
+        }
+    });
+
+    // Reloaded peer lists apply to the next connection and dial.
+    let reload_policy = Arc::clone(&policy);
+    tasks.spawn(async move {
+        while reloads.changed().await.is_ok() {
+            let cfg = Arc::clone(&reloads.borrow_and_update());
+            reload_policy.store(Arc::new(PeerPolicy::from_config(&cfg)));
+        }
+    });
+
     // Connection Retry / Maintenance Task
     let conn_endpoint = endpoint.clone();
-    let conn_bootstrap_ids = bootstrap_ids.clone();
-    let conn_secret = cluster_secret.clone();
-    tokio::spawn(async move {
+    // Filtered on each round instead, as the policy may change.
+    let conn_bootstrap_peers: Vec<BootstrapPeer> = cfg
+        .bootstrap_peers
+        .iter()
+        .filter(|peer| peer.node_id != our_id)
+        .cloned()
+        .collect();
+    let conn_secret = cfg.cluster_secret.clone();
+    let conn_status = Arc::clone(&status);
+    let conn_policy = Arc::clone(&policy);
+    let heartbeat = cfg.gossip.heartbeat();
+    tasks.spawn(async move {
+        let mut sessions = SessionTable::default();
+        // An unreachable peer fails every round; that's logged once a minute.
+        let mut dial_failures = LogDedup::new(module_path!(), Level::Warn);
         loop {
-            for &peer_id in &conn_bootstrap_ids {
-                // Check if connected
-                // This is a simplification; iroh might manage connections automatically.
-                // But we want to enforce our auth.
-                match conn_endpoint.connect(peer_id, b"glued/auth/1").await {
-                    Ok(connection) => {
-                        if let Err(e) = perform_auth_handshake(connection, &conn_secret).await {
-                            warn!(
-                                "Failed to authenticate with bootstrap peer {}: {}",
-                                peer_id, e
-                            );
-                        } else {
-                            info!("Authenticated with bootstrap peer {}", peer_id);
-                            // If auth succeeds, we can add them to gossip
-                            // gossip.add_neighbor(topic_id_struct, peer_id); // Hypothetical API
-                        }
-                    }
-                    Err(e) => {
-                        warn!("Failed to connect to bootstrap peer {}: {}", peer_id, e);
+            // Peers denied by a reload since startup aren't dialled anymore.
+            let policy = conn_policy.load();
+            let permitted: Vec<&BootstrapPeer> = conn_bootstrap_peers
+                .iter()
+                .filter(|peer| policy.rejects(&peer.node_id).is_none())
+                .collect();
+            // Tell iroh where to find peers so dialling doesn't depend on discovery.
+            let hints = discovered_rx.borrow().clone();
+            for peer in &permitted {
+                let mut addr = peer.to_node_addr();
+                addr.info.direct_addresses.extend(hints.iter().copied());
+                if addr.info.direct_addresses.is_empty() {
+                    continue;
+                }
+                if let Err(e) = conn_endpoint.add_node_addr(addr) {
+                    debug!("Failed to add addresses for {}: {}", peer.node_id, e);
+                }
+            }
+
+            // Dial every peer without a live session concurrently so one
+            // dead peer can't hold up the rest.
+            let peers: Vec<NodeId> = permitted.iter().map(|peer| peer.node_id).collect();
+            let endpoint = &conn_endpoint;
+            let secret = conn_secret.as_str();
+            let dialled = sessions
+                .maintain(&peers, |peer| dial(endpoint, peer, secret, auth_timeout))
+                .await;
+            for (peer, result) in dialled {
+                match result {
+                    Ok(()) => {
+                        dial_failures.recovered(&peer);
+                        info!("Authenticated with bootstrap peer {}", peer);
+                        conn_status.peers_mut().authenticated(peer);
                     }
+                    Err(e) => dial_failures.log(peer, format!("{:#}", e)),
                 }
             }
-            tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+            {
+                let mut table = conn_status.peers_mut();
+                for peer in &conn_bootstrap_peers {
+                    let since = sessions.get(&peer.node_id).map(|s| s.authenticated_at);
+                    table.set_session(peer.node_id, since);
+                }
+            }
+            tokio::time::sleep(heartbeat).await;
         }
     });
 
-    // Note: iroh-gossip 0.29 API has changed. The subscribe method is not directly available.
-    // For now, we'll keep the gossip instance alive and rely on the connection retry task
-    // to establish authenticated connections. Full gossip integration will be completed
-    // when the API is stabilized.
+    if !originates {
+        info!("DNS-only node: applying gossip updates, publishing only operator changes");
+    }
+
+    // Main loop: read local updates and broadcast, until asked to withdraw
+    // what we published.  A batch cut short by that is moot.
+    let batching = Batching::from_config(&cfg);
+    loop {
+        tokio::select! {
+            update = next_batch(outbound_rx, &batching) => {
+                let Some(update) = update else { break };
+                for (network, update) in networks.route(update) {
+                    let topic = topics
+                        .iter()
+                        .find(|topic| topic.network.as_ref() == network)
+                        .expect("every network has a topic");
+                    track_owned(&mut topic.owned.lock().unwrap(), &update);
+                    topic.publisher.publish(&Body::Update(update)).await;
+                }
+            }
+            Some(done) = withdrawals.recv() => {
+                // The instance that took over serves these names now.
+                if !status.is_handed_over() {
+                    for topic in &topics {
+                        let removals = withdrawal(&mut topic.owned.lock().unwrap());
+                        if let Some(update) = removals {
+                            info!("Withdrawing our entries from the {}", topic);
+                            topic.publisher.publish(&Body::Update(update)).await;
+                        }
+                    }
+                }
+                let _ = done.send(());
+            }
+        }
+    }
+    info!("Gossip update channel closed, shutting down");
+    Ok(())
+}
+
+/// Binds a fresh iroh endpoint for gossip, as configured.
+pub async fn bind_endpoint(cfg: &Config) -> anyhow::Result<Endpoint> {
+    let secret_key = SecretKey::generate();
+    let builder = Endpoint::builder()
+        .secret_key(secret_key.clone())
+        .relay_mode(cfg.relay.to_relay_mode()?)
+        .alpns(vec![
+            AUTH_ALPN.to_vec(),
+            LEGACY_AUTH_ALPN.to_vec(),
+            GOSSIP_ALPN.to_vec(),
+        ])
+        .bind_addr_v4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, cfg.gossip_port))
+        .bind_addr_v6(SocketAddrV6::new(
+            Ipv6Addr::UNSPECIFIED,
+            cfg.gossip_port,
+            0,
+            0,
+        ));
+    let builder = match cfg.discovery {
+        DiscoveryMode::N0 => builder.discovery_n0(),
+        DiscoveryMode::Local => {
+            builder.discovery(Box::new(LocalSwarmDiscovery::new(secret_key.public())?))
+        }
+        DiscoveryMode::None => builder,
+    };
+    builder.bind().await.with_context(|| {
+        format!(
+            "Failed to bind the gossip endpoint on port {}",
+            cfg.gossip_port
+        )
+    })
+}
+
+/// Connects to `peer` and authenticates both ways, each step bounded by
+/// `deadline`.
+pub async fn dial(
+    endpoint: &Endpoint,
+    peer: NodeId,
+    secret: &str,
+    deadline: Duration,
+) -> anyhow::Result<iroh::endpoint::Connection> {
+    let connection = within(deadline, endpoint.connect(peer, AUTH_ALPN))
+        .await
+        .with_context(|| format!("Failed to connect to bootstrap peer {}", peer))?;
+    let handshake = perform_auth_handshake(&connection, secret, endpoint.node_id(), peer);
+    if let Err(e) = within(deadline, handshake).await {
+        connection.close(0u32.into(), b"auth failed");
+        return Err(e.context(format!(
+            "Failed to authenticate with bootstrap peer {}",
+            peer
+        )));
+    }
+    Ok(connection)
+}
 
-    // Keep gossip alive
-    let _gossip_handle = gossip;
+/// Seals and broadcasts this node's messages.
+struct Publisher {
+    sender: GossipSender,
+    key: Arc<GossipKey>,
+    node: [u8; 32],
+    /// Our `node_name`, sent along with every message.
+    name: String,
+    seq: AtomicU64,
+    /// `max_payload_bytes`: peers drop larger messages unread.
+    max_payload: usize,
+}
 
-    // Main loop: read local updates and broadcast
-    // TODO: Integrate with gossip once API is stable
-    while let Some(update) = outbound_rx.recv().await {
-        let serialized = match serde_json::to_vec(&update) {
+impl Publisher {
+    /// Broadcasts `body`, split into batches that fit `max_payload` if
+    /// it is a batch too large for one message.
+    async fn publish(&self, body: &Body) {
+        let origin = Origin {
+            node: self.node,
+            seq: self.seq.fetch_add(1, Ordering::Relaxed),
+            timestamp: now_millis(),
+        };
+        let nonce = rand::random();
+        let serialized = match wire::encode(&origin, Some(&self.name), Some(nonce), body) {
             Ok(b) => b,
             Err(e) => {
-                error!("Failed to serialize update: {}", e);
-                continue;
+                error!("Failed to serialize gossip message: {}", e);
+                return;
             }
         };
-        info!(
-            "Broadcasting update (pending gossip integration): {:?}",
-            update
-        );
-        // Forward to inbound channel so registries also learn about their own broadcasts.
-        if let Err(e) = inbound_tx.send(update).await {
-            warn!("Failed to forward broadcasted update to registry: {}", e);
+        let sealed = self.key.seal(&serialized);
+        if sealed.len() > self.max_payload {
+            match body {
+                Body::Update(Update::Batch(updates)) | Body::SyncAnswer(Update::Batch(updates))
+                    if updates.len() > 1 =>
+                {
+                    let (first, second) = updates.split_at(updates.len() / 2);
+                    for half in [first, second] {
+                        let half = Update::batch(half.to_vec());
+                        let half = match body {
+                            Body::SyncAnswer(_) => Body::SyncAnswer(half),
+                            _ => Body::Update(half),
+                        };
+                        Box::pin(self.publish(&half)).await;
+                    }
+                }
+                _ => error!(
+                    "Not broadcasting {:?}: {} bytes sealed, over max_payload_bytes ({})",
+                    body,
+                    sealed.len(),
+                    self.max_payload
+                ),
+            }
+            return;
+        }
+        debug!("Broadcasting {:?}", body);
+        if let Err(e) = self.sender.broadcast(sealed.into()).await {
+            warn!("Failed to broadcast gossip message: {}", e);
         }
-        // TODO: Use gossip to broadcast once API is available
-        let _ = serialized;
     }
-    info!("Gossip update channel closed, shutting down");
-    Ok(())
 }
 
-async fn handle_incoming_connection(
-    incoming: iroh::endpoint::Incoming,
-    secret: String,
+/// A joined gossip topic: `topic_id`, or a network's.
+struct Topic {
+    network: Option<Network>,
+    publisher: Publisher,
+    /// Entries this node has published on it, re-sent to nodes that ask
+    /// for a sync.
+    owned: Mutex<Owned>,
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.network {
+            Some(network) => write!(f, "gossip topic of network {}", network.name),
+            None => f.write_str("gossip topic"),
+        }
+    }
+}
+
+/// What the receive loops of all topics share.
+#[derive(Clone)]
+struct Inbound {
+    key: Arc<GossipKey>,
+    status: Arc<Status>,
+    networks: Arc<Networks>,
+    updates: mpsc::Sender<(Update, Option<PeerNode>)>,
     our_id: NodeId,
-) -> anyhow::Result<()> {
-    let connection = incoming.await?;
-    let (mut send, mut recv) = connection.open_bi().await?;
+    our_name: String,
+    max_skew_secs: u64,
+    max_payload: usize,
+    malformed: Arc<Mutex<Malformed>>,
+    state: SharedState,
+    /// `digest_mismatches`: differing digests in a row from one peer after
+    /// which a sync is asked for.
+    digest_mismatches: u32,
+}
+
+impl Inbound {
+    /// Receives on `topic` until either the topic or the update channel
+    /// closes.
+    async fn receive(self, topic: Arc<Topic>, mut receiver: GossipReceiver) {
+        let mut seen = SeenTable::new(*self.our_id.as_bytes());
+        if self.max_skew_secs > 0 {
+            seen = seen.with_max_skew(Duration::from_secs(self.max_skew_secs));
+        }
+        let mut last_sync_answer: Option<Instant> = None;
+        // Differing digests in a row, by the NodeId that sent them.
+        let mut differing: HashMap<[u8; 32], u32> = HashMap::new();
+        while let Some(event) = receiver.next().await {
+            match event {
+                Ok(Event::Gossip(GossipEvent::Joined(peers))) => {
+                    info!("Joined {} with {} neighbors", topic, peers.len());
+                    {
+                        let mut table = self.status.peers_mut();
+                        for peer in peers {
+                            table.neighbor_up(peer);
+                        }
+                    }
+                    topic.publisher.publish(&Body::SyncRequest).await;
+                }
+                Ok(Event::Gossip(GossipEvent::NeighborUp(peer))) => {
+                    info!("Gossip neighbor up: {}", peer);
+                    self.status.peers_mut().neighbor_up(peer);
+                }
+                Ok(Event::Gossip(GossipEvent::NeighborDown(peer))) => {
+                    info!("Gossip neighbor down: {}", peer);
+                    self.status.peers_mut().neighbor_down(peer);
+                }
+                Ok(Event::Gossip(GossipEvent::Received(message))) => {
+                    let span = info_span!(
+                        target: "glued::gossip",
+                        "gossip_message",
+                        from = %short_node_id(message.delivered_from.as_bytes()),
+                        origin = field::Empty,
+                        origin_name = field::Empty,
+                    );
+                    let keep_going = async {
+                        let peer = message.delivered_from;
+                        self.status.peers_mut().seen(peer);
+                        if self.malformed.lock().unwrap().ignores(&peer) {
+                            metrics::inc(&METRICS.gossip_ignored);
+                            return true;
+                        }
+                        let opened =
+                            open_payload(&self.key, &mut seen, &message.content, self.max_payload);
+                        let opened = match opened {
+                            Ok(opened) => {
+                                self.malformed.lock().unwrap().forgive(&peer);
+                                opened
+                            }
+                            Err(e) => {
+                                let total = self.status.peers_mut().malformed(peer);
+                                self.malformed.lock().unwrap().record(peer, total, &e);
+                                return true;
+                            }
+                        };
+                        let Some(opened) = opened else {
+                            return true;
+                        };
+                        let node = opened.origin.map(|origin| PeerNode {
+                            id: short_node_id(&origin.node),
+                            name: opened.node_name.clone(),
+                            stamp: Some(Stamp::from(&origin)),
+                        });
+                        if let Some(node) = &node {
+                            Span::current().record("origin", node.id.as_str());
+                            if let Some(name) = &node.name {
+                                Span::current().record("origin_name", name.as_str());
+                            }
+                        }
+                        if let (Some(origin), Some(name)) = (&opened.origin, &opened.node_name) {
+                            note_node_name(&self.status, origin, name, &self.our_name);
+                        }
+                        // Peers answer the sync request sent on joining with
+                        // what they own, which ends the warm-up; a routine
+                        // update may be only a part of it.
+                        let answers_sync = matches!(opened.body, Body::SyncAnswer(_));
+                        match opened.body {
+                            Body::Update(update) | Body::SyncAnswer(update) => {
+                                let network = topic.network.as_ref();
+                                let Some(update) = self.networks.admit(network, update) else {
+                                    return true;
+                                };
+                                let sent = self.updates.send((update, node)).await.is_ok();
+                                if answers_sync {
+                                    self.status.set_synced();
+                                }
+                                return sent;
+                            }
+                            // DNS-only nodes own nothing but pins.  An
+                            // instance that handed over leaves answering to
+                            // its successor, so its own entries lapse.
+                            Body::SyncRequest => {
+                                if self.status.is_handed_over() {
+                                    debug!("Sync requested; handed over, not answering");
+                                    return true;
+                                }
+                                if last_sync_answer
+                                    .is_some_and(|t| t.elapsed() < SYNC_ANSWER_INTERVAL)
+                                {
+                                    debug!("Sync requested again; answered recently");
+                                    return true;
+                                }
+                                let answer = owned_entries(&mut topic.owned.lock().unwrap());
+                                if let Some(update) = answer {
+                                    info!(
+                                        "Answering sync request from {} on {}",
+                                        message.delivered_from, topic
+                                    );
+                                    topic.publisher.publish(&Body::SyncAnswer(update)).await;
+                                    last_sync_answer = Some(Instant::now());
+                                }
+                            }
+                            // Updates in flight make digests differ for a
+                            // moment; only a difference that lasts means
+                            // one side missed some.  Gossip has no direct
+                            // messages, so the sync request goes to every
+                            // publisher, which also answers the peer.
+                            Body::Digest(theirs) => {
+                                let (Some(origin), Some(peer)) = (opened.origin, &node) else {
+                                    return true;
+                                };
+                                if topic.network.is_some() {
+                                    return true;
+                                }
+                                let ours = self.state.digest();
+                                if theirs == ours {
+                                    // Nothing left to learn from this peer.
+                                    self.status.set_synced();
+                                    differing.remove(&origin.node);
+                                    return true;
+                                }
+                                metrics::inc(&METRICS.gossip_digest_mismatches);
+                                let count = differing.entry(origin.node).or_default();
+                                *count += 1;
+                                debug!(
+                                    "Digest of {} differs from ours ({} in a row): {} entries there, {} here",
+                                    peer, count, theirs.entries, ours.entries
+                                );
+                                if *count >= self.digest_mismatches {
+                                    differing.remove(&origin.node);
+                                    info!(
+                                        "State differs from {} ({} entries there, {} here); asking for a sync",
+                                        peer, theirs.entries, ours.entries
+                                    );
+                                    topic.publisher.publish(&Body::SyncRequest).await;
+                                }
+                            }
+                        }
+                        true
+                    }
+                    .instrument(span)
+                    .await;
+                    if !keep_going {
+                        break;
+                    }
+                }
+                Ok(Event::Lagged) => warn!("Gossip receiver lagged; some messages were missed"),
+                Err(e) => warn!("Gossip receive error: {}", e),
+            }
+        }
+        info!("Receive loop for {} stopped", topic);
+    }
+}
 
-    // 1. Wait for AUTH_INIT
-    let mut buf = vec![0u8; 9];
-    recv.read_exact(&mut buf).await?;
-    if buf.as_slice() != b"AUTH_INIT" {
-        anyhow::bail!("Invalid protocol init");
+/// What this node published, repeated when a peer asks for a sync.
+#[derive(Debug, Default)]
+struct Owned {
+    /// Container entries by name.
+    entries: HashMap<String, OwnedEntry>,
+    /// Pins: name → (IP, expiry).
+    pins: HashMap<String, (IpAddr, Option<u64>)>,
+}
+
+/// A container entry as published: its `Add` and what followed it.
+#[derive(Debug)]
+struct OwnedEntry {
+    ip: IpAddr,
+    ports: Vec<Port>,
+    /// In seconds.
+    lease: Option<u64>,
+    scoped_ips: Vec<ScopedIp>,
+    txt: Vec<String>,
+}
+
+/// Records a published update in what we own.
+fn track_owned(owned: &mut Owned, update: &Update) {
+    match update {
+        Update::Add { name, ip } => {
+            let entry = OwnedEntry {
+                ip: *ip,
+                ports: Vec::new(),
+                lease: None,
+                scoped_ips: Vec::new(),
+                txt: Vec::new(),
+            };
+            owned.entries.insert(name.clone(), entry);
+        }
+        Update::Remove { name } => {
+            owned.entries.remove(name);
+        }
+        Update::Ports { name, ports } => {
+            if let Some(entry) = owned.entries.get_mut(name) {
+                entry.ports.clone_from(ports);
+            }
+        }
+        Update::Lease {
+            name,
+            valid_for_secs,
+        } => {
+            if let Some(entry) = owned.entries.get_mut(name) {
+                entry.lease = Some(*valid_for_secs);
+            }
+        }
+        Update::ScopedIps { name, ips } => {
+            if let Some(entry) = owned.entries.get_mut(name) {
+                entry.scoped_ips.clone_from(ips);
+            }
+        }
+        Update::Txt { name, txt } => {
+            if let Some(entry) = owned.entries.get_mut(name) {
+                entry.txt.clone_from(txt);
+            }
+        }
+        Update::Pin {
+            name,
+            ip,
+            expires_at,
+        } => {
+            owned.pins.insert(name.clone(), (*ip, *expires_at));
+        }
+        Update::Unpin { name } => {
+            owned.pins.remove(name);
+        }
+        Update::EvictNode { .. } => {}
+        Update::Batch(updates) => {
+            for update in updates {
+                track_owned(owned, update);
+            }
+        }
     }
+}
 
-    // 2. Send our NodeId
-    send.write_all(our_id.as_bytes()).await?;
-    send.finish()?;
+/// The entries and live pins we own as one update, if there are any.
+fn owned_entries(owned: &mut Owned) -> Option<Update> {
+    let now = now_millis();
+    owned
+        .pins
+        .retain(|_, (_, expires_at)| expires_at.is_none_or(|at| at > now));
+    if owned.entries.is_empty() && owned.pins.is_empty() {
+        return None;
+    }
+    let mut updates = Vec::with_capacity(owned.entries.len() + owned.pins.len());
+    for (name, entry) in &owned.entries {
+        updates.push(Update::Add {
+            name: name.clone(),
+            ip: entry.ip,
+        });
+        if !entry.ports.is_empty() {
+            updates.push(Update::Ports {
+                name: name.clone(),
+                ports: entry.ports.clone(),
+            });
+        }
+        if let Some(valid_for_secs) = entry.lease {
+            updates.push(Update::Lease {
+                name: name.clone(),
+                valid_for_secs,
+            });
+        }
+        if !entry.scoped_ips.is_empty() {
+            updates.push(Update::ScopedIps {
+                name: name.clone(),
+                ips: entry.scoped_ips.clone(),
+            });
+        }
+        if !entry.txt.is_empty() {
+            updates.push(Update::Txt {
+                name: name.clone(),
+                txt: entry.txt.clone(),
+            });
+        }
+    }
+    for (name, (ip, expires_at)) in &owned.pins {
+        updates.push(Update::Pin {
+            name: name.clone(),
+            ip: *ip,
+            expires_at: *expires_at,
+        });
+    }
+    Some(Update::batch(updates))
+}
 
-    // 3. Receive Hash(Secret + OurNodeId)
-    let mut received_hash = vec![0u8; 32];
-    recv.read_exact(&mut received_hash).await?;
+/// Removes of every container entry we own, which stop being ours.  Pins
+/// are operators' and stay until unpinned.
+fn withdrawal(owned: &mut Owned) -> Option<Update> {
+    let removes: Vec<Update> = owned
+        .entries
+        .drain()
+        .map(|(name, _)| Update::Remove { name })
+        .collect();
+    (!removes.is_empty()).then(|| Update::batch(removes))
+}
+
+/// Verifies, decrypts and decodes a received payload, failing on one larger
+/// than `max_payload` or that doesn't open or decode.  Our own echoes,
+/// redeliveries, replays and messages outside the skew window, or with one
+/// not stamped at all, are `None`.
+/// Every message dropped is counted.
+fn open_payload(
+    key: &GossipKey,
+    seen: &mut SeenTable,
+    payload: &[u8],
+    max_payload: usize,
+) -> anyhow::Result<Option<wire::Message>> {
+    if payload.len() > max_payload {
+        metrics::inc(&METRICS.gossip_oversized);
+        anyhow::bail!(
+            "{} bytes, over max_payload_bytes ({})",
+            payload.len(),
+            max_payload
+        );
+    }
+    let plaintext = key.open(payload).inspect_err(|_| {
+        metrics::inc(&METRICS.gossip_rejected);
+    })?;
+    let message = wire::decode(&plaintext)
+        .inspect_err(|_| metrics::inc(&METRICS.gossip_rejected))
+        .context("Undecodable")?;
+    let (checked, from) = match &message.origin {
+        Some(origin) => (
+            seen.check(origin, message.nonce, now_millis()),
+            short_node_id(&origin.node),
+        ),
+        // Origin-less messages come from nodes that predate stamping.
+        None => (seen.check_unstamped(), "an older node".to_string()),
+    };
+    match checked {
+        Ok(()) => return Ok(Some(message)),
+        Err(reason @ (Rejection::OwnEcho | Rejection::Duplicate)) => {
+            debug!("Dropping gossip message {:?}: {:?}", message.origin, reason);
+            metrics::inc(&METRICS.gossip_duplicates);
+        }
+        Err(Rejection::Replay) => {
+            warn!(
+                "Dropping gossip message from {}: it repeats the nonce of a recent one",
+                from
+            );
+            metrics::inc(&METRICS.gossip_replays);
+        }
+        Err(Rejection::Skewed { ahead_ms: None }) => {
+            warn!(
+                "Dropping gossip message from {}: it isn't stamped, and gossip_max_skew_secs is set",
+                from
+            );
+            metrics::inc(&METRICS.gossip_skewed);
+        }
+        Err(Rejection::Skewed {
+            ahead_ms: Some(ahead_ms),
+        }) => {
+            let side = if ahead_ms > 0 { "ahead of" } else { "behind" };
+            warn!(
+                "Dropping gossip message from {}: stamped {} ms {} our clock, beyond gossip_max_skew_secs",
+                from,
+                ahead_ms.unsigned_abs(),
+                side
+            );
+            metrics::inc(&METRICS.gossip_skewed);
+        }
+    }
+    Ok(None)
+}
+
+/// Peers delivering malformed gossip: oversized, or not opening or
+/// decoding.  Each is warned about at most every
+/// [`MALFORMED_WARN_INTERVAL`], and ignored for a while after
+/// `malformed_threshold` such messages in a row.  Gossip relays messages
+/// as they are, so the peer blamed is the one that delivered the message,
+/// not necessarily the one that wrote it.
+struct Malformed {
+    lockout: Lockout<NodeId>,
+    /// When each peer was last warned about, and the messages held back
+    /// since.
+    warned: HashMap<NodeId, (Instant, u64)>,
+}
 
-    // 4. Verify Hash
-    let mut hasher = sha2::Sha256::new();
-    hasher.update(secret.as_bytes());
-    hasher.update(our_id.as_bytes());
-    let expected_hash = hasher.finalize();
+impl Malformed {
+    fn new(policy: LockoutPolicy) -> Self {
+        Self {
+            lockout: Lockout::new(policy),
+            warned: HashMap::new(),
+        }
+    }
+
+    /// Whether `peer`'s deliveries are being ignored.
+    fn ignores(&self, peer: &NodeId) -> bool {
+        self.lockout.locked_until(peer, Instant::now()).is_some()
+    }
+
+    /// Starts `peer`'s count over after a message that opened.
+    fn forgive(&mut self, peer: &NodeId) {
+        self.lockout.record_success(peer);
+    }
 
-    if received_hash != expected_hash.as_slice() {
-        anyhow::bail!("Authentication failed: Invalid hash");
+    /// Records a malformed message from `peer`, its `total` so far.
+    fn record(&mut self, peer: NodeId, total: u64, e: &anyhow::Error) {
+        let now = Instant::now();
+        if let Some(duration) = self.lockout.record_failure(peer, now) {
+            warn!(
+                "Ignoring gossip delivered by {} for {:?}: malformed messages in a row, {} in all; the last: {:#}",
+                peer, duration, total, e
+            );
+            return;
+        }
+        let held = match self.warned.get_mut(&peer) {
+            Some((at, held)) if now.duration_since(*at) < MALFORMED_WARN_INTERVAL => {
+                *held += 1;
+                return;
+            }
+            Some((_, held)) => *held,
+            None => 0,
+        };
+        self.warned
+            .retain(|_, (at, _)| now.duration_since(*at) < MALFORMED_WARN_INTERVAL);
+        self.warned.insert(peer, (now, 0));
+        if held > 0 {
+            warn!(
+                "Dropping gossip message delivered by {}: {:#} ({} more since the last warning)",
+                peer, e, held
+            );
+        } else {
+            warn!("Dropping gossip message delivered by {}: {:#}", peer, e);
+        }
     }
+}
 
-    // 5. Send AUTH_OK
-    send.write_all(b"AUTH_OK").await?;
+/// Records the name a message's sender goes by, warning when another node,
+/// or this one, goes by the same name.  Only the NodeId identifies a node,
+/// so a clash only makes logs and listings ambiguous.
+fn note_node_name(status: &Status, origin: &Origin, name: &str, our_name: &str) {
+    let Ok(node) = NodeId::from_bytes(&origin.node) else {
+        return;
+    };
+    let mut peers = status.peers_mut();
+    if !peers.named(node, name) {
+        return;
+    }
+    let short = short_node_id(&origin.node);
+    info!("Node {} is named {}", short, name);
+    if name == our_name {
+        warn!(
+            "Node {} uses this node's name {}; tell them apart by NodeId",
+            short, name
+        );
+    }
+    for other in peers.also_named(&node, name) {
+        warn!(
+            "Nodes {} and {} are both named {}; tell them apart by NodeId",
+            short,
+            short_node_id(other.as_bytes()),
+            name
+        );
+    }
+}
+
+/// A NodeId shortened the way iroh logs it: its first five bytes in hex.
+pub fn short_node_id(node: &[u8; 32]) -> String {
+    hex::encode(&node[..5])
+}
+
+/// State shared by the tasks handling incoming connections.
+struct Acceptor {
+    secret: String,
+    our_id: NodeId,
+    lockout: Mutex<Lockout<NodeId>>,
+    /// Swapped on a config reload; connections already up are kept.
+    policy: Arc<ArcSwap<PeerPolicy>>,
+    /// Deadline for each step of the auth handshake.
+    deadline: Duration,
+    gossip: Gossip,
+    status: Arc<Status>,
+}
+
+async fn handle_incoming_connection(
+    incoming: iroh::endpoint::Incoming,
+    ctx: &Acceptor,
+) -> anyhow::Result<()> {
+    let deadline = ctx.deadline;
+    let remote = incoming.remote_address();
+    let mut connecting = incoming.accept()?;
+    let alpn = connecting.alpn().await?;
+    if alpn == GOSSIP_ALPN {
+        let connection = within(deadline, async { Ok(connecting.await?) }).await?;
+        let peer = iroh::endpoint::get_remote_node_id(&connection)?;
+        if let Some(reason) = ctx.policy.load().rejects(&peer) {
+            metrics::inc(&METRICS.peers_rejected);
+            connection.close(0u32.into(), b"not permitted");
+            anyhow::bail!("Rejected gossip session from {}: {}", peer, reason);
+        }
+        // Gossip sessions are long-lived; hand them off rather than holding
+        // a handshake slot.
+        let gossip = ctx.gossip.clone();
+        tokio::spawn(async move {
+            if let Err(e) = gossip.handle_connection(connection).await {
+                debug!("Gossip session with {} ended: {}", peer, e);
+            }
+        });
+        return Ok(());
+    }
+    if alpn == LEGACY_AUTH_ALPN {
+        anyhow::bail!(
+            "Peer at {} uses the v1 auth handshake ({}); upgrade it to a release speaking {}",
+            remote,
+            String::from_utf8_lossy(LEGACY_AUTH_ALPN),
+            String::from_utf8_lossy(AUTH_ALPN)
+        );
+    }
+    if alpn != AUTH_ALPN {
+        anyhow::bail!(
+            "Peer at {} requested unknown protocol {:?}",
+            remote,
+            String::from_utf8_lossy(&alpn)
+        );
+    }
+
+    let connection = within(deadline, async { Ok(connecting.await?) }).await?;
+    let peer = iroh::endpoint::get_remote_node_id(&connection)?;
+    if let Some(reason) = ctx.policy.load().rejects(&peer) {
+        metrics::inc(&METRICS.peers_rejected);
+        connection.close(0u32.into(), b"not permitted");
+        anyhow::bail!("Rejected peer {}: {}", peer, reason);
+    }
+    let now = Instant::now();
+    let locked_until = ctx.lockout.lock().unwrap().locked_until(&peer, now);
+    if let Some(until) = locked_until {
+        connection.close(0u32.into(), b"locked out");
+        anyhow::bail!(
+            "Peer {} is locked out for another {:?} after failed auth attempts",
+            peer,
+            until - now
+        );
+    }
+
+    let handshake = authenticate_incoming(&connection, &ctx.secret, ctx.our_id, peer);
+    match within(deadline, handshake).await {
+        Ok(()) => {
+            ctx.lockout.lock().unwrap().record_success(&peer);
+            ctx.status.peers_mut().authenticated(peer);
+            info!("Authenticated incoming peer {}", peer);
+            // The initiator keeps the connection as its session with us,
+            // and closes it when done.
+            tokio::spawn(async move {
+                let reason = connection.closed().await;
+                debug!("Session with {} ended: {}", peer, reason);
+            });
+            Ok(())
+        }
+        Err(e) => {
+            let locked = ctx
+                .lockout
+                .lock()
+                .unwrap()
+                .record_failure(peer, Instant::now());
+            if let Some(duration) = locked {
+                warn!(
+                    "Locking out peer {} for {:?} after repeated auth failures",
+                    peer, duration
+                );
+            }
+            connection.close(0u32.into(), b"auth failed");
+            Err(e)
+        }
+    }
+}
+
+async fn authenticate_incoming(
+    connection: &iroh::endpoint::Connection,
+    secret: &str,
+    our_id: NodeId,
+    peer: NodeId,
+) -> anyhow::Result<()> {
+    let (mut send, mut recv) = connection.accept_bi().await?;
+    let claimed = respond_auth(&mut send, &mut recv, secret, our_id.as_bytes()).await?;
     send.finish()?;
+    if &claimed != peer.as_bytes() {
+        anyhow::bail!("Peer {} claimed a different NodeId in the handshake", peer);
+    }
     Ok(())
 }
 
+/// Authenticates a connection we opened to `peer`.  The responder must be
+/// the node dialled, so a proof reflected from our own responder fails.
 async fn perform_auth_handshake(
-    connection: iroh::endpoint::Connection,
+    connection: &iroh::endpoint::Connection,
     secret: &str,
+    our_id: NodeId,
+    peer: NodeId,
 ) -> anyhow::Result<()> {
+    let remote = iroh::endpoint::get_remote_node_id(connection)?;
+    if remote != peer {
+        anyhow::bail!("Dialled {} but reached {}", peer, remote);
+    }
     let (mut send, mut recv) = connection.open_bi().await?;
+    initiate_auth(
+        &mut send,
+        &mut recv,
+        secret,
+        our_id.as_bytes(),
+        peer.as_bytes(),
+    )
+    .await?;
+    send.finish()?;
+    Ok(())
+}
 
-    // 1. Send AUTH_INIT
-    send.write_all(b"AUTH_INIT").await?;
+/// Bounds a handshake step so a peer that goes silent can't pin the task.
+async fn within<T>(
+    deadline: Duration,
+    step: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    match tokio::time::timeout(deadline, step).await {
+        Ok(result) => result,
+        Err(_) => anyhow::bail!("Auth handshake timed out after {:?}", deadline),
+    }
+}
 
-    // 2. Receive Responder NodeId
-    let mut node_id_bytes = [0u8; 32];
-    recv.read_exact(&mut node_id_bytes).await?;
-    let responder_id = NodeId::from_bytes(&node_id_bytes)?;
+/// `HMAC(secret, peer_nonce || own_id)`: proves the sender knows the
+/// cluster secret, bound to the challenge it was given and its identity.
+fn auth_proof(secret: &str, peer_nonce: &[u8; NONCE_LEN], own_id: &[u8; 32]) -> [u8; 32] {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(peer_nonce);
+    mac.update(own_id);
+    mac.finalize().into_bytes().into()
+}
 
-    // 3. Hash(Secret + ResponderNodeId)
-    let mut hasher = sha2::Sha256::new();
-    hasher.update(secret.as_bytes());
-    hasher.update(responder_id.as_bytes());
-    let hash = hasher.finalize();
+/// Compares proofs in constant time so timing doesn't leak how much matched.
+fn proof_matches(received: &[u8; 32], expected: &[u8; 32]) -> bool {
+    received.ct_eq(expected).into()
+}
 
-    // 4. Send Hash
-    send.write_all(&hash).await?;
-    send.finish()?;
-    // 5. Wait for AUTH_OK
-    let mut buf = vec![0u8; 7];
-    recv.read_exact(&mut buf).await?;
-    if buf.as_slice() != b"AUTH_OK" {
+async fn read_auth_version<R: AsyncRead + Unpin>(recv: &mut R) -> anyhow::Result<()> {
+    let version = recv.read_u8().await?;
+    if version != AUTH_VERSION {
+        anyhow::bail!(
+            "Peer speaks auth protocol v{}, we speak v{}",
+            version,
+            AUTH_VERSION
+        );
+    }
+    Ok(())
+}
+
+/// Initiator side of the mutual handshake with the node `peer`.  Returns
+/// the responder's NodeId once both sides have proven knowledge of the
+/// secret.  A responder claiming to be anyone but `peer` fails before we
+/// send our proof: it could be replaying a proof our own responder made.
+///
+/// 1. -> version, nonce_i, id_i
+/// 2. <- version, nonce_r, id_r, HMAC(secret, nonce_i || id_r)
+/// 3. -> HMAC(secret, nonce_r || id_i)
+/// 4. <- AUTH_OK
+async fn initiate_auth<W, R>(
+    send: &mut W,
+    recv: &mut R,
+    secret: &str,
+    our_id: &[u8; 32],
+    peer: &[u8; 32],
+) -> anyhow::Result<[u8; 32]>
+where
+    W: AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+{
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    send.write_u8(AUTH_VERSION).await?;
+    send.write_all(&nonce).await?;
+    send.write_all(our_id).await?;
+    send.flush().await?;
+
+    read_auth_version(recv).await?;
+    let mut peer_nonce = [0u8; NONCE_LEN];
+    recv.read_exact(&mut peer_nonce).await?;
+    let mut peer_id = [0u8; 32];
+    recv.read_exact(&mut peer_id).await?;
+    let mut peer_proof = [0u8; 32];
+    recv.read_exact(&mut peer_proof).await?;
+    if &peer_id != peer {
+        anyhow::bail!(
+            "Authentication failed: responder claimed another NodeId than the one dialled"
+        );
+    }
+    if !proof_matches(&peer_proof, &auth_proof(secret, &nonce, &peer_id)) {
+        anyhow::bail!("Authentication failed: responder does not know the cluster secret");
+    }
+
+    send.write_all(&auth_proof(secret, &peer_nonce, our_id))
+        .await?;
+    send.flush().await?;
+
+    let mut ok = [0u8; AUTH_OK.len()];
+    recv.read_exact(&mut ok).await?;
+    if &ok != AUTH_OK {
         anyhow::bail!("Auth failed");
     }
+    Ok(peer_id)
+}
 
-    Ok(())
+/// Responder side of [`initiate_auth`].  Returns the initiator's NodeId.
+async fn respond_auth<W, R>(
+    send: &mut W,
+    recv: &mut R,
+    secret: &str,
+    our_id: &[u8; 32],
+) -> anyhow::Result<[u8; 32]>
+where
+    W: AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+{
+    read_auth_version(recv).await?;
+    let mut peer_nonce = [0u8; NONCE_LEN];
+    recv.read_exact(&mut peer_nonce).await?;
+    let mut peer_id = [0u8; 32];
+    recv.read_exact(&mut peer_id).await?;
+
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    send.write_u8(AUTH_VERSION).await?;
+    send.write_all(&nonce).await?;
+    send.write_all(our_id).await?;
+    send.write_all(&auth_proof(secret, &peer_nonce, our_id))
+        .await?;
+    send.flush().await?;
+
+    let mut peer_proof = [0u8; 32];
+    recv.read_exact(&mut peer_proof).await?;
+    if !proof_matches(&peer_proof, &auth_proof(secret, &nonce, &peer_id)) {
+        anyhow::bail!("Authentication failed: Invalid proof");
+    }
+
+    send.write_all(AUTH_OK).await?;
+    send.flush().await?;
+    Ok(peer_id)
+}
+
+/// Applies this node's own `update`, made by `source`, through the
+/// registry.
+pub async fn apply_update(update: Update, source: LocalSource, state: &SharedState) {
+    state.apply(update, ChangeOrigin::Local(source)).await;
+}
+
+/// Applies `update` from a peer, recording `node` as the publisher of added
+/// entries.
+pub async fn apply_update_from(update: Update, node: Option<&str>, state: &SharedState) {
+    let node = node.map(PeerNode::new);
+    state.apply(update, ChangeOrigin::Peer(node)).await;
+}
+
+/// Forwards this node's changes to gossip until either side closes.
+pub async fn forward_local(
+    mut changes: broadcast::Receiver<Arc<Change>>,
+    outbound: mpsc::Sender<Update>,
+) {
+    loop {
+        match changes.recv().await {
+            Ok(change) if matches!(change.origin, ChangeOrigin::Local(_)) => {
+                if let Err(e) = outbound.send(change.update.clone()).await {
+                    error!("Failed to forward update to gossip pipeline: {}", e);
+                    break;
+                }
+            }
+            Ok(_) => {}
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!(
+                    "Gossip fell {} changes behind; peers catch up at the next reconcile",
+                    missed
+                );
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
 }
 
-pub async fn apply_update(update: Update, state: &Arc<RwLock<HashMap<String, String>>>) {
+/// Applies `update` to `map`, dropping what `limits` don't allow and
+/// setting aside what `conflicts` decides another node is served for.  A
+/// peer's `Remove`, ports, lease, scoped addresses and TXT strings only
+/// change the entry it published, so its updates apply the same in any
+/// order; see [`crate::crdt`].
+pub(crate) fn apply_to(
+    map: &mut StateMap,
+    update: Update,
+    from: Option<&PeerNode>,
+    limits: &Limits,
+    conflicts: &mut Conflicts,
+) {
+    let node = from.map(|peer| peer.id.as_str());
     match update {
         Update::Add { name, ip } => {
-            let mut map = state.write().await;
-            map.insert(name.clone(), ip.clone());
-            info!("Applied update: Added {} -> {}", name, ip);
+            let name = normalize(&name);
+            if let Err(reason) = check_name(&name) {
+                return limits::reject("add", &name, &reason);
+            }
+            match map.get(&name).map(|entry| entry.source) {
+                Some(source @ (Source::Static | Source::Hosts)) => {
+                    warn!(
+                        "Ignoring {} -> {}: the name is configured locally ({:?})",
+                        name, ip, source
+                    );
+                }
+                _ => {
+                    let entry = Entry {
+                        node: node.map(str::to_owned),
+                        node_name: from.and_then(|peer| peer.name.clone()),
+                        ..Entry::new(ip)
+                    };
+                    let Some(entry) = conflicts.contest(map, &name, node, entry) else {
+                        conflicts.merge(map, &name);
+                        return debug!("Set aside {} -> {}", name, ip);
+                    };
+                    match map.get_mut(&name) {
+                        Some(pinned) if pinned.source == Source::Manual => {
+                            debug!("Applied update: {} -> {} under its pin", name, ip);
+                            pinned.shadowed = Some(Box::new(entry));
+                        }
+                        _ => {
+                            if let Err(reason) = limits.check_room(map, &name, node) {
+                                return limits::reject("add", &name, &reason);
+                            }
+                            map.insert(name.clone(), entry);
+                            match from {
+                                Some(peer) => info!(
+                                    "Applied update: Added {} -> {} from node {}",
+                                    name, ip, peer
+                                ),
+                                None => info!("Applied update: Added {} -> {}", name, ip),
+                            }
+                        }
+                    }
+                    conflicts.merge(map, &name);
+                }
+            }
         }
         Update::Remove { name } => {
-            let mut map = state.write().await;
-            map.remove(&name);
-            info!("Applied update: Removed {}", name);
+            let name = normalize(&name);
+            if conflicts.withdraw(&name, node) {
+                conflicts.merge(map, &name);
+                return debug!("Applied update: Removed {}, which was set aside", name);
+            }
+            match map.get_mut(&name) {
+                Some(entry) if matches!(entry.source, Source::Static | Source::Hosts) => {
+                    debug!("Not removing locally configured {}", name)
+                }
+                Some(pinned) if pinned.source == Source::Manual => match &pinned.shadowed {
+                    Some(entry) if !published_by(entry, node) => {
+                        debug!("Not removing {} under its pin: another node's", name)
+                    }
+                    _ => {
+                        debug!("Applied update: Removed {} under its pin", name);
+                        pinned.shadowed = None;
+                    }
+                },
+                Some(entry) if !published_by(entry, node) => {
+                    debug!("Not removing {}: another node's", name)
+                }
+                _ => {
+                    map.remove(&name);
+                    info!("Applied update: Removed {}", name);
+                }
+            }
+            conflicts.promote(map, &name);
+            conflicts.merge(map, &name);
+        }
+        Update::Ports { name, ports } => {
+            let name = normalize(&name);
+            match container_entry(map, conflicts, &name, node) {
+                Some(entry) if entry.source == Source::Cluster => {
+                    debug!("Applied update: {} has ports {:?}", name, ports);
+                    entry.ports = ports;
+                }
+                _ => debug!("Ignoring ports for {}: no container entry", name),
+            }
+        }
+        Update::ScopedIps { name, ips } => {
+            let name = normalize(&name);
+            match container_entry(map, conflicts, &name, node) {
+                Some(entry) if entry.source == Source::Cluster => {
+                    debug!("Applied update: {} has scoped addresses {:?}", name, ips);
+                    entry.scoped_ips = ips;
+                }
+                _ => debug!("Ignoring scoped addresses for {}: no container entry", name),
+            }
+        }
+        Update::Txt { name, txt } => {
+            let name = normalize(&name);
+            if !txt::fits(&txt) {
+                let reason = format!("over {} bytes of TXT strings", txt::MAX_TXT_BYTES);
+                return limits::reject("TXT", &name, &reason);
+            }
+            match container_entry(map, conflicts, &name, node) {
+                Some(entry) if entry.source == Source::Cluster => {
+                    debug!("Applied update: {} has TXT {:?}", name, txt);
+                    entry.txt = txt;
+                }
+                _ => debug!("Ignoring TXT for {}: no container entry", name),
+            }
+        }
+        Update::Pin {
+            name,
+            ip,
+            expires_at,
+        } => {
+            let name = normalize(&name);
+            let allowed = check_name(&name).and_then(|()| limits.check_room(map, &name, node));
+            if let Err(reason) = allowed {
+                return limits::reject("pin", &name, &reason);
+            }
+            let shadowed = match map.get(&name).map(|entry| entry.source) {
+                Some(source @ (Source::Static | Source::Hosts)) => {
+                    warn!(
+                        "Ignoring pin {} -> {}: the name is configured locally ({:?})",
+                        name, ip, source
+                    );
+                    return;
+                }
+                Some(Source::Manual) => map.remove(&name).and_then(|pin| pin.shadowed),
+                Some(Source::Cluster) => map.remove(&name).map(Box::new),
+                None => None,
+            };
+            let entry = Entry {
+                source: Source::Manual,
+                node: node.map(str::to_owned),
+                node_name: from.and_then(|peer| peer.name.clone()),
+                expires_at,
+                shadowed,
+                ..Entry::new(ip)
+            };
+            map.insert(name.clone(), entry);
+            info!("Applied update: Pinned {} -> {}", name, ip);
+        }
+        Update::Unpin { name } => {
+            let name = normalize(&name);
+            if pins::unpin(map, &name) {
+                info!("Applied update: Unpinned {}", name);
+            }
+        }
+        Update::Lease {
+            name,
+            valid_for_secs,
+        } => {
+            let name = normalize(&name);
+            match container_entry(map, conflicts, &name, node) {
+                Some(entry) if entry.source == Source::Cluster => {
+                    debug!("Applied update: {} valid for {}s", name, valid_for_secs);
+                    let now = now_millis();
+                    entry.refreshed_at = Some(now);
+                    entry.expires_at =
+                        Some(now.saturating_add(valid_for_secs.saturating_mul(1000)));
+                }
+                _ => debug!("Ignoring lease for {}: no container entry", name),
+            }
+        }
+        Update::EvictNode { node: evicted } => {
+            let names = evict::drop_entries(map, conflicts, &evicted);
+            info!(
+                "Applied update: Evicted node {}, dropping its entries for {} names",
+                evicted,
+                names.len()
+            );
+        }
+        Update::Batch(updates) => {
+            debug!("Applying batch of {} updates", updates.len());
+            for update in updates {
+                apply_to(map, update, from, limits, conflicts);
+            }
+        }
+    }
+}
+
+/// The container entry for `name` that updates from `node` apply to: the
+/// one it had set aside, or else the one served, even under a pin, if it
+/// published it.
+fn container_entry<'a>(
+    map: &'a mut StateMap,
+    conflicts: &'a mut Conflicts,
+    name: &str,
+    node: Option<&str>,
+) -> Option<&'a mut Entry> {
+    if let Some(entry) = conflicts.claim_mut(name, node) {
+        return Some(entry);
+    }
+    match map.get_mut(name) {
+        Some(pinned) if pinned.source == Source::Manual => pinned.shadowed.as_deref_mut(),
+        entry => entry,
+    }
+    .filter(|entry| published_by(entry, node))
+}
+
+/// Whether updates from `node` may change `entry`: any may if they don't
+/// say who published them, and otherwise only its publisher's.
+fn published_by(entry: &Entry, node: Option<&str>) -> bool {
+    node.is_none() || entry.node.as_deref() == node
+}
+
+/// Limits for coalescing a burst of updates.
+#[derive(Debug, Clone, Copy)]
+pub struct Batching {
+    /// Most updates in one batch.
+    pub max: usize,
+    /// How long to keep collecting after the first update arrives.
+    pub window: Duration,
+}
+
+impl Batching {
+    pub fn from_config(cfg: &Config) -> Self {
+        Self {
+            max: cfg.batch_max_updates.max(1),
+            window: Duration::from_millis(
+                cfg.gossip.batch_window_ms.unwrap_or(cfg.batch_window_ms),
+            ),
+        }
+    }
+}
+
+/// Waits for the next update and collects whatever else arrives within the
+/// batching window.  Returns `None` once the channel is closed and drained.
+pub async fn next_batch(rx: &mut mpsc::Receiver<Update>, batching: &Batching) -> Option<Update> {
+    let updates = next_burst(rx, batching).await;
+    (!updates.is_empty()).then(|| Update::batch(updates))
+}
+
+/// [`next_batch`] for this node's own updates: a burst made by several
+/// sources comes back as one batch per run of the same source, in order.
+/// Empty once the channel is closed.
+pub async fn next_local_batches(
+    rx: &mut mpsc::Receiver<LocalUpdate>,
+    batching: &Batching,
+) -> Vec<LocalUpdate> {
+    let mut runs: Vec<(Vec<Update>, LocalSource)> = Vec::new();
+    for (update, source) in next_burst(rx, batching).await {
+        match runs.last_mut() {
+            Some((updates, last)) if *last == source => updates.push(update),
+            _ => runs.push((vec![update], source)),
+        }
+    }
+    runs.into_iter()
+        .map(|(updates, source)| (Update::batch(updates), source))
+        .collect()
+}
+
+/// Up to `batching.max` items arriving within `batching.window` of the
+/// first; none once the channel is closed.
+async fn next_burst<T>(rx: &mut mpsc::Receiver<T>, batching: &Batching) -> Vec<T> {
+    let mut items = Vec::with_capacity(batching.max.min(128));
+    if rx.recv_many(&mut items, batching.max).await == 0 {
+        return items;
+    }
+    let deadline = Instant::now() + batching.window;
+    while items.len() < batching.max {
+        let limit = batching.max - items.len();
+        match tokio::time::timeout_at(deadline, rx.recv_many(&mut items, limit)).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn handshake(
+        initiator_secret: &str,
+        responder_secret: &str,
+    ) -> (anyhow::Result<[u8; 32]>, anyhow::Result<[u8; 32]>) {
+        let (a, b) = tokio::io::duplex(1024);
+        // Each side drops its end when done so a failed peer unblocks the other.
+        let initiator = async move {
+            let (mut recv, mut send) = tokio::io::split(a);
+            initiate_auth(&mut send, &mut recv, initiator_secret, &[1; 32], &[2; 32]).await
+        };
+        let responder = async move {
+            let (mut recv, mut send) = tokio::io::split(b);
+            respond_auth(&mut send, &mut recv, responder_secret, &[2; 32]).await
+        };
+        tokio::join!(initiator, responder)
+    }
+
+    #[tokio::test]
+    async fn both_sides_learn_each_other_with_shared_secret() {
+        let (initiator, responder) = handshake("s3cret", "s3cret").await;
+        assert_eq!(initiator.unwrap(), [2; 32]);
+        assert_eq!(responder.unwrap(), [1; 32]);
+    }
+
+    #[tokio::test]
+    async fn initiator_rejects_responder_without_secret() {
+        let (initiator, responder) = handshake("s3cret", "guess").await;
+        assert!(initiator.is_err());
+        assert!(responder.is_err());
+    }
+
+    #[tokio::test]
+    async fn a_proof_reflected_from_our_own_responder_fails() {
+        let (ours, attacker) = ([1; 32], [3; 32]);
+        // Our connection to the attacker, and its connection back to us.
+        let (dialled, mut at_attacker) = tokio::io::duplex(1024);
+        let (incoming, mut from_attacker) = tokio::io::duplex(1024);
+        let initiator = async move {
+            let (mut recv, mut send) = tokio::io::split(dialled);
+            initiate_auth(&mut send, &mut recv, "s3cret", &ours, &attacker).await
+        };
+        let responder = async move {
+            let (mut recv, mut send) = tokio::io::split(incoming);
+            respond_auth(&mut send, &mut recv, "s3cret", &ours).await
+        };
+        let attack = async move {
+            // Our challenge, sent back to us as the attacker's own.
+            let mut hello = [0u8; 1 + NONCE_LEN + 32];
+            at_attacker.read_exact(&mut hello).await.unwrap();
+            from_attacker
+                .write_all(&hello[..1 + NONCE_LEN])
+                .await
+                .unwrap();
+            from_attacker.write_all(&attacker).await.unwrap();
+            // Our responder's answer to it, passed on as the attacker's.
+            let mut answer = [0u8; 1 + NONCE_LEN + 32 + 32];
+            from_attacker.read_exact(&mut answer).await.unwrap();
+            let _ = at_attacker.write_all(&answer).await;
+            let _ = at_attacker.write_all(AUTH_OK).await;
+        };
+        let (initiator, responder, ()) = tokio::join!(initiator, responder, attack);
+        let err = initiator.unwrap_err();
+        assert!(err.to_string().contains("another NodeId"), "{}", err);
+        assert!(responder.is_err());
+    }
+
+    #[tokio::test]
+    async fn stalled_peer_times_out() {
+        let deadline = Duration::from_millis(100);
+        // Keep the far ends open but never answer.
+        let (a, _a_peer) = tokio::io::duplex(1024);
+        let (b, _b_peer) = tokio::io::duplex(1024);
+        let (mut a_recv, mut a_send) = tokio::io::split(a);
+        let (mut b_recv, mut b_send) = tokio::io::split(b);
+
+        let start = Instant::now();
+        let initiator = within(
+            deadline,
+            initiate_auth(&mut a_send, &mut a_recv, "s3cret", &[1; 32], &[2; 32]),
+        );
+        let responder = within(
+            deadline,
+            respond_auth(&mut b_send, &mut b_recv, "s3cret", &[2; 32]),
+        );
+        let (initiator, responder) = tokio::join!(initiator, responder);
+
+        assert!(initiator.unwrap_err().to_string().contains("timed out"));
+        assert!(responder.unwrap_err().to_string().contains("timed out"));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    fn add(i: usize) -> Update {
+        Update::Add {
+            name: format!("web-{}", i),
+            ip: format!("10.0.0.{}", i).parse().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn bursts_are_coalesced_up_to_the_limit() {
+        let batching = Batching {
+            max: 3,
+            window: Duration::from_millis(50),
+        };
+        let (tx, mut rx) = mpsc::channel(16);
+        for i in 0..5 {
+            tx.send(add(i)).await.unwrap();
+        }
+        assert_eq!(
+            next_batch(&mut rx, &batching).await,
+            Some(Update::Batch(vec![add(0), add(1), add(2)]))
+        );
+        assert_eq!(
+            next_batch(&mut rx, &batching).await,
+            Some(Update::Batch(vec![add(3), add(4)]))
+        );
+
+        // A lone update is sent as is, once the window has passed.
+        tx.send(add(5)).await.unwrap();
+        assert_eq!(next_batch(&mut rx, &batching).await, Some(add(5)));
+
+        drop(tx);
+        assert_eq!(next_batch(&mut rx, &batching).await, None);
+    }
+
+    #[tokio::test]
+    async fn local_bursts_are_split_by_source() {
+        let batching = Batching {
+            max: 8,
+            window: Duration::from_millis(50),
+        };
+        let (tx, mut rx) = mpsc::channel(16);
+        for (i, source) in [
+            LocalSource::DockerEvent,
+            LocalSource::DockerEvent,
+            LocalSource::AdminApi,
+            LocalSource::DockerEvent,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            tx.send((add(i), source)).await.unwrap();
+        }
+        assert_eq!(
+            next_local_batches(&mut rx, &batching).await,
+            [
+                (
+                    Update::Batch(vec![add(0), add(1)]),
+                    LocalSource::DockerEvent
+                ),
+                (add(2), LocalSource::AdminApi),
+                (add(3), LocalSource::DockerEvent),
+            ]
+        );
+        drop(tx);
+        assert!(next_local_batches(&mut rx, &batching).await.is_empty());
+    }
+
+    #[test]
+    fn sync_answer_carries_current_owned_entries() {
+        let mut owned = Owned::default();
+        assert_eq!(owned_entries(&mut owned), None);
+
+        track_owned(&mut owned, &Update::batch(vec![add(1), add(2)]));
+        track_owned(
+            &mut owned,
+            &Update::Remove {
+                name: "web-1".into(),
+            },
+        );
+        assert_eq!(owned_entries(&mut owned), Some(add(2)));
+
+        // Pins are repeated until they expire.
+        let pin = |name: &str, expires_at| Update::Pin {
+            name: name.into(),
+            ip: "10.0.0.9".parse().unwrap(),
+            expires_at,
+        };
+        track_owned(&mut owned, &pin("canary", None));
+        track_owned(&mut owned, &pin("old", Some(1)));
+        assert_eq!(
+            owned_entries(&mut owned),
+            Some(Update::Batch(vec![add(2), pin("canary", None)]))
+        );
+    }
+
+    #[test]
+    fn withdrawal_removes_owned_entries_but_not_pins() {
+        let mut owned = Owned::default();
+        assert_eq!(withdrawal(&mut owned), None);
+
+        track_owned(&mut owned, &add(1));
+        track_owned(
+            &mut owned,
+            &Update::Pin {
+                name: "canary".into(),
+                ip: "10.0.0.9".parse().unwrap(),
+                expires_at: None,
+            },
+        );
+        assert_eq!(
+            withdrawal(&mut owned),
+            Some(Update::Remove {
+                name: "web-1".into()
+            })
+        );
+        // Withdrawn once; a sync answer now only repeats the pin.
+        assert_eq!(withdrawal(&mut owned), None);
+        assert!(matches!(
+            owned_entries(&mut owned),
+            Some(Update::Pin { .. })
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn batches_are_applied_atomically() {
+        let state = SharedState::default();
+        let batch = Update::batch((0..80).map(add).collect());
+        let writer = tokio::spawn({
+            let state = Arc::clone(&state);
+            async move { apply_update(batch, LocalSource::DockerEvent, &state).await }
+        });
+        loop {
+            let len = state.read().await.len();
+            assert!(len == 0 || len == 80, "saw a partial batch: {}", len);
+            if len == 80 {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn gossip_cannot_change_static_records() {
+        let state = SharedState::default();
+        let mut nas = Entry::new("192.168.1.10".parse().unwrap());
+        nas.source = Source::Static;
+        state.write().await.insert("nas".into(), nas.clone());
+
+        apply_update(add(1), LocalSource::DockerEvent, &state).await;
+        apply_update(
+            Update::batch(vec![
+                Update::Add {
+                    name: "nas".into(),
+                    ip: "10.0.0.9".parse().unwrap(),
+                },
+                Update::Remove { name: "nas".into() },
+            ]),
+            LocalSource::DockerEvent,
+            &state,
+        )
+        .await;
+        let map = state.read().await;
+        assert_eq!(map["nas"], nas);
+        assert_eq!(map.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn version_mismatch_is_reported() {
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (mut a_recv, mut a_send) = tokio::io::split(a);
+        b.write_u8(1).await.unwrap();
+        let err = respond_auth(&mut a_send, &mut a_recv, "s3cret", &[2; 32])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("v1"), "{}", err);
+    }
+
+    #[test]
+    fn oversized_and_garbled_payloads_are_errors() {
+        let key = GossipKey::from_secret("s3cret");
+        let mut seen = SeenTable::new([1; 32]);
+        let origin = Origin {
+            node: [2; 32],
+            seq: 0,
+            timestamp: now_millis(),
+        };
+        let body = Body::Update(Update::Remove {
+            name: "web-1".into(),
+        });
+        let sealed = key.seal(&wire::encode(&origin, None, Some(1), &body).unwrap());
+        let opened = open_payload(&key, &mut seen, &sealed, 64 * 1024).unwrap();
+        assert_eq!(opened.unwrap().body, body);
+        // Redelivered, it is dropped but not malformed.
+        assert!(open_payload(&key, &mut seen, &sealed, 64 * 1024)
+            .unwrap()
+            .is_none());
+        let err = open_payload(&key, &mut seen, &sealed, sealed.len() - 1).unwrap_err();
+        assert!(err.to_string().contains("max_payload_bytes"), "{}", err);
+
+        // Random bytes fail without panicking, and sealed ones too.
+        for _ in 0..500 {
+            let len = rand::random::<usize>() % 512;
+            let bytes: Vec<u8> = (0..len).map(|_| rand::random()).collect();
+            assert!(open_payload(&key, &mut seen, &bytes, 64 * 1024).is_err());
+            let _ = open_payload(&key, &mut seen, &key.seal(&bytes), 64 * 1024);
+        }
+    }
+
+    #[test]
+    fn unstamped_gossip_is_dropped_under_a_skew_window() {
+        let key = GossipKey::from_secret("s3cret");
+        let update = Update::Remove {
+            name: "web-1".into(),
+        };
+        // As an older node sends it: no origin, no nonce.
+        let sealed = key.seal(&serde_json::to_vec(&update).unwrap());
+        let mut seen = SeenTable::new([1; 32]);
+        let opened = open_payload(&key, &mut seen, &sealed, 64 * 1024).unwrap();
+        assert_eq!(opened.unwrap().body, Body::Update(update));
+
+        let mut seen = SeenTable::new([1; 32]).with_max_skew(Duration::from_secs(300));
+        let skewed = METRICS.gossip_skewed.load(Ordering::Relaxed);
+        assert!(open_payload(&key, &mut seen, &sealed, 64 * 1024)
+            .unwrap()
+            .is_none());
+        assert!(METRICS.gossip_skewed.load(Ordering::Relaxed) > skewed);
+    }
+
+    #[test]
+    fn peers_delivering_malformed_gossip_are_ignored_for_a_while() {
+        let mut malformed = Malformed::new(LockoutPolicy {
+            threshold: 3,
+            base: Duration::from_secs(5),
+            max: Duration::from_secs(60),
+        });
+        let peer = NodeId::from_bytes(&[1; 32]).unwrap();
+        let e = anyhow::anyhow!("Undecodable");
+        for total in 1..=2 {
+            malformed.record(peer, total, &e);
+        }
+        malformed.forgive(&peer);
+        for total in 3..=4 {
+            malformed.record(peer, total, &e);
         }
+        assert!(!malformed.ignores(&peer));
+        malformed.record(peer, 5, &e);
+        assert!(malformed.ignores(&peer));
+        assert!(!malformed.ignores(&NodeId::from_bytes(&[2; 32]).unwrap()));
     }
 }
@@ -0,0 +1,229 @@
+//! Registered names written out in hosts-file format.
+//!
+//! With `hosts_export_path` set, the state map is rendered into a block
+//! between two marker comments in that file, for consumers that can read a
+//! hosts file but not use glued for DNS.  Everything outside the block is
+//! kept as it is.  The block follows the registry's changes, a second
+//! behind so a burst is written once, and the file is only touched when its
+//! block changed.  Edits the registry doesn't announce, such as expired
+//! pins, and the file being edited by others, are caught up with every ten
+//! seconds.
+//!
+//! The new file replaces the old one by rename, unless
+//! `hosts_export_in_place` is set: a bind-mounted file such as a
+//! container's `/etc/hosts` can't be renamed over and is rewritten instead.
+//! Names imported from `hosts_file` aren't exported again.
+
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use log::{debug, warn};
+
+use crate::names::is_valid_label;
+use crate::types::{SharedState, Source, StateMap};
+
+/// How long changes are let settle before the file is written.
+const SETTLE: Duration = Duration::from_secs(1);
+/// How often the file is checked without a change announced.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+const BEGIN_MARKER: &str = "# BEGIN glued: names below are managed by glued; edits are overwritten";
+const END_MARKER: &str = "# END glued";
+
+/// The managed block for `map`, markers included: one line per address,
+/// sorted by name.  With `local_domain`, names are also listed under it.
+pub fn render(map: &StateMap, local_domain: Option<&str>) -> String {
+    let mut names: Vec<_> = map
+        .iter()
+        .filter(|(_, entry)| entry.source != Source::Hosts)
+        // Wildcards have no hosts-file form.
+        .filter(|(name, _)| name.split('.').all(is_valid_label))
+        .collect();
+    names.sort_by_key(|(name, _)| *name);
+
+    let mut block = format!("{}\n", BEGIN_MARKER);
+    for (name, entry) in names {
+        for ip in entry.ips() {
+            match local_domain {
+                Some(domain) => writeln!(block, "{}\t{} {}.{}", ip, name, name, domain),
+                None => writeln!(block, "{}\t{}", ip, name),
+            }
+            .expect("writing to a String can't fail");
+        }
+    }
+    block.push_str(END_MARKER);
+    block.push('\n');
+    block
+}
+
+/// `existing` with its managed block replaced by `block`, or with `block`
+/// appended when there is none yet.
+pub fn splice(existing: &str, block: &str) -> String {
+    let lines: Vec<&str> = existing.lines().collect();
+    let begin = lines
+        .iter()
+        .position(|line| line.trim_end() == BEGIN_MARKER);
+    let Some(begin) = begin else {
+        let mut out = existing.to_string();
+        if !out.is_empty() && !out.ends_with('\n') {
+            out.push('\n');
+        }
+        out.push_str(block);
+        return out;
+    };
+    let end = lines[begin..]
+        .iter()
+        .position(|line| line.trim_end() == END_MARKER)
+        .map(|offset| begin + offset + 1);
+    if end.is_none() {
+        warn!("Hosts export block has no end marker; replacing everything after its start");
+    }
+    let mut out = String::with_capacity(existing.len() + block.len());
+    for line in &lines[..begin] {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push_str(block);
+    for line in &lines[end.unwrap_or(lines.len())..] {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Writes `contents` to `path`, by rename from a temporary file in the
+/// same directory or, with `in_place`, by rewriting the file.
+pub fn write(path: &Path, contents: &str, in_place: bool) -> anyhow::Result<()> {
+    if in_place {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        return Ok(());
+    }
+    let dir = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = dir.join(format!(".{}.glued.tmp", file_name));
+    let mut file = std::fs::File::create(&tmp)
+        .with_context(|| format!("Failed to create {}", tmp.display()))?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&tmp, path)
+        .with_context(|| format!("Failed to move {} into place", path.display()))?;
+    Ok(())
+}
+
+/// Keeps the block in `path` in step with `state`, until the task is
+/// aborted.
+pub async fn run_hosts_export(
+    path: PathBuf,
+    in_place: bool,
+    local_domain: Option<String>,
+    state: SharedState,
+) {
+    let mut changes = state.subscribe();
+    loop {
+        let block = render(&*state.read().await, local_domain.as_deref());
+        sync(&path, &block, in_place).await;
+        let _ = tokio::time::timeout(RECHECK_INTERVAL, changes.recv()).await;
+        tokio::time::sleep(SETTLE).await;
+        // The map is rendered whole; the changes since don't matter.
+        changes = changes.resubscribe();
+    }
+}
+
+/// Writes `block` into `path` if it isn't there already.
+async fn sync(path: &Path, block: &str, in_place: bool) {
+    let existing = match tokio::fs::read_to_string(path).await {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            warn!("Failed to read hosts export {}: {}", path.display(), e);
+            return;
+        }
+    };
+    let updated = splice(&existing, block);
+    if updated == existing {
+        return;
+    }
+    let path = path.to_path_buf();
+    let result = tokio::task::spawn_blocking(move || write(&path, &updated, in_place)).await;
+    match result {
+        Ok(Ok(())) => debug!("Wrote hosts export"),
+        Ok(Err(e)) => warn!("Failed to write hosts export: {:#}", e),
+        Err(e) => warn!("Hosts export writer panicked: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Entry;
+
+    fn map() -> StateMap {
+        let mut map = StateMap::new();
+        map.insert("web-1".into(), Entry::new("10.0.0.2".parse().unwrap()));
+        map.insert("*.web-1".into(), Entry::new("10.0.0.2".parse().unwrap()));
+        map.insert(
+            "nas".into(),
+            Entry {
+                source: Source::Hosts,
+                ..Entry::new("192.168.1.10".parse().unwrap())
+            },
+        );
+        map
+    }
+
+    #[test]
+    fn block_lists_names_and_survives_manual_edits() {
+        let block = render(&map(), Some("glued"));
+        assert_eq!(
+            block,
+            format!(
+                "{}\n10.0.0.2\tweb-1 web-1.glued\n{}\n",
+                BEGIN_MARKER, END_MARKER
+            )
+        );
+
+        let manual = "127.0.0.1 localhost\n192.168.1.1 router";
+        let first = splice(manual, &block);
+        assert!(first.starts_with("127.0.0.1 localhost\n192.168.1.1 router\n# BEGIN"));
+
+        // Edits around the block stay; the block is replaced as a whole.
+        let edited = format!("{}10.9.9.9 added-later\n", first);
+        let empty = render(&StateMap::new(), None);
+        let second = splice(&edited, &empty);
+        assert_eq!(
+            second,
+            format!(
+                "127.0.0.1 localhost\n192.168.1.1 router\n{}10.9.9.9 added-later\n",
+                empty
+            )
+        );
+        assert_eq!(splice(&second, &empty), second);
+    }
+
+    #[test]
+    fn writes_replace_or_rewrite_the_file() {
+        let dir = std::env::temp_dir().join(format!("glued-hosts-export-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hosts");
+        for in_place in [false, true] {
+            write(&path, "10.0.0.2\tweb-1\n", in_place).unwrap();
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), "10.0.0.2\tweb-1\n");
+        }
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,209 @@
+//! Names imported from an `/etc/hosts`-style file.
+//!
+//! Every name in `hosts_file` is put in the state map as a
+//! [`Source::Hosts`] entry.  The file is re-read when it changes and the
+//! difference applied: names dropped from the file leave DNS, while gossip
+//! can neither replace nor remove them.  Static records take precedence.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use crate::names::{is_valid_label, normalize};
+use crate::types::{now_millis, Entry, SharedState, Source, StateMap};
+
+/// How often the file is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Name → its addresses, in file order.
+pub type Hosts = BTreeMap<String, Vec<IpAddr>>;
+
+/// Parses hosts-format `text`: an address followed by one or more names,
+/// with `#` starting a comment.  Malformed lines are logged and skipped.
+pub fn parse(text: &str, origin: &Path) -> Hosts {
+    let mut hosts = Hosts::new();
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(addr) = fields.next() else {
+            continue;
+        };
+        let Ok(ip) = addr.parse::<IpAddr>() else {
+            warn!(
+                "{}:{}: '{}' is not an address; skipping line",
+                origin.display(),
+                line_no,
+                addr
+            );
+            continue;
+        };
+        let names: Vec<&str> = fields.collect();
+        if names.is_empty() {
+            warn!(
+                "{}:{}: no names for {}; skipping line",
+                origin.display(),
+                line_no,
+                ip
+            );
+            continue;
+        }
+        for name in names {
+            let name = normalize(name);
+            if !name.split('.').all(is_valid_label) {
+                warn!(
+                    "{}:{}: '{}' is not a valid host name; skipping it",
+                    origin.display(),
+                    line_no,
+                    name
+                );
+                continue;
+            }
+            let ips = hosts.entry(name).or_default();
+            if !ips.contains(&ip) {
+                ips.push(ip);
+            }
+        }
+    }
+    hosts
+}
+
+/// Makes the hosts entries in `map` match `hosts`, leaving static records
+/// alone.  Returns how many names were added, changed or removed.
+pub fn apply(map: &mut StateMap, hosts: &Hosts) -> usize {
+    let before = map.len();
+    map.retain(|name, entry| entry.source != Source::Hosts || hosts.contains_key(name));
+    let mut changed = before - map.len();
+
+    for (name, ips) in hosts {
+        match map.get(name) {
+            Some(entry) if entry.source == Source::Static => {
+                debug!("Hosts file entry {} is shadowed by a static record", name);
+                continue;
+            }
+            Some(entry) if entry.source == Source::Hosts && entry.ips().eq(ips.iter().copied()) => {
+                continue
+            }
+            Some(entry) if entry.source == Source::Cluster => {
+                warn!(
+                    "Hosts file entry {} replaces the container entry -> {}",
+                    name, entry.ip
+                );
+            }
+            _ => {}
+        }
+        let mut ips = ips.iter().copied();
+        let Some(ip) = ips.next() else {
+            continue;
+        };
+        map.insert(
+            name.clone(),
+            Entry {
+                ip,
+                extra_ips: ips.collect(),
+                txt: Vec::new(),
+                updated_at: now_millis(),
+                refreshed_at: None,
+                source: Source::Hosts,
+                node: None,
+                node_name: None,
+                ports: Vec::new(),
+                scoped_ips: Vec::new(),
+                expires_at: None,
+                shadowed: None,
+            },
+        );
+        changed += 1;
+    }
+    changed
+}
+
+/// Loads `path` into `state` and re-applies it whenever its contents
+/// change, until the task is aborted.  While the file can't be read the
+/// names last loaded from it are kept.
+pub async fn run_hosts_file(path: PathBuf, state: SharedState) {
+    let mut last: Option<String> = None;
+    let mut ticker = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let text = match tokio::fs::read_to_string(&path).await {
+            Ok(text) => text,
+            Err(e) => {
+                if last.is_some() {
+                    warn!("Failed to read hosts file {}: {}", path.display(), e);
+                    last = None;
+                } else {
+                    debug!("Hosts file {} unreadable: {}", path.display(), e);
+                }
+                continue;
+            }
+        };
+        if last.as_deref() == Some(text.as_str()) {
+            continue;
+        }
+        let hosts = parse(&text, &path);
+        let changed = apply(&mut *state.write().await, &hosts);
+        info!(
+            "Loaded {} names from hosts file {} ({} changed)",
+            hosts.len(),
+            path.display(),
+            changed
+        );
+        last = Some(text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(text: &str) -> Hosts {
+        parse(text, Path::new("hosts"))
+    }
+
+    #[test]
+    fn parses_hosts_format_and_skips_malformed_lines() {
+        let parsed = hosts(
+            "# legacy hosts\n\
+             192.168.1.10   nas nas.lan   # storage\n\
+             fd00::10\tnas\n\
+             \n\
+             192.168.1.300  broken\n\
+             192.168.1.20\n\
+             192.168.1.30   Printer bad_name\n",
+        );
+        let ips =
+            |list: &[&str]| -> Vec<IpAddr> { list.iter().map(|ip| ip.parse().unwrap()).collect() };
+        assert_eq!(parsed["nas"], ips(&["192.168.1.10", "fd00::10"]));
+        assert_eq!(parsed["nas.lan"], ips(&["192.168.1.10"]));
+        assert_eq!(parsed["printer"], ips(&["192.168.1.30"]));
+        assert_eq!(parsed.len(), 3);
+    }
+
+    #[test]
+    fn file_changes_are_applied_as_a_diff() {
+        let mut map = StateMap::new();
+        map.insert("web".into(), Entry::new("10.0.0.2".parse().unwrap()));
+        let mut router = Entry::new("192.168.1.1".parse().unwrap());
+        router.source = Source::Static;
+        map.insert("router".into(), router.clone());
+
+        let first = hosts("192.168.1.10 nas\n192.168.1.20 printer\n10.9.9.9 router\n");
+        assert_eq!(apply(&mut map, &first), 2);
+        assert_eq!(map["nas"].source, Source::Hosts);
+        assert_eq!(map["router"], router);
+        let printer = map["printer"].clone();
+
+        let second = hosts("192.168.1.11 nas\n192.168.1.20 printer\n");
+        assert_eq!(apply(&mut map, &second), 1);
+        assert_eq!(map["nas"].ip.to_string(), "192.168.1.11");
+        assert_eq!(map["printer"], printer);
+
+        assert_eq!(apply(&mut map, &Hosts::new()), 2);
+        assert_eq!(map.len(), 2);
+        assert!(map.contains_key("web") && map.contains_key("router"));
+    }
+}
@@ -0,0 +1,147 @@
+//! Container entries that lapse unless their publisher renews them.
+//!
+//! A replica follows each `Add` with an [`Update::Lease`] and renews the
+//! leases of its running containers on every reconcile scan.  Every node
+//! drops container entries whose lease ran out, so a name whose `Remove`
+//! never arrived is gone within `entry_ttl_secs` all the same.  A lease is
+//! a duration counted from when it is applied, so clock skew between nodes
+//! doesn't shorten or stretch it.  Only container entries have leases;
+//! static, hosts-file and manual entries stay until they are removed.
+//!
+//! [`Update::Lease`]: crate::types::Update::Lease
+
+use std::time::Duration;
+
+use log::info;
+
+use crate::types::{now_millis, SharedState, Source, StateMap};
+
+/// How often lapsed entries are swept.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Drops the container entries whose lease ran out by `now` (Unix time in
+/// milliseconds), including those hidden under a pin, returning their
+/// names.
+pub fn expire(map: &mut StateMap, now: u64) -> Vec<String> {
+    let lapsed = |expires_at: Option<u64>| expires_at.is_some_and(|at| at <= now);
+    let mut expired = Vec::new();
+    map.retain(|name, entry| match entry.source {
+        Source::Cluster if lapsed(entry.expires_at) => {
+            expired.push(name.clone());
+            false
+        }
+        Source::Manual
+            if entry
+                .shadowed
+                .as_ref()
+                .is_some_and(|e| lapsed(e.expires_at)) =>
+        {
+            entry.shadowed = None;
+            expired.push(name.clone());
+            true
+        }
+        _ => true,
+    });
+    expired
+}
+
+/// Drops entries as their leases run out, until the task is aborted.
+/// Names another node also publishes are then served from that node.
+pub async fn run_expiry(state: SharedState) {
+    let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let now = now_millis();
+        let mut map = state.write().await;
+        let expired = expire(&mut map, now);
+        for name in &expired {
+            info!("Lease on {} ran out", name);
+        }
+        let served = state.expire_set_aside(&mut map, now);
+        map.changed_only(expired.iter().chain(&served));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gossip::apply_update_from;
+    use crate::types::{Entry, Update};
+
+    fn add(name: &str) -> Update {
+        Update::Add {
+            name: name.into(),
+            ip: "10.0.0.2".parse().unwrap(),
+        }
+    }
+
+    fn lease(name: &str) -> Update {
+        Update::Lease {
+            name: name.into(),
+            valid_for_secs: 60,
+        }
+    }
+
+    #[tokio::test]
+    async fn leases_count_from_when_they_are_applied() {
+        let state = SharedState::default();
+        let node = Some("ab12cd34ef");
+        let before = now_millis();
+        // However far off the publisher's clock is, the lease carries only
+        // a duration: it runs from the receiver's own now.
+        apply_update_from(
+            Update::batch(vec![add("web-1"), lease("web-1")]),
+            node,
+            &state,
+        )
+        .await;
+        apply_update_from(add("web-2"), node, &state).await;
+        let after = now_millis();
+
+        let mut map = state.write().await;
+        let expires_at = map["web-1"].expires_at.unwrap();
+        assert!((before + 60_000..=after + 60_000).contains(&expires_at));
+        assert_eq!(expire(&mut map, before + 59_999), Vec::<String>::new());
+        assert_eq!(expire(&mut map, after + 60_000), ["web-1"]);
+        // Without a lease, an entry stays.
+        assert!(map.contains_key("web-2"));
+        drop(map);
+
+        // A new Add starts over without one until the next lease.
+        apply_update_from(lease("web-2"), node, &state).await;
+        apply_update_from(add("web-2"), node, &state).await;
+        assert_eq!(state.read().await["web-2"].expires_at, None);
+    }
+
+    #[tokio::test]
+    async fn only_container_entries_lapse() {
+        let state = SharedState::default();
+        state.write().await.insert(
+            "nas".into(),
+            Entry {
+                source: Source::Static,
+                ..Entry::new("192.168.1.10".parse().unwrap())
+            },
+        );
+        apply_update_from(lease("nas"), None, &state).await;
+        assert_eq!(state.read().await["nas"].expires_at, None);
+
+        // A container under a pin lapses; the pin stays.
+        for update in [
+            add("web-1"),
+            Update::Pin {
+                name: "web-1".into(),
+                ip: "10.0.0.9".parse().unwrap(),
+                expires_at: None,
+            },
+            lease("web-1"),
+        ] {
+            apply_update_from(update, None, &state).await;
+        }
+        let mut map = state.write().await;
+        assert_eq!(expire(&mut map, u64::MAX), ["web-1"]);
+        assert_eq!(map["web-1"].ip.to_string(), "10.0.0.9");
+        assert_eq!(map["web-1"].shadowed, None);
+        assert_eq!(expire(&mut map, u64::MAX), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,69 @@
+//! Glued: cross-host Docker DNS via gossip.
+//!
+//! The daemon in `main.rs` wires these subsystems together; they are exposed
+//! as a library so the update pipeline can be driven from integration tests.
+
+pub mod acl;
+pub mod admin;
+pub mod audit;
+pub mod bootstrap;
+pub mod chaos;
+pub mod cluster_info;
+pub mod config;
+pub mod conflicts;
+pub mod control;
+pub mod crdt;
+pub mod dedup;
+pub mod digest;
+#[cfg(feature = "daemon")]
+pub mod dns_server;
+#[cfg(feature = "daemon")]
+pub mod dns_tcp;
+#[cfg(feature = "daemon")]
+pub mod dns_tls;
+#[cfg(feature = "daemon")]
+pub mod doctor;
+#[cfg(feature = "daemon")]
+pub mod ede;
+pub mod evict;
+pub mod forward;
+pub mod gossip;
+pub mod hosts_export;
+pub mod hosts_file;
+pub mod leases;
+pub mod limits;
+#[cfg(feature = "daemon")]
+pub mod local_zone;
+pub mod lockout;
+pub mod log_dedup;
+pub mod logging;
+#[cfg(feature = "daemon")]
+pub mod mdns;
+pub mod metrics;
+pub mod names;
+#[cfg(feature = "daemon")]
+pub mod negative_cache;
+pub mod networks;
+pub mod peers;
+pub mod persist;
+pub mod pins;
+pub mod registry;
+pub mod reload;
+pub mod resolver;
+pub mod resync;
+pub mod rrl;
+pub mod runtime;
+pub mod seal;
+pub mod sessions;
+#[cfg(feature = "daemon")]
+pub mod srv;
+pub mod static_records;
+pub mod status;
+pub mod supervise;
+#[cfg(feature = "daemon")]
+pub mod systemd;
+pub mod txt;
+pub mod types;
+pub mod views;
+pub mod webhook;
+pub mod wire;
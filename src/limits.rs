@@ -0,0 +1,184 @@
+//! Limits on what the state map accepts.
+//!
+//! Every update is checked as it is applied, whoever sent it, so a buggy or
+//! hostile peer can't grow the map without bound or store names DNS can't
+//! answer: names with empty labels or labels longer than a DNS label,
+//! names longer than a DNS name, new names beyond `max_entries` in
+//! all or beyond `max_entries_per_node` from one publishing node.
+//! Rejected updates are counted and dropped; the warning about them is
+//! logged at most every [`WARN_INTERVAL`].  Addresses that don't parse
+//! never get this far: updates carrying one fail to decode.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::config::Config;
+use crate::metrics::{inc, METRICS};
+use crate::names::{MAX_LABEL_LEN, MAX_NAME_LEN};
+use crate::types::StateMap;
+
+/// Shortest time between two warnings about rejected updates.
+pub const WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Bounds on the state map.  A zero turns a bound off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_entries: usize,
+    pub max_entries_per_node: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::from_config(&Config::default())
+    }
+}
+
+impl Limits {
+    pub fn from_config(cfg: &Config) -> Self {
+        Self {
+            max_entries: cfg.max_entries,
+            max_entries_per_node: cfg.max_entries_per_node,
+        }
+    }
+
+    /// Why `name` can't be added to `map` for `node`, if it can't.  Names
+    /// already present never count against a bound.
+    pub fn check_room(&self, map: &StateMap, name: &str, node: Option<&str>) -> Result<(), String> {
+        let existing = map.get(name);
+        if existing.is_none() && self.max_entries != 0 && map.len() >= self.max_entries {
+            return Err(format!("the map holds max_entries ({})", self.max_entries));
+        }
+        let Some(node) = node else {
+            return Ok(());
+        };
+        if self.max_entries_per_node == 0
+            || existing.is_some_and(|entry| entry.node.as_deref() == Some(node))
+        {
+            return Ok(());
+        }
+        let published = map
+            .values()
+            .filter(|entry| entry.node.as_deref() == Some(node))
+            .count();
+        if published >= self.max_entries_per_node {
+            return Err(format!(
+                "node {} already has max_entries_per_node ({})",
+                node, self.max_entries_per_node
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Why `name` can't be stored, if it can't: it must fit a DNS name, and
+/// each of its labels, below a wildcard's `*.` and above a network's
+/// suffix, a DNS label.
+pub fn check_name(name: &str) -> Result<(), String> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(format!(
+            "the name is {} bytes, over {}",
+            name.len(),
+            MAX_NAME_LEN
+        ));
+    }
+    let name = name.strip_prefix("*.").unwrap_or(name);
+    if name.is_empty() {
+        return Err("the name is empty".into());
+    }
+    for label in name.split('.') {
+        if label.is_empty() {
+            return Err("the name has an empty label".into());
+        }
+        if label.len() > MAX_LABEL_LEN {
+            return Err(format!(
+                "a label of the name is {} bytes, over {}",
+                label.len(),
+                MAX_LABEL_LEN
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// When the last rejection warning went out, and how many were held back
+/// since.
+static WARNED: Mutex<(Option<Instant>, u64)> = Mutex::new((None, 0));
+
+/// Counts a dropped update and warns about it, unless a warning went out
+/// within [`WARN_INTERVAL`].
+pub fn reject(what: &str, name: &str, reason: &str) {
+    inc(&METRICS.updates_rejected);
+    let mut warned = WARNED.lock().unwrap();
+    let (last, held) = &mut *warned;
+    if last.is_some_and(|at| at.elapsed() < WARN_INTERVAL) {
+        *held += 1;
+        return;
+    }
+    if *held > 0 {
+        warn!(
+            "Rejected {} for {}: {} ({} more rejected since the last warning)",
+            what, name, reason, held
+        );
+    } else {
+        warn!("Rejected {} for {}: {}", what, name, reason);
+    }
+    *last = Some(Instant::now());
+    *held = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Entry;
+
+    #[test]
+    fn names_must_fit_a_label() {
+        assert!(check_name("web-1").is_ok());
+        assert!(check_name(&format!("*.{}", "a".repeat(63))).is_ok());
+        assert!(check_name(&"a".repeat(64)).is_err());
+        assert!(check_name(&format!("{}.net-a", "a".repeat(63))).is_ok());
+        assert!(check_name("").is_err());
+        for name in ["a..b", ".web-1", "web-1.", "*."] {
+            assert!(check_name(name).is_err(), "{}", name);
+        }
+        // Four labels of 63 with their dots make 255 bytes.
+        let long = vec!["a".repeat(63); 4].join(".");
+        assert!(check_name(&long).unwrap_err().contains("253"));
+        assert!(check_name(&long[..253]).is_ok());
+        assert!(check_name(&long[..254]).is_err());
+    }
+
+    #[test]
+    fn new_names_need_room() {
+        let limits = Limits {
+            max_entries: 3,
+            max_entries_per_node: 2,
+        };
+        let mut map = StateMap::new();
+        for (name, node) in [("a", "n1"), ("b", "n1")] {
+            map.insert(
+                name.into(),
+                Entry {
+                    node: Some(node.into()),
+                    ..Entry::new("10.0.0.2".parse().unwrap())
+                },
+            );
+        }
+        // n1 is at its quota, except for names it already has.
+        assert!(limits.check_room(&map, "c", Some("n1")).is_err());
+        assert!(limits.check_room(&map, "a", Some("n1")).is_ok());
+        assert!(limits.check_room(&map, "c", Some("n2")).is_ok());
+        assert!(limits.check_room(&map, "c", None).is_ok());
+
+        map.insert("c".into(), Entry::new("10.0.0.3".parse().unwrap()));
+        assert!(limits.check_room(&map, "d", None).is_err());
+        assert!(limits.check_room(&map, "c", None).is_ok());
+        let unbounded = Limits {
+            max_entries: 0,
+            max_entries_per_node: 0,
+        };
+        assert!(unbounded.check_room(&map, "d", Some("n1")).is_ok());
+    }
+}
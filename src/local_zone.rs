@@ -0,0 +1,241 @@
+//! The zone glued is authoritative for, when `local_domain` is set.
+//!
+//! `web-1.<local_domain>` answers like `web-1`.  The apex has a synthesized
+//! SOA and NS record, and negative answers for names in the zone carry the
+//! SOA so that downstream resolvers (e.g. CoreDNS forwarding the zone to
+//! glued) cache them for the SOA minimum.
+//!
+//! Secondary servers may transfer the zone (AXFR) from clients listed in
+//! `axfr_allow`.  The serial starts at `soa_serial`, or the startup time,
+//! and goes up by one whenever an SOA query or transfer finds the names or
+//! addresses changed since the last one, so secondaries polling the SOA
+//! notice and transfer the zone again.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use hickory_server::proto::rr::rdata::{NS, SOA};
+use hickory_server::proto::rr::{Name, RData, Record};
+
+use crate::config::Config;
+use crate::names::normalize;
+use crate::types::{now_millis, StateMap};
+
+/// SOA timers, for secondaries transferring the zone.
+const SOA_REFRESH: i32 = 3600;
+const SOA_RETRY: i32 = 600;
+const SOA_EXPIRE: i32 = 86400;
+
+/// Where a query name falls relative to the zone.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InZone<'a> {
+    Apex,
+    /// The name with the zone stripped, e.g. `web-1` for `web-1.glued`.
+    Below(&'a str),
+}
+
+#[derive(Debug, Clone)]
+pub struct LocalZone {
+    /// Normalized, for matching query names.
+    domain: String,
+    origin: Name,
+    /// The SOA as configured; its serial is the one in `version`.
+    soa: SOA,
+    /// Shared by clones, so every handler hands out the same serial.
+    version: Arc<Mutex<Version>>,
+}
+
+/// The serial handed out, and the digest of the state map it was last
+/// checked against.
+#[derive(Debug)]
+struct Version {
+    serial: u32,
+    digest: Option<u64>,
+}
+
+impl LocalZone {
+    /// The zone configured by `local_domain`, if any.
+    pub fn from_config(cfg: &Config) -> anyhow::Result<Option<Self>> {
+        let Some(domain) = &cfg.local_domain else {
+            return Ok(None);
+        };
+        let domain = normalize(domain);
+        let origin = fqdn(&domain)?;
+        let mname = match &cfg.soa_mname {
+            Some(mname) => fqdn(mname)?,
+            None => fqdn(&format!("ns.{}", domain))?,
+        };
+        let rname = match &cfg.soa_rname {
+            Some(rname) => fqdn(rname)?,
+            None => fqdn(&format!("hostmaster.{}", domain))?,
+        };
+        // Seconds since the epoch fit a serial until 2106.
+        let serial = cfg
+            .soa_serial
+            .unwrap_or_else(|| (now_millis() / 1000) as u32);
+        let soa = SOA::new(
+            mname,
+            rname,
+            serial,
+            SOA_REFRESH,
+            SOA_RETRY,
+            SOA_EXPIRE,
+            cfg.soa_minimum,
+        );
+        Ok(Some(Self {
+            domain,
+            origin,
+            soa,
+            version: Arc::new(Mutex::new(Version {
+                serial,
+                digest: None,
+            })),
+        }))
+    }
+
+    /// Where `qname` (normalized) falls, if it is in the zone.
+    pub fn locate<'a>(&self, qname: &'a str) -> Option<InZone<'a>> {
+        if qname == self.domain {
+            return Some(InZone::Apex);
+        }
+        let below = qname
+            .strip_suffix(self.domain.as_str())?
+            .strip_suffix('.')?;
+        Some(InZone::Below(below))
+    }
+
+    /// The name `key`, a state map key, is served under in the zone, if it
+    /// makes one.
+    pub fn owner(&self, key: &str) -> Option<Name> {
+        if self.locate(key).is_some() {
+            return fqdn(key).ok();
+        }
+        Name::from_ascii(key).ok()?.append_domain(&self.origin).ok()
+    }
+
+    /// The serial answers carry now.
+    pub fn serial(&self) -> u32 {
+        self.version.lock().unwrap().serial
+    }
+
+    /// Bumps the serial if the names or addresses in `map` changed since
+    /// the last check.
+    pub fn refresh_serial(&self, map: &StateMap) {
+        let digest = digest(map);
+        let mut version = self.version.lock().unwrap();
+        if version.digest.is_some_and(|seen| seen != digest) {
+            version.serial = version.serial.wrapping_add(1);
+        }
+        version.digest = Some(digest);
+    }
+
+    /// The apex SOA.  Its TTL is the SOA minimum, which makes it the
+    /// negative-caching TTL of answers that carry it.
+    pub fn soa_record(&self) -> Record {
+        let soa = &self.soa;
+        let soa = SOA::new(
+            soa.mname().clone(),
+            soa.rname().clone(),
+            self.serial(),
+            soa.refresh(),
+            soa.retry(),
+            soa.expire(),
+            soa.minimum(),
+        );
+        Record::from_rdata(self.origin.clone(), soa.minimum(), RData::SOA(soa))
+    }
+
+    pub fn ns_record(&self) -> Record {
+        Record::from_rdata(
+            self.origin.clone(),
+            self.soa.minimum(),
+            RData::NS(NS(self.soa.mname().clone())),
+        )
+    }
+}
+
+/// A digest of the names and addresses in `map`, whatever its order.
+fn digest(map: &StateMap) -> u64 {
+    map.iter().fold(0, |digest: u64, (name, entry)| {
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        entry.ip.hash(&mut hasher);
+        entry.extra_ips.hash(&mut hasher);
+        for scoped in &entry.scoped_ips {
+            (&scoped.scope, scoped.ip).hash(&mut hasher);
+        }
+        digest.wrapping_add(hasher.finish())
+    })
+}
+
+fn fqdn(name: &str) -> anyhow::Result<Name> {
+    let mut name = Name::from_ascii(name)
+        .map_err(|e| anyhow::anyhow!("Invalid domain name '{}': {}", name, e))?;
+    name.set_fqdn(true);
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Entry;
+
+    fn zone(domain: &str) -> LocalZone {
+        LocalZone::from_config(&Config {
+            local_domain: Some(domain.into()),
+            soa_serial: Some(7),
+            ..Config::default()
+        })
+        .unwrap()
+        .unwrap()
+    }
+
+    #[test]
+    fn names_are_located_in_the_zone() {
+        let zone = zone("Glued.");
+        assert_eq!(zone.locate("glued"), Some(InZone::Apex));
+        assert_eq!(zone.locate("web-1.glued"), Some(InZone::Below("web-1")));
+        assert_eq!(
+            zone.locate("foo.web-1.glued"),
+            Some(InZone::Below("foo.web-1"))
+        );
+        assert_eq!(zone.locate("notglued"), None);
+        assert_eq!(zone.locate("glued.example"), None);
+
+        let RData::SOA(soa) = zone.soa_record().data().unwrap().clone() else {
+            panic!("not an SOA");
+        };
+        assert_eq!(soa.serial(), 7);
+        assert_eq!(soa.mname().to_string(), "ns.glued.");
+        assert_eq!(zone.soa_record().ttl(), Config::default().soa_minimum);
+
+        assert_eq!(zone.owner("web-1").unwrap().to_string(), "web-1.glued.");
+        assert_eq!(zone.owner("db.glued").unwrap().to_string(), "db.glued.");
+        assert_eq!(zone.owner("bad name"), None);
+    }
+
+    #[test]
+    fn the_serial_follows_changes_to_the_map() {
+        let zone = zone("glued");
+        let mut map = StateMap::new();
+        map.insert("web-1".into(), Entry::new("10.0.0.2".parse().unwrap()));
+        zone.refresh_serial(&map);
+        zone.refresh_serial(&map);
+        assert_eq!(zone.serial(), 7);
+
+        map.get_mut("web-1").unwrap().ip = "10.0.0.3".parse().unwrap();
+        zone.refresh_serial(&map);
+        assert_eq!(zone.serial(), 8);
+        // Clones hand out the same serial.
+        let clone = zone.clone();
+        map.insert("web-2".into(), Entry::new("10.0.0.4".parse().unwrap()));
+        clone.refresh_serial(&map);
+        zone.refresh_serial(&map);
+        assert_eq!((zone.serial(), clone.serial()), (9, 9));
+        // What isn't transferred doesn't count.
+        map.get_mut("web-2").unwrap().txt = vec!["role=api".into()];
+        zone.refresh_serial(&map);
+        assert_eq!(zone.serial(), 9);
+    }
+}
@@ -0,0 +1,180 @@
+//! Exponential lockout of peers that repeatedly fail authentication, or
+//! keep delivering malformed gossip.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::config::Config;
+
+/// When and for how long failing peers are locked out.
+#[derive(Debug, Clone, Copy)]
+pub struct LockoutPolicy {
+    /// Consecutive failures before the first lockout.  Zero disables lockouts.
+    pub threshold: u32,
+    /// Length of the first lockout; each further failure doubles it.
+    pub base: Duration,
+    /// Upper bound on a single lockout.  A peer quiet for this long starts
+    /// from a clean slate.
+    pub max: Duration,
+}
+
+impl LockoutPolicy {
+    pub fn from_config(cfg: &Config) -> Self {
+        Self {
+            threshold: cfg.auth_lockout_threshold,
+            base: Duration::from_secs(cfg.auth_lockout_base_secs),
+            max: Duration::from_secs(cfg.auth_lockout_max_secs),
+        }
+    }
+
+    /// The policy for peers delivering malformed gossip: the auth lockout's
+    /// durations after `[gossip] malformed_threshold` messages.
+    pub fn for_malformed(cfg: &Config) -> Self {
+        Self {
+            threshold: cfg.gossip.malformed_threshold(),
+            ..Self::from_config(cfg)
+        }
+    }
+
+    fn duration(&self, failures: u32) -> Duration {
+        let doublings = failures.saturating_sub(self.threshold).min(31);
+        self.base.saturating_mul(1 << doublings).min(self.max)
+    }
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Failure counters keyed by peer.
+#[derive(Debug)]
+pub struct Lockout<K> {
+    policy: LockoutPolicy,
+    peers: HashMap<K, Failures>,
+}
+
+impl<K: Eq + Hash> Lockout<K> {
+    pub fn new(policy: LockoutPolicy) -> Self {
+        Self {
+            policy,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// When `peer`'s current lockout ends, if it is locked out at `now`.
+    pub fn locked_until(&self, peer: &K, now: Instant) -> Option<Instant> {
+        self.peers
+            .get(peer)
+            .and_then(|f| f.locked_until)
+            .filter(|until| *until > now)
+    }
+
+    /// Records a failed attempt and returns the lockout it triggered, if any.
+    pub fn record_failure(&mut self, peer: K, now: Instant) -> Option<Duration> {
+        self.prune(now);
+        if self.policy.threshold == 0 {
+            return None;
+        }
+        let failures = self.peers.entry(peer).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: None,
+        });
+        failures.count = failures.count.saturating_add(1);
+        failures.last = now;
+        if failures.count < self.policy.threshold {
+            return None;
+        }
+        let duration = self.policy.duration(failures.count);
+        failures.locked_until = Some(now + duration);
+        Some(duration)
+    }
+
+    /// Forgets `peer`'s failures after a successful attempt.
+    pub fn record_success(&mut self, peer: &K) {
+        self.peers.remove(peer);
+    }
+
+    /// Drops peers whose lockout has ended and who have been quiet for `max`.
+    fn prune(&mut self, now: Instant) {
+        let max = self.policy.max;
+        self.peers.retain(|_, f| {
+            f.locked_until.is_some_and(|until| until > now) || now.duration_since(f.last) < max
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lockout() -> Lockout<&'static str> {
+        Lockout::new(LockoutPolicy {
+            threshold: 3,
+            base: Duration::from_secs(5),
+            max: Duration::from_secs(60),
+        })
+    }
+
+    #[test]
+    fn locks_out_after_threshold_and_expires() {
+        let mut lockout = lockout();
+        let start = Instant::now();
+        assert_eq!(lockout.record_failure("peer", start), None);
+        assert_eq!(lockout.record_failure("peer", start), None);
+        assert_eq!(
+            lockout.record_failure("peer", start),
+            Some(Duration::from_secs(5))
+        );
+        assert!(lockout.locked_until(&"peer", start).is_some());
+        assert!(lockout.locked_until(&"other", start).is_none());
+
+        let later = start + Duration::from_secs(5);
+        assert!(lockout.locked_until(&"peer", later).is_none());
+    }
+
+    #[test]
+    fn repeated_failures_back_off_up_to_max() {
+        let mut lockout = lockout();
+        let now = Instant::now();
+        let lockouts: Vec<_> = (0..8)
+            .filter_map(|_| lockout.record_failure("peer", now))
+            .map(|d| d.as_secs())
+            .collect();
+        assert_eq!(lockouts, [5, 10, 20, 40, 60, 60]);
+    }
+
+    #[test]
+    fn success_and_quiet_periods_reset_the_count() {
+        let mut lockout = lockout();
+        let start = Instant::now();
+        lockout.record_failure("peer", start);
+        lockout.record_failure("peer", start);
+        lockout.record_success(&"peer");
+        assert_eq!(lockout.record_failure("peer", start), None);
+
+        lockout.record_failure("peer", start);
+        let quiet = start + Duration::from_secs(61);
+        assert_eq!(lockout.record_failure("peer", quiet), None);
+    }
+
+    #[test]
+    fn zero_threshold_disables_lockout() {
+        let mut lockout = Lockout::new(LockoutPolicy {
+            threshold: 0,
+            base: Duration::from_secs(5),
+            max: Duration::from_secs(60),
+        });
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(lockout.record_failure("peer", now), None);
+        }
+        assert!(lockout.locked_until(&"peer", now).is_none());
+    }
+}
@@ -0,0 +1,151 @@
+//! Logging for errors that recur while something stays down.
+//!
+//! A monitor retrying a dead Docker socket, or gossip redialling an
+//! unreachable peer, would log the same line on every attempt.  Through a
+//! [`LogDedup`], the first line under a key is logged, and identical ones
+//! after it are only counted until the window has passed, when the next is
+//! logged with how many were held back.  A different message under the key
+//! is logged at once, after the count of the old one, as is the count when
+//! the caller reports the key recovered.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::Duration;
+
+use log::Level;
+use tokio::time::Instant;
+
+/// How long identical messages are held back after one is logged.
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+
+/// The messages last logged under each key, and their repeats since.
+#[derive(Debug)]
+pub struct LogDedup<K> {
+    target: &'static str,
+    level: Level,
+    window: Duration,
+    seen: HashMap<K, Seen>,
+}
+
+#[derive(Debug)]
+struct Seen {
+    message: String,
+    logged_at: Instant,
+    /// Identical messages held back since.
+    repeats: u64,
+}
+
+impl Seen {
+    fn line(&self) -> String {
+        match self.repeats {
+            0 => self.message.clone(),
+            1 => format!("{} (repeated 1 time)", self.message),
+            n => format!("{} (repeated {} times)", self.message, n),
+        }
+    }
+}
+
+impl<K: Eq + Hash> LogDedup<K> {
+    /// Logs at `level` under `target`, as the caller's own lines would be.
+    pub fn new(target: &'static str, level: Level) -> Self {
+        Self {
+            target,
+            level,
+            window: DEFAULT_WINDOW,
+            seen: HashMap::new(),
+        }
+    }
+
+    pub fn with_window(self, window: Duration) -> Self {
+        Self { window, ..self }
+    }
+
+    /// Logs `message` under `key`, unless it repeats the last one within
+    /// the window.
+    pub fn log(&mut self, key: K, message: impl Into<String>) {
+        for line in self.note(key, message.into(), Instant::now()) {
+            log::log!(target: self.target, self.level, "{}", line);
+        }
+    }
+
+    /// Forgets `key`, logging the repeats held back since its last line.
+    /// Returns whether anything had been logged under it, so the caller can
+    /// say it has recovered.
+    pub fn recovered(&mut self, key: &K) -> bool {
+        let Some(seen) = self.seen.remove(key) else {
+            return false;
+        };
+        if seen.repeats > 0 {
+            log::log!(target: self.target, self.level, "{}", seen.line());
+        }
+        true
+    }
+
+    /// The lines to log for `message` under `key` at `now`.
+    fn note(&mut self, key: K, message: String, now: Instant) -> Vec<String> {
+        let mut lines = Vec::new();
+        if let Some(seen) = self.seen.get_mut(&key) {
+            if seen.message == message {
+                seen.repeats += 1;
+                if now.duration_since(seen.logged_at) < self.window {
+                    return lines;
+                }
+                // The line itself is one of them.
+                seen.repeats -= 1;
+                lines.push(seen.line());
+                seen.logged_at = now;
+                seen.repeats = 0;
+                return lines;
+            }
+            if seen.repeats > 0 {
+                lines.push(seen.line());
+            }
+        }
+        lines.push(message.clone());
+        self.seen.insert(
+            key,
+            Seen {
+                message,
+                logged_at: now,
+                repeats: 0,
+            },
+        );
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats_are_counted_until_the_window_passes_or_the_message_changes() {
+        let mut dedup = LogDedup::new("glued", Level::Warn);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let down = || "Failed to connect to Docker: no such file".to_string();
+
+        assert_eq!(dedup.note("docker", down(), at(0)), vec![down()]);
+        for secs in [5, 10, 15] {
+            assert!(dedup.note("docker", down(), at(secs)).is_empty());
+        }
+        // Other keys are counted on their own.
+        assert_eq!(dedup.note("peer", "unreachable".into(), at(15)).len(), 1);
+        assert_eq!(
+            dedup.note("docker", down(), at(60)),
+            vec![format!("{} (repeated 3 times)", down())]
+        );
+        assert!(dedup.note("docker", down(), at(65)).is_empty());
+        assert_eq!(
+            dedup.note("docker", "Failed initial scan".into(), at(70)),
+            vec![
+                format!("{} (repeated 1 time)", down()),
+                "Failed initial scan".to_string()
+            ]
+        );
+
+        assert!(dedup.recovered(&"docker"));
+        assert!(!dedup.recovered(&"docker"));
+        assert_eq!(dedup.note("docker", down(), at(75)), vec![down()]);
+    }
+}
@@ -0,0 +1,90 @@
+//! Log output, set up from `log_level` and `log_format`.
+//!
+//! Lines come from `tracing`; the `log` macros used throughout are bridged
+//! in.  Subsystems log under their module paths and open spans at their
+//! boundaries, so a filter or query can follow one of them:
+//!
+//! * `glued::dns`: a `dns_request` span per query, with a short random
+//!   request id, qname, qtype and client, and at debug a line as each is
+//!   answered.  Warnings about a forwarded lookup come with its span.
+//! * `glued::gossip`: a `gossip_message` span per message received.
+//! * `glued::runtime`: a span per Docker or containerd event.
+
+use std::sync::OnceLock;
+
+use anyhow::anyhow;
+use log::error;
+use tracing_subscriber::{reload, EnvFilter};
+
+use crate::config::{Config, LogFormat};
+
+/// Swaps the filter of the installed subscriber.
+type Reload = Box<dyn Fn(EnvFilter) -> Result<(), reload::Error> + Send + Sync>;
+
+static RELOAD: OnceLock<Reload> = OnceLock::new();
+
+/// Installs the global subscriber, and a panic hook that logs panics at
+/// error like any other failure.  Fails on an invalid `log_level`, or if
+/// logging was already set up.
+pub fn init(cfg: &Config) -> anyhow::Result<()> {
+    let builder = tracing_subscriber::fmt().with_env_filter(filter(rust_log(), &cfg.log_level)?);
+    let (reload, installed): (Reload, _) = match cfg.log_format {
+        LogFormat::Text => {
+            let builder = builder.with_filter_reloading();
+            let handle = builder.reload_handle();
+            (Box::new(move |f| handle.reload(f)), builder.try_init())
+        }
+        LogFormat::Json => {
+            let builder = builder.json().with_filter_reloading();
+            let handle = builder.reload_handle();
+            (Box::new(move |f| handle.reload(f)), builder.try_init())
+        }
+    };
+    installed.map_err(|e| anyhow!("Failed to set up logging: {}", e))?;
+    let _ = RELOAD.set(reload);
+    std::panic::set_hook(Box::new(|info| {
+        error!(target: "glued::panic", "{}", info);
+    }));
+    Ok(())
+}
+
+/// Switches to `log_level`, unless `RUST_LOG` overrides it.  Fails on an
+/// invalid level, leaving the old one in effect.
+pub fn set_level(log_level: &str) -> anyhow::Result<()> {
+    let filter = filter(rust_log(), log_level)?;
+    match RELOAD.get() {
+        Some(reload) => {
+            reload(filter).map_err(|e| anyhow!("Failed to change the log level: {}", e))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Fails if `log_level` isn't a valid filter.
+pub fn check_level(log_level: &str) -> anyhow::Result<()> {
+    filter(None, log_level).map(drop)
+}
+
+fn rust_log() -> Option<String> {
+    std::env::var("RUST_LOG").ok().filter(|s| !s.is_empty())
+}
+
+/// `rust_log` if it is set, else `log_level`.
+fn filter(rust_log: Option<String>, log_level: &str) -> anyhow::Result<EnvFilter> {
+    let directives = rust_log.unwrap_or_else(|| log_level.to_string());
+    EnvFilter::try_new(&directives)
+        .map_err(|e| anyhow!("Invalid log filter '{}': {}", directives, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_level_takes_per_target_directives() {
+        assert!(filter(None, "info,glued::gossip=debug").is_ok());
+        assert!(filter(None, "glued::dns=loud").is_err());
+        // RUST_LOG wins over the config.
+        assert!(filter(Some("debug".into()), "glued::dns=loud").is_ok());
+    }
+}
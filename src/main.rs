@@ -1,134 +1,238 @@
 //! Glued daemon entry point.
 
-use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
-use log::{error, info};
+use futures_util::future::select_all;
+use log::{debug, error, info, warn};
 use tokio::signal;
-use tokio::sync::{mpsc, RwLock};
-
-mod config;
-mod dns_server;
-mod gossip;
-mod runtime;
-mod types;
-
-use config::Config;
-use dns_server::run_dns_server;
-use gossip::run_gossip;
-use runtime::{ContainerRuntime, DockerRuntime};
-// use types::Update;
-
-#[derive(Debug, Clone)]
-enum Role {
-    Main,
-    Replica(String),
-}
+use tokio::sync::{mpsc, oneshot};
+
+use glued::admin::{self, Admin};
+use glued::audit::{self, AuditLog};
+use glued::config::{Config, NodeRole, Role, RuntimeKind, Watched};
+use glued::control::{self, Control};
+use glued::dns_server::{run_dns_server, DnsOptions, DnsSockets};
+use glued::doctor;
+use glued::gossip::{self, run_gossip};
+use glued::hosts_export;
+use glued::hosts_file;
+use glued::leases;
+use glued::limits::Limits;
+use glued::logging;
+use glued::mdns;
+use glued::networks::InNetwork;
+use glued::persist;
+use glued::pins;
+use glued::registry::{ChangeOrigin, PeerNode, Registry};
+use glued::reload::{self, Reloader};
+use glued::runtime::{ContainerRuntime, ContainerdRuntime, DockerRuntime};
+use glued::static_records;
+use glued::status::Status;
+use glued::supervise::{supervise, RestartPolicy};
+use glued::systemd;
+use glued::types::{SharedState, Update};
+use glued::webhook::Webhooks;
+
+/// How long shutdown waits for gossip to send the removals of our entries.
+const WITHDRAW_TIMEOUT: Duration = Duration::from_secs(3);
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // Initialize logging
-    env_logger::init();
+    // `glued ctl ...` talks to a running daemon instead of being one;
+    // `glued export` and `glued import` are `ctl` commands.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("ctl") => return control::ctl(&args[1..]).await,
+        Some("export" | "import") => return control::ctl(&args).await,
+        _ => {}
+    }
+    // `glued doctor` checks the setup and exits.
+    if args.first().is_some_and(|arg| arg == "doctor") {
+        if !doctor::doctor(&args[1..]).await? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
-    // Load configuration
-    let mut cfg = Config::load()?;
+    // Load configuration; logging is set up from it.
+    let cfg = Config::load()?;
+    logging::init(&cfg)?;
 
-    // If a network name is provided, act as a replica (watch containers and gossip);
-    // otherwise run as the main instance (DNS + registry only).
-    let role = match cfg.network_name.clone() {
-        Some(network) => Role::Replica(network),
-        None => Role::Main,
-    };
+    // A replica watches containers and publishes them over gossip; a DNS-only
+    // node serves what it learns from the replicas.
+    let role = cfg.resolve_role()?;
     let role_label = match &role {
-        Role::Main => "main",
+        Role::Dns => "dns",
         Role::Replica(_) => "replica",
     };
     info!("Running as {} role", role_label);
 
-    // Replica-specific logic
-    if let Role::Replica(_) = role {
-        // Infer peers from Docker DNS if a bootstrap service name is provided
-        if let Some(service_name) = cfg.bootstrap_service.clone() {
-            info!(
-                "Attempting to discover bootstrap peers for service '{}' via Docker DNS.",
-                service_name
-            );
-            // Use hickory-resolver (previously trust-dns-resolver)
-            use hickory_resolver::config::{ResolverConfig, ResolverOpts};
-            use hickory_resolver::TokioAsyncResolver;
-
-            // Create a resolver with default configuration
-            let resolver =
-                TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default());
-
-            // The hostname for a Docker Swarm service's tasks is `tasks.<service_name>`
-            let lookup_name = format!("tasks.{}", service_name);
-
-            match resolver.lookup_ip(lookup_name.clone()).await {
-                Ok(response) => {
-                    let discovered_peers: Vec<String> =
-                        response.iter().map(|ip| ip.to_string()).collect();
-                    if discovered_peers.is_empty() {
-                        info!(
-                            "DNS lookup for '{}' was successful but returned no IPs.",
-                            lookup_name
-                        );
-                    } else {
-                        info!("Discovered bootstrap peers: {:?}", discovered_peers);
-                        // Prioritise the main instance by inserting discovered peers first.
-                        let mut ordered = discovered_peers;
-                        ordered.extend(cfg.bootstrap_peers.clone());
-                        cfg.bootstrap_peers = ordered;
-                        // Remove duplicates
-                        cfg.bootstrap_peers.sort();
-                        cfg.bootstrap_peers.dedup();
-                    }
+    info!("Starting Glued daemon with config: {:?}", cfg);
+    let dns_options = DnsOptions::from_config(&cfg)?;
+
+    let needs_neighbor = matches!(role, Role::Replica(_))
+        && cfg.ready_requires_neighbor
+        && !cfg.bootstrap_peers.is_empty();
+    let status = Arc::new(Status::new(needs_neighbor).warming_up(
+        Duration::from_secs(cfg.dns_warmup_timeout_secs),
+        matches!(role, Role::Replica(_)),
+    ));
+
+    // Bind DNS first: a node that can't serve DNS shouldn't run at all.
+    let bind_dns = if cfg.dns_reuse_port {
+        DnsSockets::bind_sharing_port
+    } else {
+        DnsSockets::bind
+    };
+    let dns_sockets = bind_dns(
+        &cfg.dns_bind_addrs(),
+        &cfg.dns_fallback_ports,
+        cfg.dns_bind_best_effort,
+    )?;
+    let dns_addrs = dns_sockets.local_addrs();
+    let listing: Vec<String> = dns_addrs.iter().map(|addr| addr.to_string()).collect();
+    info!("DNS server listening on {}", listing.join(", "));
+    status.set_dns_addrs(dns_addrs.clone());
+
+    // Shared state, seeded from the last snapshot so we can answer before gossip catches up.
+    let state: SharedState = Arc::new(
+        Registry::new(cfg.update_channel_capacity, Limits::from_config(&cfg))
+            .with_conflict_policy(cfg.conflict_policy)
+            .with_eviction_grace(Duration::from_secs(cfg.eviction_grace_secs)),
+    );
+    let snapshot_path = cfg
+        .persist_state
+        .then(|| persist::state_path(&cfg.data_dir));
+    if let Some(path) = &snapshot_path {
+        persist::restore(path, &state).await;
+    }
+    static_records::install(&mut *state.write().await, &cfg.static_records)?;
+    // The running config, reloaded on SIGHUP and by the control socket.
+    let reloader = Arc::new(Reloader::new(cfg.clone(), Arc::clone(&state)));
+    #[cfg(unix)]
+    let sighup_handle = Some(tokio::spawn(reload::run_on_sighup(Arc::clone(&reloader))));
+    #[cfg(not(unix))]
+    let sighup_handle: Option<tokio::task::JoinHandle<()>> = None;
+    let hosts_handle = cfg
+        .hosts_file
+        .as_ref()
+        .map(|path| tokio::spawn(hosts_file::run_hosts_file(path.into(), Arc::clone(&state))));
+    let export_handle = cfg.hosts_export_path.as_ref().map(|path| {
+        info!("Exporting names to {}", path);
+        tokio::spawn(hosts_export::run_hosts_export(
+            path.into(),
+            cfg.hosts_export_in_place,
+            cfg.local_domain.clone(),
+            Arc::clone(&state),
+        ))
+    });
+    let snapshot_handle = snapshot_path.clone().map(|path| {
+        let interval = Duration::from_secs(cfg.snapshot_interval_secs.max(1));
+        tokio::spawn(persist::run_snapshots(path, interval, Arc::clone(&state)))
+    });
+
+    // Update channels; `mpsc::channel` panics on a zero capacity.
+    let capacity = cfg.update_channel_capacity.max(1);
+    let (local_update_tx, local_update_rx) = mpsc::channel(capacity);
+    let control_update_tx = local_update_tx.clone();
+    let admin_update_tx = local_update_tx.clone();
+    let (gossip_out_tx, gossip_out_rx) = mpsc::channel(capacity);
+    let (withdraw_tx, withdraw_rx) = mpsc::channel(1);
+    let (gossip_in_tx, gossip_in_rx) = mpsc::channel::<(Update, Option<PeerNode>)>(capacity);
+
+    // Replicas start a container runtime monitor for each watched network.
+    let restarts = RestartPolicy::from_config(&cfg);
+    let watched = match &role {
+        Role::Replica(watched) => watched.clone(),
+        Role::Dns => Vec::new(),
+    };
+    let mut runtime_handles = Vec::new();
+    for Watched {
+        network,
+        namespaced,
+    } in watched
+    {
+        info!(
+            "Starting container runtime monitor for network {}...",
+            network
+        );
+        // An explicit replica must not quietly run without its runtime, nor
+        // any replica watching a network Docker says doesn't exist.
+        let explicit = cfg.role == NodeRole::Replica;
+        let mut runtime: Arc<dyn ContainerRuntime + Send + Sync> = match cfg.runtime {
+            RuntimeKind::Docker => {
+                let runtime =
+                    DockerRuntime::new(network.clone(), &cfg)?.reporting_to(Arc::clone(&status));
+                if explicit {
+                    runtime.check().await?;
+                } else {
+                    runtime.check_network().await?;
                 }
-                Err(e) => {
-                    error!(
-                        "DNS lookup for '{}' failed: {}. Continuing with configured peers.",
-                        lookup_name, e
-                    );
+                Arc::new(runtime)
+            }
+            RuntimeKind::Containerd => {
+                let runtime = ContainerdRuntime::new(network.clone(), &cfg)?
+                    .reporting_to(Arc::clone(&status));
+                if explicit {
+                    runtime.check().await?;
                 }
+                Arc::new(runtime)
             }
+        };
+        if namespaced {
+            runtime = Arc::new(InNetwork { network, runtime });
         }
+        // A restarted monitor rescans, so nothing missed meanwhile is lost.
+        let updates = local_update_tx.clone();
+        runtime_handles.push(tokio::spawn(supervise(
+            "Container runtime monitor",
+            restarts,
+            move || {
+                let runtime = Arc::clone(&runtime);
+                let updates = updates.clone();
+                async move { runtime.monitor(updates).await }
+            },
+        )));
     }
 
-    info!("Starting Glued daemon with config: {:?}", cfg);
+    // This node's own changes go out over gossip.
+    let forward_handle = tokio::spawn(gossip::forward_local(state.subscribe(), gossip_out_tx));
 
-    // Shared state
-    let state: Arc<RwLock<HashMap<String, String>>> = Arc::new(RwLock::new(HashMap::new()));
-
-    // Update channels
-    let (local_update_tx, local_update_rx) = mpsc::channel(128);
-    let (gossip_out_tx, gossip_out_rx) = mpsc::channel(128);
-    let (gossip_in_tx, gossip_in_rx) = mpsc::channel(128);
-
-    // Conditionally start the Container Runtime monitor for replicas
-    let runtime_handle = if let Role::Replica(network_name) = role.clone() {
-        info!("Starting container runtime monitor...");
-        let runtime = DockerRuntime::new(network_name);
-        let handle = tokio::spawn(async move {
-            if let Err(e) = runtime.monitor(local_update_tx).await {
-                error!("Container runtime failed: {}", e);
-            }
-        });
-        Some(handle)
-    } else {
-        None
+    // Webhooks hear about every update applied, local or remote.
+    let webhooks = Webhooks::from_config(&cfg)?.map(Arc::new);
+    let mut webhook_handles = webhooks.as_ref().map(Webhooks::spawn).unwrap_or_default();
+    if let Some(webhooks) = webhooks {
+        webhook_handles.push(tokio::spawn(webhooks.follow(state.subscribe())));
+    }
+    let audit_handle = match &cfg.audit_log {
+        Some(path) => {
+            let log = AuditLog::open(path).await?;
+            Some(tokio::spawn(audit::run_audit(log, state.subscribe())))
+        }
+        None => None,
     };
 
-    // Local registry updater: apply local discoveries and forward to gossip.
+    // Local registry updater: apply local discoveries in batches.  Once
+    // another instance has taken over, it publishes the containers, so
+    // only operators' changes are applied here.
     let registry_for_local = Arc::clone(&state);
-    let gossip_out_forward = gossip_out_tx.clone();
+    let status_for_local = Arc::clone(&status);
+    let batching = gossip::Batching::from_config(&cfg);
     let registry_local_handle = tokio::spawn(async move {
         let mut updates = local_update_rx;
-        while let Some(update) = updates.recv().await {
-            gossip::apply_update(update.clone(), &registry_for_local).await;
-            if let Err(e) = gossip_out_forward.send(update).await {
-                error!("Failed to forward update to gossip pipeline: {}", e);
+        loop {
+            let batches = gossip::next_local_batches(&mut updates, &batching).await;
+            if batches.is_empty() {
                 break;
             }
+            for (update, source) in batches {
+                if status_for_local.is_handed_over() && source.is_runtime() {
+                    debug!("Handed over; dropping {} update", source.as_str());
+                    continue;
+                }
+                gossip::apply_update(update, source, &registry_for_local).await;
+            }
         }
     });
 
@@ -136,57 +240,268 @@ async fn main() -> anyhow::Result<()> {
     let registry_for_remote = Arc::clone(&state);
     let registry_remote_handle = tokio::spawn(async move {
         let mut updates = gossip_in_rx;
-        while let Some(update) = updates.recv().await {
-            gossip::apply_update(update, &registry_for_remote).await;
+        while let Some((update, node)) = updates.recv().await {
+            registry_for_remote
+                .apply(update, ChangeOrigin::Peer(node))
+                .await;
         }
     });
 
     // Gossip Subsystem
-    let topic_id = cfg.topic_id.clone();
-    let bootstrap_peers = cfg.bootstrap_peers.clone();
-    let cluster_secret = cfg.cluster_secret.clone();
-    let gossip_handle = tokio::spawn(async move {
-        if let Err(e) = run_gossip(
-            topic_id,
-            bootstrap_peers,
-            gossip_out_rx,
-            gossip_in_tx,
-            cluster_secret,
-        )
-        .await
-        {
-            error!("Gossip subsystem failed: {}", e);
+    let mut gossip_cfg = cfg.clone();
+    if role == Role::Dns && cfg.role == NodeRole::Auto {
+        // The main instance is what replicas discover; it doesn't look for itself.
+        gossip_cfg.bootstrap_service = None;
+    }
+    gossip_cfg.role = match role {
+        Role::Dns => NodeRole::Dns,
+        Role::Replica(_) => NodeRole::Replica,
+    };
+    let status_for_gossip = Arc::clone(&status);
+    let state_for_gossip = Arc::clone(&state);
+    let reloader_for_gossip = Arc::clone(&reloader);
+    // The outbound channel outlives each run, so nothing queued is lost.
+    let gossip_out_rx = Arc::new(tokio::sync::Mutex::new(gossip_out_rx));
+    let withdraw_rx = Arc::new(tokio::sync::Mutex::new(withdraw_rx));
+    let mut gossip_handle = tokio::spawn(supervise("Gossip subsystem", restarts, move || {
+        let cfg = gossip_cfg.clone();
+        let outbound = Arc::clone(&gossip_out_rx);
+        let withdrawals = Arc::clone(&withdraw_rx);
+        let inbound = gossip_in_tx.clone();
+        let status = Arc::clone(&status_for_gossip);
+        let state = Arc::clone(&state_for_gossip);
+        let reloads = reloader_for_gossip.subscribe();
+        async move {
+            let outbound = &mut *outbound.lock().await;
+            let withdrawals = &mut *withdrawals.lock().await;
+            run_gossip(cfg, outbound, withdrawals, inbound, status, state, reloads).await
         }
-    });
+    }));
 
-    // DNS Server
+    // DNS Server; a restart binds the addresses the first run got.
     let state_for_dns = Arc::clone(&state);
-    let dns_bind = cfg.dns_bind;
-    let dns_handle = tokio::spawn(async move {
-        if let Err(e) = run_dns_server(dns_bind, state_for_dns).await {
-            error!("DNS server failed: {}", e);
+    let reloader_for_dns = Arc::clone(&reloader);
+    let status_for_dns = Arc::clone(&status);
+    let mut dns_options = Some(dns_options);
+    let mut dns_sockets = Some(dns_sockets);
+    let mut dns_handle = tokio::spawn(supervise("DNS server", restarts, move || {
+        let sockets = dns_sockets.take();
+        let addrs = dns_addrs.clone();
+        let state = Arc::clone(&state_for_dns);
+        let status = Arc::clone(&status_for_dns);
+        // A restart picks up reloaded ACLs and forward zones.
+        let options = dns_options
+            .take()
+            .map_or_else(|| DnsOptions::from_config(&reloader_for_dns.config()), Ok);
+        let reloads = reloader_for_dns.subscribe();
+        async move {
+            let options = DnsOptions {
+                status: Some(status),
+                reloads: Some(reloads),
+                ..options?
+            };
+            let sockets = match sockets {
+                Some(sockets) => sockets,
+                None => bind_dns(&addrs, &[], false)?,
+            };
+            run_dns_server(sockets, state, options).await
         }
+    }));
+
+    // systemd readiness and watchdog; nothing when not run by systemd.
+    let systemd_handles = if systemd::enabled() {
+        let replica = matches!(role, Role::Replica(_));
+        vec![
+            tokio::spawn(systemd::ready(Arc::clone(&status), replica)),
+            tokio::spawn(systemd::run_watchdog(
+                Arc::clone(&state),
+                Arc::clone(&status),
+            )),
+        ]
+    } else {
+        Vec::new()
+    };
+
+    // mDNS responder
+    let mdns_handle = cfg.mdns_advertise.then(|| {
+        let state_for_mdns = Arc::clone(&state);
+        let suffix = cfg.mdns_suffix.clone();
+        tokio::spawn(async move {
+            if let Err(e) = mdns::run_mdns(&suffix, state_for_mdns).await {
+                error!("mDNS responder failed: {:#}", e);
+            }
+        })
     });
 
-    // Graceful Shutdown
-    match signal::ctrl_c().await {
-        Ok(()) => {
-            info!("Received Ctrl+C, shutting down...");
+    // Manual entries drop out on every node as they expire.
+    let pins_handle = tokio::spawn(pins::run_expiry(Arc::clone(&state)));
+    // So do container entries nobody renewed.
+    let leases_handle = tokio::spawn(leases::run_expiry(Arc::clone(&state)));
+
+    // Admin API
+    let admin_handle = match cfg.admin_bind {
+        Some(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to bind the admin API on {}: {}", addr, e))?;
+            info!("Admin API listening on {}", addr);
+            let admin = Admin {
+                state: Arc::clone(&state),
+                status: Arc::clone(&status),
+                updates: admin_update_tx,
+                token: cfg.admin_token.clone(),
+                metrics_token: cfg.metrics_token.clone(),
+            };
+            Some(tokio::spawn(admin::run_admin(listener, admin)))
         }
-        Err(err) => {
-            error!("Unable to listen for shutdown signal: {}", err);
+        None => None,
+    };
+
+    // Control socket; a node that can't create it still serves.
+    #[cfg(unix)]
+    let control_handle = match cfg.control_socket.as_str() {
+        "" => None,
+        path => match control::listen(path.as_ref(), cfg.control_socket_mode.0) {
+            Ok(listener) => {
+                info!("Control socket listening on {}", path);
+                let control = Control {
+                    state: Arc::clone(&state),
+                    status: Arc::clone(&status),
+                    updates: control_update_tx,
+                    reloader: Arc::clone(&reloader),
+                };
+                Some(tokio::spawn(control::run_control(
+                    listener,
+                    Arc::new(control),
+                )))
+            }
+            Err(e) => {
+                warn!("Control socket unavailable at {}: {:#}", path, e);
+                None
+            }
+        },
+    };
+    #[cfg(not(unix))]
+    let control_handle: Option<tokio::task::JoinHandle<()>> = None;
+    // Blue/green upgrade: the instance being replaced stops publishing.
+    let handover_handle = cfg
+        .handover_from
+        .as_ref()
+        .map(|path| tokio::spawn(control::take_over(path.into(), Arc::clone(&status))));
+
+    // Graceful Shutdown, on Ctrl+C, SIGTERM (what `docker stop` and
+    // `systemctl stop` send) or once a subsystem is beyond restarting.
+    #[cfg(unix)]
+    let mut terminate = match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+        Ok(terminate) => Some(terminate),
+        Err(e) => {
+            warn!("Can't shut down on SIGTERM: {}", e);
+            None
+        }
+    };
+    let terminated = async {
+        #[cfg(unix)]
+        if let Some(terminate) = terminate.as_mut() {
+            terminate.recv().await;
+            return;
+        }
+        std::future::pending::<()>().await
+    };
+    let runtime_stopped = async {
+        if runtime_handles.is_empty() {
+            return std::future::pending().await;
         }
+        select_all(runtime_handles.iter_mut()).await.0
+    };
+    let failure = tokio::select! {
+        signal = signal::ctrl_c() => {
+            match signal {
+                Ok(()) => info!("Received Ctrl+C, shutting down..."),
+                Err(err) => error!("Unable to listen for shutdown signal: {}", err),
+            }
+            None
+        }
+        () = terminated => {
+            info!("Received SIGTERM, shutting down...");
+            None
+        }
+        stopped = &mut dns_handle => Some(stopped),
+        stopped = &mut gossip_handle => Some(stopped),
+        stopped = runtime_stopped => Some(stopped),
+    };
+    let failure = failure.map(|stopped| stopped.unwrap_or_else(anyhow::Error::from));
+    if let Some(e) = &failure {
+        error!("{:#}; shutting down", e);
     }
+    systemd::notify("STOPPING=1");
 
-    // Abort tasks
-    if let Some(handle) = runtime_handle {
+    // Stop announcing, then tell peers our entries are gone instead of
+    // leaving them to serve dead addresses until leases lapse, or forever.
+    for handle in runtime_handles {
         handle.abort();
     }
     registry_local_handle.abort();
+    let (withdrawn_tx, withdrawn) = oneshot::channel();
+    if withdraw_tx.send(withdrawn_tx).await.is_ok()
+        && tokio::time::timeout(WITHDRAW_TIMEOUT, withdrawn)
+            .await
+            .is_err()
+    {
+        warn!("Gave up withdrawing our entries from peers");
+    }
+
+    // Abort tasks
     registry_remote_handle.abort();
+    forward_handle.abort();
     gossip_handle.abort();
     dns_handle.abort();
+    if let Some(handle) = mdns_handle {
+        handle.abort();
+    }
+    if let Some(handle) = admin_handle {
+        handle.abort();
+    }
+    pins_handle.abort();
+    leases_handle.abort();
+    if let Some(handle) = sighup_handle {
+        handle.abort();
+    }
+    for handle in systemd_handles {
+        handle.abort();
+    }
+    for handle in webhook_handles {
+        handle.abort();
+    }
+    // It writes out whenever it has caught up, so nothing is lost here.
+    if let Some(handle) = audit_handle {
+        handle.abort();
+    }
+    if let Some(handle) = control_handle {
+        handle.abort();
+    }
+    if let Some(handle) = handover_handle {
+        handle.abort();
+    }
+    if let Some(handle) = export_handle {
+        handle.abort();
+    }
+    if let Some(handle) = hosts_handle {
+        handle.abort();
+    }
+    if let Some(handle) = snapshot_handle {
+        handle.abort();
+    }
+
+    if let Some(path) = &snapshot_path {
+        if let Err(e) = persist::snapshot(path, &state).await {
+            error!("Failed to save state snapshot: {:#}", e);
+        }
+    }
 
     info!("Shutdown complete.");
-    Ok(())
+    match failure {
+        // Exits non-zero, so the container or unit is restarted.
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
 }
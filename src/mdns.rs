@@ -0,0 +1,391 @@
+//! Multicast DNS responder.
+//!
+//! Answers `<name>.<suffix>` queries (RFC 6762) on the LAN for names in the
+//! state map, so machines can resolve containers without pointing their
+//! resolver at glued.  Only names present in the map are answered and no
+//! other name is ever claimed, so the responder coexists with avahi or
+//! systemd-resolved on the same host.  If another responder answers one of
+//! our names with a different address, we defer to it.
+//!
+//! The map is polled for changes: new or moved entries are announced, and
+//! removed ones are withdrawn with a goodbye (TTL 0) record.
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use anyhow::Context;
+use hickory_server::proto::op::{Message, MessageType, OpCode};
+use hickory_server::proto::rr::rdata::{A, AAAA};
+use hickory_server::proto::rr::{Name, RData, Record, RecordType};
+use log::{debug, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+use crate::types::{SharedState, StateMap};
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+/// TTL of answers and announcements; RFC 6762 recommends 120s for host records.
+const RECORD_TTL: u32 = 120;
+/// Answers to legacy unicast queries must not be cached for long.
+const LEGACY_TTL: u32 = 10;
+/// Records per announcement packet, keeping packets well under the MTU.
+const ANNOUNCE_CHUNK: usize = 16;
+/// How often the state map is checked for changes to announce.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Serves mDNS for `<name>.<suffix>` until the task is aborted.
+pub async fn run_mdns(suffix: &str, state: SharedState) -> anyhow::Result<()> {
+    let socket = bind_multicast().context("Failed to bind mDNS socket on port 5353")?;
+    let mut responder = Responder::new(suffix)?;
+    info!("mDNS responder answering for *.{}", responder.suffix);
+
+    let group = SocketAddr::V4(SocketAddrV4::new(MDNS_GROUP, MDNS_PORT));
+    let mut buf = vec![0u8; 9000];
+    let mut ticker = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let (len, src) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        warn!("mDNS receive failed: {}", e);
+                        continue;
+                    }
+                };
+                let Ok(message) = Message::from_vec(&buf[..len]) else {
+                    continue;
+                };
+                let map = state.read().await;
+                let reply = responder.handle(&message, src.port() != MDNS_PORT, &map);
+                drop(map);
+                if let Some(Reply { message, unicast }) = reply {
+                    let dest = if unicast { src } else { group };
+                    send(&socket, &message, dest).await;
+                }
+            }
+            _ = ticker.tick() => {
+                let map = state.read().await;
+                let announcements = responder.changes(&map);
+                drop(map);
+                for message in announcements {
+                    send(&socket, &message, group).await;
+                }
+            }
+        }
+    }
+}
+
+async fn send(socket: &UdpSocket, message: &Message, dest: SocketAddr) {
+    match message.to_vec() {
+        Ok(bytes) => {
+            if let Err(e) = socket.send_to(&bytes, dest).await {
+                warn!("Failed to send mDNS packet to {}: {}", dest, e);
+            }
+        }
+        Err(e) => warn!("Failed to encode mDNS packet: {}", e),
+    }
+}
+
+/// Binds port 5353 shared with any other responder on the host and joins
+/// the IPv4 mDNS group.
+fn bind_multicast() -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// A response and whether it goes back to the querier rather than the group.
+struct Reply {
+    message: Message,
+    unicast: bool,
+}
+
+struct Responder {
+    suffix: Name,
+    /// Name → IP as last announced.
+    advertised: HashMap<String, IpAddr>,
+    /// Names another responder answers with a different address.
+    conflicts: HashSet<String>,
+}
+
+impl Responder {
+    fn new(suffix: &str) -> anyhow::Result<Self> {
+        let mut suffix = Name::from_ascii(suffix.trim_matches('.'))
+            .with_context(|| format!("Invalid mdns_suffix '{}'", suffix))?
+            .to_lowercase();
+        suffix.set_fqdn(true);
+        Ok(Self {
+            suffix,
+            advertised: HashMap::new(),
+            conflicts: HashSet::new(),
+        })
+    }
+
+    /// The registry name `name` refers to, if it is `<label>.<suffix>`.
+    fn label_of(&self, name: &Name) -> Option<String> {
+        if name.num_labels() != self.suffix.num_labels() + 1 || !self.suffix.zone_of(name) {
+            return None;
+        }
+        let label = name.iter().next()?;
+        Some(String::from_utf8_lossy(label).to_lowercase())
+    }
+
+    fn name_for(&self, label: &str) -> Option<Name> {
+        Name::from_ascii(label)
+            .ok()?
+            .append_domain(&self.suffix)
+            .ok()
+    }
+
+    /// Answers a query for names we hold, or notes a conflicting response.
+    fn handle(&mut self, message: &Message, legacy: bool, map: &StateMap) -> Option<Reply> {
+        if message.op_code() != OpCode::Query {
+            return None;
+        }
+        if message.message_type() == MessageType::Response {
+            self.note_conflicts(message);
+            return None;
+        }
+
+        let ttl = if legacy { LEGACY_TTL } else { RECORD_TTL };
+        let mut answers = Vec::new();
+        for query in message.queries() {
+            let Some(label) = self.label_of(query.name()) else {
+                continue;
+            };
+            if self.conflicts.contains(&label) {
+                continue;
+            }
+            let Some(ip) = map.get(&label).map(|e| e.ip) else {
+                continue;
+            };
+            if let Some(mut record) = record_for(query.name().clone(), ip, ttl, query.query_type())
+            {
+                record.set_mdns_cache_flush(!legacy);
+                answers.push(record);
+            }
+        }
+        if answers.is_empty() {
+            return None;
+        }
+
+        let mut response = Message::new();
+        response
+            .set_message_type(MessageType::Response)
+            .set_op_code(OpCode::Query)
+            .set_authoritative(true)
+            .add_answers(answers);
+        if legacy {
+            // RFC 6762 §6.7: echo the id and question for one-shot resolvers.
+            response
+                .set_id(message.id())
+                .add_queries(message.queries().iter().cloned());
+        }
+        let unicast = legacy || message.queries().iter().all(|q| q.mdns_unicast_response());
+        Some(Reply {
+            message: response,
+            unicast,
+        })
+    }
+
+    fn note_conflicts(&mut self, message: &Message) {
+        for record in message.answers() {
+            let Some(label) = self.label_of(record.name()) else {
+                continue;
+            };
+            let Some(ours) = self.advertised.get(&label) else {
+                continue;
+            };
+            let theirs = match record.data() {
+                Some(RData::A(a)) => IpAddr::V4(a.0),
+                Some(RData::AAAA(aaaa)) => IpAddr::V6(aaaa.0),
+                _ => continue,
+            };
+            if theirs != *ours && record.ttl() > 0 && self.conflicts.insert(label.clone()) {
+                warn!(
+                    "mDNS: {} is also answered with {} by another responder; no longer answering for it",
+                    record.name(),
+                    theirs
+                );
+            }
+        }
+    }
+
+    /// Announcements for entries added or changed since the last call, and
+    /// goodbyes for removed ones.
+    fn changes(&mut self, map: &StateMap) -> Vec<Message> {
+        let current: HashMap<String, IpAddr> = map
+            .iter()
+            // Only single labels; hosts file names may have several.
+            .filter(|(name, _)| !name.contains('.'))
+            .map(|(name, entry)| (name.clone(), entry.ip))
+            .collect();
+
+        let mut records = Vec::new();
+        for (label, ip) in &self.advertised {
+            if !current.contains_key(label) {
+                if let Some(name) = self.name_for(label) {
+                    debug!("mDNS: withdrawing {}", name);
+                    records.extend(record_for(name, *ip, 0, RecordType::ANY));
+                }
+            }
+        }
+        for (label, ip) in &current {
+            if self.advertised.get(label) == Some(ip) || self.conflicts.contains(label) {
+                continue;
+            }
+            if let Some(name) = self.name_for(label) {
+                debug!("mDNS: announcing {} -> {}", name, ip);
+                records.extend(record_for(name, *ip, RECORD_TTL, RecordType::ANY));
+            }
+        }
+        self.conflicts.retain(|label| current.contains_key(label));
+        self.advertised = current;
+
+        records
+            .chunks(ANNOUNCE_CHUNK)
+            .map(|chunk| {
+                let mut message = Message::new();
+                message
+                    .set_message_type(MessageType::Response)
+                    .set_op_code(OpCode::Query)
+                    .set_authoritative(true);
+                for record in chunk {
+                    let mut record = record.clone();
+                    record.set_mdns_cache_flush(true);
+                    message.add_answer(record);
+                }
+                message
+            })
+            .collect()
+    }
+}
+
+/// The address record for `ip` if it answers a `qtype` query.
+fn record_for(name: Name, ip: IpAddr, ttl: u32, qtype: RecordType) -> Option<Record> {
+    let rdata = match (ip, qtype) {
+        (IpAddr::V4(v4), RecordType::A | RecordType::ANY) => RData::A(A(v4)),
+        (IpAddr::V6(v6), RecordType::AAAA | RecordType::ANY) => RData::AAAA(AAAA(v6)),
+        _ => return None,
+    };
+    Some(Record::from_rdata(name, ttl, rdata))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Entry;
+    use hickory_server::proto::op::Query;
+
+    fn map(entries: &[(&str, &str)]) -> StateMap {
+        entries
+            .iter()
+            .map(|(name, ip)| (name.to_string(), Entry::new(ip.parse().unwrap())))
+            .collect()
+    }
+
+    fn query(name: &str, qtype: RecordType) -> Message {
+        let mut message = Message::new();
+        message.add_query(Query::query(Name::from_ascii(name).unwrap(), qtype));
+        message
+    }
+
+    #[test]
+    fn answers_only_names_in_the_map() {
+        let mut responder = Responder::new("local").unwrap();
+        let map = map(&[("web-1", "10.0.0.2")]);
+
+        let reply = responder
+            .handle(&query("Web-1.local.", RecordType::A), false, &map)
+            .unwrap();
+        assert!(!reply.unicast);
+        let answer = &reply.message.answers()[0];
+        assert_eq!(
+            answer.data(),
+            Some(&RData::A(A("10.0.0.2".parse().unwrap())))
+        );
+        assert!(answer.mdns_cache_flush());
+
+        for (name, qtype) in [
+            ("web-2.local.", RecordType::A),
+            ("web-1.local.", RecordType::AAAA),
+            ("web-1.other.", RecordType::A),
+            ("printer.web-1.local.", RecordType::A),
+        ] {
+            assert!(responder.handle(&query(name, qtype), false, &map).is_none());
+        }
+    }
+
+    #[test]
+    fn legacy_unicast_echoes_the_question() {
+        let mut responder = Responder::new("local").unwrap();
+        let mut message = query("web-1.local.", RecordType::ANY);
+        message.set_id(77);
+        let reply = responder
+            .handle(&message, true, &map(&[("web-1", "fd00::5")]))
+            .unwrap();
+        assert!(reply.unicast);
+        assert_eq!(reply.message.id(), 77);
+        assert_eq!(reply.message.queries().len(), 1);
+        assert_eq!(reply.message.answers()[0].ttl(), LEGACY_TTL);
+    }
+
+    #[test]
+    fn announces_changes_and_withdraws_removals() {
+        let mut responder = Responder::new("lan").unwrap();
+        let ttls = |messages: Vec<Message>| -> Vec<(String, u32)> {
+            let mut out: Vec<_> = messages
+                .iter()
+                .flat_map(|m| m.answers())
+                .map(|r| (r.name().to_string(), r.ttl()))
+                .collect();
+            out.sort();
+            out
+        };
+
+        let first = responder.changes(&map(&[("a", "10.0.0.1"), ("b", "10.0.0.2")]));
+        assert_eq!(
+            ttls(first),
+            [("a.lan.".into(), RECORD_TTL), ("b.lan.".into(), RECORD_TTL)]
+        );
+        assert!(responder
+            .changes(&map(&[("a", "10.0.0.1"), ("b", "10.0.0.2")]))
+            .is_empty());
+
+        let next = responder.changes(&map(&[("a", "10.0.0.9")]));
+        assert_eq!(
+            ttls(next),
+            [("a.lan.".into(), RECORD_TTL), ("b.lan.".into(), 0)]
+        );
+    }
+
+    #[test]
+    fn defers_to_other_responders() {
+        let mut responder = Responder::new("local").unwrap();
+        let map = map(&[("nas", "10.0.0.2")]);
+        responder.changes(&map);
+
+        let mut theirs = Message::new();
+        theirs.set_message_type(MessageType::Response).add_answer(
+            record_for(
+                Name::from_ascii("nas.local.").unwrap(),
+                "192.168.1.10".parse().unwrap(),
+                RECORD_TTL,
+                RecordType::A,
+            )
+            .unwrap(),
+        );
+        assert!(responder.handle(&theirs, false, &map).is_none());
+        assert!(responder
+            .handle(&query("nas.local.", RecordType::A), false, &map)
+            .is_none());
+    }
+}
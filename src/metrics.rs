@@ -0,0 +1,409 @@
+//! Process-wide counters and gauges.
+//!
+//! Both are plain atomics so any subsystem can bump them without
+//! threading a handle through; readers take a relaxed snapshot.  The age
+//! of the stalest entry is read off the state map at each scrape instead.
+//!
+//! DNS query latency is kept as a histogram per [`QueryOutcome`], but only
+//! once [`enable`] was called: the admin API does so when it serves
+//! `/metrics`, and without it queries aren't timed at all.
+
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::types::{Entry, Source, StateMap};
+
+/// Upper bounds of the latency buckets, in microseconds: 100µs to 5s.
+const LATENCY_BUCKETS: [u64; 15] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000, 2_500_000, 5_000_000,
+];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Starts timing DNS queries.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Whether DNS queries are timed.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// How a DNS query was answered, for its latency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryOutcome {
+    /// Answered from the state map without error, possibly with no records
+    /// of the type.
+    LocalHit,
+    /// NXDOMAIN for a name of ours.
+    LocalNxdomain,
+    /// Failed without forwarding: SERVFAIL during the warm-up, FORMERR for
+    /// a malformed query, and the like.
+    LocalFail,
+    ForwardedOk,
+    /// NXDOMAIN for a forwarded name, from the upstream or the negative
+    /// cache, or SERVFAIL: the upstream failed or timed out, or too many
+    /// lookups were in flight.
+    ForwardedFail,
+    Refused,
+}
+
+impl QueryOutcome {
+    const ALL: [QueryOutcome; 6] = [
+        QueryOutcome::LocalHit,
+        QueryOutcome::LocalNxdomain,
+        QueryOutcome::LocalFail,
+        QueryOutcome::ForwardedOk,
+        QueryOutcome::ForwardedFail,
+        QueryOutcome::Refused,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            QueryOutcome::LocalHit => "local-hit",
+            QueryOutcome::LocalNxdomain => "local-nxdomain",
+            QueryOutcome::LocalFail => "local-fail",
+            QueryOutcome::ForwardedOk => "forwarded-ok",
+            QueryOutcome::ForwardedFail => "forwarded-fail",
+            QueryOutcome::Refused => "refused",
+        }
+    }
+}
+
+/// Latencies counted into [`LATENCY_BUCKETS`].
+pub struct Histogram {
+    /// Observations per bucket, not cumulative; the last is above every
+    /// bound.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len() + 1],
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        let bucket = LATENCY_BUCKETS.partition_point(|&bound| bound < micros);
+        inc(&self.buckets[bucket]);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    /// Observations so far.
+    pub fn count(&self) -> u64 {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Appends the histogram's series in the Prometheus text format.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = *bound as f64 / 1e6;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, le, cumulative
+            );
+        }
+        cumulative += self.buckets[LATENCY_BUCKETS.len()].load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name, labels, cumulative
+        );
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, cumulative);
+    }
+}
+
+pub struct Metrics {
+    /// Gossip payloads dropped because they failed to verify or decrypt.
+    pub gossip_rejected: AtomicU64,
+    /// Gossip messages dropped as redeliveries or our own echoes.
+    pub gossip_duplicates: AtomicU64,
+    /// Gossip messages dropped as repeating a recent message's nonce.
+    pub gossip_replays: AtomicU64,
+    /// Gossip messages dropped as stamped outside `gossip_max_skew_secs`.
+    pub gossip_skewed: AtomicU64,
+    /// Gossip messages dropped unread as larger than `max_payload_bytes`.
+    pub gossip_oversized: AtomicU64,
+    /// Gossip messages dropped unread from peers ignored for delivering
+    /// malformed ones.
+    pub gossip_ignored: AtomicU64,
+    /// Digests from peers that differed from this node's.
+    pub gossip_digest_mismatches: AtomicU64,
+    /// Incoming peers refused by `allowed_peers`/`denied_peers`.
+    pub peers_rejected: AtomicU64,
+    /// Local updates that found the update channel full and had to wait.
+    pub updates_delayed: AtomicU64,
+    /// Updates dropped as beyond the state map's limits.
+    pub updates_rejected: AtomicU64,
+    /// Container entries set aside as another node publishes the name.
+    pub name_conflicts: AtomicU64,
+    /// DNS queries refused by `dns_allow`/`dns_deny`.
+    pub dns_refused: AtomicU64,
+    /// Queries for non-local names refused by `forward_allow`.
+    pub dns_forward_refused: AtomicU64,
+    /// Zone transfers sent.
+    pub dns_transfers: AtomicU64,
+    /// Zone transfers refused by `axfr_allow`, or asked for over UDP or of
+    /// another zone.
+    pub dns_transfers_refused: AtomicU64,
+    /// UDP responses dropped or truncated by response rate limiting.
+    pub dns_rate_limited: AtomicU64,
+    /// Forwarded lookups abandoned after `dns_forward_timeout_ms`.
+    pub dns_forward_timeouts: AtomicU64,
+    /// Queries failed because `dns_forward_max_inflight` lookups were
+    /// running and `dns_forward_max_queued` queries waiting.
+    pub dns_forward_overloaded: AtomicU64,
+    /// Queries refused by `dns_forward_rate`.
+    pub dns_forward_rate_limited: AtomicU64,
+    /// Forwarded names answered from the negative cache, and looked up.
+    pub dns_negative_cache_hits: AtomicU64,
+    pub dns_negative_cache_misses: AtomicU64,
+    /// Misses answered SERVFAIL while warming up after startup.
+    pub dns_warmup_servfail: AtomicU64,
+    /// Health probes of this node's containers that failed.
+    pub health_probes_failed: AtomicU64,
+    /// Containers withdrawn, and published again, by their health probes.
+    pub health_withdrawn: AtomicU64,
+    pub health_reinstated: AtomicU64,
+    /// Webhook events dropped because their queue was full.
+    pub webhook_dropped: AtomicU64,
+    /// Webhook events given up on after `webhook_max_retries`.
+    pub webhook_failed: AtomicU64,
+    /// Audit records not written: the log fell behind or the file failed.
+    pub audit_dropped: AtomicU64,
+    /// Queries waiting for a forwarded lookup slot right now.
+    pub dns_forward_queued: AtomicU64,
+    /// NXDOMAIN answers in the negative cache, expired ones included.
+    pub dns_negative_cache_entries: AtomicU64,
+    /// Time from receiving a DNS query to sending its response, by outcome.
+    dns_query_seconds: [Histogram; QueryOutcome::ALL.len()],
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            gossip_rejected: AtomicU64::new(0),
+            gossip_duplicates: AtomicU64::new(0),
+            gossip_replays: AtomicU64::new(0),
+            gossip_skewed: AtomicU64::new(0),
+            gossip_oversized: AtomicU64::new(0),
+            gossip_ignored: AtomicU64::new(0),
+            gossip_digest_mismatches: AtomicU64::new(0),
+            peers_rejected: AtomicU64::new(0),
+            updates_delayed: AtomicU64::new(0),
+            updates_rejected: AtomicU64::new(0),
+            name_conflicts: AtomicU64::new(0),
+            dns_refused: AtomicU64::new(0),
+            dns_forward_refused: AtomicU64::new(0),
+            dns_transfers: AtomicU64::new(0),
+            dns_transfers_refused: AtomicU64::new(0),
+            dns_rate_limited: AtomicU64::new(0),
+            dns_forward_timeouts: AtomicU64::new(0),
+            dns_forward_overloaded: AtomicU64::new(0),
+            dns_forward_rate_limited: AtomicU64::new(0),
+            dns_negative_cache_hits: AtomicU64::new(0),
+            dns_negative_cache_misses: AtomicU64::new(0),
+            dns_warmup_servfail: AtomicU64::new(0),
+            health_probes_failed: AtomicU64::new(0),
+            health_withdrawn: AtomicU64::new(0),
+            health_reinstated: AtomicU64::new(0),
+            webhook_dropped: AtomicU64::new(0),
+            webhook_failed: AtomicU64::new(0),
+            audit_dropped: AtomicU64::new(0),
+            dns_forward_queued: AtomicU64::new(0),
+            dns_negative_cache_entries: AtomicU64::new(0),
+            dns_query_seconds: [const { Histogram::new() }; QueryOutcome::ALL.len()],
+        }
+    }
+
+    /// The latency histogram of queries answered with `outcome`.
+    pub fn dns_query_seconds(&self, outcome: QueryOutcome) -> &Histogram {
+        &self.dns_query_seconds[outcome as usize]
+    }
+
+    /// Every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let counters = [
+            ("gossip_rejected", &self.gossip_rejected),
+            ("gossip_duplicates", &self.gossip_duplicates),
+            ("gossip_replays", &self.gossip_replays),
+            ("gossip_skewed", &self.gossip_skewed),
+            ("gossip_oversized", &self.gossip_oversized),
+            ("gossip_ignored", &self.gossip_ignored),
+            ("gossip_digest_mismatches", &self.gossip_digest_mismatches),
+            ("peers_rejected", &self.peers_rejected),
+            ("updates_delayed", &self.updates_delayed),
+            ("updates_rejected", &self.updates_rejected),
+            ("name_conflicts", &self.name_conflicts),
+            ("dns_refused", &self.dns_refused),
+            ("dns_forward_refused", &self.dns_forward_refused),
+            ("dns_transfers", &self.dns_transfers),
+            ("dns_transfers_refused", &self.dns_transfers_refused),
+            ("dns_rate_limited", &self.dns_rate_limited),
+            ("dns_forward_timeouts", &self.dns_forward_timeouts),
+            ("dns_forward_overloaded", &self.dns_forward_overloaded),
+            ("dns_forward_rate_limited", &self.dns_forward_rate_limited),
+            ("dns_negative_cache_hits", &self.dns_negative_cache_hits),
+            ("dns_negative_cache_misses", &self.dns_negative_cache_misses),
+            ("dns_warmup_servfail", &self.dns_warmup_servfail),
+            ("health_probes_failed", &self.health_probes_failed),
+            ("health_withdrawn", &self.health_withdrawn),
+            ("health_reinstated", &self.health_reinstated),
+            ("webhook_dropped", &self.webhook_dropped),
+            ("webhook_failed", &self.webhook_failed),
+            ("audit_dropped", &self.audit_dropped),
+        ];
+        let mut out = String::new();
+        for (name, counter) in counters {
+            let _ = writeln!(out, "# TYPE glued_{}_total counter", name);
+            let _ = writeln!(
+                out,
+                "glued_{}_total {}",
+                name,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+        let gauges = [
+            ("dns_forward_queued", &self.dns_forward_queued),
+            (
+                "dns_negative_cache_entries",
+                &self.dns_negative_cache_entries,
+            ),
+        ];
+        for (name, gauge) in gauges {
+            let _ = writeln!(out, "# TYPE glued_{} gauge", name);
+            let _ = writeln!(out, "glued_{} {}", name, gauge.load(Ordering::Relaxed));
+        }
+        let name = "glued_dns_query_seconds";
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for outcome in QueryOutcome::ALL {
+            let labels = format!("outcome=\"{}\"", outcome.label());
+            self.dns_query_seconds(outcome)
+                .render(&mut out, name, &labels);
+        }
+        out
+    }
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+/// Gauges read off the state map as it is scraped: the seconds since the
+/// stalest entry published over gossip was last set or renewed, or zero
+/// when there is none.
+pub fn render_state(map: &StateMap, now: u64) -> String {
+    let oldest = map
+        .values()
+        .filter(|entry| entry.source == Source::Cluster)
+        .map(Entry::last_refreshed)
+        .min();
+    let age = oldest.map_or(0, |at| now.saturating_sub(at) / 1000);
+    let mut out = String::new();
+    let _ = writeln!(out, "# TYPE glued_entry_oldest_age_seconds gauge");
+    let _ = writeln!(out, "glued_entry_oldest_age_seconds {}", age);
+    out
+}
+
+/// Adds one to `counter`.
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gossip::apply_update;
+    use crate::registry::LocalSource;
+    use crate::types::{SharedState, Update};
+
+    #[tokio::test]
+    async fn the_oldest_entry_age_counts_lease_renewals() {
+        let state = SharedState::default();
+        let add = |name: &str| Update::Add {
+            name: name.into(),
+            ip: "10.0.0.2".parse().unwrap(),
+        };
+        apply_update(add("web-1"), LocalSource::Control, &state).await;
+        apply_update(add("web-2"), LocalSource::Control, &state).await;
+        let renew = Update::Lease {
+            name: "web-1".into(),
+            valid_for_secs: 600,
+        };
+        apply_update(renew, LocalSource::Control, &state).await;
+
+        let mut map = state.read().await.clone();
+        assert!(map["web-1"].refreshed_at.is_some());
+        assert_eq!(map["web-2"].refreshed_at, None);
+        map.get_mut("web-1").unwrap().updated_at = 1_000;
+        map.get_mut("web-1").unwrap().refreshed_at = Some(9_000);
+        map.get_mut("web-2").unwrap().updated_at = 5_000;
+        // Locally configured names aren't published, so never go stale.
+        map.insert(
+            "nas".into(),
+            Entry {
+                source: Source::Static,
+                updated_at: 0,
+                ..Entry::new("192.168.1.5".parse().unwrap())
+            },
+        );
+        let gauge = |map: &StateMap, now| render_state(map, now).lines().nth(1).map(str::to_owned);
+        assert_eq!(
+            gauge(&map, 10_000).as_deref(),
+            Some("glued_entry_oldest_age_seconds 5")
+        );
+        // A clock stepped back doesn't make ages negative.
+        assert_eq!(
+            gauge(&map, 0).as_deref(),
+            Some("glued_entry_oldest_age_seconds 0")
+        );
+        assert_eq!(
+            gauge(&StateMap::new(), 10_000).as_deref(),
+            Some("glued_entry_oldest_age_seconds 0")
+        );
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new();
+        for micros in [50, 100, 101, 7_000, 10_000_000] {
+            histogram.observe(Duration::from_micros(micros));
+        }
+        assert_eq!(histogram.count(), 5);
+        let mut out = String::new();
+        histogram.render(&mut out, "latency", "outcome=\"refused\"");
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines[0],
+            "latency_bucket{outcome=\"refused\",le=\"0.0001\"} 2"
+        );
+        assert_eq!(
+            lines[1],
+            "latency_bucket{outcome=\"refused\",le=\"0.00025\"} 3"
+        );
+        assert_eq!(
+            lines[6],
+            "latency_bucket{outcome=\"refused\",le=\"0.01\"} 4"
+        );
+        assert_eq!(lines[14], "latency_bucket{outcome=\"refused\",le=\"5\"} 4");
+        assert_eq!(
+            lines[15],
+            "latency_bucket{outcome=\"refused\",le=\"+Inf\"} 5"
+        );
+        assert_eq!(lines[16], "latency_sum{outcome=\"refused\"} 10.007251");
+        assert_eq!(lines[17], "latency_count{outcome=\"refused\"} 5");
+    }
+}
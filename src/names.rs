@@ -0,0 +1,109 @@
+//! Mapping container names onto DNS labels.
+//!
+//! Docker allows names such as `My_Stack.web.1` that are not valid single
+//! DNS labels.  Every published name goes through a [`NamePolicy`]; the
+//! mapping is deterministic so that the `Remove` for a container always
+//! names the same entry as its `Add`.
+
+use serde::{Deserialize, Serialize};
+
+/// Maximum length of a single DNS label.
+pub const MAX_LABEL_LEN: usize = 63;
+
+/// Maximum length of a whole DNS name, in its dotted form.
+pub const MAX_NAME_LEN: usize = 253;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NamePolicy {
+    /// Lowercase, replace separator characters with `-` and truncate.
+    #[default]
+    Sanitize,
+    /// Publish only names that already are valid DNS labels.
+    Strict,
+    /// Publish names exactly as Docker reports them.
+    AsIs,
+}
+
+impl NamePolicy {
+    /// Returns the name to publish for `name`, or `None` if it can't be
+    /// turned into a valid label under this policy.  `replace` lists the
+    /// characters mapped to `-` when sanitizing.
+    pub fn apply(self, name: &str, replace: &str) -> Option<String> {
+        match self {
+            NamePolicy::AsIs => Some(name.to_string()),
+            NamePolicy::Strict => is_valid_label(name).then(|| name.to_string()),
+            NamePolicy::Sanitize => {
+                let mut label: String = name
+                    .chars()
+                    .map(|c| {
+                        if replace.contains(c) {
+                            '-'
+                        } else {
+                            c.to_ascii_lowercase()
+                        }
+                    })
+                    .collect();
+                label.truncate(MAX_LABEL_LEN);
+                let label = label.trim_matches('-');
+                is_valid_label(label).then(|| label.to_string())
+            }
+        }
+    }
+}
+
+/// Whether `label` is a valid DNS label: 1-63 letters, digits or hyphens,
+/// not starting or ending with a hyphen.
+pub fn is_valid_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.len() <= MAX_LABEL_LEN
+        && label
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+}
+
+/// The state map key under which `name`'s wildcard entry is published.
+/// A query for any name below `name` is answered from it.
+pub fn wildcard_key(name: &str) -> String {
+    format!("*.{}", name)
+}
+
+/// The state map key for `name`: lowercase, without a trailing dot.
+/// DNS names are case-insensitive, so lookups and inserts both go through this.
+pub fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_maps_separators_and_case() {
+        let p = NamePolicy::Sanitize;
+        assert_eq!(
+            p.apply("My_Stack.web.1", "_.").as_deref(),
+            Some("my-stack-web-1")
+        );
+        assert_eq!(p.apply("_web_", "_.").as_deref(), Some("web"));
+        assert_eq!(p.apply(&"a".repeat(80), "_.").map(|n| n.len()), Some(63));
+        assert_eq!(p.apply("web@1", "_."), None);
+        // Only the configured characters are replaced.
+        assert_eq!(p.apply("web.1", "_"), None);
+    }
+
+    #[test]
+    fn strict_and_as_is() {
+        assert_eq!(
+            NamePolicy::Strict.apply("web-1", "_.").as_deref(),
+            Some("web-1")
+        );
+        assert_eq!(NamePolicy::Strict.apply("web_1", "_."), None);
+        assert_eq!(
+            NamePolicy::AsIs.apply("web_1", "_.").as_deref(),
+            Some("web_1")
+        );
+    }
+}
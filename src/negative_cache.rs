@@ -0,0 +1,248 @@
+//! NXDOMAIN answers of forwarded lookups, kept for their negative TTL.
+//!
+//! A flood of queries for random names below a real domain
+//! (`x7f2.example.com`, `q9c1.example.com`, ...) would otherwise cost one
+//! upstream lookup each.  Names the upstream said don't exist are answered
+//! NXDOMAIN from here for the negative TTL it gave, at most
+//! `dns_negative_cache_max_ttl_secs`.  Answers without an SOA carry no
+//! negative TTL and aren't kept (RFC 2308); those from here carry the SOA
+//! their zone's last one came with, so downstream caches can tell how long
+//! to keep them too.
+//!
+//! Names are kept by the zone whose SOA came with them, in at most
+//! `dns_negative_cache_size` entries.  A zone may hold a quarter of them:
+//! once it has that many it forgets its oldest name, and a full cache
+//! forgets the oldest of its largest zone, so one flooded zone can't push
+//! out every other.  Entries past their TTL stay until pushed out, or
+//! replaced by a fresh answer for the name.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use hickory_server::proto::rr::{LowerName, Record};
+use tokio::time::Instant;
+
+use crate::config::Config;
+
+/// How many zones share the cache when all of them are full.
+const ZONE_SHARE: usize = 4;
+
+/// Names known not to exist, by zone.
+#[derive(Debug, Clone)]
+pub struct NegativeCache {
+    /// Entries kept at most; zero keeps none.
+    capacity: usize,
+    max_ttl: Duration,
+    zones: HashMap<LowerName, ZoneNames>,
+    len: usize,
+}
+
+#[derive(Debug, Clone)]
+struct ZoneNames {
+    /// The SOA the zone's last NXDOMAIN came with.
+    soa: Record,
+    expires: HashMap<LowerName, Instant>,
+    /// The names in `expires`, oldest first.
+    order: VecDeque<LowerName>,
+}
+
+impl NegativeCache {
+    pub fn new(capacity: usize, max_ttl: Duration) -> Self {
+        Self {
+            capacity,
+            max_ttl,
+            zones: HashMap::new(),
+            len: 0,
+        }
+    }
+
+    pub fn from_config(cfg: &Config) -> Self {
+        Self::new(
+            cfg.dns_negative_cache_size,
+            Duration::from_secs(cfg.dns_negative_cache_max_ttl_secs),
+        )
+    }
+
+    /// Entries held, expired ones included.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether `name` was answered NXDOMAIN within its negative TTL.
+    pub fn contains(&self, name: &LowerName, now: Instant) -> bool {
+        self.soa(name, now).is_some()
+    }
+
+    /// The SOA to answer `name` NXDOMAIN with, if it was within its
+    /// negative TTL, with the time left of it as its TTL.
+    pub fn soa(&self, name: &LowerName, now: Instant) -> Option<Record> {
+        let mut zone = name.clone();
+        loop {
+            if let Some(names) = self.zones.get(&zone) {
+                let left = names
+                    .expires
+                    .get(name)
+                    .map(|expires| expires.saturating_duration_since(now))
+                    .filter(|left| !left.is_zero());
+                if let Some(left) = left {
+                    let mut soa = names.soa.clone();
+                    // Rounded up: a zero TTL isn't to be cached at all.
+                    soa.set_ttl(left.as_secs_f64().ceil() as u32);
+                    return Some(soa);
+                }
+            }
+            if zone.is_root() {
+                return None;
+            }
+            zone = zone.base_name();
+        }
+    }
+
+    /// Keeps `name` as not existing, in the zone `soa` names, for the
+    /// SOA's TTL from `now`, capped at the maximum.
+    pub fn insert(&mut self, name: LowerName, soa: Record, now: Instant) {
+        let zone = LowerName::new(soa.name());
+        let ttl = Duration::from_secs(soa.ttl().into()).min(self.max_ttl);
+        if self.capacity == 0 || ttl.is_zero() || !zone.zone_of(&name) {
+            return;
+        }
+        let expires = now + ttl;
+        if let Some(names) = self.zones.get_mut(&zone) {
+            names.soa = soa.clone();
+            if let Some(known) = names.expires.get_mut(&name) {
+                *known = expires;
+                return;
+            }
+        }
+        let per_zone = (self.capacity / ZONE_SHARE).max(1);
+        if self.zones.get(&zone).map_or(0, |names| names.order.len()) >= per_zone {
+            self.evict(&zone);
+        } else if self.len >= self.capacity {
+            let largest = self
+                .zones
+                .iter()
+                .max_by_key(|(_, names)| names.order.len())
+                .map(|(zone, _)| zone.clone());
+            if let Some(largest) = largest {
+                self.evict(&largest);
+            }
+        }
+        let names = self.zones.entry(zone).or_insert_with(|| ZoneNames {
+            soa,
+            expires: HashMap::new(),
+            order: VecDeque::new(),
+        });
+        names.expires.insert(name.clone(), expires);
+        names.order.push_back(name);
+        self.len += 1;
+    }
+
+    /// Forgets the oldest name of `zone`.
+    fn evict(&mut self, zone: &LowerName) {
+        let Some(names) = self.zones.get_mut(zone) else {
+            return;
+        };
+        if let Some(oldest) = names.order.pop_front() {
+            names.expires.remove(&oldest);
+            self.len -= 1;
+        }
+        if names.order.is_empty() {
+            self.zones.remove(zone);
+        }
+    }
+
+    /// Forgets everything, as when the upstreams change.
+    pub fn clear(&mut self) {
+        self.zones.clear();
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_server::proto::rr::rdata::SOA;
+    use hickory_server::proto::rr::{Name, RData};
+
+    fn name(s: &str) -> LowerName {
+        LowerName::new(&Name::from_ascii(s).unwrap())
+    }
+
+    /// The SOA of `zone`, with `ttl` as its negative TTL.
+    fn soa(zone: &LowerName, ttl: u32) -> Record {
+        let zone = Name::from(zone);
+        let soa = SOA::new(zone.clone(), zone.clone(), 1, 3600, 600, 86400, ttl);
+        Record::from_rdata(zone, ttl, RData::SOA(soa))
+    }
+
+    #[test]
+    fn names_are_kept_for_their_ttl() {
+        let mut cache = NegativeCache::new(16, Duration::from_secs(60));
+        let now = Instant::now();
+        let zone = name("example.com.");
+        cache.insert(name("a.example.com."), soa(&zone, 30), now);
+        // Capped at the maximum.
+        cache.insert(name("b.example.com."), soa(&zone, 3600), now);
+        // Not below the zone its SOA names, or not to be kept at all.
+        cache.insert(name("c.example.org."), soa(&zone, 30), now);
+        cache.insert(name("d.example.com."), soa(&zone, 0), now);
+        assert_eq!(cache.len(), 2);
+
+        let later = now + Duration::from_secs(45);
+        assert!(cache.contains(&name("a.example.com."), now));
+        // Answered with the zone's SOA, for the time left.
+        let cached = cache
+            .soa(&name("b.example.com."), now + Duration::from_millis(500))
+            .unwrap();
+        assert_eq!(cached.name(), &Name::from(&zone));
+        assert_eq!(cached.ttl(), 60);
+        assert_eq!(cache.soa(&name("b.example.com."), later).unwrap().ttl(), 15);
+        assert!(!cache.contains(&name("a.example.com."), later));
+        assert!(cache.contains(&name("b.example.com."), later));
+        assert!(!cache.contains(&name("b.example.com."), now + Duration::from_secs(61)));
+        assert!(!cache.contains(&name("example.com."), now));
+
+        // A fresh answer renews an expired entry in place.
+        cache.insert(name("a.example.com."), soa(&zone, 30), later);
+        assert!(cache.contains(&name("a.example.com."), later));
+        assert_eq!(cache.len(), 2);
+        cache.clear();
+        assert!(cache.is_empty());
+        assert!(!cache.contains(&name("b.example.com."), now));
+    }
+
+    #[test]
+    fn a_flooded_zone_cannot_push_out_the_others() {
+        let mut cache = NegativeCache::new(8, Duration::from_secs(60));
+        let now = Instant::now();
+        let quiet: Vec<LowerName> = (0..ZONE_SHARE)
+            .flat_map(|zone| (0..2).map(move |i| name(&format!("{}.zone{}.example.", i, zone))))
+            .collect();
+        for q in &quiet {
+            cache.insert(q.clone(), soa(&q.base_name(), 60), now);
+        }
+        assert_eq!(cache.len(), 8);
+        let flooded = name("victim.example.");
+        for i in 0..1000 {
+            let q = name(&format!("r{}.victim.example.", i));
+            cache.insert(q, soa(&flooded, 60), now);
+        }
+        assert_eq!(cache.len(), 8);
+        // The flood holds its quarter, the newest names of it.
+        assert!(cache.contains(&name("r999.victim.example."), now));
+        assert!(cache.contains(&name("r998.victim.example."), now));
+        assert!(!cache.contains(&name("r997.victim.example."), now));
+        // The other zones gave up only what it took.
+        let kept = quiet.iter().filter(|q| cache.contains(q, now)).count();
+        assert_eq!(kept, 6);
+
+        // Disabled, it keeps nothing.
+        let mut off = NegativeCache::new(0, Duration::from_secs(60));
+        off.insert(name("a.example.com."), soa(&name("example.com."), 60), now);
+        assert!(off.is_empty());
+    }
+}
@@ -0,0 +1,316 @@
+//! Separate name spaces for several overlay networks.
+//!
+//! Each `[[networks]]` entry is a network replicas watch, with the gossip
+//! topic its names travel on and, optionally, the subnets its clients
+//! query from:
+//!
+//! ```toml
+//! [[networks]]
+//! name = "net-a"
+//! topic = "<64 hex digits>"
+//! subnets = ["10.1.0.0/24"]
+//! ```
+//!
+//! A network's names are kept in the registry with its name as suffix,
+//! `web-1.net-a`, so they never collide with another network's or with the
+//! shared names: `network_name` containers, pins, static and hosts file
+//! records, which stay on `topic_id`.  On the network's topic they travel
+//! without the suffix; names in a network's namespace arriving on any other
+//! topic are dropped.
+//!
+//! DNS answers `web-1.net-a`, and `web-1.net-a.<local_domain>`, from the
+//! network's namespace.  Clients in the network's `subnets` also get its
+//! entry for plain `web-1`, ahead of the shared one, and see no other
+//! network's names.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use log::debug;
+use tokio::sync::mpsc;
+
+use crate::acl::Cidr;
+use crate::config::Config;
+use crate::names::{is_valid_label, normalize};
+use crate::registry::LocalUpdate;
+use crate::runtime::ContainerRuntime;
+use crate::types::Update;
+
+/// A network with a namespace of its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Network {
+    /// The network's name, and its names' suffix.
+    pub name: String,
+    pub topic: [u8; 32],
+    /// Where the network's clients query from.
+    pub subnets: Vec<Cidr>,
+}
+
+/// The configured networks.
+#[derive(Debug, Clone, Default)]
+pub struct Networks(Vec<Network>);
+
+impl Networks {
+    /// Fails on a name that isn't a lowercase DNS label, a topic that isn't
+    /// 32 bytes of hex, or a name or topic used twice, `topic_id` included.
+    pub fn from_config(cfg: &Config) -> anyhow::Result<Self> {
+        let shared = parse_topic(&cfg.topic_id).context("Invalid topic_id")?;
+        let mut networks: Vec<Network> = Vec::new();
+        for network in &cfg.networks {
+            let name = network.name.as_str();
+            if !is_valid_label(name) || name != name.to_ascii_lowercase() {
+                anyhow::bail!("Network name '{}' isn't a lowercase DNS label", name);
+            }
+            if networks.iter().any(|other| other.name == name) {
+                anyhow::bail!("Network {} is configured twice", name);
+            }
+            let topic = parse_topic(&network.topic)
+                .with_context(|| format!("Invalid topic for network {}", name))?;
+            if topic == shared || networks.iter().any(|other| other.topic == topic) {
+                anyhow::bail!("Network {} needs a topic of its own", name);
+            }
+            networks.push(Network {
+                name: name.to_string(),
+                topic,
+                subnets: network.subnets.clone(),
+            });
+        }
+        Ok(Self(networks))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Network> {
+        self.0.iter()
+    }
+
+    /// The network whose namespace the state map key `key` is in, and the
+    /// name within it.
+    pub fn split<'a>(&self, key: &'a str) -> Option<(&Network, &'a str)> {
+        let (name, suffix) = key.rsplit_once('.')?;
+        let network = self.0.iter().find(|network| network.name == suffix)?;
+        Some((network, name))
+    }
+
+    /// The network whose subnets `client` is in.
+    pub fn of_client(&self, client: IpAddr) -> Option<&Network> {
+        self.0
+            .iter()
+            .find(|network| network.subnets.iter().any(|net| net.contains(client)))
+    }
+
+    /// Splits `update` by the topic it goes out on, `None` being
+    /// `topic_id`, with names in a network's namespace losing its suffix.
+    pub fn route(&self, update: Update) -> Vec<(Option<&Network>, Update)> {
+        let mut routed: Vec<(Option<&Network>, Vec<Update>)> = Vec::new();
+        for mut update in leaves(update) {
+            let mut network = None;
+            rename(&mut update, &mut |name| {
+                if let Some((within, base)) = self.split(name) {
+                    network = Some(within);
+                    *name = base.to_string();
+                }
+            });
+            match routed.iter_mut().find(|(topic, _)| *topic == network) {
+                Some((_, updates)) => updates.push(update),
+                None => routed.push((network, vec![update])),
+            }
+        }
+        routed
+            .into_iter()
+            .map(|(topic, updates)| (topic, Update::batch(updates)))
+            .collect()
+    }
+
+    /// `update` as received on the topic of `network`, or on `topic_id`
+    /// for `None`: a network's names get its suffix; names in a network's
+    /// namespace are dropped from `topic_id`.  `None` if nothing is left.
+    pub fn admit(&self, network: Option<&Network>, update: Update) -> Option<Update> {
+        if let Some(network) = network {
+            return Some(qualify(update, &network.name));
+        }
+        let kept: Vec<Update> = leaves(update)
+            .into_iter()
+            .filter(|update| {
+                let Some(name) = name_of(update) else {
+                    return true;
+                };
+                match self.split(&normalize(name)) {
+                    Some((network, _)) => {
+                        debug!(
+                            "Dropping {} from the shared topic: {} has a topic of its own",
+                            name, network.name
+                        );
+                        false
+                    }
+                    None => true,
+                }
+            })
+            .collect();
+        (!kept.is_empty()).then(|| Update::batch(kept))
+    }
+}
+
+/// Decodes a gossip topic: 32 bytes as hex.
+pub fn parse_topic(hex: &str) -> anyhow::Result<[u8; 32]> {
+    hex::decode(hex)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid topic ID length"))
+}
+
+/// `update` with every name put into `network`'s namespace.
+pub fn qualify(mut update: Update, network: &str) -> Update {
+    rename(&mut update, &mut |name| {
+        name.push('.');
+        name.push_str(network);
+    });
+    update
+}
+
+/// Calls `f` on every name `update` carries.
+fn rename(update: &mut Update, f: &mut impl FnMut(&mut String)) {
+    match update {
+        Update::Add { name, .. }
+        | Update::Remove { name }
+        | Update::Ports { name, .. }
+        | Update::Pin { name, .. }
+        | Update::Unpin { name }
+        | Update::Lease { name, .. }
+        | Update::ScopedIps { name, .. }
+        | Update::Txt { name, .. } => f(name),
+        Update::EvictNode { .. } => {}
+        Update::Batch(updates) => {
+            for update in updates {
+                rename(update, f);
+            }
+        }
+    }
+}
+
+/// The name a single update is about; `None` for a batch.
+pub(crate) fn name_of(update: &Update) -> Option<&str> {
+    match update {
+        Update::Add { name, .. }
+        | Update::Remove { name }
+        | Update::Ports { name, .. }
+        | Update::Pin { name, .. }
+        | Update::Unpin { name }
+        | Update::Lease { name, .. }
+        | Update::ScopedIps { name, .. }
+        | Update::Txt { name, .. } => Some(name),
+        Update::EvictNode { .. } | Update::Batch(_) => None,
+    }
+}
+
+/// The updates `update` consists of, in order, without batches.
+pub(crate) fn leaves(update: Update) -> Vec<Update> {
+    match update {
+        Update::Batch(updates) => updates.into_iter().flat_map(leaves).collect(),
+        update => vec![update],
+    }
+}
+
+/// A runtime watching one of `networks`, its containers going into the
+/// network's namespace.
+pub struct InNetwork {
+    pub network: String,
+    pub runtime: Arc<dyn ContainerRuntime + Send + Sync>,
+}
+
+#[async_trait]
+impl ContainerRuntime for InNetwork {
+    async fn monitor(&self, update_tx: mpsc::Sender<LocalUpdate>) -> anyhow::Result<()> {
+        let (tx, mut rx) = mpsc::channel(update_tx.max_capacity());
+        let forward = async {
+            while let Some((update, source)) = rx.recv().await {
+                let update = qualify(update, &self.network);
+                if update_tx.send((update, source)).await.is_err() {
+                    break;
+                }
+            }
+        };
+        // The monitor fails on its own once nothing forwards its updates.
+        let (monitored, ()) = tokio::join!(self.runtime.monitor(tx), forward);
+        monitored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NetworkConfig;
+
+    fn add(name: &str) -> Update {
+        Update::Add {
+            name: name.into(),
+            ip: "10.1.0.2".parse().unwrap(),
+        }
+    }
+
+    fn networks() -> Networks {
+        let network = |name: &str, byte: u8, subnet: &str| NetworkConfig {
+            name: name.into(),
+            topic: hex::encode([byte; 32]),
+            subnets: vec![subnet.parse().unwrap()],
+        };
+        Networks::from_config(&Config {
+            networks: vec![
+                network("net-a", 1, "10.1.0.0/16"),
+                network("net-b", 2, "10.2.0.0/16"),
+            ],
+            ..Config::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn names_travel_on_their_networks_topic_without_the_suffix() {
+        let networks = networks();
+        let update = Update::batch(vec![add("web-1.net-a"), add("db"), add("web-2.net-a")]);
+        let routed = networks.route(update);
+        assert_eq!(routed.len(), 2);
+        let (network, update) = &routed[0];
+        assert_eq!(network.unwrap().name, "net-a");
+        assert_eq!(*update, Update::Batch(vec![add("web-1"), add("web-2")]));
+        assert_eq!(routed[1], (None, add("db")));
+
+        // Received back, they land in the same namespace.
+        let net_a = networks.iter().next();
+        assert_eq!(
+            networks.admit(net_a, add("web-1")),
+            Some(add("web-1.net-a"))
+        );
+        // A network's names can't be slipped in on the shared topic.
+        let smuggled = Update::batch(vec![add("web-1.NET-B"), add("db")]);
+        assert_eq!(networks.admit(None, smuggled), Some(add("db")));
+        assert_eq!(networks.admit(None, add("web-1.net-b")), None);
+
+        assert_eq!(
+            networks
+                .of_client("10.2.3.4".parse().unwrap())
+                .unwrap()
+                .name,
+            "net-b"
+        );
+        assert!(networks.of_client("10.3.0.1".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn networks_need_a_label_and_a_topic_of_their_own() {
+        let with = |name: &str, topic: String| Config {
+            networks: vec![NetworkConfig {
+                name: name.into(),
+                topic,
+                subnets: Vec::new(),
+            }],
+            ..Config::default()
+        };
+        let topic = hex::encode([1; 32]);
+        assert!(Networks::from_config(&with("net-a", topic.clone())).is_ok());
+        assert!(Networks::from_config(&with("net_a", topic.clone())).is_err());
+        assert!(Networks::from_config(&with("Net-A", topic)).is_err());
+        assert!(Networks::from_config(&with("net-a", "abcd".into())).is_err());
+        let shared = Config::default().topic_id;
+        assert!(Networks::from_config(&with("net-a", shared)).is_err());
+    }
+}
@@ -0,0 +1,262 @@
+//! Gossip peers: which may participate, and what we know about each.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use iroh::endpoint::ConnectionType;
+use iroh::NodeId;
+
+use crate::config::Config;
+use crate::types::now_millis;
+
+/// Allow and deny lists checked before authenticating or dialling a peer.
+/// Pinning NodeIds means a leaked cluster secret alone isn't enough to join.
+#[derive(Debug, Clone, Default)]
+pub struct PeerPolicy {
+    /// When non-empty, only these peers may take part.
+    allowed: HashSet<NodeId>,
+    /// Never accepted or dialled, even if allowed.
+    denied: HashSet<NodeId>,
+}
+
+impl PeerPolicy {
+    pub fn new(
+        allowed: impl IntoIterator<Item = NodeId>,
+        denied: impl IntoIterator<Item = NodeId>,
+    ) -> Self {
+        Self {
+            allowed: allowed.into_iter().collect(),
+            denied: denied.into_iter().collect(),
+        }
+    }
+
+    pub fn from_config(cfg: &Config) -> Self {
+        Self::new(
+            cfg.allowed_peers.iter().copied(),
+            cfg.denied_peers.iter().copied(),
+        )
+    }
+
+    /// Why `peer` may not participate, if it may not.
+    pub fn rejects(&self, peer: &NodeId) -> Option<&'static str> {
+        if self.denied.contains(peer) {
+            Some("denied by denied_peers")
+        } else if !self.allowed.is_empty() && !self.allowed.contains(peer) {
+            Some("not in allowed_peers")
+        } else {
+            None
+        }
+    }
+}
+
+/// How we currently reach a peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PathKind {
+    Direct,
+    Relay,
+    Mixed,
+    #[default]
+    Unknown,
+}
+
+impl From<&ConnectionType> for PathKind {
+    fn from(conn: &ConnectionType) -> Self {
+        match conn {
+            ConnectionType::Direct(_) => PathKind::Direct,
+            ConnectionType::Relay(_) => PathKind::Relay,
+            ConnectionType::Mixed(..) => PathKind::Mixed,
+            ConnectionType::None => PathKind::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for PathKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PathKind::Direct => "direct",
+            PathKind::Relay => "relay",
+            PathKind::Mixed => "mixed",
+            PathKind::Unknown => "unknown",
+        })
+    }
+}
+
+/// What the gossip subsystem knows about one peer.
+#[derive(Debug, Clone, Default)]
+pub struct PeerInfo {
+    /// Currently a gossip neighbor on our topic.
+    pub neighbor: bool,
+    /// Completed the auth handshake with us, in either direction.
+    pub authenticated: bool,
+    pub path: PathKind,
+    /// Unix time in milliseconds we last heard from the peer.
+    pub last_seen: u64,
+    /// The `node_name` its messages carry, once one arrived.
+    pub name: Option<String>,
+    /// Unix time in milliseconds our outbound session with it was
+    /// authenticated; none while there is none.
+    pub session_since: Option<u64>,
+    /// Gossip messages it delivered that were oversized or didn't open or
+    /// decode.
+    pub malformed: u64,
+}
+
+/// Peers we have met, keyed by NodeId.
+#[derive(Debug, Default)]
+pub struct PeerTable {
+    peers: BTreeMap<NodeId, PeerInfo>,
+}
+
+impl PeerTable {
+    fn touch(&mut self, peer: NodeId) -> &mut PeerInfo {
+        let info = self.peers.entry(peer).or_default();
+        info.last_seen = now_millis();
+        info
+    }
+
+    pub fn neighbor_up(&mut self, peer: NodeId) {
+        self.touch(peer).neighbor = true;
+    }
+
+    pub fn neighbor_down(&mut self, peer: NodeId) {
+        self.touch(peer).neighbor = false;
+    }
+
+    pub fn authenticated(&mut self, peer: NodeId) {
+        self.touch(peer).authenticated = true;
+    }
+
+    /// Records when the outbound session with `peer` was authenticated.
+    pub fn set_session(&mut self, peer: NodeId, since: Option<u64>) {
+        match self.peers.get_mut(&peer) {
+            Some(info) => info.session_since = since,
+            None if since.is_some() => {
+                self.peers.entry(peer).or_default().session_since = since;
+            }
+            None => {}
+        }
+    }
+
+    pub fn seen(&mut self, peer: NodeId) {
+        self.touch(peer);
+    }
+
+    /// Counts a malformed message from `peer`, returning its total.
+    pub fn malformed(&mut self, peer: NodeId) -> u64 {
+        let info = self.peers.entry(peer).or_default();
+        info.malformed += 1;
+        info.malformed
+    }
+
+    /// Records that `peer` goes by `name`; true if that is news.
+    pub fn named(&mut self, peer: NodeId, name: &str) -> bool {
+        let info = self.peers.entry(peer).or_default();
+        if info.name.as_deref() == Some(name) {
+            return false;
+        }
+        info.name = Some(name.to_string());
+        true
+    }
+
+    /// Peers other than `peer` going by `name`.
+    pub fn also_named<'a>(
+        &'a self,
+        peer: &'a NodeId,
+        name: &'a str,
+    ) -> impl Iterator<Item = NodeId> + 'a {
+        self.peers
+            .iter()
+            .filter(move |(id, info)| *id != peer && info.name.as_deref() == Some(name))
+            .map(|(id, _)| *id)
+    }
+
+    pub fn set_path(&mut self, peer: &NodeId, path: PathKind) {
+        if let Some(info) = self.peers.get_mut(peer) {
+            info.path = path;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&NodeId, &PeerInfo)> {
+        self.peers.iter()
+    }
+
+    pub fn neighbors(&self) -> usize {
+        self.peers.values().filter(|p| p.neighbor).count()
+    }
+
+    pub fn authenticated_count(&self) -> usize {
+        self.peers.values().filter(|p| p.authenticated).count()
+    }
+}
+
+/// Serde helper for NodeId lists written as strings in config files.
+pub mod node_ids {
+    use iroh::NodeId;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(ids: &[NodeId], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(ids.iter().map(|id| id.to_string()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<NodeId>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|id| {
+                id.parse()
+                    .map_err(|e| de::Error::custom(format!("Invalid NodeId '{}': {}", id, e)))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(byte: u8) -> NodeId {
+        NodeId::from_bytes(&[byte; 32]).unwrap()
+    }
+
+    #[test]
+    fn empty_policy_permits_everyone() {
+        assert_eq!(PeerPolicy::default().rejects(&id(1)), None);
+    }
+
+    #[test]
+    fn table_tracks_neighbors_and_auth() {
+        let mut table = PeerTable::default();
+        table.neighbor_up(id(1));
+        table.neighbor_up(id(2));
+        table.authenticated(id(2));
+        table.neighbor_down(id(1));
+        assert_eq!(table.neighbors(), 1);
+        assert_eq!(table.authenticated_count(), 1);
+        assert_eq!(table.iter().count(), 2);
+    }
+
+    #[test]
+    fn names_can_collide() {
+        let mut table = PeerTable::default();
+        assert!(table.named(id(1), "worker"));
+        assert!(!table.named(id(1), "worker"));
+        assert_eq!(table.also_named(&id(1), "worker").count(), 0);
+        assert!(table.named(id(2), "worker"));
+        assert_eq!(
+            table.also_named(&id(2), "worker").collect::<Vec<_>>(),
+            [id(1)]
+        );
+        // Renamed, it no longer clashes.
+        assert!(table.named(id(1), "worker-1"));
+        assert_eq!(table.also_named(&id(2), "worker").count(), 0);
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let policy = PeerPolicy::new([id(1), id(2)], [id(2)]);
+        assert_eq!(policy.rejects(&id(1)), None);
+        assert!(policy.rejects(&id(2)).is_some());
+        assert!(policy.rejects(&id(3)).is_some());
+    }
+}
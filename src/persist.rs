@@ -0,0 +1,229 @@
+//! On-disk snapshots of the state map.
+//!
+//! A restarted node would otherwise answer NXDOMAIN for every container
+//! until gossip repopulates its map.  The map is periodically written to
+//! `<data_dir>/state.json` and loaded back before the DNS server starts.
+//! Restored entries keep their saved timestamps so that any update
+//! received afterwards replaces them.  An entry that doesn't parse, such
+//! as one with an address an older build let through, is dropped on its
+//! own; the rest of the snapshot still loads.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::types::{Entry, SharedState, Source, StateMap};
+
+/// Snapshot file name inside the data directory.
+const STATE_FILE: &str = "state.json";
+
+/// Bumped when the snapshot layout changes incompatibly.
+const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot<E = Entry> {
+    version: u32,
+    entries: HashMap<String, E>,
+}
+
+/// Path of the snapshot file in `data_dir`.
+pub fn state_path(data_dir: impl AsRef<Path>) -> PathBuf {
+    data_dir.as_ref().join(STATE_FILE)
+}
+
+/// Reads a snapshot.  A missing, unreadable or corrupt file yields an empty
+/// map; it is logged rather than treated as fatal.
+pub fn load(path: &Path) -> StateMap {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            debug!("No state snapshot at {}", path.display());
+            return StateMap::new();
+        }
+        Err(e) => {
+            warn!("Failed to read state snapshot {}: {}", path.display(), e);
+            return StateMap::new();
+        }
+    };
+    match serde_json::from_slice::<Snapshot<serde_json::Value>>(&bytes) {
+        Ok(snapshot) if snapshot.version == SNAPSHOT_VERSION => snapshot
+            .entries
+            .into_iter()
+            .filter_map(|(name, entry)| match serde_json::from_value(entry) {
+                Ok(entry) => Some((name, entry)),
+                Err(e) => {
+                    warn!(
+                        "Dropping unreadable entry {} from state snapshot: {}",
+                        name, e
+                    );
+                    None
+                }
+            })
+            .collect(),
+        Ok(snapshot) => {
+            warn!(
+                "Ignoring state snapshot {} with unsupported version {}",
+                path.display(),
+                snapshot.version
+            );
+            StateMap::new()
+        }
+        Err(e) => {
+            warn!("Ignoring corrupt state snapshot {}: {}", path.display(), e);
+            StateMap::new()
+        }
+    }
+}
+
+/// Writes `entries` to `path` atomically: the snapshot goes to a temporary
+/// file in the same directory which is then renamed over the old one.
+pub fn save(path: &Path, entries: &StateMap) -> anyhow::Result<()> {
+    let dir = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create data directory {}", dir.display()))?;
+
+    let snapshot = Snapshot {
+        version: SNAPSHOT_VERSION,
+        entries: entries.clone(),
+    };
+    let bytes = serde_json::to_vec_pretty(&snapshot)?;
+
+    let tmp = path.with_extension("json.tmp");
+    let mut file =
+        fs::File::create(&tmp).with_context(|| format!("Failed to create {}", tmp.display()))?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, path)
+        .with_context(|| format!("Failed to move snapshot into {}", path.display()))?;
+    Ok(())
+}
+
+/// Writes the current state to `path`.  Static records come from the
+/// config and aren't saved.
+pub async fn snapshot(path: &Path, state: &SharedState) -> anyhow::Result<()> {
+    let mut entries = state.read().await.clone();
+    entries.retain(|_, entry| matches!(entry.source, Source::Cluster | Source::Manual));
+    let path = path.to_path_buf();
+    let count = entries.len();
+    tokio::task::spawn_blocking(move || save(&path, &entries)).await??;
+    debug!("Saved state snapshot with {} entries", count);
+    Ok(())
+}
+
+/// Loads the snapshot at `path` into `state`.  Entries already present in
+/// the map are newer and are kept.
+pub async fn restore(path: &Path, state: &SharedState) {
+    let restored = load(path);
+    if restored.is_empty() {
+        return;
+    }
+    let count = restored.len();
+    state.restore(restored.into_iter().collect()).await;
+    info!(
+        "Restored {} entries from state snapshot {}",
+        count,
+        path.display()
+    );
+}
+
+/// Snapshots `state` every `interval` until the task is aborted.
+pub async fn run_snapshots(path: PathBuf, interval: Duration, state: SharedState) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick completes immediately; nothing has changed yet.
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        if let Err(e) = snapshot(&path, &state).await {
+            warn!("Failed to save state snapshot: {:#}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Entry;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("glued-persist-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn round_trip_keeps_timestamps() {
+        let dir = scratch_dir("round-trip");
+        let path = state_path(&dir);
+        let mut entries = StateMap::new();
+        entries.insert(
+            "web".into(),
+            Entry {
+                updated_at: 1234,
+                ..Entry::new("10.0.0.2".parse().unwrap())
+            },
+        );
+
+        save(&path, &entries).unwrap();
+        assert_eq!(load(&path), entries);
+        assert!(!path.with_extension("json.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn loads_snapshots_from_previous_versions() {
+        let dir = scratch_dir("previous");
+        let path = state_path(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            &path,
+            br#"{"version":1,"entries":{"nas":{"ip":"192.168.1.10","extra_ips":["fd00::10"],"updated_at":1234,"source":"static"}}}"#,
+        )
+        .unwrap();
+        let entries = load(&path);
+        assert_eq!(
+            entries["nas"].ip,
+            "192.168.1.10".parse::<std::net::IpAddr>().unwrap()
+        );
+        assert_eq!(entries["nas"].extra_ips[0].to_string(), "fd00::10");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_or_missing_snapshot_is_empty() {
+        let dir = scratch_dir("corrupt");
+        let path = state_path(&dir);
+        assert!(load(&path).is_empty());
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, b"{\"version\":1,\"entries\":").unwrap();
+        assert!(load(&path).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn entries_with_bad_addresses_are_dropped_alone() {
+        let dir = scratch_dir("bad-address");
+        let path = state_path(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            &path,
+            br#"{"version":1,"entries":{"web-1":{"ip":"10.0.0.2","updated_at":1},"web-2":{"ip":"10.0.0.300","updated_at":1}}}"#,
+        )
+        .unwrap();
+        let entries = load(&path);
+        assert_eq!(entries.len(), 1);
+        assert!(entries.contains_key("web-1"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
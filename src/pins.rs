@@ -0,0 +1,352 @@
+//! Manual entries: names an operator pins to an address cluster-wide.
+//!
+//! A pin is published as [`Update::Pin`] and applied on every node as a
+//! [`Source::Manual`] entry.  Containers of the same name can't replace or
+//! remove it; their entry is kept underneath and served again once the pin
+//! is dropped with [`Update::Unpin`] or expires.  Expiry is an absolute
+//! time, so every node drops the pin by itself.
+//!
+//! `glued import` pins the names of an exported entry list, see
+//! [`plan_import`].
+
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
+use std::time::Duration;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::names::{is_valid_label, normalize};
+use crate::types::{now_millis, SharedState, Source, StateMap, Update};
+
+/// How often expired pins are swept.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A pin as requested over the admin API.
+#[derive(Debug, Deserialize)]
+pub struct PinRequest {
+    pub name: String,
+    pub ip: String,
+    /// Seconds until the pin is dropped; it stays until unpinned without.
+    #[serde(default)]
+    pub ttl: Option<u64>,
+}
+
+/// A validated pin.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Pin {
+    pub name: String,
+    pub ip: IpAddr,
+    /// Unix time in milliseconds.
+    pub expires_at: Option<u64>,
+}
+
+impl From<Pin> for Update {
+    fn from(pin: Pin) -> Self {
+        Update::Pin {
+            name: pin.name,
+            ip: pin.ip,
+            expires_at: pin.expires_at,
+        }
+    }
+}
+
+impl PinRequest {
+    /// The pin asked for, or why it can't be set.
+    pub fn validate(self) -> Result<Pin, String> {
+        let name = normalize(&self.name);
+        if !is_valid_label(&name) {
+            return Err(format!("'{}' is not a valid single DNS label", self.name));
+        }
+        let ip: IpAddr = self
+            .ip
+            .parse()
+            .map_err(|_| format!("'{}' is not an IP address", self.ip))?;
+        let expires_at = match self.ttl {
+            Some(0) => return Err("ttl must be at least one second".into()),
+            Some(ttl) => Some(now_millis().saturating_add(ttl.saturating_mul(1000))),
+            None => None,
+        };
+        Ok(Pin {
+            name,
+            ip,
+            expires_at,
+        })
+    }
+}
+
+/// An entry of an export, as `glued export` and `GET /v1/entries` list
+/// them.  Other fields are ignored.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ImportRecord {
+    pub name: String,
+    pub ip: String,
+    #[serde(default)]
+    pub source: Source,
+    /// Kept for manual entries; for container entries it is their lease.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+/// What importing a list of records changes.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ImportPlan {
+    pub pinned: Vec<Pin>,
+    /// Manual entries the list doesn't have, dropped with `replace`.
+    pub unpinned: Vec<String>,
+    /// Names already served at the record's address.
+    pub unchanged: Vec<String>,
+    /// Names left out, with why.
+    pub skipped: BTreeMap<String, String>,
+}
+
+impl ImportPlan {
+    /// The updates carrying out the plan, unpins first, in batches of at
+    /// most `max`.
+    pub fn updates(&self, max: usize) -> Vec<Update> {
+        let updates: Vec<Update> = self
+            .unpinned
+            .iter()
+            .map(|name| Update::Unpin { name: name.clone() })
+            .chain(self.pinned.iter().cloned().map(Update::from))
+            .collect();
+        updates
+            .chunks(max.max(1))
+            .map(|chunk| Update::batch(chunk.to_vec()))
+            .collect()
+    }
+}
+
+/// Plans pinning every name of `records` to its address on top of `map`,
+/// as of `now`.  Names already served at that address are left alone, so
+/// importing an export of `map` changes nothing.  With `replace`, manual
+/// entries the records don't name are dropped too.  Fails, changing
+/// nothing, if any record is invalid.
+pub fn plan_import(
+    map: &StateMap,
+    records: Vec<ImportRecord>,
+    replace: bool,
+    now: u64,
+) -> Result<ImportPlan, String> {
+    let mut plan = ImportPlan::default();
+    let mut errors = Vec::new();
+    let mut named = HashSet::new();
+    for record in records {
+        let expires_at = record
+            .expires_at
+            .filter(|_| record.source == Source::Manual);
+        let request = PinRequest {
+            name: record.name,
+            ip: record.ip,
+            ttl: None,
+        };
+        let pin = match request.validate() {
+            Ok(pin) => Pin { expires_at, ..pin },
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+        if !named.insert(pin.name.clone()) {
+            errors.push(format!("'{}' is listed more than once", pin.name));
+            continue;
+        }
+        if pin.expires_at.is_some_and(|at| at <= now) {
+            plan.skipped.insert(pin.name, "expired".into());
+            continue;
+        }
+        match map.get(&pin.name) {
+            Some(entry)
+                if entry.ip == pin.ip
+                    && (entry.source != Source::Manual || entry.expires_at == pin.expires_at) =>
+            {
+                plan.unchanged.push(pin.name)
+            }
+            _ => plan.pinned.push(pin),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors.join("; "));
+    }
+    if replace {
+        plan.unpinned = map
+            .iter()
+            .filter(|(name, entry)| entry.source == Source::Manual && !named.contains(*name))
+            .map(|(name, _)| name.clone())
+            .collect();
+        plan.unpinned.sort();
+    }
+    Ok(plan)
+}
+
+/// Drops the pin on `name`, restoring the entry it hid.  Returns whether
+/// there was one.
+pub fn unpin(map: &mut StateMap, name: &str) -> bool {
+    if map.get(name).map(|entry| entry.source) != Some(Source::Manual) {
+        return false;
+    }
+    if let Some(shadowed) = map.remove(name).and_then(|entry| entry.shadowed) {
+        map.insert(name.to_string(), *shadowed);
+    }
+    true
+}
+
+/// Drops the pins that expired by `now` (Unix time in milliseconds),
+/// returning their names.
+pub fn expire(map: &mut StateMap, now: u64) -> Vec<String> {
+    let expired: Vec<String> = map
+        .iter()
+        .filter(|(_, entry)| entry.source == Source::Manual)
+        .filter(|(_, entry)| entry.expires_at.is_some_and(|at| at <= now))
+        .map(|(name, _)| name.clone())
+        .collect();
+    for name in &expired {
+        unpin(map, name);
+    }
+    expired
+}
+
+/// Drops pins as they expire, until the task is aborted.
+pub async fn run_expiry(state: SharedState) {
+    let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let mut map = state.write().await;
+        let expired = expire(&mut map, now_millis());
+        map.changed_only(&expired);
+        for name in expired {
+            info!("Pin on {} expired", name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gossip::apply_update;
+    use crate::registry::LocalSource;
+    use crate::types::Entry;
+
+    fn request(name: &str, ip: &str, ttl: Option<u64>) -> Result<Pin, String> {
+        PinRequest {
+            name: name.into(),
+            ip: ip.into(),
+            ttl,
+        }
+        .validate()
+    }
+
+    #[test]
+    fn requests_are_validated() {
+        let pin = request("Canary", "10.0.0.9", None).unwrap();
+        assert_eq!(pin.name, "canary");
+        assert_eq!(pin.expires_at, None);
+        assert!(request("canary", "10.0.0.9", Some(60))
+            .unwrap()
+            .expires_at
+            .is_some());
+        assert!(request("two.labels", "10.0.0.9", None).is_err());
+        assert!(request("canary", "10.0.0", None).is_err());
+        assert!(request("canary", "10.0.0.9", Some(0)).is_err());
+    }
+
+    #[tokio::test]
+    async fn pins_hide_containers_until_dropped() {
+        let state = SharedState::default();
+        let add = |ip: &str| Update::Add {
+            name: "web-1".into(),
+            ip: ip.parse().unwrap(),
+        };
+        let pin = |expires_at| Update::Pin {
+            name: "web-1".into(),
+            ip: "10.0.0.9".parse().unwrap(),
+            expires_at,
+        };
+        let ip = |state: &StateMap| state.get("web-1").map(|entry| entry.ip.to_string());
+
+        apply_update(add("10.0.0.2"), LocalSource::DockerEvent, &state).await;
+        apply_update(pin(None), LocalSource::AdminApi, &state).await;
+        assert_eq!(ip(&*state.read().await).as_deref(), Some("10.0.0.9"));
+
+        // The container moves underneath the pin.
+        apply_update(add("10.0.0.3"), LocalSource::DockerEvent, &state).await;
+        assert_eq!(ip(&*state.read().await).as_deref(), Some("10.0.0.9"));
+        apply_update(
+            Update::Unpin {
+                name: "web-1".into(),
+            },
+            LocalSource::AdminApi,
+            &state,
+        )
+        .await;
+        assert_eq!(ip(&*state.read().await).as_deref(), Some("10.0.0.3"));
+
+        apply_update(pin(Some(1_000)), LocalSource::AdminApi, &state).await;
+        apply_update(
+            Update::Remove {
+                name: "web-1".into(),
+            },
+            LocalSource::DockerEvent,
+            &state,
+        )
+        .await;
+        let mut map = state.write().await;
+        assert_eq!(expire(&mut map, 999), Vec::<String>::new());
+        assert_eq!(expire(&mut map, 1_000), ["web-1"]);
+        assert_eq!(map.get("web-1"), None::<&Entry>);
+    }
+
+    #[test]
+    fn importing_an_export_changes_nothing() {
+        let mut map = StateMap::new();
+        map.insert("web-1".into(), Entry::new("10.0.0.2".parse().unwrap()));
+        map.insert(
+            "canary".into(),
+            Entry {
+                source: Source::Manual,
+                expires_at: Some(5_000),
+                ..Entry::new("10.0.0.9".parse().unwrap())
+            },
+        );
+        let export = serde_json::to_string(&crate::admin::entries(&map)).unwrap();
+        let records: Vec<ImportRecord> = serde_json::from_str(&export).unwrap();
+        let plan = plan_import(&map, records.clone(), true, 1_000).unwrap();
+        assert_eq!(plan.unchanged, ["canary", "web-1"]);
+        assert_eq!(plan.updates(10), Vec::<Update>::new());
+
+        // Elsewhere, the same export pins both names, keeping the expiry.
+        let mut other = StateMap::new();
+        other.insert(
+            "old-pin".into(),
+            Entry {
+                source: Source::Manual,
+                ..Entry::new("10.0.0.1".parse().unwrap())
+            },
+        );
+        let plan = plan_import(&other, records.clone(), true, 1_000).unwrap();
+        assert_eq!(plan.unpinned, ["old-pin"]);
+        assert_eq!(plan.pinned[0].expires_at, Some(5_000));
+        assert_eq!(plan.pinned[1].expires_at, None);
+        let updates = plan.updates(2);
+        assert_eq!(updates.len(), 2);
+        assert_eq!(
+            updates[0],
+            Update::Batch(vec![
+                Update::Unpin {
+                    name: "old-pin".into()
+                },
+                plan.pinned[0].clone().into()
+            ])
+        );
+        let plan = plan_import(&other, records.clone(), false, 6_000).unwrap();
+        assert!(plan.unpinned.is_empty());
+        assert_eq!(plan.skipped["canary"], "expired");
+
+        let mut invalid = records;
+        invalid.push(invalid[0].clone());
+        invalid[1].ip = "10.0.0".into();
+        let error = plan_import(&other, invalid, false, 1_000).unwrap_err();
+        assert!(error.contains("not an IP address"), "{}", error);
+        assert!(error.contains("more than once"), "{}", error);
+    }
+}
@@ -0,0 +1,676 @@
+//! The registry: the state map, and a feed of the updates applied to it.
+//!
+//! Updates go through [`Registry::apply`], which applies them under one
+//! write lock and then announces them to every subscriber: gossip forwards
+//! this node's own changes, webhooks and the hosts export follow all of
+//! them.  Announcements happen under the lock, so subscribers see changes
+//! in the order they were applied.  A subscriber that falls more than the
+//! channel's capacity behind misses the oldest changes and is told how
+//! many.
+//!
+//! [`Registry::write`] edits the map without an announcement; it is for
+//! names configured on this node (static records, the hosts file) and for
+//! pins and leases running out, which every node does by itself.
+//!
+//! The registry also keeps the [`StateDigest`] gossip heartbeats carry,
+//! counting again the names each update touches.
+//!
+//! Alongside the map, the registry keeps the container entries set aside
+//! because another node publishes the same name; see [`crate::conflicts`].
+//! It also ignores updates from nodes evicted lately; see [`crate::evict`].
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::debug;
+use serde::Serialize;
+use tokio::sync::{broadcast, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::conflicts::{Conflict, ConflictPolicy, Conflicts};
+use crate::crdt::{Dots, Stamp};
+use crate::digest::{DigestTable, StateDigest};
+use crate::evict::Evictions;
+use crate::gossip::apply_to;
+use crate::limits::Limits;
+use crate::names::normalize;
+use crate::types::{now_millis, Entry, Source, StateMap, Update};
+
+/// Changes kept for a subscriber that is behind, unless configured.
+const DEFAULT_CAPACITY: usize = 1024;
+
+/// Where an applied update came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeOrigin {
+    /// This node: its runtime, the admin API or the control socket.
+    Local(LocalSource),
+    /// A peer over gossip, with the node that published it when the
+    /// message said.
+    Peer(Option<PeerNode>),
+}
+
+impl ChangeOrigin {
+    /// The publishing node, for entries and events that record one.
+    pub fn node(&self) -> Option<&str> {
+        self.peer().map(|peer| peer.id.as_str())
+    }
+
+    pub fn peer(&self) -> Option<&PeerNode> {
+        match self {
+            ChangeOrigin::Local(_) => None,
+            ChangeOrigin::Peer(peer) => peer.as_ref(),
+        }
+    }
+
+    /// What made the change, as audit records name it.
+    pub fn source(&self) -> &'static str {
+        match self {
+            ChangeOrigin::Local(source) => source.as_str(),
+            ChangeOrigin::Peer(_) => "gossip",
+        }
+    }
+}
+
+/// What on this node made a change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LocalSource {
+    /// A Docker event about a container.
+    DockerEvent,
+    /// A containerd event about a task.
+    ContainerdEvent,
+    /// A runtime's rescan, catching up with what events missed.
+    Reconcile,
+    /// A container's health probes starting or stopping to fail.
+    HealthCheck,
+    /// The admin HTTP API.
+    AdminApi,
+    /// `glued ctl` over the control socket.
+    Control,
+}
+
+impl LocalSource {
+    /// Whether the container runtime made the change, rather than an
+    /// operator.
+    pub fn is_runtime(self) -> bool {
+        !matches!(self, LocalSource::AdminApi | LocalSource::Control)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LocalSource::DockerEvent => "docker-event",
+            LocalSource::ContainerdEvent => "containerd-event",
+            LocalSource::Reconcile => "reconcile",
+            LocalSource::HealthCheck => "health-check",
+            LocalSource::AdminApi => "admin-api",
+            LocalSource::Control => "control",
+        }
+    }
+}
+
+/// An update made on this node, on its way to the registry.
+pub type LocalUpdate = (Update, LocalSource);
+
+/// The node that published an update.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerNode {
+    /// Short NodeId: the node's identity.
+    pub id: String,
+    /// Its `node_name`, when it sent one.  Names can collide or lie.
+    pub name: Option<String>,
+    /// When it sent the update, if it said; see [`crate::crdt`].
+    pub stamp: Option<Stamp>,
+}
+
+impl PeerNode {
+    /// A node known only by its short NodeId.
+    pub fn new(id: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            name: None,
+            stamp: None,
+        }
+    }
+}
+
+/// The name, with the short NodeId after it, or the short NodeId alone.
+impl fmt::Display for PeerNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} ({})", name, self.id),
+            None => f.write_str(&self.id),
+        }
+    }
+}
+
+/// An update as applied to the registry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub update: Update,
+    pub origin: ChangeOrigin,
+}
+
+/// The state map and its change feed.
+pub struct Registry {
+    map: RwLock<StateMap>,
+    changes: broadcast::Sender<Arc<Change>>,
+    limits: Limits,
+    /// Only locked with the map write-locked.
+    conflicts: Mutex<Conflicts>,
+    /// Only changed with the map write-locked.
+    digests: Mutex<DigestTable>,
+    /// Only locked with the map write-locked.
+    evictions: Mutex<Evictions>,
+    /// Only locked with the map write-locked.
+    dots: Mutex<Dots>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY, Limits::default())
+    }
+}
+
+impl Registry {
+    /// An empty registry keeping up to `capacity` changes for each
+    /// subscriber and applying updates within `limits`.  A zero capacity
+    /// is raised to one.
+    pub fn new(capacity: usize, limits: Limits) -> Self {
+        let (changes, _) = broadcast::channel(capacity.max(1));
+        Self {
+            map: RwLock::new(StateMap::new()),
+            changes,
+            limits,
+            conflicts: Mutex::default(),
+            digests: Mutex::default(),
+            evictions: Mutex::default(),
+            dots: Mutex::default(),
+        }
+    }
+
+    /// Serves names several nodes publish as `policy` says, rather than
+    /// from the lowest NodeId.
+    pub fn with_conflict_policy(self, policy: ConflictPolicy) -> Self {
+        Self {
+            conflicts: Mutex::new(Conflicts::new(policy)),
+            ..self
+        }
+    }
+
+    /// Ignores updates from an evicted node for `grace`, rather than
+    /// [`crate::evict::DEFAULT_GRACE`].
+    pub fn with_eviction_grace(self, grace: Duration) -> Self {
+        Self {
+            evictions: Mutex::new(Evictions::new(grace)),
+            ..self
+        }
+    }
+
+    pub fn conflict_policy(&self) -> ConflictPolicy {
+        self.conflicts.lock().unwrap().policy()
+    }
+
+    pub async fn read(&self) -> RwLockReadGuard<'_, StateMap> {
+        self.map.read().await
+    }
+
+    /// The map for edits that aren't announced.
+    pub async fn write(&self) -> MapWrite<'_> {
+        MapWrite {
+            map: self.map.write().await,
+            conflicts: &self.conflicts,
+            digests: &self.digests,
+            changed: Changed::Nothing,
+        }
+    }
+
+    /// Applies `update` under a single write lock, so DNS never observes
+    /// half of a batch, and announces it.  Parts of it beyond the limits
+    /// are dropped, but the update is announced as it came.  Updates from
+    /// a node evicted lately are neither applied nor announced, nor are
+    /// parts a peer stamped no later than what was applied from it; see
+    /// [`crate::crdt`].
+    pub async fn apply(&self, update: Update, origin: ChangeOrigin) {
+        let mut map = self.map.write().await;
+        let mut conflicts = self.conflicts.lock().unwrap();
+        let from = origin.peer();
+        let now = now_millis();
+        {
+            let mut evictions = self.evictions.lock().unwrap();
+            if let Some(node) = origin.node().filter(|node| evictions.ignores(node, now)) {
+                debug!("Ignoring update from evicted node {}", node);
+                return;
+            }
+            let mut evicted = Vec::new();
+            self::evicted(&update, &mut evicted);
+            for node in &evicted {
+                evictions.evict(node, now);
+            }
+        }
+        let update = match from {
+            Some(PeerNode {
+                id,
+                stamp: Some(stamp),
+                ..
+            }) => match self.dots.lock().unwrap().admit(id, *stamp, update, now) {
+                Some(update) => update,
+                None => return debug!("Ignoring update from node {}: nothing new", id),
+            },
+            _ => update,
+        };
+        let mut names = Vec::new();
+        let recount = touched(&update, &mut names);
+        if self.changes.receiver_count() == 0 {
+            apply_to(&mut map, update, from, &self.limits, &mut conflicts);
+            self.count(&map, &conflicts, &names, recount);
+            return;
+        }
+        apply_to(&mut map, update.clone(), from, &self.limits, &mut conflicts);
+        self.count(&map, &conflicts, &names, recount);
+        // Only fails when every subscriber has gone since the check.
+        let _ = self.changes.send(Arc::new(Change { update, origin }));
+    }
+
+    /// Counts `names` again, or with `all` the whole map.
+    fn count(&self, map: &StateMap, conflicts: &Conflicts, names: &[String], all: bool) {
+        let mut digests = self.digests.lock().unwrap();
+        if all {
+            digests.recount(map, conflicts);
+            return;
+        }
+        for name in names {
+            digests.update(map, conflicts, name);
+        }
+    }
+
+    /// Merges `entries` restored from a snapshot into the map.  Names it
+    /// doesn't have are added; a container entry for a name another node's
+    /// container is served for is decided between by the conflict policy,
+    /// as its `Add` would be.  Otherwise what the map has stays.
+    pub async fn restore(&self, entries: Vec<(String, Entry)>) {
+        let mut map = self.map.write().await;
+        let mut conflicts = self.conflicts.lock().unwrap();
+        let mut names = Vec::with_capacity(entries.len());
+        for (name, entry) in entries {
+            match map.get(&name) {
+                None => {
+                    map.insert(name.clone(), entry);
+                }
+                Some(served)
+                    if served.source == Source::Cluster
+                        && entry.source == Source::Cluster
+                        && served.node != entry.node =>
+                {
+                    let node = entry.node.clone();
+                    if let Some(entry) = conflicts.contest(&map, &name, node.as_deref(), entry) {
+                        map.insert(name.clone(), entry);
+                    }
+                    conflicts.merge(&mut map, &name);
+                }
+                Some(_) => {}
+            }
+            names.push(name);
+        }
+        self.count(&map, &conflicts, &names, false);
+    }
+
+    /// The digest of the entries gossip keeps the same on every node.
+    pub fn digest(&self) -> StateDigest {
+        self.digests.lock().unwrap().digest()
+    }
+
+    /// Changes applied from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Change>> {
+        self.changes.subscribe()
+    }
+
+    /// Sets this node's short NodeId, which its own entries count as
+    /// published by when another node publishes the same name.
+    pub async fn set_node(&self, id: String) {
+        let _map = self.map.write().await;
+        self.conflicts.lock().unwrap().set_node(id);
+    }
+
+    /// The names published by several nodes.
+    pub async fn conflicts(&self) -> Vec<Conflict> {
+        let map = self.map.read().await;
+        let conflicts = self.conflicts.lock().unwrap();
+        conflicts.list(&map)
+    }
+
+    /// Drops the set-aside entries whose lease ran out by `now`, serving
+    /// one for each name whose entry went; `map` is this registry's, write
+    /// locked.  Returns the names served from another node.
+    pub fn expire_set_aside(&self, map: &mut StateMap, now: u64) -> Vec<String> {
+        let mut conflicts = self.conflicts.lock().unwrap();
+        let names: Vec<String> = conflicts.names().cloned().collect();
+        let served = conflicts.expire(map, now);
+        for name in &names {
+            conflicts.merge(map, name);
+        }
+        self.count(map, &conflicts, &names, false);
+        served
+    }
+}
+
+/// The names `update` may change, as the map keys them.  Returns whether
+/// it may change names it doesn't give, as an eviction does.
+fn touched(update: &Update, names: &mut Vec<String>) -> bool {
+    match update {
+        Update::Add { name, .. }
+        | Update::Remove { name }
+        | Update::Ports { name, .. }
+        | Update::Pin { name, .. }
+        | Update::Unpin { name }
+        | Update::Lease { name, .. }
+        | Update::ScopedIps { name, .. }
+        | Update::Txt { name, .. } => {
+            names.push(normalize(name));
+            false
+        }
+        Update::EvictNode { .. } => true,
+        Update::Batch(updates) => {
+            let mut all = false;
+            for update in updates {
+                all |= touched(update, names);
+            }
+            all
+        }
+    }
+}
+
+/// The nodes `update` evicts.
+fn evicted(update: &Update, nodes: &mut Vec<String>) {
+    match update {
+        Update::EvictNode { node } => nodes.push(node.clone()),
+        Update::Batch(updates) => {
+            for update in updates {
+                evicted(update, nodes);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The map write-locked by [`Registry::write`].  Once dropped, the digest
+/// counts again the names said to have changed, or the whole map if it was
+/// changed without saying which.
+pub struct MapWrite<'a> {
+    map: RwLockWriteGuard<'a, StateMap>,
+    conflicts: &'a Mutex<Conflicts>,
+    digests: &'a Mutex<DigestTable>,
+    changed: Changed,
+}
+
+enum Changed {
+    Nothing,
+    Names(Vec<String>),
+    Unknown,
+}
+
+impl MapWrite<'_> {
+    /// Says the edit changed `names` and no others, so that only they are
+    /// counted again; sweeps that run every second use this.
+    pub fn changed_only<'n>(&mut self, names: impl IntoIterator<Item = &'n String>) {
+        self.changed = Changed::Names(names.into_iter().cloned().collect());
+    }
+}
+
+impl Deref for MapWrite<'_> {
+    type Target = StateMap;
+
+    fn deref(&self) -> &StateMap {
+        &self.map
+    }
+}
+
+impl DerefMut for MapWrite<'_> {
+    fn deref_mut(&mut self) -> &mut StateMap {
+        if matches!(self.changed, Changed::Nothing) {
+            self.changed = Changed::Unknown;
+        }
+        &mut self.map
+    }
+}
+
+impl Drop for MapWrite<'_> {
+    fn drop(&mut self) {
+        if matches!(self.changed, Changed::Nothing) {
+            return;
+        }
+        let conflicts = self.conflicts.lock().unwrap();
+        let mut digests = self.digests.lock().unwrap();
+        match &self.changed {
+            Changed::Nothing => {}
+            Changed::Names(names) => {
+                for name in names {
+                    digests.update(&self.map, &conflicts, name);
+                }
+            }
+            Changed::Unknown => digests.recount(&self.map, &conflicts),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+
+    fn add(i: u8) -> Update {
+        Update::Add {
+            name: format!("web-{}", i),
+            ip: format!("10.0.0.{}", i).parse().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribers_see_changes_in_order() {
+        let registry = Registry::new(2, Limits::default());
+        // Nobody listening yet: applied, not kept.
+        registry
+            .apply(add(1), ChangeOrigin::Local(LocalSource::Control))
+            .await;
+        let mut changes = registry.subscribe();
+        registry
+            .apply(add(2), ChangeOrigin::Local(LocalSource::Control))
+            .await;
+        registry
+            .apply(
+                add(3),
+                ChangeOrigin::Peer(Some(PeerNode {
+                    id: "ab12cd34ef".into(),
+                    name: Some("swarm-worker-3".into()),
+                    stamp: None,
+                })),
+            )
+            .await;
+
+        let change = changes.recv().await.unwrap();
+        assert_eq!(change.update, add(2));
+        assert_eq!(change.origin, ChangeOrigin::Local(LocalSource::Control));
+        assert_eq!(change.origin.source(), "control");
+        let change = changes.recv().await.unwrap();
+        assert_eq!(change.origin.node(), Some("ab12cd34ef"));
+        assert_eq!(change.origin.source(), "gossip");
+        assert_eq!(changes.try_recv().unwrap_err(), TryRecvError::Empty);
+        let entry = registry.read().await.get("web-3").cloned().unwrap();
+        assert_eq!(entry.node.as_deref(), Some("ab12cd34ef"));
+        assert_eq!(entry.node_name.as_deref(), Some("swarm-worker-3"));
+
+        // A subscriber that falls behind loses the oldest changes.
+        for i in 4..7 {
+            registry
+                .apply(add(i), ChangeOrigin::Local(LocalSource::Control))
+                .await;
+        }
+        assert_eq!(changes.recv().await.unwrap_err(), RecvError::Lagged(1));
+        assert_eq!(changes.recv().await.unwrap().update, add(5));
+        assert_eq!(registry.read().await.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn the_digest_follows_updates_and_edits() {
+        let registry = Registry::default();
+        let recount = |map: &StateMap| {
+            let mut table = DigestTable::default();
+            table.recount(map, &Conflicts::default());
+            table.digest()
+        };
+        registry
+            .apply(
+                Update::batch(vec![
+                    add(1),
+                    add(2),
+                    Update::Add {
+                        name: "Web-3.".into(),
+                        ip: "10.0.0.3".parse().unwrap(),
+                    },
+                    Update::Remove {
+                        name: "web-2".into(),
+                    },
+                ]),
+                ChangeOrigin::Local(LocalSource::Control),
+            )
+            .await;
+        let digest = registry.digest();
+        assert_eq!(digest.entries, 2);
+        assert_eq!(digest, recount(&*registry.read().await));
+
+        // Told which names changed, only they are counted again.
+        {
+            let mut map = registry.write().await;
+            map.get_mut("web-1").unwrap().ip = "10.0.0.9".parse().unwrap();
+            map.changed_only(&[]);
+        }
+        assert_eq!(registry.digest(), digest);
+        {
+            let mut map = registry.write().await;
+            map.remove("web-3");
+        }
+        let digest = registry.digest();
+        assert_eq!(digest.entries, 1);
+        assert_eq!(digest, recount(&*registry.read().await));
+    }
+
+    /// Every ordering of `items`.
+    fn permutations<T: Clone>(items: &[T]) -> Vec<Vec<T>> {
+        if items.len() <= 1 {
+            return vec![items.to_vec()];
+        }
+        let mut all = Vec::new();
+        for i in 0..items.len() {
+            let mut rest = items.to_vec();
+            let first = rest.remove(i);
+            for mut tail in permutations(&rest) {
+                tail.insert(0, first.clone());
+                all.push(tail);
+            }
+        }
+        all
+    }
+
+    /// Three nodes adding, changing and removing names, two of them the
+    /// same: each message with its publisher and stamp.
+    fn history() -> Vec<(Update, ChangeOrigin)> {
+        let stamped = |node: &str, timestamp: u64, update: Update| {
+            let peer = PeerNode {
+                stamp: Some(Stamp { timestamp, seq: 0 }),
+                ..PeerNode::new(node)
+            };
+            (update, ChangeOrigin::Peer(Some(peer)))
+        };
+        let add = |name: &str, ip: &str| Update::Add {
+            name: name.into(),
+            ip: ip.parse().unwrap(),
+        };
+        let remove = |name: &str| Update::Remove { name: name.into() };
+        let txt = |name: &str, txt: &str| Update::Txt {
+            name: name.into(),
+            txt: vec![txt.into()],
+        };
+        let ports = Update::Ports {
+            name: "web".into(),
+            ports: vec![crate::types::Port {
+                port: 80,
+                protocol: crate::types::PortProtocol::Tcp,
+            }],
+        };
+        vec![
+            stamped(
+                "aa11",
+                1,
+                Update::batch(vec![add("web", "10.0.0.1"), ports]),
+            ),
+            stamped("bb22", 1, add("web", "10.0.0.2")),
+            stamped("aa11", 2, remove("web")),
+            stamped(
+                "aa11",
+                3,
+                Update::batch(vec![add("web", "10.0.0.3"), txt("web", "a")]),
+            ),
+            stamped(
+                "bb22",
+                2,
+                Update::batch(vec![
+                    add("web", "10.0.0.2"),
+                    txt("web", "b"),
+                    add("db", "10.0.0.4"),
+                ]),
+            ),
+            stamped(
+                "cc33",
+                1,
+                Update::batch(vec![add("web", "10.0.0.5"), remove("db")]),
+            ),
+            stamped("cc33", 2, remove("web")),
+        ]
+    }
+
+    /// What a replica serves and has set aside, leaving out when.
+    async fn served(registry: &Registry) -> (Vec<String>, Vec<Conflict>, StateDigest) {
+        let map = registry.read().await;
+        let mut entries: Vec<String> = map
+            .iter()
+            .map(|(name, entry)| {
+                format!(
+                    "{} {} {:?} {:?} {:?} {:?}",
+                    name, entry.ip, entry.extra_ips, entry.node, entry.ports, entry.txt
+                )
+            })
+            .collect();
+        entries.sort();
+        drop(map);
+        (entries, registry.conflicts().await, registry.digest())
+    }
+
+    #[tokio::test]
+    async fn any_delivery_order_converges() {
+        let history = history();
+        for policy in [ConflictPolicy::LowestNode, ConflictPolicy::Merge] {
+            let mut expected = None;
+            for order in permutations(&history) {
+                let registry = Registry::default().with_conflict_policy(policy);
+                for (update, origin) in &order {
+                    registry.apply(update.clone(), origin.clone()).await;
+                }
+                let state = served(&registry).await;
+                // Redelivery changes nothing.
+                for (update, origin) in order.iter().rev() {
+                    registry.apply(update.clone(), origin.clone()).await;
+                }
+                assert_eq!(served(&registry).await, state, "{:?}", policy);
+                let expected = expected.get_or_insert_with(|| state.clone());
+                assert_eq!(*expected, state, "{:?} in order {:?}", policy, order);
+            }
+            let (entries, conflicts, _) = expected.unwrap();
+            assert_eq!(entries.len(), 2, "{:?}", policy);
+            assert!(entries[0].starts_with("db 10.0.0.4 "));
+            assert!(entries[1].starts_with("web 10.0.0.3 "));
+            assert!(entries[1].ends_with("Some(\"aa11\") [] [\"a\"]"));
+            assert_eq!(conflicts.len(), 1);
+            assert_eq!(conflicts[0].set_aside[0].node, "bb22");
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! Applying a changed config without a restart.
+//!
+//! On SIGHUP or the control socket's `reload-config`, the config is loaded
+//! again and compared with the running one.  What can change under a
+//! running daemon is applied: the log level and static records at once,
+//! DNS ACLs and forward zones from the next query on, and the peer lists
+//! from the next gossip connection or dial.  Any other change is logged as
+//! waiting for a restart and left out of the running config.  A config
+//! that fails to load or doesn't apply changes nothing.
+
+use std::sync::Arc;
+
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::config::Config;
+use crate::logging;
+use crate::static_records;
+use crate::types::SharedState;
+
+/// Config fields [`Reloader::apply`] puts into effect.
+pub const RELOADABLE: [&str; 9] = [
+    "log_level",
+    "static_records",
+    "forward_zones",
+    "dns_allow",
+    "dns_deny",
+    "forward_allow",
+    "axfr_allow",
+    "allowed_peers",
+    "denied_peers",
+];
+
+/// What a reload changed.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Reloaded {
+    /// Changed fields now in effect.
+    pub applied: Vec<String>,
+    /// Changed fields that only a restart puts into effect.
+    pub restart_required: Vec<String>,
+}
+
+/// The running config, and the subsystems following it.
+pub struct Reloader {
+    config: watch::Sender<Arc<Config>>,
+    state: SharedState,
+}
+
+impl Reloader {
+    pub fn new(cfg: Config, state: SharedState) -> Self {
+        Self {
+            config: watch::Sender::new(Arc::new(cfg)),
+            state,
+        }
+    }
+
+    /// The config in effect.
+    pub fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.config.borrow())
+    }
+
+    /// Each config put into effect from now on.
+    pub fn subscribe(&self) -> watch::Receiver<Arc<Config>> {
+        self.config.subscribe()
+    }
+
+    /// Loads the config again and applies it.
+    pub async fn reload(&self) -> anyhow::Result<Reloaded> {
+        self.apply(Config::load()?).await
+    }
+
+    /// Puts the reloadable parts of `loaded` into effect.
+    pub async fn apply(&self, loaded: Config) -> anyhow::Result<Reloaded> {
+        let running = self.config();
+        let mut reloaded = Reloaded::default();
+        for field in changed_fields(&running, &loaded)? {
+            if RELOADABLE.contains(&field.as_str()) {
+                reloaded.applied.push(field);
+            } else {
+                reloaded.restart_required.push(field);
+            }
+        }
+        let changed = |field: &str| reloaded.applied.iter().any(|f| f == field);
+        let mut level_changed = changed("log_level");
+
+        // Everything that can be invalid is checked before anything changes.
+        if level_changed {
+            logging::check_level(&loaded.log_level)?;
+        }
+        // Static records are checked as they are installed.
+        if changed("static_records") {
+            static_records::install(&mut *self.state.write().await, &loaded.static_records)?;
+        }
+        if level_changed {
+            if let Err(e) = logging::set_level(&loaded.log_level) {
+                error!("{:#}", e);
+                reloaded.applied.retain(|field| field != "log_level");
+                level_changed = false;
+            }
+        }
+        let effective = Config {
+            log_level: if level_changed {
+                loaded.log_level
+            } else {
+                running.log_level.clone()
+            },
+            static_records: loaded.static_records,
+            forward_zones: loaded.forward_zones,
+            dns_allow: loaded.dns_allow,
+            dns_deny: loaded.dns_deny,
+            forward_allow: loaded.forward_allow,
+            axfr_allow: loaded.axfr_allow,
+            allowed_peers: loaded.allowed_peers,
+            denied_peers: loaded.denied_peers,
+            ..Config::clone(&running)
+        };
+        self.config.send_replace(Arc::new(effective));
+
+        if !reloaded.applied.is_empty() {
+            info!("Config reloaded: {}", reloaded.applied.join(", "));
+        }
+        if !reloaded.restart_required.is_empty() {
+            warn!(
+                "Config changes waiting for a restart: {}",
+                reloaded.restart_required.join(", ")
+            );
+        }
+        Ok(reloaded)
+    }
+}
+
+/// The top-level fields that differ between `a` and `b`, by name.
+fn changed_fields(a: &Config, b: &Config) -> anyhow::Result<Vec<String>> {
+    let a = serde_json::to_value(a)?;
+    let b = serde_json::to_value(b)?;
+    let (Some(a), Some(b)) = (a.as_object(), b.as_object()) else {
+        anyhow::bail!("the config doesn't serialize to an object");
+    };
+    let mut fields: Vec<String> = a
+        .keys()
+        .chain(b.keys())
+        .filter(|field| a.get(*field) != b.get(*field))
+        .cloned()
+        .collect();
+    fields.sort();
+    fields.dedup();
+    Ok(fields)
+}
+
+/// Reloads the config on every SIGHUP, until the task is aborted.
+#[cfg(unix)]
+pub async fn run_on_sighup(reloader: Arc<Reloader>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("Can't reload the config on SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("Received SIGHUP, reloading the config");
+        if let Err(e) = reloader.reload().await {
+            error!("Config reload failed; keeping the running config: {:#}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StaticRecord;
+
+    #[tokio::test]
+    async fn reloadable_changes_apply_and_the_rest_wait() {
+        let state = SharedState::default();
+        let reloader = Reloader::new(Config::default(), Arc::clone(&state));
+        let mut reloads = reloader.subscribe();
+
+        let mut loaded = Config {
+            dns_deny: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Config::default()
+        };
+        loaded.static_records.insert(
+            "nas".into(),
+            StaticRecord {
+                ips: vec!["192.168.1.10".parse().unwrap()],
+                txt: Vec::new(),
+            },
+        );
+        loaded.topic_id = "17".repeat(32);
+        let reloaded = reloader.apply(loaded.clone()).await.unwrap();
+        assert_eq!(reloaded.applied, ["dns_deny", "static_records"]);
+        assert_eq!(reloaded.restart_required, ["topic_id"]);
+
+        assert!(reloads.has_changed().unwrap());
+        let running = reloads.borrow_and_update().clone();
+        assert_eq!(running.dns_deny, loaded.dns_deny);
+        assert_eq!(running.topic_id, Config::default().topic_id);
+        assert!(state.read().await.contains_key("nas"));
+        // Only the topic still differs.
+        let pending = changed_fields(&running, &loaded).unwrap();
+        assert_eq!(pending, ["topic_id"]);
+    }
+
+    #[tokio::test]
+    async fn an_invalid_config_changes_nothing() {
+        let reloader = Reloader::new(Config::default(), SharedState::default());
+        let mut loaded = Config {
+            dns_deny: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Config::default()
+        };
+        loaded.static_records.insert(
+            "not a label".into(),
+            StaticRecord {
+                ips: vec!["192.168.1.10".parse().unwrap()],
+                txt: Vec::new(),
+            },
+        );
+        assert!(reloader.apply(loaded).await.is_err());
+        assert!(reloader.config().dns_deny.is_empty());
+    }
+}
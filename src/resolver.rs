@@ -0,0 +1,311 @@
+//! Resolving names in-process, for Rust services that embed glued.
+//!
+//! [`GluedResolver`] answers from a registry the way the DNS server does,
+//! which looks names up through it: exact names, then the wildcard entry
+//! of the last label, in the namespace of a network named by its suffix or
+//! of the client's network, and then the shared one.  Names in the local
+//! zone are looked up without its suffix.  Outside the zone and the
+//! networks' suffixes, a wildcard only answers a single label in front of
+//! its name, so `x.example.com` is never answered for a `com` wildcard.
+//!
+//! A process already running the daemon's pieces wraps its registry.  With
+//! the `client` feature, [`GluedResolver::join`] instead joins the gossip
+//! topic as a DNS-only node of its own and keeps a registry in step.
+
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use futures_util::Stream;
+use log::warn;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::config::Config;
+use crate::names::{normalize, wildcard_key};
+use crate::networks::{Network, Networks};
+use crate::registry::Change;
+use crate::types::{Entry, SharedState, StateMap};
+use crate::views::Views;
+
+/// Looks names up in a registry.
+#[derive(Clone)]
+pub struct GluedResolver {
+    state: SharedState,
+    /// Names answering for every name below them, normalized.
+    wildcard_names: Arc<HashSet<String>>,
+    networks: Arc<Networks>,
+    views: Arc<Views>,
+}
+
+impl GluedResolver {
+    /// A resolver answering from `state`, without wildcard names, networks
+    /// or views.
+    pub fn new(state: SharedState) -> Self {
+        Self {
+            state,
+            wildcard_names: Arc::default(),
+            networks: Arc::default(),
+            views: Arc::default(),
+        }
+    }
+
+    /// A resolver answering from `state` with the wildcard names, networks
+    /// and views of `cfg`.  Fails if its networks are invalid.
+    pub fn from_config(state: SharedState, cfg: &Config) -> anyhow::Result<Self> {
+        Ok(Self::new(state)
+            .with_wildcard_names(cfg.wildcard_names.iter().map(|n| normalize(n)).collect())
+            .with_networks(Networks::from_config(cfg)?)
+            .with_views(Views::from_config(cfg)))
+    }
+
+    pub fn with_wildcard_names(self, wildcard_names: HashSet<String>) -> Self {
+        Self {
+            wildcard_names: Arc::new(wildcard_names),
+            ..self
+        }
+    }
+
+    pub fn with_networks(self, networks: Networks) -> Self {
+        Self {
+            networks: Arc::new(networks),
+            ..self
+        }
+    }
+
+    pub fn with_views(self, views: Views) -> Self {
+        Self {
+            views: Arc::new(views),
+            ..self
+        }
+    }
+
+    pub fn state(&self) -> &SharedState {
+        &self.state
+    }
+
+    pub fn networks(&self) -> &Networks {
+        &self.networks
+    }
+
+    pub fn views(&self) -> &Views {
+        &self.views
+    }
+
+    /// The addresses of `name`, as a client outside every network and
+    /// view is answered; `None` if there is no such name.
+    pub async fn lookup(&self, name: &str) -> Option<Vec<IpAddr>> {
+        let map = self.state.read().await;
+        let entry = self.entry(&map, &normalize(name), None)?;
+        Some(entry.ips().collect())
+    }
+
+    /// The addresses of `name`, as `client` is answered over DNS.
+    pub async fn lookup_for(&self, name: &str, client: IpAddr) -> Option<Vec<IpAddr>> {
+        let map = self.state.read().await;
+        let entry = self.entry(&map, &normalize(name), Some(client))?;
+        Some(entry.ips_for(self.views.scope_for(client)))
+    }
+
+    /// Changes applied to the registry from now on.  A subscriber that
+    /// falls behind misses the oldest, with a warning.
+    pub fn changes(&self) -> impl Stream<Item = Arc<Change>> + Send + 'static {
+        futures_util::stream::unfold(self.state.subscribe(), |mut changes| async move {
+            loop {
+                match changes.recv().await {
+                    Ok(change) => return Some((change, changes)),
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Resolver subscriber missed {} registry changes", missed);
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    /// The entry answering `qname` (normalized) for `client`: from the
+    /// namespace of the network its suffix names, or else from that of the
+    /// client's network and then the shared one.  Clients in a network's
+    /// subnets get nothing from other networks.
+    pub fn entry(&self, map: &StateMap, qname: &str, client: Option<IpAddr>) -> Option<Entry> {
+        self.find(map, qname, client, false)
+    }
+
+    /// [`Self::entry`] for `qname` found below the local zone, with its
+    /// suffix taken off: wildcards there answer names of any depth.
+    pub fn entry_in_zone(
+        &self,
+        map: &StateMap,
+        qname: &str,
+        client: Option<IpAddr>,
+    ) -> Option<Entry> {
+        self.find(map, qname, client, true)
+    }
+
+    fn find(
+        &self,
+        map: &StateMap,
+        qname: &str,
+        client: Option<IpAddr>,
+        in_zone: bool,
+    ) -> Option<Entry> {
+        let own = client.and_then(|client| self.networks.of_client(client));
+        if let Some((network, name)) = self.networks.split(qname) {
+            if own.is_some_and(|own| own != network) {
+                return None;
+            }
+            return self.entry_in(map, name, Some(network), true);
+        }
+        own.and_then(|own| self.entry_in(map, qname, Some(own), in_zone))
+            .or_else(|| self.entry_in(map, qname, None, in_zone))
+    }
+
+    /// The entry answering `qname` in `network`'s namespace, or the shared
+    /// one: an exact match, or else the wildcard entry of its last label.
+    /// Unless `qname` is known to be ours (`any_depth`), only one label may
+    /// come before that one, so a wildcard never claims a name that would
+    /// be forwarded, like `x.example.com` for a `com` wildcard.
+    fn entry_in(
+        &self,
+        map: &StateMap,
+        qname: &str,
+        network: Option<&Network>,
+        any_depth: bool,
+    ) -> Option<Entry> {
+        let key = |name: &str| match network {
+            Some(network) => format!("{}.{}", name, network.name),
+            None => name.to_string(),
+        };
+        if let Some(entry) = map.get(&key(qname)) {
+            return Some(entry.clone());
+        }
+        let (below, base) = qname.rsplit_once('.')?;
+        if !any_depth && below.contains('.') {
+            return None;
+        }
+        map.get(&key(&wildcard_key(base)))
+            .or_else(|| {
+                self.wildcard_names
+                    .contains(base)
+                    .then(|| map.get(&key(base)))
+                    .flatten()
+            })
+            .cloned()
+    }
+
+    /// Whether `map` has entries for names below `qname` (normalized) that
+    /// `client` would be answered from: in the shared namespace, or its
+    /// network's.
+    pub fn has_names_below(&self, map: &StateMap, qname: &str, client: IpAddr) -> bool {
+        let own = self.networks.of_client(client);
+        let suffix = format!(".{}", qname);
+        map.keys().any(|key| match self.networks.split(key) {
+            Some((network, name)) => own == Some(network) && name.ends_with(&suffix),
+            None => key.ends_with(&suffix),
+        })
+    }
+}
+
+#[cfg(feature = "client")]
+mod client {
+    use std::time::Duration;
+
+    use tokio::sync::{mpsc, watch};
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::config::NodeRole;
+    use crate::gossip::run_gossip;
+    use crate::limits::Limits;
+    use crate::registry::{ChangeOrigin, Registry};
+    use crate::status::Status;
+    use crate::types::Update;
+
+    impl GluedResolver {
+        /// Joins the cluster `cfg` describes as a DNS-only node, publishing
+        /// nothing, and answers from what its peers publish.  The task runs
+        /// gossip until it fails or is aborted; nothing restarts it.
+        pub async fn join(cfg: Config) -> anyhow::Result<(Self, JoinHandle<anyhow::Result<()>>)> {
+            let mut cfg = cfg;
+            cfg.role = NodeRole::Dns;
+            let state: SharedState = Arc::new(
+                Registry::new(cfg.update_channel_capacity, Limits::from_config(&cfg))
+                    .with_conflict_policy(cfg.conflict_policy)
+                    .with_eviction_grace(Duration::from_secs(cfg.eviction_grace_secs)),
+            );
+            let resolver = Self::from_config(Arc::clone(&state), &cfg)?;
+            let status = Arc::new(Status::new(false));
+            let task = tokio::spawn(async move {
+                // Nothing is published, but gossip stops once this closes.
+                let (_outbound_tx, mut outbound_rx) = mpsc::channel::<Update>(1);
+                let (_, mut withdrawals) = mpsc::channel(1);
+                let (inbound_tx, mut inbound_rx) =
+                    mpsc::channel(cfg.update_channel_capacity.max(1));
+                let (_reloads_tx, reloads) = watch::channel(Arc::new(cfg.clone()));
+                let apply = async {
+                    while let Some((update, node)) = inbound_rx.recv().await {
+                        state.apply(update, ChangeOrigin::Peer(node)).await;
+                    }
+                };
+                let gossip = run_gossip(
+                    cfg,
+                    &mut outbound_rx,
+                    &mut withdrawals,
+                    inbound_tx,
+                    status,
+                    Arc::clone(&state),
+                    reloads,
+                );
+                let (result, ()) = tokio::join!(gossip, apply);
+                result
+            });
+            Ok((resolver, task))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gossip::apply_update;
+    use crate::registry::LocalSource;
+    use crate::types::Update;
+    use futures_util::StreamExt;
+
+    #[tokio::test]
+    async fn names_resolve_as_over_dns() {
+        let state = SharedState::default();
+        let resolver = GluedResolver::new(Arc::clone(&state))
+            .with_wildcard_names(HashSet::from(["api".to_string()]));
+        let mut changes = Box::pin(resolver.changes());
+        for (name, ip) in [
+            ("web-1", "10.0.0.2"),
+            ("*.web", "10.0.0.3"),
+            ("api", "10.0.0.4"),
+        ] {
+            let add = Update::Add {
+                name: name.into(),
+                ip: ip.parse().unwrap(),
+            };
+            apply_update(add, LocalSource::Control, &state).await;
+        }
+
+        let ips = |ip: &str| Some(vec![ip.parse::<IpAddr>().unwrap()]);
+        assert_eq!(resolver.lookup("Web-1.").await, ips("10.0.0.2"));
+        assert_eq!(resolver.lookup("blue.web").await, ips("10.0.0.3"));
+        assert_eq!(resolver.lookup("v2.api").await, ips("10.0.0.4"));
+        assert_eq!(resolver.lookup("web-2").await, None);
+        assert_eq!(resolver.lookup("example.com").await, None);
+        // Names deeper than `<label>.<name>` are left to the upstreams.
+        let add = Update::Add {
+            name: "*.com".into(),
+            ip: "10.0.0.5".parse().unwrap(),
+        };
+        apply_update(add, LocalSource::Control, &state).await;
+        assert_eq!(resolver.lookup("x.example.com").await, None);
+        assert_eq!(resolver.lookup("a.b.web").await, None);
+
+        let change = changes.next().await.unwrap();
+        assert_eq!(change.origin.source(), "control");
+        assert!(matches!(&change.update, Update::Add { name, .. } if name == "web-1"));
+    }
+}
@@ -0,0 +1,179 @@
+//! Reconciliation and resync on demand, for `POST /v1/reconcile` and
+//! `glued ctl reconcile`.
+//!
+//! On a replica, every runtime monitor rescans its containers at once and
+//! repairs what it announced, as the periodic reconciliation does.  On
+//! every node, gossip asks its peers for their entries again, as on
+//! joining.  Requests made while a pass runs wait for the next one and
+//! share its report, so calling it repeatedly, or from several clients at
+//! once, runs one pass at a time.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex as SyncMutex;
+use std::time::Duration;
+
+use log::{info, warn};
+use serde::Serialize;
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
+
+/// How long a runtime monitor has to take a request and rescan; one that
+/// is reconnecting to its runtime may not get to it.
+const RESCAN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What one runtime monitor's rescan added or changed and removed, or why
+/// it couldn't scan.
+pub type Rescan = Result<(usize, usize), String>;
+
+/// A runtime monitor's end of the requests, answered with its rescan.
+pub type RescanRequests = mpsc::Receiver<oneshot::Sender<Rescan>>;
+
+/// What a pass did.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Report {
+    /// Entries added or changed by the rescans.
+    pub added: usize,
+    pub removed: usize,
+    /// Runtime monitors that rescanned.
+    pub rescanned: usize,
+    /// Why the others didn't.
+    pub failed: Vec<String>,
+    /// Whether gossip was running to ask its peers for a sync.
+    pub sync_requested: bool,
+}
+
+/// Where on-demand passes are requested and coalesced.
+#[derive(Debug)]
+pub struct Resync {
+    monitors: SyncMutex<Vec<mpsc::Sender<oneshot::Sender<Rescan>>>>,
+    syncs: broadcast::Sender<()>,
+    /// Passes started so far.
+    started: AtomicU64,
+    /// Held by the running pass; the number and report of the last one.
+    last: Mutex<Option<(u64, Report)>>,
+}
+
+impl Default for Resync {
+    fn default() -> Self {
+        Self {
+            monitors: SyncMutex::default(),
+            syncs: broadcast::channel(1).0,
+            started: AtomicU64::new(0),
+            last: Mutex::default(),
+        }
+    }
+}
+
+impl Resync {
+    /// Rescan requests for a runtime monitor to answer, for as long as it
+    /// keeps the receiver.
+    pub fn rescans(&self) -> RescanRequests {
+        let (tx, rx) = mpsc::channel(1);
+        let mut monitors = self.monitors.lock().unwrap();
+        monitors.retain(|monitor| !monitor.is_closed());
+        monitors.push(tx);
+        rx
+    }
+
+    /// Sync requests for gossip to send on.
+    pub fn syncs(&self) -> broadcast::Receiver<()> {
+        self.syncs.subscribe()
+    }
+
+    /// Runs a pass, or waits for the next to start and shares its report.
+    pub async fn run(&self) -> Report {
+        let arrived = self.started.load(Ordering::Relaxed);
+        let mut last = self.last.lock().await;
+        // A pass started after we came has finished while we waited.
+        if let Some((number, report)) = &*last {
+            if *number > arrived {
+                return report.clone();
+            }
+        }
+        let number = self.started.fetch_add(1, Ordering::Relaxed) + 1;
+        let report = self.pass().await;
+        info!(
+            "Reconciled on demand: {} added/changed, {} removed, {} runtime monitors rescanned",
+            report.added, report.removed, report.rescanned
+        );
+        *last = Some((number, report.clone()));
+        report
+    }
+
+    async fn pass(&self) -> Report {
+        let mut report = Report {
+            sync_requested: self.syncs.send(()).is_ok(),
+            ..Report::default()
+        };
+        let monitors = {
+            let mut monitors = self.monitors.lock().unwrap();
+            monitors.retain(|monitor| !monitor.is_closed());
+            monitors.clone()
+        };
+        let rescans = monitors.iter().map(|monitor| async move {
+            let (tx, rx) = oneshot::channel();
+            let rescan = async {
+                monitor.send(tx).await.map_err(|_| "monitor stopped")?;
+                rx.await.map_err(|_| "monitor stopped")
+            };
+            match tokio::time::timeout(RESCAN_TIMEOUT, rescan).await {
+                Ok(Ok(rescan)) => rescan,
+                Ok(Err(e)) => Err(e.to_string()),
+                Err(_) => Err("timed out waiting for the rescan".to_string()),
+            }
+        });
+        for rescan in futures_util::future::join_all(rescans).await {
+            match rescan {
+                Ok((added, removed)) => {
+                    report.added += added;
+                    report.removed += removed;
+                    report.rescanned += 1;
+                }
+                Err(e) => {
+                    warn!("Rescan on demand failed: {}", e);
+                    report.failed.push(e);
+                }
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn concurrent_requests_share_one_pass() {
+        let resync = Arc::new(Resync::default());
+        let _syncs = resync.syncs();
+        let mut rescans = resync.rescans();
+        // A monitor that has stopped answers nothing and is dropped.
+        drop(resync.rescans());
+        let monitor = tokio::spawn(async move {
+            let mut answered = 0;
+            while let Some(reply) = rescans.recv().await {
+                answered += 1;
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let _ = reply.send(Ok((answered, 1)));
+            }
+            answered
+        });
+
+        let first = tokio::spawn({
+            let resync = Arc::clone(&resync);
+            async move { resync.run().await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        // Both come while the first pass runs: one more pass serves them.
+        let (second, third) = tokio::join!(resync.run(), resync.run());
+        let first = first.await.unwrap();
+        assert_eq!((first.added, first.removed, first.rescanned), (1, 1, 1));
+        assert!(first.failed.is_empty() && first.sync_requested);
+        assert_eq!(second.added, 2);
+        assert_eq!(second, third);
+
+        drop(resync);
+        assert_eq!(monitor.await.unwrap(), 2);
+    }
+}
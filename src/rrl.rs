@@ -0,0 +1,231 @@
+//! Response rate limiting for DNS over UDP.
+//!
+//! Spoofed UDP queries turn an open resolver into an amplifier, so each
+//! client network (/24 for IPv4, /56 for IPv6) gets a token bucket.  Once it
+//! is empty, responses are dropped, except that a `slip` fraction are sent
+//! truncated so that real clients behind the network retry over TCP.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::config::Config;
+
+/// Independently locked bucket maps, so concurrent queries rarely contend.
+const SHARDS: usize = 16;
+
+/// How often each shard drops the buckets of networks that went quiet.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How fast clients are answered.
+#[derive(Debug, Clone, Copy)]
+pub struct RatePolicy {
+    /// Responses per second per client network.  Zero disables limiting.
+    pub rate: u32,
+    /// Responses a network may get in a burst beyond `rate`.
+    pub burst: u32,
+    /// Fraction of limited responses sent truncated rather than dropped.
+    pub slip: f64,
+}
+
+impl RatePolicy {
+    pub fn from_config(cfg: &Config) -> Self {
+        Self {
+            rate: cfg.dns_rrl_rate,
+            burst: cfg.dns_rrl_burst.max(1),
+            slip: cfg.dns_rrl_slip.clamp(0.0, 1.0),
+        }
+    }
+
+    /// The limit on forwarded queries, which are refused rather than
+    /// dropped or truncated.
+    pub fn for_forwarding(cfg: &Config) -> Self {
+        Self {
+            rate: cfg.dns_forward_rate,
+            burst: cfg.dns_forward_burst.max(1),
+            slip: 0.0,
+        }
+    }
+}
+
+/// What to do with one response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Answer,
+    /// Send it empty with TC set.
+    Slip,
+    Drop,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+#[derive(Debug)]
+struct Shard {
+    buckets: HashMap<IpAddr, Bucket>,
+    last_sweep: Instant,
+}
+
+impl Shard {
+    /// Forgets buckets untouched for `idle`.
+    fn sweep(&mut self, now: Instant, idle: Duration) {
+        self.last_sweep = now;
+        self.buckets
+            .retain(|_, bucket| now.duration_since(bucket.last) < idle);
+    }
+}
+
+/// Token buckets keyed by client network.
+#[derive(Debug)]
+pub struct RateLimiter {
+    policy: RatePolicy,
+    hasher: RandomState,
+    shards: Vec<Mutex<Shard>>,
+}
+
+impl RateLimiter {
+    pub fn new(policy: RatePolicy) -> Self {
+        let now = Instant::now();
+        Self {
+            policy,
+            hasher: RandomState::new(),
+            shards: (0..SHARDS)
+                .map(|_| {
+                    Mutex::new(Shard {
+                        buckets: HashMap::new(),
+                        last_sweep: now,
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.policy.rate > 0
+    }
+
+    /// Takes a token for a response to `client` at `now`.
+    pub fn check(&self, client: IpAddr, now: Instant) -> Verdict {
+        if !self.enabled() {
+            return Verdict::Answer;
+        }
+        let network = network_of(client);
+        let shard = &self.shards[self.hasher.hash_one(network) as usize % SHARDS];
+        let mut shard = shard.lock().unwrap_or_else(|e| e.into_inner());
+        if now.duration_since(shard.last_sweep) >= SWEEP_INTERVAL {
+            shard.sweep(now, self.refill_time());
+        }
+
+        let burst = f64::from(self.policy.burst);
+        let bucket = shard.buckets.entry(network).or_insert(Bucket {
+            tokens: burst,
+            last: now,
+        });
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * f64::from(self.policy.rate)).min(burst);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Verdict::Answer
+        } else if self.policy.slip > 0.0 && rand::random::<f64>() < self.policy.slip {
+            Verdict::Slip
+        } else {
+            Verdict::Drop
+        }
+    }
+
+    /// How long an untouched bucket takes to fill up again; after that it is
+    /// no different from a missing one.
+    fn refill_time(&self) -> Duration {
+        Duration::from_secs_f64(f64::from(self.policy.burst) / f64::from(self.policy.rate))
+    }
+
+    #[cfg(test)]
+    fn tracked(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().buckets.len())
+            .sum()
+    }
+}
+
+/// The /24 or /56 that `client` is limited as part of.
+fn network_of(client: IpAddr) -> IpAddr {
+    match client.to_canonical() {
+        IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip) & !0xff)),
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !((1u128 << 72) - 1))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(rate: u32, burst: u32, slip: f64) -> RateLimiter {
+        RateLimiter::new(RatePolicy { rate, burst, slip })
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn flooding_network_does_not_limit_others() {
+        let rrl = limiter(10, 5, 0.0);
+        let now = Instant::now();
+        let answered = (0..1000)
+            .filter(|_| rrl.check(ip("192.0.2.1"), now) == Verdict::Answer)
+            .count();
+        assert_eq!(answered, 5);
+        // The rest of the /24 shares the bucket.
+        assert_eq!(rrl.check(ip("192.0.2.77"), now), Verdict::Drop);
+        assert_eq!(rrl.check(ip("198.51.100.1"), now), Verdict::Answer);
+        assert_eq!(rrl.check(ip("2001:db8::1"), now), Verdict::Answer);
+
+        // Refilled at `rate`.
+        let later = now + Duration::from_millis(200);
+        assert_eq!(rrl.check(ip("192.0.2.1"), later), Verdict::Answer);
+        assert_eq!(rrl.check(ip("192.0.2.1"), later), Verdict::Answer);
+        assert_eq!(rrl.check(ip("192.0.2.1"), later), Verdict::Drop);
+    }
+
+    #[test]
+    fn slip_truncates_instead_of_dropping() {
+        let rrl = limiter(1, 1, 1.0);
+        let now = Instant::now();
+        assert_eq!(rrl.check(ip("2001:db8:0:1::1"), now), Verdict::Answer);
+        // Same /56.
+        assert_eq!(rrl.check(ip("2001:db8:0:ff::2"), now), Verdict::Slip);
+        assert_eq!(rrl.check(ip("2001:db8:0:100::1"), now), Verdict::Answer);
+    }
+
+    #[test]
+    fn disabled_by_default_and_idle_buckets_are_swept() {
+        let rrl = RateLimiter::new(RatePolicy::from_config(&Config::default()));
+        assert!(!rrl.enabled());
+        let now = Instant::now();
+        assert!((0..100).all(|_| rrl.check(ip("192.0.2.1"), now) == Verdict::Answer));
+        assert_eq!(rrl.tracked(), 0);
+
+        let rrl = limiter(10, 5, 0.0);
+        for i in 0..=255u8 {
+            rrl.check(IpAddr::V4(Ipv4Addr::new(10, 0, i, 1)), now);
+        }
+        assert_eq!(rrl.tracked(), 256);
+        // Buckets full again are dropped; one just used is kept.
+        let later = now + Duration::from_secs(1);
+        rrl.check(ip("10.0.7.1"), later);
+        for shard in &rrl.shards {
+            shard.lock().unwrap().sweep(later, rrl.refill_time());
+        }
+        assert_eq!(rrl.tracked(), 1);
+    }
+}
@@ -0,0 +1,756 @@
+//! Runtime backed by containerd's gRPC API, for hosts running nerdctl or
+//! Kubernetes instead of Docker.
+//!
+//! Containers are listed through the containers and tasks services and then
+//! followed through `/tasks/start` and `/tasks/exit` events in one namespace.
+//! containerd knows nothing about networks, so a container's address comes
+//! from its `glued.ip` label or from the result the CNI plugin cached for
+//! `network_name`.  In Kubernetes' `k8s.io` namespace a pod is published
+//! under its name through its sandbox, which owns the pod's network
+//! namespace; the pod's other containers are skipped.
+
+use super::docker::{publish, MonitorState, NodeEntry, Registration, WILDCARD_LABEL};
+use super::exclude::Exclusions;
+use super::health::HealthPolicy;
+use super::ContainerRuntime;
+use crate::config::Config;
+use crate::limits::check_name;
+use crate::log_dedup::LogDedup;
+use crate::names::NamePolicy;
+use crate::registry::{LocalSource, LocalUpdate};
+use crate::resync::Rescan;
+use crate::status::Status;
+use crate::txt;
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use log::{debug, error, info, warn, Level};
+use prost::Message;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{interval_at, sleep, Instant};
+use tonic::client::Grpc;
+use tonic::codec::ProstCodec;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::metadata::AsciiMetadataValue;
+use tonic::transport::Channel;
+use tracing::{info_span, Instrument};
+
+/// Container label giving the address to publish, overriding CNI.
+const IP_LABEL: &str = "glued.ip";
+
+/// Names set by nerdctl and by the CRI plugin, in order of preference.
+const NAME_LABELS: [&str; 2] = ["nerdctl/name", "io.kubernetes.pod.name"];
+
+/// `sandbox` or `container` on containers created through CRI.
+const CRI_KIND_LABEL: &str = "io.cri-containerd.kind";
+
+/// Request header selecting the containerd namespace.
+const NAMESPACE_HEADER: &str = "containerd-namespace";
+
+const CONTAINERS_LIST: &str = "/containerd.services.containers.v1.Containers/List";
+const TASKS_LIST: &str = "/containerd.services.tasks.v1.Tasks/List";
+const EVENTS_SUBSCRIBE: &str = "/containerd.services.events.v1.Events/Subscribe";
+
+const TOPIC_TASK_START: &str = "/tasks/start";
+const TOPIC_TASK_EXIT: &str = "/tasks/exit";
+
+/// The subset of containerd's API messages glued uses.  Field tags follow
+/// containerd's `api/` protos; unknown fields are skipped when decoding.
+mod proto {
+    use std::collections::HashMap;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Container {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(map = "string, string", tag = "2")]
+        pub labels: HashMap<String, String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListContainersRequest {
+        #[prost(string, repeated, tag = "1")]
+        pub filters: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListContainersResponse {
+        #[prost(message, repeated, tag = "1")]
+        pub containers: Vec<Container>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListTasksRequest {
+        #[prost(string, tag = "1")]
+        pub filter: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListTasksResponse {
+        #[prost(message, repeated, tag = "1")]
+        pub tasks: Vec<Process>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Process {
+        #[prost(string, tag = "1")]
+        pub container_id: String,
+        #[prost(string, tag = "2")]
+        pub id: String,
+        #[prost(uint32, tag = "3")]
+        pub pid: u32,
+        /// `containerd.v1.types.Status`.
+        #[prost(int32, tag = "4")]
+        pub status: i32,
+    }
+
+    /// `Status.RUNNING`.
+    pub const STATUS_RUNNING: i32 = 2;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SubscribeRequest {
+        #[prost(string, repeated, tag = "1")]
+        pub filters: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Envelope {
+        #[prost(message, optional, tag = "1")]
+        pub timestamp: Option<prost_types::Timestamp>,
+        #[prost(string, tag = "2")]
+        pub namespace: String,
+        #[prost(string, tag = "3")]
+        pub topic: String,
+        #[prost(message, optional, tag = "4")]
+        pub event: Option<prost_types::Any>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TaskStart {
+        #[prost(string, tag = "1")]
+        pub container_id: String,
+        #[prost(uint32, tag = "2")]
+        pub pid: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TaskExit {
+        #[prost(string, tag = "1")]
+        pub container_id: String,
+        #[prost(string, tag = "2")]
+        pub id: String,
+        #[prost(uint32, tag = "3")]
+        pub pid: u32,
+        #[prost(uint32, tag = "4")]
+        pub exit_status: u32,
+    }
+}
+
+/// A task event that matters for registration.
+#[derive(Debug, PartialEq, Eq)]
+enum TaskEvent {
+    Start(String),
+    Exit(String),
+}
+
+impl TaskEvent {
+    /// The event in `envelope`, if it is a task start or the exit of a
+    /// container's init process in `namespace`.
+    fn decode(envelope: &proto::Envelope, namespace: &str) -> Option<Self> {
+        if envelope.namespace != namespace {
+            return None;
+        }
+        let event = envelope.event.as_ref()?;
+        let decoded = match envelope.topic.as_str() {
+            TOPIC_TASK_START => proto::TaskStart::decode(event.value.as_slice())
+                .map(|e| Some(Self::Start(e.container_id))),
+            // An exec'd process exiting leaves the container running.
+            TOPIC_TASK_EXIT => proto::TaskExit::decode(event.value.as_slice())
+                .map(|e| (e.id == e.container_id).then_some(Self::Exit(e.container_id))),
+            _ => return None,
+        };
+        decoded.unwrap_or_else(|e| {
+            warn!("Undecodable {} event: {}", envelope.topic, e);
+            None
+        })
+    }
+}
+
+pub struct ContainerdRuntime {
+    network_name: String,
+    socket: PathBuf,
+    namespace: String,
+    namespace_header: AsciiMetadataValue,
+    cni_results_dir: PathBuf,
+    exclusions: Exclusions,
+    name_policy: NamePolicy,
+    name_replace_chars: String,
+    reconcile_interval: Duration,
+    /// Seconds announced entries stay valid without a renewal.
+    lease: Option<u64>,
+    /// Told when the first scan is in.
+    status: Arc<Status>,
+    health: HealthPolicy,
+    node_entry: Option<NodeEntry>,
+}
+
+/// A connection to containerd, with every request scoped to the namespace.
+struct Client {
+    grpc: Grpc<Channel>,
+    namespace: AsciiMetadataValue,
+}
+
+impl Client {
+    #[cfg(unix)]
+    async fn connect(socket: &Path, namespace: AsciiMetadataValue) -> Result<Self> {
+        use hyper_util::rt::TokioIo;
+        use tokio::net::UnixStream;
+        use tonic::transport::{Endpoint, Uri};
+
+        let socket = socket.to_path_buf();
+        // The endpoint URI is required but unused: every connection goes to the socket.
+        let channel = Endpoint::from_static("http://containerd")
+            .connect_with_connector(tower::service_fn(move |_: Uri| {
+                let socket = socket.clone();
+                async move { UnixStream::connect(socket).await.map(TokioIo::new) }
+            }))
+            .await?;
+        Ok(Self {
+            grpc: Grpc::new(channel),
+            namespace,
+        })
+    }
+
+    #[cfg(not(unix))]
+    async fn connect(_socket: &Path, _namespace: AsciiMetadataValue) -> Result<Self> {
+        anyhow::bail!("The containerd runtime is only supported on Unix")
+    }
+
+    fn request<T>(&self, message: T) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        request
+            .metadata_mut()
+            .insert(NAMESPACE_HEADER, self.namespace.clone());
+        request
+    }
+
+    async fn unary<Req, Resp>(&mut self, path: &'static str, message: Req) -> Result<Resp>
+    where
+        Req: Message + Send + Sync + 'static,
+        Resp: Message + Default + Send + Sync + 'static,
+    {
+        self.grpc.ready().await?;
+        let request = self.request(message);
+        let response = self
+            .grpc
+            .unary(
+                request,
+                PathAndQuery::from_static(path),
+                ProstCodec::default(),
+            )
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    async fn subscribe(
+        &mut self,
+        filters: Vec<String>,
+    ) -> Result<tonic::Streaming<proto::Envelope>> {
+        self.grpc.ready().await?;
+        let request = self.request(proto::SubscribeRequest { filters });
+        let response = self
+            .grpc
+            .server_streaming(
+                request,
+                PathAndQuery::from_static(EVENTS_SUBSCRIBE),
+                ProstCodec::default(),
+            )
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    async fn containers(&mut self, filters: Vec<String>) -> Result<Vec<proto::Container>> {
+        let response: proto::ListContainersResponse = self
+            .unary(CONTAINERS_LIST, proto::ListContainersRequest { filters })
+            .await?;
+        Ok(response.containers)
+    }
+
+    /// IDs of the containers whose task is running.
+    async fn running(&mut self) -> Result<HashSet<String>> {
+        let response: proto::ListTasksResponse = self
+            .unary(TASKS_LIST, proto::ListTasksRequest::default())
+            .await?;
+        Ok(response
+            .tasks
+            .into_iter()
+            .filter(|task| task.status == proto::STATUS_RUNNING)
+            .map(|task| task.container_id)
+            .collect())
+    }
+}
+
+impl ContainerdRuntime {
+    /// Creates the runtime, validating the settings up front.  containerd
+    /// itself is only contacted by `check` and `monitor`.
+    pub fn new(network_name: String, cfg: &Config) -> Result<Self> {
+        let namespace_header = cfg.containerd_namespace.parse().map_err(|_| {
+            anyhow!(
+                "Invalid containerd namespace '{}'",
+                cfg.containerd_namespace
+            )
+        })?;
+        let exclusions = Exclusions::from_config(cfg)?;
+        if let Some(id) = exclusions.self_id() {
+            info!("Running in container {}; it will not be registered", id);
+        }
+        info!(
+            "Using containerd at {} (namespace '{}')",
+            cfg.containerd_socket, cfg.containerd_namespace
+        );
+
+        Ok(Self {
+            network_name,
+            socket: PathBuf::from(&cfg.containerd_socket),
+            namespace: cfg.containerd_namespace.clone(),
+            namespace_header,
+            cni_results_dir: PathBuf::from(&cfg.cni_results_dir),
+            exclusions,
+            name_policy: cfg.name_policy,
+            name_replace_chars: cfg.name_replace_chars.clone(),
+            // A zero interval would make `interval_at` panic; clamp to one second.
+            reconcile_interval: Duration::from_secs(cfg.reconcile_interval_secs.max(1)),
+            lease: cfg.entry_lease(),
+            status: Arc::default(),
+            health: HealthPolicy::from_config(cfg)?,
+            node_entry: NodeEntry::from_config(cfg),
+        })
+    }
+
+    /// Reports the first scan to `status`.
+    pub fn reporting_to(self, status: Arc<Status>) -> Self {
+        Self { status, ..self }
+    }
+
+    async fn connect(&self) -> Result<Client> {
+        Client::connect(&self.socket, self.namespace_header.clone())
+            .await
+            .with_context(|| format!("containerd at {} is not reachable", self.socket.display()))
+    }
+
+    /// Fails unless containerd answers.  `monitor` itself retries indefinitely.
+    pub async fn check(&self) -> Result<()> {
+        let mut client = self.connect().await?;
+        let containers = client
+            .containers(Vec::new())
+            .await
+            .with_context(|| format!("containerd at {} is not reachable", self.socket.display()))?;
+        info!(
+            "Monitoring containerd namespace '{}' ({} containers) on network '{}'",
+            self.namespace,
+            containers.len(),
+            self.network_name
+        );
+        Ok(())
+    }
+
+    async fn get_initial_state(
+        &self,
+        client: &mut Client,
+    ) -> Result<HashMap<String, Registration>> {
+        let running = client.running().await?;
+        let mut map = HashMap::new();
+        for container in client.containers(Vec::new()).await? {
+            if !running.contains(&container.id) {
+                continue;
+            }
+            if let Some(reg) = self.registration_for(&container) {
+                map.insert(container_name(&container).to_string(), reg);
+            }
+        }
+        if let Some(node) = &self.node_entry {
+            let found = node
+                .self_id()
+                .and_then(|id| cni_ip(&self.cni_results_dir, &self.network_name, id));
+            if let Some(reg) = node.registration(found) {
+                map.insert(node.name().to_string(), reg);
+            }
+        }
+        Ok(map)
+    }
+
+    /// Scans the running containers and repairs what was announced, as
+    /// the reconcile timer and on-demand rescans do.
+    async fn rescan(
+        &self,
+        client: &mut Client,
+        state: &mut MonitorState,
+        update_tx: &mpsc::Sender<LocalUpdate>,
+    ) -> Result<Rescan> {
+        let observed = match self.get_initial_state(client).await {
+            Ok(observed) => observed,
+            Err(e) => {
+                warn!("Reconciliation scan failed: {}", e);
+                return Ok(Err(format!("scan of {} failed: {}", self.network_name, e)));
+            }
+        };
+        let (added, removed) = state.reconcile(observed, update_tx).await?;
+        if added + removed > 0 {
+            warn!(
+                "Reconciliation repaired drift: {} added/changed, {} removed",
+                added, removed
+            );
+        } else {
+            debug!("Reconciliation found no drift");
+        }
+        Ok(Ok((added, removed)))
+    }
+
+    async fn handle_event(
+        &self,
+        client: &mut Client,
+        event: TaskEvent,
+        state: &mut MonitorState,
+        update_tx: &mpsc::Sender<LocalUpdate>,
+    ) -> Result<()> {
+        match event {
+            TaskEvent::Start(id) => {
+                let filter = format!("id=={}", id);
+                let container = match client.containers(vec![filter]).await {
+                    Ok(containers) => containers.into_iter().next(),
+                    Err(e) => {
+                        warn!("Failed to look up container {}: {}", id, e);
+                        return Ok(());
+                    }
+                };
+                let Some(container) = container else {
+                    debug!("Container {} is gone", id);
+                    return Ok(());
+                };
+                let Some(reg) = self.registration_for(&container) else {
+                    return Ok(());
+                };
+                let name = container_name(&container);
+                info!("Container started: {} -> {} as {}", name, reg.ip, reg.name);
+                publish(
+                    update_tx,
+                    LocalSource::ContainerdEvent,
+                    state.register(name, reg),
+                )
+                .await
+            }
+            TaskEvent::Exit(id) => {
+                let Some(name) = state.announced.name_of(&id) else {
+                    return Ok(());
+                };
+                info!("Container stopped: {}", name);
+                let update = state.unregister(&name, Some(&id));
+                publish(update_tx, LocalSource::ContainerdEvent, update).await
+            }
+        }
+    }
+
+    /// The registration a running container should have, if any: it must
+    /// not be excluded and must have an address on the monitored network.
+    fn registration_for(&self, container: &proto::Container) -> Option<Registration> {
+        let labels = &container.labels;
+        if labels
+            .get(CRI_KIND_LABEL)
+            .is_some_and(|kind| kind == "container")
+        {
+            // Published through its pod's sandbox, which holds the address.
+            return None;
+        }
+        let name = container_name(container);
+        if let Some(reason) = self.exclusions.reason(&container.id, name, Some(labels)) {
+            debug!("Not registering {}: {}", name, reason);
+            return None;
+        }
+
+        let ip = match labels.get(IP_LABEL) {
+            Some(ip) => match ip.parse::<IpAddr>() {
+                Ok(ip) => ip,
+                Err(e) => {
+                    warn!(
+                        "Not registering {}: invalid {} '{}': {}",
+                        name, IP_LABEL, ip, e
+                    );
+                    return None;
+                }
+            },
+            None => {
+                let Some(ip) = cni_ip(&self.cni_results_dir, &self.network_name, &container.id)
+                else {
+                    debug!(
+                        "Not registering {}: no address on network '{}'",
+                        name, self.network_name
+                    );
+                    return None;
+                };
+                ip
+            }
+        };
+
+        let Some(published) = self.name_policy.apply(name, &self.name_replace_chars) else {
+            warn!(
+                "Not registering {}: not a valid DNS label under the {:?} name policy",
+                name, self.name_policy
+            );
+            return None;
+        };
+        // What every node would drop anyway is better reported here.
+        if let Err(reason) = check_name(&published) {
+            warn!("Not registering {}: {}", name, reason);
+            return None;
+        }
+
+        Some(Registration {
+            id: container.id.clone(),
+            name: published,
+            ip,
+            wildcard: labels.get(WILDCARD_LABEL).is_some_and(|v| v == "true"),
+            ports: Vec::new(),
+            host_ips: Vec::new(),
+            health: self.health.probe_for(name, Some(labels)),
+            txt: txt::from_labels(name, labels),
+        })
+    }
+}
+
+#[async_trait]
+impl ContainerRuntime for ContainerdRuntime {
+    async fn monitor(&self, update_tx: mpsc::Sender<LocalUpdate>) -> Result<()> {
+        // Kept across reconnects so that reconciliation can diff against what
+        // this node has announced so far.
+        let mut state = MonitorState::leasing(self.lease).probing(self.health.clone());
+        // Answered once connected; until then they wait, up to their timeout.
+        let mut rescans = self.status.resync().rescans();
+        // Why it's down, logged once a minute while it stays so.
+        let mut failures = LogDedup::new(module_path!(), Level::Error);
+
+        loop {
+            let mut client = match self.connect().await {
+                Ok(client) => client,
+                Err(e) => {
+                    failures.log((), format!("{:#}. Retrying in 5s...", e));
+                    sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            };
+
+            // Subscribe before the initial scan so that nothing starting in
+            // between is missed.
+            let filters = [TOPIC_TASK_START, TOPIC_TASK_EXIT]
+                .iter()
+                .map(|topic| format!("namespace==\"{}\",topic==\"{}\"", self.namespace, topic))
+                .collect();
+            let mut events = match client.subscribe(filters).await {
+                Ok(events) => events,
+                Err(e) => {
+                    failures.log(
+                        (),
+                        format!(
+                            "Failed to subscribe to containerd events: {}. Retrying in 5s...",
+                            e
+                        ),
+                    );
+                    sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            };
+
+            match self.get_initial_state(&mut client).await {
+                Ok(initial_map) => {
+                    info!("Initial scan found {} containers", initial_map.len());
+                    state.reconcile(initial_map, &update_tx).await?;
+                    self.status.set_scanned();
+                    if failures.recovered(&()) {
+                        info!("containerd connection restored");
+                    }
+                }
+                Err(e) => {
+                    failures.log((), format!("Failed initial scan: {}. Retrying...", e));
+                    sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            }
+
+            let mut reconcile_timer = interval_at(
+                Instant::now() + self.reconcile_interval,
+                self.reconcile_interval,
+            );
+
+            info!("Listening for containerd events...");
+            loop {
+                tokio::select! {
+                    msg = events.message() => {
+                        let envelope = match msg {
+                            Ok(Some(envelope)) => envelope,
+                            Ok(None) => break,
+                            Err(e) => {
+                                error!("Error in containerd event stream: {}", e);
+                                break; // Break inner loop to reconnect
+                            }
+                        };
+                        if let Some(event) = TaskEvent::decode(&envelope, &self.namespace) {
+                            let span = info_span!(
+                                target: "glued::runtime",
+                                "containerd_event",
+                                topic = %envelope.topic,
+                            );
+                            debug!("Task event: {:?}", event);
+                            self.handle_event(&mut client, event, &mut state, &update_tx)
+                                .instrument(span)
+                                .await?;
+                        }
+                    }
+                    update = state.health_update() => {
+                        publish(&update_tx, LocalSource::HealthCheck, update).await?;
+                    }
+                    _ = reconcile_timer.tick() => {
+                        // A failed scan is logged; the next tick tries again.
+                        let _ = self.rescan(&mut client, &mut state, &update_tx).await?;
+                    }
+                    Some(reply) = rescans.recv() => {
+                        info!("Rescanning on request");
+                        let rescan = self.rescan(&mut client, &mut state, &update_tx).await?;
+                        let _ = reply.send(rescan);
+                        reconcile_timer.reset();
+                    }
+                }
+            }
+
+            warn!("containerd event stream ended. Reconnecting in 2s...");
+            sleep(Duration::from_secs(2)).await;
+        }
+    }
+}
+
+/// The name a container is published under: nerdctl's name, the pod name
+/// for a Kubernetes sandbox, or else its ID.
+fn container_name(container: &proto::Container) -> &str {
+    NAME_LABELS
+        .iter()
+        .find_map(|label| container.labels.get(*label))
+        .filter(|name| !name.is_empty())
+        .unwrap_or(&container.id)
+}
+
+/// The address the CNI plugin gave container `id` on `network`, from the
+/// result cached in `dir` as `<network>-<id>-<ifname>`.
+fn cni_ip(dir: &Path, network: &str, id: &str) -> Option<IpAddr> {
+    let prefix = format!("{}-{}-", network, id);
+    let entries = std::fs::read_dir(dir).ok()?;
+    entries
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+        .find_map(|entry| cni_result_ip(&std::fs::read_to_string(entry.path()).ok()?))
+}
+
+#[derive(Deserialize)]
+struct CniCache {
+    result: CniResult,
+}
+
+#[derive(Deserialize)]
+struct CniResult {
+    #[serde(default)]
+    ips: Vec<CniIp>,
+}
+
+#[derive(Deserialize)]
+struct CniIp {
+    /// In CIDR form, e.g. `10.4.0.2/24`.
+    address: String,
+}
+
+/// The address in a cached CNI result, preferring IPv4 like the Docker runtime.
+fn cni_result_ip(text: &str) -> Option<IpAddr> {
+    let cache: CniCache = serde_json::from_str(text).ok()?;
+    let ips: Vec<IpAddr> = cache
+        .result
+        .ips
+        .iter()
+        .filter_map(|ip| ip.address.split('/').next()?.parse().ok())
+        .collect();
+    ips.iter().find(|ip| ip.is_ipv4()).or(ips.first()).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(namespace: &str, topic: &str, event: impl Message) -> proto::Envelope {
+        proto::Envelope {
+            timestamp: None,
+            namespace: namespace.into(),
+            topic: topic.into(),
+            event: Some(prost_types::Any {
+                type_url: String::new(),
+                value: event.encode_to_vec(),
+            }),
+        }
+    }
+
+    #[test]
+    fn task_events_are_decoded_for_the_namespace() {
+        let start = proto::TaskStart {
+            container_id: "abc".into(),
+            pid: 42,
+        };
+        let exit = |id: &str| proto::TaskExit {
+            container_id: "abc".into(),
+            id: id.into(),
+            pid: 42,
+            exit_status: 0,
+        };
+        let decode = |envelope| TaskEvent::decode(&envelope, "default");
+
+        assert_eq!(
+            decode(envelope("default", TOPIC_TASK_START, start.clone())),
+            Some(TaskEvent::Start("abc".into()))
+        );
+        assert_eq!(
+            decode(envelope("default", TOPIC_TASK_EXIT, exit("abc"))),
+            Some(TaskEvent::Exit("abc".into()))
+        );
+        assert_eq!(
+            decode(envelope("default", TOPIC_TASK_EXIT, exit("exec-1"))),
+            None
+        );
+        assert_eq!(
+            decode(envelope("k8s.io", TOPIC_TASK_START, start.clone())),
+            None
+        );
+        assert_eq!(
+            decode(envelope("default", "/containers/create", start)),
+            None
+        );
+    }
+
+    #[test]
+    fn cni_results_give_the_address_on_the_network() {
+        let dir = std::env::temp_dir().join(format!("glued-cni-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let result = r#"{"kind":"cniCacheV1","containerId":"abc","ifName":"eth0",
+            "networkName":"bridge","result":{"cniVersion":"1.0.0",
+            "ips":[{"interface":2,"address":"fd00::2/64"},{"interface":2,"address":"10.4.0.2/24","gateway":"10.4.0.1"}]}}"#;
+        std::fs::write(dir.join("bridge-abc-eth0"), result).unwrap();
+
+        assert_eq!(cni_ip(&dir, "bridge", "abc"), "10.4.0.2".parse().ok());
+        assert_eq!(cni_ip(&dir, "other", "abc"), None);
+        assert_eq!(cni_ip(&dir, "bridge", "abcd"), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let pod = proto::Container {
+            id: "abc".into(),
+            labels: HashMap::from([("io.kubernetes.pod.name".into(), "web-0".into())]),
+        };
+        assert_eq!(container_name(&pod), "web-0");
+    }
+}
@@ -1,36 +1,585 @@
+use super::docker_host::DockerHost;
+use super::exclude::{detect_self_container_id, Exclusions};
+use super::health::{HealthChecks, HealthPolicy, Probe, Transition};
 use super::ContainerRuntime;
-use crate::types::Update;
+use crate::cluster_info;
+use crate::config::{Config, PortReport};
+use crate::limits::check_name;
+use crate::log_dedup::LogDedup;
+use crate::metrics::{self, METRICS};
+use crate::names::{wildcard_key, NamePolicy};
+use crate::registry::{LocalSource, LocalUpdate};
+use crate::resync::Rescan;
+use crate::status::Status;
+use crate::txt;
+use crate::types::{Port, ScopedIp, Update};
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use bollard::container::ListContainersOptions;
+use bollard::models::{
+    ContainerInspectResponse, EventMessage, EventMessageTypeEnum, HealthStatusEnum,
+};
+use bollard::network::ListNetworksOptions;
 use bollard::system::EventsOptions;
 use bollard::Docker;
 use futures_util::stream::StreamExt;
-use log::{debug, error, info, warn};
-use std::collections::HashMap;
+use log::{debug, error, info, warn, Level};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::mpsc;
-use tokio::time::sleep;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::{interval_at, sleep, sleep_until, Instant};
+use tracing::{info_span, Instrument};
 
 pub struct DockerRuntime {
     network_name: String,
+    /// Wait for the network to be created rather than fail the check.
+    network_wait: bool,
+    host: DockerHost,
+    exclusions: Exclusions,
+    name_policy: NamePolicy,
+    name_replace_chars: String,
+    reconcile_interval: Duration,
+    debounce: Duration,
+    flap_restart_limit: usize,
+    flap_window: Duration,
+    require_healthy: bool,
+    port_report: PortReport,
+    /// Published for ports bound on every interface.
+    host_address: Option<IpAddr>,
+    /// Event actions subscribed to.
+    events: Vec<String>,
+    inspect_max_inflight: usize,
+    inspect_timeout: Duration,
+    /// Withdraw this node's entries once Docker has been unreachable this long.
+    clear_on_disconnect: Option<Duration>,
+    /// Seconds announced entries stay valid without a renewal.
+    lease: Option<u64>,
+    /// Told when the first scan is in.
+    status: Arc<Status>,
+    health: HealthPolicy,
+    node_entry: Option<NodeEntry>,
+}
+
+/// How often a send blocked on a full update channel is reported.
+const FULL_CHANNEL_WARN: Duration = Duration::from_secs(5);
+
+/// Container label that opts a single container into health-gated registration.
+const REQUIRE_HEALTHY_LABEL: &str = "glued.require_healthy";
+
+/// Container label that makes every name below the container's resolve to it.
+pub(super) const WILDCARD_LABEL: &str = "glued.wildcard";
+
+/// Scope of the addresses a container's published ports are reachable on.
+pub const HOST_SCOPE: &str = "host";
+
+/// A running container as published: its ID, the DNS name it is published
+/// under and its address on the monitored network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Registration {
+    pub(super) id: String,
+    pub(super) name: String,
+    pub(super) ip: IpAddr,
+    /// Also published as `*.<name>`.
+    pub(super) wildcard: bool,
+    pub(super) ports: Vec<Port>,
+    /// Where its published ports are reachable, as the `host` scope.
+    pub(super) host_ips: Vec<IpAddr>,
+    /// How its address is probed, if it is.
+    pub(super) health: Option<Probe>,
+    /// TXT strings from its `glued.txt` labels.
+    pub(super) txt: Vec<String>,
+}
+
+/// The entry a replica publishes for itself; see [`crate::cluster_info`].
+#[derive(Debug)]
+pub(super) struct NodeEntry {
+    name: String,
+    advertise_ip: Option<IpAddr>,
+    /// The container glued runs in, whose address is published unless
+    /// one is advertised.
+    self_id: Option<String>,
+    /// Whether having no address to publish was warned about.
+    warned: AtomicBool,
+}
+
+impl NodeEntry {
+    /// `None` when `node_entry` is off.
+    pub(super) fn from_config(cfg: &Config) -> Option<Self> {
+        let name = cluster_info::node_entry_name(cfg)?;
+        let self_id = match cfg.advertise_ip {
+            Some(_) => None,
+            None => cfg
+                .self_container_id
+                .clone()
+                .or_else(detect_self_container_id),
+        };
+        Some(Self {
+            name,
+            advertise_ip: cfg.advertise_ip,
+            self_id,
+            warned: AtomicBool::new(false),
+        })
+    }
+
+    pub(super) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The container whose address to look up, when none is advertised.
+    pub(super) fn self_id(&self) -> Option<&str> {
+        self.self_id.as_deref()
+    }
+
+    /// The registration to publish, at the advertised address or else at
+    /// `found`.  Without either, there is none; that is warned about once.
+    pub(super) fn registration(&self, found: Option<IpAddr>) -> Option<Registration> {
+        let Some(ip) = self.advertise_ip.or(found) else {
+            if !self.warned.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "Not publishing {}: glued has no address on the monitored network; set advertise_ip",
+                    self.name
+                );
+            }
+            return None;
+        };
+        Some(Registration {
+            id: self.self_id.clone().unwrap_or_default(),
+            name: self.name.clone(),
+            ip,
+            wildcard: false,
+            ports: Vec::new(),
+            host_ips: Vec::new(),
+            health: None,
+            txt: Vec::new(),
+        })
+    }
+}
+
+/// Registrations this node has announced, keyed by container name.
+///
+/// Tracking the container ID behind each name lets late events from a
+/// replaced container (same name, new ID) be told apart from events for the
+/// container currently registered.
+#[derive(Debug, Default)]
+pub(super) struct Announced(HashMap<String, Registration>);
+
+impl Announced {
+    pub(super) fn get(&self, name: &str) -> Option<&Registration> {
+        self.0.get(name)
+    }
+
+    /// The name `id` is registered under, if it is.
+    pub(super) fn name_of(&self, id: &str) -> Option<String> {
+        self.0
+            .iter()
+            .find(|(_, reg)| reg.id == id)
+            .map(|(name, _)| name.clone())
+    }
+
+    /// Records `reg` under `name`, returning the `Add` to publish if anything
+    /// changed.  A new address is published as a plain `Add`, which replaces
+    /// the old one everywhere without a `Remove` in between.
+    pub(super) fn register(&mut self, name: &str, reg: Registration) -> Option<Update> {
+        let previous = self.0.get(name);
+        if previous == Some(&reg) {
+            return None;
+        }
+        let mut updates = vec![Update::Add {
+            name: reg.name.clone(),
+            ip: reg.ip,
+        }];
+        let scoped_ips: Vec<ScopedIp> = reg
+            .host_ips
+            .iter()
+            .map(|&ip| ScopedIp {
+                scope: HOST_SCOPE.into(),
+                ip,
+            })
+            .collect();
+        if !scoped_ips.is_empty() {
+            updates.push(Update::ScopedIps {
+                name: reg.name.clone(),
+                ips: scoped_ips.clone(),
+            });
+        }
+        if reg.wildcard {
+            updates.push(Update::Add {
+                name: wildcard_key(&reg.name),
+                ip: reg.ip,
+            });
+            if !scoped_ips.is_empty() {
+                updates.push(Update::ScopedIps {
+                    name: wildcard_key(&reg.name),
+                    ips: scoped_ips,
+                });
+            }
+        } else if let Some(previous) = previous.filter(|p| p.wildcard) {
+            updates.push(Update::Remove {
+                name: wildcard_key(&previous.name),
+            });
+        }
+        if !reg.ports.is_empty() {
+            updates.push(Update::Ports {
+                name: reg.name.clone(),
+                ports: reg.ports.clone(),
+            });
+        }
+        if !reg.txt.is_empty() {
+            updates.push(Update::Txt {
+                name: reg.name.clone(),
+                txt: reg.txt.clone(),
+            });
+        }
+        self.0.insert(name.to_string(), reg);
+        Some(Update::batch(updates))
+    }
+
+    /// Forgets `name`, returning the `Remove` to publish.  With `id` set the
+    /// entry is only dropped while it still belongs to that container.
+    pub(super) fn unregister(&mut self, name: &str, id: Option<&str>) -> Option<Update> {
+        let current = self.0.get(name)?;
+        if id.is_some_and(|id| id != current.id) {
+            debug!(
+                "Not removing {}: registered to container {}, not {}",
+                name,
+                current.id,
+                id.unwrap_or_default()
+            );
+            return None;
+        }
+        // Remove under the name the entry was published as.
+        let removed = self.0.remove(name)?;
+        let mut updates = Vec::new();
+        if removed.wildcard {
+            updates.push(Update::Remove {
+                name: wildcard_key(&removed.name),
+            });
+        }
+        updates.push(Update::Remove { name: removed.name });
+        Some(Update::batch(updates))
+    }
+}
+
+/// A Docker event as it affects registration.
+#[derive(Debug, PartialEq, Eq)]
+enum DockerEvent {
+    Start {
+        name: String,
+        id: String,
+    },
+    /// Anything after which the container's state must be looked at again.
+    Changed {
+        action: String,
+        name: String,
+        id: String,
+    },
+    /// The container was removed.
+    Destroy {
+        name: String,
+        id: String,
+    },
+    Rename {
+        old: String,
+        name: String,
+        id: String,
+    },
+    /// A container was attached to or detached from the monitored network.
+    Network {
+        action: String,
+        container: String,
+    },
+}
+
+/// Whether `name` is registered to a container other than `id`, making an
+/// event for `id` a late one from a container since replaced under that name.
+fn is_replaced(state: &MonitorState, name: &str, id: &str) -> bool {
+    state
+        .announced
+        .get(name)
+        .is_some_and(|current| current.id != id)
+}
+
+/// Book-keeping the monitor carries across events and reconnects.
+#[derive(Default)]
+pub(super) struct MonitorState {
+    /// Entries this node has announced.
+    pub(super) announced: Announced,
+    /// Containers with events waiting for their debounce window to close.
+    pending: HashMap<String, Instant>,
+    /// Recent start times per container, used for flap detection.
+    starts: HashMap<String, VecDeque<Instant>>,
+    /// Containers withheld from registration because they are flapping.
+    suppressed: HashSet<String>,
+    /// The latest event or inspect per container, numbered from `sequence`.
+    /// Inspect results carrying an older number are dropped, so that a slow
+    /// inspect can't undo what a later event did.
+    latest: HashMap<String, u64>,
+    sequence: u64,
+    /// Seconds each announced entry stays valid without a renewal.
+    lease: Option<u64>,
+    /// Probes of the containers announced, and of those withdrawn.
+    health: HealthChecks,
+    /// Containers withdrawn for failing their probes, published again once
+    /// they pass.
+    unhealthy: HashMap<String, Registration>,
+}
+
+impl MonitorState {
+    /// A fresh state that leases what it announces for `lease` seconds.
+    pub(super) fn leasing(lease: Option<u64>) -> Self {
+        Self {
+            lease,
+            ..Self::default()
+        }
+    }
+
+    /// Probes containers as `policy` says.
+    pub(super) fn probing(self, policy: HealthPolicy) -> Self {
+        Self {
+            health: HealthChecks::new(policy),
+            ..self
+        }
+    }
+
+    /// Marks a new event or inspect for `name`, superseding earlier ones.
+    fn stamp(&mut self, name: &str) -> u64 {
+        self.sequence += 1;
+        self.latest.insert(name.to_string(), self.sequence);
+        self.sequence
+    }
+
+    fn is_latest(&self, name: &str, token: u64) -> bool {
+        self.latest.get(name) == Some(&token)
+    }
+
+    /// Records `reg` like [`Announced::register`], leasing the names the
+    /// update adds, and probes it if it has a probe.  A container withdrawn
+    /// for failing its probes stays withdrawn until it passes, unless it
+    /// moved to another address.
+    pub(super) fn register(&mut self, name: &str, reg: Registration) -> Option<Update> {
+        if let Some(withdrawn) = self.unhealthy.get_mut(name) {
+            if withdrawn.ip == reg.ip && withdrawn.health == reg.health {
+                *withdrawn = reg;
+                return None;
+            }
+            self.unhealthy.remove(name);
+        }
+        match &reg.health {
+            Some(probe) => self.health.track(name, reg.ip, probe),
+            None => self.health.forget(name),
+        }
+        let update = self.announced.register(name, reg)?;
+        let Some(valid_for_secs) = self.lease else {
+            return Some(update);
+        };
+        let updates = match update {
+            Update::Batch(updates) => updates,
+            update => vec![update],
+        };
+        let mut leased = Vec::with_capacity(updates.len() * 2);
+        for update in updates {
+            let added = match &update {
+                Update::Add { name, .. } => Some(name.clone()),
+                _ => None,
+            };
+            leased.push(update);
+            if let Some(name) = added {
+                leased.push(Update::Lease {
+                    name,
+                    valid_for_secs,
+                });
+            }
+        }
+        Some(Update::batch(leased))
+    }
+
+    /// Forgets `name` like [`Announced::unregister`], and stops probing it.
+    /// Nothing is published for a container already withdrawn.
+    pub(super) fn unregister(&mut self, name: &str, id: Option<&str>) -> Option<Update> {
+        if let Some(withdrawn) = self.unhealthy.get(name) {
+            if id.is_none_or(|id| id == withdrawn.id) {
+                self.unhealthy.remove(name);
+                self.health.forget(name);
+            }
+            return None;
+        }
+        let update = self.announced.unregister(name, id)?;
+        self.health.forget(name);
+        Some(update)
+    }
+
+    /// Waits for a container to start or stop failing its probes, returning
+    /// the update that withdraws or publishes it again.
+    pub(super) async fn health_update(&mut self) -> Option<Update> {
+        match self.health.next().await {
+            Transition::Down(name) => {
+                let reg = self.announced.get(&name)?.clone();
+                warn!(
+                    "{} at {} failed {} health probes in a row; withdrawing it",
+                    name,
+                    reg.ip,
+                    self.health.policy().fall
+                );
+                metrics::inc(&METRICS.health_withdrawn);
+                let update = self.announced.unregister(&name, None);
+                self.unhealthy.insert(name, reg);
+                update
+            }
+            Transition::Up(name) => {
+                let reg = self.unhealthy.remove(&name)?;
+                info!(
+                    "{} at {} passed {} health probes in a row; publishing it again",
+                    name,
+                    reg.ip,
+                    self.health.policy().rise
+                );
+                metrics::inc(&METRICS.health_reinstated);
+                self.register(&name, reg)
+            }
+        }
+    }
+
+    /// Emits the compensating updates that turn the announced set into `observed`.
+    ///
+    /// Names that are unchanged produce no traffic, so running this against an
+    /// already consistent view is free of Remove/Add churn.  Containers still in
+    /// their debounce window or withheld for flapping are settled separately and
+    /// left as they are.  The leases of the names left unchanged are renewed.
+    /// Returns the number of adds (including IP changes) and removes sent.
+    pub(super) async fn reconcile(
+        &mut self,
+        mut observed: HashMap<String, Registration>,
+        update_tx: &mpsc::Sender<LocalUpdate>,
+    ) -> Result<(usize, usize)> {
+        let unsettled = self.pending.keys().chain(self.latest.keys());
+        for name in unsettled.chain(self.suppressed.iter()) {
+            observed.remove(name);
+            if let Some(reg) = self.announced.get(name).or(self.unhealthy.get(name)) {
+                observed.insert(name.clone(), reg.clone());
+            }
+        }
+
+        let stale: Vec<String> = self
+            .announced
+            .0
+            .keys()
+            .filter(|name| !observed.contains_key(*name))
+            .cloned()
+            .collect();
+        let removed = stale.len();
+        for name in stale {
+            info!("Reconcile: {} is no longer running", name);
+            publish(
+                update_tx,
+                LocalSource::Reconcile,
+                self.unregister(&name, None),
+            )
+            .await?;
+        }
+        // Those withdrawn are gone already.
+        let gone: Vec<String> = self
+            .unhealthy
+            .keys()
+            .filter(|name| !observed.contains_key(*name))
+            .cloned()
+            .collect();
+        for name in gone {
+            self.unregister(&name, None);
+        }
+
+        let mut added = 0;
+        let mut unchanged = Vec::new();
+        for (name, reg) in observed {
+            match self.register(&name, reg) {
+                Some(update) => {
+                    info!("Reconcile: {:?}", update);
+                    publish(update_tx, LocalSource::Reconcile, Some(update)).await?;
+                    added += 1;
+                }
+                None => unchanged.push(name),
+            }
+        }
+        if let Some(valid_for_secs) = self.lease {
+            let mut renewals = Vec::new();
+            for reg in unchanged.iter().filter_map(|name| self.announced.get(name)) {
+                if reg.wildcard {
+                    renewals.push(Update::Lease {
+                        name: wildcard_key(&reg.name),
+                        valid_for_secs,
+                    });
+                }
+                renewals.push(Update::Lease {
+                    name: reg.name.clone(),
+                    valid_for_secs,
+                });
+            }
+            if !renewals.is_empty() {
+                debug!("Reconcile: renewing {} leases", renewals.len());
+                publish(
+                    update_tx,
+                    LocalSource::Reconcile,
+                    Some(Update::batch(renewals)),
+                )
+                .await?;
+            }
+        }
+        Ok((added, removed))
+    }
 }
 
 impl DockerRuntime {
-    pub fn new(network_name: String) -> Self {
-        Self { network_name }
+    /// Creates the runtime, validating the Docker connection settings up front
+    /// so that a bad address or unreadable certificate fails startup instead
+    /// of being retried forever.
+    pub fn new(network_name: String, cfg: &Config) -> Result<Self> {
+        let host = DockerHost::resolve(cfg, |key| std::env::var(key).ok())?;
+        host.connect()?;
+        info!("Using Docker daemon at {}", host);
+        let exclusions = Exclusions::from_config(cfg)?;
+        if let Some(id) = exclusions.self_id() {
+            info!("Running in container {}; it will not be registered", id);
+        }
+
+        Ok(Self {
+            network_name,
+            network_wait: cfg.network_wait,
+            host,
+            exclusions,
+            name_policy: cfg.name_policy,
+            name_replace_chars: cfg.name_replace_chars.clone(),
+            // A zero interval would make `interval_at` panic; clamp to one second.
+            reconcile_interval: Duration::from_secs(cfg.reconcile_interval_secs.max(1)),
+            debounce: Duration::from_millis(cfg.debounce_ms),
+            flap_restart_limit: cfg.flap_restart_limit as usize,
+            flap_window: Duration::from_secs(cfg.flap_window_secs),
+            require_healthy: cfg.require_healthy,
+            port_report: cfg.port_report,
+            host_address: cfg.host_address,
+            events: cfg.docker_events.clone(),
+            // A semaphore without permits would never inspect anything.
+            inspect_max_inflight: cfg.inspect_max_inflight.max(1),
+            inspect_timeout: Duration::from_secs(cfg.inspect_timeout_secs),
+            clear_on_disconnect: cfg
+                .clear_on_disconnect
+                .then(|| Duration::from_secs(cfg.clear_on_disconnect_secs)),
+            lease: cfg.entry_lease(),
+            status: Arc::default(),
+            health: HealthPolicy::from_config(cfg)?,
+            node_entry: NodeEntry::from_config(cfg),
+        })
     }
 
-    async fn connect() -> Result<Docker> {
-        // Connect to the local Docker daemon using default settings.
-        // This handles unix socket on Linux.
-        Docker::connect_with_local_defaults().map_err(Into::into)
+    /// Reports the first scan to `status`.
+    pub fn reporting_to(self, status: Arc<Status>) -> Self {
+        Self { status, ..self }
     }
 
-    async fn get_initial_state(
-        docker: &Docker,
-        network_name: &str,
-    ) -> Result<HashMap<String, String>> {
+    async fn get_initial_state(&self, docker: &Docker) -> Result<HashMap<String, Registration>> {
         let mut map = HashMap::new();
         let opts = ListContainersOptions::<String> {
             all: false,
@@ -45,24 +594,514 @@ impl DockerRuntime {
                 .and_then(|n| n.first())
                 .map(|n| n.trim_start_matches('/').to_string());
             let id = c.id.as_ref().map(|s| s.to_string());
-            let name = match (name, id) {
+            let name = match (name, id.clone()) {
                 (Some(n), _) => n,
                 (_, Some(id)) => id,
                 _ => continue,
             };
 
             if let Ok(detail) = docker.inspect_container(&name, None).await {
-                if let Some(ip) = get_ip_for_network(&detail, network_name) {
-                    map.insert(name, ip);
+                if let Some(mut reg) = self.registration_for(&detail) {
+                    if reg.id.is_empty() {
+                        reg.id = id.unwrap_or_default();
+                    }
+                    map.insert(name, reg);
                 }
             }
         }
+        if let Some(node) = &self.node_entry {
+            let found = match node.self_id() {
+                Some(id) => docker
+                    .inspect_container(id, None)
+                    .await
+                    .ok()
+                    .and_then(|detail| get_ip_for_network(&detail, &self.network_name)),
+                None => None,
+            };
+            if let Some(reg) = node.registration(found) {
+                map.insert(node.name().to_string(), reg);
+            }
+        }
         Ok(map)
     }
 
-    async fn ensure_target_network(docker: &Docker, network_name: &str) -> Result<()> {
+    /// What `event` means for registration, if it concerns us.
+    fn classify(&self, event: EventMessage) -> Option<DockerEvent> {
+        let action = event.action.unwrap_or_default();
+        // `health_status: healthy` and the like are listed as `health_status`.
+        let kind = action.split(':').next().unwrap_or_default();
+        if !self.events.iter().any(|listed| listed == kind) {
+            debug!("Ignoring unlisted {} event", action);
+            return None;
+        }
+        let actor = event.actor?;
+        let attributes = actor.attributes.unwrap_or_default();
+
+        if event.typ == Some(EventMessageTypeEnum::NETWORK) {
+            // Network events carry the network name in `name` and the affected
+            // container's ID in `container`.
+            let network = attributes
+                .get("name")
+                .map(String::as_str)
+                .unwrap_or_default();
+            if network != self.network_name {
+                debug!("Ignoring {} event for network '{}'", action, network);
+                return None;
+            }
+            let container = attributes.get("container")?.clone();
+            return Some(DockerEvent::Network { action, container });
+        }
+
+        let id = actor.id.unwrap_or_default();
+        let name = attributes
+            .get("name")
+            .filter(|name| !name.is_empty())
+            .cloned()
+            .unwrap_or_else(|| id.clone());
+        if name.is_empty() {
+            return None;
+        }
+        Some(match kind {
+            "start" => DockerEvent::Start { name, id },
+            "destroy" => DockerEvent::Destroy { name, id },
+            "rename" => {
+                let old = attributes
+                    .get("oldName")
+                    .map(|old| old.trim_start_matches('/').to_string())
+                    .unwrap_or_default();
+                DockerEvent::Rename { old, name, id }
+            }
+            // die, stop, kill, oom, health_status and anything added to
+            // `docker_events`: look at the container again.
+            _ => DockerEvent::Changed { action, name, id },
+        })
+    }
+
+    async fn handle_event(
+        &self,
+        docker: &Docker,
+        event: EventMessage,
+        state: &mut MonitorState,
+        inspects: &mut Inspects,
+        update_tx: &mpsc::Sender<LocalUpdate>,
+    ) -> Result<()> {
+        let Some(event) = self.classify(event) else {
+            return Ok(());
+        };
+        debug!("Docker event: {:?}", event);
+        match event {
+            DockerEvent::Network { action, container } => {
+                inspects.spawn(docker, container.clone(), move |inspection| {
+                    Inspected::Network {
+                        action,
+                        container,
+                        inspection,
+                    }
+                });
+            }
+            DockerEvent::Start { name, id: _ } => {
+                state
+                    .starts
+                    .entry(name.clone())
+                    .or_default()
+                    .push_back(Instant::now());
+                self.schedule(state, name);
+            }
+            DockerEvent::Changed { action, name, id } => {
+                if is_replaced(state, &name, &id) {
+                    debug!(
+                        "Ignoring {} for replaced container {} ({})",
+                        action, name, id
+                    );
+                    return Ok(());
+                }
+                self.schedule(state, name);
+            }
+            DockerEvent::Destroy { name, id } => {
+                // Nothing left to inspect; a container destroyed while we were
+                // disconnected is caught by the rescan on reconnect.
+                if is_replaced(state, &name, &id) {
+                    debug!("Ignoring destroy of replaced container {} ({})", name, id);
+                    return Ok(());
+                }
+                state.pending.remove(&name);
+                state.latest.remove(&name);
+                state.starts.remove(&name);
+                state.suppressed.remove(&name);
+                if state.announced.get(&name).is_some() {
+                    info!("Container destroyed: {}", name);
+                }
+                publish(
+                    update_tx,
+                    LocalSource::DockerEvent,
+                    state.unregister(&name, Some(&id)),
+                )
+                .await?;
+            }
+            DockerEvent::Rename { old, name, id } => {
+                info!("Container renamed: {} -> {}", old, name);
+                state.pending.remove(&old);
+                state.latest.remove(&old);
+                if let Some(starts) = state.starts.remove(&old) {
+                    state.starts.insert(name.clone(), starts);
+                }
+                state.suppressed.remove(&old);
+                publish(
+                    update_tx,
+                    LocalSource::DockerEvent,
+                    state.unregister(&old, Some(&id)),
+                )
+                .await?;
+                self.schedule(state, name);
+            }
+        }
+        Ok(())
+    }
+
+    /// (Re)starts the debounce window for a container.  Events arriving within
+    /// the window are coalesced and only the container's final state is published.
+    fn schedule(&self, state: &mut MonitorState, name: String) {
+        state.stamp(&name);
+        state.pending.insert(name, Instant::now() + self.debounce);
+    }
+
+    /// Starts inspecting every container whose debounce window closed.  The
+    /// results come back through `inspects` and are applied by [`Self::inspected`].
+    fn settle_pending(&self, docker: &Docker, state: &mut MonitorState, inspects: &mut Inspects) {
+        let now = Instant::now();
+        let due: Vec<String> = state
+            .pending
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in due {
+            state.pending.remove(&name);
+            let token = state.stamp(&name);
+            inspects.spawn(docker, name.clone(), move |inspection| Inspected::Settle {
+                name,
+                token,
+                inspection,
+            });
+        }
+    }
+
+    /// Applies a finished inspect.
+    async fn inspected(
+        &self,
+        state: &mut MonitorState,
+        update_tx: &mpsc::Sender<LocalUpdate>,
+        inspected: Inspected,
+    ) -> Result<()> {
+        match inspected {
+            Inspected::Network {
+                action,
+                container,
+                inspection,
+            } => {
+                let Inspection::Found(detail) = inspection else {
+                    // A disconnect for a removed container is also covered by its die event.
+                    debug!(
+                        "Failed to inspect container {} after network {}: {:?}",
+                        container, action, inspection
+                    );
+                    return Ok(());
+                };
+                let name = detail
+                    .name
+                    .map(|n| n.trim_start_matches('/').to_string())
+                    .unwrap_or_default();
+                if !name.is_empty() {
+                    debug!("Network event: {} for {}", action, name);
+                    self.schedule(state, name);
+                }
+                Ok(())
+            }
+            Inspected::Settle {
+                name,
+                token,
+                inspection,
+            } => {
+                if !state.is_latest(&name, token) {
+                    debug!("Discarding inspect of {} overtaken by a later event", name);
+                    return Ok(());
+                }
+                state.latest.remove(&name);
+                self.settle(state, update_tx, name, inspection).await
+            }
+        }
+    }
+
+    async fn settle(
+        &self,
+        state: &mut MonitorState,
+        update_tx: &mpsc::Sender<LocalUpdate>,
+        name: String,
+        inspection: Inspection,
+    ) -> Result<()> {
+        let (id, reg) = match inspection {
+            Inspection::Found(detail) => {
+                let reg = self.registration_for(&detail);
+                (detail.id, reg)
+            }
+            Inspection::Gone => (None, None),
+            Inspection::TimedOut => {
+                warn!(
+                    "Inspecting container {} timed out after {:?}; retrying",
+                    name, self.inspect_timeout
+                );
+                self.schedule(state, name);
+                return Ok(());
+            }
+            Inspection::Failed(e) => {
+                warn!("Failed to inspect container {}: {}", name, e);
+                return Ok(());
+            }
+        };
+
+        let Some(reg) = reg else {
+            info!("Container stopped: {}", name);
+            let update = state.unregister(&name, id.as_deref());
+            return publish(update_tx, LocalSource::DockerEvent, update).await;
+        };
+
+        if self.is_flapping(state, &name) {
+            if state.suppressed.insert(name.clone()) {
+                warn!(
+                    "Container {} restarted more than {} times in {:?}; suppressing registration until it stays up",
+                    name, self.flap_restart_limit, self.flap_window
+                );
+            }
+            // Look again once the window has passed.
+            state
+                .pending
+                .insert(name.clone(), Instant::now() + self.flap_window);
+            let update = state.unregister(&name, None);
+            return publish(update_tx, LocalSource::DockerEvent, update).await;
+        }
+        if state.suppressed.remove(&name) {
+            info!("Container {} is stable again; resuming registration", name);
+        }
+
+        match state.announced.0.get(&name) {
+            // The new Add overwrites the entry in place, so the name never
+            // goes missing while the change spreads.
+            Some(previous) if previous.ip != reg.ip => info!(
+                "Container {} changed address: {} -> {}",
+                name, previous.ip, reg.ip
+            ),
+            _ => info!("Container started: {} -> {} as {}", name, reg.ip, reg.name),
+        }
+        let update = state.register(&name, reg);
+        publish(update_tx, LocalSource::DockerEvent, update).await
+    }
+
+    /// The registration a container should currently have, if any: it must be
+    /// running, attached to the monitored network and, when health gating
+    /// applies, report `healthy`.
+    fn registration_for(&self, detail: &ContainerInspectResponse) -> Option<Registration> {
+        let id = detail.id.as_deref().unwrap_or_default();
+        let name = detail
+            .name
+            .as_deref()
+            .unwrap_or_default()
+            .trim_start_matches('/');
+        let labels = detail.config.as_ref().and_then(|c| c.labels.as_ref());
+        if let Some(reason) = self.exclusions.reason(id, name, labels) {
+            debug!("Not registering {}: {}", name, reason);
+            return None;
+        }
+
+        let state = detail.state.as_ref()?;
+        if !state.running.unwrap_or(false) {
+            return None;
+        }
+        let ip = get_ip_for_network(detail, &self.network_name)?;
+
+        // Containers without a healthcheck register as soon as they run.
+        let health = state
+            .health
+            .as_ref()
+            .and_then(|h| h.status)
+            .filter(|s| !matches!(s, HealthStatusEnum::EMPTY | HealthStatusEnum::NONE));
+        if let Some(health) = health {
+            if self.requires_healthy(detail) && health != HealthStatusEnum::HEALTHY {
+                debug!(
+                    "Container {} is {}; waiting for it to become healthy",
+                    detail.name.as_deref().unwrap_or_default(),
+                    health
+                );
+                return None;
+            }
+        }
+
+        let Some(published) = self.name_policy.apply(name, &self.name_replace_chars) else {
+            warn!(
+                "Not registering {}: not a valid DNS label under the {:?} name policy",
+                name, self.name_policy
+            );
+            return None;
+        };
+        // What every node would drop anyway is better reported here.
+        if let Err(reason) = check_name(&published) {
+            warn!("Not registering {}: {}", name, reason);
+            return None;
+        }
+
+        Some(Registration {
+            id: detail.id.clone().unwrap_or_default(),
+            name: published,
+            ip,
+            wildcard: labels
+                .and_then(|labels| labels.get(WILDCARD_LABEL))
+                .is_some_and(|v| v == "true"),
+            ports: ports_for(detail, self.port_report),
+            host_ips: host_ips_for(detail, self.host_address),
+            health: self.health.probe_for(name, labels),
+            txt: labels.map_or_else(Vec::new, |labels| txt::from_labels(name, labels)),
+        })
+    }
+
+    fn requires_healthy(&self, detail: &ContainerInspectResponse) -> bool {
+        self.require_healthy
+            || detail
+                .config
+                .as_ref()
+                .and_then(|c| c.labels.as_ref())
+                .and_then(|labels| labels.get(REQUIRE_HEALTHY_LABEL))
+                .is_some_and(|v| v == "true")
+    }
+
+    /// Whether the container started more than `flap_restart_limit` times within
+    /// `flap_window`.  Always false when flap suppression is disabled.
+    fn is_flapping(&self, state: &mut MonitorState, name: &str) -> bool {
+        if self.flap_restart_limit == 0 {
+            state.starts.remove(name);
+            return false;
+        }
+        let Some(starts) = state.starts.get_mut(name) else {
+            return false;
+        };
+        let now = Instant::now();
+        while starts
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > self.flap_window)
+        {
+            starts.pop_front();
+        }
+        let flapping = starts.len() > self.flap_restart_limit;
+        if starts.is_empty() {
+            state.starts.remove(name);
+        }
+        flapping
+    }
+
+    /// Records that Docker is unreachable and, with `clear_on_disconnect`,
+    /// withdraws everything this node announced once the outage is long enough.
+    async fn docker_down(
+        &self,
+        state: &mut MonitorState,
+        down_since: &mut Option<Instant>,
+        update_tx: &mpsc::Sender<LocalUpdate>,
+    ) -> Result<()> {
+        let since = *down_since.get_or_insert_with(Instant::now);
+        let Some(threshold) = self.clear_on_disconnect else {
+            return Ok(());
+        };
+        if since.elapsed() < threshold || state.announced.0.is_empty() {
+            return Ok(());
+        }
+        warn!(
+            "Docker unreachable for {:?}; withdrawing {} entries",
+            since.elapsed(),
+            state.announced.0.len()
+        );
+        // Pending containers are rescanned on reconnect anyway.
+        state.pending.clear();
+        let (_, removed) = state.reconcile(HashMap::new(), update_tx).await?;
+        debug!("Withdrew {} entries", removed);
+        Ok(())
+    }
+
+    /// Scans the running containers and repairs what was announced, as
+    /// the reconcile timer and on-demand rescans do.
+    async fn rescan(
+        &self,
+        docker: &Docker,
+        state: &mut MonitorState,
+        update_tx: &mpsc::Sender<LocalUpdate>,
+    ) -> Result<Rescan> {
+        let observed = match self.get_initial_state(docker).await {
+            Ok(observed) => observed,
+            Err(e) => {
+                warn!("Reconciliation scan failed: {}", e);
+                return Ok(Err(format!("scan of {} failed: {}", self.network_name, e)));
+            }
+        };
+        let (added, removed) = state.reconcile(observed, update_tx).await?;
+        if added + removed > 0 {
+            warn!(
+                "Reconciliation repaired drift: {} added/changed, {} removed",
+                added, removed
+            );
+        } else {
+            debug!("Reconciliation found no drift");
+        }
+        Ok(Ok((added, removed)))
+    }
+
+    /// Fails unless the Docker daemon answers and the monitored network
+    /// exists, or with `network_wait` is waited for.  `monitor` itself
+    /// retries both indefinitely.
+    pub async fn check(&self) -> Result<()> {
+        let docker = self.host.connect()?;
+        docker
+            .ping()
+            .await
+            .map_err(|e| anyhow!("Docker daemon at {} is not reachable: {}", self.host, e))?;
+        if !Self::ensure_target_network(&docker, &self.network_name).await? {
+            self.network_missing(&docker).await?;
+        }
+        Ok(())
+    }
+
+    /// Fails if the Docker daemon answers that the monitored network
+    /// doesn't exist and `network_wait` is off, as for a misspelled name,
+    /// which no retry fixes.  A daemon that can't be reached, or fails
+    /// otherwise, is left to `monitor` to retry.
+    pub async fn check_network(&self) -> Result<()> {
+        let Ok(docker) = self.host.connect() else {
+            return Ok(());
+        };
+        if docker.ping().await.is_err() {
+            return Ok(());
+        }
+        match Self::ensure_target_network(&docker, &self.network_name).await {
+            Ok(false) => self.network_missing(&docker).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails for the monitored network not existing, unless it is to be
+    /// waited for.
+    async fn network_missing(&self, docker: &Docker) -> Result<()> {
+        if !self.network_wait {
+            return Err(Self::missing_network(docker, &self.network_name).await);
+        }
+        info!(
+            "Network '{}' doesn't exist yet; waiting for it to be created",
+            self.network_name
+        );
+        Ok(())
+    }
+
+    /// Checks the monitored network; `false` if it doesn't exist.
+    async fn ensure_target_network(docker: &Docker, network_name: &str) -> Result<bool> {
         match docker
-            .inspect_network(network_name, None::<bollard::network::InspectNetworkOptions<String>>)
+            .inspect_network(
+                network_name,
+                None::<bollard::network::InspectNetworkOptions<String>>,
+            )
             .await
         {
             Ok(details) => {
@@ -79,9 +1118,17 @@ impl DockerRuntime {
                         "Network '{}' is using driver '{}'; replicas expect an overlay network.",
                         network_name, driver
                     );
+                } else if details.attachable == Some(false) {
+                    warn!(
+                        "Network '{}' isn't attachable; only swarm services can join it, not containers started on their own.",
+                        network_name
+                    );
                 }
-                Ok(())
+                Ok(true)
             }
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => Ok(false),
             Err(e) => Err(anyhow!(
                 "Network '{}' could not be inspected: {}",
                 network_name,
@@ -89,169 +1136,1191 @@ impl DockerRuntime {
             )),
         }
     }
+
+    /// Why the monitored network can't be found, with the overlay networks
+    /// that can be, in case its name is misspelled.
+    async fn missing_network(docker: &Docker, network_name: &str) -> anyhow::Error {
+        let options = ListNetworksOptions {
+            filters: HashMap::from([("driver", vec!["overlay"])]),
+        };
+        let overlays = match docker.list_networks(Some(options)).await {
+            Ok(networks) => {
+                let mut names: Vec<String> = networks
+                    .into_iter()
+                    .filter_map(|network| network.name)
+                    .collect();
+                names.sort();
+                if names.is_empty() {
+                    "none".to_string()
+                } else {
+                    names.join(", ")
+                }
+            }
+            Err(e) => format!("unknown, as they could not be listed: {}", e),
+        };
+        anyhow!(
+            "Network '{}' does not exist (set network_wait to wait for it); overlay networks: {}",
+            network_name,
+            overlays
+        )
+    }
 }
 
 #[async_trait]
 impl ContainerRuntime for DockerRuntime {
-    async fn monitor(&self, update_tx: mpsc::Sender<Update>) -> Result<()> {
+    async fn monitor(&self, update_tx: mpsc::Sender<LocalUpdate>) -> Result<()> {
+        // Kept across reconnects so that reconciliation can diff against what
+        // this node has announced so far.
+        let mut state = MonitorState::leasing(self.lease).probing(self.health.clone());
+        // When the connection to Docker was last lost, while it stays down.
+        let mut down_since: Option<Instant> = None;
+        // Why it's down, logged once a minute while it stays so.
+        let mut failures = LogDedup::new(module_path!(), Level::Error);
+        // Whether waiting for the network to be created was logged.
+        let mut waiting = false;
+        // Answered once connected; until then they wait, up to their timeout.
+        let mut rescans = self.status.resync().rescans();
+
         loop {
-            let docker = match Self::connect().await {
+            let docker = match self.host.connect() {
                 Ok(d) => d,
                 Err(e) => {
-                    error!("Failed to connect to Docker: {}. Retrying in 5s...", e);
+                    failures.log(
+                        (),
+                        format!("Failed to connect to Docker: {}. Retrying in 5s...", e),
+                    );
+                    self.docker_down(&mut state, &mut down_since, &update_tx)
+                        .await?;
                     sleep(Duration::from_secs(5)).await;
                     continue;
                 }
             };
             let network_name = self.network_name.clone();
 
-            if let Err(e) = Self::ensure_target_network(&docker, &network_name).await {
-                error!("{}", e);
-                sleep(Duration::from_secs(10)).await;
-                continue;
+            match Self::ensure_target_network(&docker, &network_name).await {
+                Ok(true) => {}
+                Ok(false) if self.network_wait => {
+                    if !waiting {
+                        info!("Waiting for network '{}' to be created...", network_name);
+                        waiting = true;
+                    }
+                    self.docker_down(&mut state, &mut down_since, &update_tx)
+                        .await?;
+                    sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+                found => {
+                    let e = match found {
+                        Ok(_) => Self::missing_network(&docker, &network_name).await,
+                        Err(e) => e,
+                    };
+                    failures.log((), e.to_string());
+                    self.docker_down(&mut state, &mut down_since, &update_tx)
+                        .await?;
+                    sleep(Duration::from_secs(10)).await;
+                    continue;
+                }
             }
             info!("Starting Docker monitor for network: {}", network_name);
 
-            // Initial scan
-            match Self::get_initial_state(&docker, &network_name).await {
+            // Initial scan.  After a reconnect (e.g. dockerd restarted) this also
+            // withdraws entries whose containers did not come back.
+            match self.get_initial_state(&docker).await {
                 Ok(initial_map) => {
                     info!("Initial scan found {} containers", initial_map.len());
-                    for (name, ip) in initial_map {
-                        if let Err(e) = update_tx
-                            .send(Update::Add {
-                                name: name.clone(),
-                                ip: ip.clone(),
-                            })
-                            .await
-                        {
-                            error!("Failed to send initial update for {}: {}", name, e);
-                            return Err(anyhow::anyhow!("Channel closed"));
-                        }
+                    let (added, removed) = state.reconcile(initial_map, &update_tx).await?;
+                    self.status.set_scanned();
+                    failures.recovered(&());
+                    if down_since.take().is_some() {
+                        info!(
+                            "Docker connection restored: {} added/changed, {} removed",
+                            added, removed
+                        );
                     }
                 }
                 Err(e) => {
-                    error!("Failed initial scan: {}. Retrying...", e);
+                    failures.log((), format!("Failed initial scan: {}. Retrying...", e));
+                    self.docker_down(&mut state, &mut down_since, &update_tx)
+                        .await?;
                     sleep(Duration::from_secs(5)).await;
                     continue;
                 }
             }
 
-            // Event stream
+            // Event stream: container lifecycle plus network attach/detach, so that
+            // `docker network connect` on a running container is picked up too.
+            // `health_status` matches both the healthy and unhealthy transitions.
             let opts = EventsOptions::<String> {
-                filters: [
-                    ("type", ["container"].as_slice()),
-                    ("event", ["start", "die", "kill", "stop"].as_slice()),
-                ]
-                .iter()
-                .map(|(k, v)| (k.to_string(), v.iter().map(|s| s.to_string()).collect()))
-                .collect(),
+                filters: HashMap::from([
+                    (
+                        "type".to_string(),
+                        vec!["container".to_string(), "network".to_string()],
+                    ),
+                    ("event".to_string(), self.events.clone()),
+                ]),
                 ..Default::default()
             };
 
             let mut stream = docker.events(Some(opts));
+            let mut inspects = Inspects::new(self.inspect_max_inflight, self.inspect_timeout);
+            let mut reconcile_timer = interval_at(
+                Instant::now() + self.reconcile_interval,
+                self.reconcile_interval,
+            );
 
             info!("Listening for Docker events...");
-            while let Some(msg) = stream.next().await {
-                match msg {
-                    Ok(event) => {
-                        if let Some(actor) = event.actor {
-                            if let Some(attributes) = actor.attributes {
-                                let name = attributes.get("name").cloned().unwrap_or_default();
-                                let id = actor.id.unwrap_or_default();
-                                let container_name =
-                                    if !name.is_empty() { name } else { id.clone() };
-
-                                if container_name.is_empty() {
-                                    continue;
-                                }
-
-                                let action = event.action.unwrap_or_default();
-                                debug!("Container event: {} for {}", action, container_name);
-
-                                match action.as_str() {
-                                    "start" => {
-                                        // Inspect to get IP
-                                        match docker.inspect_container(&container_name, None).await
-                                        {
-                                            Ok(detail) => {
-                                                if let Some(ip) =
-                                                    get_ip_for_network(&detail, &network_name)
-                                                {
-                                                    info!(
-                                                        "Container started: {} -> {}",
-                                                        container_name, ip
-                                                    );
-                                                    if let Err(e) = update_tx
-                                                        .send(Update::Add {
-                                                            name: container_name,
-                                                            ip,
-                                                        })
-                                                        .await
-                                                    {
-                                                        error!("Failed to send Add update: {}", e);
-                                                        return Err(anyhow::anyhow!(
-                                                            "Channel closed"
-                                                        ));
-                                                    }
-                                                }
-                                            }
-                                            Err(e) => {
-                                                warn!(
-                                                    "Failed to inspect started container {}: {}",
-                                                    container_name, e
-                                                );
-                                            }
-                                        }
-                                    }
-                                    "die" | "kill" | "stop" => {
-                                        info!("Container stopped: {}", container_name);
-                                        if let Err(e) = update_tx
-                                            .send(Update::Remove {
-                                                name: container_name,
-                                            })
-                                            .await
-                                        {
-                                            error!("Failed to send Remove update: {}", e);
-                                            return Err(anyhow::anyhow!("Channel closed"));
-                                        }
-                                    }
-                                    _ => {}
-                                }
+            loop {
+                let next_settle = state.pending.values().min().copied();
+                tokio::select! {
+                    msg = stream.next() => {
+                        let event = match msg {
+                            Some(Ok(event)) => event,
+                            Some(Err(e)) => {
+                                error!("Error in Docker event stream: {}", e);
+                                break; // Break inner loop to reconnect
                             }
-                        }
+                            None => break,
+                        };
+                        let span = info_span!(
+                            target: "glued::runtime",
+                            "docker_event",
+                            action = event.action.as_deref().unwrap_or_default(),
+                            container = event
+                                .actor
+                                .as_ref()
+                                .and_then(|actor| actor.attributes.as_ref())
+                                .and_then(|attributes| attributes.get("name"))
+                                .map(String::as_str)
+                                .unwrap_or_default(),
+                        );
+                        self.handle_event(&docker, event, &mut state, &mut inspects, &update_tx)
+                            .instrument(span)
+                            .await?;
                     }
-                    Err(e) => {
-                        error!("Error in Docker event stream: {}", e);
-                        break; // Break inner loop to reconnect
+                    _ = sleep_until(next_settle.unwrap_or_else(Instant::now)), if next_settle.is_some() => {
+                        self.settle_pending(&docker, &mut state, &mut inspects);
+                    }
+                    Some(inspected) = inspects.next(), if !inspects.is_empty() => {
+                        self.inspected(&mut state, &update_tx, inspected).await?;
+                    }
+                    update = state.health_update() => {
+                        publish(&update_tx, LocalSource::HealthCheck, update).await?;
+                    }
+                    _ = reconcile_timer.tick() => {
+                        // A failed scan is logged; the next tick tries again.
+                        let _ = self.rescan(&docker, &mut state, &update_tx).await?;
+                    }
+                    Some(reply) = rescans.recv() => {
+                        info!("Rescanning on request");
+                        let rescan = self.rescan(&docker, &mut state, &update_tx).await?;
+                        let _ = reply.send(rescan);
+                        reconcile_timer.reset();
                     }
                 }
             }
 
+            // Inspects still running are dropped with the connection; the
+            // rescan after reconnecting covers their containers.
+            drop(inspects);
+            state.latest.clear();
             warn!("Docker event stream ended. Reconnecting in 2s...");
+            self.docker_down(&mut state, &mut down_since, &update_tx)
+                .await?;
             sleep(Duration::from_secs(2)).await;
         }
     }
 }
 
-fn get_ip_for_network(
-    detail: &bollard::models::ContainerInspectResponse,
-    network_name: &str,
-) -> Option<String> {
-    if let Some(settings) = &detail.network_settings {
-        if let Some(networks) = &settings.networks {
-            if let Some(net) = networks.get(network_name) {
-                if let Some(ipv4) = &net.ip_address {
-                    if !ipv4.is_empty() {
-                        return Some(ipv4.clone());
+/// What an inspect found.
+#[derive(Debug)]
+enum Inspection {
+    Found(Box<ContainerInspectResponse>),
+    /// The container no longer exists.
+    Gone,
+    TimedOut,
+    Failed(String),
+}
+
+/// A finished inspect and what it was for.
+#[derive(Debug)]
+enum Inspected {
+    /// Settling `name`; only applied while `token` is its latest.
+    Settle {
+        name: String,
+        token: u64,
+        inspection: Inspection,
+    },
+    /// Finding the name of a container a network event was about.
+    Network {
+        action: String,
+        container: String,
+        inspection: Inspection,
+    },
+}
+
+/// Container inspects run off the event loop, so that a slow or wedged
+/// inspect holds up neither events nor other containers.
+struct Inspects {
+    tasks: JoinSet<Inspected>,
+    slots: Arc<Semaphore>,
+    timeout: Duration,
+}
+
+impl Inspects {
+    fn new(max_inflight: usize, timeout: Duration) -> Self {
+        Self {
+            tasks: JoinSet::new(),
+            slots: Arc::new(Semaphore::new(max_inflight)),
+            timeout,
+        }
+    }
+
+    /// Inspects `container` in the background; `done` says what for.
+    fn spawn(
+        &mut self,
+        docker: &Docker,
+        container: String,
+        done: impl FnOnce(Inspection) -> Inspected + Send + 'static,
+    ) {
+        let docker = docker.clone();
+        let slots = Arc::clone(&self.slots);
+        let timeout = self.timeout;
+        self.tasks.spawn(async move {
+            let _slot = slots.acquire_owned().await;
+            let inspect = docker.inspect_container(&container, None);
+            let inspection = match tokio::time::timeout(timeout, inspect).await {
+                Ok(Ok(detail)) => Inspection::Found(Box::new(detail)),
+                Ok(Err(bollard::errors::Error::DockerResponseServerError {
+                    status_code: 404,
+                    ..
+                })) => Inspection::Gone,
+                Ok(Err(e)) => Inspection::Failed(e.to_string()),
+                Err(_) => Inspection::TimedOut,
+            };
+            done(inspection)
+        });
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// The next inspect to finish.
+    async fn next(&mut self) -> Option<Inspected> {
+        loop {
+            match self.tasks.join_next().await? {
+                Ok(inspected) => return Some(inspected),
+                Err(e) => error!("Inspect task failed: {}", e),
+            }
+        }
+    }
+}
+
+/// Sends an update produced by [`Announced`], if there is one, as made by
+/// `source`.
+///
+/// A full channel means the pipeline is slow, not gone: the update is held
+/// until there is room, with a warning every [`FULL_CHANNEL_WARN`].  Only a
+/// closed channel is an error.  Updates are never dropped, since
+/// [`Announced`] already records them as sent.
+pub(super) async fn publish(
+    update_tx: &mpsc::Sender<LocalUpdate>,
+    source: LocalSource,
+    update: Option<Update>,
+) -> Result<()> {
+    let Some(update) = update else {
+        return Ok(());
+    };
+    match update_tx.try_send((update, source)) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Closed(_)) => {
+            error!("Failed to send update: receiver dropped");
+            Err(anyhow!("Channel closed"))
+        }
+        Err(TrySendError::Full(update)) => {
+            metrics::inc(&METRICS.updates_delayed);
+            let started = Instant::now();
+            loop {
+                warn!(
+                    "Update channel full ({} queued); waiting for the gossip pipeline",
+                    update_tx.max_capacity() - update_tx.capacity()
+                );
+                match tokio::time::timeout(FULL_CHANNEL_WARN, update_tx.reserve()).await {
+                    Ok(Ok(permit)) => {
+                        permit.send(update);
+                        debug!("Update channel drained after {:?}", started.elapsed());
+                        return Ok(());
                     }
+                    Ok(Err(_)) => {
+                        error!("Failed to send update: receiver dropped");
+                        return Err(anyhow!("Channel closed"));
+                    }
+                    Err(_) => {}
                 }
-                if let Some(ipv6) = &net.global_ipv6_address {
-                    if !ipv6.is_empty() {
-                        return Some(ipv6.clone());
+            }
+        }
+    }
+}
+
+/// The ports to list for a container: its published ports, by container
+/// or host port as `report` says.  A container publishing nothing lists the
+/// ports it exposes.
+fn ports_for(detail: &ContainerInspectResponse, report: PortReport) -> Vec<Port> {
+    let mut ports = Vec::new();
+    let published = detail
+        .network_settings
+        .as_ref()
+        .and_then(|settings| settings.ports.as_ref());
+    for (key, bindings) in published.into_iter().flatten() {
+        let Some(port) = parse_port_key(key) else {
+            continue;
+        };
+        for binding in bindings.iter().flatten() {
+            match report {
+                PortReport::Container => ports.push(port),
+                PortReport::Host => {
+                    let host_port = binding.host_port.as_deref().and_then(|p| p.parse().ok());
+                    if let Some(host_port) = host_port {
+                        ports.push(Port {
+                            port: host_port,
+                            ..port
+                        });
                     }
                 }
             }
         }
     }
-    None
+    if ports.is_empty() {
+        let exposed = detail
+            .config
+            .as_ref()
+            .and_then(|config| config.exposed_ports.as_ref());
+        ports.extend(
+            exposed
+                .into_iter()
+                .flatten()
+                .filter_map(|(key, _)| parse_port_key(key)),
+        );
+    }
+    ports.sort();
+    ports.dedup();
+    ports
+}
+
+/// The host addresses the container's published ports are bound to, with
+/// `host_address` standing in for every interface.
+fn host_ips_for(detail: &ContainerInspectResponse, host_address: Option<IpAddr>) -> Vec<IpAddr> {
+    let published = detail
+        .network_settings
+        .as_ref()
+        .and_then(|settings| settings.ports.as_ref());
+    let mut ips: Vec<IpAddr> = published
+        .into_iter()
+        .flatten()
+        .flat_map(|(_, bindings)| bindings.iter().flatten())
+        .filter(|binding| binding.host_port.as_deref().is_some_and(|p| !p.is_empty()))
+        .filter_map(|binding| {
+            match binding
+                .host_ip
+                .as_deref()
+                .unwrap_or_default()
+                .parse::<IpAddr>()
+            {
+                Ok(ip) if !ip.is_unspecified() => Some(ip),
+                _ => host_address,
+            }
+        })
+        .collect();
+    ips.sort();
+    ips.dedup();
+    ips
+}
+
+/// Parses Docker's `80/tcp` port notation.
+fn parse_port_key(key: &str) -> Option<Port> {
+    let (port, protocol) = key.split_once('/').unwrap_or((key, "tcp"));
+    Some(Port {
+        port: port.parse().ok()?,
+        protocol: protocol.parse().ok()?,
+    })
+}
+
+/// The container's address on `network_name`, IPv4 first.  An address
+/// Docker reports that doesn't parse is passed over.
+fn get_ip_for_network(
+    detail: &bollard::models::ContainerInspectResponse,
+    network_name: &str,
+) -> Option<IpAddr> {
+    let net = detail
+        .network_settings
+        .as_ref()?
+        .networks
+        .as_ref()?
+        .get(network_name)?;
+    [&net.ip_address, &net.global_ipv6_address]
+        .into_iter()
+        .flatten()
+        .filter(|ip| !ip.is_empty())
+        .find_map(|ip| match ip.parse() {
+            Ok(ip) => Some(ip),
+            Err(_) => {
+                warn!("Docker reported an invalid address '{}'", ip);
+                None
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// A Docker API that answers pings, has no network `missing-net` and
+    /// lists the overlay networks `apps` and `web`.  Returns its address
+    /// for `docker_host`.
+    async fn daemon_without_the_network() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = vec![0; 4096];
+                    let len = stream.read(&mut buf).await.unwrap_or(0);
+                    let head = String::from_utf8_lossy(&buf[..len]);
+                    let path = head.split_whitespace().nth(1).unwrap_or("");
+                    let (status, body) = if path.ends_with("/_ping") {
+                        ("200 OK", "OK")
+                    } else if path.contains("/networks/") {
+                        (
+                            "404 Not Found",
+                            r#"{"message": "network missing-net not found"}"#,
+                        )
+                    } else if path.contains("/networks") {
+                        (
+                            "200 OK",
+                            r#"[{"Name": "web", "Driver": "overlay"}, {"Name": "apps", "Driver": "overlay"}]"#,
+                        )
+                    } else {
+                        ("404 Not Found", r#"{"message": "page not found"}"#)
+                    };
+                    let response = format!(
+                        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        format!("tcp://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn a_missing_network_fails_startup_unless_waited_for() {
+        let cfg = Config {
+            docker_host: Some(daemon_without_the_network().await),
+            ..Config::default()
+        };
+        let runtime = DockerRuntime::new("missing-net".into(), &cfg).unwrap();
+        let err = runtime.check().await.unwrap_err().to_string();
+        assert!(err.contains("'missing-net' does not exist"), "{}", err);
+        assert!(err.contains("overlay networks: apps, web"), "{}", err);
+        // Replicas by `auto` fail as well.
+        let err_auto = runtime.check_network().await.unwrap_err().to_string();
+        assert_eq!(err_auto, err);
+
+        let wait = Config {
+            network_wait: true,
+            ..cfg.clone()
+        };
+        let waiting = DockerRuntime::new("missing-net".into(), &wait).unwrap();
+        waiting.check().await.unwrap();
+        waiting.check_network().await.unwrap();
+
+        // A daemon that doesn't answer is only fatal to explicit replicas.
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let down = Config {
+            docker_host: Some(format!("tcp://{}", closed.local_addr().unwrap())),
+            ..Config::default()
+        };
+        drop(closed);
+        let unreachable = DockerRuntime::new("missing-net".into(), &down).unwrap();
+        assert!(unreachable.check().await.is_err());
+        unreachable.check_network().await.unwrap();
+    }
+
+    fn reg(id: &str, ip: &str) -> Registration {
+        Registration {
+            id: id.into(),
+            name: "web-1".into(),
+            ip: ip.parse().unwrap(),
+            wildcard: false,
+            ports: Vec::new(),
+            host_ips: Vec::new(),
+            health: None,
+            txt: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn late_die_of_replaced_container_keeps_successor() {
+        let mut announced = Announced::default();
+
+        // start A
+        let update = announced.register("web-1", reg("aaaa", "10.0.0.2"));
+        assert!(matches!(update, Some(Update::Add { .. })));
+        // start B under the same name
+        let update = announced.register("web-1", reg("bbbb", "10.0.0.3"));
+        assert!(matches!(update, Some(Update::Add { ref ip, .. }) if ip.to_string() == "10.0.0.3"));
+        // die A arrives late
+        assert!(announced.unregister("web-1", Some("aaaa")).is_none());
+
+        assert_eq!(announced.get("web-1"), Some(&reg("bbbb", "10.0.0.3")));
+        assert!(matches!(
+            announced.unregister("web-1", Some("bbbb")),
+            Some(Update::Remove { .. })
+        ));
+        assert!(announced.get("web-1").is_none());
+    }
+
+    #[test]
+    fn address_change_is_a_single_add() {
+        let mut announced = Announced::default();
+        announced.register("web-1", reg("aaaa", "10.0.0.2"));
+
+        // Reconnected with a new address: one Add, no Remove first.
+        let update = announced.register("web-1", reg("aaaa", "10.0.0.7"));
+        assert_eq!(
+            update,
+            Some(Update::Add {
+                name: "web-1".into(),
+                ip: "10.0.0.7".parse().unwrap()
+            })
+        );
+        assert!(announced
+            .register("web-1", reg("aaaa", "10.0.0.7"))
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn failing_probes_withdraw_a_container_until_it_passes() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let mut state = MonitorState::default().probing(HealthPolicy {
+            interval: Duration::from_millis(10),
+            jitter: Duration::ZERO,
+            fall: 1,
+            rise: 1,
+            ..HealthPolicy::default()
+        });
+        let probed = Registration {
+            health: Some(Probe::Tcp(port)),
+            ..reg("aaaa", "127.0.0.1")
+        };
+        let add = Update::Add {
+            name: "web-1".into(),
+            ip: "127.0.0.1".parse().unwrap(),
+        };
+        assert_eq!(state.register("web-1", probed.clone()), Some(add.clone()));
+        let within = Duration::from_secs(5);
+        assert_eq!(
+            tokio::time::timeout(within, state.health_update())
+                .await
+                .unwrap(),
+            Some(Update::Remove {
+                name: "web-1".into()
+            })
+        );
+
+        // Rescans and events leave it withdrawn.
+        let (tx, mut rx) = mpsc::channel(8);
+        let observed = HashMap::from([("web-1".to_string(), probed.clone())]);
+        assert_eq!(state.reconcile(observed, &tx).await.unwrap(), (0, 0));
+        assert!(state.register("web-1", probed).is_none());
+        assert!(rx.try_recv().is_err());
+
+        let _listener = tokio::net::TcpListener::bind(("127.0.0.1", port))
+            .await
+            .unwrap();
+        assert_eq!(
+            tokio::time::timeout(within, state.health_update())
+                .await
+                .unwrap(),
+            Some(add)
+        );
+    }
+
+    #[tokio::test]
+    async fn adds_are_leased_and_reconcile_renews_the_rest() {
+        let mut state = MonitorState::leasing(Some(600));
+        let lease = |name: &str| Update::Lease {
+            name: name.into(),
+            valid_for_secs: 600,
+        };
+        let wildcard = Registration {
+            wildcard: true,
+            ..reg("aaaa", "10.0.0.2")
+        };
+        assert_eq!(
+            state.register("web-1", wildcard.clone()),
+            Some(Update::Batch(vec![
+                Update::Add {
+                    name: "web-1".into(),
+                    ip: "10.0.0.2".parse().unwrap()
+                },
+                lease("web-1"),
+                Update::Add {
+                    name: "*.web-1".into(),
+                    ip: "10.0.0.2".parse().unwrap()
+                },
+                lease("*.web-1"),
+            ]))
+        );
+
+        let (tx, mut rx) = mpsc::channel(4);
+        let observed = HashMap::from([("web-1".to_string(), wildcard)]);
+        assert_eq!(state.reconcile(observed, &tx).await.unwrap(), (0, 0));
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            (
+                Update::Batch(vec![lease("*.web-1"), lease("web-1")]),
+                LocalSource::Reconcile
+            )
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn full_channel_delays_and_closed_channel_fails() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let add = |ip: &str| Update::Add {
+            name: "web-1".into(),
+            ip: ip.parse().unwrap(),
+        };
+        let event = LocalSource::DockerEvent;
+        publish(&tx, event, Some(add("10.0.0.2"))).await.unwrap();
+
+        // The second send waits for room rather than failing.
+        let blocked = tokio::spawn({
+            let tx = tx.clone();
+            let update = add("10.0.0.3");
+            async move { publish(&tx, event, Some(update)).await }
+        });
+        sleep(Duration::from_millis(50)).await;
+        assert!(!blocked.is_finished());
+        assert_eq!(rx.recv().await, Some((add("10.0.0.2"), event)));
+        blocked.await.unwrap().unwrap();
+        assert_eq!(rx.recv().await, Some((add("10.0.0.3"), event)));
+
+        drop(rx);
+        assert!(publish(&tx, event, Some(add("10.0.0.4"))).await.is_err());
+    }
+
+    #[test]
+    fn wildcard_registrations_publish_and_withdraw_both_names() {
+        let mut announced = Announced::default();
+        let wildcard = Registration {
+            wildcard: true,
+            ..reg("aaaa", "10.0.0.2")
+        };
+        let add = |name: &str| Update::Add {
+            name: name.into(),
+            ip: "10.0.0.2".parse().unwrap(),
+        };
+        let remove = |name: &str| Update::Remove { name: name.into() };
+
+        assert_eq!(
+            announced.register("web-1", wildcard.clone()),
+            Some(Update::Batch(vec![add("web-1"), add("*.web-1")]))
+        );
+        assert_eq!(
+            announced.register("web-1", reg("aaaa", "10.0.0.2")),
+            Some(Update::Batch(vec![add("web-1"), remove("*.web-1")]))
+        );
+        announced.register("web-1", wildcard);
+        assert_eq!(
+            announced.unregister("web-1", None),
+            Some(Update::Batch(vec![remove("*.web-1"), remove("web-1")]))
+        );
+    }
+
+    #[test]
+    fn unchanged_registration_is_not_republished() {
+        let mut announced = Announced::default();
+        assert!(announced
+            .register("web-1", reg("aaaa", "10.0.0.2"))
+            .is_some());
+        assert!(announced
+            .register("web-1", reg("aaaa", "10.0.0.2"))
+            .is_none());
+        assert!(announced.unregister("db", None).is_none());
+    }
+
+    #[test]
+    fn ports_are_published_or_else_exposed() {
+        use crate::types::PortProtocol::{Tcp, Udp};
+        use bollard::models::{ContainerConfig, NetworkSettings, PortBinding};
+
+        let binding = |port: &str| PortBinding {
+            host_ip: Some("0.0.0.0".into()),
+            host_port: Some(port.into()),
+        };
+        let exposed = ["80/tcp", "53/udp", "9000/tcp"]
+            .into_iter()
+            .map(|key| (key.to_string(), HashMap::new()))
+            .collect();
+        let mut detail = ContainerInspectResponse {
+            config: Some(ContainerConfig {
+                exposed_ports: Some(exposed),
+                ..Default::default()
+            }),
+            network_settings: Some(NetworkSettings {
+                ports: Some(HashMap::from([
+                    (
+                        "80/tcp".into(),
+                        Some(vec![binding("8080"), binding("8081")]),
+                    ),
+                    ("53/udp".into(), Some(vec![binding("5353")])),
+                    ("9000/tcp".into(), None),
+                ])),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let port = |port, protocol| Port { port, protocol };
+
+        assert_eq!(
+            ports_for(&detail, PortReport::Container),
+            [port(53, Udp), port(80, Tcp)]
+        );
+        assert_eq!(
+            ports_for(&detail, PortReport::Host),
+            [port(5353, Udp), port(8080, Tcp), port(8081, Tcp)]
+        );
+
+        detail.network_settings = None;
+        assert_eq!(
+            ports_for(&detail, PortReport::Host),
+            [port(53, Udp), port(80, Tcp), port(9000, Tcp)]
+        );
+
+        let mut announced = Announced::default();
+        let with_ports = Registration {
+            ports: vec![port(80, Tcp)],
+            ..reg("aaaa", "10.0.0.2")
+        };
+        assert_eq!(
+            announced.register("web-1", with_ports),
+            Some(Update::Batch(vec![
+                Update::Add {
+                    name: "web-1".into(),
+                    ip: "10.0.0.2".parse().unwrap(),
+                },
+                Update::Ports {
+                    name: "web-1".into(),
+                    ports: vec![port(80, Tcp)],
+                },
+            ]))
+        );
+    }
+
+    #[test]
+    fn published_ports_give_the_host_scope() {
+        use bollard::models::{NetworkSettings, PortBinding};
+
+        let binding = |ip: &str, port: &str| PortBinding {
+            host_ip: Some(ip.into()),
+            host_port: Some(port.into()),
+        };
+        let detail = ContainerInspectResponse {
+            network_settings: Some(NetworkSettings {
+                ports: Some(HashMap::from([
+                    (
+                        "80/tcp".into(),
+                        Some(vec![binding("0.0.0.0", "8080"), binding("::", "8080")]),
+                    ),
+                    ("53/udp".into(), Some(vec![binding("192.168.1.5", "53")])),
+                    ("9000/tcp".into(), None),
+                ])),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        // Without a host address, only ports bound to one address count.
+        assert_eq!(host_ips_for(&detail, None), [ip("192.168.1.5")]);
+        assert_eq!(
+            host_ips_for(&detail, Some(ip("192.168.1.2"))),
+            [ip("192.168.1.2"), ip("192.168.1.5")]
+        );
+
+        let mut announced = Announced::default();
+        let published = Registration {
+            host_ips: vec![ip("192.168.1.2")],
+            ..reg("aaaa", "10.0.0.2")
+        };
+        assert_eq!(
+            announced.register("web-1", published),
+            Some(Update::Batch(vec![
+                Update::Add {
+                    name: "web-1".into(),
+                    ip: ip("10.0.0.2"),
+                },
+                Update::ScopedIps {
+                    name: "web-1".into(),
+                    ips: vec![ScopedIp {
+                        scope: HOST_SCOPE.into(),
+                        ip: ip("192.168.1.2"),
+                    }],
+                },
+            ]))
+        );
+    }
+
+    #[test]
+    fn labelled_txt_strings_are_published_with_the_name() {
+        let mut announced = Announced::default();
+        let labelled = Registration {
+            txt: vec!["role=api".into()],
+            ..reg("aaaa", "10.0.0.2")
+        };
+        assert_eq!(
+            announced.register("web-1", labelled),
+            Some(Update::Batch(vec![
+                Update::Add {
+                    name: "web-1".into(),
+                    ip: "10.0.0.2".parse().unwrap(),
+                },
+                Update::Txt {
+                    name: "web-1".into(),
+                    txt: vec!["role=api".into()],
+                },
+            ]))
+        );
+    }
+
+    /// A runtime watching `events`, or the default events when `None`.
+    fn runtime(events: Option<&[&str]>) -> DockerRuntime {
+        let mut cfg = Config {
+            docker_host: Some("tcp://127.0.0.1:1".into()),
+            exclude_self: false,
+            ..Config::default()
+        };
+        if let Some(events) = events {
+            cfg.docker_events = events.iter().map(|e| e.to_string()).collect();
+        }
+        DockerRuntime::new("app".into(), &cfg).unwrap()
+    }
+
+    fn event(
+        typ: EventMessageTypeEnum,
+        action: &str,
+        id: &str,
+        attrs: &[(&str, &str)],
+    ) -> EventMessage {
+        EventMessage {
+            typ: Some(typ),
+            action: Some(action.into()),
+            actor: Some(bollard::models::EventActor {
+                id: Some(id.into()),
+                attributes: Some(
+                    attrs
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect(),
+                ),
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn container_event(action: &str, id: &str, name: &str) -> EventMessage {
+        event(
+            EventMessageTypeEnum::CONTAINER,
+            action,
+            id,
+            &[("name", name)],
+        )
+    }
+
+    /// Runs `event` through the runtime, returning what it published.
+    async fn handle(
+        runtime: &DockerRuntime,
+        state: &mut MonitorState,
+        event: EventMessage,
+    ) -> Vec<Update> {
+        let docker = runtime.host.connect().unwrap();
+        let (tx, mut rx) = mpsc::channel(8);
+        let mut inspects = Inspects::new(1, Duration::from_secs(1));
+        runtime
+            .handle_event(&docker, event, state, &mut inspects, &tx)
+            .await
+            .unwrap();
+        drop(tx);
+        let mut published = Vec::new();
+        while let Some((update, source)) = rx.recv().await {
+            assert_eq!(source, LocalSource::DockerEvent);
+            published.push(update);
+        }
+        published
+    }
+
+    #[tokio::test]
+    async fn start_event_schedules_the_container_and_counts_the_start() {
+        let runtime = runtime(Some(&["start"]));
+        let mut state = MonitorState::default();
+        let published = handle(
+            &runtime,
+            &mut state,
+            container_event("start", "aaaa", "web-1"),
+        )
+        .await;
+        assert!(published.is_empty());
+        assert!(state.pending.contains_key("web-1"));
+        assert_eq!(state.starts["web-1"].len(), 1);
+    }
+
+    #[tokio::test]
+    async fn die_stop_kill_oom_and_health_events_recheck_the_container() {
+        let runtime = runtime(None);
+        for action in ["die", "kill", "stop", "oom", "health_status: unhealthy"] {
+            assert_eq!(
+                runtime.classify(container_event(action, "aaaa", "web-1")),
+                Some(DockerEvent::Changed {
+                    action: action.into(),
+                    name: "web-1".into(),
+                    id: "aaaa".into(),
+                }),
+                "{}",
+                action
+            );
+            let mut state = MonitorState::default();
+            handle(
+                &runtime,
+                &mut state,
+                container_event(action, "aaaa", "web-1"),
+            )
+            .await;
+            assert!(state.pending.contains_key("web-1"), "{}", action);
+        }
+
+        // A late event from a container replaced under the same name is ignored.
+        let mut state = MonitorState::default();
+        state.announced.register("web-1", reg("bbbb", "10.0.0.3"));
+        handle(
+            &runtime,
+            &mut state,
+            container_event("die", "aaaa", "web-1"),
+        )
+        .await;
+        assert!(state.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn destroy_event_withdraws_the_name_at_once() {
+        let runtime = runtime(Some(&["destroy"]));
+        let mut state = MonitorState::default();
+        state.announced.register("web-1", reg("aaaa", "10.0.0.2"));
+        state.pending.insert("web-1".into(), Instant::now());
+
+        // Destroying a replaced container leaves its successor alone.
+        let published = handle(
+            &runtime,
+            &mut state,
+            container_event("destroy", "bbbb", "web-1"),
+        )
+        .await;
+        assert!(published.is_empty());
+        assert!(state.announced.get("web-1").is_some());
+
+        let published = handle(
+            &runtime,
+            &mut state,
+            container_event("destroy", "aaaa", "web-1"),
+        )
+        .await;
+        assert_eq!(
+            published,
+            [Update::Remove {
+                name: "web-1".into()
+            }]
+        );
+        assert!(state.announced.get("web-1").is_none());
+        assert!(state.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rename_event_withdraws_the_old_name_and_schedules_the_new() {
+        let runtime = runtime(Some(&["rename"]));
+        let mut state = MonitorState::default();
+        state.announced.register("web-1", reg("aaaa", "10.0.0.2"));
+        let rename = event(
+            EventMessageTypeEnum::CONTAINER,
+            "rename",
+            "aaaa",
+            &[("name", "web-2"), ("oldName", "/web-1")],
+        );
+        let published = handle(&runtime, &mut state, rename).await;
+        assert_eq!(
+            published,
+            [Update::Remove {
+                name: "web-1".into()
+            }]
+        );
+        assert!(state.announced.get("web-1").is_none());
+        assert!(state.pending.contains_key("web-2"));
+    }
+
+    #[test]
+    fn network_events_only_count_for_the_monitored_network() {
+        let runtime = runtime(Some(&["connect", "disconnect"]));
+        let network_event = |action, network| {
+            event(
+                EventMessageTypeEnum::NETWORK,
+                action,
+                "net-id",
+                &[("name", network), ("container", "aaaa")],
+            )
+        };
+        assert_eq!(
+            runtime.classify(network_event("connect", "app")),
+            Some(DockerEvent::Network {
+                action: "connect".into(),
+                container: "aaaa".into(),
+            })
+        );
+        assert!(runtime
+            .classify(network_event("disconnect", "app"))
+            .is_some());
+        assert_eq!(runtime.classify(network_event("connect", "other")), None);
+    }
+
+    #[test]
+    fn unlisted_actions_are_ignored_and_the_list_is_configurable() {
+        let pause = || container_event("pause", "aaaa", "web-1");
+        assert_eq!(runtime(None).classify(pause()), None);
+        assert_eq!(
+            runtime(Some(&["start"])).classify(container_event("stop", "aaaa", "web-1")),
+            None
+        );
+        assert!(matches!(
+            runtime(Some(&["pause"])).classify(pause()),
+            Some(DockerEvent::Changed { .. })
+        ));
+    }
+
+    fn running(id: &str, ip: &str) -> Inspection {
+        use bollard::models::{ContainerState, EndpointSettings, NetworkSettings};
+
+        Inspection::Found(Box::new(ContainerInspectResponse {
+            id: Some(id.into()),
+            name: Some("/web-1".into()),
+            state: Some(ContainerState {
+                running: Some(true),
+                ..Default::default()
+            }),
+            network_settings: Some(NetworkSettings {
+                networks: Some(HashMap::from([(
+                    "app".to_string(),
+                    EndpointSettings {
+                        ip_address: Some(ip.into()),
+                        ..Default::default()
+                    },
+                )])),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }))
+    }
+
+    #[tokio::test]
+    async fn slow_inspects_never_land_after_later_events() {
+        let runtime = runtime(None);
+        let mut state = MonitorState::default();
+        state.announced.register("web-1", reg("aaaa", "10.0.0.2"));
+        let (tx, mut rx) = mpsc::channel(8);
+
+        // Inspects for web-1 finishing in the given order, each after its delay.
+        let mut tasks = JoinSet::new();
+        fn inspect(
+            tasks: &mut JoinSet<Inspected>,
+            state: &mut MonitorState,
+            delay: u64,
+            inspection: Inspection,
+        ) {
+            let token = state.stamp("web-1");
+            tasks.spawn(async move {
+                sleep(Duration::from_millis(delay)).await;
+                Inspected::Settle {
+                    name: "web-1".into(),
+                    token,
+                    inspection,
+                }
+            });
+        }
+
+        // A restart: the inspect after start is slow and sees the new address,
+        // the one after the following die is quick and finds it gone.
+        runtime.schedule(&mut state, "web-1".into());
+        inspect(&mut tasks, &mut state, 150, running("aaaa", "10.0.0.3"));
+        runtime.schedule(&mut state, "web-1".into());
+        inspect(&mut tasks, &mut state, 10, Inspection::Gone);
+        while let Some(inspected) = tasks.join_next().await {
+            runtime
+                .inspected(&mut state, &tx, inspected.unwrap())
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            rx.try_recv(),
+            Ok((
+                Update::Remove {
+                    name: "web-1".into()
+                },
+                LocalSource::DockerEvent
+            ))
+        );
+        assert!(
+            rx.try_recv().is_err(),
+            "stale Add published after the Remove"
+        );
+        assert!(state.announced.get("web-1").is_none());
+
+        // A destroy overtakes an inspect still running.
+        state.announced.register("web-1", reg("aaaa", "10.0.0.2"));
+        inspect(&mut tasks, &mut state, 100, running("aaaa", "10.0.0.4"));
+        let published = handle(
+            &runtime,
+            &mut state,
+            container_event("destroy", "aaaa", "web-1"),
+        )
+        .await;
+        assert_eq!(
+            published,
+            [Update::Remove {
+                name: "web-1".into()
+            }]
+        );
+        let inspected = tasks.join_next().await.unwrap().unwrap();
+        runtime.inspected(&mut state, &tx, inspected).await.unwrap();
+        assert!(rx.try_recv().is_err());
+        assert!(state.announced.get("web-1").is_none());
+
+        // The latest inspect is applied.
+        let token = state.stamp("web-1");
+        let latest = Inspected::Settle {
+            name: "web-1".into(),
+            token,
+            inspection: running("bbbb", "10.0.0.5"),
+        };
+        runtime.inspected(&mut state, &tx, latest).await.unwrap();
+        assert!(
+            matches!(rx.try_recv(), Ok((Update::Add { ip, .. }, _)) if ip.to_string() == "10.0.0.5")
+        );
+        assert!(state.latest.is_empty());
+    }
 }
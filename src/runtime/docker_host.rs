@@ -0,0 +1,268 @@
+//! Resolution of the Docker daemon address.
+//!
+//! Mirrors the Docker CLI: explicit config wins over `DOCKER_HOST`, and TLS is
+//! used for TCP daemons when `DOCKER_TLS_VERIFY` is set, the scheme is
+//! `https://`, or certificate paths are configured.  Certificates default to
+//! `ca.pem`/`cert.pem`/`key.pem` in `DOCKER_CERT_PATH` (or `~/.docker`).
+//!
+//! On Windows the local daemon is reached through the named pipe
+//! `//./pipe/docker_engine`, or another one given as `npipe://`.
+
+use std::fmt;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Result};
+use bollard::{Docker, API_DEFAULT_VERSION};
+
+use crate::config::Config;
+
+/// Request timeout handed to bollard, matching its own default.
+const DOCKER_TIMEOUT_SECS: u64 = 120;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DockerHost {
+    /// The platform's default local socket.
+    Local,
+    /// A unix socket, as `unix:///path`.
+    Unix(String),
+    /// A Windows named pipe, as `npipe:////./pipe/name`.
+    NamedPipe(String),
+    /// Plain TCP, as `tcp://host:port`.
+    Http(String),
+    /// TCP with TLS client authentication.
+    Tls {
+        addr: String,
+        ca: PathBuf,
+        cert: PathBuf,
+        key: PathBuf,
+    },
+}
+
+impl DockerHost {
+    /// Resolves the daemon address from config, falling back to the standard
+    /// Docker environment variables looked up through `env`.
+    pub fn resolve(cfg: &Config, env: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let host = cfg
+            .docker_host
+            .clone()
+            .or_else(|| env("DOCKER_HOST"))
+            .filter(|h| !h.is_empty());
+        let explicit_tls =
+            cfg.docker_ca.is_some() || cfg.docker_cert.is_some() || cfg.docker_key.is_some();
+
+        let Some(host) = host else {
+            if explicit_tls {
+                bail!("docker_ca/docker_cert/docker_key are set but no docker_host is configured");
+            }
+            return Ok(Self::Local);
+        };
+
+        let (scheme, rest) = host
+            .split_once("://")
+            .ok_or_else(|| anyhow!("Invalid Docker host '{}': expected scheme://address", host))?;
+        if rest.is_empty() {
+            bail!("Invalid Docker host '{}': missing address", host);
+        }
+
+        match scheme {
+            "unix" => Ok(Self::Unix(host.clone())),
+            "npipe" => Ok(Self::NamedPipe(host.clone())),
+            "tcp" | "http" | "https" => {
+                let addr = format!("tcp://{}", rest);
+                let tls_verify =
+                    env("DOCKER_TLS_VERIFY").is_some_and(|v| !v.is_empty() && v != "0");
+                if scheme != "https" && !tls_verify && !explicit_tls {
+                    return Ok(Self::Http(addr));
+                }
+
+                let cert_dir = env("DOCKER_CERT_PATH")
+                    .filter(|p| !p.is_empty())
+                    .map(PathBuf::from)
+                    .or_else(|| env("HOME").map(|home| PathBuf::from(home).join(".docker")));
+                let pick = |explicit: &Option<String>, file: &str| {
+                    explicit
+                        .as_ref()
+                        .map(PathBuf::from)
+                        .or_else(|| cert_dir.as_ref().map(|dir| dir.join(file)))
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "TLS requested for {} but no location for {} is known",
+                                host,
+                                file
+                            )
+                        })
+                };
+                Ok(Self::Tls {
+                    ca: pick(&cfg.docker_ca, "ca.pem")?,
+                    cert: pick(&cfg.docker_cert, "cert.pem")?,
+                    key: pick(&cfg.docker_key, "key.pem")?,
+                    addr,
+                })
+            }
+            other => bail!("Unsupported Docker host scheme '{}' in '{}'", other, host),
+        }
+    }
+
+    /// Builds a client.  This does not contact the daemon, but it does load
+    /// TLS material, so certificate problems surface here.
+    pub fn connect(&self) -> Result<Docker> {
+        let docker = match self {
+            Self::Local => Docker::connect_with_local_defaults(),
+            #[cfg(unix)]
+            Self::Unix(path) => {
+                Docker::connect_with_unix(path, DOCKER_TIMEOUT_SECS, API_DEFAULT_VERSION)
+            }
+            #[cfg(not(unix))]
+            Self::Unix(path) => bail!("Unix socket Docker host {} needs a Unix system", path),
+            #[cfg(windows)]
+            Self::NamedPipe(path) => {
+                Docker::connect_with_named_pipe(path, DOCKER_TIMEOUT_SECS, API_DEFAULT_VERSION)
+            }
+            #[cfg(not(windows))]
+            Self::NamedPipe(path) => bail!("Named pipe Docker host {} needs Windows", path),
+            Self::Http(addr) => {
+                Docker::connect_with_http(addr, DOCKER_TIMEOUT_SECS, API_DEFAULT_VERSION)
+            }
+            Self::Tls {
+                addr,
+                ca,
+                cert,
+                key,
+            } => {
+                for path in [ca, cert, key] {
+                    std::fs::metadata(path).map_err(|e| {
+                        anyhow!("Cannot read Docker TLS file {}: {}", path.display(), e)
+                    })?;
+                }
+                Docker::connect_with_ssl(
+                    addr,
+                    key,
+                    cert,
+                    ca,
+                    DOCKER_TIMEOUT_SECS,
+                    API_DEFAULT_VERSION,
+                )
+            }
+        };
+        docker.map_err(|e| anyhow!("Failed to set up Docker client for {}: {}", self, e))
+    }
+}
+
+impl fmt::Display for DockerHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Local => write!(f, "local socket"),
+            Self::Unix(path) | Self::NamedPipe(path) => write!(f, "{}", path),
+            Self::Http(addr) => write!(f, "{}", addr),
+            Self::Tls { addr, .. } => write!(f, "{} (TLS)", addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn resolve(cfg: &Config, env: &[(&str, &str)]) -> Result<DockerHost> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        DockerHost::resolve(cfg, |k| env.get(k).cloned())
+    }
+
+    #[test]
+    fn defaults_to_local_socket() {
+        assert_eq!(resolve(&Config::default(), &[]).unwrap(), DockerHost::Local);
+    }
+
+    #[test]
+    fn docker_host_env_selects_scheme() {
+        let cfg = Config::default();
+        assert_eq!(
+            resolve(&cfg, &[("DOCKER_HOST", "unix:///run/docker.sock")]).unwrap(),
+            DockerHost::Unix("unix:///run/docker.sock".into())
+        );
+        assert_eq!(
+            resolve(&cfg, &[("DOCKER_HOST", "tcp://10.0.0.5:2375")]).unwrap(),
+            DockerHost::Http("tcp://10.0.0.5:2375".into())
+        );
+        assert_eq!(
+            resolve(&cfg, &[("DOCKER_HOST", "npipe:////./pipe/docker_engine")]).unwrap(),
+            DockerHost::NamedPipe("npipe:////./pipe/docker_engine".into())
+        );
+        assert!(resolve(&cfg, &[("DOCKER_HOST", "ssh://box")]).is_err());
+        assert!(resolve(&cfg, &[("DOCKER_HOST", "10.0.0.5:2375")]).is_err());
+        assert!(resolve(&cfg, &[("DOCKER_HOST", "tcp://")]).is_err());
+    }
+
+    #[test]
+    fn tls_verify_uses_cert_path() {
+        let host = resolve(
+            &Config::default(),
+            &[
+                ("DOCKER_HOST", "tcp://10.0.0.5:2376"),
+                ("DOCKER_TLS_VERIFY", "1"),
+                ("DOCKER_CERT_PATH", "/certs"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            host,
+            DockerHost::Tls {
+                addr: "tcp://10.0.0.5:2376".into(),
+                ca: "/certs/ca.pem".into(),
+                cert: "/certs/cert.pem".into(),
+                key: "/certs/key.pem".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn config_overrides_environment() {
+        let cfg = Config {
+            docker_host: Some("https://daemon:2376".into()),
+            docker_ca: Some("/etc/glued/ca.pem".into()),
+            ..Config::default()
+        };
+        let host = resolve(
+            &cfg,
+            &[
+                ("DOCKER_HOST", "unix:///var/run/docker.sock"),
+                ("HOME", "/root"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            host,
+            DockerHost::Tls {
+                addr: "tcp://daemon:2376".into(),
+                ca: "/etc/glued/ca.pem".into(),
+                cert: "/root/.docker/cert.pem".into(),
+                key: "/root/.docker/key.pem".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn tls_files_without_host_is_an_error() {
+        let cfg = Config {
+            docker_cert: Some("/cert.pem".into()),
+            ..Config::default()
+        };
+        assert!(resolve(&cfg, &[]).is_err());
+    }
+
+    #[test]
+    fn missing_certificates_fail_on_connect() {
+        let host = DockerHost::Tls {
+            addr: "tcp://daemon:2376".into(),
+            ca: "/nonexistent/ca.pem".into(),
+            cert: "/nonexistent/cert.pem".into(),
+            key: "/nonexistent/key.pem".into(),
+        };
+        let err = host.connect().unwrap_err().to_string();
+        assert!(err.contains("/nonexistent/ca.pem"), "{}", err);
+    }
+}
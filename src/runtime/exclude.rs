@@ -0,0 +1,196 @@
+//! Containers that must never be published: the glued container itself and
+//! any infrastructure matched by the `exclude_names`/`exclude_labels` globs.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use glob::Pattern;
+
+use crate::config::Config;
+
+pub struct Exclusions {
+    /// Our own container ID (or its 12-character prefix), when running in Docker.
+    self_id: Option<String>,
+    names: Vec<Pattern>,
+    /// `(key, value)` patterns; a missing value matches any value of the key.
+    labels: Vec<(Pattern, Option<Pattern>)>,
+}
+
+impl Exclusions {
+    pub fn from_config(cfg: &Config) -> Result<Self> {
+        let compile = |p: &str| {
+            Pattern::new(p).map_err(|e| anyhow!("Invalid exclude pattern '{}': {}", p, e))
+        };
+
+        let names = cfg
+            .exclude_names
+            .iter()
+            .map(|p| compile(p))
+            .collect::<Result<_>>()?;
+        let labels = cfg
+            .exclude_labels
+            .iter()
+            .map(|p| match p.split_once('=') {
+                Some((key, value)) => Ok((compile(key)?, Some(compile(value)?))),
+                None => Ok((compile(p)?, None)),
+            })
+            .collect::<Result<_>>()?;
+        let self_id = if cfg.exclude_self {
+            cfg.self_container_id
+                .clone()
+                .or_else(detect_self_container_id)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            self_id,
+            names,
+            labels,
+        })
+    }
+
+    pub fn self_id(&self) -> Option<&str> {
+        self.self_id.as_deref()
+    }
+
+    /// Returns why a container is excluded, or `None` if it may be published.
+    pub fn reason(
+        &self,
+        id: &str,
+        name: &str,
+        labels: Option<&HashMap<String, String>>,
+    ) -> Option<String> {
+        if let Some(self_id) = &self.self_id {
+            if !id.is_empty() && id.starts_with(self_id.as_str()) {
+                return Some("it is the glued container itself".into());
+            }
+        }
+        if let Some(p) = self.names.iter().find(|p| p.matches(name)) {
+            return Some(format!("name matches '{}'", p));
+        }
+        for (key, value) in labels.into_iter().flatten() {
+            for (kp, vp) in &self.labels {
+                if kp.matches(key) && vp.as_ref().is_none_or(|vp| vp.matches(value)) {
+                    return Some(format!("label {}={} is excluded", key, value));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Finds the ID of the container this process runs in, if any.
+///
+/// cgroup v1 exposes the full ID in `/proc/self/cgroup`; with cgroup v2 it only
+/// shows up in the bind mounts Docker sets up (`/etc/hostname` etc.).  As a
+/// last resort Docker's default hostname is the 12-character short ID, which
+/// Windows containers only expose, upper-cased, as `COMPUTERNAME`.
+pub fn detect_self_container_id() -> Option<String> {
+    let from_file = |path: &str| {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| find_container_id(&contents))
+    };
+    let from_hostname = |var: &str| {
+        std::env::var(var)
+            .ok()
+            .map(|h| h.to_ascii_lowercase())
+            .filter(|h| h.len() == 12 && h.chars().all(|c| c.is_ascii_hexdigit()))
+    };
+    from_file("/proc/self/cgroup")
+        .or_else(|| from_file("/proc/self/mountinfo"))
+        .or_else(|| from_hostname("HOSTNAME"))
+        .or_else(|| from_hostname("COMPUTERNAME"))
+}
+
+/// Extracts a 64-hex-digit container ID that appears as a path segment
+/// following `docker` or `containers`, or as a systemd `docker-<id>.scope` unit.
+fn find_container_id(contents: &str) -> Option<String> {
+    let is_id = |s: &str| s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit());
+    contents.lines().find_map(|line| {
+        let segments: Vec<&str> = line.split(['/', ' ']).collect();
+        segments.iter().enumerate().find_map(|(i, segment)| {
+            let scope = segment
+                .strip_prefix("docker-")
+                .and_then(|s| s.strip_suffix(".scope"));
+            if let Some(id) = scope.filter(|s| is_id(s)) {
+                return Some(id.to_string());
+            }
+            let parent = i.checked_sub(1).map(|j| segments[j]);
+            (is_id(segment) && matches!(parent, Some("docker" | "containers")))
+                .then(|| segment.to_string())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "3f4b8e1f0b6f4fcbb2a8d1c5e9e0a7f6c2d4b1a0e9f8c7d6b5a4f3e2d1c0b9a8";
+
+    #[test]
+    fn finds_container_id_in_cgroup_and_mountinfo() {
+        let v1 = format!("12:pids:/docker/{}\n0::/", ID);
+        assert_eq!(find_container_id(&v1).as_deref(), Some(ID));
+
+        let systemd = format!("0::/system.slice/docker-{}.scope", ID);
+        assert_eq!(find_container_id(&systemd).as_deref(), Some(ID));
+
+        let v2 = format!(
+            "612 600 0:45 /var/lib/docker/containers/{}/hostname /etc/hostname rw",
+            ID
+        );
+        assert_eq!(find_container_id(&v2).as_deref(), Some(ID));
+
+        assert_eq!(find_container_id("0::/user.slice"), None);
+    }
+
+    #[test]
+    fn matches_self_names_and_labels() {
+        let cfg = Config {
+            exclude_self: false,
+            exclude_names: vec!["cadvisor*".into()],
+            exclude_labels: vec!["com.example.infra".into(), "tier=mon*".into()],
+            ..Config::default()
+        };
+        let mut ex = Exclusions::from_config(&cfg).unwrap();
+        ex.self_id = Some(ID[..12].to_string());
+
+        assert!(ex.reason(ID, "glued-replica", None).is_some());
+        assert!(ex.reason("other", "cadvisor-1", None).is_some());
+        assert!(ex.reason("other", "web-1", None).is_none());
+
+        let labels = |k: &str, v: &str| HashMap::from([(k.to_string(), v.to_string())]);
+        assert!(ex
+            .reason("other", "web-1", Some(&labels("com.example.infra", "yes")))
+            .is_some());
+        assert!(ex
+            .reason("other", "web-1", Some(&labels("tier", "monitoring")))
+            .is_some());
+        assert!(ex
+            .reason("other", "web-1", Some(&labels("tier", "frontend")))
+            .is_none());
+    }
+
+    #[test]
+    fn configured_self_id_wins() {
+        let cfg = Config {
+            self_container_id: Some(ID[..12].to_string()),
+            ..Config::default()
+        };
+        let ex = Exclusions::from_config(&cfg).unwrap();
+        assert_eq!(ex.self_id(), Some(&ID[..12]));
+        assert!(ex.reason(ID, "glued", None).is_some());
+    }
+
+    #[test]
+    fn invalid_pattern_is_rejected() {
+        let cfg = Config {
+            exclude_names: vec!["[".into()],
+            ..Config::default()
+        };
+        assert!(Exclusions::from_config(&cfg).is_err());
+    }
+}
@@ -0,0 +1,402 @@
+//! Active health probes of the containers this node publishes.
+//!
+//! A running container can still be unreachable, say across a network
+//! partition.  With `health_check` set, or a `glued.healthcheck` label on
+//! the container, its address is probed every `health_interval_secs` plus
+//! up to `health_jitter_ms`: `tcp:<port>` connects, `http:<port>[/path]`
+//! also sends a GET and wants a 2xx or 3xx status.  After `health_fall`
+//! failures in a row its names are withdrawn, and after `health_rise`
+//! successes they are published again.
+//!
+//! Only the node running a container probes it; the others learn of the
+//! outcome over gossip like any other change.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time::{sleep_until, Instant};
+
+use crate::config::Config;
+use crate::metrics::{self, METRICS};
+
+/// Container label setting its probe, or `none` for none.
+pub const HEALTHCHECK_LABEL: &str = "glued.healthcheck";
+
+/// How a container is probed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Probe {
+    /// Connects to the port.
+    Tcp(u16),
+    /// Sends `GET <path>` to the port.
+    Http { port: u16, path: String },
+}
+
+impl FromStr for Probe {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let (scheme, target) = spec
+            .split_once(':')
+            .ok_or_else(|| anyhow!("'{}' isn't <scheme>:<port>", spec))?;
+        let (port, path) = match target.find('/') {
+            Some(at) => target.split_at(at),
+            None => (target, "/"),
+        };
+        let port: u16 = port
+            .parse()
+            .ok()
+            .filter(|port| *port != 0)
+            .ok_or_else(|| anyhow!("'{}' has no valid port", spec))?;
+        match scheme {
+            "tcp" if target.contains('/') => bail!("'{}': tcp probes take no path", spec),
+            "tcp" => Ok(Probe::Tcp(port)),
+            "http" => Ok(Probe::Http {
+                port,
+                path: path.to_string(),
+            }),
+            "icmp" => bail!("'{}': icmp needs raw sockets; probe with tcp or http", spec),
+            _ => bail!("'{}': unknown scheme {}", spec, scheme),
+        }
+    }
+}
+
+impl Probe {
+    /// Whether `ip` passes within `timeout`.
+    async fn run(&self, ip: IpAddr, timeout: Duration) -> Result<()> {
+        let probe = async {
+            match self {
+                Probe::Tcp(port) => {
+                    TcpStream::connect(SocketAddr::new(ip, *port)).await?;
+                    Ok(())
+                }
+                Probe::Http { port, path } => {
+                    let addr = SocketAddr::new(ip, *port);
+                    let mut stream = TcpStream::connect(addr).await?;
+                    let request = format!(
+                        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: glued\r\n\r\n",
+                        path, addr
+                    );
+                    stream.write_all(request.as_bytes()).await?;
+                    // The status line is all that matters.
+                    let mut head = [0; 12];
+                    stream.read_exact(&mut head).await?;
+                    let status = std::str::from_utf8(&head[9..])
+                        .ok()
+                        .and_then(|code| code.parse::<u16>().ok())
+                        .filter(|_| head.starts_with(b"HTTP/"))
+                        .ok_or_else(|| anyhow!("not an HTTP response"))?;
+                    if !(200..400).contains(&status) {
+                        bail!("status {}", status);
+                    }
+                    Ok(())
+                }
+            }
+        };
+        tokio::time::timeout(timeout, probe)
+            .await
+            .map_err(|_| anyhow!("no answer within {:?}", timeout))?
+    }
+}
+
+/// How containers are probed, and how many results change their state.
+#[derive(Debug, Clone)]
+pub struct HealthPolicy {
+    /// The probe of containers without a label.
+    pub default: Option<Probe>,
+    pub interval: Duration,
+    pub jitter: Duration,
+    pub timeout: Duration,
+    pub fall: u32,
+    pub rise: u32,
+}
+
+impl Default for HealthPolicy {
+    fn default() -> Self {
+        Self::from_config(&Config::default()).expect("the default config is valid")
+    }
+}
+
+impl HealthPolicy {
+    /// Fails on an invalid `health_check`, or thresholds of zero.
+    pub fn from_config(cfg: &Config) -> Result<Self> {
+        let default = cfg
+            .health_check
+            .as_deref()
+            .filter(|spec| *spec != "none")
+            .map(str::parse)
+            .transpose()
+            .context("Invalid health_check")?;
+        if cfg.health_fall == 0 || cfg.health_rise == 0 {
+            bail!("health_fall and health_rise must be at least 1");
+        }
+        Ok(Self {
+            default,
+            // A zero interval would probe without pause.
+            interval: Duration::from_secs(cfg.health_interval_secs.max(1)),
+            jitter: Duration::from_millis(cfg.health_jitter_ms),
+            timeout: Duration::from_millis(cfg.health_timeout_ms),
+            fall: cfg.health_fall,
+            rise: cfg.health_rise,
+        })
+    }
+
+    /// The probe of a container with `labels`.  A label that doesn't parse
+    /// is warned about and the container isn't probed.
+    pub fn probe_for(&self, name: &str, labels: Option<&HashMap<String, String>>) -> Option<Probe> {
+        let Some(spec) = labels.and_then(|labels| labels.get(HEALTHCHECK_LABEL)) else {
+            return self.default.clone();
+        };
+        if spec == "none" {
+            return None;
+        }
+        match spec.parse() {
+            Ok(probe) => Some(probe),
+            Err(e) => {
+                warn!(
+                    "Not probing {}: invalid {} label: {:#}",
+                    name, HEALTHCHECK_LABEL, e
+                );
+                None
+            }
+        }
+    }
+
+    /// When to probe next, from `now`.
+    fn next_due(&self, now: Instant) -> Instant {
+        let jitter = match self.jitter.as_millis() as u64 {
+            0 => 0,
+            max => rand::random::<u64>() % (max + 1),
+        };
+        now + self.interval + Duration::from_millis(jitter)
+    }
+}
+
+/// A container's names starting or stopping to fail their probes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transition {
+    Down(String),
+    Up(String),
+}
+
+/// A probed container.
+#[derive(Debug)]
+struct Target {
+    ip: IpAddr,
+    probe: Probe,
+    healthy: bool,
+    /// Results in a row that disagree with `healthy`.
+    streak: u32,
+    due: Instant,
+    /// Told apart from the target a name had before, whose probe may
+    /// still be out.
+    generation: u64,
+    probing: bool,
+}
+
+/// The containers being probed, by name.
+#[derive(Debug, Default)]
+pub struct HealthChecks {
+    policy: HealthPolicy,
+    targets: HashMap<String, Target>,
+    probes: JoinSet<(String, u64, Result<()>)>,
+    generation: u64,
+}
+
+impl HealthChecks {
+    pub fn new(policy: HealthPolicy) -> Self {
+        Self {
+            policy,
+            ..Self::default()
+        }
+    }
+
+    pub fn policy(&self) -> &HealthPolicy {
+        &self.policy
+    }
+
+    /// Probes `ip` for `name`, starting over as healthy if it was probed
+    /// at another address or otherwise.
+    pub fn track(&mut self, name: &str, ip: IpAddr, probe: &Probe) {
+        if self
+            .targets
+            .get(name)
+            .is_some_and(|target| target.ip == ip && target.probe == *probe)
+        {
+            return;
+        }
+        self.generation += 1;
+        self.targets.insert(
+            name.to_string(),
+            Target {
+                ip,
+                probe: probe.clone(),
+                healthy: true,
+                streak: 0,
+                due: self.policy.next_due(Instant::now()),
+                generation: self.generation,
+                probing: false,
+            },
+        );
+    }
+
+    /// Stops probing `name`.
+    pub fn forget(&mut self, name: &str) {
+        self.targets.remove(name);
+    }
+
+    /// Probes what is due until a container's state changes.  Pending
+    /// while nothing is probed.  Cancelling it loses no result.
+    pub async fn next(&mut self) -> Transition {
+        loop {
+            let due = self
+                .targets
+                .values()
+                .filter(|target| !target.probing)
+                .map(|target| target.due)
+                .min();
+            tokio::select! {
+                () = sleep_until(due.unwrap_or_else(Instant::now)), if due.is_some() => {
+                    self.start_due();
+                }
+                Some(joined) = self.probes.join_next(), if !self.probes.is_empty() => {
+                    let Ok((name, generation, result)) = joined else {
+                        continue;
+                    };
+                    if let Some(transition) = self.record(&name, generation, result) {
+                        return transition;
+                    }
+                }
+                else => std::future::pending().await,
+            }
+        }
+    }
+
+    /// Starts the probes that are due.
+    fn start_due(&mut self) {
+        let now = Instant::now();
+        let timeout = self.policy.timeout;
+        for (name, target) in &mut self.targets {
+            if target.probing || target.due > now {
+                continue;
+            }
+            target.probing = true;
+            let (name, generation) = (name.clone(), target.generation);
+            let (ip, probe) = (target.ip, target.probe.clone());
+            self.probes.spawn(async move {
+                let result = probe.run(ip, timeout).await;
+                (name, generation, result)
+            });
+        }
+    }
+
+    /// Counts a probe's `result`, returning the transition it completes.
+    fn record(&mut self, name: &str, generation: u64, result: Result<()>) -> Option<Transition> {
+        let target = self
+            .targets
+            .get_mut(name)
+            .filter(|target| target.generation == generation)?;
+        target.probing = false;
+        target.due = self.policy.next_due(Instant::now());
+        if let Err(e) = &result {
+            metrics::inc(&METRICS.health_probes_failed);
+            debug!("Probe of {} at {} failed: {:#}", name, target.ip, e);
+        }
+        if result.is_ok() == target.healthy {
+            target.streak = 0;
+            return None;
+        }
+        target.streak += 1;
+        let needed = if target.healthy {
+            self.policy.fall
+        } else {
+            self.policy.rise
+        };
+        if target.streak < needed {
+            return None;
+        }
+        target.streak = 0;
+        target.healthy = !target.healthy;
+        Some(if target.healthy {
+            Transition::Up(name.to_string())
+        } else {
+            Transition::Down(name.to_string())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn probes_parse_from_specs() {
+        assert_eq!("tcp:8080".parse::<Probe>().unwrap(), Probe::Tcp(8080));
+        assert_eq!(
+            "http:80/healthz".parse::<Probe>().unwrap(),
+            Probe::Http {
+                port: 80,
+                path: "/healthz".into()
+            }
+        );
+        assert_eq!(
+            "http:80".parse::<Probe>().unwrap(),
+            Probe::Http {
+                port: 80,
+                path: "/".into()
+            }
+        );
+        for invalid in ["tcp", "tcp:0", "tcp:80/x", "udp:53", "icmp:1", "http:http"] {
+            assert!(invalid.parse::<Probe>().is_err(), "{}", invalid);
+        }
+
+        let policy = HealthPolicy {
+            default: Some(Probe::Tcp(80)),
+            ..HealthPolicy::default()
+        };
+        let labels = |spec: &str| HashMap::from([(HEALTHCHECK_LABEL.to_string(), spec.into())]);
+        assert_eq!(policy.probe_for("web-1", None), Some(Probe::Tcp(80)));
+        assert_eq!(
+            policy.probe_for("web-1", Some(&labels("tcp:5432"))),
+            Some(Probe::Tcp(5432))
+        );
+        assert_eq!(policy.probe_for("web-1", Some(&labels("none"))), None);
+        assert_eq!(policy.probe_for("web-1", Some(&labels("bogus"))), None);
+    }
+
+    #[tokio::test]
+    async fn names_go_down_after_failures_and_up_after_successes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let mut checks = HealthChecks::new(HealthPolicy {
+            default: None,
+            interval: Duration::from_millis(20),
+            jitter: Duration::ZERO,
+            timeout: Duration::from_millis(500),
+            fall: 2,
+            rise: 2,
+        });
+        let ip = "127.0.0.1".parse().unwrap();
+        checks.track("web-1", ip, &Probe::Tcp(port));
+
+        let within = Duration::from_secs(5);
+        let down = tokio::time::timeout(within, checks.next()).await.unwrap();
+        assert_eq!(down, Transition::Down("web-1".into()));
+        let _listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        let up = tokio::time::timeout(within, checks.next()).await.unwrap();
+        assert_eq!(up, Transition::Up("web-1".into()));
+
+        // Forgotten, nothing is probed.
+        checks.forget("web-1");
+        let idle = tokio::time::timeout(Duration::from_millis(100), checks.next()).await;
+        assert!(idle.is_err());
+    }
+}
@@ -0,0 +1,63 @@
+//! Scripted runtime for tests.
+
+use super::ContainerRuntime;
+use crate::registry::{LocalSource, LocalUpdate};
+use crate::types::Update;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+/// A [`ContainerRuntime`] that replays a fixed sequence of updates, each
+/// after its own delay, and then returns.
+#[derive(Debug, Clone, Default)]
+pub struct MockRuntime {
+    script: Vec<(Duration, Update)>,
+}
+
+impl MockRuntime {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `update`, sent `delay` after the previous step.
+    pub fn then(mut self, delay: Duration, update: Update) -> Self {
+        self.script.push((delay, update));
+        self
+    }
+
+    /// Appends an `Add` sent immediately after the previous step.  Panics
+    /// unless `ip` is an address.
+    pub fn add(self, name: &str, ip: &str) -> Self {
+        self.then(
+            Duration::ZERO,
+            Update::Add {
+                name: name.into(),
+                ip: ip.parse().expect("MockRuntime::add takes an IP address"),
+            },
+        )
+    }
+
+    /// Appends a `Remove` sent immediately after the previous step.
+    pub fn remove(self, name: &str) -> Self {
+        self.then(Duration::ZERO, Update::Remove { name: name.into() })
+    }
+}
+
+#[async_trait]
+impl ContainerRuntime for MockRuntime {
+    async fn monitor(&self, update_tx: mpsc::Sender<LocalUpdate>) -> Result<()> {
+        for (delay, update) in &self.script {
+            if !delay.is_zero() {
+                sleep(*delay).await;
+            }
+            // Standing in for Docker.
+            update_tx
+                .send((update.clone(), LocalSource::DockerEvent))
+                .await
+                .map_err(|_| anyhow!("Channel closed"))?;
+        }
+        Ok(())
+    }
+}
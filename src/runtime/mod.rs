@@ -1,14 +1,30 @@
-use crate::types::Update;
+use crate::registry::LocalUpdate;
 use anyhow::Result;
 use async_trait::async_trait;
 use tokio::sync::mpsc;
 
+#[cfg(feature = "daemon")]
+pub mod containerd;
+#[cfg(feature = "daemon")]
 pub mod docker;
+#[cfg(feature = "daemon")]
+mod docker_host;
+#[cfg(feature = "daemon")]
+mod exclude;
+#[cfg(feature = "daemon")]
+mod health;
+#[cfg(any(test, feature = "testing"))]
+pub mod mock;
+#[cfg(feature = "daemon")]
+pub use containerd::ContainerdRuntime;
+#[cfg(feature = "daemon")]
 pub use docker::DockerRuntime;
+#[cfg(any(test, feature = "testing"))]
+pub use mock::MockRuntime;
 
 #[async_trait]
 pub trait ContainerRuntime {
     /// Start monitoring the runtime for container changes.
-    /// Updates should be sent to the provided channel.
-    async fn monitor(&self, update_tx: mpsc::Sender<Update>) -> Result<()>;
+    /// Updates should be sent to the provided channel, with what made them.
+    async fn monitor(&self, update_tx: mpsc::Sender<LocalUpdate>) -> Result<()>;
 }
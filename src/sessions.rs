@@ -0,0 +1,179 @@
+//! Outbound sessions with bootstrap peers.
+//!
+//! The gossip maintenance loop keeps one authenticated connection to each
+//! bootstrap peer.  A peer is dialled, and the handshake run, only when it
+//! has no session or its connection has closed; a live session is left
+//! alone.  A new session closes the one it replaces, and sessions with
+//! peers no longer dialled, as after a reload denied them, are closed.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+
+use futures_util::future::join_all;
+use iroh::endpoint::Connection;
+use iroh::NodeId;
+
+use crate::types::now_millis;
+
+/// The connection a session holds.
+pub trait Link {
+    fn is_closed(&self) -> bool;
+    fn close(&self, reason: &'static [u8]);
+}
+
+impl Link for Connection {
+    fn is_closed(&self) -> bool {
+        self.close_reason().is_some()
+    }
+
+    fn close(&self, reason: &'static [u8]) {
+        Connection::close(self, 0u32.into(), reason);
+    }
+}
+
+/// An authenticated connection to one peer.
+#[derive(Debug)]
+pub struct Session<C> {
+    pub connection: C,
+    /// Unix time in milliseconds the handshake completed.
+    pub authenticated_at: u64,
+    /// Unix time in milliseconds the connection was last found open.
+    pub last_seen: u64,
+}
+
+/// Sessions by peer.
+#[derive(Debug)]
+pub struct SessionTable<C> {
+    sessions: BTreeMap<NodeId, Session<C>>,
+}
+
+impl<C> Default for SessionTable<C> {
+    fn default() -> Self {
+        Self {
+            sessions: BTreeMap::new(),
+        }
+    }
+}
+
+impl<C: Link> SessionTable<C> {
+    /// One round of upkeep for the sessions with `peers`: drops those whose
+    /// connection closed, closes those with other peers, and dials the
+    /// peers without one, all at once.  Returns how each dial went.
+    pub async fn maintain<F, Fut>(
+        &mut self,
+        peers: &[NodeId],
+        dial: F,
+    ) -> Vec<(NodeId, anyhow::Result<()>)>
+    where
+        F: Fn(NodeId) -> Fut,
+        Fut: Future<Output = anyhow::Result<C>>,
+    {
+        let now = now_millis();
+        self.sessions.retain(|peer, session| {
+            if !peers.contains(peer) {
+                session.connection.close(b"no longer dialled");
+                return false;
+            }
+            if session.connection.is_closed() {
+                return false;
+            }
+            session.last_seen = now;
+            true
+        });
+        let missing = peers
+            .iter()
+            .filter(|peer| !self.sessions.contains_key(peer))
+            .map(|&peer| {
+                let dial = &dial;
+                async move { (peer, dial(peer).await) }
+            });
+        let dialled = join_all(missing).await;
+        dialled
+            .into_iter()
+            .map(|(peer, result)| (peer, result.map(|conn| self.insert(peer, conn))))
+            .collect()
+    }
+
+    /// Records a session with `peer` over `connection`, closing the one it
+    /// supersedes.
+    pub fn insert(&mut self, peer: NodeId, connection: C) {
+        let now = now_millis();
+        let session = Session {
+            connection,
+            authenticated_at: now,
+            last_seen: now,
+        };
+        if let Some(old) = self.sessions.insert(peer, session) {
+            old.connection.close(b"superseded");
+        }
+    }
+
+    pub fn get(&self, peer: &NodeId) -> Option<&Session<C>> {
+        self.sessions.get(peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct MockLink(Arc<AtomicBool>);
+
+    impl Link for MockLink {
+        fn is_closed(&self) -> bool {
+            self.0.load(Ordering::SeqCst)
+        }
+
+        fn close(&self, _: &'static [u8]) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn id(byte: u8) -> NodeId {
+        NodeId::from_bytes(&[byte; 32]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn live_sessions_are_not_dialled_again() {
+        let handshakes = AtomicUsize::new(0);
+        let links: std::sync::Mutex<Vec<MockLink>> = Default::default();
+        let dial = |peer: NodeId| {
+            let (handshakes, links) = (&handshakes, &links);
+            async move {
+                if peer == id(3) {
+                    anyhow::bail!("unreachable");
+                }
+                handshakes.fetch_add(1, Ordering::SeqCst);
+                let link = MockLink::default();
+                links.lock().unwrap().push(link.clone());
+                Ok(link)
+            }
+        };
+        let mut table = SessionTable::default();
+
+        for _ in 0..5 {
+            let dialled = table.maintain(&[id(1), id(2), id(3)], dial).await;
+            // Only the peer without a session is dialled after the first round.
+            assert!(dialled
+                .iter()
+                .any(|(peer, result)| *peer == id(3) && result.is_err()));
+        }
+        assert_eq!(handshakes.load(Ordering::SeqCst), 2);
+        assert!(table.get(&id(3)).is_none());
+
+        // A closed connection is replaced; a peer no longer dialled is closed.
+        links.lock().unwrap()[0].close(b"");
+        table.maintain(&[id(1), id(2)], dial).await;
+        assert_eq!(handshakes.load(Ordering::SeqCst), 3);
+        table.maintain(&[id(1)], dial).await;
+        assert!(links.lock().unwrap()[1].is_closed());
+        assert!(table.get(&id(2)).is_none());
+
+        let replaced = table.get(&id(1)).unwrap().connection.clone();
+        table.insert(id(1), MockLink::default());
+        assert!(replaced.is_closed());
+    }
+}
@@ -0,0 +1,97 @@
+//! SRV records for the ports containers expose.
+//!
+//! `_<service>._<proto>.<name>` lists the ports of `<name>` with that
+//! protocol, each pointing at `<name>` itself.  Containers don't say which
+//! service a port carries, so the service label only narrows the answer
+//! when it is a port number (`_8080._tcp.web-1`); any other service lists
+//! every port of the protocol.
+
+use hickory_server::proto::rr::rdata::SRV;
+use hickory_server::proto::rr::{Name, RData};
+
+use crate::types::{Port, PortProtocol};
+
+/// A parsed SRV query name.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SrvQuery<'a> {
+    /// Set when the service label is a port number.
+    port: Option<u16>,
+    protocol: PortProtocol,
+    /// The name whose ports are asked for.
+    pub name: &'a str,
+}
+
+impl<'a> SrvQuery<'a> {
+    /// Splits `qname` (normalized), if it starts with `_service._proto`.
+    pub fn parse(qname: &'a str) -> Option<Self> {
+        let (service, rest) = qname.split_once('.')?;
+        let (protocol, name) = rest.split_once('.')?;
+        let service = service.strip_prefix('_')?;
+        let protocol = protocol.strip_prefix('_')?.parse().ok()?;
+        Some(Self {
+            port: service.parse().ok(),
+            protocol,
+            name,
+        })
+    }
+
+    /// SRV data for the ports in `ports` that match, pointing at `target`.
+    pub fn rdatas(&self, ports: &[Port], target: &Name) -> Vec<RData> {
+        ports
+            .iter()
+            .filter(|p| p.protocol == self.protocol && self.port.is_none_or(|port| port == p.port))
+            .map(|p| RData::SRV(SRV::new(0, 0, p.port, target.clone())))
+            .collect()
+    }
+}
+
+/// The name an SRV query asks about: `owner` without its first two labels.
+pub fn target(owner: &Name) -> Name {
+    owner.trim_to(usize::from(owner.num_labels()).saturating_sub(2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_and_protocol_select_ports() {
+        let ports = [
+            Port {
+                port: 53,
+                protocol: PortProtocol::Udp,
+            },
+            Port {
+                port: 80,
+                protocol: PortProtocol::Tcp,
+            },
+            Port {
+                port: 443,
+                protocol: PortProtocol::Tcp,
+            },
+        ];
+        let owner = Name::from_ascii("_http._tcp.Web-1.glued.").unwrap();
+        let target = target(&owner);
+        assert_eq!(target.to_string(), "Web-1.glued.");
+        let served = |qname| {
+            SrvQuery::parse(qname)
+                .unwrap()
+                .rdatas(&ports, &target)
+                .into_iter()
+                .map(|rdata| match rdata {
+                    RData::SRV(srv) => srv.port(),
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(SrvQuery::parse("_http._tcp.web-1").unwrap().name, "web-1");
+        assert_eq!(served("_http._tcp.web-1"), [80, 443]);
+        assert_eq!(served("_443._tcp.web-1"), [443]);
+        assert_eq!(served("_dns._udp.web-1"), [53]);
+        assert_eq!(served("_8080._tcp.web-1"), Vec::<u16>::new());
+        assert_eq!(SrvQuery::parse("_http._quic.web-1"), None);
+        assert_eq!(SrvQuery::parse("http._tcp.web-1"), None);
+        assert_eq!(SrvQuery::parse("_tcp.web-1"), None);
+    }
+}
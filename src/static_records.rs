@@ -0,0 +1,99 @@
+//! Names from `static_records` in the config.
+//!
+//! Static records sit in the state map next to container entries, marked
+//! [`Source::Static`] so gossip can neither replace nor remove them.  They
+//! are local to the node: nothing about them is published.
+
+use std::collections::BTreeMap;
+
+use log::{info, warn};
+
+use crate::config::StaticRecord;
+use crate::names::{is_valid_label, normalize};
+use crate::types::{now_millis, Entry, Source, StateMap};
+
+/// Replaces the static records in `map` with `records`.  A static record
+/// takes the name over from a container entry.
+pub fn install(map: &mut StateMap, records: &BTreeMap<String, StaticRecord>) -> anyhow::Result<()> {
+    let mut entries = Vec::with_capacity(records.len());
+    for (name, record) in records {
+        let label = normalize(name);
+        if !is_valid_label(&label) {
+            anyhow::bail!("Static record '{}' is not a valid single DNS label", name);
+        }
+        let mut ips = record.ips.iter().copied();
+        let Some(ip) = ips.next() else {
+            anyhow::bail!("Static record '{}' has no addresses", name);
+        };
+        let entry = Entry {
+            ip,
+            extra_ips: ips.collect(),
+            txt: record.txt.clone(),
+            updated_at: now_millis(),
+            refreshed_at: None,
+            source: Source::Static,
+            node: None,
+            node_name: None,
+            ports: Vec::new(),
+            scoped_ips: Vec::new(),
+            expires_at: None,
+            shadowed: None,
+        };
+        entries.push((label, entry));
+    }
+
+    map.retain(|_, entry| entry.source != Source::Static);
+    for (name, entry) in entries {
+        if let Some(old) = map.insert(name.clone(), entry) {
+            warn!(
+                "Static record {} replaces the {:?} entry -> {}",
+                name, old.source, old.ip
+            );
+        }
+    }
+    if !records.is_empty() {
+        info!("Installed {} static records", records.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(ips: &[&str]) -> StaticRecord {
+        StaticRecord {
+            ips: ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+            txt: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn static_records_replace_containers_and_each_other() {
+        let mut map = StateMap::new();
+        map.insert("nas".into(), Entry::new("10.0.0.5".parse().unwrap()));
+        map.insert("web".into(), Entry::new("10.0.0.6".parse().unwrap()));
+
+        let mut records = BTreeMap::new();
+        records.insert("NAS".to_string(), record(&["192.168.1.10", "fd00::10"]));
+        records.insert("printer".to_string(), record(&["192.168.1.20"]));
+        install(&mut map, &records).unwrap();
+        assert_eq!(map["nas"].source, Source::Static);
+        assert_eq!(
+            map["nas"]
+                .ips()
+                .map(|ip| ip.to_string())
+                .collect::<Vec<_>>(),
+            ["192.168.1.10", "fd00::10"]
+        );
+        assert_eq!(map["web"].source, Source::Cluster);
+
+        records.remove("printer");
+        install(&mut map, &records).unwrap();
+        assert!(!map.contains_key("printer"));
+        assert_eq!(map.len(), 2);
+
+        records.insert("not_a_label".to_string(), record(&["192.168.1.30"]));
+        assert!(install(&mut map, &records).is_err());
+    }
+}
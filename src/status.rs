@@ -0,0 +1,168 @@
+//! Runtime status shared between subsystems and reported to operators.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::peers::PeerTable;
+use crate::resync::Resync;
+
+/// Live view of the daemon's health.
+#[derive(Debug, Default)]
+pub struct Status {
+    peers: RwLock<PeerTable>,
+    /// Only ready once at least one gossip neighbor is up.
+    require_neighbor: bool,
+    /// Where the DNS server actually listens, fallback ports included.
+    dns_addrs: RwLock<Vec<SocketAddr>>,
+    /// Set once the container runtime's first scan has been published.
+    scanned: AtomicBool,
+    /// Set once a peer's entries have come in over gossip.
+    synced: AtomicBool,
+    /// Woken on either.
+    changed: Notify,
+    /// Until when a node that is neither synced nor, with a runtime,
+    /// scanned is warming up; `None` never warms up.
+    warmup_until: Option<Instant>,
+    warmup_needs_scan: bool,
+    /// Reconciliation and resync requested by operators.
+    resync: Resync,
+    /// Set once another instance on the host has taken over; see
+    /// [`crate::control`].
+    handed_over: AtomicBool,
+}
+
+impl Status {
+    pub fn new(require_neighbor: bool) -> Self {
+        Self {
+            require_neighbor,
+            ..Self::default()
+        }
+    }
+
+    /// Warms up from now until a peer has answered our sync request and, if
+    /// `needs_scan`, the runtime's first scan, or at most for `timeout`.
+    /// A zero `timeout` doesn't warm up.
+    pub fn warming_up(self, timeout: Duration, needs_scan: bool) -> Self {
+        Self {
+            warmup_until: (!timeout.is_zero()).then(|| Instant::now() + timeout),
+            warmup_needs_scan: needs_scan,
+            ..self
+        }
+    }
+
+    pub fn peers(&self) -> RwLockReadGuard<'_, PeerTable> {
+        self.peers.read().unwrap()
+    }
+
+    pub fn peers_mut(&self) -> RwLockWriteGuard<'_, PeerTable> {
+        self.peers.write().unwrap()
+    }
+
+    pub fn resync(&self) -> &Resync {
+        &self.resync
+    }
+
+    pub fn dns_addrs(&self) -> Vec<SocketAddr> {
+        self.dns_addrs.read().unwrap().clone()
+    }
+
+    pub fn set_dns_addrs(&self, addrs: Vec<SocketAddr>) {
+        *self.dns_addrs.write().unwrap() = addrs;
+    }
+
+    pub fn set_scanned(&self) {
+        self.scanned.store(true, Ordering::Relaxed);
+        self.changed.notify_waiters();
+    }
+
+    /// Returns once the container runtime's first scan has been published.
+    pub async fn scanned(&self) {
+        loop {
+            // Created before the check, so a scan finishing in between
+            // still wakes it.
+            let done = self.changed.notified();
+            if self.scanned.load(Ordering::Relaxed) {
+                return;
+            }
+            done.await;
+        }
+    }
+
+    /// Notes that a peer has answered our sync request with its entries, or
+    /// that its digest matches ours.
+    pub fn set_synced(&self) {
+        if !self.synced.swap(true, Ordering::Relaxed) {
+            self.changed.notify_waiters();
+        }
+    }
+
+    /// What the node is still warming up for, if it is.
+    fn warming_up_for(&self) -> Option<&'static str> {
+        let until = self.warmup_until?;
+        if Instant::now() >= until {
+            return None;
+        }
+        if self.warmup_needs_scan && !self.scanned.load(Ordering::Relaxed) {
+            return Some("warming up: the first container scan isn't in");
+        }
+        if !self.synced.load(Ordering::Relaxed) {
+            return Some("warming up: no peer has answered our sync request yet");
+        }
+        None
+    }
+
+    /// Whether it may be too early to tell that a name doesn't exist.
+    pub fn is_warming_up(&self) -> bool {
+        self.warming_up_for().is_some()
+    }
+
+    /// Returns once the node is done warming up.
+    pub async fn warmed_up(&self) {
+        let Some(until) = self.warmup_until else {
+            return;
+        };
+        loop {
+            let changed = self.changed.notified();
+            if !self.is_warming_up() {
+                return;
+            }
+            tokio::select! {
+                () = changed => {}
+                () = tokio::time::sleep_until(until) => return,
+            }
+        }
+    }
+
+    /// Notes that another instance has taken over: this one publishes no
+    /// more changes of its runtime, stops answering sync requests and
+    /// reports itself unready.  Returns whether it had already.
+    pub fn hand_over(&self) -> bool {
+        self.handed_over.swap(true, Ordering::Relaxed)
+    }
+
+    pub fn is_handed_over(&self) -> bool {
+        self.handed_over.load(Ordering::Relaxed)
+    }
+
+    /// Why the node isn't in a state to serve, if it isn't.
+    pub fn unready(&self) -> Option<&'static str> {
+        if self.is_handed_over() {
+            return Some("handed over to another instance");
+        }
+        if let Some(warming_up) = self.warming_up_for() {
+            return Some(warming_up);
+        }
+        (self.require_neighbor && self.peers().neighbors() == 0)
+            .then_some("no gossip neighbors yet")
+    }
+
+    /// Whether the node is in a state to serve.
+    pub fn is_ready(&self) -> bool {
+        self.unready().is_none()
+    }
+}
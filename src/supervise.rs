@@ -0,0 +1,130 @@
+//! Restarting the subsystems the daemon can't do without.
+//!
+//! DNS, gossip and the container runtime monitor run under [`supervise`].
+//! None of them should ever stop, so when one panics, fails or returns it
+//! is logged and started again, after a pause that grows with each restart.
+//! Once it has been restarted `subsystem_restarts` times in a row, without
+//! running for [`HEALTHY_RUN`] in between, [`supervise`] returns and the
+//! daemon shuts down with an error: a node that can't serve DNS or hear its
+//! peers is better restarted whole by its supervisor than left running
+//! broken.  Failures weeks apart never add up to that.
+
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use futures_util::FutureExt;
+use log::{error, warn};
+use tokio::time::Instant;
+
+use crate::config::Config;
+
+/// Pause before the first restart; the n-th restart waits n times as long.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// How long a start has to run for its failure to count as the first again.
+pub const HEALTHY_RUN: Duration = Duration::from_secs(600);
+
+/// How often a subsystem is started again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestartPolicy {
+    pub max_restarts: u32,
+    pub delay: Duration,
+    /// A start that ran this long resets the count of restarts.
+    pub healthy_after: Duration,
+}
+
+impl RestartPolicy {
+    pub fn from_config(cfg: &Config) -> Self {
+        Self {
+            max_restarts: cfg.subsystem_restarts,
+            delay: RESTART_DELAY,
+            healthy_after: HEALTHY_RUN,
+        }
+    }
+}
+
+/// Runs the future `start` makes, making a new one each time it stops,
+/// until it has been restarted as often as `policy` allows without running
+/// healthily in between.  Returns why the subsystem stopped for good.
+/// Aborting the task running this stops the subsystem too.
+pub async fn supervise<F, Fut>(name: &str, policy: RestartPolicy, mut start: F) -> anyhow::Error
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let mut restarts = 0;
+    loop {
+        let started = Instant::now();
+        // The panic itself is logged by the hook `logging::init` installs.
+        let stopped = match AssertUnwindSafe(start()).catch_unwind().await {
+            Ok(Ok(())) => "stopped".to_string(),
+            Ok(Err(e)) => format!("failed: {:#}", e),
+            Err(_) => "panicked".to_string(),
+        };
+        error!("{} {}", name, stopped);
+        if started.elapsed() >= policy.healthy_after {
+            restarts = 0;
+        }
+        if restarts >= policy.max_restarts {
+            return anyhow!("{} {}; gave up after {} restarts", name, stopped, restarts);
+        }
+        restarts += 1;
+        let delay = policy.delay * restarts;
+        warn!(
+            "Restarting {} in {:?} ({} of {})",
+            name, delay, restarts, policy.max_restarts
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[tokio::test]
+    async fn restarts_until_the_policy_runs_out() {
+        let policy = RestartPolicy {
+            max_restarts: 2,
+            delay: Duration::from_millis(1),
+            healthy_after: Duration::from_secs(60),
+        };
+        let starts = AtomicU32::new(0);
+        let error = supervise("flaky", policy, || async {
+            match starts.fetch_add(1, Ordering::Relaxed) {
+                0 => panic!("first start"),
+                1 => anyhow::bail!("second start"),
+                _ => Ok(()),
+            }
+        })
+        .await;
+        assert_eq!(starts.load(Ordering::Relaxed), 3);
+        assert_eq!(error.to_string(), "flaky stopped; gave up after 2 restarts");
+    }
+
+    #[tokio::test]
+    async fn a_healthy_run_resets_the_count() {
+        let policy = RestartPolicy {
+            max_restarts: 1,
+            delay: Duration::from_millis(1),
+            healthy_after: Duration::from_millis(50),
+        };
+        let starts = AtomicU32::new(0);
+        let error = supervise("flaky", policy, || async {
+            // Fails at once, then after running a while, then at once.
+            if starts.fetch_add(1, Ordering::Relaxed) == 1 {
+                tokio::time::sleep(Duration::from_millis(60)).await;
+            }
+            anyhow::bail!("broken")
+        })
+        .await;
+        assert_eq!(starts.load(Ordering::Relaxed), 3);
+        assert_eq!(
+            error.to_string(),
+            "flaky failed: broken; gave up after 1 restarts"
+        );
+    }
+}
@@ -0,0 +1,207 @@
+//! systemd service notifications, for `Type=notify` units.
+//!
+//! Built with the `systemd` feature.  glued says `READY=1` once DNS is bound
+//! and, on a replica, the runtime's first scan is published, keeps
+//! `STATUS=` current with its entry and neighbor counts, and says
+//! `STOPPING=1` when it starts shutting down.  With `WatchdogSec=` set, the
+//! watchdog is only pinged while the registry can be locked and the DNS
+//! server answers, so systemd restarts a daemon that has wedged.
+//!
+//! Without `NOTIFY_SOCKET`, i.e. when not started by systemd, or built
+//! without the feature, all of this does nothing.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hickory_server::proto::op::{Message, MessageType, OpCode, Query};
+use hickory_server::proto::rr::{DNSClass, Name, RecordType};
+use log::warn;
+use tokio::net::UdpSocket;
+
+use crate::status::Status;
+use crate::types::SharedState;
+
+/// How often `STATUS=` is refreshed when there is no watchdog to ping.
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Whether glued was started by systemd and can notify it.
+pub fn enabled() -> bool {
+    cfg!(all(target_os = "linux", feature = "systemd"))
+        && std::env::var_os("NOTIFY_SOCKET").is_some()
+}
+
+/// Sends `message`, such as `READY=1`, to systemd if it is listening.
+pub fn notify(message: &str) {
+    #[cfg(all(target_os = "linux", feature = "systemd"))]
+    if let Err(e) = send(message) {
+        log::debug!("Failed to notify systemd: {}", e);
+    }
+    #[cfg(not(all(target_os = "linux", feature = "systemd")))]
+    let _ = message;
+}
+
+#[cfg(all(target_os = "linux", feature = "systemd"))]
+fn send(message: &str) -> std::io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket = UnixDatagram::unbound()?;
+    // A leading `@` names a socket in the abstract namespace.
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(&path)?,
+    };
+    socket.send_to_addr(message.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/// How often systemd expects a watchdog ping, if it does.
+pub fn watchdog_interval() -> Option<Duration> {
+    if !enabled() {
+        return None;
+    }
+    // Meant for another process, e.g. a wrapper script.
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Says `READY=1` once the runtime's first scan is in, or at once without
+/// a runtime, and the node is done warming up.
+pub async fn ready(status: Arc<Status>, replica: bool) {
+    if replica {
+        status.scanned().await;
+    }
+    status.warmed_up().await;
+    notify("READY=1");
+}
+
+/// Keeps `STATUS=` current and, with a watchdog, pings it while the node is
+/// responsive, until the task is aborted.
+pub async fn run_watchdog(state: SharedState, status: Arc<Status>) {
+    let watchdog = watchdog_interval();
+    // Twice per watchdog period, as sd_watchdog_enabled(3) suggests.
+    let period = watchdog.map_or(STATUS_INTERVAL, |interval| interval / 2);
+    let mut ticker = tokio::time::interval(period);
+    loop {
+        ticker.tick().await;
+        let Ok(entries) = tokio::time::timeout(period, state.read())
+            .await
+            .map(|map| map.len())
+        else {
+            warn!(
+                "Registry stayed locked for {:?}; not pinging the watchdog",
+                period
+            );
+            continue;
+        };
+        let neighbors = status.peers().neighbors();
+        notify(&format!(
+            "STATUS=Serving {} names, {} gossip neighbors",
+            entries, neighbors
+        ));
+        if watchdog.is_none() {
+            continue;
+        }
+        match tokio::time::timeout(period, probe_dns(&status.dns_addrs())).await {
+            Ok(Ok(())) => notify("WATCHDOG=1"),
+            Ok(Err(e)) => warn!("DNS server failed its watchdog probe: {:#}", e),
+            Err(_) => warn!(
+                "DNS server didn't answer its watchdog probe in {:?}",
+                period
+            ),
+        }
+    }
+}
+
+/// Asks the DNS server on the first of `addrs` for `id.server`, in the
+/// CHAOS class so no lookup or forwarding is involved.  Any answer, even
+/// REFUSED, shows it is serving.
+async fn probe_dns(addrs: &[SocketAddr]) -> anyhow::Result<()> {
+    let Some(&addr) = addrs.first() else {
+        anyhow::bail!("no DNS address");
+    };
+    // A wildcard bind answers on loopback.
+    let target = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), addr.port())
+        }
+        _ => addr,
+    };
+    let local: SocketAddr = if target.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let id = rand::random();
+    let mut query = Query::query(Name::from_ascii("id.server.")?, RecordType::TXT);
+    query.set_query_class(DNSClass::CH);
+    let mut message = Message::new();
+    message
+        .set_id(id)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .add_query(query);
+
+    let socket = UdpSocket::bind(local).await?;
+    socket.send_to(&message.to_vec()?, target).await?;
+    let mut buf = [0u8; 512];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        if from == target && Message::from_vec(&buf[..len]).is_ok_and(|reply| reply.id() == id) {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_server::{run_dns_server, DnsOptions, DnsSockets};
+
+    #[tokio::test]
+    async fn probe_gets_an_answer_from_a_wildcard_bind() {
+        let sockets = DnsSockets::bind(&["0.0.0.0:0".parse().unwrap()], &[], false).unwrap();
+        let addrs = sockets.local_addrs();
+        let server = tokio::spawn(run_dns_server(
+            sockets,
+            SharedState::default(),
+            DnsOptions::default(),
+        ));
+        // Queued on the bound socket until the server gets to it.
+        tokio::time::timeout(Duration::from_secs(5), probe_dns(&addrs))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(probe_dns(&[]).await.is_err());
+        server.abort();
+    }
+
+    #[cfg(all(target_os = "linux", feature = "systemd"))]
+    #[test]
+    fn notifications_reach_the_socket() {
+        let path = std::env::temp_dir().join(format!("glued-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        // Nothing else in the tests reads it.
+        std::env::set_var("NOTIFY_SOCKET", &path);
+        assert!(enabled());
+        notify("READY=1");
+        let mut buf = [0u8; 64];
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+//! TXT strings served for entries.
+//!
+//! A TXT record is a list of character-strings of at most 255 bytes each, so
+//! longer strings are split.  With `dns_txt_metadata`, cluster entries also
+//! get a record saying who published them and when, for debugging.
+//!
+//! Containers publish strings of their own with labels: `glued.txt` holds
+//! comma-separated `key=value` pairs, and `glued.txt.<key>=<value>` one
+//! pair whose value may hold commas.  Each pair is served as one string,
+//! `key=value`, sorted by key, up to [`MAX_TXT_BYTES`] per name; pairs
+//! beyond that are dropped with a warning.
+
+use std::collections::HashMap;
+
+use log::warn;
+
+use crate::types::{Entry, Source};
+
+/// Longest character-string a TXT record can hold.
+const MAX_SEGMENT: usize = 255;
+
+/// Label of comma-separated `key=value` pairs to serve as TXT strings.
+pub const TXT_LABEL: &str = "glued.txt";
+
+/// Most bytes of TXT strings a container may publish for its name.
+pub const MAX_TXT_BYTES: usize = 1024;
+
+/// The TXT strings the `glued.txt` labels of container `name` publish,
+/// within [`MAX_TXT_BYTES`].
+pub fn from_labels(name: &str, labels: &HashMap<String, String>) -> Vec<String> {
+    let mut pairs: Vec<(&str, &str)> = Vec::new();
+    for (label, value) in labels {
+        if label == TXT_LABEL {
+            for pair in value
+                .split(',')
+                .map(str::trim)
+                .filter(|pair| !pair.is_empty())
+            {
+                match pair.split_once('=') {
+                    Some((key, value)) if !key.trim().is_empty() => {
+                        pairs.push((key.trim(), value.trim()))
+                    }
+                    _ => warn!(
+                        "Ignoring '{}' in {} of {}: not key=value",
+                        pair, TXT_LABEL, name
+                    ),
+                }
+            }
+        } else if let Some(key) = label
+            .strip_prefix(TXT_LABEL)
+            .and_then(|rest| rest.strip_prefix('.'))
+            .filter(|key| !key.is_empty())
+        {
+            pairs.push((key, value));
+        }
+    }
+    pairs.sort();
+    pairs.dedup_by(|a, b| a.0 == b.0);
+
+    let mut strings = Vec::with_capacity(pairs.len());
+    let mut size = 0;
+    for (key, value) in pairs {
+        let string = format!("{}={}", key, value);
+        if size + string.len() > MAX_TXT_BYTES {
+            warn!(
+                "Not publishing TXT {} for {}: over {} bytes of TXT strings",
+                key, name, MAX_TXT_BYTES
+            );
+            continue;
+        }
+        size += string.len();
+        strings.push(string);
+    }
+    strings
+}
+
+/// Whether `strings` are within [`MAX_TXT_BYTES`], as a peer's must be.
+pub fn fits(strings: &[String]) -> bool {
+    strings.iter().map(String::len).sum::<usize>() <= MAX_TXT_BYTES
+}
+
+/// `strings` split into character-strings that fit a TXT record, breaking
+/// only between characters.
+pub fn segments(strings: &[String]) -> Vec<String> {
+    let mut out = Vec::with_capacity(strings.len());
+    for s in strings {
+        let mut rest = s.as_str();
+        while rest.len() > MAX_SEGMENT {
+            let mut end = MAX_SEGMENT;
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            out.push(rest[..end].to_string());
+            rest = &rest[end..];
+        }
+        out.push(rest.to_string());
+    }
+    out
+}
+
+/// `node=<short id>`, `node_name=<name>`, `updated=<RFC 3339 time>` and,
+/// once its lease was renewed, `refreshed=<RFC 3339 time>` for an entry
+/// published over gossip.  Locally configured entries have none.
+pub fn metadata(entry: &Entry) -> Vec<String> {
+    if entry.source != Source::Cluster {
+        return Vec::new();
+    }
+    let mut strings = Vec::with_capacity(4);
+    if let Some(node) = &entry.node {
+        strings.push(format!("node={}", node));
+    }
+    if let Some(name) = &entry.node_name {
+        strings.push(format!("node_name={}", name));
+    }
+    strings.push(format!("updated={}", rfc3339(entry.updated_at)));
+    if let Some(refreshed_at) = entry.refreshed_at {
+        strings.push(format!("refreshed={}", rfc3339(refreshed_at)));
+    }
+    strings
+}
+
+/// Unix time in milliseconds as an RFC 3339 UTC timestamp.
+fn rfc3339(millis: u64) -> String {
+    let secs = millis / 1000;
+    let (days, time) = (secs / 86400, secs % 86400);
+    // Civil date from days since 1970-01-01 (H. Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_strings_are_split_between_characters() {
+        let long = "é".repeat(200);
+        let split = segments(&["short".into(), long.clone()]);
+        assert_eq!(split[0], "short");
+        assert!(split[1..].iter().all(|s| s.len() <= MAX_SEGMENT));
+        assert_eq!(split[1..].concat(), long);
+        assert_eq!(split.len(), 3);
+    }
+
+    #[test]
+    fn labels_publish_sorted_pairs_within_the_limit() {
+        let labels: HashMap<String, String> = [
+            ("glued.txt", "version=1.4.2, role=api,bogus"),
+            ("glued.txt.owners", "ops,dev"),
+            ("glued.txt.", "ignored"),
+            ("glued.wildcard", "true"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        assert_eq!(
+            from_labels("web-1", &labels),
+            ["owners=ops,dev", "role=api", "version=1.4.2"]
+        );
+
+        let labels: HashMap<String, String> = [
+            ("glued.txt.a", "x".repeat(600)),
+            ("glued.txt.b", "y".repeat(600)),
+            ("glued.txt.c", "z".repeat(100)),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+        let strings = from_labels("web-1", &labels);
+        assert_eq!(strings.len(), 2);
+        assert!(strings[0].starts_with("a=") && strings[1].starts_with("c="));
+        assert!(fits(&strings));
+        assert!(!fits(&["x".repeat(MAX_TXT_BYTES + 1)]));
+    }
+
+    #[test]
+    fn metadata_names_node_and_time() {
+        let entry = Entry {
+            updated_at: 1_709_251_199_999,
+            node: Some("ab12cd34ef".into()),
+            ..Entry::new("10.0.0.2".parse().unwrap())
+        };
+        assert_eq!(
+            metadata(&entry),
+            ["node=ab12cd34ef", "updated=2024-02-29T23:59:59Z"]
+        );
+        let named = Entry {
+            node_name: Some("swarm-worker-3".into()),
+            ..entry.clone()
+        };
+        assert_eq!(
+            metadata(&named),
+            [
+                "node=ab12cd34ef",
+                "node_name=swarm-worker-3",
+                "updated=2024-02-29T23:59:59Z"
+            ]
+        );
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        let renewed = Entry {
+            refreshed_at: Some(1_709_251_260_000),
+            ..entry.clone()
+        };
+        assert_eq!(
+            metadata(&renewed)[1..],
+            [
+                "updated=2024-02-29T23:59:59Z",
+                "refreshed=2024-03-01T00:01:00Z"
+            ]
+        );
+
+        let local = Entry {
+            source: Source::Static,
+            ..entry
+        };
+        assert!(metadata(&local).is_empty());
+    }
+}
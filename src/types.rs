@@ -5,21 +5,321 @@
 //! operations on the container registry such as adding or removing
 //! entries.  The fields are kept minimal to reduce bandwidth usage.
 
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use serde::{Deserialize, Serialize};
 
+use crate::registry::Registry;
+
 /// An update message describing a change in the container mapping.
 ///
 /// This enum is sent via iroh‑gossip to all peers.  Each message
-/// either adds a new name → IP entry or removes an existing entry.
-/// Timestamps or generation numbers can be added in the future to
-/// improve conflict resolution; currently the last update wins.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// adds a new name → IP entry, removes an existing entry, or carries
+/// a batch of such changes.
+/// When several nodes add the same name, the one with the lowest NodeId
+/// is served; see [`crate::conflicts`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Update {
     /// A container has been discovered or updated on a host.  `name` is
     /// the container name (single label) and `ip` is its IPv4/IPv6
     /// address on the designated network.
-    Add { name: String, ip: String },
+    Add {
+        name: String,
+        #[serde(with = "ip_text")]
+        ip: IpAddr,
+    },
     /// A container has stopped or detached from the network.  Only
     /// the name is required to remove the mapping.
     Remove { name: String },
+    /// Several updates sent as one message and applied together, in
+    /// order.  Coalesces bursts such as a host booting its containers.
+    /// Batches nest at most [`MAX_BATCH_DEPTH`] deep.
+    #[serde(deserialize_with = "batch_depth::deserialize")]
+    Batch(Vec<Update>),
+    /// The ports of a name, following its `Add`.  An `Add` alone clears
+    /// them.  Nodes that predate this variant drop messages carrying it,
+    /// so upgrade DNS-only nodes before replicas.
+    Ports { name: String, ports: Vec<Port> },
+    /// Pins `name` to `ip` on every node, ahead of any container of that
+    /// name, until an `Unpin` or `expires_at` (Unix time in milliseconds)
+    /// passes.  Set by operators; needs the same upgrade order as `Ports`.
+    Pin {
+        name: String,
+        #[serde(with = "ip_text")]
+        ip: IpAddr,
+        expires_at: Option<u64>,
+    },
+    /// Drops the pin on `name`, serving the container it hid again.
+    Unpin { name: String },
+    /// How long the container entry for `name` stays without being
+    /// announced again, following its `Add`.  Receivers count from when
+    /// they apply it, so the publisher's clock doesn't matter.  An `Add`
+    /// alone never expires.  Needs the same upgrade order as `Ports`.
+    Lease { name: String, valid_for_secs: u64 },
+    /// Addresses of `name` in scopes besides its default one, following
+    /// its `Add`, which alone clears them.  Clients of a view preferring
+    /// one of these scopes get them instead; see [`crate::views`].  Needs
+    /// the same upgrade order as `Ports`.
+    ScopedIps { name: String, ips: Vec<ScopedIp> },
+    /// TXT strings of `name` from its container's `glued.txt` labels,
+    /// following its `Add`, which alone clears them; see [`crate::txt`].
+    /// Needs the same upgrade order as `Ports`.
+    Txt { name: String, txt: Vec<String> },
+    /// Drops every entry the node with short NodeId `node` published, and
+    /// ignores its updates for a while; see [`crate::evict`].  Set by
+    /// operators; needs the same upgrade order as `Ports`.
+    EvictNode { node: String },
+}
+
+impl Update {
+    /// Combines `updates` into one, flattening nested batches and
+    /// unwrapping a batch of one.
+    pub fn batch(updates: Vec<Update>) -> Update {
+        let mut flat = Vec::with_capacity(updates.len());
+        for update in updates {
+            match update {
+                Update::Batch(inner) => flat.extend(inner),
+                update => flat.push(update),
+            }
+        }
+        if flat.len() == 1 {
+            flat.pop().unwrap()
+        } else {
+            Update::Batch(flat)
+        }
+    }
+}
+
+/// A single name's record in the local state map.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub ip: IpAddr,
+    /// Further addresses; only static records have more than one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_ips: Vec<IpAddr>,
+    /// TXT strings served for the name.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub txt: Vec<String>,
+    /// Unix time in milliseconds at which this mapping was last set.
+    /// Entries restored from a snapshot keep their saved time, so any
+    /// update received afterwards is newer.
+    pub updated_at: u64,
+    /// Unix time in milliseconds its publisher last renewed its lease;
+    /// none until it has, when `updated_at` is the last it was heard of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refreshed_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Source::is_cluster")]
+    pub source: Source,
+    /// Short NodeId of the peer that published the entry, when its message
+    /// said.  Entries of our own containers and from older peers have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+    /// That peer's `node_name`, when its message said.  For people reading
+    /// logs and listings; nothing relies on it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_name: Option<String>,
+    /// Ports the container exposes, served as SRV records.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<Port>,
+    /// Addresses in other scopes, such as the host address its ports are
+    /// published on.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scoped_ips: Vec<ScopedIp>,
+    /// Unix time in milliseconds at which a manual entry is dropped, or a
+    /// container entry whose lease wasn't renewed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// The container entry a manual one hides, restored when the pin goes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shadowed: Option<Box<Entry>>,
+}
+
+impl Entry {
+    /// An entry for `ip` stamped with the current time.
+    pub fn new(ip: IpAddr) -> Self {
+        Self {
+            ip,
+            extra_ips: Vec::new(),
+            txt: Vec::new(),
+            updated_at: now_millis(),
+            refreshed_at: None,
+            source: Source::Cluster,
+            node: None,
+            node_name: None,
+            ports: Vec::new(),
+            scoped_ips: Vec::new(),
+            expires_at: None,
+            shadowed: None,
+        }
+    }
+
+    /// Unix time in milliseconds its publisher last set or renewed it.
+    pub fn last_refreshed(&self) -> u64 {
+        self.refreshed_at.unwrap_or(0).max(self.updated_at)
+    }
+
+    /// All of the entry's addresses, `ip` first.
+    pub fn ips(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        std::iter::once(self.ip).chain(self.extra_ips.iter().copied())
+    }
+
+    /// The addresses for a client preferring `scope`: the entry's in that
+    /// scope, or its default ones when it has none there.
+    pub fn ips_for(&self, scope: Option<&str>) -> Vec<IpAddr> {
+        let scoped: Vec<IpAddr> = self
+            .scoped_ips
+            .iter()
+            .filter(|scoped| Some(scoped.scope.as_str()) == scope)
+            .map(|scoped| scoped.ip)
+            .collect();
+        if scoped.is_empty() {
+            self.ips().collect()
+        } else {
+            scoped
+        }
+    }
+}
+
+/// An address of a name in one scope, such as `host`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ScopedIp {
+    pub scope: String,
+    #[serde(with = "ip_text")]
+    pub ip: IpAddr,
+}
+
+/// Deepest a received batch may nest.  Senders flatten their batches, so
+/// this only stops a crafted message from exhausting the stack.
+pub const MAX_BATCH_DEPTH: u32 = 8;
+
+mod batch_depth {
+    use std::cell::Cell;
+
+    use serde::{de, Deserialize, Deserializer};
+
+    use super::{Update, MAX_BATCH_DEPTH};
+
+    thread_local! {
+        /// Batches being decoded on this thread, one inside the other.
+        static DEPTH: Cell<u32> = const { Cell::new(0) };
+    }
+
+    /// Leaves a batch, however decoding it ended.
+    struct Level;
+
+    impl Drop for Level {
+        fn drop(&mut self) {
+            DEPTH.with(|depth| depth.set(depth.get() - 1));
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Update>, D::Error> {
+        let depth = DEPTH.with(|depth| {
+            depth.set(depth.get() + 1);
+            depth.get()
+        });
+        let _level = Level;
+        if depth > MAX_BATCH_DEPTH {
+            return Err(de::Error::custom(format_args!(
+                "batches nested more than {} deep",
+                MAX_BATCH_DEPTH
+            )));
+        }
+        Vec::deserialize(deserializer)
+    }
+}
+
+/// Addresses in updates travel as text, as they did when they were
+/// strings, so nodes on either side of that change understand each other:
+/// `postcard` has no self-description to tell two encodings apart.
+mod ip_text {
+    use std::net::IpAddr;
+
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(ip: &IpAddr, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(ip)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<IpAddr, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse()
+            .map_err(|_| de::Error::custom(format_args!("'{}' is not an IP address", text)))
+    }
+}
+
+/// A port a container exposes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Port {
+    pub port: u16,
+    pub protocol: PortProtocol,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PortProtocol {
+    Tcp,
+    Udp,
+    Sctp,
+}
+
+impl std::str::FromStr for PortProtocol {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "tcp" => Ok(Self::Tcp),
+            "udp" => Ok(Self::Udp),
+            "sctp" => Ok(Self::Sctp),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Where an entry came from, and so what may replace or remove it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    /// Published by a replica, ours or a peer's, and changed by gossip.
+    #[default]
+    Cluster,
+    /// From `static_records` in the config; gossip can't touch it.
+    Static,
+    /// From `hosts_file`; only changes to the file touch it.
+    Hosts,
+    /// Pinned by an operator over gossip; containers of the same name
+    /// can't replace or remove it.
+    Manual,
+}
+
+impl Source {
+    fn is_cluster(&self) -> bool {
+        *self == Source::Cluster
+    }
+}
+
+/// The name → entry map served over DNS.
+pub type StateMap = HashMap<String, Entry>;
+
+/// The registry shared between the updaters, the DNS server and everything
+/// following its changes.
+pub type SharedState = Arc<Registry>;
+
+/// Current Unix time in milliseconds.
+///
+/// Entries and peers are stamped with the wall clock rather than a
+/// monotonic one: the times travel between nodes, outlive restarts in
+/// snapshots and are shown to people, none of which an `Instant` can do.
+/// A clock stepped back can leave them in the future, so ages reckoned
+/// from them saturate at zero.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
 }
@@ -0,0 +1,71 @@
+//! Split-horizon answers: which of an entry's addresses a client gets.
+//!
+//! Besides its default addresses, an entry may carry addresses in named
+//! scopes.  Replicas publish the `host` scope for containers with published
+//! ports: the address their ports are reachable on from outside the
+//! overlay.  `views` map client networks to the scope they prefer:
+//!
+//! ```toml
+//! [[views]]
+//! subnets = ["192.168.1.0/24"]
+//! scope = "host"
+//! ```
+//!
+//! A client gets the addresses in the scope of the view with the longest
+//! prefix containing it.  Clients in no view, and names without addresses
+//! in the preferred scope, get the default addresses.
+
+use std::net::IpAddr;
+
+use crate::acl::Cidr;
+use crate::config::Config;
+
+/// Client networks and their preferred scopes.
+#[derive(Debug, Clone, Default)]
+pub struct Views(Vec<(Cidr, String)>);
+
+impl Views {
+    pub fn from_config(cfg: &Config) -> Self {
+        Self(
+            cfg.views
+                .iter()
+                .flat_map(|view| view.subnets.iter().map(|net| (*net, view.scope.clone())))
+                .collect(),
+        )
+    }
+
+    /// The scope `client` prefers, if it is in a view.
+    pub fn scope_for(&self, client: IpAddr) -> Option<&str> {
+        self.0
+            .iter()
+            .filter(|(net, _)| net.contains(client))
+            .max_by_key(|(net, _)| net.prefix())
+            .map(|(_, scope)| scope.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::View;
+
+    #[test]
+    fn the_narrowest_view_wins() {
+        let view = |subnets: &[&str], scope: &str| View {
+            subnets: subnets.iter().map(|net| net.parse().unwrap()).collect(),
+            scope: scope.into(),
+        };
+        let views = Views::from_config(&Config {
+            views: vec![
+                view(&["192.168.1.10/32", "fd00::/8"], "bastion"),
+                view(&["192.168.0.0/16"], "host"),
+            ],
+            ..Config::default()
+        });
+        let scope = |ip: &str| views.scope_for(ip.parse().unwrap());
+        assert_eq!(scope("192.168.1.10"), Some("bastion"));
+        assert_eq!(scope("192.168.7.1"), Some("host"));
+        assert_eq!(scope("fd00::1"), Some("bastion"));
+        assert_eq!(scope("10.0.0.2"), None);
+    }
+}
@@ -0,0 +1,412 @@
+//! Webhook notifications of registry changes.
+//!
+//! Every update applied, local or from gossip, is POSTed to each URL in
+//! `webhooks` as one JSON event per name:
+//!
+//! ```text
+//! {"event": "add", "name": "web-1", "ip": "10.0.0.2", "origin_node": "ab12cd34ef", "timestamp": 1709251199999}
+//! ```
+//!
+//! `origin_node` is null for this node's own changes.  The body is signed
+//! in `X-Glued-Signature: sha256=<hex>`, an HMAC-SHA256 keyed with the
+//! bytes of `webhook_secret` or, without one, with HKDF-SHA256 of the
+//! cluster secret (salt `glued webhook`, info `glued/webhook/v1 signing
+//! key`, 32 bytes), so receivers needn't hold the cluster secret itself.
+//!
+//! Each URL has its own bounded queue and delivery task, so a slow
+//! receiver delays nobody else and never the state updates: when its queue
+//! is full the oldest event is dropped.  Failed deliveries are retried
+//! with exponential backoff, then given up.
+
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use log::{debug, warn};
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, Notify};
+use tokio::task::JoinHandle;
+
+use crate::config::Config;
+use crate::metrics::{inc, METRICS};
+use crate::registry::Change;
+use crate::types::{now_millis, Update};
+
+const KDF_SALT: &[u8] = b"glued webhook";
+const KEY_INFO: &[u8] = b"glued/webhook/v1 signing key";
+
+/// One delivery attempt may take this long.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A change to one name, as delivered.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Event {
+    pub event: EventKind,
+    pub name: String,
+    /// The new address; none for removals.
+    pub ip: Option<IpAddr>,
+    pub origin_node: Option<String>,
+    /// Unix time in milliseconds the change was applied.
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Add,
+    Remove,
+}
+
+impl Event {
+    /// The events `update` makes, in order.  Pins count as adds and unpins
+    /// as removals; port lists aren't reported.
+    pub fn from_update(update: &Update, origin_node: Option<&str>) -> Vec<Event> {
+        let event = |event, name: &str, ip: Option<IpAddr>| Event {
+            event,
+            name: name.to_string(),
+            ip,
+            origin_node: origin_node.map(str::to_owned),
+            timestamp: now_millis(),
+        };
+        match update {
+            Update::Add { name, ip } | Update::Pin { name, ip, .. } => {
+                vec![event(EventKind::Add, name, Some(*ip))]
+            }
+            Update::Remove { name } | Update::Unpin { name } => {
+                vec![event(EventKind::Remove, name, None)]
+            }
+            Update::Ports { .. }
+            | Update::Lease { .. }
+            | Update::ScopedIps { .. }
+            | Update::Txt { .. }
+            | Update::EvictNode { .. } => Vec::new(),
+            Update::Batch(updates) => updates
+                .iter()
+                .flat_map(|update| Event::from_update(update, origin_node))
+                .collect(),
+        }
+    }
+}
+
+/// Where a webhook is delivered.  Only plain `http://` URLs are supported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Target {
+    /// `host:port`, as connected to.
+    authority: String,
+    host: String,
+    path: String,
+}
+
+impl Target {
+    fn parse(url: &str) -> anyhow::Result<Self> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            bail!("Webhook {} is not an http:// URL", url);
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(at) => (&rest[..at], &rest[at..]),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            bail!("Webhook {} has no host", url);
+        }
+        // A bracketed IPv6 address may contain colons of its own.
+        let has_port = authority
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| !port.contains(']'));
+        let authority = if has_port {
+            authority.to_string()
+        } else {
+            format!("{}:80", authority)
+        };
+        let host = authority
+            .rsplit_once(':')
+            .map(|(host, _)| host.to_string())
+            .unwrap_or_default();
+        Ok(Self {
+            authority,
+            host,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// One URL's queue of undelivered events.
+struct Sink {
+    url: String,
+    target: Target,
+    queue: Mutex<VecDeque<Event>>,
+    ready: Notify,
+}
+
+/// The configured webhooks.
+pub struct Webhooks {
+    sinks: Vec<Arc<Sink>>,
+    capacity: usize,
+    key: Vec<u8>,
+    max_retries: u32,
+}
+
+impl Webhooks {
+    /// The webhooks in `cfg`, or `None` when there are none.  Fails on a URL
+    /// that can't be delivered to.
+    pub fn from_config(cfg: &Config) -> anyhow::Result<Option<Self>> {
+        if cfg.webhooks.is_empty() {
+            return Ok(None);
+        }
+        let sinks = cfg
+            .webhooks
+            .iter()
+            .map(|url| {
+                Ok(Arc::new(Sink {
+                    url: url.clone(),
+                    target: Target::parse(url)?,
+                    queue: Mutex::new(VecDeque::new()),
+                    ready: Notify::new(),
+                }))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let key = match &cfg.webhook_secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => signing_key(&cfg.cluster_secret).to_vec(),
+        };
+        Ok(Some(Self {
+            sinks,
+            capacity: cfg.webhook_queue_capacity.max(1),
+            key,
+            max_retries: cfg.webhook_max_retries,
+        }))
+    }
+
+    /// Queues the events of `update` for every webhook.  Never waits.
+    pub fn notify(&self, update: &Update, origin_node: Option<&str>) {
+        let events = Event::from_update(update, origin_node);
+        if events.is_empty() {
+            return;
+        }
+        for sink in &self.sinks {
+            let mut queue = sink.queue.lock().unwrap();
+            for event in &events {
+                if queue.len() >= self.capacity {
+                    queue.pop_front();
+                    inc(&METRICS.webhook_dropped);
+                }
+                queue.push_back(event.clone());
+            }
+            drop(queue);
+            sink.ready.notify_one();
+        }
+    }
+
+    /// Queues every change announced on `changes`, until the registry goes.
+    pub async fn follow(self: Arc<Self>, mut changes: broadcast::Receiver<Arc<Change>>) {
+        loop {
+            match changes.recv().await {
+                Ok(change) => self.notify(&change.update, change.origin.node()),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Webhooks missed {} registry changes", missed);
+                    METRICS.webhook_dropped.fetch_add(missed, Ordering::Relaxed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+
+    /// Starts delivering to every webhook; the tasks run until aborted.
+    pub fn spawn(self: &Arc<Self>) -> Vec<JoinHandle<()>> {
+        self.sinks
+            .iter()
+            .map(|sink| tokio::spawn(Arc::clone(self).deliver_all(Arc::clone(sink))))
+            .collect()
+    }
+
+    async fn deliver_all(self: Arc<Self>, sink: Arc<Sink>) {
+        loop {
+            let next = sink.queue.lock().unwrap().pop_front();
+            let Some(event) = next else {
+                sink.ready.notified().await;
+                continue;
+            };
+            let body = serde_json::to_vec(&event).expect("events serialize");
+            self.deliver_with_retries(&sink, &body).await;
+        }
+    }
+
+    async fn deliver_with_retries(&self, sink: &Sink, body: &[u8]) {
+        let signature = sign(&self.key, body);
+        let mut backoff = FIRST_BACKOFF;
+        for attempt in 0..=self.max_retries {
+            match tokio::time::timeout(DELIVERY_TIMEOUT, post(&sink.target, body, &signature)).await
+            {
+                Ok(Ok(())) => return,
+                Ok(Err(e)) => debug!("Webhook {} failed: {:#}", sink.url, e),
+                Err(_) => debug!("Webhook {} timed out", sink.url),
+            }
+            if attempt < self.max_retries {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+        inc(&METRICS.webhook_failed);
+        warn!(
+            "Giving up on webhook {} after {} attempts",
+            sink.url,
+            self.max_retries + 1
+        );
+    }
+}
+
+/// The signing key derived from the cluster secret.
+fn signing_key(cluster_secret: &str) -> [u8; 32] {
+    let hkdf = Hkdf::<Sha256>::new(Some(KDF_SALT), cluster_secret.as_bytes());
+    let mut key = [0u8; 32];
+    hkdf.expand(KEY_INFO, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+/// `sha256=<hex HMAC of body>`.
+fn sign(key: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// POSTs `body` to `target`; any 2xx status is success.
+async fn post(target: &Target, body: &[u8], signature: &str) -> anyhow::Result<()> {
+    let mut stream = TcpStream::connect(&target.authority)
+        .await
+        .with_context(|| format!("connecting to {}", target.authority))?;
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nX-Glued-Signature: {}\r\nConnection: close\r\n\r\n",
+        target.path,
+        target.host,
+        body.len(),
+        signature
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    // The status line is all we need.
+    let mut response = Vec::new();
+    let mut buf = [0u8; 256];
+    while !response.contains(&b'\n') {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        response.extend_from_slice(&buf[..n]);
+    }
+    let status_line = String::from_utf8_lossy(&response);
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| anyhow!("malformed response"))?;
+    if !(200..300).contains(&status) {
+        bail!("answered {}", status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn urls_and_events() {
+        let target = Target::parse("http://ingress:8080/glued-hook").unwrap();
+        assert_eq!(target.authority, "ingress:8080");
+        assert_eq!(target.host, "ingress");
+        assert_eq!(target.path, "/glued-hook");
+        assert_eq!(
+            Target::parse("http://[fd00::1]").unwrap().authority,
+            "[fd00::1]:80"
+        );
+        assert!(Target::parse("https://ingress/hook").is_err());
+
+        let batch = Update::batch(vec![
+            Update::Add {
+                name: "web-1".into(),
+                ip: "10.0.0.2".parse().unwrap(),
+            },
+            Update::Remove {
+                name: "web-2".into(),
+            },
+        ]);
+        let events = Event::from_update(&batch, Some("ab12cd34ef"));
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event, EventKind::Add);
+        assert_eq!(events[1].ip, None);
+        assert_eq!(events[1].origin_node.as_deref(), Some("ab12cd34ef"));
+    }
+
+    #[tokio::test]
+    async fn full_queues_drop_the_oldest_and_deliveries_are_signed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cfg = Config {
+            webhooks: vec![format!("http://{}/hook", listener.local_addr().unwrap())],
+            webhook_queue_capacity: 2,
+            ..Config::default()
+        };
+        let webhooks = Arc::new(Webhooks::from_config(&cfg).unwrap().unwrap());
+        let add = |i: usize| Update::Add {
+            name: format!("web-{}", i),
+            ip: "10.0.0.2".parse().unwrap(),
+        };
+        let dropped = METRICS
+            .webhook_dropped
+            .load(std::sync::atomic::Ordering::Relaxed);
+        for i in 1..=3 {
+            webhooks.notify(&add(i), None);
+        }
+        assert!(
+            METRICS
+                .webhook_dropped
+                .load(std::sync::atomic::Ordering::Relaxed)
+                > dropped
+        );
+        let tasks = webhooks.spawn();
+
+        let key = signing_key(&cfg.cluster_secret);
+        for expected in ["web-2", "web-3"] {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if text.ends_with('}') || n == 0 {
+                    break;
+                }
+            }
+            let request = String::from_utf8(request).unwrap();
+            let (head, body) = request.split_once("\r\n\r\n").unwrap();
+            assert!(head.starts_with("POST /hook HTTP/1.1"));
+            assert!(head.contains(&format!(
+                "X-Glued-Signature: {}",
+                sign(&key, body.as_bytes())
+            )));
+            let event: serde_json::Value = serde_json::from_str(body).unwrap();
+            assert_eq!(event["name"], expected);
+            assert_eq!(event["event"], "add");
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+        }
+        for task in tasks {
+            task.abort();
+        }
+    }
+}
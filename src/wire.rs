@@ -0,0 +1,447 @@
+//! Gossip message encoding.
+//!
+//! Messages are a small versioned envelope around a `postcard` payload:
+//! `version (1) | message type (1) | payload`.  Nodes that predate the
+//! envelope sent bare JSON, which always starts with `{`, so the first byte
+//! tells the formats apart during the transition.
+//!
+//! Current senders stamp each message with an [`Origin`] so receivers can
+//! drop redelivered copies and their own echoes, and follow the payload
+//! with their node name and a random nonce.  `postcard` stops reading once
+//! it has what it asked for, so nodes that predate the name ignore both,
+//! and nodes that predate the nonce ignore it.  An update answering a sync
+//! request is flagged after the nonce, which older nodes ignore in turn,
+//! applying it as any update.  Everything is inside the sealed payload, so
+//! none of it can be altered without the secret.
+//!
+//! Nodes that predate a message type count it as malformed, so digests are
+//! only sent once every node reads them; see `gossip.digest_interval_secs`.
+
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::digest::StateDigest;
+use crate::types::Update;
+
+/// Current envelope version.  Must never be `b'{'`.
+const WIRE_VERSION: u8 = 1;
+
+/// Longest node name accepted; longer ones are dropped.
+pub const MAX_NODE_NAME: usize = 64;
+
+/// Envelope message types.
+/// A bare [`Update`].
+const MSG_UPDATE: u8 = 1;
+/// An [`Update`] preceded by its [`Origin`].
+const MSG_ORIGIN_UPDATE: u8 = 2;
+/// A [`Body::SyncRequest`]; the payload is only its [`Origin`].
+const MSG_SYNC_REQUEST: u8 = 3;
+/// A [`Body::Digest`] preceded by its [`Origin`].
+const MSG_DIGEST: u8 = 4;
+
+/// Identifies one message from one node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Origin {
+    /// NodeId of the sender.
+    pub node: [u8; 32],
+    /// Per-process counter; restarts from zero when the sender restarts.
+    pub seq: u64,
+    /// Sender's Unix time in milliseconds, used to order messages across a
+    /// sequence reset.
+    pub timestamp: u64,
+}
+
+/// What a gossip message carries.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum Body {
+    Update(Update),
+    /// Sent on joining the topic: asks publishing nodes to rebroadcast the
+    /// entries they own.
+    SyncRequest,
+    /// What a node owns, answering a [`Body::SyncRequest`].  Sent without a
+    /// nonce, it goes out as a plain [`Body::Update`].
+    SyncAnswer(Update),
+    /// Sent periodically on the shared topic: the sender's digest, for
+    /// receivers to compare with theirs.
+    Digest(StateDigest),
+}
+
+/// A decoded gossip message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Message {
+    /// Absent on messages from nodes that predate origin stamping.
+    pub origin: Option<Origin>,
+    /// The sender's `node_name`, absent from nodes that predate it.  Not
+    /// authenticated beyond the cluster secret; the NodeId is the identity.
+    pub node_name: Option<String>,
+    /// Random per message, absent from nodes that predate it.  Tells a
+    /// replayed copy apart from a new message.
+    pub nonce: Option<u64>,
+    pub body: Body,
+}
+
+/// Encodes `body` sent by `origin`, called `node_name`, in the current
+/// binary format.
+pub fn encode(
+    origin: &Origin,
+    node_name: Option<&str>,
+    nonce: Option<u64>,
+    body: &Body,
+) -> anyhow::Result<Vec<u8>> {
+    let mut out = match body {
+        Body::Update(update) | Body::SyncAnswer(update) => {
+            let mut out = vec![WIRE_VERSION, MSG_ORIGIN_UPDATE];
+            out.extend(postcard::to_allocvec(&(origin, update))?);
+            out
+        }
+        Body::SyncRequest => {
+            let mut out = vec![WIRE_VERSION, MSG_SYNC_REQUEST];
+            out.extend(postcard::to_allocvec(origin)?);
+            out
+        }
+        Body::Digest(digest) => {
+            let mut out = vec![WIRE_VERSION, MSG_DIGEST];
+            out.extend(postcard::to_allocvec(&(origin, digest))?);
+            out
+        }
+    };
+    // The nonce follows the name, so an empty name stands in for none.
+    if node_name.is_some() || nonce.is_some() {
+        out.extend(postcard::to_allocvec(node_name.unwrap_or_default())?);
+    }
+    if let Some(nonce) = nonce {
+        out.extend(postcard::to_allocvec(&nonce)?);
+        if matches!(body, Body::SyncAnswer(_)) {
+            out.extend(postcard::to_allocvec(&true)?);
+        }
+    }
+    Ok(out)
+}
+
+/// The node name and nonce following a payload, each if there is a usable
+/// one, and whether the sync answer flag follows them.
+fn trailer(rest: &[u8]) -> (Option<String>, Option<u64>, bool) {
+    let Ok((name, rest)) = postcard::take_from_bytes::<String>(rest) else {
+        return (None, None, false);
+    };
+    let usable =
+        !name.is_empty() && name.len() <= MAX_NODE_NAME && !name.chars().any(char::is_control);
+    let Ok((nonce, rest)) = postcard::take_from_bytes::<u64>(rest) else {
+        return (usable.then_some(name), None, false);
+    };
+    let answer = postcard::from_bytes(rest).unwrap_or(false);
+    (usable.then_some(name), Some(nonce), answer)
+}
+
+/// Decodes the binary envelope or a legacy JSON message.
+pub fn decode(bytes: &[u8]) -> anyhow::Result<Message> {
+    let (origin, body, rest) = match bytes {
+        [b'{', ..] => (None, Body::Update(serde_json::from_slice(bytes)?), &[][..]),
+        [WIRE_VERSION, MSG_UPDATE, payload @ ..] => (
+            None,
+            Body::Update(
+                postcard::from_bytes(payload)
+                    .map_err(|e| anyhow!("Invalid update payload: {}", e))?,
+            ),
+            &[][..],
+        ),
+        [WIRE_VERSION, MSG_ORIGIN_UPDATE, payload @ ..] => {
+            let ((origin, update), rest) = postcard::take_from_bytes(payload)
+                .map_err(|e| anyhow!("Invalid update payload: {}", e))?;
+            (Some(origin), Body::Update(update), rest)
+        }
+        [WIRE_VERSION, MSG_SYNC_REQUEST, payload @ ..] => {
+            let (origin, rest) = postcard::take_from_bytes(payload)
+                .map_err(|e| anyhow!("Invalid sync request: {}", e))?;
+            (Some(origin), Body::SyncRequest, rest)
+        }
+        [WIRE_VERSION, MSG_DIGEST, payload @ ..] => {
+            let ((origin, digest), rest) =
+                postcard::take_from_bytes(payload).map_err(|e| anyhow!("Invalid digest: {}", e))?;
+            (Some(origin), Body::Digest(digest), rest)
+        }
+        [WIRE_VERSION, kind, ..] => bail!("Unknown message type {}", kind),
+        [version, ..] => bail!("Unsupported wire version {}", version),
+        [] => bail!("Empty message"),
+    };
+    let (node_name, nonce, answer) = trailer(rest);
+    let body = match body {
+        Body::Update(update) if answer => Body::SyncAnswer(update),
+        body => body,
+    };
+    Ok(Message {
+        origin,
+        node_name,
+        nonce,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Port, PortProtocol, ScopedIp};
+
+    fn add() -> Update {
+        Update::Add {
+            name: "web-1".into(),
+            ip: "10.0.0.2".parse().unwrap(),
+        }
+    }
+
+    fn origin() -> Origin {
+        Origin {
+            node: [7; 32],
+            seq: 42,
+            timestamp: 1_700_000_000_000,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for update in [
+            add(),
+            Update::Remove {
+                name: "web-1".into(),
+            },
+            Update::Ports {
+                name: "web-1".into(),
+                ports: vec![Port {
+                    port: 80,
+                    protocol: PortProtocol::Tcp,
+                }],
+            },
+            Update::Pin {
+                name: "canary".into(),
+                ip: "10.0.0.9".parse().unwrap(),
+                expires_at: Some(1_700_000_000_000),
+            },
+            Update::Unpin {
+                name: "canary".into(),
+            },
+            Update::Lease {
+                name: "web-1".into(),
+                valid_for_secs: 600,
+            },
+            Update::ScopedIps {
+                name: "web-1".into(),
+                ips: vec![ScopedIp {
+                    scope: "host".into(),
+                    ip: "192.168.1.2".parse().unwrap(),
+                }],
+            },
+            Update::Txt {
+                name: "web-1".into(),
+                txt: vec!["role=api".into()],
+            },
+            Update::EvictNode {
+                node: "ab12cd34ef".into(),
+            },
+        ] {
+            let body = Body::Update(update);
+            let message = decode(&encode(&origin(), None, None, &body).unwrap()).unwrap();
+            assert_eq!(message.origin, Some(origin()));
+            assert_eq!(message.node_name, None);
+            assert_eq!(message.body, body);
+        }
+
+        let sync = encode(&origin(), Some("swarm-worker-3"), None, &Body::SyncRequest).unwrap();
+        let message = decode(&sync).unwrap();
+        assert_eq!(message.origin, Some(origin()));
+        assert_eq!(message.node_name.as_deref(), Some("swarm-worker-3"));
+        assert_eq!(message.body, Body::SyncRequest);
+
+        let digest = Body::Digest(StateDigest {
+            entries: 12,
+            hash: u64::MAX - 7,
+        });
+        let message = decode(&encode(&origin(), None, Some(9), &digest).unwrap()).unwrap();
+        assert_eq!((message.origin, message.nonce), (Some(origin()), Some(9)));
+        assert_eq!(message.body, digest);
+    }
+
+    #[test]
+    fn batch_round_trip() {
+        let batch = Body::Update(Update::Batch(vec![
+            add(),
+            Update::Remove {
+                name: "web-2".into(),
+            },
+        ]));
+        let message = decode(&encode(&origin(), None, None, &batch).unwrap()).unwrap();
+        assert_eq!(message.body, batch);
+    }
+
+    #[test]
+    fn node_names_are_ignored_by_previous_versions() {
+        let body = Body::Update(add());
+        let named = encode(&origin(), Some("swarm-worker-3"), None, &body).unwrap();
+        let message = decode(&named).unwrap();
+        assert_eq!(message.node_name.as_deref(), Some("swarm-worker-3"));
+        assert_eq!(message.body, body);
+
+        // What a node without names reads from the same payload.
+        let previous: (Origin, Update) = postcard::from_bytes(&named[2..]).unwrap();
+        assert_eq!(previous, (origin(), add()));
+
+        // A name that isn't usable is left out, not the message.
+        for name in ["", "line\nbreak", &"x".repeat(MAX_NODE_NAME + 1)] {
+            let message = decode(&encode(&origin(), Some(name), None, &body).unwrap()).unwrap();
+            assert_eq!((message.node_name, message.body), (None, body.clone()));
+        }
+        let mut garbled = encode(&origin(), None, None, &body).unwrap();
+        garbled.push(0xff);
+        assert_eq!(decode(&garbled).unwrap().node_name, None);
+    }
+
+    #[test]
+    fn nonces_follow_the_name() {
+        let body = Body::Update(add());
+        let stamped = encode(&origin(), Some("swarm-worker-3"), Some(u64::MAX), &body).unwrap();
+        let message = decode(&stamped).unwrap();
+        assert_eq!(message.node_name.as_deref(), Some("swarm-worker-3"));
+        assert_eq!(message.nonce, Some(u64::MAX));
+        // Nodes that predate the nonce still read the name.
+        let (_, rest): ((Origin, Update), _) = postcard::take_from_bytes(&stamped[2..]).unwrap();
+        assert_eq!(
+            postcard::from_bytes::<String>(rest).unwrap(),
+            "swarm-worker-3"
+        );
+
+        let unnamed = decode(&encode(&origin(), None, Some(7), &body).unwrap()).unwrap();
+        assert_eq!(unnamed.body, body);
+        assert_eq!((unnamed.node_name, unnamed.nonce), (None, Some(7)));
+        let plain = decode(&encode(&origin(), Some("a"), None, &body).unwrap()).unwrap();
+        assert_eq!(plain.nonce, None);
+    }
+
+    #[test]
+    fn accepts_legacy_formats() {
+        let json = serde_json::to_vec(&add()).unwrap();
+        let message = decode(&json).unwrap();
+        assert_eq!((message.origin, message.body), (None, Body::Update(add())));
+
+        let mut bare = vec![WIRE_VERSION, MSG_UPDATE];
+        bare.extend(postcard::to_allocvec(&add()).unwrap());
+        assert_eq!(decode(&bare).unwrap().body, Body::Update(add()));
+    }
+
+    #[test]
+    fn decodes_updates_from_previous_versions() {
+        // As sent by nodes that carried addresses as strings.
+        let json = br#"{"Batch":[{"Add":{"name":"web-1","ip":"10.0.0.2"}},{"Pin":{"name":"canary","ip":"fd00::9","expires_at":null}}]}"#;
+        let expected = Update::Batch(vec![
+            add(),
+            Update::Pin {
+                name: "canary".into(),
+                ip: "fd00::9".parse().unwrap(),
+                expires_at: None,
+            },
+        ]);
+        assert_eq!(decode(json).unwrap().body, Body::Update(expected));
+
+        #[derive(Serialize)]
+        enum Previous {
+            Add { name: String, ip: String },
+        }
+        let previous = Previous::Add {
+            name: "web-1".into(),
+            ip: "10.0.0.2".into(),
+        };
+        assert_eq!(
+            postcard::to_allocvec(&previous).unwrap(),
+            postcard::to_allocvec(&add()).unwrap()
+        );
+
+        let bad = br#"{"Add":{"name":"web-1","ip":"10.0.0.999"}}"#;
+        assert!(decode(bad).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_envelopes() {
+        assert!(decode(&[]).is_err());
+        assert!(decode(&[WIRE_VERSION, 99]).is_err());
+        assert!(decode(&[7, MSG_UPDATE]).is_err());
+        assert!(decode(&[WIRE_VERSION, MSG_UPDATE, 0xff]).is_err());
+        assert!(decode(&[WIRE_VERSION, MSG_ORIGIN_UPDATE, 1, 2, 3]).is_err());
+        assert!(decode(&[WIRE_VERSION, MSG_SYNC_REQUEST]).is_err());
+        assert!(decode(&[WIRE_VERSION, MSG_DIGEST, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn binary_is_smaller_than_json() {
+        let message = Message {
+            origin: Some(origin()),
+            node_name: Some("swarm-worker-3".into()),
+            nonce: Some(u64::MAX),
+            body: Body::Update(add()),
+        };
+        let json = serde_json::to_vec(&message).unwrap().len();
+        let binary = encode(
+            &origin(),
+            message.node_name.as_deref(),
+            message.nonce,
+            &message.body,
+        )
+        .unwrap()
+        .len();
+        assert!(binary < json);
+    }
+
+    #[test]
+    fn sync_answers_are_updates_to_previous_versions() {
+        let answer = Body::SyncAnswer(add());
+        let flagged = encode(&origin(), Some("swarm-worker-3"), Some(7), &answer).unwrap();
+        let message = decode(&flagged).unwrap();
+        assert_eq!((message.nonce, message.body), (Some(7), answer.clone()));
+
+        // What a node without the flag reads from the same payload.
+        let (previous, rest): ((Origin, Update), _) =
+            postcard::take_from_bytes(&flagged[2..]).unwrap();
+        assert_eq!(previous, (origin(), add()));
+        let (_, rest) = postcard::take_from_bytes::<String>(rest).unwrap();
+        assert_eq!(postcard::from_bytes::<u64>(rest).unwrap(), 7);
+
+        // Without a nonce to follow, the flag isn't sent.
+        let unflagged = encode(&origin(), None, None, &answer).unwrap();
+        assert_eq!(decode(&unflagged).unwrap().body, Body::Update(add()));
+    }
+
+    #[test]
+    fn garbage_is_an_error_not_a_panic() {
+        let valid = encode(
+            &origin(),
+            Some("swarm-worker-3"),
+            Some(7),
+            &Body::Update(add()),
+        )
+        .unwrap();
+        for _ in 0..2000 {
+            let len = rand::random::<usize>() % 256;
+            let mut bytes: Vec<u8> = (0..len).map(|_| rand::random()).collect();
+            let _ = decode(&bytes);
+            // Behind a valid header, and as a valid message cut short or
+            // with a byte changed.
+            let kind = rand::random::<u8>() % 5;
+            bytes.splice(0..0, [WIRE_VERSION, kind]);
+            let _ = decode(&bytes);
+            let _ = decode(&valid[..rand::random::<usize>() % valid.len()]);
+            let mut mutated = valid.clone();
+            mutated[rand::random::<usize>() % valid.len()] = rand::random();
+            let _ = decode(&mutated);
+        }
+        // A length far beyond the bytes there are.
+        let mut huge = vec![WIRE_VERSION, MSG_UPDATE, 2];
+        huge.extend([0xff, 0xff, 0xff, 0xff, 0x0f]);
+        assert!(decode(&huge).is_err());
+        // Batches of one batch, nested until the stack would run out.
+        let mut nested = vec![WIRE_VERSION, MSG_UPDATE];
+        for _ in 0..30_000 {
+            nested.extend([2, 1]);
+        }
+        assert!(decode(&nested).is_err());
+        let nested = Update::Batch(vec![Update::Batch(vec![add(), add()]), add()]);
+        let bytes = encode(&origin(), None, None, &Body::Update(nested.clone())).unwrap();
+        assert_eq!(decode(&bytes).unwrap().body, Body::Update(nested));
+    }
+}
@@ -0,0 +1,166 @@
+//! Harness for driving the update pipeline end to end without Docker.
+
+#![allow(dead_code)]
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use glued::dns_server::{serve_dns, DnsOptions};
+use glued::ede;
+use glued::gossip::apply_update;
+use glued::runtime::{ContainerRuntime, MockRuntime};
+use hickory_server::proto::op::{Edns, Message, MessageType, OpCode, Query, ResponseCode};
+use hickory_server::proto::rr::rdata::opt::EdnsCode;
+use hickory_server::proto::rr::{DNSClass, Name, RData, RecordType};
+use hickory_server::proto::serialize::binary::BinEncodable;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout, Instant};
+
+pub use glued::types::SharedState as State;
+
+/// How long helpers wait for the pipeline to converge before failing.
+const DEADLINE: Duration = Duration::from_secs(5);
+
+/// Starts a DNS server for `state` on an ephemeral localhost port.
+pub async fn spawn_dns(state: State) -> SocketAddr {
+    spawn_dns_with(state, DnsOptions::default()).await
+}
+
+/// Like [`spawn_dns`], with non-default server options.
+pub async fn spawn_dns_with(state: State, options: DnsOptions) -> SocketAddr {
+    let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = udp.local_addr().unwrap();
+    let tcp = TcpListener::bind(addr).await.unwrap();
+    tokio::spawn(serve_dns(udp, tcp, state, options));
+    addr
+}
+
+/// Runs `runtime` into a registry fed the same way the daemon feeds it and
+/// serves the result over DNS.  Returns the state map and server address.
+pub async fn spawn_pipeline(runtime: MockRuntime) -> (State, SocketAddr) {
+    let state = State::default();
+    let (update_tx, mut update_rx) = mpsc::channel(128);
+    tokio::spawn(async move { runtime.monitor(update_tx).await });
+
+    let registry = Arc::clone(&state);
+    tokio::spawn(async move {
+        while let Some((update, source)) = update_rx.recv().await {
+            apply_update(update, source, &registry).await;
+        }
+    });
+
+    let addr = spawn_dns(Arc::clone(&state)).await;
+    (state, addr)
+}
+
+/// Sends one query over UDP and returns the parsed response.
+pub async fn query(server: SocketAddr, name: &str, rtype: RecordType) -> Message {
+    query_class(server, name, rtype, DNSClass::IN).await
+}
+
+/// Like [`query`], in another class.
+pub async fn query_class(
+    server: SocketAddr,
+    name: &str,
+    rtype: RecordType,
+    class: DNSClass,
+) -> Message {
+    let mut msg = Message::new();
+    msg.set_id(0x4242)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true);
+    let mut query = Query::query(Name::from_ascii(name).unwrap(), rtype);
+    query.set_query_class(class);
+    msg.add_query(query);
+    exchange(server, &msg).await
+}
+
+/// Like [`query`], advertising EDNS with a 4096-byte payload.
+pub async fn query_edns(server: SocketAddr, name: &str, rtype: RecordType) -> Message {
+    let mut msg = Message::new();
+    msg.set_id(0x4343)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(Query::query(Name::from_ascii(name).unwrap(), rtype));
+    let mut edns = Edns::new();
+    edns.set_max_payload(4096);
+    msg.set_edns(edns);
+    exchange(server, &msg).await
+}
+
+/// The extended DNS error of a response, if it has one.
+pub fn extended_error(msg: &Message) -> Option<(u16, String)> {
+    let option = msg
+        .extensions()
+        .as_ref()?
+        .option(EdnsCode::from(ede::EDE))?;
+    ede::parse(option)
+}
+
+/// Sends `msg` over UDP and returns the parsed response.
+async fn exchange(server: SocketAddr, msg: &Message) -> Message {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket
+        .send_to(&msg.to_bytes().unwrap(), server)
+        .await
+        .unwrap();
+    let mut buf = vec![0u8; 4096];
+    let (len, _) = timeout(DEADLINE, socket.recv_from(&mut buf))
+        .await
+        .expect("DNS response timed out")
+        .unwrap();
+    Message::from_vec(&buf[..len]).unwrap()
+}
+
+/// The addresses in a response's answer section.
+pub fn answer_ips(msg: &Message) -> Vec<IpAddr> {
+    msg.answers()
+        .iter()
+        .filter_map(|r| match r.data() {
+            Some(RData::A(a)) => Some(IpAddr::V4(a.0)),
+            Some(RData::AAAA(aaaa)) => Some(IpAddr::V6(aaaa.0)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Re-queries until `check` accepts the response, panicking after a deadline.
+pub async fn wait_for(
+    server: SocketAddr,
+    name: &str,
+    rtype: RecordType,
+    check: impl Fn(&Message) -> bool,
+) -> Message {
+    let start = Instant::now();
+    loop {
+        let msg = query(server, name, rtype).await;
+        if check(&msg) {
+            return msg;
+        }
+        if start.elapsed() > DEADLINE {
+            panic!(
+                "{} never reached the expected state; last response: {:?}",
+                name, msg
+            );
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+}
+
+/// Waits until `name` answers with exactly `ips`.
+pub async fn wait_for_ips(server: SocketAddr, name: &str, rtype: RecordType, ips: &[&str]) {
+    let expected: Vec<IpAddr> = ips.iter().map(|ip| ip.parse().unwrap()).collect();
+    wait_for(server, name, rtype, |msg| answer_ips(msg) == expected).await;
+}
+
+/// Waits until `name` yields `code`.
+pub async fn wait_for_code(server: SocketAddr, name: &str, code: ResponseCode) -> Message {
+    wait_for(server, name, RecordType::A, |msg| {
+        msg.response_code() == code
+    })
+    .await
+}
@@ -0,0 +1,1443 @@
+//! DNS answers for local and non-local names.
+
+mod common;
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{
+    answer_ips, extended_error, query, query_class, query_edns, spawn_dns, spawn_dns_with,
+    wait_for_ips, State,
+};
+use glued::acl::DnsAcl;
+use glued::config::{Config, ForwardZone, NetworkConfig, StaticRecord, View};
+use glued::dns_server::{run_dns_server, DnsOptions, DnsSockets};
+use glued::dns_tls::{self, TlsListener};
+use glued::forward::ForwardLimits;
+use glued::gossip::{apply_update, apply_update_from};
+use glued::hosts_file;
+use glued::limits::Limits;
+use glued::metrics::{self, QueryOutcome, METRICS};
+use glued::registry::{LocalSource, Registry};
+use glued::rrl::RatePolicy;
+use glued::static_records;
+use glued::status::Status;
+use glued::types::{Entry, Port, PortProtocol, ScopedIp, Update};
+use hickory_server::proto::op::{Message, MessageType, Query, ResponseCode};
+use hickory_server::proto::rr::rdata::SOA;
+use hickory_server::proto::rr::{DNSClass, Name, RData, Record, RecordType};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::Instant;
+use tokio_rustls::rustls;
+use tokio_rustls::TlsConnector;
+
+async fn local_state() -> State {
+    let state = State::default();
+    state
+        .write()
+        .await
+        .insert("web-1".into(), Entry::new("10.0.0.2".parse().unwrap()));
+    state
+}
+
+#[tokio::test]
+async fn authoritative_only_refuses_other_names() {
+    let dns = spawn_dns_with(
+        local_state().await,
+        DnsOptions {
+            forwarding: false,
+            ..DnsOptions::default()
+        },
+    )
+    .await;
+
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.0.0.2"]).await;
+    let response = query(dns, "example.com.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::Refused);
+    assert!(!response.recursion_available());
+    assert_eq!(
+        query(dns, "missing", RecordType::A).await.response_code(),
+        ResponseCode::NXDomain
+    );
+}
+
+#[tokio::test]
+async fn denied_clients_are_refused() {
+    let acl = DnsAcl::new(
+        Vec::new(),
+        vec!["127.0.0.0/8".parse().unwrap()],
+        Vec::new(),
+        Vec::new(),
+    );
+    let dns = spawn_dns_with(
+        local_state().await,
+        DnsOptions {
+            acl,
+            ..DnsOptions::default()
+        },
+    )
+    .await;
+
+    let response = query(dns, "web-1", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::Refused);
+    assert!(response.answers().is_empty());
+}
+
+#[tokio::test]
+async fn forwarding_can_be_limited_to_fewer_clients() {
+    let acl = DnsAcl::new(
+        vec!["127.0.0.0/8".parse().unwrap()],
+        Vec::new(),
+        vec!["10.0.0.0/8".parse().unwrap()],
+        Vec::new(),
+    );
+    let dns = spawn_dns_with(
+        local_state().await,
+        DnsOptions {
+            acl,
+            ..DnsOptions::default()
+        },
+    )
+    .await;
+
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.0.0.2"]).await;
+    assert_eq!(
+        query(dns, "example.com.", RecordType::A)
+            .await
+            .response_code(),
+        ResponseCode::Refused
+    );
+}
+
+#[tokio::test]
+async fn static_records_serve_every_address_and_txt() {
+    let state = State::default();
+    let mut records = BTreeMap::new();
+    records.insert(
+        "router".to_string(),
+        StaticRecord {
+            ips: vec![
+                "192.168.1.1".parse().unwrap(),
+                "192.168.1.2".parse().unwrap(),
+                "fd00::1".parse().unwrap(),
+            ],
+            txt: vec!["model=ax3000".into()],
+        },
+    );
+    static_records::install(&mut *state.write().await, &records).unwrap();
+    let dns = spawn_dns(state).await;
+
+    wait_for_ips(
+        dns,
+        "router",
+        RecordType::A,
+        &["192.168.1.1", "192.168.1.2"],
+    )
+    .await;
+    wait_for_ips(dns, "router", RecordType::AAAA, &["fd00::1"]).await;
+    let response = query(dns, "router", RecordType::TXT).await;
+    assert_eq!(response.answers().len(), 1);
+    assert_eq!(
+        response.answers()[0].data().unwrap().to_string(),
+        "model=ax3000"
+    );
+}
+
+#[tokio::test]
+async fn hosts_file_names_are_answered_without_forwarding() {
+    let state = State::default();
+    let hosts = hosts_file::parse("192.168.1.10 nas nas.lan\n", Path::new("hosts"));
+    hosts_file::apply(&mut *state.write().await, &hosts);
+    let dns = spawn_dns_with(
+        state,
+        DnsOptions {
+            forwarding: false,
+            ..DnsOptions::default()
+        },
+    )
+    .await;
+
+    wait_for_ips(dns, "nas", RecordType::A, &["192.168.1.10"]).await;
+    wait_for_ips(dns, "nas.lan.", RecordType::A, &["192.168.1.10"]).await;
+    assert_eq!(
+        query(dns, "other.lan.", RecordType::A)
+            .await
+            .response_code(),
+        ResponseCode::Refused
+    );
+}
+
+#[tokio::test]
+async fn dns_over_tls_answers_like_plain_dns() {
+    let dir = std::env::temp_dir().join(format!("glued-dot-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let cert = rcgen::generate_simple_self_signed(vec!["dns.example".into()]).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+    std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+    // Find a free port for the TLS listener.
+    let bind = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = dns_tls::server_config(&cert_path, &key_path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    spawn_dns_with(
+        local_state().await,
+        DnsOptions {
+            tls: Some(TlsListener { bind, config }),
+            ..DnsOptions::default()
+        },
+    )
+    .await;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots
+        .add(&rustls::Certificate(cert.serialize_der().unwrap()))
+        .unwrap();
+    let client = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let tcp = wait_for_listener(bind).await;
+    let mut stream = TlsConnector::from(Arc::new(client))
+        .connect("dns.example".try_into().unwrap(), tcp)
+        .await
+        .unwrap();
+
+    let mut msg = Message::new();
+    msg.set_id(7).add_query(Query::query(
+        Name::from_ascii("web-1").unwrap(),
+        RecordType::A,
+    ));
+    let bytes = msg.to_vec().unwrap();
+    stream.write_u16(bytes.len() as u16).await.unwrap();
+    stream.write_all(&bytes).await.unwrap();
+    let len = stream.read_u16().await.unwrap();
+    let mut buf = vec![0; usize::from(len)];
+    stream.read_exact(&mut buf).await.unwrap();
+    let response = Message::from_vec(&buf).unwrap();
+    assert_eq!(response.id(), 7);
+    assert_eq!(
+        answer_ips(&response),
+        ["10.0.0.2".parse::<std::net::IpAddr>().unwrap()]
+    );
+}
+
+/// Connects to `addr` once the server is listening there.
+async fn wait_for_listener(addr: SocketAddr) -> TcpStream {
+    for _ in 0..50 {
+        if let Ok(stream) = TcpStream::connect(addr).await {
+            return stream;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("nothing listening on {}", addr);
+}
+
+async fn dual_stack_state() -> State {
+    let state = State::default();
+    let mut records = BTreeMap::new();
+    records.insert(
+        "nas".to_string(),
+        StaticRecord {
+            ips: vec!["192.168.1.10".parse().unwrap(), "fd00::10".parse().unwrap()],
+            txt: vec!["role=storage".into()],
+        },
+    );
+    static_records::install(&mut *state.write().await, &records).unwrap();
+    state
+}
+
+#[tokio::test]
+async fn any_returns_every_record_for_the_name() {
+    let dns = spawn_dns(dual_stack_state().await).await;
+    let response = query(dns, "nas", RecordType::ANY).await;
+    let mut types: Vec<RecordType> = response
+        .answers()
+        .iter()
+        .map(|record| record.record_type())
+        .collect();
+    types.sort();
+    assert_eq!(types, [RecordType::A, RecordType::TXT, RecordType::AAAA]);
+}
+
+#[tokio::test]
+async fn minimal_any_answers_with_hinfo() {
+    let dns = spawn_dns_with(
+        dual_stack_state().await,
+        DnsOptions {
+            minimal_any: true,
+            ..DnsOptions::default()
+        },
+    )
+    .await;
+    let response = query(dns, "nas", RecordType::ANY).await;
+    assert_eq!(response.answers().len(), 1);
+    assert_eq!(response.answers()[0].record_type(), RecordType::HINFO);
+    wait_for_ips(dns, "nas", RecordType::AAAA, &["fd00::10"]).await;
+}
+
+#[tokio::test]
+async fn lookups_ignore_case_and_keep_the_query_spelling() {
+    let state = State::default();
+    apply_update(
+        Update::Add {
+            name: "Web-2".into(),
+            ip: "10.0.0.3".parse().unwrap(),
+        },
+        LocalSource::DockerEvent,
+        &state,
+    )
+    .await;
+    let dns = spawn_dns(state.clone()).await;
+
+    for name in ["web-2", "WEB-2", "wEb-2."] {
+        let response = query(dns, name, RecordType::A).await;
+        assert_eq!(
+            answer_ips(&response),
+            ["10.0.0.3".parse::<std::net::IpAddr>().unwrap()],
+            "{}",
+            name
+        );
+        assert_eq!(
+            response.answers()[0]
+                .name()
+                .to_string()
+                .trim_end_matches('.'),
+            name.trim_end_matches('.')
+        );
+    }
+
+    apply_update(
+        Update::Remove {
+            name: "WEB-2".into(),
+        },
+        LocalSource::DockerEvent,
+        &state,
+    )
+    .await;
+    assert_eq!(
+        query(dns, "web-2", RecordType::A).await.response_code(),
+        ResponseCode::NXDomain
+    );
+}
+
+#[tokio::test]
+async fn container_txt_strings_are_served_until_it_goes() {
+    let state = State::default();
+    apply_update(
+        Update::batch(vec![
+            Update::Add {
+                name: "web-1".into(),
+                ip: "10.0.0.2".parse().unwrap(),
+            },
+            Update::Txt {
+                name: "web-1".into(),
+                txt: vec!["role=api".into(), "version=1.4.2".into()],
+            },
+        ]),
+        LocalSource::DockerEvent,
+        &state,
+    )
+    .await;
+    let dns = spawn_dns(state.clone()).await;
+
+    let response = query(dns, "web-1", RecordType::TXT).await;
+    let Some(RData::TXT(txt)) = response.answers()[0].data() else {
+        panic!("no TXT answer: {:?}", response);
+    };
+    let strings: Vec<&[u8]> = txt.iter().map(|s| &**s).collect();
+    assert_eq!(strings, [&b"role=api"[..], b"version=1.4.2"]);
+
+    apply_update(
+        Update::Remove {
+            name: "web-1".into(),
+        },
+        LocalSource::DockerEvent,
+        &state,
+    )
+    .await;
+    assert_eq!(
+        query(dns, "web-1", RecordType::TXT).await.response_code(),
+        ResponseCode::NXDomain
+    );
+}
+
+#[tokio::test]
+async fn names_below_wildcard_entries_resolve_to_them() {
+    let state = State::default();
+    let add = |name: &str, ip: &str| Update::Add {
+        name: name.into(),
+        ip: ip.parse().unwrap(),
+    };
+    apply_update(
+        Update::batch(vec![
+            add("web-1", "10.0.0.2"),
+            add("*.web-1", "10.0.0.2"),
+            add("db", "10.0.0.4"),
+            add("cache", "10.0.0.5"),
+        ]),
+        LocalSource::DockerEvent,
+        &state,
+    )
+    .await;
+    let hosts = hosts_file::parse("192.168.1.9 api.web-1\n", Path::new("hosts"));
+    hosts_file::apply(&mut *state.write().await, &hosts);
+    let dns = spawn_dns_with(
+        state,
+        DnsOptions {
+            forwarding: false,
+            wildcard_names: ["db".to_string()].into(),
+            ..DnsOptions::default()
+        },
+    )
+    .await;
+
+    wait_for_ips(dns, "foo.web-1.", RecordType::A, &["10.0.0.2"]).await;
+    wait_for_ips(dns, "API.web-1.", RecordType::A, &["192.168.1.9"]).await;
+    wait_for_ips(dns, "foo.db.", RecordType::A, &["10.0.0.4"]).await;
+    // Deeper names outside the local zone could be anyone's.
+    for name in ["foo.cache.", "a.b.web-1."] {
+        assert_eq!(
+            query(dns, name, RecordType::A).await.response_code(),
+            ResponseCode::Refused,
+            "{}",
+            name
+        );
+    }
+}
+
+#[tokio::test]
+async fn wildcards_leave_other_domains_to_the_upstreams() {
+    let (upstream, queries) = nxdomain_upstream("example.com.").await;
+    let mut forward_zones = BTreeMap::new();
+    forward_zones.insert(
+        "example.com".to_string(),
+        ForwardZone {
+            upstreams: vec![upstream.to_string().parse().unwrap()],
+            fallthrough: false,
+        },
+    );
+    let state = State::default();
+    let add = |name: &str| Update::Add {
+        name: name.into(),
+        ip: "10.0.0.2".parse().unwrap(),
+    };
+    apply_update(
+        Update::batch(vec![add("com"), add("*.com")]),
+        LocalSource::DockerEvent,
+        &state,
+    )
+    .await;
+    let dns = spawn_dns_with(
+        state,
+        DnsOptions {
+            forward_zones,
+            wildcard_names: ["com".to_string()].into(),
+            ..DnsOptions::default()
+        },
+    )
+    .await;
+
+    wait_for_ips(dns, "com.", RecordType::A, &["10.0.0.2"]).await;
+    let response = query(dns, "x.example.com.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NXDomain);
+    assert!(queries.load(Ordering::Relaxed) > 0);
+}
+
+#[tokio::test]
+async fn networks_are_answered_by_suffix_or_client_subnet() {
+    let state = local_state().await;
+    for (name, ip) in [
+        ("web-1.net-a", "10.1.0.2"),
+        ("web-1.net-b", "10.2.0.2"),
+        ("api.net-b", "10.2.0.3"),
+    ] {
+        state
+            .write()
+            .await
+            .insert(name.into(), Entry::new(ip.parse().unwrap()));
+    }
+    let network = |name: &str, byte: u8, subnet: &str| NetworkConfig {
+        name: name.into(),
+        topic: hex::encode([byte; 32]),
+        subnets: vec![subnet.parse().unwrap()],
+    };
+    let options = |subnet: &str| {
+        let cfg = Config {
+            networks: vec![
+                network("net-a", 1, subnet),
+                network("net-b", 2, "10.2.0.0/16"),
+            ],
+            local_domain: Some("glued".into()),
+            ..Config::default()
+        };
+        DnsOptions {
+            forwarding: false,
+            ..DnsOptions::from_config(&cfg).unwrap()
+        }
+    };
+
+    // From outside every network's subnets, the suffix picks the network.
+    let dns = spawn_dns_with(state.clone(), options("10.1.0.0/16")).await;
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.0.0.2"]).await;
+    wait_for_ips(dns, "web-1.net-a", RecordType::A, &["10.1.0.2"]).await;
+    wait_for_ips(dns, "web-1.net-b.glued.", RecordType::A, &["10.2.0.2"]).await;
+    let response = query(dns, "missing.net-a", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NXDomain);
+
+    // Clients in net-a get its names without the suffix, and none of net-b's.
+    let dns = spawn_dns_with(state, options("127.0.0.0/8")).await;
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.1.0.2"]).await;
+    wait_for_ips(dns, "web-1.net-a.glued.", RecordType::A, &["10.1.0.2"]).await;
+    for name in ["web-1.net-b", "api.net-b.glued.", "api"] {
+        let response = query(dns, name, RecordType::A).await;
+        assert_eq!(response.response_code(), ResponseCode::NXDomain, "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn views_pick_the_address_scope_by_client_subnet() {
+    let state = State::default();
+    let scoped = |scope: &str, ip: &str| ScopedIp {
+        scope: scope.into(),
+        ip: ip.parse().unwrap(),
+    };
+    apply_update(
+        Update::batch(vec![
+            Update::Add {
+                name: "web-1".into(),
+                ip: "10.0.0.2".parse().unwrap(),
+            },
+            Update::ScopedIps {
+                name: "web-1".into(),
+                ips: vec![scoped("host", "192.168.1.2"), scoped("lo", "127.0.0.9")],
+            },
+            Update::Add {
+                name: "db".into(),
+                ip: "10.0.0.3".parse().unwrap(),
+            },
+        ]),
+        LocalSource::DockerEvent,
+        &state,
+    )
+    .await;
+    let view = |subnet: &str, scope: &str| View {
+        subnets: vec![subnet.parse().unwrap()],
+        scope: scope.into(),
+    };
+    let options = |views| {
+        let cfg = Config {
+            views,
+            ..Config::default()
+        };
+        DnsOptions {
+            forwarding: false,
+            ..DnsOptions::from_config(&cfg).unwrap()
+        }
+    };
+
+    // A client in no view gets the default addresses.
+    let dns = spawn_dns_with(state.clone(), options(vec![view("10.0.0.0/8", "host")])).await;
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.0.0.2"]).await;
+
+    // Of overlapping views, the longest prefix wins; a name without
+    // addresses in its scope gets the default ones.
+    let views = vec![view("127.0.0.0/8", "host"), view("127.0.0.1/32", "lo")];
+    let dns = spawn_dns_with(state.clone(), options(views)).await;
+    wait_for_ips(dns, "web-1", RecordType::A, &["127.0.0.9"]).await;
+    wait_for_ips(dns, "db", RecordType::A, &["10.0.0.3"]).await;
+    let dns = spawn_dns_with(state, options(vec![view("127.0.0.0/8", "host")])).await;
+    wait_for_ips(dns, "web-1", RecordType::A, &["192.168.1.2"]).await;
+}
+
+#[tokio::test]
+async fn local_zone_has_soa_and_ns_and_negative_answers_carry_the_soa() {
+    let state = local_state().await;
+    state
+        .write()
+        .await
+        .insert("*.web-1".into(), Entry::new("10.0.0.2".parse().unwrap()));
+    let cfg = Config {
+        local_domain: Some("glued.internal".into()),
+        soa_serial: Some(2024),
+        soa_minimum: 45,
+        ..Config::default()
+    };
+    let dns = spawn_dns_with(state, DnsOptions::from_config(&cfg).unwrap()).await;
+
+    wait_for_ips(dns, "web-1.glued.internal.", RecordType::A, &["10.0.0.2"]).await;
+    wait_for_ips(dns, "x.web-1.glued.internal.", RecordType::A, &["10.0.0.2"]).await;
+    wait_for_ips(
+        dns,
+        "a.b.web-1.glued.internal.",
+        RecordType::A,
+        &["10.0.0.2"],
+    )
+    .await;
+
+    let response = query(dns, "glued.internal.", RecordType::SOA).await;
+    assert!(response.authoritative());
+    let Some(RData::SOA(soa)) = response.answers()[0].data() else {
+        panic!("no SOA in {:?}", response);
+    };
+    assert_eq!(soa.serial(), 2024);
+    assert_eq!(soa.mname().to_string(), "ns.glued.internal.");
+    let response = query(dns, "glued.internal.", RecordType::NS).await;
+    assert!(matches!(response.answers()[0].data(), Some(RData::NS(_))));
+
+    // NXDOMAIN and NODATA carry the SOA, with the minimum as its TTL; names
+    // in the zone are never forwarded.
+    for (name, qtype, code) in [
+        (
+            "missing.glued.internal.",
+            RecordType::A,
+            ResponseCode::NXDomain,
+        ),
+        (
+            "web-1.glued.internal.",
+            RecordType::AAAA,
+            ResponseCode::NoError,
+        ),
+        ("glued.internal.", RecordType::A, ResponseCode::NoError),
+    ] {
+        let response = query(dns, name, qtype).await;
+        assert_eq!(response.response_code(), code, "{}", name);
+        assert!(response.answers().is_empty(), "{}", name);
+        let soa = &response.name_servers()[0];
+        assert_eq!(soa.record_type(), RecordType::SOA, "{}", name);
+        assert_eq!(soa.ttl(), 45, "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn empty_non_terminals_are_nodata_not_nxdomain() {
+    let state = local_state().await;
+    state
+        .write()
+        .await
+        .insert("a.b".into(), Entry::new("10.0.0.3".parse().unwrap()));
+    let cfg = Config {
+        local_domain: Some("glued".into()),
+        forwarding: false,
+        ..Config::default()
+    };
+    let dns = spawn_dns_with(state, DnsOptions::from_config(&cfg).unwrap()).await;
+    wait_for_ips(dns, "a.b.glued.", RecordType::A, &["10.0.0.3"]).await;
+
+    // Each step of a minimized lookup for a.b.glued, and one off it.
+    for (name, code) in [
+        ("glued.", ResponseCode::NoError),
+        ("b.glued.", ResponseCode::NoError),
+        ("B.glued.", ResponseCode::NoError),
+        ("c.b.glued.", ResponseCode::NXDomain),
+        ("a.b.glued.", ResponseCode::NoError),
+        ("b.", ResponseCode::NoError),
+        ("c.", ResponseCode::NXDomain),
+    ] {
+        let response = query(dns, name, RecordType::AAAA).await;
+        assert_eq!(response.response_code(), code, "{}", name);
+        assert!(response.answers().is_empty(), "{}", name);
+        if name.ends_with("glued.") {
+            assert_eq!(
+                response.name_servers()[0].record_type(),
+                RecordType::SOA,
+                "{}",
+                name
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn txt_metadata_names_the_publishing_node() {
+    let state = State::default();
+    apply_update_from(
+        Update::Add {
+            name: "web-1".into(),
+            ip: "10.0.0.2".parse().unwrap(),
+        },
+        Some("ab12cd34ef"),
+        &state,
+    )
+    .await;
+    let mut records = BTreeMap::new();
+    records.insert(
+        "nas".to_string(),
+        StaticRecord {
+            ips: vec!["192.168.1.10".parse().unwrap()],
+            txt: Vec::new(),
+        },
+    );
+    static_records::install(&mut *state.write().await, &records).unwrap();
+    let plain = spawn_dns(Arc::clone(&state)).await;
+    let dns = spawn_dns_with(
+        state,
+        DnsOptions {
+            txt_metadata: true,
+            ..DnsOptions::default()
+        },
+    )
+    .await;
+
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.0.0.2"]).await;
+    let response = query(dns, "web-1", RecordType::TXT).await;
+    let Some(RData::TXT(txt)) = response.answers()[0].data() else {
+        panic!("no TXT in {:?}", response);
+    };
+    let strings: Vec<String> = txt
+        .iter()
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect();
+    assert_eq!(strings[0], "node=ab12cd34ef");
+    assert!(strings[1].starts_with("updated=20"), "{:?}", strings);
+
+    for (server, name, code) in [
+        (dns, "nas", ResponseCode::NoError),
+        (dns, "missing", ResponseCode::NXDomain),
+        (plain, "web-1", ResponseCode::NoError),
+    ] {
+        let response = query(server, name, RecordType::TXT).await;
+        assert_eq!(response.response_code(), code, "{}", name);
+        assert!(response.answers().is_empty(), "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn query_latency_is_recorded_by_outcome() {
+    metrics::enable();
+    let histogram = |outcome| METRICS.dns_query_seconds(outcome).count();
+    let before = [QueryOutcome::LocalHit, QueryOutcome::LocalNxdomain].map(histogram);
+    let dns = spawn_dns(local_state().await).await;
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.0.0.2"]).await;
+    query(dns, "missing", RecordType::A).await;
+
+    // Other tests query too; these only add to the counts.
+    assert!(histogram(QueryOutcome::LocalHit) > before[0]);
+    assert!(histogram(QueryOutcome::LocalNxdomain) > before[1]);
+    let rendered = METRICS.render();
+    assert!(rendered.contains("glued_dns_query_seconds_bucket{outcome=\"local-hit\",le=\"+Inf\"}"));
+    assert!(!rendered.contains("glued_dns_query_seconds_count{outcome=\"local-hit\"} 0\n"));
+}
+
+#[tokio::test]
+async fn misses_are_servfail_until_warmed_up() {
+    let state = local_state().await;
+    let status = Arc::new(Status::new(false).warming_up(Duration::from_secs(60), false));
+    let dns = spawn_dns_with(
+        Arc::clone(&state),
+        DnsOptions {
+            status: Some(Arc::clone(&status)),
+            warmup_delay: Duration::from_secs(5),
+            ..DnsOptions::default()
+        },
+    )
+    .await;
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.0.0.2"]).await;
+
+    // A miss waits for the warm-up, and is answered from what it brought.
+    let late = tokio::spawn(query(dns, "web-2", RecordType::A));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    state
+        .write()
+        .await
+        .insert("web-2".into(), Entry::new("10.0.0.3".parse().unwrap()));
+    status.set_synced();
+    assert_eq!(
+        answer_ips(&late.await.unwrap()),
+        ["10.0.0.3".parse::<std::net::IpAddr>().unwrap()]
+    );
+    assert_eq!(
+        query(dns, "missing", RecordType::A).await.response_code(),
+        ResponseCode::NXDomain
+    );
+
+    // Without a delay, a miss during the warm-up fails at once, and isn't
+    // counted as a hit.
+    metrics::enable();
+    let failed = METRICS.dns_query_seconds(QueryOutcome::LocalFail).count();
+    let status = Arc::new(Status::new(false).warming_up(Duration::from_millis(500), false));
+    let dns = spawn_dns_with(
+        local_state().await,
+        DnsOptions {
+            status: Some(Arc::clone(&status)),
+            ..DnsOptions::default()
+        },
+    )
+    .await;
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.0.0.2"]).await;
+    assert_eq!(
+        query(dns, "missing", RecordType::A).await.response_code(),
+        ResponseCode::ServFail
+    );
+    assert!(METRICS.dns_query_seconds(QueryOutcome::LocalFail).count() > failed);
+    assert!(!status.is_ready());
+    // The warm-up ends with its timeout even if no peer answers.
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(status.is_ready());
+    assert_eq!(
+        query(dns, "missing", RecordType::A).await.response_code(),
+        ResponseCode::NXDomain
+    );
+}
+
+#[tokio::test]
+async fn slow_forwarded_lookups_time_out_and_are_capped() {
+    // An upstream that never answers.
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut forward_zones = BTreeMap::new();
+    forward_zones.insert(
+        "slow.example".to_string(),
+        ForwardZone {
+            upstreams: vec![silent.local_addr().unwrap().to_string().parse().unwrap()],
+            fallthrough: false,
+        },
+    );
+    let dns = spawn_dns_with(
+        local_state().await,
+        DnsOptions {
+            forward_zones: forward_zones.clone(),
+            forward_limits: ForwardLimits {
+                timeout: Duration::from_millis(300),
+                max_inflight: 1,
+                max_queued: 0,
+                rate: RatePolicy::for_forwarding(&Config::default()),
+            },
+            ..DnsOptions::default()
+        },
+    )
+    .await;
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.0.0.2"]).await;
+
+    let started = Instant::now();
+    let first = tokio::spawn(query(dns, "a.slow.example.", RecordType::A));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let second = query(dns, "b.slow.example.", RecordType::A).await;
+    assert_eq!(second.response_code(), ResponseCode::ServFail);
+    assert!(started.elapsed() < Duration::from_millis(250));
+
+    let first = first.await.unwrap();
+    assert_eq!(first.response_code(), ResponseCode::ServFail);
+    let elapsed = started.elapsed();
+    assert!(
+        elapsed >= Duration::from_millis(300) && elapsed < Duration::from_secs(2),
+        "{:?}",
+        elapsed
+    );
+
+    // The slot is free again once the lookup was abandoned.
+    let started = Instant::now();
+    let third = query(dns, "c.slow.example.", RecordType::A).await;
+    assert_eq!(third.response_code(), ResponseCode::ServFail);
+    assert!(started.elapsed() >= Duration::from_millis(300));
+
+    // Queued queries wait for a slot within their own timeout.
+    let dns = spawn_dns_with(
+        local_state().await,
+        DnsOptions {
+            forward_zones,
+            forward_limits: ForwardLimits {
+                timeout: Duration::from_millis(300),
+                max_inflight: 1,
+                max_queued: 1,
+                rate: RatePolicy::for_forwarding(&Config::default()),
+            },
+            ..DnsOptions::default()
+        },
+    )
+    .await;
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.0.0.2"]).await;
+    let started = Instant::now();
+    let first = tokio::spawn(query(dns, "a.slow.example.", RecordType::A));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let queued = tokio::spawn(query(dns, "b.slow.example.", RecordType::A));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(METRICS.dns_forward_queued.load(Ordering::Relaxed) >= 1);
+    let third = query(dns, "c.slow.example.", RecordType::A).await;
+    assert_eq!(third.response_code(), ResponseCode::ServFail);
+    assert!(started.elapsed() < Duration::from_millis(250));
+    for waiting in [first, queued] {
+        assert_eq!(
+            waiting.await.unwrap().response_code(),
+            ResponseCode::ServFail
+        );
+    }
+    // The queued query gave up by its deadline, not one after the first's.
+    assert!(started.elapsed() < Duration::from_millis(550));
+}
+
+#[tokio::test]
+async fn failures_carry_extended_dns_errors() {
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut forward_zones = BTreeMap::new();
+    forward_zones.insert(
+        "slow.example".to_string(),
+        ForwardZone {
+            upstreams: vec![silent.local_addr().unwrap().to_string().parse().unwrap()],
+            fallthrough: false,
+        },
+    );
+    let status = Arc::new(Status::new(false).warming_up(Duration::from_secs(60), false));
+    let dns = spawn_dns_with(
+        local_state().await,
+        DnsOptions {
+            forward_zones,
+            forward_limits: ForwardLimits {
+                timeout: Duration::from_millis(200),
+                ..ForwardLimits::from_config(&Config::default()).unwrap()
+            },
+            status: Some(status),
+            ..DnsOptions::default()
+        },
+    )
+    .await;
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.0.0.2"]).await;
+
+    let response = query_edns(dns, "missing", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::ServFail);
+    let edns = response.extensions().as_ref().expect("no OPT record");
+    assert_eq!(edns.max_payload(), 1232);
+    let (code, text) = extended_error(&response).unwrap();
+    assert_eq!(code, 14);
+    assert!(
+        text.starts_with("not ready (warming up) (request "),
+        "{}",
+        text
+    );
+    let response = query_edns(dns, "a.slow.example.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::ServFail);
+    let (code, text) = extended_error(&response).unwrap();
+    assert_eq!(code, 22);
+    assert!(text.starts_with("upstream timeout"), "{}", text);
+    // Answers carry the OPT record too, without an error.
+    let response = query_edns(dns, "web-1", RecordType::A).await;
+    assert!(response.extensions().is_some());
+    assert_eq!(extended_error(&response), None);
+    // Clients without EDNS get no OPT record.
+    let response = query(dns, "missing", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::ServFail);
+    assert!(response.extensions().is_none());
+
+    let acl = DnsAcl::new(
+        Vec::new(),
+        vec!["127.0.0.0/8".parse().unwrap()],
+        Vec::new(),
+        Vec::new(),
+    );
+    let dns = spawn_dns_with(
+        local_state().await,
+        DnsOptions {
+            acl,
+            ..DnsOptions::default()
+        },
+    )
+    .await;
+    let response = query_edns(dns, "web-1", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::Refused);
+    let (code, text) = extended_error(&response).unwrap();
+    assert_eq!(code, 18);
+    assert!(text.starts_with("blocked by ACL"), "{}", text);
+
+    let dns = spawn_dns_with(
+        local_state().await,
+        DnsOptions {
+            forwarding: false,
+            ..DnsOptions::default()
+        },
+    )
+    .await;
+    let response = query_edns(dns, "www.example.com.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::Refused);
+    assert_eq!(extended_error(&response).unwrap().0, 20);
+}
+
+/// An upstream answering NXDOMAIN, with the SOA of `zone`, to every
+/// query, and the number of queries it got.
+async fn nxdomain_upstream(zone: &str) -> (SocketAddr, Arc<AtomicUsize>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let zone = Name::from_ascii(zone).unwrap();
+    let queries = Arc::new(AtomicUsize::new(0));
+    let counted = Arc::clone(&queries);
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 512];
+        loop {
+            let (len, src) = socket.recv_from(&mut buf).await.unwrap();
+            counted.fetch_add(1, Ordering::Relaxed);
+            let mut msg = Message::from_vec(&buf[..len]).unwrap();
+            let soa = SOA::new(zone.clone(), zone.clone(), 1, 3600, 600, 86400, 60);
+            msg.set_message_type(MessageType::Response)
+                .set_response_code(ResponseCode::NXDomain)
+                .add_name_server(Record::from_rdata(zone.clone(), 60, RData::SOA(soa)));
+            socket.send_to(&msg.to_vec().unwrap(), src).await.unwrap();
+        }
+    });
+    (addr, queries)
+}
+
+/// An upstream answering `<ttl>.<zone>` with 192.0.2.1 for `ttl` seconds,
+/// and the number of queries it got.
+async fn ttl_upstream() -> (SocketAddr, Arc<AtomicUsize>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let queries = Arc::new(AtomicUsize::new(0));
+    let counted = Arc::clone(&queries);
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 512];
+        loop {
+            let (len, src) = socket.recv_from(&mut buf).await.unwrap();
+            counted.fetch_add(1, Ordering::Relaxed);
+            let mut msg = Message::from_vec(&buf[..len]).unwrap();
+            let name = msg.queries()[0].name().clone();
+            let ttl = name.iter().next().unwrap();
+            let ttl = std::str::from_utf8(ttl).unwrap().parse().unwrap();
+            msg.set_message_type(MessageType::Response);
+            if msg.queries()[0].query_type() == RecordType::A {
+                msg.add_answer(Record::from_rdata(
+                    name,
+                    ttl,
+                    RData::A("192.0.2.1".parse().unwrap()),
+                ));
+            }
+            socket.send_to(&msg.to_vec().unwrap(), src).await.unwrap();
+        }
+    });
+    (addr, queries)
+}
+
+#[tokio::test]
+async fn forwarded_answers_keep_upstream_ttls_within_the_clamp() {
+    let (upstream, queries) = ttl_upstream().await;
+    let mut forward_zones = BTreeMap::new();
+    forward_zones.insert(
+        "ttl.example".to_string(),
+        ForwardZone {
+            upstreams: vec![upstream.to_string().parse().unwrap()],
+            fallthrough: false,
+        },
+    );
+    let ttl = |response: Message| response.answers()[0].ttl();
+    let plain = spawn_dns_with(
+        local_state().await,
+        DnsOptions {
+            forward_zones: forward_zones.clone(),
+            ..DnsOptions::default()
+        },
+    )
+    .await;
+    wait_for_ips(plain, "web-1", RecordType::A, &["10.0.0.2"]).await;
+    assert_eq!(
+        ttl(query(plain, "14400.ttl.example.", RecordType::A).await),
+        14400
+    );
+    assert_eq!(ttl(query(plain, "5.ttl.example.", RecordType::A).await), 5);
+
+    let cfg = Config {
+        dns_forward_ttl_min: 30,
+        dns_forward_ttl_max: 3600,
+        ..Config::default()
+    };
+    let dns = spawn_dns_with(
+        local_state().await,
+        DnsOptions {
+            forward_zones,
+            ..DnsOptions::from_config(&cfg).unwrap()
+        },
+    )
+    .await;
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.0.0.2"]).await;
+    assert_eq!(
+        ttl(query(dns, "14400.ttl.example.", RecordType::A).await),
+        3600
+    );
+    assert_eq!(ttl(query(dns, "1.ttl.example.", RecordType::A).await), 30);
+
+    // The cache keeps records for the upstream's TTL, not the clamped one.
+    let asked = queries.load(Ordering::Relaxed);
+    query(dns, "14400.ttl.example.", RecordType::A).await;
+    assert_eq!(queries.load(Ordering::Relaxed), asked);
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+    assert_eq!(ttl(query(dns, "1.ttl.example.", RecordType::A).await), 30);
+    assert!(queries.load(Ordering::Relaxed) > asked);
+
+    let inverted = Config {
+        dns_forward_ttl_min: 600,
+        dns_forward_ttl_max: 60,
+        ..Config::default()
+    };
+    assert!(DnsOptions::from_config(&inverted).is_err());
+}
+
+#[tokio::test]
+async fn random_subdomains_are_cached_as_missing_and_rate_limited() {
+    let (upstream, queries) = nxdomain_upstream("flood.example.").await;
+    let mut forward_zones = BTreeMap::new();
+    forward_zones.insert(
+        "flood.example".to_string(),
+        ForwardZone {
+            upstreams: vec![upstream.to_string().parse().unwrap()],
+            fallthrough: false,
+        },
+    );
+    let cfg = Config {
+        dns_forward_rate: 1,
+        dns_forward_burst: 3,
+        ..Config::default()
+    };
+    let dns = spawn_dns_with(
+        local_state().await,
+        DnsOptions {
+            forward_zones,
+            ..DnsOptions::from_config(&cfg).unwrap()
+        },
+    )
+    .await;
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.0.0.2"]).await;
+
+    let hits = METRICS.dns_negative_cache_hits.load(Ordering::Relaxed);
+    // The upstream's SOA comes along, for downstream caches.
+    let authority_soa = |response: &Message| {
+        let soa = &response.name_servers()[0];
+        assert_eq!(soa.record_type(), RecordType::SOA);
+        assert_eq!(soa.name().to_string(), "flood.example.");
+        assert!((1..=60).contains(&soa.ttl()), "{}", soa.ttl());
+    };
+    let response = query(dns, "x7f2.flood.example.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NXDomain);
+    authority_soa(&response);
+    let asked = queries.load(Ordering::Relaxed);
+    assert!(asked >= 1);
+    // Asked again, in another spelling, it isn't forwarded.
+    let response = query(dns, "X7F2.flood.example.", RecordType::AAAA).await;
+    assert_eq!(response.response_code(), ResponseCode::NXDomain);
+    authority_soa(&response);
+    assert_eq!(queries.load(Ordering::Relaxed), asked);
+    assert!(METRICS.dns_negative_cache_hits.load(Ordering::Relaxed) > hits);
+    assert!(METRICS.dns_negative_cache_entries.load(Ordering::Relaxed) >= 1);
+
+    // New random names are forwarded, until the client is over its rate.
+    let codes: Vec<ResponseCode> = futures_util::future::join_all(
+        ["a1", "b2", "c3"]
+            .map(|label| format!("{}.flood.example.", label))
+            .iter()
+            .map(|name| query(dns, name, RecordType::A)),
+    )
+    .await
+    .iter()
+    .map(Message::response_code)
+    .collect();
+    assert_eq!(
+        codes
+            .iter()
+            .filter(|code| **code == ResponseCode::NXDomain)
+            .count(),
+        2
+    );
+    assert_eq!(
+        codes
+            .iter()
+            .filter(|code| **code == ResponseCode::Refused)
+            .count(),
+        1
+    );
+    // Names of ours, and names cached as missing, aren't limited.
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.0.0.2"]).await;
+    let response = query(dns, "x7f2.flood.example.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NXDomain);
+}
+
+#[tokio::test]
+async fn chaos_queries_identify_the_server_unless_disabled() {
+    let cfg = Config {
+        dns_chaos_version: Some("dns 1.0".into()),
+        dns_chaos_hostname: Some("dns-a".into()),
+        ..Config::default()
+    };
+    let dns = spawn_dns_with(local_state().await, DnsOptions::from_config(&cfg).unwrap()).await;
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.0.0.2"]).await;
+
+    for (name, expected) in [("version.bind.", "dns 1.0"), ("HOSTNAME.bind.", "dns-a")] {
+        let response = query_class(dns, name, RecordType::TXT, DNSClass::CH).await;
+        let answer = &response.answers()[0];
+        assert_eq!(answer.dns_class(), DNSClass::CH);
+        let Some(RData::TXT(txt)) = answer.data() else {
+            panic!("no TXT in {:?}", response);
+        };
+        assert_eq!(txt.iter().next().unwrap().as_ref(), expected.as_bytes());
+    }
+    // Other CHAOS names are never looked up or forwarded.
+    for name in ["authors.bind.", "web-1."] {
+        let response = query_class(dns, name, RecordType::TXT, DNSClass::CH).await;
+        assert_eq!(response.response_code(), ResponseCode::Refused, "{}", name);
+    }
+
+    let cfg = Config {
+        dns_chaos: false,
+        ..Config::default()
+    };
+    let dns = spawn_dns_with(local_state().await, DnsOptions::from_config(&cfg).unwrap()).await;
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.0.0.2"]).await;
+    let response = query_class(dns, "version.bind.", RecordType::TXT, DNSClass::CH).await;
+    assert_eq!(response.response_code(), ResponseCode::Refused);
+    assert!(response.answers().is_empty());
+}
+
+#[tokio::test]
+async fn cluster_names_are_answered_locally_unless_disabled() {
+    let state = local_state().await;
+    for (name, ip) in [("glued-node-b", "10.0.0.12"), ("glued-node-a", "10.0.0.11")] {
+        state
+            .write()
+            .await
+            .insert(name.into(), Entry::new(ip.parse().unwrap()));
+    }
+    let status = Arc::new(Status::new(false));
+    let options = DnsOptions {
+        forwarding: false,
+        status: Some(status),
+        ..DnsOptions::default()
+    };
+    let dns = spawn_dns_with(Arc::clone(&state), options.clone()).await;
+    wait_for_ips(dns, "glued-node-a", RecordType::A, &["10.0.0.11"]).await;
+
+    let strings = |response: &Message| -> Vec<String> {
+        response
+            .answers()
+            .iter()
+            .filter_map(|record| match record.data() {
+                Some(RData::TXT(txt)) => Some(txt.iter()),
+                _ => None,
+            })
+            .flatten()
+            .map(|s| String::from_utf8_lossy(s).into_owned())
+            .collect()
+    };
+    let response = query(dns, "_glued.cluster.", RecordType::TXT).await;
+    assert_eq!(strings(&response), ["entries=3", "nodes=2", "peers=0"]);
+    let response = query(dns, "_GLUED.nodes.", RecordType::A).await;
+    assert_eq!(
+        answer_ips(&response),
+        [
+            "10.0.0.11".parse::<std::net::IpAddr>().unwrap(),
+            "10.0.0.12".parse().unwrap()
+        ]
+    );
+    let response = query(dns, "_glued.nodes.", RecordType::TXT).await;
+    assert_eq!(
+        strings(&response),
+        ["glued-node-a=10.0.0.11", "glued-node-b=10.0.0.12"]
+    );
+
+    let dns = spawn_dns_with(
+        state,
+        DnsOptions {
+            cluster_info: false,
+            ..options
+        },
+    )
+    .await;
+    wait_for_ips(dns, "glued-node-a", RecordType::A, &["10.0.0.11"]).await;
+    let response = query(dns, "_glued.cluster.", RecordType::TXT).await;
+    assert_eq!(response.response_code(), ResponseCode::Refused);
+}
+
+/// A port nothing is listening on right now.
+fn free_port() -> u16 {
+    std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+#[tokio::test]
+async fn dns_is_served_on_every_bind_address() {
+    let first: SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+    let second: SocketAddr = format!("127.0.0.2:{}", free_port()).parse().unwrap();
+    let sockets = DnsSockets::bind(&[first, second], &[], false).unwrap();
+    assert_eq!(sockets.local_addrs(), [first, second]);
+    tokio::spawn(run_dns_server(
+        sockets,
+        local_state().await,
+        DnsOptions::default(),
+    ));
+    wait_for_ips(first, "web-1", RecordType::A, &["10.0.0.2"]).await;
+    wait_for_ips(second, "web-1", RecordType::A, &["10.0.0.2"]).await;
+    wait_for_listener(second).await;
+
+    // An address in use fails startup, naming it, unless best effort.
+    let taken = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let in_use = taken.local_addr().unwrap();
+    let other: SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+    let err = DnsSockets::bind(&[other, in_use], &[], false).unwrap_err();
+    assert!(
+        format!("{:#}", err).contains(&in_use.to_string()),
+        "{:#}",
+        err
+    );
+    let sockets = DnsSockets::bind(&[in_use, other], &[], true).unwrap();
+    assert_eq!(sockets.local_addrs(), [other]);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn instances_sharing_the_port_both_bind_it() {
+    let addr: SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+    let old = DnsSockets::bind_sharing_port(&[addr], &[], false).unwrap();
+    assert!(DnsSockets::bind(&[addr], &[], false).is_err());
+    let new = DnsSockets::bind_sharing_port(&[addr], &[], false).unwrap();
+    assert_eq!(new.local_addrs(), old.local_addrs());
+    drop(old);
+    tokio::spawn(run_dns_server(
+        new,
+        local_state().await,
+        DnsOptions::default(),
+    ));
+    wait_for_ips(addr, "web-1", RecordType::A, &["10.0.0.2"]).await;
+}
+
+#[tokio::test]
+async fn taken_dns_port_falls_back_in_order() {
+    let taken = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let in_use = taken.local_addr().unwrap();
+    let fallback = free_port();
+
+    let sockets = DnsSockets::bind(&[in_use], &[in_use.port(), fallback], false).unwrap();
+    let bound = SocketAddr::new(in_use.ip(), fallback);
+    assert_eq!(sockets.local_addrs(), [bound]);
+    tokio::spawn(run_dns_server(
+        sockets,
+        local_state().await,
+        DnsOptions::default(),
+    ));
+    wait_for_ips(bound, "web-1", RecordType::A, &["10.0.0.2"]).await;
+
+    // Every fallback taken too.
+    let err = DnsSockets::bind(&[in_use], &[fallback], false).unwrap_err();
+    assert!(format!("{:#}", err).contains("fallback ports"), "{:#}", err);
+}
+
+#[tokio::test]
+async fn srv_lists_the_ports_a_container_exposes() {
+    let state = State::default();
+    let port = |port, protocol| Port { port, protocol };
+    apply_update(
+        Update::Batch(vec![
+            Update::Add {
+                name: "web-1".into(),
+                ip: "10.0.0.2".parse().unwrap(),
+            },
+            Update::Ports {
+                name: "web-1".into(),
+                ports: vec![
+                    port(53, PortProtocol::Udp),
+                    port(80, PortProtocol::Tcp),
+                    port(443, PortProtocol::Tcp),
+                ],
+            },
+        ]),
+        LocalSource::DockerEvent,
+        &state,
+    )
+    .await;
+    let dns = spawn_dns(state).await;
+
+    let srv_ports = |response: Message| -> Vec<(u16, String)> {
+        response
+            .answers()
+            .iter()
+            .filter_map(|record| match record.data() {
+                Some(RData::SRV(srv)) => Some((srv.port(), srv.target().to_string())),
+                _ => None,
+            })
+            .collect()
+    };
+    let response = query(dns, "_http._tcp.web-1", RecordType::SRV).await;
+    assert_eq!(
+        srv_ports(response),
+        [(80, "web-1.".to_string()), (443, "web-1.".to_string())]
+    );
+    let response = query(dns, "_443._tcp.web-1", RecordType::SRV).await;
+    assert_eq!(srv_ports(response), [(443, "web-1.".to_string())]);
+
+    // A name without matching ports has no SRV records, but exists.
+    let response = query(dns, "_sip._sctp.web-1", RecordType::SRV).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert!(response.answers().is_empty());
+    let response = query(dns, "_http._tcp.missing", RecordType::SRV).await;
+    assert_eq!(response.response_code(), ResponseCode::NXDomain);
+}
+
+#[tokio::test]
+async fn address_changes_never_leave_the_name_missing() {
+    let state = local_state().await;
+    let dns = spawn_dns(Arc::clone(&state)).await;
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.0.0.2"]).await;
+
+    let flipper = tokio::spawn(async move {
+        for i in 0..200 {
+            let ip = if i % 2 == 0 { "10.0.0.3" } else { "10.0.0.2" };
+            apply_update(
+                Update::Add {
+                    name: "web-1".into(),
+                    ip: ip.parse().unwrap(),
+                },
+                LocalSource::DockerEvent,
+                &state,
+            )
+            .await;
+            tokio::task::yield_now().await;
+        }
+    });
+    while !flipper.is_finished() {
+        let response = query(dns, "web-1", RecordType::A).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(answer_ips(&response).len(), 1);
+    }
+    flipper.await.unwrap();
+}
+
+#[tokio::test]
+async fn updates_beyond_the_limits_are_dropped() {
+    let state: State = Arc::new(Registry::new(
+        16,
+        Limits {
+            max_entries: 100,
+            max_entries_per_node: 3,
+        },
+    ));
+    let add = |name: String, ip: &str| Update::Add {
+        name,
+        ip: ip.parse().unwrap(),
+    };
+    let flood = (0..50)
+        .map(|i| add(format!("flood-{}", i), "10.0.0.2"))
+        .collect();
+    apply_update_from(Update::Batch(flood), Some("ab12cd34ef"), &state).await;
+    apply_update(
+        add("x".repeat(64), "10.0.0.2"),
+        LocalSource::DockerEvent,
+        &state,
+    )
+    .await;
+    apply_update(
+        add("web-1".into(), "10.0.0.3"),
+        LocalSource::DockerEvent,
+        &state,
+    )
+    .await;
+    assert_eq!(state.read().await.len(), 4);
+
+    let dns = spawn_dns(state).await;
+    wait_for_ips(dns, "flood-2", RecordType::A, &["10.0.0.2"]).await;
+    let response = query(dns, "flood-3", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NXDomain);
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.0.0.3"]).await;
+}
@@ -0,0 +1,216 @@
+//! DNS over TCP: pipelining, per-connection limits and zone transfers.
+
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use common::{answer_ips, query, spawn_dns_with, State};
+use glued::config::Config;
+use glued::dns_server::DnsOptions;
+use glued::dns_tcp::TcpLimits;
+use glued::types::{Entry, ScopedIp};
+use hickory_server::proto::op::{Message, Query, ResponseCode};
+use hickory_server::proto::rr::{Name, RData, Record, RecordType};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+async fn server(limits: TcpLimits) -> SocketAddr {
+    let state = State::default();
+    state
+        .write()
+        .await
+        .insert("web-1".into(), Entry::new("10.0.0.2".parse().unwrap()));
+    spawn_dns_with(
+        state,
+        DnsOptions {
+            tcp: limits,
+            ..DnsOptions::default()
+        },
+    )
+    .await
+}
+
+async fn send_query(stream: &mut TcpStream, id: u16) {
+    send(stream, id, "web-1", RecordType::A).await;
+}
+
+async fn send(stream: &mut TcpStream, id: u16, name: &str, rtype: RecordType) {
+    let mut msg = Message::new();
+    msg.set_id(id)
+        .add_query(Query::query(Name::from_ascii(name).unwrap(), rtype));
+    let bytes = msg.to_vec().unwrap();
+    stream.write_u16(bytes.len() as u16).await.unwrap();
+    stream.write_all(&bytes).await.unwrap();
+}
+
+/// Reads one response, or `None` once the server has closed the connection.
+async fn read_response(stream: &mut TcpStream) -> Option<Message> {
+    let len = timeout(Duration::from_secs(5), stream.read_u16())
+        .await
+        .expect("TCP response timed out")
+        .ok()?;
+    let mut buf = vec![0; usize::from(len)];
+    stream.read_exact(&mut buf).await.unwrap();
+    Some(Message::from_vec(&buf).unwrap())
+}
+
+#[tokio::test]
+async fn pipelined_queries_are_answered_up_to_the_limit() {
+    let dns = server(TcpLimits {
+        max_queries: 2,
+        ..TcpLimits::default()
+    })
+    .await;
+    let mut stream = TcpStream::connect(dns).await.unwrap();
+    for id in 1..=3 {
+        send_query(&mut stream, id).await;
+    }
+
+    let mut ids = Vec::new();
+    while let Some(response) = read_response(&mut stream).await {
+        assert_eq!(
+            answer_ips(&response),
+            ["10.0.0.2".parse::<std::net::IpAddr>().unwrap()]
+        );
+        ids.push(response.id());
+    }
+    ids.sort();
+    assert_eq!(ids, [1, 2]);
+}
+
+#[tokio::test]
+async fn connections_over_the_limit_are_closed() {
+    let dns = server(TcpLimits {
+        max_connections: 1,
+        ..TcpLimits::default()
+    })
+    .await;
+    let mut first = TcpStream::connect(dns).await.unwrap();
+    // Make sure the first connection holds the only slot.
+    send_query(&mut first, 1).await;
+    assert!(read_response(&mut first).await.is_some());
+
+    let mut second = TcpStream::connect(dns).await.unwrap();
+    send_query(&mut second, 2).await;
+    assert!(read_response(&mut second).await.is_none());
+
+    send_query(&mut first, 3).await;
+    assert_eq!(read_response(&mut first).await.unwrap().id(), 3);
+}
+
+#[tokio::test]
+async fn idle_connections_time_out() {
+    let dns = server(TcpLimits {
+        idle_timeout: Duration::from_millis(200),
+        ..TcpLimits::default()
+    })
+    .await;
+    let mut stream = TcpStream::connect(dns).await.unwrap();
+    assert!(read_response(&mut stream).await.is_none());
+}
+
+/// Transfers `zone`, returning every record, or the response code of a
+/// refusal.
+async fn transfer(dns: SocketAddr, zone: &str) -> Result<Vec<Record>, ResponseCode> {
+    let mut stream = TcpStream::connect(dns).await.unwrap();
+    send(&mut stream, 7, zone, RecordType::AXFR).await;
+    let mut records: Vec<Record> = Vec::new();
+    loop {
+        let response = read_response(&mut stream).await.unwrap();
+        if response.response_code() != ResponseCode::NoError {
+            return Err(response.response_code());
+        }
+        assert!(response.authoritative());
+        records.extend(response.answers().iter().cloned());
+        // The zone ends with its SOA again.
+        if records.len() > 1 && records.last().unwrap().record_type() == RecordType::SOA {
+            return Ok(records);
+        }
+    }
+}
+
+fn serial(record: &Record) -> u32 {
+    let Some(RData::SOA(soa)) = record.data() else {
+        panic!("not an SOA: {:?}", record);
+    };
+    soa.serial()
+}
+
+#[tokio::test]
+async fn allowed_secondaries_can_transfer_the_zone() {
+    let state = State::default();
+    {
+        let mut map = state.write().await;
+        let mut web = Entry::new("10.0.0.2".parse().unwrap());
+        web.extra_ips.push("fd00::2".parse().unwrap());
+        map.insert("web-1".into(), web);
+        map.insert("*.web-1".into(), Entry::new("10.0.0.2".parse().unwrap()));
+        let mut db = Entry::new("10.0.0.3".parse().unwrap());
+        db.scoped_ips.push(ScopedIp {
+            scope: "host".into(),
+            ip: "192.168.1.5".parse().unwrap(),
+        });
+        map.insert("db".into(), db);
+    }
+    let cfg = Config {
+        local_domain: Some("glued.internal".into()),
+        soa_serial: Some(100),
+        axfr_allow: vec!["127.0.0.1".parse().unwrap()],
+        ..Config::default()
+    };
+    let dns = spawn_dns_with(state.clone(), DnsOptions::from_config(&cfg).unwrap()).await;
+
+    let records = transfer(dns, "glued.internal.").await.unwrap();
+    let listed: Vec<(String, RecordType)> = records
+        .iter()
+        .map(|record| (record.name().to_string(), record.record_type()))
+        .collect();
+    let expected = [
+        ("glued.internal.", RecordType::SOA),
+        ("glued.internal.", RecordType::NS),
+        ("db.glued.internal.", RecordType::A),
+        ("web-1.glued.internal.", RecordType::A),
+        ("web-1.glued.internal.", RecordType::AAAA),
+        ("glued.internal.", RecordType::SOA),
+    ];
+    let expected: Vec<(String, RecordType)> = expected
+        .iter()
+        .map(|(name, rtype)| (name.to_string(), *rtype))
+        .collect();
+    assert_eq!(listed, expected);
+    // Addresses as the client's view has them.
+    assert!(matches!(records[2].data(), Some(RData::A(a)) if a.0.to_string() == "10.0.0.3"));
+    assert_eq!(serial(&records[0]), 100);
+
+    // A change is seen by the next SOA query, which a secondary polls.
+    state
+        .write()
+        .await
+        .insert("web-2".into(), Entry::new("10.0.0.4".parse().unwrap()));
+    let response = query(dns, "glued.internal.", RecordType::SOA).await;
+    assert_eq!(serial(&response.answers()[0]), 101);
+    let records = transfer(dns, "glued.internal.").await.unwrap();
+    assert_eq!(records.len(), 7);
+    assert_eq!(serial(&records[0]), 101);
+
+    // Not over UDP, and not of other zones.
+    let response = query(dns, "glued.internal.", RecordType::AXFR).await;
+    assert_eq!(response.response_code(), ResponseCode::Refused);
+    assert_eq!(
+        transfer(dns, "example.com.").await.unwrap_err(),
+        ResponseCode::Refused
+    );
+
+    // Nor to clients missing from axfr_allow.
+    let cfg = Config {
+        axfr_allow: vec!["10.0.0.53".parse().unwrap()],
+        ..cfg
+    };
+    let dns = spawn_dns_with(state, DnsOptions::from_config(&cfg).unwrap()).await;
+    assert_eq!(
+        transfer(dns, "glued.internal.").await.unwrap_err(),
+        ResponseCode::Refused
+    );
+}
@@ -0,0 +1,78 @@
+//! End-to-end tests: scripted runtime updates through the registry to DNS.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{spawn_pipeline, wait_for_code, wait_for_ips, State};
+use glued::gossip::{apply_update, apply_update_from, forward_local};
+use glued::registry::LocalSource;
+use glued::runtime::MockRuntime;
+use glued::types::Update;
+use hickory_server::proto::op::ResponseCode;
+use hickory_server::proto::rr::RecordType;
+use tokio::sync::mpsc;
+
+#[tokio::test]
+async fn add_is_answered() {
+    let runtime = MockRuntime::new()
+        .add("web-1", "10.0.0.2")
+        .add("db", "fd00::5");
+    let (_state, dns) = spawn_pipeline(runtime).await;
+
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.0.0.2"]).await;
+    wait_for_ips(dns, "db", RecordType::AAAA, &["fd00::5"]).await;
+}
+
+#[tokio::test]
+async fn remove_yields_nxdomain() {
+    let runtime = MockRuntime::new().add("web-1", "10.0.0.2").then(
+        Duration::from_millis(200),
+        Update::Remove {
+            name: "web-1".into(),
+        },
+    );
+    let (_state, dns) = spawn_pipeline(runtime).await;
+
+    wait_for_ips(dns, "web-1", RecordType::A, &["10.0.0.2"]).await;
+    wait_for_code(dns, "web-1", ResponseCode::NXDomain).await;
+    wait_for_code(dns, "never-registered", ResponseCode::NXDomain).await;
+}
+
+#[tokio::test]
+async fn rapid_add_remove_leaves_no_stale_entries() {
+    let mut runtime = MockRuntime::new();
+    for i in 0..50 {
+        runtime = runtime
+            .add("flappy", &format!("10.0.1.{}", i))
+            .remove("flappy");
+    }
+    runtime = runtime
+        .add("steady", "10.0.2.1")
+        .add("steady", "10.0.2.2")
+        .remove("gone");
+    let (state, dns) = spawn_pipeline(runtime).await;
+
+    wait_for_ips(dns, "steady", RecordType::A, &["10.0.2.2"]).await;
+    wait_for_code(dns, "flappy", ResponseCode::NXDomain).await;
+
+    let map = state.read().await;
+    assert_eq!(map.len(), 1, "unexpected entries: {:?}", *map);
+}
+
+#[tokio::test]
+async fn only_local_changes_are_gossiped() {
+    let state = State::default();
+    let (outbound, mut published) = mpsc::channel(8);
+    tokio::spawn(forward_local(state.subscribe(), outbound));
+    let add = |name: &str, ip: &str| Update::Add {
+        name: name.into(),
+        ip: ip.parse().unwrap(),
+    };
+
+    apply_update_from(add("remote", "10.0.0.3"), Some("ab12cd34ef"), &state).await;
+    apply_update(add("local", "10.0.0.2"), LocalSource::DockerEvent, &state).await;
+    assert_eq!(published.recv().await, Some(add("local", "10.0.0.2")));
+    assert!(published.try_recv().is_err());
+    assert_eq!(state.read().await.len(), 2);
+}